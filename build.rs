@@ -0,0 +1,113 @@
+// Build script --> runs on the host before the kernel is compiled
+// creates the blank disk image QEMU attaches as the primary slave drive when running tests (see test-args in Cargo.toml)
+// with the `symbols` feature it also writes the symbol table the backtraces resolve addresses with (see SYMBOL TABLE)
+extern crate alloc; // for backtrace/demangle.rs, which the kernel compiles too
+
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+#[path = "src/backtrace/demangle.rs"]
+mod demangle;
+
+use demangle::demangle;
+
+const TEST_DISK_SIZE: u64 = 1024 * 1024; // 1 MiB = 2048 sectors
+
+fn main() {
+    let target_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("target");
+    let disk = target_dir.join("test_disk.img");
+    if !disk.exists() {
+        fs::create_dir_all(&target_dir).expect("failed to create the target directory");
+        File::create(&disk)
+            .and_then(|file| file.set_len(TEST_DISK_SIZE))
+            .expect("failed to create the test disk image");
+    }
+    if std::env::var_os("CARGO_FEATURE_SYMBOLS").is_some() {
+        write_symbol_table();
+    }
+    println!("cargo:rerun-if-changed=build.rs");
+}
+
+// SYMBOL TABLE ====================================
+// the kernel can't contain its own symbol table when it is compiled (its addresses aren't known yet) --> it takes two
+// builds: the first one is built, its ELF file is given to the second one in MINI_OS_SYMBOLS, the second one embeds
+// the functions of the first one
+//   cargo build --features symbols
+//   MINI_OS_SYMBOLS=target/x86_64-mini_os/debug/mini_os cargo build --features symbols
+// - the table is always padded to SYMBOL_TABLE_CAPACITY --> both builds have the same layout, so the addresses of the
+//   first one are the addresses of the second one (as long as nothing else changed in between)
+// - without MINI_OS_SYMBOLS the table is empty, every address resolves to None
+// - the first build's address of ANCHOR_SYMBOL goes into the table: backtrace.rs compares it with where the function
+//   is in the running kernel and ignores a table that came from another build (ex. the normal kernel's table in the
+//   test kernel, or a build from before a change) instead of naming the wrong functions
+// - the layout (all little endian), read by backtrace.rs:
+//     "MSYM", u32 count, u64 address of ANCHOR_SYMBOL (0 if it isn't there)
+//     count x { u64 address, u64 size, u32 name offset, u32 name length }, sorted by address
+//     the names (UTF-8, demangled), then zeros up to SYMBOL_TABLE_CAPACITY
+
+const SYMBOL_TABLE_CAPACITY: usize = 2 * 1024 * 1024;
+// a #[no_mangle] function of backtrace.rs, its name is the same in every build
+const ANCHOR_SYMBOL: &str = "mini_os_symbol_anchor";
+
+fn write_symbol_table() {
+    println!("cargo:rerun-if-env-changed=MINI_OS_SYMBOLS");
+    let mut functions = match std::env::var_os("MINI_OS_SYMBOLS") {
+        Some(path) => {
+            println!("cargo:rerun-if-changed={}", Path::new(&path).display());
+            let elf = fs::read(&path).unwrap_or_else(|e| panic!("can't read MINI_OS_SYMBOLS {:?}: {}", path, e));
+            elf_functions(&elf)
+        }
+        None => Vec::new(),
+    };
+    functions.sort_by_key(|&(address, _, _)| address);
+    functions.dedup_by_key(|&mut (address, _, _)| address);
+
+    let mut table = Vec::with_capacity(SYMBOL_TABLE_CAPACITY);
+    table.extend_from_slice(b"MSYM");
+    table.extend_from_slice(&(functions.len() as u32).to_le_bytes());
+    let anchor = functions.iter().find(|(_, _, name)| name == ANCHOR_SYMBOL).map_or(0, |&(address, _, _)| address);
+    table.extend_from_slice(&anchor.to_le_bytes());
+    let mut names = Vec::new();
+    for (address, size, name) in &functions {
+        table.extend_from_slice(&address.to_le_bytes());
+        table.extend_from_slice(&size.to_le_bytes());
+        table.extend_from_slice(&(names.len() as u32).to_le_bytes());
+        table.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        names.extend_from_slice(name.as_bytes());
+    }
+    table.extend_from_slice(&names);
+    assert!(table.len() <= SYMBOL_TABLE_CAPACITY, "the symbol table needs {} bytes, raise SYMBOL_TABLE_CAPACITY", table.len());
+    table.resize(SYMBOL_TABLE_CAPACITY, 0);
+
+    let out = PathBuf::from(std::env::var_os("OUT_DIR").expect("no OUT_DIR")).join("symbols.bin");
+    fs::write(&out, table).expect("failed to write the symbol table");
+}
+
+// the functions (address, size, demangled name) in the symbol table of a 64 bit little endian ELF file
+fn elf_functions(elf: &[u8]) -> Vec<(u64, u64, String)> {
+    const SHT_SYMTAB: u32 = 2;
+    const STT_FUNC: u8 = 2;
+    let u16_at = |offset: usize| u16::from_le_bytes(elf[offset..offset + 2].try_into().unwrap());
+    let u32_at = |offset: usize| u32::from_le_bytes(elf[offset..offset + 4].try_into().unwrap());
+    let u64_at = |offset: usize| u64::from_le_bytes(elf[offset..offset + 8].try_into().unwrap());
+    assert!(elf.starts_with(b"\x7fELF\x02\x01"), "MINI_OS_SYMBOLS is no 64 bit little endian ELF file");
+
+    let (section_headers, header_size, count) = (u64_at(0x28) as usize, u16_at(0x3A) as usize, u16_at(0x3C) as usize);
+    let section = |index: usize| section_headers + index * header_size;
+    let symtab = (0..count).map(section).find(|&header| u32_at(header + 4) == SHT_SYMTAB).expect("no symbol table, stripped?");
+    let strtab = section(u32_at(symtab + 0x28) as usize);
+    let strings = u64_at(strtab + 0x18) as usize;
+    let (symbols, symbols_size) = (u64_at(symtab + 0x18) as usize, u64_at(symtab + 0x20) as usize);
+
+    let mut functions = Vec::new();
+    for symbol in (symbols..symbols + symbols_size).step_by(24) {
+        let (info, address, size) = (elf[symbol + 4], u64_at(symbol + 8), u64_at(symbol + 16));
+        if info & 0xf != STT_FUNC || size == 0 {
+            continue;
+        }
+        let name = &elf[strings + u32_at(symbol) as usize..];
+        let name = String::from_utf8_lossy(&name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())]);
+        functions.push((address, size, demangle(&name)));
+    }
+    functions
+}
@@ -0,0 +1,490 @@
+// ACPI tables --> what the firmware tells us about the machine: interrupt controllers (MADT), power management (FADT),
+// timers (HPET)...
+// see: https://wiki.osdev.org/RSDP and https://wiki.osdev.org/RSDT
+//
+// - the RSDP ("root system description pointer") is found by searching for "RSD PTR " on 16 byte boundaries in the first
+//   KiB of the EBDA (extended BIOS data area) and in the BIOS area 0xE0000-0xFFFFF
+// - it points to the RSDT (32 bit table addresses, ACPI 1.0) or the XSDT (64 bit addresses, ACPI 2.0+), which list the
+//   other tables, every table starts with the same 36 byte header (signature, length, checksum...)
+// - all bytes of a table (and of the RSDP) sum to 0 mod 256 --> checked before anything in a table is believed
+// - everything is read through the physical memory mapping and parsed from byte slices: a malformed table is an error,
+//   never a panic or a read past its end
+use crate::memory::{phys_read, phys_slice};
+use core::fmt;
+use spin::Once;
+use x86_64::PhysAddr;
+
+pub const HEADER_LEN: usize = 36;
+// nothing real comes close, a larger length is a broken table
+const MAX_TABLE_LEN: usize = 1 << 20;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+const RSDP_V1_LEN: usize = 20;
+const RSDP_V2_LEN: usize = 36;
+// real mode segment of the EBDA, stored by the BIOS at 0x40E
+const EBDA_POINTER: u64 = 0x40E;
+const EBDA_SEARCH_LEN: usize = 1024;
+const BIOS_AREA_START: u64 = 0xE0000;
+const BIOS_AREA_END: u64 = 0x100000;
+
+pub const MADT_SIGNATURE: [u8; 4] = *b"APIC";
+pub const FADT_SIGNATURE: [u8; 4] = *b"FACP";
+
+/// A 4 character table signature, printed as text.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Signature(pub [u8; 4]);
+
+impl fmt::Display for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for &byte in &self.0 {
+            let c = if byte.is_ascii_graphic() { byte as char } else { '?' };
+            write!(f, "{}", c)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "\"{}\"", self)
+    }
+}
+
+/// Errors of ACPI table discovery and parsing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    /// No valid RSDP in the EBDA or the BIOS area.
+    NoRsdp,
+    /// The RSDP has a revision we don't know.
+    UnsupportedRevision(u8),
+    /// The bytes of a table don't sum to 0.
+    BadChecksum(Signature),
+    /// A table is shorter than its header or its content needs, or implausibly long.
+    BadLength(Signature),
+    /// A table has another signature than expected.
+    WrongSignature(Signature),
+    /// No table with this signature.
+    NotFound(Signature),
+    /// An address in the RSDP or a root table that can't be a physical address (over 52 bits).
+    BadAddress(u64),
+}
+
+// the firmware's tables may hold any value where an address should be --> never PhysAddr::new(), which panics
+fn phys_addr(address: u64) -> Result<PhysAddr, AcpiError> {
+    PhysAddr::try_new(address).map_err(|_| AcpiError::BadAddress(address))
+}
+
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+// RSDP ====================================
+
+/// The root system description pointer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rsdp {
+    /// 0 for ACPI 1.0, 2 for ACPI 2.0 and later.
+    pub revision: u8,
+    pub oem_id: [u8; 6],
+    pub rsdt_address: u32,
+    /// Only for revision 2+.
+    pub xsdt_address: Option<u64>,
+}
+
+impl Rsdp {
+    /// Check and decode an RSDP (at least 20 bytes, 36 for revision 2+).
+    pub fn parse(bytes: &[u8]) -> Result<Self, AcpiError> {
+        let signature = Signature(*b"RSDP");
+        if bytes.len() < RSDP_V1_LEN || &bytes[..8] != RSDP_SIGNATURE {
+            return Err(AcpiError::WrongSignature(signature));
+        }
+        if !checksum_ok(&bytes[..RSDP_V1_LEN]) {
+            return Err(AcpiError::BadChecksum(signature));
+        }
+        let revision = bytes[15];
+        let xsdt_address = match revision {
+            0 => None,
+            1 => return Err(AcpiError::UnsupportedRevision(revision)),
+            _ => {
+                // the extended checksum covers the whole v2 structure
+                if bytes.len() < RSDP_V2_LEN || (u32_at(bytes, 20) as usize) < RSDP_V2_LEN {
+                    return Err(AcpiError::BadLength(signature));
+                }
+                if !checksum_ok(&bytes[..RSDP_V2_LEN]) {
+                    return Err(AcpiError::BadChecksum(signature));
+                }
+                Some(u64_at(bytes, 24))
+            }
+        };
+        let mut oem_id = [0; 6];
+        oem_id.copy_from_slice(&bytes[9..15]);
+        Ok(Rsdp { revision, oem_id, rsdt_address: u32_at(bytes, 16), xsdt_address })
+    }
+}
+
+// the first valid RSDP on a 16 byte boundary in `len` bytes at `start`
+fn search_rsdp(start: u64, len: usize) -> Option<(PhysAddr, Rsdp)> {
+    let start = phys_addr(start).ok()?;
+    let area = unsafe { phys_slice(start, len) };
+    (0..len.saturating_sub(RSDP_V1_LEN - 1)).step_by(16).find_map(|offset| {
+        let end = (offset + RSDP_V2_LEN).min(len);
+        let rsdp = Rsdp::parse(&area[offset..end]).ok()?;
+        Some((start + offset as u64, rsdp))
+    })
+}
+
+/// Find the RSDP in the EBDA or the BIOS area, returns its address too.
+pub fn find_rsdp() -> Result<(PhysAddr, Rsdp), AcpiError> {
+    let ebda = u64::from(unsafe { phys_read::<u16>(phys_addr(EBDA_POINTER)?) }) << 4;
+    let in_ebda = if ebda != 0 && ebda < BIOS_AREA_START { search_rsdp(ebda, EBDA_SEARCH_LEN) } else { None };
+    in_ebda
+        .or_else(|| search_rsdp(BIOS_AREA_START, (BIOS_AREA_END - BIOS_AREA_START) as usize))
+        .ok_or(AcpiError::NoRsdp)
+}
+
+// TABLES ====================================
+
+/// The common header of every table (besides the RSDP).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SdtHeader {
+    pub signature: Signature,
+    /// Of the whole table, header included.
+    pub length: u32,
+    pub revision: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    /// Where the table is.
+    pub address: PhysAddr,
+}
+
+/// Check a whole table (`bytes` is exactly the table) and decode its header.
+pub fn parse_header(bytes: &[u8], address: PhysAddr) -> Result<SdtHeader, AcpiError> {
+    if bytes.len() < HEADER_LEN {
+        return Err(AcpiError::BadLength(Signature(*b"????")));
+    }
+    let signature = Signature(bytes[..4].try_into().unwrap());
+    if u32_at(bytes, 4) as usize != bytes.len() {
+        return Err(AcpiError::BadLength(signature));
+    }
+    if !checksum_ok(bytes) {
+        return Err(AcpiError::BadChecksum(signature));
+    }
+    Ok(SdtHeader {
+        signature,
+        length: u32_at(bytes, 4),
+        revision: bytes[8],
+        oem_id: bytes[10..16].try_into().unwrap(),
+        oem_table_id: bytes[16..24].try_into().unwrap(),
+        address,
+    })
+}
+
+/// The bytes of the table at `address` (its length from the header), checked with `parse_header()`.
+pub fn read_table(address: PhysAddr) -> Result<(SdtHeader, &'static [u8]), AcpiError> {
+    let header = unsafe { phys_slice(address, HEADER_LEN) };
+    let len = u32_at(header, 4) as usize;
+    if !(HEADER_LEN..=MAX_TABLE_LEN).contains(&len) {
+        return Err(AcpiError::BadLength(Signature(header[..4].try_into().unwrap())));
+    }
+    let bytes = unsafe { phys_slice(address, len) };
+    Ok((parse_header(bytes, address)?, bytes))
+}
+
+// an entry of the root table: 4 bytes in the RSDT, 8 in the XSDT
+fn table_address(entry: &[u8]) -> Result<PhysAddr, AcpiError> {
+    phys_addr(if entry.len() == 8 { u64_at(entry, 0) } else { u64::from(u32_at(entry, 0)) })
+}
+
+/// The RSDP and the root table (RSDT or XSDT) listing all other tables.
+#[derive(Debug, Clone, Copy)]
+pub struct AcpiTables {
+    pub rsdp: Rsdp,
+    pub root: SdtHeader,
+    root_bytes: &'static [u8],
+    entry_size: usize, // 4 for the RSDT, 8 for the XSDT
+}
+
+impl AcpiTables {
+    /// Find the RSDP and check the root table it points to.
+    pub fn discover() -> Result<Self, AcpiError> {
+        let (_, rsdp) = find_rsdp()?;
+        let (address, signature, entry_size) = match rsdp.xsdt_address {
+            Some(xsdt) => (xsdt, *b"XSDT", 8),
+            None => (u64::from(rsdp.rsdt_address), *b"RSDT", 4),
+        };
+        let (root, root_bytes) = read_table(phys_addr(address)?)?;
+        if root.signature.0 != signature {
+            return Err(AcpiError::WrongSignature(root.signature));
+        }
+        Ok(AcpiTables { rsdp, root, root_bytes, entry_size })
+    }
+
+    /// The addresses of all tables listed in the root table, an error for the ones that can't be physical addresses.
+    pub fn table_addresses(&self) -> impl Iterator<Item = Result<PhysAddr, AcpiError>> + '_ {
+        self.root_bytes[HEADER_LEN..].chunks_exact(self.entry_size).map(table_address)
+    }
+
+    /// The header of every listed table, an error for the ones that are broken.
+    pub fn headers(&self) -> impl Iterator<Item = Result<SdtHeader, AcpiError>> + '_ {
+        self.table_addresses().map(|address| read_table(address?).map(|(header, _)| header))
+    }
+
+    /// The first valid table with `signature`.
+    pub fn find(&self, signature: [u8; 4]) -> Result<&'static [u8], AcpiError> {
+        let mut error = AcpiError::NotFound(Signature(signature));
+        for address in self.table_addresses() {
+            match address.and_then(read_table) {
+                Ok((header, bytes)) if header.signature.0 == signature => return Ok(bytes),
+                Ok(_) => {}
+                Err(table_error) => error = table_error, // maybe it was the one we're looking for
+            }
+        }
+        Err(error)
+    }
+
+    pub fn madt(&self) -> Result<Madt<'static>, AcpiError> {
+        Madt::parse(self.find(MADT_SIGNATURE)?)
+    }
+
+    pub fn fadt(&self) -> Result<Fadt, AcpiError> {
+        Fadt::parse(self.find(FADT_SIGNATURE)?)
+    }
+}
+
+// MADT ====================================
+// header, local APIC address u32, flags u32, then entries [type u8][length u8][...]
+
+const MADT_ENTRIES: usize = HEADER_LEN + 8;
+
+/// An entry of the MADT (multiple APIC description table).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MadtEntry {
+    /// A CPU and its local APIC.
+    LocalApic { processor_id: u8, apic_id: u8, flags: u32 },
+    /// An I/O APIC whose inputs start at global system interrupt `gsi_base`.
+    IoApic { id: u8, address: u32, gsi_base: u32 },
+    /// ISA IRQ `source` arrives at global system interrupt `gsi` instead of the identical number.
+    InterruptOverride { bus: u8, source: u8, gsi: u32, flags: u16 },
+    /// Anything else (NMI sources, address overrides, x2APIC entries...).
+    Other { entry_type: u8 },
+}
+
+/// Bit 0 of a local APIC entry's flags: the CPU can be used.
+pub const LAPIC_ENABLED: u32 = 1;
+
+/// A checked MADT.
+#[derive(Debug, Clone, Copy)]
+pub struct Madt<'a> {
+    pub local_apic_address: u32,
+    pub flags: u32,
+    entries: &'a [u8],
+}
+
+impl<'a> Madt<'a> {
+    /// Check the table, including that every entry has a plausible length and lies inside it.
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, AcpiError> {
+        let header = parse_header(bytes, PhysAddr::zero())?;
+        if header.signature.0 != MADT_SIGNATURE {
+            return Err(AcpiError::WrongSignature(header.signature));
+        }
+        let bad_length = AcpiError::BadLength(header.signature);
+        if bytes.len() < MADT_ENTRIES {
+            return Err(bad_length);
+        }
+        let entries = &bytes[MADT_ENTRIES..];
+        let mut offset = 0;
+        while offset < entries.len() {
+            let rest = &entries[offset..];
+            let len = rest.get(1).copied().ok_or(bad_length)? as usize;
+            let min_len = match rest[0] {
+                0 => 8,
+                1 => 12,
+                2 => 10,
+                _ => 2,
+            };
+            if len < min_len || len > rest.len() {
+                return Err(bad_length);
+            }
+            offset += len;
+        }
+        Ok(Madt { local_apic_address: u32_at(bytes, HEADER_LEN), flags: u32_at(bytes, HEADER_LEN + 4), entries })
+    }
+
+    pub fn entries(&self) -> MadtEntries<'a> {
+        MadtEntries { rest: self.entries }
+    }
+}
+
+/// Iterator over the entries of a `Madt`.
+pub struct MadtEntries<'a> {
+    rest: &'a [u8],
+}
+
+impl Iterator for MadtEntries<'_> {
+    type Item = MadtEntry;
+
+    fn next(&mut self) -> Option<MadtEntry> {
+        // Madt::parse() checked the lengths
+        let entry = self.rest.get(..usize::from(*self.rest.get(1)?))?;
+        self.rest = &self.rest[entry.len()..];
+        Some(match entry[0] {
+            0 => MadtEntry::LocalApic { processor_id: entry[2], apic_id: entry[3], flags: u32_at(entry, 4) },
+            1 => MadtEntry::IoApic { id: entry[2], address: u32_at(entry, 4), gsi_base: u32_at(entry, 8) },
+            2 => MadtEntry::InterruptOverride {
+                bus: entry[2],
+                source: entry[3],
+                gsi: u32_at(entry, 4),
+                flags: u16_at(entry, 8),
+            },
+            entry_type => MadtEntry::Other { entry_type },
+        })
+    }
+}
+
+// FADT ====================================
+
+// offsets of the fields we use
+const FADT_DSDT: usize = 40;
+const FADT_SCI_INTERRUPT: usize = 46;
+const FADT_SMI_COMMAND: usize = 48;
+const FADT_ACPI_ENABLE: usize = 52;
+const FADT_PM1A_CONTROL: usize = 64;
+const FADT_PM1B_CONTROL: usize = 68;
+const FADT_PM1_CONTROL_LEN: usize = 89;
+const FADT_X_DSDT: usize = 140; // ACPI 2.0+, used instead of the 32 bit address if set
+
+/// The power management parts of the FADT (fixed ACPI description table).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fadt {
+    /// The ISA IRQ of ACPI events.
+    pub sci_interrupt: u16,
+    /// I/O port taking `acpi_enable` to switch from legacy (SMM) to ACPI mode (0 = always in ACPI mode).
+    pub smi_command_port: u32,
+    pub acpi_enable: u8,
+    /// I/O port of the PM1a control register (0 = none).
+    pub pm1a_control_block: u32,
+    /// I/O port of the PM1b control register (0 = none).
+    pub pm1b_control_block: u32,
+    pub pm1_control_length: u8,
+    /// Where the DSDT (the AML code with the sleep states) is.
+    pub dsdt_address: u64,
+}
+
+impl Fadt {
+    pub fn parse(bytes: &[u8]) -> Result<Self, AcpiError> {
+        let header = parse_header(bytes, PhysAddr::zero())?;
+        if header.signature.0 != FADT_SIGNATURE {
+            return Err(AcpiError::WrongSignature(header.signature));
+        }
+        if bytes.len() <= FADT_PM1_CONTROL_LEN {
+            return Err(AcpiError::BadLength(header.signature));
+        }
+        let x_dsdt = if bytes.len() >= FADT_X_DSDT + 8 { u64_at(bytes, FADT_X_DSDT) } else { 0 };
+        Ok(Fadt {
+            sci_interrupt: u16_at(bytes, FADT_SCI_INTERRUPT),
+            smi_command_port: u32_at(bytes, FADT_SMI_COMMAND),
+            acpi_enable: bytes[FADT_ACPI_ENABLE],
+            pm1a_control_block: u32_at(bytes, FADT_PM1A_CONTROL),
+            pm1b_control_block: u32_at(bytes, FADT_PM1B_CONTROL),
+            pm1_control_length: bytes[FADT_PM1_CONTROL_LEN],
+            dsdt_address: if x_dsdt != 0 { x_dsdt } else { u64::from(u32_at(bytes, FADT_DSDT)) },
+        })
+    }
+}
+
+// GLOBAL ====================================
+
+static TABLES: Once<Option<AcpiTables>> = Once::new();
+
+/// Discover the tables and print their signatures (only the first call does anything), `None` without ACPI.
+///
+/// Needs the physical memory mapping (`memory::init_frame_allocator()`).
+pub fn init() -> Option<&'static AcpiTables> {
+    TABLES
+        .call_once(|| match AcpiTables::discover() {
+            Ok(tables) => {
+                crate::print!("ACPI {} tables:", if tables.entry_size == 8 { "XSDT" } else { "RSDT" });
+                for header in tables.headers() {
+                    match header {
+                        Ok(header) => crate::print!(" {}", header.signature),
+                        Err(error) => crate::print!(" ({:?})", error),
+                    }
+                }
+                crate::println!();
+                Some(tables)
+            }
+            Err(error) => {
+                crate::println!("ACPI: {:?}", error);
+                None
+            }
+        })
+        .as_ref()
+}
+
+/// The tables found by `init()`.
+pub fn tables() -> Option<&'static AcpiTables> {
+    TABLES.r#try().and_then(Option::as_ref)
+}
+
+// TESTS ===================================
+
+#[test_case]
+fn test_madt_has_apics() {
+    let tables = init().expect("no ACPI tables");
+    let madt = tables.madt().expect("bad MADT");
+    let local_apics = madt.entries().filter(|entry| matches!(entry, MadtEntry::LocalApic { .. })).count();
+    let io_apics = madt.entries().filter(|entry| matches!(entry, MadtEntry::IoApic { .. })).count();
+    assert!(local_apics >= 1, "no local APIC in {:?}", madt);
+    assert!(io_apics >= 1, "no I/O APIC in {:?}", madt);
+    assert_eq!(madt.local_apic_address, 0xFEE0_0000);
+
+    let fadt = tables.fadt().expect("bad FADT");
+    assert_ne!(fadt.pm1a_control_block, 0);
+    assert_ne!(fadt.dsdt_address, 0);
+}
+
+#[test_case]
+fn test_malformed_tables_are_errors() {
+    use alloc::vec::Vec;
+
+    let tables = init().expect("no ACPI tables");
+    let madt = tables.find(MADT_SIGNATURE).expect("no MADT");
+    let signature = Signature(MADT_SIGNATURE);
+
+    // a flipped byte
+    let mut corrupted = madt.to_vec();
+    corrupted[HEADER_LEN] ^= 1;
+    assert_eq!(Madt::parse(&corrupted).unwrap_err(), AcpiError::BadChecksum(signature));
+
+    // cut short (length and checksum fixed up) in the middle of an entry
+    let mut cut = madt[..madt.len() - 1].to_vec();
+    let len = cut.len() as u32;
+    cut[4..8].copy_from_slice(&len.to_le_bytes());
+    cut[9] = 0;
+    cut[9] = 0u8.wrapping_sub(cut.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)));
+    assert_eq!(Madt::parse(&cut).unwrap_err(), AcpiError::BadLength(signature));
+
+    // not a MADT, too short for a header
+    assert!(matches!(Madt::parse(tables.find(FADT_SIGNATURE).unwrap()), Err(AcpiError::WrongSignature(_))));
+    assert!(Fadt::parse(&[0; 10]).is_err());
+    assert_eq!(Rsdp::parse(b"RSD PTX ....................").unwrap_err(), AcpiError::WrongSignature(Signature(*b"RSDP")));
+
+    let headers: Vec<_> = tables.headers().collect();
+    assert!(headers.iter().all(Result::is_ok), "{:?}", headers);
+
+    // an XSDT entry over 52 bits is an error, not a panic
+    assert_eq!(table_address(&[0xFF; 8]), Err(AcpiError::BadAddress(u64::MAX)));
+    assert_eq!(table_address(&[0x00, 0x10, 0, 0]), Ok(PhysAddr::new(0x1000)));
+}
@@ -1,3 +1,4 @@
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use x86_64::{
     structures::paging::{
         mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
@@ -31,17 +32,225 @@ fn align_up(addr: usize, align: usize) -> usize {
     (addr + align - 1) & !(align - 1)
 }
 
+// No-alloc guard ===================================
+
+// set while some code that must never allocate is running (interrupt handlers, spinlock holders the allocator could deadlock on)
+static NO_ALLOC_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// While alive, every heap allocation panics with "heap allocation attempted in no-alloc context".
+///
+/// Guards nest: dropping one restores the state from before it was taken, so an interrupt handler's
+/// guard doesn't end the guard of the code it interrupted.
+pub struct NoAllocGuard {
+    was_active: bool,
+}
+
+/// Forbid heap allocations until the returned guard is dropped.
+pub fn no_alloc_guard() -> NoAllocGuard {
+    NoAllocGuard { was_active: NO_ALLOC_ACTIVE.swap(true, Ordering::SeqCst) }
+}
+
+impl Drop for NoAllocGuard {
+    fn drop(&mut self) {
+        NO_ALLOC_ACTIVE.store(self.was_active, Ordering::SeqCst);
+    }
+}
+
+/// Allow allocations again no matter which guards are alive, for the test runner after a test panicked with a guard
+/// whose stack it threw away (see TEST RECOVERY in lib.rs).
+pub fn reset_no_alloc_guards() {
+    NO_ALLOC_ACTIVE.store(false, Ordering::SeqCst);
+}
+
+/// Called first thing by every `GlobalAlloc::alloc()` implementation.
+fn assert_alloc_allowed() {
+    if NO_ALLOC_ACTIVE.load(Ordering::SeqCst) {
+        // the panic handler must not allocate either --> lift the guard before panicking
+        NO_ALLOC_ACTIVE.store(false, Ordering::SeqCst);
+        panic!("heap allocation attempted in no-alloc context");
+    }
+}
+
+// Statistics ===================================
+
+// updated by every GlobalAlloc implementation through record_alloc()/record_dealloc()
+static HEAP_BYTES: AtomicU64 = AtomicU64::new(0);
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+static DEALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+static FAILED_ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+static BYTES_IN_USE: AtomicU64 = AtomicU64::new(0);
+// the most BYTES_IN_USE was since boot or reset_high_water_mark() --> tells how big HEAP_SIZE really has to be
+static HIGH_WATER_MARK: AtomicU64 = AtomicU64::new(0);
+
+/// What the heap allocator did since boot (see `stats()`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AllocatorStats {
+    /// Size of the mapped heap.
+    pub heap_size: u64,
+    pub allocations: u64,
+    pub deallocations: u64,
+    /// Allocations that returned null (out of memory).
+    pub failed_allocations: u64,
+    /// Bytes requested by allocations that haven't been freed (without block size rounding).
+    pub bytes_in_use: u64,
+}
+
+/// A snapshot of the heap allocator's counters.
+pub fn stats() -> AllocatorStats {
+    AllocatorStats {
+        heap_size: HEAP_BYTES.load(Ordering::Relaxed),
+        allocations: ALLOCATIONS.load(Ordering::Relaxed),
+        deallocations: DEALLOCATIONS.load(Ordering::Relaxed),
+        failed_allocations: FAILED_ALLOCATIONS.load(Ordering::Relaxed),
+        bytes_in_use: BYTES_IN_USE.load(Ordering::Relaxed),
+    }
+}
+
+/// The most bytes that were in use at once (`AllocatorStats::bytes_in_use`) since boot or `reset_high_water_mark()`.
+pub fn heap_high_water_mark() -> usize {
+    HIGH_WATER_MARK.load(Ordering::Relaxed) as usize
+}
+
+/// Start the high-water mark over at the current usage, ex. between two benchmarks.
+pub fn reset_high_water_mark() {
+    HIGH_WATER_MARK.store(BYTES_IN_USE.load(Ordering::Relaxed), Ordering::Relaxed);
+}
+
+/// Print the high-water mark against the heap size to serial (at shutdown and when leaving QEMU), nothing without a heap.
+pub fn print_heap_peak() {
+    let heap_size = HEAP_BYTES.load(Ordering::Relaxed);
+    if heap_size == 0 {
+        return;
+    }
+    let peak = heap_high_water_mark() as u64;
+    crate::serial_println!("Heap peak usage: {} / {} bytes ({}%)", peak, heap_size, peak * 100 / heap_size);
+}
+
+/// Called last by every `GlobalAlloc::alloc()` implementation with its result, returns `ptr`.
+fn record_alloc(size: usize, ptr: *mut u8) -> *mut u8 {
+    if ptr.is_null() {
+        FAILED_ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    } else {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        let in_use = BYTES_IN_USE.fetch_add(size as u64, Ordering::Relaxed) + size as u64;
+        HIGH_WATER_MARK.fetch_max(in_use, Ordering::Relaxed);
+    }
+    ptr
+}
+
+/// Called by every `GlobalAlloc::dealloc()` implementation.
+fn record_dealloc(size: usize) {
+    DEALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    BYTES_IN_USE.fetch_sub(size as u64, Ordering::Relaxed);
+}
+
+/// Called by a `GlobalAlloc::realloc()` implementation that resized a block in place.
+fn record_realloc(old_size: usize, new_size: usize) {
+    if new_size >= old_size {
+        let grown = (new_size - old_size) as u64;
+        let in_use = BYTES_IN_USE.fetch_add(grown, Ordering::Relaxed) + grown;
+        HIGH_WATER_MARK.fetch_max(in_use, Ordering::Relaxed);
+    } else {
+        BYTES_IN_USE.fetch_sub((old_size - new_size) as u64, Ordering::Relaxed);
+    }
+}
+
 // Allocator implementations ================================
 
 pub mod bump;
 pub mod fixed_size_block;
+pub mod linked_list;
 
 // Choose an allocator
 use fixed_size_block::FixedSizeBlockAllocator;
 #[global_allocator]
-static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(
+pub(crate) static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(
     FixedSizeBlockAllocator::new());
 
+/// Where the free memory of the heap allocator is (see `heap_caches()`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HeapCaches {
+    /// Free blocks of every size in `fixed_size_block::BLOCK_SIZES`, only usable for allocations of that size class.
+    pub free_blocks: [usize; fixed_size_block::NUM_SIZE_CLASSES],
+    /// Free bytes of the fallback allocator, usable for anything.
+    pub fallback_free: usize,
+}
+
+impl HeapCaches {
+    /// Bytes held by the free blocks.
+    pub fn cached_bytes(&self) -> usize {
+        self.free_blocks.iter().zip(fixed_size_block::BLOCK_SIZES).map(|(count, size)| count * size).sum()
+    }
+}
+
+/// A snapshot of the heap allocator's free lists. Walks every list --> for debugging, not for hot paths.
+pub fn heap_caches() -> HeapCaches {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let allocator = ALLOCATOR.lock();
+        HeapCaches { free_blocks: allocator.free_list_lengths(), fallback_free: allocator.fallback_free() }
+    })
+}
+
+/// Print how many free blocks each block size of the heap allocator has (to serial).
+pub fn dump_allocator_state() {
+    use fixed_size_block::BLOCK_SIZES;
+
+    let lengths = x86_64::instructions::interrupts::without_interrupts(|| ALLOCATOR.lock().free_list_lengths());
+    crate::serial_println!("heap free lists:");
+    for (size, length) in BLOCK_SIZES.iter().zip(lengths) {
+        crate::serial_println!("  {:>5} bytes: {} free", size, length);
+    }
+}
+
+// Heap growth (heap_growth feature) ===================================
+// without it the heap is what init_heap() mapped at boot and an allocation that doesn't fit fails (see Out of memory)
+// --> with it a failed allocation of the fallback allocator maps more pages right after the heap's end (the kernel's
+// page tables and the global frame allocator, see memory::map_new_pages()) and tries again
+// - at least HEAP_GROWTH_STEP at a time, never past MAX_HEAP_SIZE
+// - needs memory::init_frame_allocator(), a heap set up with another frame allocator can't grow
+
+/// The least the heap grows by at a time.
+#[cfg(feature = "heap_growth")]
+pub const HEAP_GROWTH_STEP: usize = 64 * 1024;
+/// The heap never grows past this.
+#[cfg(feature = "heap_growth")]
+pub const MAX_HEAP_SIZE: usize = 16 * 1024 * 1024;
+
+/// Map at least `min_bytes` more heap after its current end, returns how many bytes it grew by (0 if it can't).
+///
+/// Called with the allocator locked, only maps --> the caller hands the new memory to its allocator.
+#[cfg(feature = "heap_growth")]
+fn grow_heap(min_bytes: usize) -> usize {
+    let size = HEAP_BYTES.load(Ordering::Relaxed) as usize;
+    let grow = align_up(min_bytes.max(HEAP_GROWTH_STEP), 4096).min(MAX_HEAP_SIZE.saturating_sub(size));
+    if size == 0 || grow < min_bytes {
+        return 0;
+    }
+    let end = VirtAddr::new((HEAP_START + size) as u64);
+    match crate::memory::map_new_pages(end, (grow / 4096) as u64, PageTableFlags::WRITABLE) {
+        Ok(()) => {
+            HEAP_BYTES.store((size + grow) as u64, Ordering::Relaxed);
+            grow
+        }
+        Err(_) => 0,
+    }
+}
+
+// Out of memory ===================================
+// an allocation the heap can't satisfy (after growing, with heap_growth) returns null --> the alloc crate calls
+// alloc_error() below, which prints the layout and the heap stats to serial and panics with OOM_PANIC_PREFIX
+// - test runs exit QEMU with QemuExitCode::Oom on that panic (see test_failure_code() in lib.rs), the kernel halts
+// - fallible allocations (try_reserve(), Box::try_new()) get an Err instead and never come here
+
+/// How an out of memory panic starts: "memory allocation of 409600 bytes (align 1) failed".
+pub const OOM_PANIC_PREFIX: &str = "memory allocation of ";
+
+#[alloc_error_handler]
+fn alloc_error(layout: core::alloc::Layout) -> ! {
+    crate::serial_println!("out of memory: {:?}\n{:?}", layout, stats());
+    panic!("{}{} bytes (align {}) failed", OOM_PANIC_PREFIX, layout.size(), layout.align());
+}
+
 // Heap Initialization ====================================
 
 // create a heap virtual memory region to use
@@ -53,9 +262,32 @@ pub fn init_heap(
     mapper: &mut impl Mapper<Size4KiB>,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
 ) -> Result<(), MapToError<Size4KiB>> {
+    init_heap_with_size(mapper, frame_allocator, HEAP_SIZE)
+}
+
+// lets LoggingFrameAllocator wrap the borrowed frame allocator init_heap_with_size() gets
+#[cfg(feature = "frame_trace")]
+struct ByRef<'a, A>(&'a mut A);
+
+#[cfg(feature = "frame_trace")]
+unsafe impl<A: FrameAllocator<Size4KiB>> FrameAllocator<Size4KiB> for ByRef<'_, A> {
+    fn allocate_frame(&mut self) -> Option<x86_64::structures::paging::PhysFrame> {
+        self.0.allocate_frame()
+    }
+}
+
+/// Same as `init_heap()` but maps `heap_size` bytes instead of the default `HEAP_SIZE` (see config.rs)
+pub fn init_heap_with_size(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    heap_size: usize,
+) -> Result<(), MapToError<Size4KiB>> {
+    #[cfg(feature = "frame_trace")]
+    let frame_allocator = &mut crate::memory::LoggingFrameAllocator::new(ByRef(frame_allocator));
+
     let page_range = {
         let heap_start = VirtAddr::new(HEAP_START as u64); // convert heap start to a virt addr
-        let heap_end = heap_start + HEAP_SIZE - 1u64; // calculate the end of the heap into a virt addr (inclusive so subtract 1)
+        let heap_end = heap_start + heap_size - 1u64; // calculate the end of the heap into a virt addr (inclusive so subtract 1)
         let heap_start_page = Page::containing_address(heap_start); // get the page containing the start heap address
         let heap_end_page = Page::containing_address(heap_end); // get the page containing the end heap addresses
         Page::range_inclusive(heap_start_page, heap_end_page) // return a range of pages in between (inclusive)
@@ -73,8 +305,28 @@ pub fn init_heap(
 
     // initialize allocator
     unsafe {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+        ALLOCATOR.lock().init(HEAP_START, heap_size);
     }
+    HEAP_BYTES.store(heap_size as u64, Ordering::Relaxed);
 
     Ok(())
-}
\ No newline at end of file
+}
+
+// TESTS ===================================
+
+#[test_case]
+fn test_align_up() {
+    for shift in 0..=12 {
+        let align = 1usize << shift;
+        for base in [0, align, 3 * align, 0x4444_4444_0000] {
+            assert_eq!(align_up(base, align), base, "aligned {:#x} moved for align {}", base, align);
+            // every misaligned address goes up to the next multiple
+            for offset in [1, align / 2, align - 1] {
+                if offset == 0 || offset >= align {
+                    continue;
+                }
+                assert_eq!(align_up(base + offset, align), base + align, "{:#x} + {} for align {}", base, offset, align);
+            }
+        }
+    }
+}
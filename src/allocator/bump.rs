@@ -1,6 +1,6 @@
 use alloc::alloc::{GlobalAlloc, Layout}; // we have to implement GlobalAlloc and #[global_allocator] attribute for our heap allocator instance
 use core::ptr;
-use super::{align_up, Locked};
+use super::{align_up, assert_alloc_allowed, record_alloc, record_dealloc, Locked};
 
 // See the pros and cons of this allocation style online...
 // Will fail the tests/heap_allocation.rs --> many_boxes_long_lived() test
@@ -36,24 +36,27 @@ impl BumpAllocator {
 
 unsafe impl GlobalAlloc for Locked<BumpAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        assert_alloc_allowed();
         let mut bump = self.lock(); // get a mutable reference
 
         let alloc_start = align_up(bump.next, layout.align()); // align bump.next
         let alloc_end = match alloc_start.checked_add(layout.size()) { // prevent integer overflow on large allocations
             Some(end) => end,
-            None => return ptr::null_mut(),
+            None => return record_alloc(layout.size(), ptr::null_mut()),
         };
 
-        if alloc_end > bump.heap_end {
+        let ptr = if alloc_end > bump.heap_end {
             ptr::null_mut() // out of memory
         } else {
             bump.next = alloc_end;
             bump.allocations += 1;
             alloc_start as *mut u8
-        }
+        };
+        record_alloc(layout.size(), ptr)
     }
 
-    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) { // free entire heap memory --> set bump.next equal to start of heap
+    unsafe fn dealloc(&self, _ptr: *mut u8, layout: Layout) { // free entire heap memory --> set bump.next equal to start of heap
+        record_dealloc(layout.size());
         let mut bump = self.lock(); // get a mutable reference
 
         bump.allocations -= 1;
@@ -1,19 +1,23 @@
 use alloc::alloc::{ Layout, GlobalAlloc };
-use super::Locked;
+use super::{assert_alloc_allowed, record_alloc, record_dealloc, record_realloc, Locked};
 use core::{mem, ptr::{NonNull, self}};
 
 /// The block sizes to use.
 ///
 /// The sizes must each be power of 2 because they are also used as
 /// the block alignment (alignments must be always powers of 2).
-const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+/// They are the consecutive powers of 2 from 8 on, see `size_class_for()`.
+pub const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// Number of block sizes (one free list each).
+pub const NUM_SIZE_CLASSES: usize = BLOCK_SIZES.len();
 
 struct ListNode {
     next: Option<&'static mut ListNode>,
 }
 
 pub struct FixedSizeBlockAllocator {
-    list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
+    list_heads: [Option<&'static mut ListNode>; NUM_SIZE_CLASSES],
     fallback_allocator: linked_list_allocator::Heap,
 }
 
@@ -22,7 +26,7 @@ impl FixedSizeBlockAllocator {
     pub const fn new() -> Self {
         const EMPTY: Option<&'static mut ListNode> = None;
         FixedSizeBlockAllocator {
-            list_heads: [EMPTY; BLOCK_SIZES.len()],
+            list_heads: [EMPTY; NUM_SIZE_CLASSES],
             fallback_allocator: linked_list_allocator::Heap::empty(),
         }
     }
@@ -36,27 +40,88 @@ impl FixedSizeBlockAllocator {
         self.fallback_allocator.init(heap_start, heap_size);
     }
 
+    /// The number of free blocks of each size in `BLOCK_SIZES`.
+    ///
+    /// Walks every free list --> for debugging, not for hot paths.
+    pub fn free_list_lengths(&self) -> [usize; NUM_SIZE_CLASSES] {
+        let mut lengths = [0; NUM_SIZE_CLASSES];
+        for (length, head) in lengths.iter_mut().zip(&self.list_heads) {
+            let mut current = head.as_deref();
+            while let Some(node) = current {
+                *length += 1;
+                current = node.next.as_deref();
+            }
+        }
+        lengths
+    }
+
+    /// Free bytes of the fallback allocator (heap that was never cut into blocks, or freed in pieces too big for them).
+    pub fn fallback_free(&self) -> usize {
+        self.fallback_allocator.free()
+    }
+
+    // put the block at `ptr` on free list `index`
+    unsafe fn push_block(&mut self, index: usize, ptr: *mut u8) {
+        let new_node = ListNode {
+            next: self.list_heads[index].take(),
+        };
+        // verify that block has size and alignment required for storing node
+        assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[index]);
+        assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[index]);
+        let new_node_ptr = ptr as *mut ListNode;
+        new_node_ptr.write(new_node);
+        self.list_heads[index] = Some(&mut *new_node_ptr);
+    }
+
     /// Allocates using the fallback allocator.
     fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
         match self.fallback_allocator.allocate_first_fit(layout) {
             Ok(ptr) => ptr.as_ptr(),
+            #[cfg(feature = "heap_growth")]
+            Err(_) if self.grow(layout) => self.fallback_alloc(layout),
             Err(_) => ptr::null_mut(),
         }
     }
+
+    // map more heap for `layout` after the end of the fallback allocator's memory, only if that memory is the kernel
+    // heap (see Heap growth in allocator.rs), returns whether it grew
+    #[cfg(feature = "heap_growth")]
+    fn grow(&mut self, layout: Layout) -> bool {
+        let heap_end = super::HEAP_START + super::stats().heap_size as usize;
+        if self.fallback_allocator.top() != heap_end {
+            return false; // another allocator (ex. in a test), its memory can't grow
+        }
+        match super::grow_heap(layout.size() + layout.align()) {
+            0 => false,
+            grown => {
+                unsafe { self.fallback_allocator.extend(grown) };
+                true
+            }
+        }
+    }
+}
+
+/// The index into `BLOCK_SIZES` of the smallest block that holds `size` bytes, `None` if none of them does.
+///
+/// No search: the sizes are consecutive powers of 2, so the class is the exponent of the next one.
+pub fn size_class_for(size: usize) -> Option<usize> {
+    let block_size = size.max(BLOCK_SIZES[0]).checked_next_power_of_two()?;
+    let index = (block_size.trailing_zeros() - BLOCK_SIZES[0].trailing_zeros()) as usize;
+    (index < NUM_SIZE_CLASSES).then_some(index)
 }
 
 /// Choose an appropriate block size for the given layout.
 ///
 /// Returns an index into the `BLOCK_SIZES` array.
 fn list_index(layout: &Layout) -> Option<usize> {
-    let required_block_size = layout.size().max(layout.align());
-    BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
+    size_class_for(layout.size().max(layout.align()))
 }
 
 unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        assert_alloc_allowed();
         let mut allocator = self.lock();
-        match list_index(&layout) {
+        let ptr = match list_index(&layout) {
             Some(index) => {
                 match allocator.list_heads[index].take() {
                     Some(node) => {
@@ -75,27 +140,168 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
                 }
             }
             None => allocator.fallback_alloc(layout),
-        }
+        };
+        record_alloc(layout.size(), ptr)
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        record_dealloc(layout.size());
         let mut allocator = self.lock();
         match list_index(&layout) {
-            Some(index) => {
-                let new_node = ListNode {
-                    next: allocator.list_heads[index].take(),
-                };
-                // verify that block has size and alignment required for storing node
-                assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[index]);
-                assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[index]);
-                let new_node_ptr = ptr as *mut ListNode;
-                new_node_ptr.write(new_node);
-                allocator.list_heads[index] = Some(&mut *new_node_ptr);
-            }
+            Some(index) => allocator.push_block(index, ptr),
             None => {
                 let ptr = NonNull::new(ptr).unwrap();
                 allocator.fallback_allocator.deallocate(ptr, layout);
             }
         }
     }
-}
\ No newline at end of file
+
+    // a block that stays in its size class (or goes to a smaller one) isn't moved, only one that needs a bigger block is
+    // - shrinking to a smaller class splits the rest of the block off and puts it on the free lists: the block at ptr
+    //   keeps the new size, then come the sizes in between (each aligned to itself as the block is aligned to its size)
+    //   --> dealloc() with the new size later frees only what is left
+    // - from or to the fallback allocator it is alloc + copy + dealloc, like the default realloc()
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        assert_alloc_allowed();
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        if let (Some(old_index), Some(new_index)) = (list_index(&layout), list_index(&new_layout)) {
+            if new_index <= old_index {
+                let mut allocator = self.lock();
+                for index in new_index..old_index {
+                    allocator.push_block(index, ptr.add(BLOCK_SIZES[index]));
+                }
+                record_realloc(layout.size(), new_size);
+                return ptr;
+            }
+        }
+        let new_ptr = self.alloc(new_layout);
+        if !new_ptr.is_null() {
+            ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
+    }
+}
+
+// TESTS ===================================
+
+// memory for an allocator of a test, taken from the kernel heap and given back when dropped
+// (test_free_list_lengths alone needs 8 * (1 * 1 + 2 * 2 + ... + 9 * 256) = 32776 bytes of blocks)
+#[cfg(test)]
+struct TestArena(*mut u8);
+
+#[cfg(test)]
+impl TestArena {
+    const LAYOUT: Layout = unsafe { Layout::from_size_align_unchecked(64 * 1024, 4096) };
+
+    fn new() -> Self {
+        let start = unsafe { alloc::alloc::alloc_zeroed(Self::LAYOUT) };
+        assert!(!start.is_null(), "no room on the heap for the test arena");
+        TestArena(start)
+    }
+
+    // an allocator managing the whole arena, must be dropped before the arena
+    fn allocator(&self) -> Locked<FixedSizeBlockAllocator> {
+        let allocator = Locked::new(FixedSizeBlockAllocator::new());
+        unsafe { allocator.lock().init(self.0 as usize, Self::LAYOUT.size()) };
+        allocator
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestArena {
+    fn drop(&mut self) {
+        unsafe { alloc::alloc::dealloc(self.0, Self::LAYOUT) };
+    }
+}
+
+#[test_case]
+fn test_free_list_lengths() {
+    let arena = TestArena::new();
+    let allocator = arena.allocator();
+    assert_eq!(allocator.lock().free_list_lengths(), [0; NUM_SIZE_CLASSES]);
+
+    // i + 1 blocks of every size, all freed --> i + 1 blocks on list i
+    let mut blocks = alloc::vec::Vec::new();
+    for (i, &size) in BLOCK_SIZES.iter().enumerate() {
+        let layout = Layout::from_size_align(size, 1).unwrap();
+        for _ in 0..=i {
+            let block = unsafe { allocator.alloc(layout) };
+            assert!(!block.is_null(), "arena too small");
+            blocks.push((block, layout));
+        }
+    }
+    for &(block, layout) in &blocks {
+        unsafe { allocator.dealloc(block, layout) };
+    }
+    let expected: [usize; NUM_SIZE_CLASSES] = core::array::from_fn(|i| i + 1);
+    assert_eq!(allocator.lock().free_list_lengths(), expected);
+
+    // blocks are reused from the lists, not taken from the fallback allocator
+    let block = unsafe { allocator.alloc(Layout::from_size_align(64, 64).unwrap()) };
+    assert_eq!(allocator.lock().free_list_lengths()[3], expected[3] - 1);
+    unsafe { allocator.dealloc(block, Layout::from_size_align(64, 64).unwrap()) };
+}
+
+#[test_case]
+fn test_list_index() {
+    let index = |size, align| list_index(&Layout::from_size_align(size, align).unwrap());
+    assert_eq!(index(1, 1), Some(0));
+    assert_eq!(index(8, 8), Some(0));
+    assert_eq!(index(9, 1), Some(1));
+    assert_eq!(index(24, 8), Some(2));
+    // the alignment counts like the size: a block is aligned to its size
+    assert_eq!(index(1, 64), Some(3));
+    assert_eq!(index(2048, 1), Some(NUM_SIZE_CLASSES - 1));
+    // too big for any class --> the fallback allocator
+    assert_eq!(index(2049, 1), None);
+    assert_eq!(index(8, 4096), None);
+    for (i, &size) in BLOCK_SIZES.iter().enumerate() {
+        assert_eq!(index(size, 1), Some(i));
+        assert_eq!(index(size + 1, 1), BLOCK_SIZES.get(i + 1).map(|_| i + 1));
+    }
+}
+
+#[test_case]
+fn test_size_class_for() {
+    for size in 0..=4096 {
+        assert_eq!(size_class_for(size), BLOCK_SIZES.iter().position(|&block| block >= size), "size {}", size);
+    }
+    assert_eq!(size_class_for(usize::MAX), None);
+}
+
+#[test_case]
+fn test_realloc_in_place_and_across_classes() {
+    let arena = TestArena::new();
+    let allocator = arena.allocator();
+
+    unsafe {
+        // 24 --> 32 bytes: the same 32 byte block
+        let layout = Layout::from_size_align(24, 8).unwrap();
+        let block = allocator.alloc(layout);
+        for i in 0..24 {
+            block.add(i).write(i as u8);
+        }
+        let grown = allocator.realloc(block, layout, 32);
+        assert_eq!(grown, block, "moved within its size class");
+
+        // 32 --> 100 bytes: a 128 byte block, the data comes along
+        let layout = Layout::from_size_align(32, 8).unwrap();
+        let moved = allocator.realloc(grown, layout, 100);
+        assert_ne!(moved, grown);
+        assert!((0..24).all(|i| moved.add(i).read() == i as u8), "data lost on the way");
+        assert_eq!(allocator.lock().free_list_lengths()[2], 1, "the old block wasn't freed");
+
+        // 100 --> 8 bytes: stays, the other 120 bytes go to the lists as 8 + 16 + 32 + 64
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        let before = allocator.lock().free_list_lengths();
+        let shrunk = allocator.realloc(moved, layout, 8);
+        assert_eq!(shrunk, moved);
+        let after = allocator.lock().free_list_lengths();
+        let gained: [usize; NUM_SIZE_CLASSES] = core::array::from_fn(|i| after[i] - before[i]);
+        assert_eq!(gained[..4], [1, 1, 1, 1]);
+        assert!(gained[4..].iter().all(|&count| count == 0));
+        assert_eq!(shrunk.read(), 0, "data lost in place");
+        allocator.dealloc(shrunk, Layout::from_size_align(8, 8).unwrap());
+    }
+}
@@ -0,0 +1,280 @@
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::{mem, ptr};
+use super::{align_up, assert_alloc_allowed, record_alloc, record_dealloc, Locked};
+
+// A free list allocator: the free regions of the heap are chained together through headers stored inside the regions
+// themselves --> no memory is needed besides the heap (there is no other allocator to get it from anyway)
+// - allocating walks the list for the first region that fits (first fit), the part of a region that isn't used is put
+//   back into the list
+// - freed blocks are pushed to the front of the list as they are, coalesce() sorts the list by address and merges
+//   blocks that touch --> otherwise the heap fragments into small pieces over time
+
+/// The header at the start of every free block.
+pub struct ListNode {
+    size: usize,
+    next: *mut ListNode,
+}
+
+// every block has to be able to hold the header once it is freed --> no allocation is smaller than this
+const _: () = assert!(mem::size_of::<ListNode>() == 2 * mem::size_of::<usize>());
+const _: () = assert!(mem::align_of::<ListNode>() == mem::align_of::<usize>());
+
+impl ListNode {
+    const fn new(size: usize) -> Self {
+        ListNode { size, next: ptr::null_mut() }
+    }
+
+    /// Write a header for the free block of `size` bytes at `ptr` into the block itself.
+    ///
+    /// `None` if the block is too small for a header or misaligned for one. Unsafe because the block must be unused
+    /// memory the caller owns.
+    pub unsafe fn try_store_in(ptr: *mut u8, size: usize) -> Option<*mut ListNode> {
+        if size < mem::size_of::<ListNode>() || ptr as usize % mem::align_of::<ListNode>() != 0 {
+            return None;
+        }
+        let node = ptr as *mut ListNode;
+        node.write(ListNode::new(size));
+        Some(node)
+    }
+
+    fn start_addr(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    fn end_addr(&self) -> usize {
+        self.start_addr() + self.size
+    }
+}
+
+pub struct LinkedListAllocator {
+    head: ListNode, // a dummy node, the first free block is head.next
+}
+
+// the allocator is only ever used through a lock (see Locked)
+unsafe impl Send for LinkedListAllocator {}
+
+impl LinkedListAllocator {
+    /// Creates an empty LinkedListAllocator.
+    pub const fn new() -> Self {
+        Self { head: ListNode::new(0) }
+    }
+
+    /// Initialize the allocator with the given heap bounds.
+    ///
+    /// This function is unsafe because the caller must guarantee that the given
+    /// heap bounds are valid and that the heap is unused. This method must be
+    /// called only once.
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.add_free_region(heap_start, heap_size);
+    }
+
+    /// Adds the given memory region to the front of the list (too small parts at its start and end are lost).
+    unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
+        let start = align_up(addr, mem::align_of::<ListNode>());
+        let size = size.saturating_sub(start - addr);
+        if let Some(node) = ListNode::try_store_in(start as *mut u8, size) {
+            (*node).next = self.head.next;
+            self.head.next = node;
+        }
+    }
+
+    /// Looks for a free region that fits `size` bytes aligned to `align` and removes it from the list.
+    ///
+    /// Returns the region and the start address of the allocation in it.
+    fn find_region(&mut self, size: usize, align: usize) -> Option<(*mut ListNode, usize)> {
+        let mut current: *mut ListNode = &mut self.head;
+        unsafe {
+            while !(*current).next.is_null() {
+                let region = (*current).next;
+                if let Ok(alloc_start) = Self::alloc_from_region(&*region, size, align) {
+                    (*current).next = (*region).next;
+                    return Some((region, alloc_start));
+                }
+                current = region;
+            }
+        }
+        None
+    }
+
+    /// Try to use the given region for an allocation with given size and alignment, returns the start address.
+    fn alloc_from_region(region: &ListNode, size: usize, align: usize) -> Result<usize, ()> {
+        let alloc_start = align_up(region.start_addr(), align);
+        let alloc_end = alloc_start.checked_add(size).ok_or(())?;
+        if alloc_end > region.end_addr() {
+            return Err(()); // region too small
+        }
+        let excess_size = region.end_addr() - alloc_end;
+        if excess_size > 0 && excess_size < mem::size_of::<ListNode>() {
+            // the rest of the region can't hold a header --> it would be lost
+            return Err(());
+        }
+        Ok(alloc_start)
+    }
+
+    /// Adjust the given layout so that the resulting allocated memory region is also capable of storing a `ListNode`.
+    fn size_align(layout: Layout) -> (usize, usize) {
+        let layout = layout
+            .align_to(mem::align_of::<ListNode>())
+            .expect("adjusting alignment failed")
+            .pad_to_align();
+        let size = layout.size().max(mem::size_of::<ListNode>());
+        (size, layout.align())
+    }
+
+    /// First fit allocation, a null pointer if no free region fits.
+    pub fn allocate_first_fit(&mut self, layout: Layout) -> *mut u8 {
+        let (size, align) = Self::size_align(layout);
+        match self.find_region(size, align) {
+            Some((region, alloc_start)) => {
+                let (region_start, region_end) = unsafe { ((*region).start_addr(), (*region).end_addr()) };
+                let alloc_end = alloc_start + size;
+                unsafe {
+                    // the parts before (alignment) and after the allocation go back into the list
+                    self.add_free_region(alloc_end, region_end - alloc_end);
+                    self.add_free_region(region_start, alloc_start - region_start);
+                }
+                alloc_start as *mut u8
+            }
+            None => ptr::null_mut(),
+        }
+    }
+
+    /// Give back a block from `allocate_first_fit()`.
+    ///
+    /// Unsafe because `ptr` must have come from this allocator with the same `layout`.
+    pub unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        let (size, _) = Self::size_align(layout);
+        self.add_free_region(ptr as usize, size);
+    }
+
+    /// Sort the free list by address and merge blocks that are next to each other in memory.
+    ///
+    /// Doesn't allocate: the list is sorted in place by moving nodes (insertion sort).
+    pub fn coalesce(&mut self) {
+        unsafe {
+            // insertion sort: take the nodes off the list one by one and insert each in order into `sorted`
+            let mut sorted: *mut ListNode = ptr::null_mut();
+            let mut unsorted = self.head.next;
+            while !unsorted.is_null() {
+                let node = unsorted;
+                unsorted = (*node).next;
+                let mut link: *mut *mut ListNode = &mut sorted;
+                while !(*link).is_null() && (**link).start_addr() < (*node).start_addr() {
+                    link = &mut (**link).next;
+                }
+                (*node).next = *link;
+                *link = node;
+            }
+            self.head.next = sorted;
+
+            // merge neighbors, the header of the second block becomes part of the first one
+            let mut current = self.head.next;
+            while !current.is_null() {
+                let next = (*current).next;
+                if !next.is_null() && (*current).end_addr() == (*next).start_addr() {
+                    (*current).size += (*next).size;
+                    (*current).next = (*next).next;
+                } else {
+                    current = next;
+                }
+            }
+        }
+    }
+
+    /// The free blocks as (address, size), in list order.
+    pub fn free_blocks(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        let mut current = self.head.next as *const ListNode;
+        core::iter::from_fn(move || {
+            let node = unsafe { current.as_ref()? };
+            current = node.next;
+            Some((node.start_addr(), node.size))
+        })
+    }
+}
+
+unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        assert_alloc_allowed();
+        let ptr = self.lock().allocate_first_fit(layout);
+        record_alloc(layout.size(), ptr)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        record_dealloc(layout.size());
+        self.lock().deallocate(ptr, layout);
+    }
+}
+
+// TESTS ===================================
+
+#[test_case]
+fn test_store_node_in_block() {
+    let mut block = [0usize; 4];
+    let ptr = block.as_mut_ptr() as *mut u8;
+    unsafe {
+        assert!(ListNode::try_store_in(ptr, mem::size_of::<ListNode>() - 1).is_none());
+        assert!(ListNode::try_store_in(ptr.add(1), 16).is_none(), "misaligned header accepted");
+        let node = ListNode::try_store_in(ptr, 32).expect("header didn't fit");
+        assert_eq!((*node).size, 32);
+        assert!((*node).next.is_null());
+    }
+}
+
+#[test_case]
+fn test_coalesce_adjacent_blocks() {
+    #[repr(align(16))]
+    struct Arena([u8; 96]);
+    let mut arena = Arena([0; 96]);
+    let start = arena.0.as_mut_ptr() as usize;
+
+    let mut allocator = LinkedListAllocator::new();
+    unsafe { allocator.init(start, 96) };
+    let layout = Layout::from_size_align(32, 8).unwrap();
+    let blocks = [(); 3].map(|_| allocator.allocate_first_fit(layout));
+    assert_eq!(blocks.map(|block| block as usize), [start, start + 32, start + 64], "blocks not adjacent");
+    assert!(allocator.allocate_first_fit(layout).is_null());
+    assert_eq!(allocator.free_blocks().count(), 0);
+
+    unsafe {
+        allocator.deallocate(blocks[1], layout);
+        allocator.deallocate(blocks[2], layout);
+        allocator.deallocate(blocks[0], layout);
+    }
+    assert_eq!(allocator.free_blocks().count(), 3);
+    allocator.coalesce();
+    assert!(allocator.free_blocks().eq([(start, 96)]));
+
+    // the merged block can be handed out as a whole
+    let big = Layout::from_size_align(96, 8).unwrap();
+    assert_eq!(allocator.allocate_first_fit(big) as usize, start);
+}
+
+#[test_case]
+fn test_coalesce_leaves_gaps() {
+    #[repr(align(16))]
+    struct Arena([u8; 128]);
+    let mut arena = Arena([0; 128]);
+    let start = arena.0.as_mut_ptr() as usize;
+
+    let mut allocator = LinkedListAllocator::new();
+    unsafe { allocator.init(start, 128) };
+    let layout = Layout::from_size_align(32, 8).unwrap();
+    let blocks = [(); 4].map(|_| allocator.allocate_first_fit(layout));
+    assert!(blocks.iter().all(|block| !block.is_null()), "arena too small");
+
+    // the block still in use keeps the first one apart from the last two
+    unsafe {
+        allocator.deallocate(blocks[3], layout);
+        allocator.deallocate(blocks[0], layout);
+        allocator.deallocate(blocks[2], layout);
+    }
+    allocator.coalesce();
+    assert!(allocator.free_blocks().eq([(start, 32), (start + 64, 64)]));
+    // merging twice changes nothing
+    allocator.coalesce();
+    assert!(allocator.free_blocks().eq([(start, 32), (start + 64, 64)]));
+
+    unsafe { allocator.deallocate(blocks[1], layout) };
+    allocator.coalesce();
+    assert!(allocator.free_blocks().eq([(start, 128)]));
+}
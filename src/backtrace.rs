@@ -0,0 +1,272 @@
+// Stack backtraces --> every function keeps its caller's frame pointer (the target forces frame pointers, see
+// x86_64-mini_os.json), so the frames form a chain: [rbp] is the caller's rbp, [rbp + 8] the return address into it
+// - the return addresses are printed with the function they are in (see SYMBOLS) when the kernel was built with its
+//   symbol table, otherwise raw --> look them up on the host, ex. `addr2line -e target/x86_64-mini_os/debug/mini_os 0x2041a3`
+// - it runs in panic handlers, where a fault would end in a double fault --> a frame pointer is only followed if it is
+//   aligned, mapped, above the previous one and within MAX_STACK_SPAN of where the walk started (a frame chain only
+//   goes up the stack), at most MAX_FRAMES of them
+// - no heap and no locks besides the console's, a panic may come from the allocator
+#[cfg(test)]
+mod demangle;
+
+use crate::{println, serial_println};
+use core::arch::asm;
+use x86_64::VirtAddr;
+
+/// Frames a backtrace follows at most.
+pub const MAX_FRAMES: usize = 32;
+/// How far above its start a frame may be, the biggest kernel stack (the bootloader's) is smaller.
+pub const MAX_STACK_SPAN: u64 = 1024 * 1024;
+
+/// The return addresses of the calls that led here (the innermost first), stored in `frames`, returns how many there are.
+#[inline(never)] // its own frame has the return address into the caller, the first one
+pub fn collect(frames: &mut [u64; MAX_FRAMES]) -> usize {
+    let (rbp, rsp): (u64, u64);
+    unsafe { asm!("mov {}, rbp", "mov {}, rsp", out(reg) rbp, out(reg) rsp, options(nomem, nostack, preserves_flags)) };
+    collect_from(rbp, rsp, frames)
+}
+
+/// Like `collect()`, for the frame chain starting at frame pointer `rbp` on a stack that is used from `stack_low` up.
+pub fn collect_from(mut rbp: u64, stack_low: u64, frames: &mut [u64; MAX_FRAMES]) -> usize {
+    let mut count = 0;
+    while count < MAX_FRAMES && plausible_frame(rbp, stack_low) {
+        // checked by plausible_frame(): aligned and both words mapped
+        let (caller_rbp, return_address) = unsafe { (*(rbp as *const u64), *((rbp + 8) as *const u64)) };
+        if return_address == 0 {
+            break; // the outermost frame (ex. _start), nothing called it
+        }
+        frames[count] = return_address;
+        count += 1;
+        if caller_rbp <= rbp {
+            break; // the chain has to go up the stack, anything else isn't a frame pointer
+        }
+        rbp = caller_rbp;
+    }
+    count
+}
+
+// whether the two words at `rbp` can be read as a frame
+fn plausible_frame(rbp: u64, stack_low: u64) -> bool {
+    rbp % 8 == 0
+        && rbp >= stack_low
+        && rbp.saturating_add(16) <= stack_low.saturating_add(MAX_STACK_SPAN)
+        && VirtAddr::try_new(rbp).map_or(false, crate::memory::is_mapped)
+        && VirtAddr::try_new(rbp + 8).map_or(false, crate::memory::is_mapped)
+}
+
+/// Print the backtrace of the caller to the console and the serial port, after a double fault the one of the code that
+/// double faulted as well.
+#[inline(never)]
+pub fn print() {
+    let mut frames = [0; MAX_FRAMES];
+    let count = collect(&mut frames);
+    print_frames(&frames[..count]);
+    if let Some((rbp, rsp)) = crate::interrupts::double_fault_frame() {
+        println!("Where the double fault happened:");
+        serial_println!("Where the double fault happened:");
+        print_from(rbp, rsp);
+    }
+}
+
+/// Print the backtrace of the frame chain at `rbp` (see `collect_from()`) to the console and the serial port.
+pub fn print_from(rbp: u64, stack_low: u64) {
+    let mut frames = [0; MAX_FRAMES];
+    let count = collect_from(rbp, stack_low, &mut frames);
+    print_frames(&frames[..count]);
+}
+
+fn print_frames(frames: &[u64]) {
+    if !crate::memory::can_check_mappings() {
+        println!("Backtrace: unavailable (the page tables can't be checked before memory::init_frame_allocator())");
+        serial_println!("Backtrace: unavailable (the page tables can't be checked before memory::init_frame_allocator())");
+        return;
+    }
+    println!("Backtrace:");
+    serial_println!("Backtrace:");
+    for (number, &address) in frames.iter().enumerate() {
+        // a return address is the instruction after the call, past the end of the caller if the call was its last
+        // instruction (ex. to a function that never returns) --> the call itself is looked up, the offset printed is
+        // the return address's
+        match address.checked_sub(1).and_then(resolve) {
+            Some((name, offset)) => {
+                println!("  #{:<2} {:#x}  {}+{:#x}", number, address, name, offset + 1);
+                serial_println!("  #{:<2} {:#x}  {}+{:#x}", number, address, name, offset + 1);
+            }
+            None => {
+                println!("  #{:<2} {:#x}", number, address);
+                serial_println!("  #{:<2} {:#x}", number, address);
+            }
+        }
+    }
+}
+
+// SYMBOLS ====================================
+// the `symbols` feature embeds the table of the kernel's functions that build.rs writes (see SYMBOL TABLE there for
+// the layout and the two builds it takes) --> without the feature, or without a table, nothing resolves
+// - a lookup is a binary search for the last function starting at or below the address, the address has to be inside
+//   of it: between two functions (padding, or code without a symbol) is None, not the closest name
+// - the table has the address mini_os_symbol_anchor() had in the build it came from --> if that isn't where the
+//   function is now, the table belongs to another build and nothing resolves
+
+#[cfg(feature = "symbols")]
+static SYMBOL_TABLE: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/symbols.bin"));
+#[cfg(not(feature = "symbols"))]
+static SYMBOL_TABLE: &[u8] = &[];
+
+const SYMBOL_HEADER_SIZE: usize = 16;
+const SYMBOL_ENTRY_SIZE: usize = 24;
+
+// the function build.rs looks for by name (ANCHOR_SYMBOL there), never called
+#[no_mangle]
+#[inline(never)]
+extern "C" fn mini_os_symbol_anchor() -> u64 {
+    core::hint::black_box(0)
+}
+
+/// The function `addr` is in and how far into it, `None` without a symbol table (of this build) or if no function
+/// contains it.
+pub fn resolve(addr: u64) -> Option<(&'static str, usize)> {
+    resolve_in(SYMBOL_TABLE, mini_os_symbol_anchor as usize as u64, addr)
+}
+
+// resolve() in the table `table` of the build where mini_os_symbol_anchor() is at `anchor`, None if it is malformed
+fn resolve_in(table: &[u8], anchor: u64, addr: u64) -> Option<(&str, usize)> {
+    let read_u32 = |offset: usize| Some(u32::from_le_bytes(table.get(offset..offset + 4)?.try_into().ok()?));
+    let read_u64 = |offset: usize| Some(u64::from_le_bytes(table.get(offset..offset + 8)?.try_into().ok()?));
+    if table.get(..4)? != b"MSYM" || read_u64(8)? != anchor {
+        return None;
+    }
+    let count = read_u32(4)? as usize;
+    let entry = |index: usize| SYMBOL_HEADER_SIZE + index * SYMBOL_ENTRY_SIZE;
+    let names = entry(count);
+
+    // the number of functions starting at or below addr
+    let (mut low, mut high) = (0, count);
+    while low < high {
+        let middle = (low + high) / 2;
+        if read_u64(entry(middle))? <= addr {
+            low = middle + 1;
+        } else {
+            high = middle;
+        }
+    }
+    let symbol = entry(low.checked_sub(1)?);
+    let (start, size) = (read_u64(symbol)?, read_u64(symbol + 8)?);
+    if addr - start >= size {
+        return None;
+    }
+    let name_start = names + read_u32(symbol + 16)? as usize;
+    let name = table.get(name_start..name_start + read_u32(symbol + 20)? as usize)?;
+    Some((core::str::from_utf8(name).ok()?, (addr - start) as usize))
+}
+
+// TESTS ===================================
+
+#[cfg(test)]
+#[inline(never)]
+fn three_deep(frames: &mut [u64; MAX_FRAMES]) -> usize {
+    two_deep(frames) + core::hint::black_box(0) // not a tail call, its frame stays
+}
+
+#[cfg(test)]
+#[inline(never)]
+fn two_deep(frames: &mut [u64; MAX_FRAMES]) -> usize {
+    one_deep(frames) + core::hint::black_box(0)
+}
+
+#[cfg(test)]
+#[inline(never)]
+fn one_deep(frames: &mut [u64; MAX_FRAMES]) -> usize {
+    collect(frames) + core::hint::black_box(0)
+}
+
+#[test_case]
+fn test_collect_three_calls_deep() {
+    let mut frames = [0; MAX_FRAMES];
+    let count = three_deep(&mut frames);
+    // one_deep, two_deep, three_deep, this test and the test runner's frames above it
+    assert!(count >= 4, "only {} frames: {:x?}", count, &frames[..count]);
+    let frames = &frames[..count];
+    assert!(frames[0] != frames[1] && frames[1] != frames[2] && frames[0] != frames[2], "{:x?}", frames);
+    // the return address into three_deep() is in it (the function's code comes after its address)
+    assert!(frames[1] > two_deep as usize as u64 && frames[2] > three_deep as usize as u64);
+
+    assert_eq!(collect_from(0, 0, &mut [0; MAX_FRAMES]), 0, "followed a null frame pointer");
+    assert_eq!(collect_from(0x1000, 0x2000, &mut [0; MAX_FRAMES]), 0, "followed a frame below the stack");
+}
+
+// a table in build.rs's layout with `functions` (address, size, name), sorted by address, from the build where
+// mini_os_symbol_anchor() is at `anchor`
+#[cfg(test)]
+fn symbol_table(anchor: u64, functions: &[(u64, u64, &str)]) -> alloc::vec::Vec<u8> {
+    let mut table = alloc::vec::Vec::from(*b"MSYM");
+    table.extend_from_slice(&(functions.len() as u32).to_le_bytes());
+    table.extend_from_slice(&anchor.to_le_bytes());
+    let mut names = alloc::vec::Vec::new();
+    for &(address, size, name) in functions {
+        table.extend_from_slice(&address.to_le_bytes());
+        table.extend_from_slice(&size.to_le_bytes());
+        table.extend_from_slice(&(names.len() as u32).to_le_bytes());
+        table.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        names.extend_from_slice(name.as_bytes());
+    }
+    table.extend_from_slice(&names);
+    table.resize(table.len() + 64, 0); // the padding
+    table
+}
+
+#[test_case]
+fn test_resolve() {
+    let anchor = mini_os_symbol_anchor as usize as u64;
+    let functions = [(0x1000, 0x10, "first"), (anchor, 0x20, "mini_os_symbol_anchor"), (anchor + 0x40, 8, "after")];
+    let table = symbol_table(anchor, &functions);
+    assert_eq!(resolve_in(&table, anchor, anchor), Some(("mini_os_symbol_anchor", 0)));
+    assert_eq!(resolve_in(&table, anchor, anchor + 0x12), Some(("mini_os_symbol_anchor", 0x12)));
+    assert_eq!(resolve_in(&table, anchor, 0x100f), Some(("first", 0xf)));
+    assert_eq!(resolve_in(&table, anchor, anchor + 0x20), None, "between two functions");
+    assert_eq!(resolve_in(&table, anchor, 0xfff), None, "below the first function");
+    assert_eq!(resolve_in(&table, anchor, anchor + 0x48), None, "past the last function");
+    assert_eq!(resolve_in(&[0; 16], anchor, anchor), None, "no table");
+    assert_eq!(resolve_in(&symbol_table(anchor, &[]), anchor, anchor), None);
+    // the table of a build where the anchor was somewhere else names the wrong functions --> nothing
+    assert_eq!(resolve_in(&symbol_table(anchor + 0x10, &functions), anchor, anchor), None);
+}
+
+#[test_case]
+fn test_resolve_with_the_kernel_table() {
+    let anchor = mini_os_symbol_anchor as usize as u64;
+    let table_anchor = SYMBOL_TABLE.get(8..16).map(|bytes| u64::from_le_bytes(bytes.try_into().unwrap()));
+    if table_anchor != Some(anchor) {
+        // no table, or the one of another build (ex. of the kernel instead of this test kernel, see build.rs)
+        assert_eq!(resolve(anchor), None, "resolved with a table of another build");
+        assert_eq!(resolve(three_deep as usize as u64), None);
+        return;
+    }
+    assert_eq!(resolve(anchor + 1), Some(("mini_os_symbol_anchor", 1)));
+    // a return address from a real backtrace, looked up like print_frames() does
+    let mut frames = [0; MAX_FRAMES];
+    three_deep(&mut frames);
+    let (name, _) = resolve(frames[1] - 1).expect("the return address into two_deep() didn't resolve");
+    assert!(name.ends_with("backtrace::two_deep"), "got {}", name);
+}
+
+#[test_case]
+fn test_demangle() {
+    use demangle::demangle;
+
+    assert_eq!(demangle("_ZN7mini_os9backtrace5print17h0123456789abcdefE"), "mini_os::backtrace::print");
+    assert_eq!(demangle("_ZN4core3ptr13drop_in_place17h0123456789abcdefE"), "core::ptr::drop_in_place");
+    // a hash has to be `h` and 16 hex digits, anything else is a part of the path
+    assert_eq!(demangle("_ZN3foo3barE"), "foo::bar");
+    assert_eq!(demangle("_ZN3foo17hxyz0000000000000E"), "foo::hxyz0000000000000");
+    // escapes, the one at the start of an identifier behind a `_`
+    assert_eq!(
+        demangle("_ZN51_$LT$mini_os..Thing$u20$as$u20$core..fmt..Debug$GT$3fmt17h0123456789abcdefE"),
+        "<mini_os::Thing as core::fmt::Debug>::fmt"
+    );
+    assert_eq!(demangle("_ZN5alloc3vec12Vec$LT$T$GT$4push17h0123456789abcdefE"), "alloc::vec::Vec<T>::push");
+    // not the legacy mangling, or broken --> as it is
+    for name in ["mini_os_symbol_anchor", "_RNvC7mini_os4main", "_ZN3fooE3", "_ZN9fooE", "_ZN"] {
+        assert_eq!(demangle(name), name);
+    }
+}
@@ -0,0 +1,37 @@
+// Demangling of Rust symbol names --> shared by build.rs (which writes the names into the symbol table, see SYMBOL TABLE
+// there) and the kernel's tests (`#[path]` includes this file in build.rs, so it only uses what std and alloc share)
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+/// The legacy Rust mangling `_ZN` (<length><identifier>)* `E` as `a::b::c`, without the trailing hash (`h` and 16 hex
+/// digits) and with the common escapes replaced --> anything else (ex. the v0 mangling `_R...`) stays as it is.
+pub fn demangle(name: &str) -> String {
+    let Some(mut rest) = name.strip_prefix("_ZN").and_then(|rest| rest.strip_suffix('E')) else {
+        return name.to_string();
+    };
+    let mut parts = Vec::new();
+    while !rest.is_empty() {
+        let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+        let Some(len) = rest[..digits].parse::<usize>().ok().filter(|&len| digits + len <= rest.len()) else {
+            return name.to_string();
+        };
+        let part = &rest[digits..digits + len];
+        // an identifier can't start with `$`, an escape at the start gets a `_` in front of it
+        parts.push(part.strip_prefix('_').filter(|part| part.starts_with('$')).unwrap_or(part));
+        rest = &rest[digits + len..];
+    }
+    if let Some(hash) = parts.last() {
+        if hash.len() == 17 && hash.starts_with('h') && hash[1..].bytes().all(|b| b.is_ascii_hexdigit()) {
+            parts.pop();
+        }
+    }
+    let mut demangled = parts.join("::");
+    for (escape, character) in [
+        ("$LT$", "<"), ("$GT$", ">"), ("$LP$", "("), ("$RP$", ")"), ("$RF$", "&"), ("$BP$", "*"), ("$C$", ","),
+        ("$u20$", " "), ("$u27$", "'"), ("$u5b$", "["), ("$u5d$", "]"), ("$u7b$", "{"), ("$u7d$", "}"), ("$u7e$", "~"),
+        ("..", "::"),
+    ] {
+        demangled = demangled.replace(escape, character);
+    }
+    demangled
+}
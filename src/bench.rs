@@ -0,0 +1,102 @@
+// Micro-benchmarks --> run() times a closure with the TSC and prints one line per benchmark to serial:
+//   BENCH,<name>,<iterations>,<min ns>,<median ns>
+// a script on the host picks these lines out of the serial output (everything else is ignored) and graphs them over time
+// - the iterations are split into up to BENCH_SAMPLES batches, every batch is one sample (its cycles per iteration)
+//   --> min and median come from a fixed array on the stack, no heap needed for the math
+// - a batch runs with interrupts off, so a timer tick or a keypress in the middle doesn't end up in a sample, the
+//   interrupts are back on between batches (the timer ticks that were missed are not made up)
+// - the first WARMUP_SAMPLES batches aren't counted: caches, TLB and the branch predictor settle first
+// - the closure's result is passed through black_box() so the compiler can't drop the work
+// the benchmarks themselves are in tests/benchmarks.rs, `cargo test --test benchmarks --features benchmarks`
+use crate::serial_println;
+use crate::task::usage::rdtsc;
+use crate::time;
+use core::hint::black_box;
+use x86_64::instructions::interrupts;
+
+/// Samples a benchmark takes at most.
+pub const BENCH_SAMPLES: usize = 64;
+const WARMUP_SAMPLES: usize = 4;
+
+/// What `run()` measured, per iteration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BenchResult {
+    pub name: &'static str,
+    pub iterations: u64,
+    pub min_ns: u64,
+    pub median_ns: u64,
+}
+
+/// Run `f` `iterations` times (plus a warm-up), print its `BENCH` line to serial and return it.
+///
+/// Calibrates the TSC if nothing did yet. Panics if that fails, if `iterations` is 0 or if `name` has a comma in it
+/// (the line is CSV).
+pub fn run<R>(name: &'static str, iterations: u64, mut f: impl FnMut() -> R) -> BenchResult {
+    assert!(iterations > 0, "benchmark {} without iterations", name);
+    assert!(!name.contains(','), "benchmark name {:?} has a comma", name);
+    if time::tsc_hz().is_none() {
+        time::calibrate_tsc().expect("no TSC rate for the benchmarks");
+    }
+
+    let sample_count = iterations.min(BENCH_SAMPLES as u64) as usize;
+    let batch = iterations / sample_count as u64; // the remainder is dropped, iterations is what actually ran
+    let mut samples = [0u64; BENCH_SAMPLES];
+    for i in 0..WARMUP_SAMPLES + sample_count {
+        let cycles = interrupts::without_interrupts(|| {
+            let start = rdtsc();
+            for _ in 0..batch {
+                black_box(f());
+            }
+            rdtsc().wrapping_sub(start)
+        });
+        if i >= WARMUP_SAMPLES {
+            samples[i - WARMUP_SAMPLES] = cycles / batch;
+        }
+    }
+
+    let (min, median) = min_and_median(&mut samples[..sample_count]);
+    let result = BenchResult {
+        name,
+        iterations: batch * sample_count as u64,
+        min_ns: time::tsc_cycles_to_ns(min).unwrap_or(0),
+        median_ns: time::tsc_cycles_to_ns(median).unwrap_or(0),
+    };
+    serial_println!("BENCH,{},{},{},{}", result.name, result.iterations, result.min_ns, result.median_ns);
+    result
+}
+
+// sorts `samples` (no allocation), the median of an even count is the lower of the middle two
+fn min_and_median(samples: &mut [u64]) -> (u64, u64) {
+    samples.sort_unstable();
+    (samples[0], samples[(samples.len() - 1) / 2])
+}
+
+// TESTS ===================================
+
+#[test_case]
+fn test_min_and_median() {
+    assert_eq!(min_and_median(&mut [7]), (7, 7));
+    assert_eq!(min_and_median(&mut [9, 1, 5]), (1, 5));
+    assert_eq!(min_and_median(&mut [4, 8, 2, 6]), (2, 4));
+}
+
+#[test_case]
+fn test_bench_run() {
+    let enabled = interrupts::are_enabled();
+    let mut calls = 0u64;
+    let result = run("test_bench_run", 100, || calls += 1);
+    // 64 samples of 1 iteration each, after 4 warm-up samples
+    assert_eq!(result.iterations, 64);
+    assert_eq!(calls, 68);
+    assert!(result.min_ns <= result.median_ns);
+    assert_eq!(interrupts::are_enabled(), enabled, "interrupts not restored");
+
+    let result = run("test_bench_run_busy", 5, || {
+        let start = rdtsc();
+        while rdtsc().wrapping_sub(start) < 100_000 {
+            core::hint::spin_loop();
+        }
+    });
+    let ns = time::tsc_cycles_to_ns(100_000).unwrap();
+    assert!(result.min_ns >= ns, "{} ns for at least {} ns", result.min_ns, ns);
+}
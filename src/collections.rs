@@ -0,0 +1,6 @@
+// Collections tuned for the kernel, built straight on the kernel's allocator (see allocator.rs)
+pub mod hashmap;
+pub mod vec;
+
+pub use hashmap::KHashMap;
+pub use vec::KVec;
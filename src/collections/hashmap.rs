@@ -0,0 +1,260 @@
+// KHashMap --> a hash map with open addressing: every entry lives directly in one flat array of slots
+// - a key goes to the slot its hash points at, or the next free one after it (linear probing, wrapping around)
+// - removing leaves a tombstone so lookups keep probing past it, tombstones are reused by inserts and dropped on resize
+// - the table doubles once the used slots (entries + tombstones) would exceed LOAD_FACTOR_NUM / LOAD_FACTOR_DEN, the
+//   capacity is always a power of two so the slot is `hash & (capacity - 1)`
+// - hashing is FNV-1a through core::hash::Hasher --> any `Hash` type works as a key, but it isn't DoS resistant
+use alloc::vec::Vec;
+use core::hash::{Hash, Hasher};
+use core::mem;
+
+/// The capacity of the first allocation.
+pub const MIN_CAPACITY: usize = 8;
+
+// FNV-1a ====================================
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// The 64 bit FNV-1a hash, see http://www.isthe.com/chongo/tech/comp/fnv/
+#[derive(Debug, Clone, Copy)]
+pub struct FnvHasher {
+    hash: u64,
+}
+
+impl FnvHasher {
+    pub const fn new() -> Self {
+        FnvHasher { hash: FNV_OFFSET_BASIS }
+    }
+}
+
+impl Default for FnvHasher {
+    fn default() -> Self {
+        FnvHasher::new()
+    }
+}
+
+impl Hasher for FnvHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.hash ^= u64::from(byte);
+            self.hash = self.hash.wrapping_mul(FNV_PRIME);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.hash
+    }
+}
+
+fn hash_of<K: Hash + ?Sized>(key: &K) -> u64 {
+    let mut hasher = FnvHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+// MAP ====================================
+
+enum Slot<K, V> {
+    Empty,
+    Deleted,
+    Full(K, V),
+}
+
+/// A hash map with linear probing, see the top of this file.
+pub struct KHashMap<K: Eq + Hash, V, const LOAD_FACTOR_NUM: usize = 7, const LOAD_FACTOR_DEN: usize = 10> {
+    slots: Vec<Slot<K, V>>,
+    len: usize,
+    deleted: usize,
+}
+
+impl<K: Eq + Hash, V, const LOAD_FACTOR_NUM: usize, const LOAD_FACTOR_DEN: usize>
+    KHashMap<K, V, LOAD_FACTOR_NUM, LOAD_FACTOR_DEN>
+{
+    // a load factor of 1 or more would let the table fill up and find() probe forever --> checked at compile time (a
+    // free `const _` can't name the const parameters, new() evaluates this for every instantiation instead)
+    const LOAD_FACTOR_OK: () = assert!(0 < LOAD_FACTOR_NUM && LOAD_FACTOR_NUM < LOAD_FACTOR_DEN);
+
+    /// An empty map, allocates nothing until the first insert.
+    pub const fn new() -> Self {
+        let () = Self::LOAD_FACTOR_OK;
+        KHashMap { slots: Vec::new(), len: 0, deleted: 0 }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Number of slots.
+    pub fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+
+    // the slot holding `key`
+    fn find(&self, key: &K) -> Option<usize> {
+        if self.slots.is_empty() {
+            return None;
+        }
+        let mask = self.slots.len() - 1;
+        let mut index = hash_of(key) as usize & mask;
+        // the load factor keeps at least one slot empty, so this ends
+        loop {
+            match &self.slots[index] {
+                Slot::Empty => return None,
+                Slot::Full(slot_key, _) if slot_key == key => return Some(index),
+                _ => index = (index + 1) & mask,
+            }
+        }
+    }
+
+    /// Insert `value` under `key`, returns the value that was there before.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        if let Some(index) = self.find(&key) {
+            if let Slot::Full(_, old) = &mut self.slots[index] {
+                return Some(mem::replace(old, value));
+            }
+        }
+        if (self.len + self.deleted + 1) * LOAD_FACTOR_DEN > self.slots.len() * LOAD_FACTOR_NUM {
+            self.resize();
+        }
+        // the first empty or deleted slot on the key's probe sequence
+        let mask = self.slots.len() - 1;
+        let mut index = hash_of(&key) as usize & mask;
+        while let Slot::Full(..) = self.slots[index] {
+            index = (index + 1) & mask;
+        }
+        if let Slot::Deleted = self.slots[index] {
+            self.deleted -= 1;
+        }
+        self.slots[index] = Slot::Full(key, value);
+        self.len += 1;
+        None
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        match &self.slots[self.find(key)?] {
+            Slot::Full(_, value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        let index = self.find(key)?;
+        match &mut self.slots[index] {
+            Slot::Full(_, value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.find(key).is_some()
+    }
+
+    /// Remove `key`, returns its value.
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let index = self.find(key)?;
+        self.len -= 1;
+        self.deleted += 1;
+        match mem::replace(&mut self.slots[index], Slot::Deleted) {
+            Slot::Full(_, value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// The entries in slot order (no particular order).
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> + '_ {
+        self.slots.iter().filter_map(|slot| match slot {
+            Slot::Full(key, value) => Some((key, value)),
+            _ => None,
+        })
+    }
+
+    // rehash everything into a table that is big enough for one more entry (tombstones are dropped)
+    fn resize(&mut self) {
+        let mut capacity = self.slots.len().max(MIN_CAPACITY);
+        while (self.len + 1) * LOAD_FACTOR_DEN > capacity * LOAD_FACTOR_NUM / 2 {
+            capacity *= 2;
+        }
+        let mut slots = Vec::with_capacity(capacity);
+        slots.resize_with(capacity, || Slot::Empty);
+        let old = mem::replace(&mut self.slots, slots);
+        let mask = capacity - 1;
+        for slot in old {
+            if let Slot::Full(key, value) = slot {
+                let mut index = hash_of(&key) as usize & mask;
+                while let Slot::Full(..) = self.slots[index] {
+                    index = (index + 1) & mask;
+                }
+                self.slots[index] = Slot::Full(key, value);
+            }
+        }
+        self.deleted = 0;
+    }
+}
+
+impl<K: Eq + Hash, V, const LOAD_FACTOR_NUM: usize, const LOAD_FACTOR_DEN: usize> Default
+    for KHashMap<K, V, LOAD_FACTOR_NUM, LOAD_FACTOR_DEN>
+{
+    fn default() -> Self {
+        KHashMap::new()
+    }
+}
+
+// TESTS ===================================
+
+#[test_case]
+fn test_fnv1a_vectors() {
+    // from the reference test suite
+    assert_eq!(hash_of_bytes(b""), 0xcbf2_9ce4_8422_2325);
+    assert_eq!(hash_of_bytes(b"a"), 0xaf63_dc4c_8601_ec8c);
+    assert_eq!(hash_of_bytes(b"foobar"), 0x8594_4171_f739_67e8);
+
+    fn hash_of_bytes(bytes: &[u8]) -> u64 {
+        let mut hasher = FnvHasher::new();
+        hasher.write(bytes);
+        hasher.finish()
+    }
+}
+
+#[test_case]
+fn test_hashmap_insert_get_remove() {
+    // only 16 distinct hashes --> long probe sequences full of collisions
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    struct Colliding(u32);
+    impl Hash for Colliding {
+        fn hash<H: Hasher>(&self, state: &mut H) {
+            (self.0 % 16).hash(state);
+        }
+    }
+
+    let mut map: KHashMap<Colliding, u32> = KHashMap::new();
+    assert_eq!(map.get(&Colliding(1)), None);
+    for i in 0..1000 {
+        assert_eq!(map.insert(Colliding(i), i * 2), None);
+    }
+    assert_eq!(map.len(), 1000);
+    assert!(map.len() * 10 <= map.capacity() * 7, "load factor exceeded");
+    assert!((0..1000).all(|i| map.get(&Colliding(i)) == Some(&(i * 2))));
+
+    // every other key goes, the rest must still be found past the tombstones
+    for i in (0..1000).step_by(2) {
+        assert_eq!(map.remove(&Colliding(i)), Some(i * 2));
+    }
+    assert_eq!(map.remove(&Colliding(0)), None);
+    assert_eq!(map.len(), 500);
+    for i in 0..1000 {
+        let expected = if i % 2 == 1 { Some(i * 2) } else { None };
+        assert_eq!(map.get(&Colliding(i)).copied(), expected);
+    }
+    assert_eq!(map.iter().count(), 500);
+    assert!(map.iter().all(|(key, value)| key.0 % 2 == 1 && *value == key.0 * 2));
+
+    assert_eq!(map.insert(Colliding(1), 7), Some(2));
+    *map.get_mut(&Colliding(3)).unwrap() += 1;
+    assert_eq!(map.get(&Colliding(3)), Some(&7));
+    assert_eq!(map.len(), 500);
+}
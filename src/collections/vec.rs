@@ -0,0 +1,235 @@
+// KVec --> a growable array like alloc::vec::Vec, allocating from the kernel's heap allocator directly
+// - the buffer grows by doubling, starting at MIN_CAPACITY elements --> a handful of pushes means one allocation
+// - elements [0, len) are initialized, [len, cap) are not
+// - zero sized types never allocate: the capacity is usize::MAX and ptr stays dangling (but aligned)
+use crate::allocator::ALLOCATOR;
+use alloc::alloc::{handle_alloc_error, GlobalAlloc, Layout};
+use core::marker::PhantomData;
+use core::mem;
+use core::ops::{Index, IndexMut};
+use core::ptr::{self, NonNull};
+
+/// The capacity of the first allocation.
+pub const MIN_CAPACITY: usize = 4;
+
+/// A growable array on the kernel heap.
+pub struct KVec<T> {
+    ptr: *mut T,
+    len: usize,
+    cap: usize,
+}
+
+// a KVec owns its elements like a Vec does
+unsafe impl<T: Send> Send for KVec<T> {}
+unsafe impl<T: Sync> Sync for KVec<T> {}
+
+impl<T> KVec<T> {
+    /// An empty KVec, allocates nothing until the first push.
+    pub const fn new() -> Self {
+        let cap = if mem::size_of::<T>() == 0 { usize::MAX } else { 0 };
+        KVec { ptr: NonNull::dangling().as_ptr(), len: 0, cap }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// How many elements fit before the buffer has to grow.
+    pub fn capacity(&self) -> usize {
+        self.cap
+    }
+
+    /// Append `item`, growing the buffer if it is full.
+    pub fn push(&mut self, item: T) {
+        if self.len == self.cap {
+            self.grow();
+        }
+        unsafe { self.ptr.add(self.len).write(item) };
+        self.len += 1;
+    }
+
+    /// Remove the last element.
+    pub fn pop(&mut self) -> Option<T> {
+        if self.len == 0 {
+            return None;
+        }
+        self.len -= 1;
+        Some(unsafe { self.ptr.add(self.len).read() })
+    }
+
+    pub fn get(&self, idx: usize) -> Option<&T> {
+        self.as_slice().get(idx)
+    }
+
+    pub fn get_mut(&mut self, idx: usize) -> Option<&mut T> {
+        self.as_mut_slice().get_mut(idx)
+    }
+
+    pub fn as_slice(&self) -> &[T] {
+        unsafe { core::slice::from_raw_parts(self.ptr, self.len) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [T] {
+        unsafe { core::slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+
+    /// The elements in order, front to back.
+    pub fn iter(&self) -> KVecIter<'_, T> {
+        KVecIter { ptr: self.ptr, end: self.len, index: 0, _vec: PhantomData }
+    }
+
+    // the layout of a buffer for `cap` elements
+    fn layout(cap: usize) -> Layout {
+        Layout::array::<T>(cap).expect("KVec capacity overflow")
+    }
+
+    // double the capacity (at least MIN_CAPACITY), moving the elements to the new buffer
+    fn grow(&mut self) {
+        // a zero sized T has cap == usize::MAX, so this is only reached when len overflows
+        assert!(mem::size_of::<T>() != 0, "KVec capacity overflow");
+        let new_cap = self.cap.checked_mul(2).expect("KVec capacity overflow").max(MIN_CAPACITY);
+        let new_layout = Self::layout(new_cap);
+        let new_ptr = unsafe { ALLOCATOR.alloc(new_layout) } as *mut T;
+        if new_ptr.is_null() {
+            handle_alloc_error(new_layout);
+        }
+        if self.cap > 0 {
+            unsafe {
+                ptr::copy_nonoverlapping(self.ptr, new_ptr, self.len);
+                ALLOCATOR.dealloc(self.ptr as *mut u8, Self::layout(self.cap));
+            }
+        }
+        self.ptr = new_ptr;
+        self.cap = new_cap;
+    }
+}
+
+impl<T> Default for KVec<T> {
+    fn default() -> Self {
+        KVec::new()
+    }
+}
+
+impl<T> Drop for KVec<T> {
+    fn drop(&mut self) {
+        // the elements first, then the buffer they live in
+        unsafe { ptr::drop_in_place(self.as_mut_slice()) };
+        if mem::size_of::<T>() != 0 && self.cap > 0 {
+            unsafe { ALLOCATOR.dealloc(self.ptr as *mut u8, Self::layout(self.cap)) };
+        }
+    }
+}
+
+impl<T> Index<usize> for KVec<T> {
+    type Output = T;
+
+    fn index(&self, idx: usize) -> &T {
+        &self.as_slice()[idx]
+    }
+}
+
+impl<T> IndexMut<usize> for KVec<T> {
+    fn index_mut(&mut self, idx: usize) -> &mut T {
+        &mut self.as_mut_slice()[idx]
+    }
+}
+
+impl<T: core::fmt::Debug> core::fmt::Debug for KVec<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl<'a, T> IntoIterator for &'a KVec<T> {
+    type Item = &'a T;
+    type IntoIter = KVecIter<'a, T>;
+
+    fn into_iter(self) -> KVecIter<'a, T> {
+        self.iter()
+    }
+}
+
+/// Iterator over the elements of a `KVec`, see `KVec::iter()`.
+pub struct KVecIter<'a, T> {
+    ptr: *const T,
+    end: usize,
+    index: usize,
+    _vec: PhantomData<&'a KVec<T>>,
+}
+
+impl<'a, T> Iterator for KVecIter<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        if self.index == self.end {
+            return None;
+        }
+        let item = unsafe { &*self.ptr.add(self.index) };
+        self.index += 1;
+        Some(item)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.end - self.index, Some(self.end - self.index))
+    }
+}
+
+impl<T> ExactSizeIterator for KVecIter<'_, T> {}
+
+// TESTS ===================================
+
+#[test_case]
+fn test_kvec_push_pop() {
+    let mut vec = KVec::new();
+    assert_eq!(vec.capacity(), 0);
+    assert_eq!(vec.pop(), None);
+    for i in 0..1000u64 {
+        vec.push(i);
+    }
+    assert_eq!(vec.len(), 1000);
+    assert!(vec.capacity() >= 1000 && vec.capacity().is_power_of_two());
+    assert!((0..1000).all(|i| vec[i as usize] == i && vec.get(i as usize) == Some(&i)));
+    assert_eq!(vec.get(1000), None);
+
+    for i in (500..1000).rev() {
+        assert_eq!(vec.pop(), Some(i));
+    }
+    for i in 0..200 {
+        vec.push(10_000 + i);
+    }
+    assert_eq!(vec.len(), 700);
+    assert!(vec.iter().take(500).copied().eq(0..500));
+    assert!(vec.iter().skip(500).copied().eq(10_000..10_200));
+
+    vec[0] = 42;
+    assert_eq!(vec[0], 42);
+}
+
+#[test_case]
+fn test_kvec_drops_elements() {
+    use alloc::rc::Rc;
+
+    let counted = Rc::new(());
+    let mut vec = KVec::new();
+    for _ in 0..10 {
+        vec.push(counted.clone());
+    }
+    drop(vec.pop());
+    assert_eq!(Rc::strong_count(&counted), 10);
+    let bytes_before = crate::allocator::stats().bytes_in_use;
+    drop(vec);
+    assert_eq!(Rc::strong_count(&counted), 1);
+    assert!(crate::allocator::stats().bytes_in_use < bytes_before, "buffer not freed");
+
+    // zero sized elements never allocate
+    let mut units = KVec::new();
+    for _ in 0..100 {
+        units.push(());
+    }
+    assert_eq!(units.len(), 100);
+    assert_eq!(units.pop(), Some(()));
+}
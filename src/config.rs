@@ -0,0 +1,224 @@
+// Kernel boot configuration --> parsed from a "kernel command line" made up of space separated `key=value` pairs
+// ex. "heap_size=256 log_level=debug kaslr=true timer_hz=1000 panic_poweroff=10 test_timeout=120"
+// bootloader 0.9.x has no way of handing us a command line through BootInfo, so it comes from QEMU's fw_cfg instead
+// (`-fw_cfg name=opt/org.mini_os/cmdline,string="timer_hz=100"`, no rebuild needed) or, without that, from the
+// MINI_OS_CMDLINE environment variable at compile time (ex. `MINI_OS_CMDLINE="timer_hz=100" cargo run`)
+// unknown keys and malformed values are ignored and fall back to the defaults, we never want a typo to stop the kernel from booting
+use spin::Once;
+
+/// How chatty the kernel should be, from least to most verbose.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    /// Parse a log level name (case sensitive, lowercase only).
+    pub fn parse(s: &str) -> Option<LogLevel> {
+        match s {
+            "error" => Some(LogLevel::Error),
+            "warn" => Some(LogLevel::Warn),
+            "info" => Some(LogLevel::Info),
+            "debug" => Some(LogLevel::Debug),
+            "trace" => Some(LogLevel::Trace),
+            _ => None,
+        }
+    }
+}
+
+/// The largest heap `heap_size=` can ask for (16 MiB), bigger values are clamped to it.
+pub const MAX_HEAP_SIZE_KB: usize = 16 * 1024;
+
+/// Settings the kernel reads from its command line at boot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KernelConfig {
+    pub heap_size_kb: usize, // size of the kernel heap in KiB (1..=MAX_HEAP_SIZE_KB) --> see allocator::init_heap_with_size()
+    pub log_level: LogLevel,
+    pub kaslr: bool, // the bootloader we use can't randomize the kernel base yet, so this is only recorded for now
+    pub timer_hz: u32, // frequency of the PIT timer interrupt --> see interrupts::set_timer_frequency()
+    pub panic_poweroff_secs: u32, // power off this long after a panic (0 = halt forever) --> see power::shutdown_after()
+    pub framebuffer: bool, // switch to a graphics mode at boot instead of staying in VGA text mode --> see framebuffer.rs
+    pub test_timeout_secs: u32, // a single test running longer has failed (0 = no limit) --> see lib.rs test_runner()
+}
+
+impl KernelConfig {
+    /// The configuration used for every key that is not on the command line.
+    pub const DEFAULT: KernelConfig = KernelConfig {
+        heap_size_kb: crate::allocator::HEAP_SIZE / 1024,
+        log_level: LogLevel::Info,
+        kaslr: false,
+        timer_hz: 18, // roughly the ~18.2 Hz the PIT runs at after power-on
+        panic_poweroff_secs: 0,
+        framebuffer: false,
+        test_timeout_secs: 30,
+    };
+}
+
+impl Default for KernelConfig {
+    fn default() -> Self {
+        KernelConfig::DEFAULT
+    }
+}
+
+/// Parse an unsigned decimal number, returning `None` on an empty string, a non digit character or overflow.
+pub fn parse_u64_dec(s: &str) -> Option<u64> {
+    if s.is_empty() {
+        return None;
+    }
+    let mut value: u64 = 0;
+    for byte in s.bytes() {
+        let digit = match byte {
+            b'0'..=b'9' => (byte - b'0') as u64,
+            _ => return None,
+        };
+        value = value.checked_mul(10)?.checked_add(digit)?;
+    }
+    Some(value)
+}
+
+fn parse_bool(s: &str) -> Option<bool> {
+    match s {
+        "true" | "1" | "on" | "yes" => Some(true),
+        "false" | "0" | "off" | "no" => Some(false),
+        _ => None,
+    }
+}
+
+// a heap of 0 KiB can't hold anything and one whose size in bytes overflows can't be mapped
+fn checked_heap_size_kb(kb: u64) -> usize {
+    let default = KernelConfig::DEFAULT.heap_size_kb;
+    match usize::try_from(kb).ok().filter(|&kb| kb.checked_mul(1024).is_some()) {
+        Some(0) | None => {
+            crate::klog!(LogLevel::Warn, "heap_size={} is not a usable heap size, using {} KiB", kb, default);
+            default
+        }
+        Some(kb) if kb > MAX_HEAP_SIZE_KB => {
+            crate::klog!(LogLevel::Warn, "heap_size={} is over the maximum, using {} KiB", kb, MAX_HEAP_SIZE_KB);
+            MAX_HEAP_SIZE_KB
+        }
+        Some(kb) => kb,
+    }
+}
+
+/// Parse a kernel command line of space separated `key=value` pairs into a `KernelConfig`.
+///
+/// Keys that are missing, unknown or have a value that fails to parse keep their default.
+pub fn parse_kernel_args(cmdline: &str) -> KernelConfig {
+    let mut config = KernelConfig::default();
+
+    for arg in cmdline.split_whitespace() {
+        let (key, value) = match arg.split_once('=') {
+            Some(pair) => pair,
+            None => continue, // bare words don't mean anything (yet)
+        };
+        match key {
+            "heap_size" => {
+                if let Some(kb) = parse_u64_dec(value) {
+                    config.heap_size_kb = checked_heap_size_kb(kb);
+                }
+            }
+            "log_level" => {
+                if let Some(level) = LogLevel::parse(value) {
+                    config.log_level = level;
+                }
+            }
+            "kaslr" => {
+                if let Some(enabled) = parse_bool(value) {
+                    config.kaslr = enabled;
+                }
+            }
+            "timer_hz" => {
+                if let Some(hz) = parse_u64_dec(value) {
+                    if hz > 0 && hz <= u32::MAX as u64 {
+                        config.timer_hz = hz as u32;
+                    }
+                }
+            }
+            "framebuffer" => {
+                if let Some(enabled) = parse_bool(value) {
+                    config.framebuffer = enabled;
+                }
+            }
+            "panic_poweroff" => {
+                if let Some(secs) = parse_u64_dec(value) {
+                    config.panic_poweroff_secs = secs.min(u32::MAX as u64) as u32;
+                }
+            }
+            "test_timeout" => {
+                if let Some(secs) = parse_u64_dec(value) {
+                    config.test_timeout_secs = secs.min(u32::MAX as u64) as u32;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    config
+}
+
+/// The kernel command line: the fw_cfg file `opt/org.mini_os/cmdline` if QEMU passes one (read into `buf`, which
+/// limits its length), otherwise MINI_OS_CMDLINE from compile time. Doesn't need the heap.
+pub fn boot_cmdline(buf: &mut [u8]) -> &str {
+    let compiled_in = option_env!("MINI_OS_CMDLINE").unwrap_or("");
+    match crate::drivers::fw_cfg::read_file_into(crate::drivers::fw_cfg::CMDLINE_FILE, buf) {
+        // QEMU's `string=` items have no terminating NUL, but files from `file=` may
+        Some(size) => core::str::from_utf8(&buf[..size.min(buf.len())])
+            .map(|cmdline| cmdline.trim_end_matches('\0'))
+            .unwrap_or(compiled_in),
+        None => compiled_in,
+    }
+}
+
+// GLOBAL CONFIG =====================================
+
+// the parsed config is stored once at boot so subsystems can read it later without having it passed around
+static CONFIG: Once<KernelConfig> = Once::new();
+
+/// Store the boot configuration, only the first call has any effect.
+pub fn init(config: KernelConfig) {
+    CONFIG.call_once(|| config);
+}
+
+/// The boot configuration, or the defaults if `init()` was never called.
+pub fn get() -> &'static KernelConfig {
+    CONFIG.r#try().unwrap_or(&KernelConfig::DEFAULT)
+}
+
+// TESTS ===================================
+
+#[test_case]
+fn test_parse_kernel_args_all_fields() {
+    let config = parse_kernel_args(
+        "heap_size=256 log_level=debug kaslr=true timer_hz=1000 panic_poweroff=10 framebuffer=on test_timeout=120",
+    );
+    assert_eq!(config.heap_size_kb, 256);
+    assert_eq!(config.log_level, LogLevel::Debug);
+    assert!(config.kaslr);
+    assert_eq!(config.timer_hz, 1000);
+    assert_eq!(config.panic_poweroff_secs, 10);
+    assert!(config.framebuffer);
+    assert_eq!(config.test_timeout_secs, 120);
+}
+
+#[test_case]
+fn test_parse_kernel_args_defaults() {
+    assert_eq!(parse_kernel_args(""), KernelConfig::DEFAULT);
+    // bad values and unknown keys are ignored
+    let config = parse_kernel_args("heap_size=lots  colour=red timer_hz=0 log_level=debug");
+    assert_eq!(config.heap_size_kb, KernelConfig::DEFAULT.heap_size_kb);
+    assert_eq!(config.timer_hz, KernelConfig::DEFAULT.timer_hz);
+    assert_eq!(config.log_level, LogLevel::Debug);
+}
+
+#[test_case]
+fn test_parse_kernel_args_heap_size_limits() {
+    assert_eq!(parse_kernel_args("heap_size=0").heap_size_kb, KernelConfig::DEFAULT.heap_size_kb);
+    // 2^60 KiB doesn't fit in a usize worth of bytes
+    assert_eq!(parse_kernel_args("heap_size=1152921504606846976").heap_size_kb, KernelConfig::DEFAULT.heap_size_kb);
+    assert_eq!(parse_kernel_args("heap_size=1048576").heap_size_kb, MAX_HEAP_SIZE_KB);
+    assert_eq!(parse_kernel_args("heap_size=16384").heap_size_kb, MAX_HEAP_SIZE_KB);
+}
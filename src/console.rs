@@ -0,0 +1,243 @@
+// Where print!/println! output goes --> one display (the VGA text buffer, or the framebuffer's text console once it is
+// started) and up to MAX_SINKS extra outputs that get a copy of everything (ex. a debug port)
+// - outputs are `&'static dyn ConsoleOutput`, so nothing here allocates and printing works from interrupt handlers
+// - the lists are copied out of their locks before writing --> an output may print itself (or panic) without deadlocking
+// - with_output() binds print! to another output than the display for a while (ex. a shell on the serial port runs its
+//   commands with it, see kshell.rs) --> the bound output takes the display's place, clear/colors/columns included
+// - tests capture the output with a sink of their own, see console/capture.rs
+// - an output that is bound and registered as a sink at the same time gets print! output once, not twice
+pub mod capture;
+
+#[cfg(test)]
+pub(crate) use capture::{capture_output, capture_writes};
+
+use crate::vga_buffer::Color;
+use core::fmt;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// Something print! can write text to.
+pub trait ConsoleOutput: Sync {
+    fn write_str(&self, s: &str);
+
+    /// Blank the output and start over at the top, if it is a screen (see `clear_screen()`).
+    fn clear(&self) {}
+
+    /// Characters per row if it is a screen, a longer line wraps into the next row.
+    fn columns(&self) -> Option<usize> {
+        None
+    }
+
+    /// Show the text written from now on in these colors, if it is a screen (see `set_colors()`).
+    fn set_colors(&self, _foreground: Color, _background: Color) {}
+}
+
+/// The most extra outputs `register()` takes.
+pub const MAX_SINKS: usize = 4;
+
+static DISPLAY: Mutex<&'static dyn ConsoleOutput> = Mutex::new(&crate::vga_buffer::VgaTextOutput);
+static SINKS: Mutex<[Option<&'static dyn ConsoleOutput>; MAX_SINKS]> = Mutex::new([None; MAX_SINKS]);
+static BOUND: Mutex<Option<&'static dyn ConsoleOutput>> = Mutex::new(None);
+
+// where print! writes now: the bound output, or the display
+fn current() -> &'static dyn ConsoleOutput {
+    interrupts::without_interrupts(|| BOUND.lock().unwrap_or_else(|| *DISPLAY.lock()))
+}
+
+/// Show print! output on `display` instead of the current one.
+pub fn set_display(display: &'static dyn ConsoleOutput) {
+    interrupts::without_interrupts(|| *DISPLAY.lock() = display);
+}
+
+/// Run `f` with print! going to `output` instead of the display, the sinks still get their copy.
+///
+/// Everything printed while `f` runs goes there, interrupt handlers included --> `f` shouldn't take long.
+pub fn with_output<R>(output: &'static dyn ConsoleOutput, f: impl FnOnce() -> R) -> R {
+    let previous = interrupts::without_interrupts(|| BOUND.lock().replace(output));
+    let result = f();
+    interrupts::without_interrupts(|| *BOUND.lock() = previous);
+    result
+}
+
+/// The display, whichever it is at the time (for binding a shell to the screen, see `with_output()`).
+pub struct CurrentDisplay;
+
+impl ConsoleOutput for CurrentDisplay {
+    fn write_str(&self, s: &str) {
+        let display = interrupts::without_interrupts(|| *DISPLAY.lock());
+        display.write_str(s);
+    }
+
+    fn clear(&self) {
+        let display = interrupts::without_interrupts(|| *DISPLAY.lock());
+        display.clear();
+    }
+
+    fn columns(&self) -> Option<usize> {
+        let display = interrupts::without_interrupts(|| *DISPLAY.lock());
+        display.columns()
+    }
+
+    fn set_colors(&self, foreground: Color, background: Color) {
+        let display = interrupts::without_interrupts(|| *DISPLAY.lock());
+        display.set_colors(foreground, background);
+    }
+}
+
+/// Send a copy of all print! output to `sink` as well. Returns false if all MAX_SINKS places are taken.
+pub fn register(sink: &'static dyn ConsoleOutput) -> bool {
+    interrupts::without_interrupts(|| match SINKS.lock().iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => {
+            *slot = Some(sink);
+            true
+        }
+        None => false,
+    })
+}
+
+// whether `a` and `b` are the same output --> compares the data pointers only, the vtable of the same type may differ
+// between codegen units
+fn same_output(a: &'static dyn ConsoleOutput, b: &'static dyn ConsoleOutput) -> bool {
+    core::ptr::eq(a as *const dyn ConsoleOutput as *const (), b as *const dyn ConsoleOutput as *const ())
+}
+
+/// Stop sending print! output to `sink`. Returns false if it wasn't registered.
+pub fn unregister(sink: &'static dyn ConsoleOutput) -> bool {
+    let same = |registered: &'static dyn ConsoleOutput| same_output(registered, sink);
+    interrupts::without_interrupts(|| match SINKS.lock().iter_mut().find(|slot| slot.map_or(false, same)) {
+        Some(slot) => {
+            *slot = None;
+            true
+        }
+        None => false,
+    })
+}
+
+/// Write `s` to the extra outputs only (for early_print(), which writes to the screen itself).
+pub fn write_to_sinks(s: &str) {
+    interrupts::without_interrupts(|| {
+        let sinks = *SINKS.lock();
+        for sink in sinks.iter().flatten() {
+            sink.write_str(s);
+        }
+    });
+}
+
+/// Write `args` to the extra outputs if their list isn't locked, for panic handlers (see `panic_println!`).
+pub fn try_print_to_sinks(args: fmt::Arguments) {
+    use core::fmt::Write;
+
+    let sinks = match SINKS.try_lock() {
+        Some(sinks) => *sinks,
+        None => return,
+    };
+    for sink in sinks.iter().flatten() {
+        let _ = Adapter(*sink).write_fmt(args);
+    }
+}
+
+/// Clear the display, or the bound output (the extra outputs are left alone, they keep everything).
+pub fn clear_screen() {
+    current().clear();
+}
+
+/// Write on the display (or the bound output) in `foreground` on `background` from now on.
+pub fn set_colors(foreground: Color, background: Color) {
+    current().set_colors(foreground, background);
+}
+
+/// Characters per row of the display (or the bound output), `None` if it doesn't have rows.
+pub fn columns() -> Option<usize> {
+    current().columns()
+}
+
+// lets write_fmt() format straight into an output, piece by piece
+struct Adapter(&'static dyn ConsoleOutput);
+
+impl fmt::Write for Adapter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.write_str(s);
+        Ok(())
+    }
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    use core::fmt::Write;
+
+    // make sure no interrupts occur while an output is locked --> prevents deadlocks with interrupts
+    interrupts::without_interrupts(|| {
+        let display = current();
+        let sinks = *SINKS.lock();
+        let sinks = sinks.iter().flatten().copied().filter(|&sink| !same_output(sink, display));
+        for output in core::iter::once(display).chain(sinks) {
+            Adapter(output).write_fmt(args).unwrap();
+        }
+    });
+}
+
+// TESTS ===================================
+
+#[test_case]
+fn test_registered_sink_gets_output() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    struct Counter(AtomicUsize);
+    impl ConsoleOutput for Counter {
+        fn write_str(&self, s: &str) {
+            self.0.fetch_add(s.len(), Ordering::Relaxed);
+        }
+    }
+    static COUNTER: Counter = Counter(AtomicUsize::new(0));
+
+    // nothing else may print in between (ex. the timer interrupt printing dots)
+    interrupts::without_interrupts(|| {
+        assert!(register(&COUNTER));
+        crate::print!("{}-{}", 12, "ab");
+        assert_eq!(COUNTER.0.load(Ordering::Relaxed), 5);
+        write_to_sinks("xyz");
+        assert_eq!(COUNTER.0.load(Ordering::Relaxed), 8);
+        assert!(unregister(&COUNTER));
+        assert!(!unregister(&COUNTER), "unregistered twice");
+        crate::print!("not counted");
+        assert_eq!(COUNTER.0.load(Ordering::Relaxed), 8);
+    });
+}
+
+#[test_case]
+fn test_capture_output() {
+    let output = capture_output(|| crate::println!("captured {}", 42));
+    assert!(output.ends_with("captured 42\n"), "got {:?}", output);
+}
+
+#[test_case]
+fn test_with_output() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    struct Counter(AtomicUsize);
+    impl ConsoleOutput for Counter {
+        fn write_str(&self, s: &str) {
+            self.0.fetch_add(s.len(), Ordering::Relaxed);
+        }
+    }
+    static COUNTER: Counter = Counter(AtomicUsize::new(0));
+
+    let mut result = 0;
+    // the capture is a sink --> gets a copy of what the bound output gets
+    let output = capture_output(|| {
+        result = with_output(&COUNTER, || {
+            crate::print!("bound");
+            7
+        });
+        crate::print!(" display");
+    });
+    assert_eq!((output.as_str(), result), ("bound display", 7));
+    assert_eq!(COUNTER.0.load(Ordering::Relaxed), 5, "the display got the bound output's text or the other way round");
+}
+
+#[test_case]
+fn test_bound_sink_gets_output_once() {
+    // the capture bound and registered --> print! writes it once (ex. a shell writing to the capture in a test)
+    let output = capture_output(|| with_output(&capture::CAPTURE, || crate::print!("once")));
+    assert_eq!(output, "once");
+}
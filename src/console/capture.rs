@@ -0,0 +1,142 @@
+// Capturing console output in tests --> start() registers CAPTURE as a console sink, take() unregisters it and returns
+// what it got as a String, ex. to check what klog! printed
+// - the text goes into a ring buffer in a static, not onto the heap: sinks are written from interrupt handlers, which
+//   must not allocate (see no_alloc_guard() in allocator.rs) --> only take() allocates, for the String
+// - the buffer keeps the last CAPTURE_LEN bytes, older output is dropped
+// - like every sink the buffer is only locked with interrupts off (_print() and write_to_sinks() turn them off, start()
+//   and take() do it themselves) --> an interrupt handler printing can't find the lock held on its own CPU
+// - compiled into every build, not just the library's tests: the integration tests in tests/ capture with it too
+use super::ConsoleOutput;
+use alloc::string::String;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// Bytes the capture keeps.
+pub const CAPTURE_LEN: usize = 4096;
+
+struct Ring {
+    bytes: [u8; CAPTURE_LEN],
+    // the oldest byte and how many there are
+    start: usize,
+    len: usize,
+}
+
+/// A console output that keeps the last `CAPTURE_LEN` bytes written to it.
+pub struct CaptureSink(Mutex<Ring>);
+
+/// The sink `start()` and `take()` use.
+pub static CAPTURE: CaptureSink = CaptureSink(Mutex::new(Ring { bytes: [0; CAPTURE_LEN], start: 0, len: 0 }));
+
+// whether CAPTURE is registered by start()
+static CAPTURING: AtomicBool = AtomicBool::new(false);
+
+impl CaptureSink {
+    /// Forget everything written so far.
+    pub fn clear(&self) {
+        interrupts::without_interrupts(|| {
+            let mut ring = self.0.lock();
+            ring.start = 0;
+            ring.len = 0;
+        });
+    }
+
+    /// What was written since the last `clear()` (the last `CAPTURE_LEN` bytes of it).
+    pub fn text(&self) -> String {
+        interrupts::without_interrupts(|| {
+            let ring = self.0.lock();
+            let end = ring.start + ring.len;
+            let mut bytes = alloc::vec::Vec::with_capacity(ring.len);
+            bytes.extend_from_slice(&ring.bytes[ring.start..end.min(CAPTURE_LEN)]);
+            bytes.extend_from_slice(&ring.bytes[..end.saturating_sub(CAPTURE_LEN)]);
+            // the oldest character may have been cut in half
+            String::from_utf8_lossy(&bytes).into_owned()
+        })
+    }
+}
+
+impl ConsoleOutput for CaptureSink {
+    fn write_str(&self, s: &str) {
+        let mut ring = self.0.lock();
+        for &byte in s.as_bytes() {
+            let end = (ring.start + ring.len) % CAPTURE_LEN;
+            ring.bytes[end] = byte;
+            if ring.len == CAPTURE_LEN {
+                ring.start = (ring.start + 1) % CAPTURE_LEN; // full, the oldest byte goes
+            } else {
+                ring.len += 1;
+            }
+        }
+    }
+}
+
+/// Start capturing all print! output (and klog!, which prints through it) into `CAPTURE`.
+///
+/// Panics if a capture is running already or all console sinks are taken.
+pub fn start() {
+    assert!(!CAPTURING.swap(true, Ordering::SeqCst), "capture::start() while capturing");
+    CAPTURE.clear();
+    assert!(super::register(&CAPTURE), "no free console sink for the capture");
+}
+
+/// Stop capturing and return what was printed since `start()` (the last `CAPTURE_LEN` bytes).
+pub fn take() -> String {
+    if CAPTURING.swap(false, Ordering::SeqCst) {
+        super::unregister(&CAPTURE);
+    }
+    let text = CAPTURE.text();
+    CAPTURE.clear();
+    text
+}
+
+/// Everything written to the output `f` gets (the last `CAPTURE_LEN` bytes), for tests of code that writes to an
+/// output of its own.
+///
+/// `f` runs with interrupts off, so nothing else gets into the output (ex. the timer interrupt printing dots).
+pub fn capture_writes(f: impl FnOnce(&'static dyn ConsoleOutput)) -> String {
+    interrupts::without_interrupts(|| {
+        CAPTURE.clear();
+        f(&CAPTURE);
+        let text = CAPTURE.text();
+        CAPTURE.clear();
+        text
+    })
+}
+
+/// Everything `f` prints, for tests of code that prints its results (see `capture_writes()`).
+pub fn capture_output(f: impl FnOnce()) -> String {
+    interrupts::without_interrupts(|| {
+        start();
+        f();
+        take()
+    })
+}
+
+// TESTS ===================================
+
+#[test_case]
+fn test_capture_start_take() {
+    // nothing else may print in between (ex. the timer interrupt printing dots)
+    interrupts::without_interrupts(|| {
+        start();
+        crate::println!("captured {}", 42);
+        super::write_to_sinks("sink only");
+        let text = take();
+        assert!(text.ends_with("captured 42\nsink only"), "got {:?}", text);
+        crate::print!("not captured");
+        assert_eq!(take(), "");
+    });
+}
+
+#[test_case]
+fn test_capture_keeps_the_last_bytes() {
+    let text = capture_writes(|output| {
+        for _ in 0..CAPTURE_LEN / 8 {
+            output.write_str("12345678");
+        }
+        output.write_str("abc");
+    });
+    assert_eq!(text.len(), CAPTURE_LEN);
+    assert!(text.starts_with("45678123"), "got {:?}", &text[..8]);
+    assert!(text.ends_with("5678abc"));
+}
@@ -0,0 +1,3 @@
+// CPU registers and instructions that don't belong to a subsystem (see cpuid.rs for feature detection)
+pub mod msr;
+pub mod sse;
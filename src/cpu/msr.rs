@@ -0,0 +1,232 @@
+// MSRs (model specific registers) --> CPU settings outside the normal register set, read with `rdmsr` and written with
+// `wrmsr` (ecx = MSR number, edx:eax = value)
+// - reading or writing an MSR the CPU doesn't have, or setting a reserved bit, raises #GP --> read()/write() are unsafe
+// - writes can change how the CPU runs (paging, syscalls, the APIC...) --> the registers we understand get safe wrappers
+// see the Intel SDM volume 4 for the numbers and bits
+use core::arch::asm;
+use core::fmt;
+use x86_64::PhysAddr;
+
+pub const IA32_APIC_BASE: u32 = 0x1B;
+pub const IA32_MISC_ENABLE: u32 = 0x1A0;
+pub const IA32_EFER: u32 = 0xC000_0080;
+/// Segment selectors for `syscall`/`sysret`.
+pub const IA32_STAR: u32 = 0xC000_0081;
+/// Entry point of `syscall` in 64 bit mode.
+pub const IA32_LSTAR: u32 = 0xC000_0082;
+/// RFLAGS bits cleared on `syscall`.
+pub const IA32_FMASK: u32 = 0xC000_0084;
+pub const IA32_FS_BASE: u32 = 0xC000_0100;
+pub const IA32_GS_BASE: u32 = 0xC000_0101;
+/// Swapped with the GS base by `swapgs`.
+pub const IA32_KERNEL_GS_BASE: u32 = 0xC000_0102;
+
+/// Read MSR `msr`.
+///
+/// The caller must make sure the CPU has this MSR (ex. through CPUID), otherwise the read raises #GP.
+pub unsafe fn read(msr: u32) -> u64 {
+    let (low, high): (u32, u32);
+    asm!("rdmsr", in("ecx") msr, out("eax") low, out("edx") high, options(nomem, nostack, preserves_flags));
+    (u64::from(high) << 32) | u64::from(low)
+}
+
+/// Write `value` to MSR `msr`.
+///
+/// The caller must make sure the CPU has this MSR, that `value` sets no reserved bits (both raise #GP), and that the
+/// change is safe for the running kernel (ex. an FS base that task code still dereferences, or turning long mode off).
+pub unsafe fn write(msr: u32, value: u64) {
+    let (low, high) = (value as u32, (value >> 32) as u32);
+    asm!("wrmsr", in("ecx") msr, in("eax") low, in("edx") high, options(nostack, preserves_flags));
+}
+
+/// Replace MSR `msr` with `f` of its value. Same requirements as `read()` and `write()`.
+pub unsafe fn update(msr: u32, f: impl FnOnce(u64) -> u64) {
+    write(msr, f(read(msr)));
+}
+
+/// Set `bits` in MSR `msr`. Same requirements as `read()` and `write()`.
+pub unsafe fn set_bits(msr: u32, bits: u64) {
+    update(msr, |value| value | bits);
+}
+
+/// Clear `bits` in MSR `msr`. Same requirements as `read()` and `write()`.
+pub unsafe fn clear_bits(msr: u32, bits: u64) {
+    update(msr, |value| value & !bits);
+}
+
+// EFER ====================================
+
+pub const EFER_SCE: u64 = 1 << 0; // syscall/sysret enabled
+pub const EFER_LME: u64 = 1 << 8; // long mode enabled
+pub const EFER_LMA: u64 = 1 << 10; // long mode active (read only)
+pub const EFER_NXE: u64 = 1 << 11; // no-execute page flag enabled
+
+/// The decoded EFER (extended feature enable register).
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Efer(pub u64);
+
+impl Efer {
+    pub fn syscall_enabled(&self) -> bool {
+        self.0 & EFER_SCE != 0
+    }
+
+    pub fn long_mode_enabled(&self) -> bool {
+        self.0 & EFER_LME != 0
+    }
+
+    pub fn long_mode_active(&self) -> bool {
+        self.0 & EFER_LMA != 0
+    }
+
+    pub fn no_execute_enabled(&self) -> bool {
+        self.0 & EFER_NXE != 0
+    }
+}
+
+impl fmt::Debug for Efer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Efer")
+            .field("raw", &format_args!("{:#x}", self.0))
+            .field("sce", &self.syscall_enabled())
+            .field("lme", &self.long_mode_enabled())
+            .field("lma", &self.long_mode_active())
+            .field("nxe", &self.no_execute_enabled())
+            .finish()
+    }
+}
+
+/// The current EFER (every x86_64 CPU has it).
+pub fn efer() -> Efer {
+    Efer(unsafe { read(IA32_EFER) })
+}
+
+// CPUID.80000001h:EDX, the no-execute page flag
+const CPUID_NX: u32 = 1 << 20;
+
+/// Whether the CPU has the NO_EXECUTE page table flag (EFER.NXE can only be set if it does).
+pub fn nx_supported() -> bool {
+    crate::cpuid::detect_cpu_features().leaf80000001.edx & CPUID_NX != 0
+}
+
+/// Allow the NO_EXECUTE page table flag (without NXE it is a reserved bit and using it page faults).
+///
+/// Returns false on a CPU without NX (setting NXE there raises #GP), see `memory::no_execute()` for the flag to use then.
+pub fn enable_nxe() -> bool {
+    if !nx_supported() {
+        return false;
+    }
+    unsafe { set_bits(IA32_EFER, EFER_NXE) };
+    true
+}
+
+// APIC BASE ====================================
+
+pub const APIC_BASE_BSP: u64 = 1 << 8; // this is the bootstrap processor (read only)
+pub const APIC_BASE_X2APIC: u64 = 1 << 10; // x2APIC mode
+pub const APIC_BASE_ENABLE: u64 = 1 << 11; // the local APIC is enabled
+const APIC_BASE_ADDRESS_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+/// The decoded IA32_APIC_BASE register.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct ApicBase(pub u64);
+
+impl ApicBase {
+    /// Whether this CPU is the one that booted.
+    pub fn is_bsp(&self) -> bool {
+        self.0 & APIC_BASE_BSP != 0
+    }
+
+    pub fn x2apic_enabled(&self) -> bool {
+        self.0 & APIC_BASE_X2APIC != 0
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.0 & APIC_BASE_ENABLE != 0
+    }
+
+    /// Where the local APIC's registers are mapped (physical).
+    pub fn address(&self) -> PhysAddr {
+        PhysAddr::new(self.0 & APIC_BASE_ADDRESS_MASK)
+    }
+}
+
+impl fmt::Debug for ApicBase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ApicBase")
+            .field("address", &self.address())
+            .field("bsp", &self.is_bsp())
+            .field("x2apic", &self.x2apic_enabled())
+            .field("enabled", &self.enabled())
+            .finish()
+    }
+}
+
+/// The current IA32_APIC_BASE (every CPU with a local APIC has it, all x86_64 CPUs do).
+pub fn apic_base() -> ApicBase {
+    ApicBase(unsafe { read(IA32_APIC_BASE) })
+}
+
+/// Move the local APIC's registers to `address` (page aligned), keeping the enable bits as they are.
+///
+/// Nothing uses the local APIC yet (interrupts go through the PICs), a driver that does has to map the new address.
+pub fn set_apic_base(address: PhysAddr) {
+    assert!(address.is_aligned(4096u64), "APIC base {:?} isn't page aligned", address);
+    assert_eq!(address.as_u64() & !APIC_BASE_ADDRESS_MASK, 0, "APIC base {:?} out of range", address);
+    unsafe { update(IA32_APIC_BASE, |value| (value & !APIC_BASE_ADDRESS_MASK) | address.as_u64()) };
+}
+
+// MACHINE CHECK ====================================
+// the machine check architecture (MCA) reports hardware errors in banks of MSRs, MCG_CAP says how many banks there are
+
+pub const IA32_MCG_CAP: u32 = 0x179;
+pub const IA32_MCG_STATUS: u32 = 0x17A;
+const IA32_MC0_STATUS: u32 = 0x401;
+
+pub const MCG_CAP_COUNT_MASK: u64 = 0xFF;
+pub const MCG_STATUS_RIPV: u64 = 1 << 0; // restarting at the RIP on the stack is possible
+pub const MCG_STATUS_EIPV: u64 = 1 << 1; // the RIP on the stack is where the error happened
+pub const MCG_STATUS_MCIP: u64 = 1 << 2; // a machine check is in progress (a second one shuts the CPU down)
+pub const MC_STATUS_VAL: u64 = 1 << 63; // the bank holds an error
+pub const MC_STATUS_UC: u64 = 1 << 61; // uncorrected
+
+/// The status MSR of machine check bank `bank` (IA32_MCi_STATUS).
+pub const fn ia32_mc_status(bank: u8) -> u32 {
+    IA32_MC0_STATUS + 4 * bank as u32
+}
+
+/// The MCA error code (bits 0-15) of an IA32_MCi_STATUS value.
+pub const fn mca_error_code(status: u64) -> u16 {
+    status as u16
+}
+
+// TESTS ===================================
+
+#[test_case]
+fn test_efer_boot_state() {
+    let efer = efer();
+    assert!(efer.long_mode_enabled() && efer.long_mode_active(), "{:?}", efer);
+    // memory::init() enables NXE if the CPU has NX, tests run after it
+    assert_eq!(efer.no_execute_enabled(), nx_supported(), "{:?}", efer);
+    // we use int 0x80 for syscalls, nobody enabled syscall/sysret
+    assert!(!efer.syscall_enabled(), "{:?}", efer);
+    assert_eq!(efer.0, unsafe { read(IA32_EFER) });
+}
+
+#[test_case]
+fn test_apic_base_decode() {
+    let apic_base = apic_base();
+    // the firmware leaves the local APIC at its default address, we boot on the BSP
+    assert_eq!(apic_base.address(), PhysAddr::new(0xFEE0_0000), "{:?}", apic_base);
+    assert!(apic_base.is_bsp());
+
+    // moving it to where it already is changes nothing
+    set_apic_base(apic_base.address());
+    assert_eq!(self::apic_base(), apic_base);
+}
+
+#[test_case]
+fn test_machine_check_bank_msrs() {
+    assert_eq!(ia32_mc_status(0), 0x401);
+    assert_eq!(ia32_mc_status(3), 0x40D);
+    assert_eq!(mca_error_code(MC_STATUS_VAL | MC_STATUS_UC | 0x0150), 0x0150);
+}
@@ -0,0 +1,74 @@
+// SSE control --> the kernel itself is built without SSE (see x86_64-mini_os.json), but the CPU can still be switched
+// to allow it, ex. for code that needs it
+// - CR0.EM off and CR0.MP on: SSE instructions are executed instead of raising #UD / #NM
+// - CR4.OSFXSR: the OS saves the SSE registers (fxsave) --> SSE instructions are allowed at all
+// - CR4.OSXMMEXCPT: an unmasked SIMD floating point exception raises #XF (vector 19) instead of #UD
+// - MXCSR holds the sticky exception flags (bits 0-5) and their masks (bits 7-12), a raised exception whose mask is
+//   clear faults, a masked one just produces the default result (ex. infinity for a division by zero)
+//
+// MXCSR is read and written with inline asm: core::arch::x86_64::{_mm_getcsr, _mm_setcsr} are gated behind the sse
+// target feature, which our target spec turns off (same as the fences in mmio.rs)
+// nothing saves the SSE registers on a task switch yet --> only one piece of code may use them at a time
+use core::arch::asm;
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+
+pub const MXCSR_IE: u32 = 1 << 0; // invalid operation
+pub const MXCSR_DE: u32 = 1 << 1; // denormal operand
+pub const MXCSR_ZE: u32 = 1 << 2; // divide by zero
+pub const MXCSR_OE: u32 = 1 << 3; // overflow
+pub const MXCSR_UE: u32 = 1 << 4; // underflow
+pub const MXCSR_PE: u32 = 1 << 5; // precision (inexact result)
+/// All exception flags.
+pub const MXCSR_FLAGS: u32 = 0x3F;
+/// The mask bit of an exception flag is the flag shifted by this.
+pub const MXCSR_MASK_SHIFT: u32 = 7;
+/// The value after reset: every exception masked, no flags.
+pub const MXCSR_DEFAULT: u32 = 0x1F80;
+
+/// The exception flags and their names, in bit order.
+pub const MXCSR_FLAG_NAMES: [(u32, &str); 6] =
+    [(MXCSR_IE, "IE"), (MXCSR_DE, "DE"), (MXCSR_ZE, "ZE"), (MXCSR_OE, "OE"), (MXCSR_UE, "UE"), (MXCSR_PE, "PE")];
+
+/// Allow SSE instructions and deliver unmasked SIMD floating point exceptions as #XF.
+pub fn enable_sse() {
+    unsafe {
+        Cr0::update(|flags| {
+            flags.remove(Cr0Flags::EMULATE_COPROCESSOR);
+            flags.insert(Cr0Flags::MONITOR_COPROCESSOR);
+        });
+        Cr4::update(|flags| flags.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE));
+    }
+}
+
+/// Whether `enable_sse()` was called.
+pub fn sse_enabled() -> bool {
+    Cr4::read().contains(Cr4Flags::OSFXSR)
+}
+
+/// The current MXCSR (`stmxcsr`). Raises #UD unless SSE is enabled.
+pub fn read_mxcsr() -> u32 {
+    let mut mxcsr: u32 = 0;
+    unsafe { asm!("stmxcsr [{}]", in(reg) &mut mxcsr, options(nostack, preserves_flags)) };
+    mxcsr
+}
+
+/// Load `value` into MXCSR (`ldmxcsr`).
+///
+/// This function is unsafe because setting a reserved bit raises #GP, and SSE must be enabled (#UD otherwise).
+pub unsafe fn write_mxcsr(value: u32) {
+    asm!("ldmxcsr [{}]", in(reg) &value, options(nostack, preserves_flags, readonly));
+}
+
+// TESTS ===================================
+
+#[test_case]
+fn test_mxcsr_round_trip() {
+    enable_sse();
+    assert!(sse_enabled());
+    let previous = read_mxcsr();
+    // round toward zero (bits 13-14) on top of the default masks
+    unsafe { write_mxcsr(MXCSR_DEFAULT | 0x6000) };
+    assert_eq!(read_mxcsr(), MXCSR_DEFAULT | 0x6000);
+    unsafe { write_mxcsr(previous) };
+    assert_eq!(read_mxcsr(), previous);
+}
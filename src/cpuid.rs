@@ -0,0 +1,89 @@
+// CPUID --> asks the CPU what it is and what it supports (SSE, RDRAND, NX, ...)
+// the answers never change while running and every `cpuid` instruction is slow (it even traps to the hypervisor in a VM),
+// so the leaves we use for feature checks are read once and cached
+use core::arch::x86_64::{CpuidResult, __cpuid, __cpuid_count};
+use spin::Once;
+
+const EXTENDED_LEAF_BASE: u32 = 0x8000_0000;
+
+/// The CPUID leaves used for feature checks, read once by `detect_cpu_features()`.
+#[derive(Debug, Clone, Copy)]
+pub struct CpuidCache {
+    /// Highest basic leaf + vendor string.
+    pub leaf0: CpuidResult,
+    /// Family/model/stepping + basic feature flags.
+    pub leaf1: CpuidResult,
+    /// Structured extended feature flags (subleaf 0).
+    pub leaf7: CpuidResult,
+    /// Extended feature flags (NX, long mode, ...).
+    pub leaf80000001: CpuidResult,
+    vendor: [u8; 12],
+}
+
+// what unsupported leaves are reported as
+const EMPTY: CpuidResult = CpuidResult { eax: 0, ebx: 0, ecx: 0, edx: 0 };
+
+static CPUID_CACHE: Once<CpuidCache> = Once::new();
+
+/// Read the cached CPUID leaves (only the first call executes `cpuid`).
+pub fn detect_cpu_features() -> &'static CpuidCache {
+    CPUID_CACHE.call_once(|| {
+        // cpuid is always available in long mode
+        let leaf0 = unsafe { __cpuid(0) };
+        let max_leaf = leaf0.eax;
+        let max_extended_leaf = unsafe { __cpuid(EXTENDED_LEAF_BASE) }.eax;
+
+        // asking for a leaf above the maximum returns the data of the highest leaf instead of zeros --> check first
+        let leaf1 = if max_leaf >= 1 { unsafe { __cpuid(1) } } else { EMPTY };
+        let leaf7 = if max_leaf >= 7 { unsafe { __cpuid_count(7, 0) } } else { EMPTY };
+        let leaf80000001 = if max_extended_leaf >= 0x8000_0001 { unsafe { __cpuid(0x8000_0001) } } else { EMPTY };
+
+        // the vendor string is stored in ebx, edx, ecx (in that order)
+        let mut vendor = [0u8; 12];
+        vendor[0..4].copy_from_slice(&leaf0.ebx.to_le_bytes());
+        vendor[4..8].copy_from_slice(&leaf0.edx.to_le_bytes());
+        vendor[8..12].copy_from_slice(&leaf0.ecx.to_le_bytes());
+
+        CpuidCache { leaf0, leaf1, leaf7, leaf80000001, vendor }
+    })
+}
+
+/// The cached result of CPUID `leaf`/`subleaf`, `None` for leaves that aren't cached (call `__cpuid` for those).
+pub fn cpuid_cached(leaf: u32, subleaf: u32) -> Option<CpuidResult> {
+    let cache = detect_cpu_features();
+    match (leaf, subleaf) {
+        (0, _) => Some(cache.leaf0),
+        (1, _) => Some(cache.leaf1),
+        (7, 0) => Some(cache.leaf7),
+        (0x8000_0001, _) => Some(cache.leaf80000001),
+        _ => None,
+    }
+}
+
+/// The CPU vendor from leaf 0, ex. "GenuineIntel" or "AuthenticAMD".
+pub fn cpu_vendor_string() -> &'static str {
+    let vendor = &detect_cpu_features().vendor;
+    core::str::from_utf8(vendor).unwrap_or("").trim_end_matches('\0')
+}
+
+// TESTS ===================================
+
+#[test_case]
+fn test_cpu_vendor_string() {
+    let vendor = cpu_vendor_string();
+    assert!(
+        ["GenuineIntel", "AuthenticAMD", "KVMKVMKVM"].contains(&vendor),
+        "unexpected vendor {:?}",
+        vendor
+    );
+}
+
+#[test_case]
+fn test_cpuid_cached_matches_cpuid() {
+    let leaf1 = cpuid_cached(1, 0).expect("leaf 1 not cached");
+    let fresh = unsafe { __cpuid(1) };
+    // ebx bits 24-31 hold the APIC id of the CPU we run on, compare the feature flags only
+    assert_eq!((leaf1.eax, leaf1.ecx, leaf1.edx), (fresh.eax, fresh.ecx, fresh.edx));
+    assert!(cpuid_cached(7, 1).is_none());
+    assert!(cpuid_cached(0x4000_0000, 0).is_none());
+}
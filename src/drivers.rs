@@ -0,0 +1,14 @@
+// Device drivers --> code that talks to a specific piece of hardware
+pub mod ata;
+pub mod bga;
+pub mod block;
+pub mod cmos;
+pub mod debugcon;
+pub mod fw_cfg;
+pub mod pci;
+pub mod port;
+pub mod ramdisk;
+pub mod rtl8139;
+pub mod speaker;
+pub mod virtio;
+pub mod virtio_net;
@@ -0,0 +1,368 @@
+// ATA PIO driver --> the oldest and simplest way of talking to IDE hard disks: every 16 bit word goes through an I/O port
+// for an overview see: https://wiki.osdev.org/ATA_PIO_Mode
+//
+// a channel (bus) has 8 I/O ports starting at its base (0x1F0 for the primary channel) + a control port (0x3F6)
+// and up to two drives (master and slave), one of which is selected at a time through the drive/head register
+// every command follows the same pattern: select the drive, write the parameters, write the command, then poll the status
+// register until the drive isn't busy and either has data ready (DRQ) or reports an error (ERR/DF)
+//
+// we only use 28 bit LBA addressing (up to 128 GiB) and polling --> the drive's interrupt is switched off (nIEN)
+// every wait is bounded so a missing or broken drive turns into an AtaError instead of hanging the kernel
+use core::fmt;
+use spin::Mutex;
+use x86_64::instructions::port::{Port, PortReadOnly, PortWriteOnly};
+
+pub const SECTOR_SIZE: usize = 512;
+
+/// Largest sector number reachable with 28 bit LBA addressing (+ 1).
+pub const LBA28_LIMIT: u64 = 1 << 28;
+
+// how many times the status register is read before giving up --> each read is an I/O port access (~1us on real hardware)
+const POLL_LIMIT: u32 = 1_000_000;
+
+// status register bits
+const STATUS_ERR: u8 = 1 << 0; // an error occurred, details in the error register
+const STATUS_DRQ: u8 = 1 << 3; // the drive is ready to send/receive a sector of data
+const STATUS_DF: u8 = 1 << 5; // drive fault (doesn't set ERR)
+const STATUS_BSY: u8 = 1 << 7; // busy, every other bit is meaningless while this is set
+
+// device control register bits
+const CONTROL_NIEN: u8 = 1 << 1; // don't raise interrupts
+
+// commands
+const COMMAND_READ_SECTORS: u8 = 0x20;
+const COMMAND_WRITE_SECTORS: u8 = 0x30;
+const COMMAND_CACHE_FLUSH: u8 = 0xE7;
+const COMMAND_IDENTIFY: u8 = 0xEC;
+
+/// Errors reported by the ATA driver.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AtaError {
+    /// Nothing is attached at this position of the channel (or the channel itself is missing).
+    NoDevice,
+    /// The device answered but isn't an ATA hard disk (ex. an ATAPI CD-ROM or a SATA drive).
+    NotAta,
+    /// The drive didn't become ready in time.
+    Timeout,
+    /// The drive set the ERR bit, the value is its error register.
+    DeviceError(u8),
+    /// The drive set the DF (drive fault) bit.
+    DeviceFault,
+    /// The sectors lie past the end of the disk (or past what 28 bit LBA can address).
+    OutOfRange,
+    /// The buffer isn't exactly `count` sectors long, or `count` is zero.
+    BadBuffer,
+}
+
+/// Master or slave on a channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Drive {
+    Master,
+    Slave,
+}
+
+impl Drive {
+    fn select_bit(self) -> u8 {
+        match self {
+            Drive::Master => 0,
+            Drive::Slave => 1 << 4,
+        }
+    }
+}
+
+/// The I/O ports of an ATA channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Channel {
+    io_base: u16,
+    control_base: u16,
+}
+
+impl Channel {
+    pub const PRIMARY: Channel = Channel { io_base: 0x1F0, control_base: 0x3F6 };
+    pub const SECONDARY: Channel = Channel { io_base: 0x170, control_base: 0x376 };
+
+    fn data(&self) -> Port<u16> {
+        Port::new(self.io_base)
+    }
+
+    fn error(&self) -> PortReadOnly<u8> {
+        PortReadOnly::new(self.io_base + 1)
+    }
+
+    fn sector_count(&self) -> PortWriteOnly<u8> {
+        PortWriteOnly::new(self.io_base + 2)
+    }
+
+    // lba_low, lba_mid, lba_high
+    fn lba(&self, index: u16) -> Port<u8> {
+        Port::new(self.io_base + 3 + index)
+    }
+
+    fn drive_head(&self) -> PortWriteOnly<u8> {
+        PortWriteOnly::new(self.io_base + 6)
+    }
+
+    // reading clears a pending interrupt, writing sends a command
+    fn status(&self) -> PortReadOnly<u8> {
+        PortReadOnly::new(self.io_base + 7)
+    }
+
+    fn command(&self) -> PortWriteOnly<u8> {
+        PortWriteOnly::new(self.io_base + 7)
+    }
+
+    // reading is the "alternate status" (the status without side effects), writing is the device control register
+    fn alternate_status(&self) -> PortReadOnly<u8> {
+        PortReadOnly::new(self.control_base)
+    }
+
+    fn device_control(&self) -> PortWriteOnly<u8> {
+        PortWriteOnly::new(self.control_base)
+    }
+
+    // the drive needs ~400ns after being selected before its status is valid --> reading the alternate status 4 times takes that long
+    fn delay_400ns(&self) {
+        for _ in 0..4 {
+            unsafe { self.alternate_status().read() };
+        }
+    }
+
+    // select `drive` and write the top 4 bits of the LBA (0xE0 --> LBA mode + the two bits that are always set)
+    fn select(&self, drive: Drive, lba: u32) {
+        unsafe {
+            self.device_control().write(CONTROL_NIEN);
+            self.drive_head().write(0xE0 | drive.select_bit() | ((lba >> 24) & 0x0F) as u8);
+        }
+        self.delay_400ns();
+    }
+
+    fn wait_not_busy(&self) -> Result<u8, AtaError> {
+        for _ in 0..POLL_LIMIT {
+            let status = unsafe { self.alternate_status().read() };
+            if status & STATUS_BSY == 0 {
+                return Ok(status);
+            }
+        }
+        Err(AtaError::Timeout)
+    }
+
+    fn check_error(&self, status: u8) -> Result<(), AtaError> {
+        if status & STATUS_ERR != 0 {
+            return Err(AtaError::DeviceError(unsafe { self.error().read() }));
+        }
+        if status & STATUS_DF != 0 {
+            return Err(AtaError::DeviceFault);
+        }
+        Ok(())
+    }
+
+    // wait until the drive is ready to transfer a sector
+    fn wait_data_request(&self) -> Result<(), AtaError> {
+        for _ in 0..POLL_LIMIT {
+            let status = self.wait_not_busy()?;
+            self.check_error(status)?;
+            if status & STATUS_DRQ != 0 {
+                return Ok(());
+            }
+        }
+        Err(AtaError::Timeout)
+    }
+
+    fn start_command(&self, drive: Drive, lba: u32, count: u8, command: u8) -> Result<(), AtaError> {
+        self.select(drive, lba);
+        self.wait_not_busy()?;
+        unsafe {
+            self.sector_count().write(count);
+            self.lba(0).write(lba as u8);
+            self.lba(1).write((lba >> 8) as u8);
+            self.lba(2).write((lba >> 16) as u8);
+            self.command().write(command);
+        }
+        self.delay_400ns();
+        Ok(())
+    }
+}
+
+// only one command can be in flight per channel
+static PRIMARY_LOCK: Mutex<()> = Mutex::new(());
+static SECONDARY_LOCK: Mutex<()> = Mutex::new(());
+
+fn channel_lock(channel: Channel) -> &'static Mutex<()> {
+    if channel == Channel::PRIMARY {
+        &PRIMARY_LOCK
+    } else {
+        &SECONDARY_LOCK
+    }
+}
+
+/// An ATA hard disk found by IDENTIFY.
+#[derive(Clone, Copy)]
+pub struct AtaDevice {
+    channel: Channel,
+    drive: Drive,
+    model: [u8; 40],
+    sectors: u64,
+}
+
+impl AtaDevice {
+    /// Detect the drive at `drive` on `channel` with the IDENTIFY command.
+    pub fn identify(channel: Channel, drive: Drive) -> Result<AtaDevice, AtaError> {
+        let _lock = channel_lock(channel).lock();
+
+        // nothing connected pulls the bus high ("floating bus") --> the status reads 0xFF
+        if unsafe { channel.alternate_status().read() } == 0xFF {
+            return Err(AtaError::NoDevice);
+        }
+
+        channel.start_command(drive, 0, 0, COMMAND_IDENTIFY)?;
+        if unsafe { channel.status().read() } == 0 {
+            return Err(AtaError::NoDevice);
+        }
+        channel.wait_not_busy()?;
+        // ATAPI and SATA devices abort IDENTIFY and leave a signature in the LBA mid/high registers
+        let signature = unsafe { (channel.lba(1).read(), channel.lba(2).read()) };
+        if signature != (0, 0) {
+            return Err(AtaError::NotAta);
+        }
+        channel.wait_data_request()?;
+
+        let mut identify = [0u16; 256];
+        for word in identify.iter_mut() {
+            *word = unsafe { channel.data().read() };
+        }
+
+        // the model is an ascii string in words 27-46 with the two bytes of every word swapped
+        let mut model = [0u8; 40];
+        for (i, word) in identify[27..47].iter().enumerate() {
+            model[i * 2] = (word >> 8) as u8;
+            model[i * 2 + 1] = *word as u8;
+        }
+        // words 60-61 --> number of sectors reachable with 28 bit LBA
+        let sectors = u64::from(identify[60]) | (u64::from(identify[61]) << 16);
+
+        Ok(AtaDevice { channel, drive, model, sectors })
+    }
+
+    pub fn channel(&self) -> Channel {
+        self.channel
+    }
+
+    pub fn drive(&self) -> Drive {
+        self.drive
+    }
+
+    /// The model name reported by the drive.
+    pub fn model(&self) -> &str {
+        core::str::from_utf8(&self.model).unwrap_or("").trim()
+    }
+
+    /// Size of the disk in sectors.
+    pub fn sectors(&self) -> u64 {
+        self.sectors
+    }
+
+    /// Size of the disk in bytes.
+    pub fn size(&self) -> u64 {
+        self.sectors * SECTOR_SIZE as u64
+    }
+
+    fn check_request(&self, lba: u64, count: u8, buf_len: usize) -> Result<u32, AtaError> {
+        if count == 0 || buf_len != usize::from(count) * SECTOR_SIZE {
+            return Err(AtaError::BadBuffer);
+        }
+        let end = lba.checked_add(u64::from(count)).ok_or(AtaError::OutOfRange)?;
+        if end > self.sectors || end > LBA28_LIMIT {
+            return Err(AtaError::OutOfRange);
+        }
+        Ok(lba as u32)
+    }
+
+    /// Read `count` sectors starting at `lba` into `buf` (which has to be exactly `count` sectors long).
+    pub fn read_sectors(&self, lba: u64, count: u8, buf: &mut [u8]) -> Result<(), AtaError> {
+        let lba = self.check_request(lba, count, buf.len())?;
+        let _lock = channel_lock(self.channel).lock();
+
+        self.channel.start_command(self.drive, lba, count, COMMAND_READ_SECTORS)?;
+        for sector in buf.chunks_exact_mut(SECTOR_SIZE) {
+            self.channel.wait_data_request()?;
+            for bytes in sector.chunks_exact_mut(2) {
+                let word = unsafe { self.channel.data().read() };
+                bytes.copy_from_slice(&word.to_le_bytes());
+            }
+        }
+        Ok(())
+    }
+
+    /// Write `buf` (exactly `count` sectors) to the disk starting at `lba`, then flush the drive's write cache.
+    pub fn write_sectors(&self, lba: u64, count: u8, buf: &[u8]) -> Result<(), AtaError> {
+        let lba = self.check_request(lba, count, buf.len())?;
+        let _lock = channel_lock(self.channel).lock();
+
+        self.channel.start_command(self.drive, lba, count, COMMAND_WRITE_SECTORS)?;
+        for sector in buf.chunks_exact(SECTOR_SIZE) {
+            self.channel.wait_data_request()?;
+            for bytes in sector.chunks_exact(2) {
+                unsafe { self.channel.data().write(u16::from_le_bytes([bytes[0], bytes[1]])) };
+            }
+        }
+
+        // without the flush the data may only be in the drive's cache when we report success
+        unsafe { self.channel.command().write(COMMAND_CACHE_FLUSH) };
+        self.channel.delay_400ns();
+        let status = self.channel.wait_not_busy()?;
+        self.channel.check_error(status)
+    }
+}
+
+impl fmt::Debug for AtaDevice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AtaDevice")
+            .field("channel", &self.channel)
+            .field("drive", &self.drive)
+            .field("model", &self.model())
+            .field("sectors", &self.sectors)
+            .finish()
+    }
+}
+
+/// Identify the drives on both channels and register every ATA hard disk found as a block device,
+/// named by position: "ata0" (primary master), "ata1" (primary slave), "ata2" and "ata3" (secondary master and slave).
+///
+/// Returns the number of disks registered.
+pub fn register_drives() -> usize {
+    use crate::drivers::block;
+    use alloc::{format, sync::Arc};
+
+    let positions = [
+        (Channel::PRIMARY, Drive::Master),
+        (Channel::PRIMARY, Drive::Slave),
+        (Channel::SECONDARY, Drive::Master),
+        (Channel::SECONDARY, Drive::Slave),
+    ];
+    let mut registered = 0;
+    for (index, (channel, drive)) in positions.into_iter().enumerate() {
+        if let Ok(device) = AtaDevice::identify(channel, drive) {
+            if block::register(&format!("ata{}", index), Arc::new(device)).is_ok() {
+                registered += 1;
+            }
+        }
+    }
+    registered
+}
+
+// TESTS ===================================
+
+#[test_case]
+fn test_rejects_bad_requests_before_touching_hardware() {
+    let device = AtaDevice {
+        channel: Channel::PRIMARY,
+        drive: Drive::Slave,
+        model: [b' '; 40],
+        sectors: 8,
+    };
+    let mut buf = [0u8; SECTOR_SIZE * 2];
+    assert_eq!(device.read_sectors(0, 0, &mut buf), Err(AtaError::BadBuffer));
+    assert_eq!(device.read_sectors(0, 1, &mut buf), Err(AtaError::BadBuffer));
+    assert_eq!(device.read_sectors(7, 2, &mut buf), Err(AtaError::OutOfRange));
+    assert_eq!(device.write_sectors(u64::MAX - 1, 2, &buf), Err(AtaError::OutOfRange));
+}
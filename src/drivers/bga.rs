@@ -0,0 +1,103 @@
+// Bochs graphics adapter (BGA) --> the "VBE extensions" of QEMU's standard VGA card (and Bochs, VirtualBox)
+// see: https://wiki.osdev.org/Bochs_VBE_Extensions
+//
+// bootloader 0.9 leaves the card in text mode and its BootInfo has no framebuffer fields, so instead of asking the
+// firmware for a VBE mode (real mode only) we set one through the card's own registers:
+// - a 16 bit register is selected by writing its index to port 0x1CE and then read/written through port 0x1CF
+// - the linear framebuffer is at the card's PCI BAR 0 (vendor 0x1234, device 0x1111)
+// - pixels are stored little endian as blue, green, red (and an unused byte at 32 bpp), rows are `width * bpp / 8` apart
+use super::pci::{self, Bar};
+use x86_64::instructions::port::Port;
+use x86_64::PhysAddr;
+
+const INDEX_PORT: u16 = 0x01CE;
+const DATA_PORT: u16 = 0x01CF;
+
+const REG_ID: u16 = 0;
+const REG_XRES: u16 = 1;
+const REG_YRES: u16 = 2;
+const REG_BPP: u16 = 3;
+const REG_ENABLE: u16 = 4;
+
+const ENABLED: u16 = 0x01;
+const LFB_ENABLED: u16 = 0x40;
+
+// the oldest interface version with 32 bpp and the linear framebuffer
+const MIN_VERSION: u16 = 0xB0C4;
+const MAX_VERSION: u16 = 0xB0CF;
+
+pub const PCI_VENDOR: u16 = 0x1234;
+pub const PCI_DEVICE: u16 = 0x1111;
+
+/// A mode that was set by `set_mode()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BgaMode {
+    pub framebuffer: PhysAddr,
+    /// Size of the framebuffer BAR in bytes.
+    pub framebuffer_size: u64,
+    pub width: u16,
+    pub height: u16,
+    pub bpp: u16,
+}
+
+impl BgaMode {
+    /// Bytes from one row of pixels to the next.
+    pub fn pitch(&self) -> usize {
+        usize::from(self.width) * usize::from(self.bpp / 8)
+    }
+}
+
+fn write_register(register: u16, value: u16) {
+    unsafe {
+        Port::new(INDEX_PORT).write(register);
+        Port::new(DATA_PORT).write(value);
+    }
+}
+
+fn read_register(register: u16) -> u16 {
+    unsafe {
+        Port::new(INDEX_PORT).write(register);
+        Port::new(DATA_PORT).read()
+    }
+}
+
+/// Whether the VGA card understands the BGA registers.
+pub fn is_present() -> bool {
+    (MIN_VERSION..=MAX_VERSION).contains(&read_register(REG_ID))
+}
+
+// the card's framebuffer BAR (needs pci::init())
+fn framebuffer_bar() -> Option<(PhysAddr, u64)> {
+    let card = pci::devices().iter().find(|device| device.vendor_id == PCI_VENDOR && device.device_id == PCI_DEVICE)?;
+    card.bars.iter().find_map(|(index, bar)| match bar {
+        Bar::Memory { address, size, .. } if *index == 0 => Some((PhysAddr::new(*address), *size)),
+        _ => None,
+    })
+}
+
+/// Switch to a `width` x `height` graphics mode with `bpp` (24 or 32) bits per pixel and the linear framebuffer on.
+///
+/// `None` without a BGA card, for an unsupported depth or if the mode doesn't fit the framebuffer. The text mode is
+/// gone afterwards (the VGA buffer isn't shown anymore).
+pub fn set_mode(width: u16, height: u16, bpp: u16) -> Option<BgaMode> {
+    if !(bpp == 24 || bpp == 32) || !is_present() {
+        return None;
+    }
+    let (framebuffer, framebuffer_size) = framebuffer_bar()?;
+    let mode = BgaMode { framebuffer, framebuffer_size, width, height, bpp };
+    if (mode.pitch() * usize::from(height)) as u64 > framebuffer_size {
+        return None;
+    }
+    // the mode registers can only be changed while the extensions are off
+    write_register(REG_ENABLE, 0);
+    write_register(REG_XRES, width);
+    write_register(REG_YRES, height);
+    write_register(REG_BPP, bpp);
+    write_register(REG_ENABLE, ENABLED | LFB_ENABLED);
+    // the card clamps values it can't do
+    if read_register(REG_XRES) != width || read_register(REG_YRES) != height || read_register(REG_BPP) != bpp {
+        write_register(REG_ENABLE, 0);
+        return None;
+    }
+    Some(mode)
+}
@@ -0,0 +1,248 @@
+// Block devices --> storage read and written in fixed size blocks (ATA disks, RAM disks, ...)
+// filesystems only talk to the BlockDevice trait, so they don't care what the blocks are stored on
+// drivers register their devices under a name ("ata0", "ram0") in a global registry where everything else looks them up
+use crate::drivers::ata::{AtaDevice, AtaError, SECTOR_SIZE};
+use alloc::{collections::BTreeMap, string::String, sync::Arc, vec::Vec};
+use spin::Mutex;
+
+/// Errors reported by block devices and the registry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// The blocks lie past the end of the device.
+    OutOfRange,
+    /// The buffer isn't a (non zero) multiple of the block size.
+    BadBuffer,
+    /// A device with that name is already registered.
+    NameInUse,
+    /// The device can't be written to.
+    ReadOnly,
+    /// The ATA drive behind the device failed.
+    Ata(AtaError),
+}
+
+impl From<AtaError> for BlockError {
+    fn from(error: AtaError) -> Self {
+        match error {
+            AtaError::OutOfRange => BlockError::OutOfRange,
+            AtaError::BadBuffer => BlockError::BadBuffer,
+            error => BlockError::Ata(error),
+        }
+    }
+}
+
+/// A device storing `num_blocks()` blocks of `block_size()` bytes each.
+///
+/// Methods take `&self` so a device can be shared through the registry --> implementations lock internally.
+pub trait BlockDevice: Send + Sync {
+    fn block_size(&self) -> usize;
+    fn num_blocks(&self) -> u64;
+    /// Read block `lba` into `buf`, which has to be exactly one block long.
+    fn read_block(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError>;
+    /// Write `buf` (exactly one block) to block `lba`.
+    fn write_block(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError>;
+
+    /// Read consecutive blocks starting at `lba`, as many as fit in `buf` (a multiple of the block size).
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_range(self, lba, buf.len())?;
+        for (i, block) in buf.chunks_exact_mut(self.block_size()).enumerate() {
+            self.read_block(lba + i as u64, block)?;
+        }
+        Ok(())
+    }
+
+    /// Write `buf` (a multiple of the block size) to consecutive blocks starting at `lba`.
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        check_range(self, lba, buf.len())?;
+        for (i, block) in buf.chunks_exact(self.block_size()).enumerate() {
+            self.write_block(lba + i as u64, block)?;
+        }
+        Ok(())
+    }
+}
+
+/// Check that a transfer of `len` bytes starting at block `lba` is whole blocks and stays inside `device`,
+/// returns the number of blocks.
+pub fn check_range<D: BlockDevice + ?Sized>(device: &D, lba: u64, len: usize) -> Result<u64, BlockError> {
+    let block_size = device.block_size();
+    if len == 0 || len % block_size != 0 {
+        return Err(BlockError::BadBuffer);
+    }
+    let count = (len / block_size) as u64;
+    match lba.checked_add(count) {
+        Some(end) if end <= device.num_blocks() => Ok(count),
+        _ => Err(BlockError::OutOfRange),
+    }
+}
+
+// ATA DRIVES =============================
+
+impl BlockDevice for AtaDevice {
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.sectors()
+    }
+
+    fn read_block(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        Ok(self.read_sectors(lba, 1, buf)?)
+    }
+
+    fn write_block(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        Ok(self.write_sectors(lba, 1, buf)?)
+    }
+
+    // one command for up to 255 sectors instead of one per sector
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_range(self, lba, buf.len())?;
+        for (i, chunk) in buf.chunks_mut(SECTOR_SIZE * 255).enumerate() {
+            let count = (chunk.len() / SECTOR_SIZE) as u8;
+            self.read_sectors(lba + i as u64 * 255, count, chunk)?;
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        check_range(self, lba, buf.len())?;
+        for (i, chunk) in buf.chunks(SECTOR_SIZE * 255).enumerate() {
+            let count = (chunk.len() / SECTOR_SIZE) as u8;
+            self.write_sectors(lba + i as u64 * 255, count, chunk)?;
+        }
+        Ok(())
+    }
+}
+
+// REGISTRY =============================
+
+static DEVICES: Mutex<BTreeMap<String, Arc<dyn BlockDevice>>> = Mutex::new(BTreeMap::new());
+
+/// Make `device` available under `name`, fails if the name is taken.
+pub fn register(name: &str, device: Arc<dyn BlockDevice>) -> Result<(), BlockError> {
+    let mut devices = DEVICES.lock();
+    if devices.contains_key(name) {
+        return Err(BlockError::NameInUse);
+    }
+    devices.insert(String::from(name), device);
+    Ok(())
+}
+
+/// Remove the device registered under `name`, returning it.
+pub fn unregister(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    DEVICES.lock().remove(name)
+}
+
+/// The device registered under `name`.
+pub fn lookup(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    DEVICES.lock().get(name).cloned()
+}
+
+/// Names of all registered devices, sorted.
+pub fn device_names() -> Vec<String> {
+    DEVICES.lock().keys().cloned().collect()
+}
+
+// CONFORMANCE TEST =============================
+
+/// Check that `device` behaves like a block device should: single and multi block round trips (at the start, in the
+/// middle and at the end of the device) and rejection of out of range blocks and badly sized buffers.
+///
+/// Panics on the first failure. Meant for tests: the blocks it touches are restored afterwards, but the device
+/// must not be used by anything else while it runs.
+pub fn conformance_test(device: &dyn BlockDevice) {
+    use alloc::vec;
+
+    let block_size = device.block_size();
+    let num_blocks = device.num_blocks();
+    assert!(block_size > 0 && num_blocks >= 2, "device too small for the conformance test");
+
+    let mut saved = vec![0u8; block_size * 2];
+    let mut pattern = vec![0u8; block_size * 2];
+    let mut read = vec![0u8; block_size * 2];
+
+    for lba in [0, num_blocks / 2 - 1, num_blocks - 2] {
+        device.read_blocks(lba, &mut saved).expect("reading the original contents failed");
+        for (i, byte) in pattern.iter_mut().enumerate() {
+            *byte = (i as u64 ^ lba).wrapping_mul(31) as u8;
+        }
+
+        // two blocks at once, read back one at a time
+        device.write_blocks(lba, &pattern).expect("multi block write failed");
+        device.read_block(lba, &mut read[..block_size]).expect("read failed");
+        device.read_block(lba + 1, &mut read[block_size..]).expect("read failed");
+        assert!(read == pattern, "multi block write didn't round trip at block {}", lba);
+
+        // one block at a time, read back both at once
+        pattern.reverse();
+        device.write_block(lba, &pattern[..block_size]).expect("write failed");
+        device.write_block(lba + 1, &pattern[block_size..]).expect("write failed");
+        device.read_blocks(lba, &mut read).expect("multi block read failed");
+        assert!(read == pattern, "single block writes didn't round trip at block {}", lba);
+
+        device.write_blocks(lba, &saved).expect("restoring the original contents failed");
+    }
+
+    let block = &mut read[..block_size];
+    assert_eq!(device.read_block(num_blocks, block), Err(BlockError::OutOfRange));
+    assert_eq!(device.write_block(num_blocks, block), Err(BlockError::OutOfRange));
+    assert_eq!(device.read_blocks(num_blocks - 1, &mut read), Err(BlockError::OutOfRange));
+    assert_eq!(device.write_blocks(u64::MAX, &pattern), Err(BlockError::OutOfRange));
+    assert_eq!(device.read_block(0, &mut read[..block_size - 1]), Err(BlockError::BadBuffer));
+    assert_eq!(device.read_blocks(0, &mut []), Err(BlockError::BadBuffer));
+}
+
+// TESTS ===================================
+
+// a minimal device on the heap, enough to check the trait's provided methods and the registry
+#[cfg(test)]
+struct VecDevice {
+    data: Mutex<Vec<u8>>,
+}
+
+#[cfg(test)]
+impl BlockDevice for VecDevice {
+    fn block_size(&self) -> usize {
+        64
+    }
+
+    fn num_blocks(&self) -> u64 {
+        (self.data.lock().len() / 64) as u64
+    }
+
+    fn read_block(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        if buf.len() != 64 {
+            return Err(BlockError::BadBuffer);
+        }
+        check_range(self, lba, 64)?;
+        let start = lba as usize * 64;
+        buf.copy_from_slice(&self.data.lock()[start..start + 64]);
+        Ok(())
+    }
+
+    fn write_block(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        if buf.len() != 64 {
+            return Err(BlockError::BadBuffer);
+        }
+        check_range(self, lba, 64)?;
+        let start = lba as usize * 64;
+        self.data.lock()[start..start + 64].copy_from_slice(buf);
+        Ok(())
+    }
+}
+
+#[test_case]
+fn test_conformance_on_memory_device() {
+    let device = VecDevice { data: Mutex::new(alloc::vec![0; 64 * 16]) };
+    conformance_test(&device);
+}
+
+#[test_case]
+fn test_registry() {
+    let device: Arc<dyn BlockDevice> = Arc::new(VecDevice { data: Mutex::new(alloc::vec![0; 64 * 4]) });
+    register("test0", device.clone()).expect("register failed");
+    assert_eq!(register("test0", device), Err(BlockError::NameInUse));
+    assert!(device_names().iter().any(|name| name == "test0"));
+    assert_eq!(lookup("test0").expect("lookup failed").num_blocks(), 4);
+    assert!(unregister("test0").is_some());
+    assert!(lookup("test0").is_none());
+}
@@ -0,0 +1,329 @@
+// CMOS --> the 128 bytes of battery backed RAM next to the RTC (real time clock), reached through two I/O ports
+// for an overview see: https://wiki.osdev.org/CMOS
+//
+// every access writes the register number to the index port (0x70), then reads or writes the data port (0x71)
+// - bit 7 of the index byte is the NMI ("non maskable interrupt") disable bit, so every index write also sets whether
+//   NMIs are disabled --> it is always written according to the module wide policy (see set_nmi_disabled()) instead of
+//   whatever a caller happened to pass in
+// - the chip needs a moment between the two accesses, a write to the unused port 0x80 is the usual delay
+// - an index/data pair must never be split by another CMOS user (the data port would belong to the wrong register)
+//   --> all accesses go through one spinlock, taken with interrupts disabled
+use super::port::{HardwarePorts, PortIo};
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+pub const CMOS_INDEX: u16 = 0x70;
+pub const CMOS_DATA: u16 = 0x71;
+const DELAY_PORT: u16 = 0x80; // POST diagnostic port, writing to it takes ~1us and has no effect
+
+const NMI_DISABLE: u8 = 1 << 7;
+const REGISTER_MASK: u8 = 0x7F;
+
+// RTC registers
+pub const RTC_SECONDS: u8 = 0x00;
+pub const RTC_MINUTES: u8 = 0x02;
+pub const RTC_HOURS: u8 = 0x04;
+pub const RTC_WEEKDAY: u8 = 0x06;
+pub const RTC_DAY_OF_MONTH: u8 = 0x07;
+pub const RTC_MONTH: u8 = 0x08;
+pub const RTC_YEAR: u8 = 0x09;
+pub const RTC_STATUS_A: u8 = 0x0A;
+pub const RTC_STATUS_B: u8 = 0x0B;
+pub const RTC_STATUS_C: u8 = 0x0C;
+/// Century, where most BIOSes put it (the ACPI FADT has the authoritative register number).
+pub const RTC_CENTURY: u8 = 0x32;
+
+/// What the BIOS does after a CPU reset (read by the BIOS on reset, ex. when starting application processors).
+pub const SHUTDOWN_STATUS: u8 = 0x0F;
+
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2; // values are binary instead of BCD
+const HOURS_PM: u8 = 1 << 7; // in 12 hour mode
+
+// how many times status A is checked for the end of an RTC update (an update takes < 2ms)
+const UPDATE_POLL_LIMIT: u32 = 100_000;
+
+/// Values of the shutdown status register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ShutdownStatus {
+    /// Normal POST on reset.
+    SoftReset = 0x00,
+    /// Skip POST and jump through the warm reset vector at 0x40:0x67 (used to start application processors).
+    JumpWarmResetVector = 0x0A,
+}
+
+// the NMI policy applied to every index write, NMIs are enabled unless someone asks otherwise
+static NMI_DISABLED: AtomicBool = AtomicBool::new(false);
+
+/// Set whether NMIs are disabled, written along with the next CMOS access (and every one after).
+pub fn set_nmi_disabled(disabled: bool) {
+    NMI_DISABLED.store(disabled, Ordering::Relaxed);
+}
+
+pub fn nmi_disabled() -> bool {
+    NMI_DISABLED.load(Ordering::Relaxed)
+}
+
+/// The CMOS behind some ports.
+pub struct Cmos<P: PortIo> {
+    ports: P,
+}
+
+impl<P: PortIo> Cmos<P> {
+    pub const fn new(ports: P) -> Self {
+        Cmos { ports }
+    }
+
+    // write the index (with the NMI bit) and wait for the chip
+    fn select(&mut self, register: u8, nmi_disabled: bool) {
+        let nmi_bit = if nmi_disabled { NMI_DISABLE } else { 0 };
+        self.ports.write_u8(CMOS_INDEX, register & REGISTER_MASK | nmi_bit);
+        self.ports.write_u8(DELAY_PORT, 0);
+    }
+
+    /// Read `register` (0-127), setting the NMI disable bit to `nmi_disabled`.
+    pub fn read(&mut self, register: u8, nmi_disabled: bool) -> u8 {
+        self.select(register, nmi_disabled);
+        self.ports.read_u8(CMOS_DATA)
+    }
+
+    /// Write `value` to `register` (0-127), setting the NMI disable bit to `nmi_disabled`.
+    pub fn write(&mut self, register: u8, value: u8, nmi_disabled: bool) {
+        self.select(register, nmi_disabled);
+        self.ports.write_u8(CMOS_DATA, value);
+    }
+}
+
+static CMOS: Mutex<Cmos<HardwarePorts>> = Mutex::new(Cmos::new(HardwarePorts));
+
+/// Read CMOS register `register` (0-127).
+pub fn read(register: u8) -> u8 {
+    interrupts::without_interrupts(|| CMOS.lock().read(register, nmi_disabled()))
+}
+
+/// Write `value` to CMOS register `register` (0-127).
+pub fn write(register: u8, value: u8) {
+    interrupts::without_interrupts(|| CMOS.lock().write(register, value, nmi_disabled()))
+}
+
+pub fn shutdown_status() -> u8 {
+    read(SHUTDOWN_STATUS)
+}
+
+pub fn set_shutdown_status(status: ShutdownStatus) {
+    write(SHUTDOWN_STATUS, status as u8);
+}
+
+// RTC =============================
+
+/// A date and time read from the RTC (in whatever time zone the RTC is set to, usually UTC).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RtcTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8, // 0-23
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl fmt::Display for RtcTime {
+    /// "2024-02-29 23:59:59"
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+fn bcd_to_binary(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0F)
+}
+
+// the raw time registers, in one lock so nothing else can touch the CMOS in between
+fn read_raw_time<P: PortIo>(cmos: &mut Cmos<P>, nmi_disabled: bool) -> [u8; 7] {
+    [RTC_SECONDS, RTC_MINUTES, RTC_HOURS, RTC_DAY_OF_MONTH, RTC_MONTH, RTC_YEAR, RTC_CENTURY]
+        .map(|register| cmos.read(register, nmi_disabled))
+}
+
+// the RTC updates its registers once a second --> wait for an update to finish, then read until two reads agree
+fn read_time_with<P: PortIo>(cmos: &mut Cmos<P>, nmi_disabled: bool) -> RtcTime {
+    let wait_for_update = |cmos: &mut Cmos<P>| {
+        for _ in 0..UPDATE_POLL_LIMIT {
+            if cmos.read(RTC_STATUS_A, nmi_disabled) & STATUS_A_UPDATE_IN_PROGRESS == 0 {
+                break;
+            }
+        }
+    };
+    wait_for_update(cmos);
+    let mut raw = read_raw_time(cmos, nmi_disabled);
+    loop {
+        wait_for_update(cmos);
+        let again = read_raw_time(cmos, nmi_disabled);
+        if again == raw {
+            break;
+        }
+        raw = again;
+    }
+
+    let status_b = cmos.read(RTC_STATUS_B, nmi_disabled);
+    let [second, minute, hour, day, month, year, century] = raw;
+    let pm = hour & HOURS_PM != 0;
+    let decode = |value: u8| if status_b & STATUS_B_BINARY != 0 { value } else { bcd_to_binary(value) };
+    let mut hour = decode(hour & !HOURS_PM);
+    if status_b & STATUS_B_24_HOUR == 0 {
+        hour %= 12; // 12 AM is midnight
+        if pm {
+            hour += 12;
+        }
+    }
+    let century = match decode(century) {
+        0 => 20, // no century register
+        century => century,
+    };
+    RtcTime {
+        year: u16::from(century) * 100 + u16::from(decode(year)),
+        month: decode(month),
+        day: decode(day),
+        hour,
+        minute: decode(minute),
+        second: decode(second),
+    }
+}
+
+/// The current date and time from the RTC.
+pub fn read_rtc_time() -> RtcTime {
+    interrupts::without_interrupts(|| read_time_with(&mut CMOS.lock(), nmi_disabled()))
+}
+
+// TESTS ===================================
+
+// a fake CMOS that records every port access
+#[cfg(test)]
+struct RecordingPorts {
+    memory: [u8; 128],
+    index: u8,
+    log: alloc::vec::Vec<(char, u16, u8)>, // ('r' or 'w', port, value)
+}
+
+#[cfg(test)]
+impl RecordingPorts {
+    fn new() -> Self {
+        RecordingPorts { memory: [0; 128], index: 0, log: alloc::vec::Vec::new() }
+    }
+}
+
+#[cfg(test)]
+impl PortIo for RecordingPorts {
+    fn read_u8(&mut self, port: u16) -> u8 {
+        assert_eq!(port, CMOS_DATA, "unexpected read");
+        let value = self.memory[usize::from(self.index & REGISTER_MASK)];
+        self.log.push(('r', port, value));
+        value
+    }
+
+    fn write_u8(&mut self, port: u16, value: u8) {
+        match port {
+            CMOS_INDEX => self.index = value,
+            CMOS_DATA => self.memory[usize::from(self.index & REGISTER_MASK)] = value,
+            _ => {}
+        }
+        self.log.push(('w', port, value));
+    }
+}
+
+#[test_case]
+fn test_cmos_register_constants() {
+    assert_eq!((RTC_SECONDS, RTC_MINUTES, RTC_HOURS), (0x00, 0x02, 0x04));
+    assert_eq!((RTC_WEEKDAY, RTC_DAY_OF_MONTH, RTC_MONTH, RTC_YEAR), (0x06, 0x07, 0x08, 0x09));
+    assert_eq!((RTC_STATUS_A, RTC_STATUS_B, RTC_STATUS_C), (0x0A, 0x0B, 0x0C));
+    assert_eq!((SHUTDOWN_STATUS, RTC_CENTURY), (0x0F, 0x32));
+    assert_eq!(ShutdownStatus::JumpWarmResetVector as u8, 0x0A);
+    assert_eq!((CMOS_INDEX, CMOS_DATA), (0x70, 0x71));
+}
+
+#[test_case]
+fn test_cmos_nmi_bit() {
+    let mut cmos = Cmos::new(RecordingPorts::new());
+    cmos.write(SHUTDOWN_STATUS, 0x0A, true);
+    // a register number with bit 7 set can't flip the NMI bit
+    assert_eq!(cmos.read(SHUTDOWN_STATUS | 0x80, false), 0x0A);
+    assert_eq!(cmos.ports.log, [
+        ('w', CMOS_INDEX, 0x8F),
+        ('w', DELAY_PORT, 0),
+        ('w', CMOS_DATA, 0x0A),
+        ('w', CMOS_INDEX, 0x0F),
+        ('w', DELAY_PORT, 0),
+        ('r', CMOS_DATA, 0x0A),
+    ]);
+}
+
+#[test_case]
+fn test_cmos_pairs_not_interleaved() {
+    use crate::task::{executor::Executor, yield_now, Task};
+    use alloc::{sync::Arc, vec::Vec};
+
+    // several tasks taking turns after every access, each access goes through the lock like read()/write() do
+    let cmos = Arc::new(Mutex::new(Cmos::new(RecordingPorts::new())));
+    let mut executor = Executor::new();
+    for task in 0..4u8 {
+        let cmos = cmos.clone();
+        executor.spawn(Task::new(async move {
+            for i in 0..20u8 {
+                let register = 0x10 + task;
+                interrupts::without_interrupts(|| cmos.lock().write(register, i, false));
+                yield_now().await;
+                assert_eq!(interrupts::without_interrupts(|| cmos.lock().read(register, false)), i);
+                yield_now().await;
+            }
+        }));
+    }
+    executor.run_until_idle();
+
+    // every data access comes right after its own index write and delay
+    let cmos = cmos.lock();
+    let log = &cmos.ports.log;
+    assert_eq!(log.len(), 4 * 20 * 2 * 3);
+    for pair in log.chunks(3) {
+        assert_eq!((pair[0].0, pair[0].1), ('w', CMOS_INDEX));
+        assert_eq!((pair[1].0, pair[1].1), ('w', DELAY_PORT));
+        assert_eq!(pair[2].1, CMOS_DATA);
+    }
+    // ...while the tasks really did take turns: all 4 write, then all 4 read back, 20 times over
+    let turns: Vec<(char, u8)> = log.chunks(3).map(|pair| (pair[2].0, (pair[0].2 & REGISTER_MASK) - 0x10)).collect();
+    let expected: Vec<(char, u8)> = (0..20).flat_map(|_| ['w', 'r']).flat_map(|access| (0..4).map(move |task| (access, task))).collect();
+    assert_eq!(turns, expected);
+}
+
+#[test_case]
+fn test_rtc_time_decoding() {
+    let mut ports = RecordingPorts::new();
+    // 2024-02-29 11:59:58 PM in BCD, 12 hour mode
+    ports.memory[usize::from(RTC_SECONDS)] = 0x58;
+    ports.memory[usize::from(RTC_MINUTES)] = 0x59;
+    ports.memory[usize::from(RTC_HOURS)] = HOURS_PM | 0x11;
+    ports.memory[usize::from(RTC_DAY_OF_MONTH)] = 0x29;
+    ports.memory[usize::from(RTC_MONTH)] = 0x02;
+    ports.memory[usize::from(RTC_YEAR)] = 0x24;
+    ports.memory[usize::from(RTC_CENTURY)] = 0x20;
+    let mut cmos = Cmos::new(ports);
+    let expected = RtcTime { year: 2024, month: 2, day: 29, hour: 23, minute: 59, second: 58 };
+    assert_eq!(read_time_with(&mut cmos, false), expected);
+
+    // the same time in binary, 24 hour mode
+    cmos.ports.memory[usize::from(RTC_STATUS_B)] = STATUS_B_BINARY | STATUS_B_24_HOUR;
+    for (register, value) in [(RTC_SECONDS, 58), (RTC_MINUTES, 59), (RTC_HOURS, 23), (RTC_DAY_OF_MONTH, 29), (RTC_MONTH, 2), (RTC_YEAR, 24), (RTC_CENTURY, 20)] {
+        cmos.ports.memory[usize::from(register)] = value;
+    }
+    assert_eq!(read_time_with(&mut cmos, false), expected);
+
+    // the real clock gives something plausible
+    let now = read_rtc_time();
+    assert!(now.year >= 2000 && (1..=12).contains(&now.month) && now.hour < 24);
+}
@@ -0,0 +1,55 @@
+// Bochs/QEMU debug console --> every byte written to port 0xE9 shows up on the host (QEMU: `-debugcon stdio`, or
+// `-debugcon file:debugcon.log` next to `-serial stdio`)
+// - no state and no setup, unlike the UART --> works from the first instruction on, even before early_print()'s VGA
+//   buffer is of any use (ex. with the framebuffer on)
+// - on hardware (or QEMU without -debugcon) nothing listens on the port and the writes go nowhere
+// - with the `debugcon` feature it gets a copy of early_print() and all print! output (see init() in lib.rs)
+use crate::console::ConsoleOutput;
+use x86_64::instructions::port::PortWriteOnly;
+
+pub const PORT: u16 = 0xE9;
+
+/// The debug console as a console output.
+pub struct DebugCon;
+
+pub static DEBUGCON: DebugCon = DebugCon;
+
+/// Send `s` to the debug console, byte by byte.
+pub fn write_str(s: &str) {
+    let mut port: PortWriteOnly<u8> = PortWriteOnly::new(PORT);
+    for byte in s.bytes() {
+        unsafe { port.write(byte) };
+    }
+}
+
+/// The debug console as a `fmt::Write`, for code that can't go through the console (ex. panic handlers).
+pub struct DebugConWriter;
+
+impl core::fmt::Write for DebugConWriter {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        write_str(s);
+        Ok(())
+    }
+}
+
+impl ConsoleOutput for DebugCon {
+    fn write_str(&self, s: &str) {
+        write_str(s);
+    }
+}
+
+/// Give the debug console a copy of all print! output.
+pub fn register() -> bool {
+    crate::console::register(&DEBUGCON)
+}
+
+// TESTS ===================================
+
+#[test_case]
+fn test_debugcon_long_string() {
+    // what arrives on the host can't be checked from in here, only that writing doesn't fail
+    let line = "debugcon test line 0123456789 abcdefghijklmnopqrstuvwxyz\n";
+    let text = line.repeat(4096 / line.len() + 1);
+    assert!(text.len() > 4096);
+    DEBUGCON.write_str(&text);
+}
@@ -0,0 +1,293 @@
+// QEMU firmware configuration (fw_cfg) --> named blobs the host hands to the guest, ex. `-fw_cfg name=opt/x,string=...`
+// see: https://www.qemu.org/docs/master/specs/fw_cfg.html
+// - an item is selected by writing its 16 bit key to port 0x510, then its bytes are read one by one from port 0x511
+// - key 0x0000 is the signature ("QEMU"), 0x0001 the feature bits (bit 1: DMA), 0x0019 the file directory: a big endian
+//   count followed by 64 byte entries of big endian size, key and a 56 byte NUL padded name
+// - the fixed keys below 0x0020 (RAM size, number of CPUs...) are little endian and have no size in the directory,
+//   read_key() reads as many bytes as asked for
+// - with DMA a whole item is copied into memory in one go: a 16 byte access struct (all fields big endian) describes the
+//   read and its physical address is written (big endian too) to port 0x514 (high half) and 0x518 (low half, starts it)
+// - without fw_cfg (real hardware, other emulators) the signature doesn't match and every lookup is `None`
+use crate::memory::dma::DmaBuffer;
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::{Port, PortWriteOnly};
+
+const SELECTOR_PORT: u16 = 0x510;
+const DATA_PORT: u16 = 0x511;
+const DMA_ADDRESS_HIGH_PORT: u16 = 0x514;
+const DMA_ADDRESS_LOW_PORT: u16 = 0x518;
+
+pub const KEY_SIGNATURE: u16 = 0x0000;
+pub const KEY_FEATURES: u16 = 0x0001;
+/// RAM size in bytes (u64).
+pub const KEY_RAM_SIZE: u16 = 0x0003;
+/// Number of CPUs (u16).
+pub const KEY_NB_CPUS: u16 = 0x0005;
+pub const KEY_FILE_DIR: u16 = 0x0019;
+
+const SIGNATURE: [u8; 4] = *b"QEMU";
+const FEATURE_DMA: u32 = 1 << 1;
+
+// control bits of a DMA access, the selected key goes in the upper 16 bits
+const DMA_ERROR: u32 = 1 << 0;
+const DMA_READ: u32 = 1 << 1;
+const DMA_SELECT: u32 = 1 << 3;
+const DMA_ACCESS_SIZE: usize = 16;
+
+/// Size of a file directory entry in bytes.
+pub const FILE_ENTRY_SIZE: usize = 64;
+const FILE_NAME_SIZE: usize = 56;
+
+/// Where the kernel command line is looked up, see `config::boot_cmdline()`.
+/// (`-fw_cfg name=opt/org.mini_os/cmdline,string="timer_hz=100"`)
+pub const CMDLINE_FILE: &str = "opt/org.mini_os/cmdline";
+
+/// Which tests run, see `test_runner()` in lib.rs.
+/// (`-fw_cfg name=opt/org.mini_os/test-filter,string="heap !many_boxes"`)
+pub const TEST_FILTER_FILE: &str = "opt/org.mini_os/test-filter";
+
+/// An entry of the file directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileEntry {
+    pub size: u32,
+    /// The key to select the file with.
+    pub key: u16,
+    name: [u8; FILE_NAME_SIZE],
+}
+
+impl FileEntry {
+    /// Decode a directory entry (the numbers are big endian).
+    pub fn parse(bytes: &[u8; FILE_ENTRY_SIZE]) -> FileEntry {
+        let mut name = [0; FILE_NAME_SIZE];
+        name.copy_from_slice(&bytes[8..]);
+        FileEntry {
+            size: u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            key: u16::from_be_bytes([bytes[4], bytes[5]]),
+            name,
+        }
+    }
+
+    /// The name up to the first NUL, empty if it isn't UTF-8.
+    pub fn name(&self) -> &str {
+        let len = self.name.iter().position(|&byte| byte == 0).unwrap_or(FILE_NAME_SIZE);
+        core::str::from_utf8(&self.name[..len]).unwrap_or("")
+    }
+}
+
+// the selected item is state in the device --> one reader at a time
+static LOCK: Mutex<()> = Mutex::new(());
+
+fn select(key: u16) {
+    unsafe { PortWriteOnly::<u16>::new(SELECTOR_PORT).write(key) };
+}
+
+// the next bytes of the selected item
+fn read_bytes(buf: &mut [u8]) {
+    let mut data: Port<u8> = Port::new(DATA_PORT);
+    for byte in buf {
+        *byte = unsafe { data.read() };
+    }
+}
+
+// run `f` with the lock held, `None` without a fw_cfg device
+fn with_device<R>(f: impl FnOnce() -> Option<R>) -> Option<R> {
+    interrupts::without_interrupts(|| {
+        let _lock = LOCK.lock();
+        let mut signature = [0; 4];
+        select(KEY_SIGNATURE);
+        read_bytes(&mut signature);
+        if signature != SIGNATURE {
+            return None;
+        }
+        f()
+    })
+}
+
+/// Whether QEMU's fw_cfg device is there.
+pub fn is_present() -> bool {
+    with_device(|| Some(())).is_some()
+}
+
+/// Read the first `buf.len()` bytes of the item `key` through the I/O ports, false without fw_cfg.
+///
+/// Reading past the end of an item gives zeros. Doesn't allocate.
+pub fn read_key(key: u16, buf: &mut [u8]) -> bool {
+    with_device(|| {
+        select(key);
+        read_bytes(buf);
+        Some(())
+    })
+    .is_some()
+}
+
+/// The guest's RAM size as QEMU sees it (`-m`).
+pub fn ram_size() -> Option<u64> {
+    let mut bytes = [0; 8];
+    read_key(KEY_RAM_SIZE, &mut bytes).then(|| u64::from_le_bytes(bytes))
+}
+
+// the feature bits
+fn features() -> u32 {
+    let mut bytes = [0; 4];
+    select(KEY_FEATURES);
+    read_bytes(&mut bytes);
+    u32::from_le_bytes(bytes)
+}
+
+// walk the file directory until `f` returns something
+fn find_in_directory<R>(mut f: impl FnMut(&FileEntry) -> Option<R>) -> Option<R> {
+    let mut count = [0; 4];
+    select(KEY_FILE_DIR);
+    read_bytes(&mut count);
+    for _ in 0..u32::from_be_bytes(count) {
+        let mut bytes = [0; FILE_ENTRY_SIZE];
+        read_bytes(&mut bytes);
+        if let Some(result) = f(&FileEntry::parse(&bytes)) {
+            return Some(result);
+        }
+    }
+    None
+}
+
+/// The directory entry of the file `name`.
+pub fn find_file(name: &str) -> Option<FileEntry> {
+    with_device(|| find_in_directory(|entry| (entry.name() == name).then_some(*entry)))
+}
+
+/// All entries of the file directory (empty without fw_cfg).
+pub fn files() -> Vec<FileEntry> {
+    let mut files = Vec::new();
+    with_device(|| {
+        find_in_directory(|entry| {
+            files.push(*entry);
+            None::<()>
+        })
+    });
+    files
+}
+
+/// Read the start of the file `name` into `buf` through the I/O ports, returns the size of the whole file.
+///
+/// Doesn't allocate, so it works before the heap is set up (ex. for the kernel command line).
+pub fn read_file_into(name: &str, buf: &mut [u8]) -> Option<usize> {
+    with_device(|| {
+        let entry = find_in_directory(|entry| (entry.name() == name).then_some(*entry))?;
+        let size = entry.size as usize;
+        select(entry.key);
+        read_bytes(&mut buf[..size.min(buf.len())]);
+        Some(size)
+    })
+}
+
+// copy the item `key` into `buf` by DMA, false if the device reported an error
+fn dma_read(key: u16, buf: &mut [u8]) -> bool {
+    let dma = match DmaBuffer::new(DMA_ACCESS_SIZE + buf.len()) {
+        Some(dma) => dma,
+        None => return false,
+    };
+    let control = u32::from(key) << 16 | DMA_SELECT | DMA_READ;
+    let data_addr = dma.phys_addr().as_u64() + DMA_ACCESS_SIZE as u64;
+    let access = dma.as_mut_ptr();
+    unsafe {
+        let mut bytes = [0u8; DMA_ACCESS_SIZE];
+        bytes[..4].copy_from_slice(&control.to_be_bytes());
+        bytes[4..8].copy_from_slice(&(buf.len() as u32).to_be_bytes());
+        bytes[8..].copy_from_slice(&data_addr.to_be_bytes());
+        crate::memory::volatile_copy_to(access, bytes.as_ptr(), DMA_ACCESS_SIZE);
+
+        let addr = dma.phys_addr().as_u64();
+        PortWriteOnly::<u32>::new(DMA_ADDRESS_HIGH_PORT).write(((addr >> 32) as u32).to_be());
+        PortWriteOnly::<u32>::new(DMA_ADDRESS_LOW_PORT).write((addr as u32).to_be());
+
+        // QEMU finishes before the port write returns, the loop is for anything that doesn't
+        let control = loop {
+            let control = u32::from_be((access as *const u32).read_volatile());
+            if control & !DMA_ERROR == 0 {
+                break control;
+            }
+            core::hint::spin_loop();
+        };
+        if control & DMA_ERROR != 0 {
+            return false;
+        }
+        core::ptr::copy_nonoverlapping(access.add(DMA_ACCESS_SIZE), buf.as_mut_ptr(), buf.len());
+    }
+    true
+}
+
+/// The contents of the file `name` (by DMA if the device can, otherwise through the I/O ports).
+pub fn read_file(name: &str) -> Option<Vec<u8>> {
+    with_device(|| {
+        let entry = find_in_directory(|entry| (entry.name() == name).then_some(*entry))?;
+        let mut contents = vec![0; entry.size as usize];
+        if features() & FEATURE_DMA == 0 || !dma_read(entry.key, &mut contents) {
+            select(entry.key);
+            read_bytes(&mut contents);
+        }
+        Some(contents)
+    })
+}
+
+// TESTS ===================================
+
+// the test runs pass `-fw_cfg name=opt/org.mini_os/test,string=...` (see Cargo.toml)
+#[cfg(test)]
+const TEST_FILE: (&str, &[u8]) = ("opt/org.mini_os/test", b"fw_cfg test string 0123456789");
+
+#[test_case]
+fn test_parse_file_entry() {
+    let mut bytes = [0u8; FILE_ENTRY_SIZE];
+    bytes[..4].copy_from_slice(&[0x00, 0x00, 0x01, 0x02]);
+    bytes[4..6].copy_from_slice(&[0x00, 0x20]);
+    bytes[8..8 + 9].copy_from_slice(b"etc/e820\0");
+    let entry = FileEntry::parse(&bytes);
+    assert_eq!(entry.size, 0x102);
+    assert_eq!(entry.key, 0x20);
+    assert_eq!(entry.name(), "etc/e820");
+}
+
+#[test_case]
+fn test_read_injected_file() {
+    let (name, contents) = TEST_FILE;
+    assert!(is_present());
+    assert!(files().iter().any(|entry| entry.name() == name));
+    assert_eq!(read_file(name).as_deref(), Some(contents));
+
+    let mut buf = [0u8; 64];
+    assert_eq!(read_file_into(name, &mut buf), Some(contents.len()));
+    assert_eq!(&buf[..contents.len()], contents);
+    // too small a buffer gets the start
+    let mut short = [0u8; 6];
+    assert_eq!(read_file_into(name, &mut short), Some(contents.len()));
+    assert_eq!(&short, &contents[..6]);
+}
+
+#[test_case]
+fn test_missing_file() {
+    assert_eq!(find_file("opt/org.mini_os/missing"), None);
+    assert_eq!(read_file("opt/org.mini_os/missing"), None);
+    assert_eq!(read_file_into("opt/org.mini_os/missing", &mut [0; 8]), None);
+}
+
+#[test_case]
+fn test_ram_size_matches_memory_map() {
+    use bootloader::bootinfo::MemoryRegionType;
+
+    let mut signature = [0u8; 4];
+    assert!(read_key(KEY_SIGNATURE, &mut signature));
+    assert_eq!(&signature, b"QEMU");
+
+    let ram_size = ram_size().expect("no fw_cfg");
+    // below 3 GiB all of the RAM is in one piece, the BIOS keeps a little at its end for the ACPI tables
+    let usable_end = crate::memory::memory_map()
+        .iter()
+        .filter(|region| region.region_type == MemoryRegionType::Usable)
+        .map(|region| region.range.end_addr())
+        .max()
+        .expect("no usable memory");
+    assert!(usable_end <= ram_size, "usable memory up to {:#x} but only {:#x} of RAM", usable_end, ram_size);
+    assert!(usable_end >= ram_size - 2 * 1024 * 1024, "{:#x} of RAM but usable memory ends at {:#x}", ram_size, usable_end);
+}
@@ -0,0 +1,326 @@
+// PCI --> finding the devices on the PCI bus (network cards, disk controllers, the graphics card...)
+// for an overview see: https://wiki.osdev.org/PCI
+//
+// every function of every device has 256 bytes of configuration space, reached through two 32 bit I/O ports
+// ("configuration mechanism #1"): write the bus/device/function/register to CONFIG_ADDRESS (0xCF8), then read or
+// write the register through CONFIG_DATA (0xCFC)
+// - a function that doesn't exist reads as vendor 0xFFFF
+// - bit 7 of the header type says whether a device has more than one function (functions 1-7 are only checked then)
+// - BARs ("base address registers") hold where a device's memory or I/O ports are mapped; writing all ones and reading
+//   back gives the size (the address bits the device doesn't decode stay zero), after which the original value is
+//   restored --> decoding is switched off in the command register meanwhile so the half written BAR isn't used
+use alloc::vec::Vec;
+use core::fmt;
+use spin::{Mutex, Once};
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+const CONFIG_ADDRESS: u16 = 0xCF8;
+const CONFIG_DATA: u16 = 0xCFC;
+
+// configuration space registers (all accessed as aligned u32s)
+const REG_ID: u8 = 0x00; // device id << 16 | vendor id
+const REG_COMMAND: u8 = 0x04; // status << 16 | command
+const REG_CLASS: u8 = 0x08; // class << 24 | subclass << 16 | prog if << 8 | revision
+const REG_HEADER: u8 = 0x0C; // bist << 24 | header type << 16 | latency timer << 8 | cache line size
+const REG_BAR0: u8 = 0x10;
+const REG_INTERRUPT: u8 = 0x3C; // ... | interrupt pin << 8 | interrupt line
+
+const VENDOR_NONE: u16 = 0xFFFF;
+const HEADER_MULTI_FUNCTION: u8 = 1 << 7;
+const HEADER_TYPE_MASK: u8 = 0x7F;
+const COMMAND_IO_SPACE: u32 = 1 << 0;
+const COMMAND_MEMORY_SPACE: u32 = 1 << 1;
+const COMMAND_BUS_MASTER: u32 = 1 << 2;
+
+const BAR_IO: u32 = 1 << 0;
+const BAR_TYPE_MASK: u32 = 0b110;
+const BAR_TYPE_64: u32 = 0b100;
+const BAR_PREFETCHABLE: u32 = 1 << 3;
+
+pub const CLASS_MASS_STORAGE: u8 = 0x01;
+pub const CLASS_NETWORK: u8 = 0x02;
+pub const CLASS_DISPLAY: u8 = 0x03;
+pub const CLASS_BRIDGE: u8 = 0x06;
+
+/// Where a function sits on the bus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PciAddress {
+    pub bus: u8,
+    pub device: u8, // 0-31
+    pub function: u8, // 0-7
+}
+
+impl fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+// only one CONFIG_ADDRESS/CONFIG_DATA pair can be in flight
+static CONFIG_LOCK: Mutex<()> = Mutex::new(());
+
+impl PciAddress {
+    fn config_address(&self, register: u8) -> u32 {
+        1 << 31 // enable bit
+            | u32::from(self.bus) << 16
+            | u32::from(self.device & 0x1F) << 11
+            | u32::from(self.function & 0x07) << 8
+            | u32::from(register & 0xFC)
+    }
+
+    /// Read the configuration register at `register` (rounded down to a multiple of 4).
+    pub fn read_config(&self, register: u8) -> u32 {
+        let address = self.config_address(register);
+        interrupts::without_interrupts(|| {
+            let _lock = CONFIG_LOCK.lock();
+            unsafe {
+                Port::new(CONFIG_ADDRESS).write(address);
+                Port::new(CONFIG_DATA).read()
+            }
+        })
+    }
+
+    /// Write the configuration register at `register` (rounded down to a multiple of 4).
+    pub fn write_config(&self, register: u8, value: u32) {
+        let address = self.config_address(register);
+        interrupts::without_interrupts(|| {
+            let _lock = CONFIG_LOCK.lock();
+            unsafe {
+                Port::new(CONFIG_ADDRESS).write(address);
+                Port::new(CONFIG_DATA).write(value);
+            }
+        })
+    }
+
+    /// Let the function answer on its I/O and memory BARs and access memory by itself (DMA).
+    pub fn enable_bus_mastering(&self) {
+        let command = self.read_config(REG_COMMAND);
+        self.write_config(REG_COMMAND, command | COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE | COMMAND_BUS_MASTER);
+    }
+
+    fn vendor_id(&self) -> u16 {
+        self.read_config(REG_ID) as u16
+    }
+
+    fn header_type(&self) -> u8 {
+        (self.read_config(REG_HEADER) >> 16) as u8
+    }
+}
+
+/// A base address register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    /// Memory mapped registers at physical address `address`.
+    Memory { address: u64, size: u64, prefetchable: bool, is_64bit: bool },
+    /// I/O ports starting at `port`.
+    Io { port: u32, size: u32 },
+}
+
+/// A function found on the bus.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PciDevice {
+    pub address: PciAddress,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub header_type: u8, // without the multi function bit: 0 = device, 1 = PCI to PCI bridge, 2 = CardBus bridge
+    /// The BARs in use, with the index of their (first) register.
+    pub bars: Vec<(usize, Bar)>,
+    pub interrupt_line: u8,
+}
+
+impl PciDevice {
+    /// A short name for the device's class, ex. "VGA controller".
+    pub fn class_name(&self) -> &'static str {
+        match (self.class, self.subclass) {
+            (CLASS_MASS_STORAGE, 0x01) => "IDE controller",
+            (CLASS_MASS_STORAGE, 0x06) => "SATA controller",
+            (CLASS_MASS_STORAGE, _) => "storage controller",
+            (CLASS_NETWORK, 0x00) => "ethernet controller",
+            (CLASS_NETWORK, _) => "network controller",
+            (CLASS_DISPLAY, 0x00) => "VGA controller",
+            (CLASS_DISPLAY, _) => "display controller",
+            (CLASS_BRIDGE, 0x00) => "host bridge",
+            (CLASS_BRIDGE, 0x01) => "ISA bridge",
+            (CLASS_BRIDGE, 0x04) => "PCI bridge",
+            (CLASS_BRIDGE, _) => "bridge",
+            _ => "unknown",
+        }
+    }
+}
+
+impl fmt::Display for PciDevice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {:04x}:{:04x} {} ({:02x}.{:02x}.{:02x}) irq {}",
+            self.address, self.vendor_id, self.device_id, self.class_name(), self.class, self.subclass, self.prog_if,
+            self.interrupt_line
+        )?;
+        for (index, bar) in &self.bars {
+            match bar {
+                Bar::Memory { address, size, .. } => write!(f, " bar{}=mem {:#x}+{:#x}", index, address, size)?,
+                Bar::Io { port, size } => write!(f, " bar{}=io {:#x}+{:#x}", index, port, size)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+// write all ones to BAR register `index`, read back what stuck and restore the original value
+fn probe_bar_register(address: PciAddress, index: usize) -> (u32, u32) {
+    let register = REG_BAR0 + 4 * index as u8;
+    let original = address.read_config(register);
+    address.write_config(register, 0xFFFF_FFFF);
+    let mask = address.read_config(register);
+    address.write_config(register, original);
+    (original, mask)
+}
+
+// the BARs of a function with `count` BAR registers, a 64 bit memory BAR takes up two registers
+// interrupts stay off while decoding is switched off --> ex. the timer interrupt printing to the (legacy VGA) screen
+// while the VGA card doesn't decode memory would write into nothing
+fn probe_bars(address: PciAddress, count: usize) -> Vec<(usize, Bar)> {
+    interrupts::without_interrupts(|| probe_bars_with_decoding_off(address, count))
+}
+
+fn probe_bars_with_decoding_off(address: PciAddress, count: usize) -> Vec<(usize, Bar)> {
+    let command = address.read_config(REG_COMMAND);
+    address.write_config(REG_COMMAND, command & !(COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE));
+
+    let mut bars = Vec::new();
+    let mut index = 0;
+    while index < count {
+        let (original, mask) = probe_bar_register(address, index);
+        if original & BAR_IO != 0 {
+            let size = (!(mask & !0b11)).wrapping_add(1) & 0xFFFF; // I/O space is 16 bit
+            if mask & !0b11 != 0 {
+                bars.push((index, Bar::Io { port: original & !0b11, size }));
+            }
+            index += 1;
+            continue;
+        }
+
+        let is_64bit = original & BAR_TYPE_MASK == BAR_TYPE_64 && index + 1 < count;
+        let mut address_bits = u64::from(original & !0xF);
+        let mut mask_bits = u64::from(mask & !0xF);
+        if is_64bit {
+            let (high_original, high_mask) = probe_bar_register(address, index + 1);
+            address_bits |= u64::from(high_original) << 32;
+            mask_bits |= u64::from(high_mask) << 32;
+        } else {
+            mask_bits |= 0xFFFF_FFFF_0000_0000; // a 32 bit BAR can't decode the upper half
+        }
+        if mask_bits & 0xFFFF_FFFF != 0 || (is_64bit && mask_bits != 0) {
+            bars.push((index, Bar::Memory {
+                address: address_bits,
+                size: (!mask_bits).wrapping_add(1),
+                prefetchable: original & BAR_PREFETCHABLE != 0,
+                is_64bit,
+            }));
+        }
+        index += if is_64bit { 2 } else { 1 };
+    }
+
+    address.write_config(REG_COMMAND, command);
+    bars
+}
+
+fn read_device(address: PciAddress) -> PciDevice {
+    let id = address.read_config(REG_ID);
+    let class = address.read_config(REG_CLASS);
+    let header_type = address.header_type() & HEADER_TYPE_MASK;
+    let bar_count = match header_type {
+        0 => 6,
+        1 => 2,
+        _ => 0, // CardBus bridges have no BARs in the usual place
+    };
+    PciDevice {
+        address,
+        vendor_id: id as u16,
+        device_id: (id >> 16) as u16,
+        class: (class >> 24) as u8,
+        subclass: (class >> 16) as u8,
+        prog_if: (class >> 8) as u8,
+        header_type,
+        bars: probe_bars(address, bar_count),
+        interrupt_line: address.read_config(REG_INTERRUPT) as u8,
+    }
+}
+
+/// Check every bus, device and function, returns what was found in bus/device/function order.
+pub fn scan() -> Vec<PciDevice> {
+    let mut devices = Vec::new();
+    for bus in 0..=255 {
+        for device in 0..32 {
+            let first = PciAddress { bus, device, function: 0 };
+            if first.vendor_id() == VENDOR_NONE {
+                continue;
+            }
+            let functions = if first.header_type() & HEADER_MULTI_FUNCTION != 0 { 8 } else { 1 };
+            for function in 0..functions {
+                let address = PciAddress { bus, device, function };
+                if address.vendor_id() != VENDOR_NONE {
+                    devices.push(read_device(address));
+                }
+            }
+        }
+    }
+    devices
+}
+
+static DEVICES: Once<Vec<PciDevice>> = Once::new();
+
+/// Scan the bus once (needs the heap) and print a line per device.
+pub fn init() {
+    let devices = DEVICES.call_once(scan);
+    for device in devices {
+        crate::println!("pci {}", device);
+    }
+}
+
+/// The devices found by `init()` (none before it).
+pub fn devices() -> &'static [PciDevice] {
+    DEVICES.r#try().map_or(&[], Vec::as_slice)
+}
+
+/// The first device of the given class and subclass.
+pub fn find(class: u8, subclass: u8) -> Option<&'static PciDevice> {
+    devices().iter().find(|device| device.class == class && device.subclass == subclass)
+}
+
+// TESTS ===================================
+
+#[test_case]
+fn test_pci_scan_finds_qemu_devices() {
+    // raw BAR registers of everything on bus 0 before probing
+    let raw_bars = || -> Vec<u32> {
+        (0..32)
+            .map(|device| PciAddress { bus: 0, device, function: 0 })
+            .filter(|address| address.vendor_id() != VENDOR_NONE)
+            .flat_map(|address| (0..6).map(move |index| address.read_config(REG_BAR0 + 4 * index)))
+            .collect()
+    };
+    let before = raw_bars();
+    let devices = scan();
+    assert_eq!(raw_bars(), before, "BAR probing didn't restore the original values");
+
+    let has = |class, subclass| devices.iter().any(|device| device.class == class && device.subclass == subclass);
+    assert!(has(CLASS_BRIDGE, 0x00), "no host bridge");
+    assert!(has(CLASS_BRIDGE, 0x01), "no ISA bridge");
+    assert!(has(CLASS_DISPLAY, 0x00), "no VGA controller");
+
+    // QEMU's VGA card has its framebuffer in a memory BAR 0
+    let vga = devices.iter().find(|device| device.class == CLASS_DISPLAY).expect("no display");
+    assert!(matches!(vga.bars.first(), Some((0, Bar::Memory { size, .. })) if size.is_power_of_two()));
+}
+
+#[test_case]
+fn test_pci_config_address() {
+    let address = PciAddress { bus: 1, device: 2, function: 3 };
+    assert_eq!(address.config_address(0x3E), 0x8001_133C);
+    assert_eq!(alloc::format!("{}", address), "01:02.3");
+}
@@ -0,0 +1,22 @@
+// Byte wide I/O port access behind a trait --> drivers are generic over it so tests can swap in a backend that
+// records the port sequence instead of touching real hardware
+use x86_64::instructions::port::Port;
+
+/// Byte wide I/O port access, so the port sequence can be checked in tests without real hardware.
+pub trait PortIo {
+    fn read_u8(&mut self, port: u16) -> u8;
+    fn write_u8(&mut self, port: u16, value: u8);
+}
+
+/// The real I/O ports.
+pub struct HardwarePorts;
+
+impl PortIo for HardwarePorts {
+    fn read_u8(&mut self, port: u16) -> u8 {
+        unsafe { Port::new(port).read() }
+    }
+
+    fn write_u8(&mut self, port: u16, value: u8) {
+        unsafe { Port::new(port).write(value) }
+    }
+}
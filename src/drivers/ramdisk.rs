@@ -0,0 +1,133 @@
+// RAM disk --> a block device stored in memory, for trying out filesystems without attaching a disk image to QEMU
+// either blank and writable (on the heap) or a read-only view of an image built into the kernel with include_bytes!
+use crate::drivers::block::{check_range, BlockDevice, BlockError};
+use alloc::{vec, vec::Vec};
+use spin::Mutex;
+
+pub const BLOCK_SIZE: usize = 512;
+
+enum Storage {
+    Heap(Mutex<Vec<u8>>),
+    Image(&'static [u8]),
+}
+
+/// A block device with 512 byte blocks backed by memory.
+pub struct RamDisk {
+    storage: Storage,
+    blocks: u64,
+}
+
+impl RamDisk {
+    /// A writable disk of `blocks` zeroed blocks on the heap.
+    pub fn new(blocks: usize) -> RamDisk {
+        RamDisk {
+            storage: Storage::Heap(Mutex::new(vec![0; blocks * BLOCK_SIZE])),
+            blocks: blocks as u64,
+        }
+    }
+
+    /// A read-only disk showing `image` (ex. `include_bytes!("disk.img")`), a partial last block is left out.
+    pub fn from_bytes(image: &'static [u8]) -> RamDisk {
+        RamDisk {
+            storage: Storage::Image(image),
+            blocks: (image.len() / BLOCK_SIZE) as u64,
+        }
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        matches!(self.storage, Storage::Image(_))
+    }
+
+    fn check_block(&self, lba: u64, len: usize) -> Result<usize, BlockError> {
+        if len != BLOCK_SIZE {
+            return Err(BlockError::BadBuffer);
+        }
+        check_range(self, lba, len)?;
+        Ok(lba as usize * BLOCK_SIZE)
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn block_size(&self) -> usize {
+        BLOCK_SIZE
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.blocks
+    }
+
+    fn read_block(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        let start = self.check_block(lba, buf.len())?;
+        match &self.storage {
+            Storage::Heap(data) => buf.copy_from_slice(&data.lock()[start..start + BLOCK_SIZE]),
+            Storage::Image(image) => buf.copy_from_slice(&image[start..start + BLOCK_SIZE]),
+        }
+        Ok(())
+    }
+
+    fn write_block(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        let start = self.check_block(lba, buf.len())?;
+        match &self.storage {
+            Storage::Heap(data) => data.lock()[start..start + BLOCK_SIZE].copy_from_slice(buf),
+            Storage::Image(_) => return Err(BlockError::ReadOnly),
+        }
+        Ok(())
+    }
+
+    // one copy under one lock instead of one per block
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_range(self, lba, buf.len())?;
+        let start = lba as usize * BLOCK_SIZE;
+        match &self.storage {
+            Storage::Heap(data) => buf.copy_from_slice(&data.lock()[start..start + buf.len()]),
+            Storage::Image(image) => buf.copy_from_slice(&image[start..start + buf.len()]),
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        check_range(self, lba, buf.len())?;
+        let start = lba as usize * BLOCK_SIZE;
+        match &self.storage {
+            Storage::Heap(data) => data.lock()[start..start + buf.len()].copy_from_slice(buf),
+            Storage::Image(_) => return Err(BlockError::ReadOnly),
+        }
+        Ok(())
+    }
+}
+
+// TESTS ===================================
+
+#[test_case]
+fn test_ramdisk_conformance() {
+    crate::drivers::block::conformance_test(&RamDisk::new(16));
+}
+
+#[test_case]
+fn test_ramdisk_round_trip_across_blocks() {
+    let disk = RamDisk::new(4);
+    // 2 blocks starting at block 1 --> spans the 1/2 boundary
+    let data: Vec<u8> = (0..BLOCK_SIZE * 2).map(|i| (i % 251) as u8).collect();
+    disk.write_blocks(1, &data).expect("write failed");
+
+    let mut block = [0u8; BLOCK_SIZE];
+    disk.read_block(2, &mut block).expect("read failed");
+    assert_eq!(&block[..], &data[BLOCK_SIZE..]);
+    disk.read_block(0, &mut block).expect("read failed");
+    assert!(block.iter().all(|&byte| byte == 0));
+}
+
+#[test_case]
+fn test_ramdisk_image_is_read_only() {
+    static IMAGE: [u8; BLOCK_SIZE * 2 + 100] = [0x42; BLOCK_SIZE * 2 + 100];
+    let disk = RamDisk::from_bytes(&IMAGE);
+    assert!(disk.is_read_only());
+    assert_eq!(disk.num_blocks(), 2);
+
+    let mut block = [0u8; BLOCK_SIZE];
+    disk.read_block(1, &mut block).expect("read failed");
+    assert!(block.iter().all(|&byte| byte == 0x42));
+    assert_eq!(disk.write_block(0, &block), Err(BlockError::ReadOnly));
+    assert_eq!(disk.write_blocks(0, &[0; BLOCK_SIZE * 2]), Err(BlockError::ReadOnly));
+    assert_eq!(disk.read_block(2, &mut block), Err(BlockError::OutOfRange));
+}
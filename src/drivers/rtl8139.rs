@@ -0,0 +1,453 @@
+// RTL8139 --> the Realtek 10/100 ethernet card, emulated by QEMU with `-device rtl8139` (see Cargo.toml)
+// for an overview see: https://wiki.osdev.org/RTL8139
+//
+// all registers are I/O ports at an offset from the card's I/O BAR, frames are moved to and from memory by the card
+// itself (bus mastering) --> the buffers are DMA buffers (see memory/dma.rs) and the card is told their physical address
+// - receiving: one ring buffer (64 KiB here), the card appends every frame as [status u16][length u16][frame][CRC] at a
+//   4 byte aligned offset and continues at the start of the ring when it reaches the end --> a frame can be split in two
+//   we tell the card how far we have read through CAPR, which for historical reasons lags 16 bytes behind the real
+//   read offset, and the BUFE bit of the command register says whether there is anything left to read
+// - sending: four transmit buffers used round robin, each with a start address (TSAD) and a status register (TSD):
+//   writing the length into TSD starts the transmission, the card sets OWN once it copied the frame and TOK once it's out
+//
+// received frames are handed to the callback set with set_rx_callback(), from the interrupt handler
+use super::pci::{self, Bar};
+use crate::memory::{dma::DmaBuffer, volatile_copy_to, volatile_zero};
+use crate::mmio::mmio_sfence;
+use crate::net::ethernet::{MAX_FRAME_LEN, MIN_FRAME_LEN, MTU};
+use crate::net::{MacAddress, NetError, NetworkDevice};
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::{Mutex, Once};
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+const VENDOR_REALTEK: u16 = 0x10EC;
+const DEVICE_RTL8139: u16 = 0x8139;
+
+// registers (offsets from the I/O BAR)
+const REG_IDR0: u16 = 0x00; // MAC address, 6 bytes
+const REG_TSD0: u16 = 0x10; // transmit status of buffer 0, the other three follow 4 bytes apart
+const REG_TSAD0: u16 = 0x20; // transmit start address of buffer 0, same layout
+const REG_RBSTART: u16 = 0x30; // receive ring start address
+const REG_CR: u16 = 0x37; // command
+const REG_CAPR: u16 = 0x38; // current address of packet read (- 16)
+const REG_IMR: u16 = 0x3C; // interrupt mask
+const REG_ISR: u16 = 0x3E; // interrupt status, bits are cleared by writing 1s
+const REG_RCR: u16 = 0x44; // receive configuration
+const REG_CONFIG1: u16 = 0x52;
+
+// command register bits
+const CR_BUFE: u8 = 1 << 0; // receive ring empty
+const CR_TE: u8 = 1 << 2; // transmitter enable
+const CR_RE: u8 = 1 << 3; // receiver enable
+const CR_RST: u8 = 1 << 4; // software reset, cleared by the card when done
+
+// interrupt bits (IMR and ISR)
+const INT_ROK: u16 = 1 << 0; // frame received
+const INT_RER: u16 = 1 << 1; // receive error
+const INT_TOK: u16 = 1 << 2; // frame sent
+const INT_TER: u16 = 1 << 3; // transmit error
+const INT_RXOVW: u16 = 1 << 4; // receive ring overflow
+const INT_FOVW: u16 = 1 << 6; // receive FIFO overflow
+const INT_RX: u16 = INT_ROK | INT_RER | INT_RXOVW | INT_FOVW;
+
+// transmit status bits
+const TSD_OWN: u32 = 1 << 13; // the card is done with the buffer
+const TSD_TOK: u32 = 1 << 15; // the frame was sent
+
+// receive configuration: accept frames to our address, multicast and broadcast, 64 KiB ring without WRAP
+// (WRAP would let the card write past the end of the ring instead of splitting frames, but it isn't allowed with 64 KiB)
+const RCR_APM: u32 = 1 << 1;
+const RCR_AM: u32 = 1 << 2;
+const RCR_AB: u32 = 1 << 3;
+const RCR_MXDMA_UNLIMITED: u32 = 0b111 << 8;
+const RCR_RBLEN_64K: u32 = 0b11 << 11;
+
+// status bit in the header the card writes before every received frame
+const RX_HEADER_ROK: u16 = 1 << 0;
+// length the card writes into a header it hasn't finished yet ("early receive")
+const RX_HEADER_IN_PROGRESS: usize = 0xFFF0;
+
+const RX_RING_LEN: usize = 64 * 1024;
+const RX_BUFFER_LEN: usize = RX_RING_LEN + 16; // the datasheet asks for 16 bytes of slack after the ring
+const CAPR_LAG: u16 = 16;
+const CRC_LEN: usize = 4;
+
+const TX_BUFFERS: usize = 4;
+const TX_BUFFER_LEN: usize = 2048;
+
+// how many times a register is read while waiting for the card before giving up (see ata.rs)
+const POLL_LIMIT: u32 = 1_000_000;
+
+struct RxRing {
+    buffer: DmaBuffer,
+    offset: usize, // where the next header is
+    frame: [u8; MAX_FRAME_LEN], // received frames are copied here, out of the ring, before the callback sees them
+}
+
+struct TxBuffers {
+    buffer: DmaBuffer, // the four transmit buffers, TX_BUFFER_LEN bytes each
+    next: usize,
+    in_use: [bool; TX_BUFFERS], // handed to the card and not checked for completion since
+}
+
+/// An RTL8139 card.
+///
+/// The receive ring and the transmit buffers have their own locks (always taken in that order, with interrupts off):
+/// the rx callback runs with the receive ring locked and may still send frames.
+pub struct Rtl8139 {
+    io_base: u16,
+    irq: u8,
+    mac: MacAddress,
+    rx: Mutex<RxRing>,
+    tx: Mutex<TxBuffers>,
+    rx_resets: AtomicU64, // times a bad receive header made us reset the card, see receive()
+}
+
+impl Rtl8139 {
+    // set up the card found at `device`, its interrupts stay masked until enable_interrupts()
+    fn new(device: &pci::PciDevice) -> Result<Self, NetError> {
+        // I/O ports don't need mapping, the BAR just says where they are
+        let io_base = device
+            .bars
+            .iter()
+            .find_map(|(_, bar)| match bar {
+                Bar::Io { port, .. } => Some(*port as u16),
+                Bar::Memory { .. } => None,
+            })
+            .ok_or(NetError::NoDevice)?;
+        device.address.enable_bus_mastering();
+
+        let mut nic = Rtl8139 {
+            io_base,
+            irq: device.interrupt_line,
+            mac: MacAddress([0; 6]),
+            rx: Mutex::new(RxRing {
+                buffer: DmaBuffer::new(RX_BUFFER_LEN).ok_or(NetError::NoDevice)?,
+                offset: 0,
+                frame: [0; MAX_FRAME_LEN],
+            }),
+            tx: Mutex::new(TxBuffers {
+                buffer: DmaBuffer::new(TX_BUFFERS * TX_BUFFER_LEN).ok_or(NetError::NoDevice)?,
+                next: 0,
+                in_use: [false; TX_BUFFERS],
+            }),
+            rx_resets: AtomicU64::new(0),
+        };
+        let mut mac = [0; 6];
+        for (i, byte) in mac.iter_mut().enumerate() {
+            *byte = nic.read_u8(REG_IDR0 + i as u16);
+        }
+        nic.mac = MacAddress(mac);
+        nic.start(&mut nic.rx.lock(), &mut nic.tx.lock())?;
+        Ok(nic)
+    }
+
+    // reset the card and point it at our buffers, anything in them is forgotten
+    fn start(&self, rx: &mut RxRing, tx: &mut TxBuffers) -> Result<(), NetError> {
+        self.write_u8(REG_CONFIG1, 0); // power on (LWAKE + LWPTN low)
+        self.write_u8(REG_CR, CR_RST);
+        if !(0..POLL_LIMIT).any(|_| self.read_u8(REG_CR) & CR_RST == 0) {
+            return Err(NetError::Timeout);
+        }
+
+        rx.offset = 0;
+        tx.next = 0;
+        tx.in_use = [false; TX_BUFFERS];
+        // the card only takes 32 bit addresses, which DmaBuffer guarantees
+        self.write_u32(REG_RBSTART, rx.buffer.phys_addr().as_u64() as u32);
+        for i in 0..TX_BUFFERS {
+            let address = tx.buffer.phys_addr().as_u64() + (i * TX_BUFFER_LEN) as u64;
+            self.write_u32(REG_TSAD0 + 4 * i as u16, address as u32);
+        }
+        // the configuration registers only take effect once the receiver and transmitter are on (see Linux' 8139too)
+        self.write_u8(REG_CR, CR_RE | CR_TE);
+        self.write_u32(REG_RCR, RCR_APM | RCR_AM | RCR_AB | RCR_MXDMA_UNLIMITED | RCR_RBLEN_64K);
+        self.write_u16(REG_CAPR, 0u16.wrapping_sub(CAPR_LAG));
+        Ok(())
+    }
+
+    fn enable_interrupts(&self) {
+        self.write_u16(REG_IMR, INT_RX | INT_TOK | INT_TER);
+    }
+
+    /// How many times the receive ring got lost track of (a bad frame header) and the card was reset.
+    pub fn rx_resets(&self) -> u64 {
+        self.rx_resets.load(Ordering::Relaxed)
+    }
+
+    /// The card's MAC address (from its ID registers).
+    pub fn mac(&self) -> MacAddress {
+        self.mac
+    }
+
+    /// Send `frame` (starting with the destination MAC, without CRC), padded to the minimum frame length.
+    ///
+    /// Returns once the card has the frame, use `flush()` to wait until it's sent.
+    pub fn send(&self, frame: &[u8]) -> Result<(), NetError> {
+        if frame.len() > MAX_FRAME_LEN {
+            return Err(NetError::FrameTooLarge);
+        }
+        interrupts::without_interrupts(|| {
+            let mut tx = self.tx.lock();
+            let index = tx.next;
+            // the buffer may still hold an earlier frame, whether that one failed is flush()'s business
+            if let Err(NetError::Timeout) = self.wait_transmitted(&mut tx, index) {
+                return Err(NetError::Timeout);
+            }
+
+            let len = frame.len().max(MIN_FRAME_LEN);
+            unsafe {
+                let buffer = tx.buffer.as_mut_ptr().add(index * TX_BUFFER_LEN);
+                volatile_copy_to(buffer, frame.as_ptr(), frame.len());
+                volatile_zero(buffer.add(frame.len()), len - frame.len());
+            }
+            mmio_sfence(); // the frame has to be in memory before the card starts reading it
+            // the length with OWN cleared starts the transmission (early transmit threshold 0 --> 8 bytes)
+            self.write_u32(REG_TSD0 + 4 * index as u16, len as u32);
+            tx.in_use[index] = true;
+            tx.next = (index + 1) % TX_BUFFERS;
+            Ok(())
+        })
+    }
+
+    /// Wait until every frame handed to `send()` is sent, fails if the card couldn't send one of them.
+    pub fn flush(&self) -> Result<(), NetError> {
+        interrupts::without_interrupts(|| {
+            let mut tx = self.tx.lock();
+            let mut result = Ok(());
+            for index in 0..TX_BUFFERS {
+                if let Err(error) = self.wait_transmitted(&mut tx, index) {
+                    result = result.and(Err(error));
+                }
+            }
+            result
+        })
+    }
+
+    // wait until the card is done with transmit buffer `index` and check whether its frame went out
+    fn wait_transmitted(&self, tx: &mut TxBuffers, index: usize) -> Result<(), NetError> {
+        if !tx.in_use[index] {
+            return Ok(());
+        }
+        let register = REG_TSD0 + 4 * index as u16;
+        let status = (0..POLL_LIMIT)
+            .map(|_| self.read_u32(register))
+            .find(|status| status & TSD_OWN != 0)
+            .ok_or(NetError::Timeout)?;
+        tx.in_use[index] = false;
+        if status & TSD_TOK == 0 {
+            return Err(NetError::TransmitFailed);
+        }
+        Ok(())
+    }
+
+    /// Hand every frame in the receive ring to the rx callback, for when interrupts can't be used.
+    pub fn poll(&self) {
+        interrupts::without_interrupts(|| self.receive(&mut self.rx.lock()));
+    }
+
+    fn receive(&self, rx: &mut RxRing) {
+        let callback = *RX_CALLBACK.lock();
+        while self.read_u8(REG_CR) & CR_BUFE == 0 {
+            // headers are 4 byte aligned --> never split by the end of the ring
+            let header = unsafe { (rx.buffer.as_mut_ptr().add(rx.offset) as *const u32).read_volatile() };
+            let status = header as u16;
+            let length = (header >> 16) as usize; // including the CRC
+            if length == RX_HEADER_IN_PROGRESS {
+                break; // the rest arrives with the next interrupt
+            }
+            if status & RX_HEADER_ROK == 0 || length < CRC_LEN || length > MAX_FRAME_LEN + CRC_LEN {
+                // a bad frame or we lost track of where the frames are --> start over with an empty ring
+                // (the card only restarts the ring on a reset, the transmit buffers have to be set up again with it)
+                // counted instead of printed, this runs in the interrupt handler, see rx_resets()
+                self.rx_resets.fetch_add(1, Ordering::Relaxed);
+                let mut tx = self.tx.lock();
+                if self.start(rx, &mut tx).is_ok() {
+                    self.enable_interrupts();
+                }
+                return;
+            }
+
+            let frame_len = length - CRC_LEN;
+            let start = (rx.offset + 4) % RX_RING_LEN;
+            let first_part = frame_len.min(RX_RING_LEN - start); // the rest wrapped around to the start of the ring
+            unsafe {
+                let ring = rx.buffer.as_mut_ptr();
+                volatile_copy_to(rx.frame.as_mut_ptr(), ring.add(start), first_part);
+                volatile_copy_to(rx.frame.as_mut_ptr().add(first_part), ring, frame_len - first_part);
+            }
+
+            rx.offset = ((rx.offset + 4 + length + 3) & !3) % RX_RING_LEN;
+            self.write_u16(REG_CAPR, (rx.offset as u16).wrapping_sub(CAPR_LAG));
+            if let Some(callback) = callback {
+                callback(&rx.frame[..frame_len]);
+            }
+        }
+    }
+
+    // the interrupt handler (see init()): acknowledge and empty the receive ring, sent frames are checked by send()/flush()
+    fn handle_interrupt(&self) {
+        let status = self.read_u16(REG_ISR);
+        self.write_u16(REG_ISR, status);
+        if status & INT_RX != 0 {
+            self.receive(&mut self.rx.lock());
+        }
+    }
+
+    fn read_u8(&self, register: u16) -> u8 {
+        unsafe { Port::new(self.io_base + register).read() }
+    }
+
+    fn read_u16(&self, register: u16) -> u16 {
+        unsafe { Port::new(self.io_base + register).read() }
+    }
+
+    fn read_u32(&self, register: u16) -> u32 {
+        unsafe { Port::new(self.io_base + register).read() }
+    }
+
+    fn write_u8(&self, register: u16, value: u8) {
+        unsafe { Port::new(self.io_base + register).write(value) }
+    }
+
+    fn write_u16(&self, register: u16, value: u16) {
+        unsafe { Port::new(self.io_base + register).write(value) }
+    }
+
+    fn write_u32(&self, register: u16, value: u32) {
+        unsafe { Port::new(self.io_base + register).write(value) }
+    }
+}
+
+impl NetworkDevice for Rtl8139 {
+    fn mac(&self) -> MacAddress {
+        self.mac
+    }
+
+    fn mtu(&self) -> usize {
+        MTU
+    }
+
+    fn send(&self, frame: &[u8]) -> Result<(), NetError> {
+        Rtl8139::send(self, frame)
+    }
+
+    // there is only one callback for all cards, but also only one card (see init())
+    fn set_rx_sink(&self, sink: fn(&[u8])) {
+        set_rx_callback(sink);
+    }
+}
+
+// GLOBAL CARD =============================
+
+static DEVICE: Once<Option<Rtl8139>> = Once::new();
+
+// called with every received frame, from the interrupt handler
+static RX_CALLBACK: Mutex<Option<fn(&[u8])>> = Mutex::new(None);
+
+/// Set up the first RTL8139 found by `pci::init()` and print its MAC address, `None` without one.
+///
+/// Only the first call does anything, later ones return the same card.
+pub fn init() -> Option<&'static Rtl8139> {
+    let mut first_call = false;
+    let nic = DEVICE
+        .call_once(|| {
+            first_call = true;
+            let device = pci::devices()
+                .iter()
+                .find(|device| device.vendor_id == VENDOR_REALTEK && device.device_id == DEVICE_RTL8139)?;
+            Rtl8139::new(device).map_err(|error| crate::println!("rtl8139: {}", error)).ok()
+        })
+        .as_ref()?;
+    if first_call {
+        crate::println!("rtl8139: mac {} irq {}", nic.mac, nic.irq);
+        // IRQs 0-2 are taken and 0xFF means none was assigned --> only poll() works then
+        if (3..crate::interrupts::IRQ_LINES as u8).contains(&nic.irq) {
+            crate::interrupts::register_irq_handler(nic.irq, || {
+                if let Some(Some(nic)) = DEVICE.r#try() {
+                    nic.handle_interrupt();
+                }
+            });
+            nic.enable_interrupts();
+        }
+    }
+    Some(nic)
+}
+
+/// The card set up by `init()`.
+pub fn device() -> Option<&'static Rtl8139> {
+    DEVICE.r#try()?.as_ref()
+}
+
+/// Send `frame` through the card set up by `init()` (see `Rtl8139::send()`).
+pub fn send(frame: &[u8]) -> Result<(), NetError> {
+    device().ok_or(NetError::NoDevice)?.send(frame)
+}
+
+/// Call `callback` with every frame received from now on (without the CRC).
+///
+/// It runs inside the interrupt handler --> like a timer callback it has to be short and must not allocate, the frame
+/// has to be copied out if it's needed later.
+pub fn set_rx_callback(callback: fn(&[u8])) {
+    interrupts::without_interrupts(|| *RX_CALLBACK.lock() = Some(callback));
+}
+
+// TESTS ===================================
+
+// a broadcast "who has 10.0.2.2 (QEMU's user network gateway), tell 10.0.2.15" ARP request from `mac`
+#[cfg(test)]
+fn arp_request(mac: MacAddress) -> [u8; 42] {
+    let mut frame = [0u8; 42];
+    frame[0..6].copy_from_slice(&MacAddress::BROADCAST.0);
+    frame[6..12].copy_from_slice(&mac.0);
+    frame[12..14].copy_from_slice(&[0x08, 0x06]); // ethertype ARP
+    frame[14..22].copy_from_slice(&[0x00, 0x01, 0x08, 0x00, 6, 4, 0x00, 0x01]); // ethernet/IPv4, request
+    frame[22..28].copy_from_slice(&mac.0);
+    frame[28..32].copy_from_slice(&[10, 0, 2, 15]);
+    frame[38..42].copy_from_slice(&[10, 0, 2, 2]);
+    frame
+}
+
+#[test_case]
+fn test_rtl8139_transmit() {
+    pci::init();
+    let nic = init().expect("no RTL8139, is QEMU started with -device rtl8139?");
+    assert_ne!(nic.mac(), MacAddress([0; 6]));
+    assert_eq!(nic.send(&[0; MAX_FRAME_LEN + 1]), Err(NetError::FrameTooLarge));
+
+    let index = interrupts::without_interrupts(|| nic.tx.lock().next);
+    nic.send(&arp_request(nic.mac())).expect("send failed");
+    assert_eq!(nic.flush(), Ok(()));
+    let status = nic.read_u32(REG_TSD0 + 4 * index as u16);
+    assert_ne!(status & TSD_TOK, 0, "TX-ok not set, status {:#x}", status);
+    assert_eq!(status & 0x1FFF, 60, "short frame not padded");
+}
+
+#[test_case]
+fn test_rtl8139_receive() {
+    use core::sync::atomic::AtomicUsize;
+
+    static FRAMES: AtomicUsize = AtomicUsize::new(0);
+    static ARP_REPLIES: AtomicUsize = AtomicUsize::new(0);
+
+    pci::init();
+    let nic = init().expect("no RTL8139, is QEMU started with -device rtl8139?");
+    set_rx_callback(|frame| {
+        FRAMES.fetch_add(1, Ordering::SeqCst);
+        // ethertype ARP, opcode reply
+        if frame.len() >= 22 && frame[12..14] == [0x08, 0x06] && frame[20..22] == [0x00, 0x02] {
+            ARP_REPLIES.fetch_add(1, Ordering::SeqCst);
+        }
+    });
+
+    // QEMU's gateway answers ARP requests --> the reply arrives through the interrupt handler
+    nic.send(&arp_request(nic.mac())).expect("send failed");
+    let deadline = crate::interrupts::timer_ticks() + u64::from(crate::config::get().timer_hz);
+    while ARP_REPLIES.load(Ordering::SeqCst) == 0 && crate::interrupts::timer_ticks() < deadline {
+        x86_64::instructions::hlt();
+    }
+    assert!(FRAMES.load(Ordering::SeqCst) > 0, "nothing received");
+    assert!(ARP_REPLIES.load(Ordering::SeqCst) > 0, "no ARP reply from the gateway");
+    assert_eq!(nic.rx_resets(), 0, "lost track of the receive ring");
+}
@@ -0,0 +1,145 @@
+// PC speaker --> the beeper driven by PIT channel 2 (see interrupts.rs for channel 0, the timer)
+// for an overview see: https://wiki.osdev.org/PC_Speaker
+//
+// channel 2 is programmed like channel 0 (command port 0x43, then the 16 bit divisor low byte first to port 0x42),
+// its square wave only reaches the speaker while bits 0 (gate channel 2) and 1 (speaker data) of port 0x61 are set
+// the other bits of port 0x61 belong to other hardware --> it is always read-modify-written and restored afterwards
+use super::port::{HardwarePorts, PortIo};
+use crate::interrupts::PIT_BASE_FREQUENCY;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+const PIT_COMMAND: u16 = 0x43;
+const PIT_CHANNEL_2: u16 = 0x42;
+const SPEAKER_CONTROL: u16 = 0x61;
+
+// channel 2, low byte then high byte, mode 3 (square wave), binary counting
+const COMMAND_CHANNEL_2_SQUARE_WAVE: u8 = 0xB6;
+const SPEAKER_GATE_BITS: u8 = 0b11;
+
+/// Lowest frequency a tone is played at, lower ones are clamped to it.
+pub const MIN_FREQUENCY: u32 = 20;
+/// Highest frequency a tone is played at, higher ones are clamped to it.
+pub const MAX_FREQUENCY: u32 = 20_000;
+
+/// The speaker behind some ports.
+pub struct Speaker<P: PortIo> {
+    ports: P,
+    saved_control: Option<u8>, // port 0x61 before the current tone started, `None` while silent
+}
+
+impl<P: PortIo> Speaker<P> {
+    pub const fn new(ports: P) -> Self {
+        Speaker { ports, saved_control: None }
+    }
+
+    /// Start playing `freq_hz` (clamped to 20 Hz - 20 kHz) until `stop_tone()`, changes the frequency if a tone is already on.
+    pub fn start_tone(&mut self, freq_hz: u32) {
+        let divisor = (PIT_BASE_FREQUENCY / freq_hz.clamp(MIN_FREQUENCY, MAX_FREQUENCY)) as u16;
+        self.ports.write_u8(PIT_COMMAND, COMMAND_CHANNEL_2_SQUARE_WAVE);
+        self.ports.write_u8(PIT_CHANNEL_2, (divisor & 0xff) as u8);
+        self.ports.write_u8(PIT_CHANNEL_2, (divisor >> 8) as u8);
+
+        let control = self.ports.read_u8(SPEAKER_CONTROL);
+        // a tone that is already on keeps the value from before the first one
+        if self.saved_control.is_none() {
+            self.saved_control = Some(control);
+        }
+        if control & SPEAKER_GATE_BITS != SPEAKER_GATE_BITS {
+            self.ports.write_u8(SPEAKER_CONTROL, control | SPEAKER_GATE_BITS);
+        }
+    }
+
+    /// Silence the speaker, restoring port 0x61 to what it was before the tone started.
+    pub fn stop_tone(&mut self) {
+        let control = match self.saved_control.take() {
+            Some(saved) => saved,
+            None => self.ports.read_u8(SPEAKER_CONTROL) & !SPEAKER_GATE_BITS, // not started by us --> just ungate
+        };
+        self.ports.write_u8(SPEAKER_CONTROL, control);
+    }
+}
+
+static SPEAKER: Mutex<Speaker<HardwarePorts>> = Mutex::new(Speaker::new(HardwarePorts));
+
+/// Start playing `freq_hz` on the PC speaker until `stop_tone()` (ex. from the panic handler, which never stops it).
+pub fn start_tone(freq_hz: u32) {
+    interrupts::without_interrupts(|| SPEAKER.lock().start_tone(freq_hz));
+}
+
+/// Silence the PC speaker.
+pub fn stop_tone() {
+    interrupts::without_interrupts(|| SPEAKER.lock().stop_tone());
+}
+
+/// Play `freq_hz` for `duration_ms` milliseconds, blocking the CPU meanwhile (see `syscall::sleep_ms()`).
+pub fn beep(freq_hz: u32, duration_ms: u64) {
+    start_tone(freq_hz);
+    crate::syscall::sleep_ms(duration_ms);
+    stop_tone();
+}
+
+// TESTS ===================================
+
+// remembers every port access, port 0x61 keeps the last value written to it
+#[cfg(test)]
+struct RecordingPorts {
+    control: u8,
+    log: alloc::vec::Vec<(char, u16, u8)>, // ('r' or 'w', port, value)
+}
+
+#[cfg(test)]
+impl PortIo for RecordingPorts {
+    fn read_u8(&mut self, port: u16) -> u8 {
+        assert_eq!(port, SPEAKER_CONTROL, "unexpected read");
+        self.log.push(('r', port, self.control));
+        self.control
+    }
+
+    fn write_u8(&mut self, port: u16, value: u8) {
+        if port == SPEAKER_CONTROL {
+            self.control = value;
+        }
+        self.log.push(('w', port, value));
+    }
+}
+
+#[test_case]
+fn test_speaker_port_sequence() {
+    let mut speaker = Speaker::new(RecordingPorts { control: 0x0C, log: alloc::vec::Vec::new() });
+
+    // 1193182 / 1000 = 1193 = 0x04A9
+    speaker.start_tone(1000);
+    assert_eq!(speaker.ports.log, [
+        ('w', PIT_COMMAND, 0xB6),
+        ('w', PIT_CHANNEL_2, 0xA9),
+        ('w', PIT_CHANNEL_2, 0x04),
+        ('r', SPEAKER_CONTROL, 0x0C),
+        ('w', SPEAKER_CONTROL, 0x0F),
+    ]);
+
+    // changing the frequency of a tone that is on doesn't touch the gate, stopping restores the original value
+    speaker.ports.log.clear();
+    speaker.start_tone(2000);
+    speaker.stop_tone();
+    assert_eq!(speaker.ports.log[3..], [('r', SPEAKER_CONTROL, 0x0F), ('w', SPEAKER_CONTROL, 0x0C)]);
+
+    // stopping while silent only clears the gate bits
+    speaker.ports.control = 0x4F;
+    speaker.ports.log.clear();
+    speaker.stop_tone();
+    assert_eq!(speaker.ports.log, [('r', SPEAKER_CONTROL, 0x4F), ('w', SPEAKER_CONTROL, 0x4C)]);
+}
+
+#[test_case]
+fn test_speaker_frequency_clamped() {
+    let divisor = |freq_hz| {
+        let mut speaker = Speaker::new(RecordingPorts { control: 0, log: alloc::vec::Vec::new() });
+        speaker.start_tone(freq_hz);
+        u16::from(speaker.ports.log[1].2) | u16::from(speaker.ports.log[2].2) << 8
+    };
+    assert_eq!(divisor(1), divisor(MIN_FREQUENCY));
+    assert_eq!(divisor(MIN_FREQUENCY), (PIT_BASE_FREQUENCY / MIN_FREQUENCY) as u16);
+    assert_eq!(divisor(1_000_000), divisor(MAX_FREQUENCY));
+    assert_eq!(divisor(MAX_FREQUENCY), (PIT_BASE_FREQUENCY / MAX_FREQUENCY) as u16);
+}
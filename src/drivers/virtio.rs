@@ -0,0 +1,375 @@
+// Virtio --> the paravirtualized devices of QEMU (and other hypervisors), see the spec at
+// https://docs.oasis-open.org/virtio/virtio/v1.1/virtio-v1.1.html (the "legacy interface" sections)
+//
+// only the legacy PCI transport: every register is an I/O port at an offset from BAR 0, QEMU's virtio-*-pci devices
+// offer it next to the modern one as long as they sit on a plain PCI bus (the default pc machine)
+// - bring up: reset (status 0), ACKNOWLEDGE, DRIVER, agree on the features, set up the queues, DRIVER_OK
+// - the device specific configuration (ex. the MAC address of a network card) follows the common registers at
+//   DEVICE_CONFIG, 4 bytes further when MSI-X is enabled (which we never do)
+//
+// a VirtQueue is the "split" ring the driver and the device share, in one physically contiguous DMA buffer:
+// - descriptor table: [address u64][length u32][flags u16][next u16] per buffer, chained with NEXT for several parts
+// - available ring: [flags u16][index u16][ring u16 * size] --> the heads of the chains we hand to the device
+// - used ring, at the next page boundary: [flags u16][index u16][(id u32, length u32) * size] --> the chains the device is
+//   done with and how many bytes it wrote into them
+// the indexes count up forever (wrapping at 2^16), the slot is the index modulo the queue size
+use super::pci::{Bar, PciDevice};
+use crate::memory::dma::DmaBuffer;
+use crate::mmio::{mmio_lfence, mmio_sfence};
+use x86_64::instructions::port::Port;
+use x86_64::PhysAddr;
+
+pub const VENDOR_VIRTIO: u16 = 0x1AF4;
+/// Legacy (transitional) PCI device id of a network card.
+pub const DEVICE_NET: u16 = 0x1000;
+/// Legacy (transitional) PCI device id of a block device.
+pub const DEVICE_BLOCK: u16 = 0x1001;
+
+// legacy registers (offsets from the I/O BAR)
+const REG_DEVICE_FEATURES: u16 = 0x00;
+const REG_GUEST_FEATURES: u16 = 0x04;
+const REG_QUEUE_ADDRESS: u16 = 0x08; // physical page number of the queue
+const REG_QUEUE_SIZE: u16 = 0x0C;
+const REG_QUEUE_SELECT: u16 = 0x0E;
+const REG_QUEUE_NOTIFY: u16 = 0x10;
+const REG_DEVICE_STATUS: u16 = 0x12;
+const REG_ISR_STATUS: u16 = 0x13; // reading it acknowledges the interrupt
+/// Where the device specific configuration starts (without MSI-X).
+pub const DEVICE_CONFIG: u16 = 0x14;
+
+// device status bits
+const STATUS_ACKNOWLEDGE: u8 = 1 << 0; // we found the device
+const STATUS_DRIVER: u8 = 1 << 1; // and know how to drive it
+const STATUS_DRIVER_OK: u8 = 1 << 2; // it's set up
+const STATUS_FAILED: u8 = 1 << 7; // we gave up on it
+
+// descriptor flags
+const DESC_NEXT: u16 = 1 << 0;
+const DESC_WRITE: u16 = 1 << 1; // the device writes the buffer (instead of reading it)
+
+// available ring flags
+const AVAIL_NO_INTERRUPT: u16 = 1 << 0;
+
+// the legacy interface puts the used ring at the next multiple of this
+const QUEUE_ALIGN: usize = 4096;
+const DESCRIPTOR_LEN: usize = 16;
+
+/// Errors while setting up or using a virtio device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VirtioError {
+    /// The device has no I/O BAR --> it doesn't offer the legacy interface.
+    NotLegacy,
+    /// The device lacks a feature the driver needs (the bit number).
+    MissingFeature(u32),
+    /// The queue doesn't exist on the device (its size is 0).
+    NoQueue(u16),
+    /// No DMA memory left for the queue or its buffers.
+    OutOfMemory,
+    /// Not enough free descriptors for the chain.
+    QueueFull,
+    /// The device put a chain into the used ring that doesn't exist (the id it gave, or a descriptor of the chain).
+    BadUsedEntry(u32),
+}
+
+// TRANSPORT ====================================
+
+/// The legacy registers of one virtio PCI device.
+#[derive(Debug, Clone, Copy)]
+pub struct LegacyTransport {
+    io_base: u16,
+}
+
+impl LegacyTransport {
+    /// Find the I/O BAR of `device` and let it access memory by itself (the queues are DMA).
+    pub fn new(device: &PciDevice) -> Result<Self, VirtioError> {
+        let io_base = device
+            .bars
+            .iter()
+            .find_map(|(index, bar)| match bar {
+                Bar::Io { port, .. } if *index == 0 => Some(*port as u16),
+                _ => None,
+            })
+            .ok_or(VirtioError::NotLegacy)?;
+        device.address.enable_bus_mastering();
+        Ok(LegacyTransport { io_base })
+    }
+
+    /// Reset the device and tell it we found it and have a driver, returns the features it offers.
+    pub fn begin_init(&self) -> u32 {
+        self.reset();
+        self.write_u8(REG_DEVICE_STATUS, STATUS_ACKNOWLEDGE);
+        self.write_u8(REG_DEVICE_STATUS, STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+        self.read_u32(REG_DEVICE_FEATURES)
+    }
+
+    /// Accept `features` (a subset of the offered ones).
+    pub fn set_features(&self, features: u32) {
+        self.write_u32(REG_GUEST_FEATURES, features);
+    }
+
+    /// Everything is set up, the device may start using the queues.
+    pub fn finish_init(&self) {
+        let status = self.read_u8(REG_DEVICE_STATUS);
+        self.write_u8(REG_DEVICE_STATUS, status | STATUS_DRIVER_OK);
+    }
+
+    /// Stop the device, it forgets the queues and doesn't touch their memory anymore.
+    pub fn reset(&self) {
+        self.write_u8(REG_DEVICE_STATUS, 0);
+    }
+
+    /// Tell the device we gave up on it.
+    pub fn fail(&self) {
+        let status = self.read_u8(REG_DEVICE_STATUS);
+        self.write_u8(REG_DEVICE_STATUS, status | STATUS_FAILED);
+    }
+
+    /// Create queue `index` with the size the device asks for and give it to the device.
+    pub fn setup_queue(&self, index: u16) -> Result<VirtQueue, VirtioError> {
+        self.write_u16(REG_QUEUE_SELECT, index);
+        let size = self.read_u16(REG_QUEUE_SIZE);
+        if size == 0 {
+            return Err(VirtioError::NoQueue(index));
+        }
+        let queue = VirtQueue::new(index, size)?;
+        // DmaBuffer is page aligned and below 4 GiB --> the page number fits the 32 bit register
+        self.write_u32(REG_QUEUE_ADDRESS, (queue.memory.phys_addr().as_u64() / QUEUE_ALIGN as u64) as u32);
+        Ok(queue)
+    }
+
+    /// Tell the device there is something new in the available ring of `queue`.
+    pub fn notify(&self, queue: &VirtQueue) {
+        mmio_sfence(); // the ring has to be in memory before the device looks at it
+        self.write_u16(REG_QUEUE_NOTIFY, queue.index);
+    }
+
+    /// Read (and acknowledge) the interrupt status: bit 0 = a queue was used, bit 1 = the configuration changed.
+    pub fn interrupt_status(&self) -> u8 {
+        self.read_u8(REG_ISR_STATUS)
+    }
+
+    /// Read a byte of the device specific configuration.
+    pub fn read_config_u8(&self, offset: u16) -> u8 {
+        self.read_u8(DEVICE_CONFIG + offset)
+    }
+
+    fn read_u8(&self, register: u16) -> u8 {
+        unsafe { Port::new(self.io_base + register).read() }
+    }
+
+    fn read_u16(&self, register: u16) -> u16 {
+        unsafe { Port::new(self.io_base + register).read() }
+    }
+
+    fn read_u32(&self, register: u16) -> u32 {
+        unsafe { Port::new(self.io_base + register).read() }
+    }
+
+    fn write_u8(&self, register: u16, value: u8) {
+        unsafe { Port::new(self.io_base + register).write(value) }
+    }
+
+    fn write_u16(&self, register: u16, value: u16) {
+        unsafe { Port::new(self.io_base + register).write(value) }
+    }
+
+    fn write_u32(&self, register: u16, value: u32) {
+        unsafe { Port::new(self.io_base + register).write(value) }
+    }
+}
+
+// QUEUE ====================================
+
+/// One part of a descriptor chain: a buffer the device reads, or writes if `device_writes`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueBuffer {
+    pub addr: PhysAddr,
+    pub len: u32,
+    pub device_writes: bool,
+}
+
+/// A chain the device is done with: the head returned by `VirtQueue::add()` and the bytes it wrote.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UsedChain {
+    pub head: u16,
+    pub len: u32,
+}
+
+/// A split virtqueue (see the top of the file).
+///
+/// The free descriptors are chained through their `next` fields, starting at `free_head`.
+#[derive(Debug)]
+pub struct VirtQueue {
+    index: u16,
+    size: u16,
+    memory: DmaBuffer,
+    used_offset: usize,
+    free_head: u16,
+    free_count: u16,
+    next_avail: u16, // the available index we write next
+    last_used: u16, // the used index up to which we looked
+}
+
+impl VirtQueue {
+    fn new(index: u16, size: u16) -> Result<Self, VirtioError> {
+        let entries = usize::from(size);
+        let used_offset = (DESCRIPTOR_LEN * entries + 6 + 2 * entries).next_multiple_of(QUEUE_ALIGN);
+        let memory = DmaBuffer::new(used_offset + 6 + 8 * entries).ok_or(VirtioError::OutOfMemory)?;
+        let queue = VirtQueue { index, size, memory, used_offset, free_head: 0, free_count: size, next_avail: 0, last_used: 0 };
+        for descriptor in 0..size {
+            queue.write_descriptor(descriptor, PhysAddr::zero(), 0, 0, (descriptor + 1) % size);
+        }
+        // we poll the used ring, the device doesn't have to interrupt
+        unsafe { queue.avail_ptr(0).write_volatile(AVAIL_NO_INTERRUPT) };
+        Ok(queue)
+    }
+
+    /// Number of descriptors.
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// Descriptors not in use by a chain.
+    pub fn free_descriptors(&self) -> u16 {
+        self.free_count
+    }
+
+    /// Hand the chain `buffers` to the device (after `LegacyTransport::notify()`), returns the head of the chain.
+    pub fn add(&mut self, buffers: &[QueueBuffer]) -> Result<u16, VirtioError> {
+        if buffers.is_empty() || buffers.len() > usize::from(self.free_count) {
+            return Err(VirtioError::QueueFull);
+        }
+        let head = self.free_head;
+        let mut descriptor = head;
+        for (i, buffer) in buffers.iter().enumerate() {
+            let next = self.read_next(descriptor);
+            let mut flags = if buffer.device_writes { DESC_WRITE } else { 0 };
+            if i + 1 < buffers.len() {
+                flags |= DESC_NEXT;
+            }
+            self.write_descriptor(descriptor, buffer.addr, buffer.len, flags, next);
+            if i + 1 < buffers.len() {
+                descriptor = next;
+            } else {
+                self.free_head = next;
+            }
+        }
+        self.free_count -= buffers.len() as u16;
+
+        let slot = self.next_avail % self.size;
+        unsafe { self.avail_ptr(2 + usize::from(slot)).write_volatile(head) };
+        mmio_sfence(); // the entry before the index that makes it visible
+        self.next_avail = self.next_avail.wrapping_add(1);
+        unsafe { self.avail_ptr(1).write_volatile(self.next_avail) };
+        Ok(head)
+    }
+
+    /// The next chain the device is done with, its descriptors are free again. `None` if there is none.
+    ///
+    /// The id and the descriptors come from memory the device writes --> checked, a bad entry is skipped with an error
+    /// (its descriptors are lost, the device is broken anyway).
+    pub fn pop_used(&mut self) -> Result<Option<UsedChain>, VirtioError> {
+        let used_index = unsafe { (self.used_ptr(1) as *const u16).read_volatile() };
+        if used_index == self.last_used {
+            return Ok(None);
+        }
+        mmio_lfence(); // the entry is only valid once the index says so
+        let slot = usize::from(self.last_used % self.size);
+        let element = unsafe { self.used_ptr(2 + 4 * slot) as *const u32 };
+        let (id, len) = unsafe { (element.read_volatile(), element.add(1).read_volatile()) };
+        self.last_used = self.last_used.wrapping_add(1);
+
+        if id >= u32::from(self.size) {
+            return Err(VirtioError::BadUsedEntry(id));
+        }
+        let head = id as u16;
+        // give the chain back: its last descriptor points to the old free list
+        // (a chain longer than the free descriptors we handed out, or leaving the table, is a loop or garbage)
+        let mut last = head;
+        let mut count = 1;
+        while self.read_flags(last) & DESC_NEXT != 0 {
+            last = self.read_next(last);
+            count += 1;
+            if last >= self.size || self.free_count + count > self.size {
+                return Err(VirtioError::BadUsedEntry(id));
+            }
+        }
+        self.write_descriptor(last, PhysAddr::zero(), 0, 0, self.free_head);
+        self.free_head = head;
+        self.free_count += count;
+        Ok(Some(UsedChain { head, len }))
+    }
+
+    // the u16 at `index` (in u16s) of the available ring
+    fn avail_ptr(&self, index: usize) -> *mut u16 {
+        unsafe { (self.memory.as_mut_ptr().add(DESCRIPTOR_LEN * usize::from(self.size)) as *mut u16).add(index) }
+    }
+
+    // the u16 at `index` (in u16s) of the used ring
+    fn used_ptr(&self, index: usize) -> *mut u16 {
+        unsafe { (self.memory.as_mut_ptr().add(self.used_offset) as *mut u16).add(index) }
+    }
+
+    fn descriptor_ptr(&self, descriptor: u16) -> *mut u8 {
+        unsafe { self.memory.as_mut_ptr().add(DESCRIPTOR_LEN * usize::from(descriptor)) }
+    }
+
+    fn write_descriptor(&self, descriptor: u16, addr: PhysAddr, len: u32, flags: u16, next: u16) {
+        let ptr = self.descriptor_ptr(descriptor);
+        unsafe {
+            (ptr as *mut u64).write_volatile(addr.as_u64());
+            (ptr.add(8) as *mut u32).write_volatile(len);
+            (ptr.add(12) as *mut u16).write_volatile(flags);
+            (ptr.add(14) as *mut u16).write_volatile(next);
+        }
+    }
+
+    fn read_flags(&self, descriptor: u16) -> u16 {
+        unsafe { (self.descriptor_ptr(descriptor).add(12) as *const u16).read_volatile() }
+    }
+
+    fn read_next(&self, descriptor: u16) -> u16 {
+        unsafe { (self.descriptor_ptr(descriptor).add(14) as *const u16).read_volatile() }
+    }
+}
+
+// TESTS ===================================
+
+#[test_case]
+fn test_virtqueue_descriptor_chains() {
+    let mut queue = VirtQueue::new(0, 8).expect("out of DMA memory");
+    let buffer = |addr: u64, device_writes| QueueBuffer { addr: PhysAddr::new(addr), len: 16, device_writes };
+
+    let first = queue.add(&[buffer(0x1000, false), buffer(0x2000, true)]).expect("queue full");
+    let second = queue.add(&[buffer(0x3000, true)]).expect("queue full");
+    assert_eq!(queue.free_descriptors(), 5);
+    assert_eq!(queue.read_flags(first), DESC_NEXT);
+    assert_eq!(queue.read_flags(queue.read_next(first)), DESC_WRITE);
+    assert_eq!(unsafe { queue.avail_ptr(1).read_volatile() }, 2);
+    assert_eq!(queue.add(&[buffer(0, false); 6]), Err(VirtioError::QueueFull));
+    assert_eq!(queue.pop_used(), Ok(None));
+
+    // play the device: the second chain comes back first, with 12 bytes written
+    unsafe {
+        let element = queue.used_ptr(2) as *mut u32;
+        element.write_volatile(u32::from(second));
+        element.add(1).write_volatile(12);
+        queue.used_ptr(1).write_volatile(1);
+    }
+    assert_eq!(queue.pop_used(), Ok(Some(UsedChain { head: second, len: 12 })));
+    assert_eq!(queue.pop_used(), Ok(None));
+    assert_eq!(queue.free_descriptors(), 6);
+    // the freed descriptor is reused before the untouched ones
+    assert_eq!(queue.add(&[buffer(0x4000, false)]), Ok(second));
+
+    // a broken device: an id past the descriptor table is refused and skipped, the free list stays as it was
+    unsafe {
+        let element = queue.used_ptr(2 + 4) as *mut u32;
+        element.write_volatile(8);
+        element.add(1).write_volatile(0);
+        queue.used_ptr(1).write_volatile(2);
+    }
+    assert_eq!(queue.pop_used(), Err(VirtioError::BadUsedEntry(8)));
+    assert_eq!(queue.pop_used(), Ok(None));
+    assert_eq!(queue.free_descriptors(), 5);
+}
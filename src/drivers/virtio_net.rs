@@ -0,0 +1,253 @@
+// Virtio network card --> QEMU's `-device virtio-net-pci` (see Cargo.toml), through the legacy interface (see virtio.rs)
+// - queue 0 receives, queue 1 transmits, every frame is preceded by a 10 byte virtio_net_hdr (checksum offload and
+//   segmentation offload, all zero as we negotiate neither)
+// - the header and the frame are two descriptors of one chain: a legacy device without VIRTIO_F_ANY_LAYOUT may expect
+//   them apart
+// - the MAC address is in the device configuration, which only holds it with VIRTIO_NET_F_MAC
+// - RX_BUFFERS receive buffers are handed to the device up front, each one goes back to it as soon as its frame is copied out
+//
+// polled for now: recv_ethernet_frame() looks at the used ring and the device is told not to interrupt --> there is no
+// rx sink, so it isn't a NetworkDevice (yet), the RTL8139 stays the interface
+use super::pci::PciDevice;
+use super::virtio::{LegacyTransport, QueueBuffer, VirtQueue, VirtioError};
+use crate::memory::{dma::DmaBuffer, volatile_copy_to, volatile_zero};
+use crate::net::ethernet::MAX_FRAME_LEN;
+use crate::net::{MacAddress, NetError};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+// feature bits
+const NET_F_MAC: u32 = 1 << 5;
+const NET_F_MAC_BIT: u32 = 5;
+
+const QUEUE_RX: u16 = 0;
+const QUEUE_TX: u16 = 1;
+
+// the legacy virtio_net_hdr without VIRTIO_NET_F_MRG_RXBUF
+const NET_HEADER_LEN: usize = 10;
+// every buffer is [header][padding][frame], the frame 16 byte aligned
+const FRAME_OFFSET: usize = 16;
+const BUFFER_LEN: usize = 2048;
+
+const RX_BUFFERS: usize = 16;
+const TX_BUFFERS: usize = 16;
+
+// how many times the used ring is checked for a free transmit buffer before giving up (see rtl8139.rs)
+const POLL_LIMIT: u32 = 1_000_000;
+
+struct RxQueue {
+    queue: VirtQueue,
+    buffers: DmaBuffer, // RX_BUFFERS * BUFFER_LEN
+    heads: [u16; RX_BUFFERS], // the chain of every buffer
+}
+
+struct TxQueue {
+    queue: VirtQueue,
+    buffers: DmaBuffer, // TX_BUFFERS * BUFFER_LEN
+    heads: [Option<u16>; TX_BUFFERS], // the chain of every buffer the device still has
+}
+
+// the chain [header][frame] of buffer `index` in `buffers`
+fn buffer_chain(buffers: &DmaBuffer, index: usize, frame_len: usize, device_writes: bool) -> [QueueBuffer; 2] {
+    let base = buffers.phys_addr() + (index * BUFFER_LEN) as u64;
+    [
+        QueueBuffer { addr: base, len: NET_HEADER_LEN as u32, device_writes },
+        QueueBuffer { addr: base + FRAME_OFFSET as u64, len: frame_len as u32, device_writes },
+    ]
+}
+
+/// A virtio network card.
+///
+/// Like the RTL8139 the two queues have their own locks, taken with interrupts off.
+pub struct VirtioNet {
+    pci: PciDevice,
+    transport: LegacyTransport,
+    mac: MacAddress,
+    rx_queue: Mutex<RxQueue>,
+    tx_queue: Mutex<TxQueue>,
+}
+
+impl VirtioNet {
+    /// Set up the card `pci` (a virtio PCI function with device id `DEVICE_NET`) and fill its receive queue.
+    pub fn init(pci: PciDevice) -> Result<Self, VirtioError> {
+        let transport = LegacyTransport::new(&pci)?;
+        VirtioNet::setup(pci, transport).map_err(|error| {
+            transport.fail();
+            error
+        })
+    }
+
+    fn setup(pci: PciDevice, transport: LegacyTransport) -> Result<Self, VirtioError> {
+        let features = transport.begin_init();
+        if features & NET_F_MAC == 0 {
+            return Err(VirtioError::MissingFeature(NET_F_MAC_BIT));
+        }
+        transport.set_features(NET_F_MAC);
+
+        let mut mac = [0; 6];
+        for (i, byte) in mac.iter_mut().enumerate() {
+            *byte = transport.read_config_u8(i as u16);
+        }
+
+        let mut rx = RxQueue {
+            queue: transport.setup_queue(QUEUE_RX)?,
+            buffers: DmaBuffer::new(RX_BUFFERS * BUFFER_LEN).ok_or(VirtioError::OutOfMemory)?,
+            heads: [0; RX_BUFFERS],
+        };
+        let tx = TxQueue {
+            queue: transport.setup_queue(QUEUE_TX)?,
+            buffers: DmaBuffer::new(TX_BUFFERS * BUFFER_LEN).ok_or(VirtioError::OutOfMemory)?,
+            heads: [None; TX_BUFFERS],
+        };
+        for index in 0..RX_BUFFERS {
+            rx.heads[index] = rx.queue.add(&buffer_chain(&rx.buffers, index, MAX_FRAME_LEN, true))?;
+        }
+        transport.finish_init();
+        transport.notify(&rx.queue);
+
+        Ok(VirtioNet { pci, transport, mac: MacAddress(mac), rx_queue: Mutex::new(rx), tx_queue: Mutex::new(tx) })
+    }
+
+    /// The card's MAC address (from its device configuration).
+    pub fn mac(&self) -> MacAddress {
+        self.mac
+    }
+
+    /// The PCI function of the card.
+    pub fn pci(&self) -> &PciDevice {
+        &self.pci
+    }
+
+    /// Send `data` (a whole frame starting with the destination MAC, without CRC).
+    ///
+    /// Returns once the device has the frame, waits for a free transmit buffer first if all of them are in use.
+    pub fn send_ethernet_frame(&self, data: &[u8]) -> Result<(), NetError> {
+        if data.len() > MAX_FRAME_LEN {
+            return Err(NetError::FrameTooLarge);
+        }
+        interrupts::without_interrupts(|| {
+            let mut tx = self.tx_queue.lock();
+            let index = (0..POLL_LIMIT)
+                .find_map(|_| {
+                    // the buffers of the frames the device has sent are free again (a bad entry ends the loop, the
+                    // queue already moved past it)
+                    while let Ok(Some(used)) = tx.queue.pop_used() {
+                        if let Some(slot) = tx.heads.iter_mut().find(|head| **head == Some(used.head)) {
+                            *slot = None;
+                        }
+                    }
+                    tx.heads.iter().position(Option::is_none)
+                })
+                .ok_or(NetError::Timeout)?;
+
+            unsafe {
+                let buffer = tx.buffers.as_mut_ptr().add(index * BUFFER_LEN);
+                volatile_zero(buffer, NET_HEADER_LEN);
+                volatile_copy_to(buffer.add(FRAME_OFFSET), data.as_ptr(), data.len());
+            }
+            let chain = buffer_chain(&tx.buffers, index, data.len(), false);
+            let head = tx.queue.add(&chain).map_err(|_| NetError::TransmitFailed)?;
+            tx.heads[index] = Some(head);
+            self.transport.notify(&tx.queue);
+            Ok(())
+        })
+    }
+
+    /// Copy the next received frame (without CRC) into `buf` and return its length, 0 if nothing was received.
+    ///
+    /// A frame longer than `buf` is dropped with `FrameTooLarge`.
+    pub fn recv_ethernet_frame(&self, buf: &mut [u8]) -> Result<usize, NetError> {
+        interrupts::without_interrupts(|| {
+            let mut rx = self.rx_queue.lock();
+            let used = match rx.queue.pop_used() {
+                Ok(Some(used)) => used,
+                Ok(None) | Err(_) => return Ok(0), // a bad entry was skipped, nothing to give back
+            };
+            let index = match rx.heads.iter().position(|&head| head == used.head) {
+                Some(index) => index,
+                None => return Ok(0), // not one of ours, nothing to give back
+            };
+            // the length covers the header too
+            let frame_len = (used.len as usize).saturating_sub(NET_HEADER_LEN);
+            let result = if frame_len > buf.len() {
+                Err(NetError::FrameTooLarge)
+            } else {
+                unsafe {
+                    let frame = rx.buffers.as_mut_ptr().add(index * BUFFER_LEN + FRAME_OFFSET);
+                    volatile_copy_to(buf.as_mut_ptr(), frame, frame_len);
+                }
+                Ok(frame_len)
+            };
+
+            // the chain just came back --> there is room to hand the buffer to the device again
+            let chain = buffer_chain(&rx.buffers, index, MAX_FRAME_LEN, true);
+            if let Ok(head) = rx.queue.add(&chain) {
+                rx.heads[index] = head;
+                self.transport.notify(&rx.queue);
+            }
+            result
+        })
+    }
+}
+
+impl Drop for VirtioNet {
+    // the device must stop writing into the buffers before they go back to the frame allocator
+    fn drop(&mut self) {
+        self.transport.reset();
+    }
+}
+
+// TESTS ===================================
+
+#[test_case]
+fn test_virtio_net_mac_and_arp() {
+    use super::pci;
+    use super::virtio::{DEVICE_NET, VENDOR_VIRTIO};
+    use crate::net::arp::{ArpPacket, OPCODE_REPLY, OPCODE_REQUEST};
+    use crate::net::ethernet::{EthernetFrame, ETHERTYPE_ARP};
+    use crate::net::Ipv4Address;
+
+    pci::init();
+    let device = pci::devices()
+        .iter()
+        .find(|device| device.vendor_id == VENDOR_VIRTIO && device.device_id == DEVICE_NET)
+        .expect("no virtio-net card, is QEMU started with -device virtio-net-pci?");
+    let nic = VirtioNet::init(device.clone()).expect("virtio-net setup failed");
+    // QEMU's OUI: locally administered, unicast
+    assert_eq!(nic.mac().0[..3], [0x52, 0x54, 0x00]);
+    assert!(!nic.mac().is_multicast());
+    assert_eq!(nic.send_ethernet_frame(&[0; MAX_FRAME_LEN + 1]), Err(NetError::FrameTooLarge));
+
+    // ask QEMU's gateway for its MAC address, it answers on this card's own user network
+    let gateway = Ipv4Address([10, 0, 2, 2]);
+    let request = ArpPacket {
+        opcode: OPCODE_REQUEST,
+        sender_mac: nic.mac(),
+        sender_ip: Ipv4Address([10, 0, 2, 15]),
+        target_mac: MacAddress([0; 6]),
+        target_ip: gateway,
+    };
+    let payload = request.to_bytes();
+    let mut frame = [0; MAX_FRAME_LEN];
+    let len = EthernetFrame { destination: MacAddress::BROADCAST, source: nic.mac(), ethertype: ETHERTYPE_ARP, payload: &payload }
+        .build(&mut frame)
+        .expect("ARP request doesn't fit a frame");
+    nic.send_ethernet_frame(&frame[..len]).expect("send failed");
+
+    let deadline = crate::interrupts::timer_ticks() + u64::from(crate::config::get().timer_hz);
+    let mut replied = false;
+    while !replied && crate::interrupts::timer_ticks() < deadline {
+        let mut buf = [0; MAX_FRAME_LEN];
+        match nic.recv_ethernet_frame(&mut buf).expect("receive failed") {
+            0 => x86_64::instructions::hlt(),
+            len => {
+                replied = EthernetFrame::parse(&buf[..len])
+                    .ok()
+                    .filter(|frame| frame.ethertype == ETHERTYPE_ARP)
+                    .and_then(|frame| ArpPacket::parse(frame.payload))
+                    .map_or(false, |reply| reply.opcode == OPCODE_REPLY && reply.sender_ip == gateway);
+            }
+        }
+    }
+    assert!(replied, "no ARP reply from the gateway");
+}
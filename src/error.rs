@@ -0,0 +1,786 @@
+// A common interface for the error types of the kernel's subsystems (the equivalent of std::error::Error, which core doesn't give us here)
+// every variant of every error type maps to its own negative error code --> each type gets its own block of codes,
+// so a code on its own (ex. in a log or returned from a syscall) says exactly what went wrong
+use crate::acpi::AcpiError;
+use crate::BootInfoError;
+use crate::config::LogLevel;
+use crate::drivers::ata::AtaError;
+use crate::drivers::block::BlockError;
+use crate::drivers::virtio::VirtioError;
+use crate::fs::fat::FatError;
+use crate::fs::FsError;
+use crate::interrupts::KeyboardError;
+use crate::ipc::IpcError;
+use crate::klog;
+use crate::kshell::ShellError;
+use crate::memory::address_space::AddressSpaceError;
+use crate::net::NetError;
+use crate::process::elf::ElfError;
+use crate::smbios::SmbiosError;
+use crate::smp::SmpError;
+use core::fmt;
+
+/// Implemented by every kernel error type.
+pub trait KernelError: fmt::Display + fmt::Debug {
+    /// A negative number unique to this error (type and variant).
+    fn error_code(&self) -> i64;
+    /// Whether trying again later could succeed (ex. out of memory), as opposed to bad input or broken hardware.
+    fn is_recoverable(&self) -> bool;
+}
+
+/// Log an error (with its code) at the error level.
+pub fn log_error(e: &dyn KernelError) {
+    klog!(LogLevel::Error, "Error {}: {}", e.error_code(), e);
+}
+
+// ADDRESS SPACE ERRORS (-100..) =============================
+
+impl fmt::Display for AddressSpaceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AddressSpaceError::NotUserAddress => write!(f, "address is not in user space"),
+            AddressSpaceError::AlreadyMapped => write!(f, "page is already mapped"),
+            AddressSpaceError::FrameAllocationFailed => write!(f, "out of physical frames"),
+        }
+    }
+}
+
+impl KernelError for AddressSpaceError {
+    fn error_code(&self) -> i64 {
+        match self {
+            AddressSpaceError::NotUserAddress => -100,
+            AddressSpaceError::AlreadyMapped => -101,
+            AddressSpaceError::FrameAllocationFailed => -102,
+        }
+    }
+
+    fn is_recoverable(&self) -> bool {
+        *self == AddressSpaceError::FrameAllocationFailed
+    }
+}
+
+// ELF ERRORS (-200..) =============================
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ElfError::TooShort => write!(f, "elf: file too short"),
+            ElfError::BadMagic => write!(f, "elf: bad magic number"),
+            ElfError::UnsupportedClass => write!(f, "elf: not a 64 bit file"),
+            ElfError::UnsupportedEndianness => write!(f, "elf: not little endian"),
+            ElfError::UnsupportedMachine => write!(f, "elf: not an x86_64 file"),
+            ElfError::UnsupportedType => write!(f, "elf: not an executable"),
+            ElfError::BadProgramHeaders => write!(f, "elf: bad program header table"),
+            ElfError::SegmentOutOfBounds => write!(f, "elf: segment extends past the end of the file"),
+            ElfError::SizeOverflow => write!(f, "elf: size or address overflow"),
+            ElfError::BadSegmentSize => write!(f, "elf: segment file size larger than memory size"),
+            ElfError::MisalignedSegment => write!(f, "elf: misaligned segment"),
+            ElfError::OverlappingSegments => write!(f, "elf: overlapping segments"),
+            ElfError::BadEntryPoint => write!(f, "elf: entry point outside of executable code"),
+            ElfError::Map(error) => write!(f, "elf: mapping failed: {}", error),
+        }
+    }
+}
+
+impl KernelError for ElfError {
+    fn error_code(&self) -> i64 {
+        match self {
+            ElfError::TooShort => -200,
+            ElfError::BadMagic => -201,
+            ElfError::UnsupportedClass => -202,
+            ElfError::UnsupportedEndianness => -203,
+            ElfError::UnsupportedMachine => -204,
+            ElfError::UnsupportedType => -205,
+            ElfError::BadProgramHeaders => -206,
+            ElfError::SegmentOutOfBounds => -207,
+            ElfError::SizeOverflow => -208,
+            ElfError::BadSegmentSize => -209,
+            ElfError::MisalignedSegment => -210,
+            ElfError::OverlappingSegments => -211,
+            ElfError::BadEntryPoint => -212,
+            ElfError::Map(_) => -213, // which mapping error it was is in the Display output
+        }
+    }
+
+    fn is_recoverable(&self) -> bool {
+        match self {
+            ElfError::Map(error) => error.is_recoverable(),
+            _ => false, // a malformed file stays malformed
+        }
+    }
+}
+
+// ATA ERRORS (-300..) =============================
+
+impl fmt::Display for AtaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AtaError::NoDevice => write!(f, "ata: no drive"),
+            AtaError::NotAta => write!(f, "ata: not an ATA hard disk"),
+            AtaError::Timeout => write!(f, "ata: drive timed out"),
+            AtaError::DeviceError(error) => write!(f, "ata: drive reported error {:#04x}", error),
+            AtaError::DeviceFault => write!(f, "ata: drive fault"),
+            AtaError::OutOfRange => write!(f, "ata: sector out of range"),
+            AtaError::BadBuffer => write!(f, "ata: buffer doesn't match the sector count"),
+        }
+    }
+}
+
+impl KernelError for AtaError {
+    fn error_code(&self) -> i64 {
+        match self {
+            AtaError::NoDevice => -300,
+            AtaError::NotAta => -301,
+            AtaError::Timeout => -302,
+            AtaError::DeviceError(_) => -303,
+            AtaError::DeviceFault => -304,
+            AtaError::OutOfRange => -305,
+            AtaError::BadBuffer => -306,
+        }
+    }
+
+    fn is_recoverable(&self) -> bool {
+        // a slow drive may answer next time, anything else won't change by retrying
+        *self == AtaError::Timeout
+    }
+}
+
+// BLOCK DEVICE ERRORS (-400..) =============================
+
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BlockError::OutOfRange => write!(f, "block: block out of range"),
+            BlockError::BadBuffer => write!(f, "block: buffer isn't a multiple of the block size"),
+            BlockError::NameInUse => write!(f, "block: device name already registered"),
+            BlockError::ReadOnly => write!(f, "block: device is read-only"),
+            BlockError::Ata(error) => write!(f, "block: {}", error),
+        }
+    }
+}
+
+impl KernelError for BlockError {
+    fn error_code(&self) -> i64 {
+        match self {
+            BlockError::OutOfRange => -400,
+            BlockError::BadBuffer => -401,
+            BlockError::NameInUse => -402,
+            BlockError::ReadOnly => -403,
+            BlockError::Ata(error) => error.error_code(),
+        }
+    }
+
+    fn is_recoverable(&self) -> bool {
+        match self {
+            BlockError::Ata(error) => error.is_recoverable(),
+            _ => false,
+        }
+    }
+}
+
+// FAT ERRORS (-500..) =============================
+
+impl fmt::Display for FatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FatError::Block(error) => write!(f, "fat: {}", error),
+            FatError::BadBootSector => write!(f, "fat: not a FAT volume"),
+            FatError::Unsupported => write!(f, "fat: unsupported volume (FAT32 or sector size)"),
+            FatError::Truncated => write!(f, "fat: volume larger than its device"),
+            FatError::BadCluster => write!(f, "fat: bad cluster"),
+            FatError::BadChain => write!(f, "fat: broken cluster chain"),
+            FatError::UnexpectedEndOfChain => write!(f, "fat: cluster chain shorter than the file"),
+            FatError::NotFound => write!(f, "fat: no such file or directory"),
+            FatError::NotADirectory => write!(f, "fat: not a directory"),
+            FatError::IsADirectory => write!(f, "fat: is a directory"),
+        }
+    }
+}
+
+impl KernelError for FatError {
+    fn error_code(&self) -> i64 {
+        match self {
+            FatError::Block(error) => error.error_code(),
+            FatError::BadBootSector => -500,
+            FatError::Unsupported => -501,
+            FatError::Truncated => -502,
+            FatError::BadCluster => -503,
+            FatError::BadChain => -504,
+            FatError::UnexpectedEndOfChain => -505,
+            FatError::NotFound => -506,
+            FatError::NotADirectory => -507,
+            FatError::IsADirectory => -508,
+        }
+    }
+
+    fn is_recoverable(&self) -> bool {
+        match self {
+            FatError::Block(error) => error.is_recoverable(),
+            _ => false,
+        }
+    }
+}
+
+// VFS ERRORS (-600..) =============================
+
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FsError::RelativePath => write!(f, "fs: path is not absolute"),
+            FsError::NotFound => write!(f, "fs: no such file or directory"),
+            FsError::NotADirectory => write!(f, "fs: not a directory"),
+            FsError::IsADirectory => write!(f, "fs: is a directory"),
+            FsError::NotMounted => write!(f, "fs: no filesystem mounted there"),
+            FsError::AlreadyMounted => write!(f, "fs: a filesystem is already mounted there"),
+            FsError::ReadOnly => write!(f, "fs: file is read-only"),
+            FsError::Fat(error) => write!(f, "fs: {}", error),
+        }
+    }
+}
+
+impl KernelError for FsError {
+    fn error_code(&self) -> i64 {
+        match self {
+            FsError::RelativePath => -600,
+            FsError::NotFound => -601,
+            FsError::NotADirectory => -602,
+            FsError::IsADirectory => -603,
+            FsError::NotMounted => -604,
+            FsError::AlreadyMounted => -605,
+            FsError::ReadOnly => -606,
+            FsError::Fat(error) => error.error_code(),
+        }
+    }
+
+    fn is_recoverable(&self) -> bool {
+        match self {
+            FsError::Fat(error) => error.is_recoverable(),
+            _ => false,
+        }
+    }
+}
+
+// NETWORK ERRORS (-700..) =============================
+
+impl fmt::Display for NetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NetError::NoDevice => write!(f, "net: no network card"),
+            NetError::FrameTooLarge => write!(f, "net: frame too large"),
+            NetError::Timeout => write!(f, "net: network card timed out"),
+            NetError::TransmitFailed => write!(f, "net: transmission failed"),
+            NetError::NoAddress => write!(f, "net: no IPv4 address set"),
+        }
+    }
+}
+
+impl KernelError for NetError {
+    fn error_code(&self) -> i64 {
+        match self {
+            NetError::NoDevice => -700,
+            NetError::FrameTooLarge => -701,
+            NetError::Timeout => -702,
+            NetError::TransmitFailed => -703,
+            NetError::NoAddress => -704,
+        }
+    }
+
+    fn is_recoverable(&self) -> bool {
+        // a busy card, a collision or a slow peer may be gone next time
+        matches!(self, NetError::Timeout | NetError::TransmitFailed)
+    }
+}
+
+// IPC ERRORS (-800..) =============================
+
+impl fmt::Display for IpcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IpcError::Closed => write!(f, "ipc: queue closed"),
+        }
+    }
+}
+
+impl KernelError for IpcError {
+    fn error_code(&self) -> i64 {
+        match self {
+            IpcError::Closed => -800,
+        }
+    }
+
+    fn is_recoverable(&self) -> bool {
+        false // nobody receives anymore
+    }
+}
+
+// ACPI ERRORS (-900..) =============================
+
+impl fmt::Display for AcpiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AcpiError::NoRsdp => write!(f, "acpi: no RSDP found"),
+            AcpiError::UnsupportedRevision(revision) => write!(f, "acpi: unsupported RSDP revision {}", revision),
+            AcpiError::BadChecksum(signature) => write!(f, "acpi: bad checksum in {}", signature),
+            AcpiError::BadLength(signature) => write!(f, "acpi: bad length of {}", signature),
+            AcpiError::WrongSignature(signature) => write!(f, "acpi: unexpected table {}", signature),
+            AcpiError::NotFound(signature) => write!(f, "acpi: no {} table", signature),
+            AcpiError::BadAddress(address) => write!(f, "acpi: bad table address {:#x}", address),
+        }
+    }
+}
+
+impl KernelError for AcpiError {
+    fn error_code(&self) -> i64 {
+        match self {
+            AcpiError::NoRsdp => -900,
+            AcpiError::UnsupportedRevision(_) => -901,
+            AcpiError::BadChecksum(_) => -902,
+            AcpiError::BadLength(_) => -903,
+            AcpiError::WrongSignature(_) => -904,
+            AcpiError::NotFound(_) => -905,
+            AcpiError::BadAddress(_) => -906,
+        }
+    }
+
+    fn is_recoverable(&self) -> bool {
+        false // the firmware's tables don't change
+    }
+}
+
+// KEYBOARD ERRORS (-1000..) =============================
+
+impl fmt::Display for KeyboardError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeyboardError::UnsupportedScancodeSet(set) => write!(f, "keyboard: no decoder for scancode set {}", set),
+            KeyboardError::Timeout => write!(f, "keyboard: controller timed out"),
+            KeyboardError::NoAck(byte) => write!(f, "keyboard: {:#04x} not acknowledged", byte),
+        }
+    }
+}
+
+impl KernelError for KeyboardError {
+    fn error_code(&self) -> i64 {
+        match self {
+            KeyboardError::UnsupportedScancodeSet(_) => -1000,
+            KeyboardError::Timeout => -1001,
+            KeyboardError::NoAck(_) => -1002,
+        }
+    }
+
+    fn is_recoverable(&self) -> bool {
+        !matches!(self, KeyboardError::UnsupportedScancodeSet(_))
+    }
+}
+
+// SMBIOS ERRORS (-1100..) =============================
+
+impl fmt::Display for SmbiosError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SmbiosError::NoEntryPoint => write!(f, "smbios: no entry point found"),
+            SmbiosError::BadChecksum => write!(f, "smbios: bad entry point checksum"),
+            SmbiosError::BadLength => write!(f, "smbios: bad entry point or table length"),
+            SmbiosError::BadAddress(address) => write!(f, "smbios: bad table address {:#x}", address),
+        }
+    }
+}
+
+impl KernelError for SmbiosError {
+    fn error_code(&self) -> i64 {
+        match self {
+            SmbiosError::NoEntryPoint => -1100,
+            SmbiosError::BadChecksum => -1101,
+            SmbiosError::BadLength => -1102,
+            SmbiosError::BadAddress(_) => -1103,
+        }
+    }
+
+    fn is_recoverable(&self) -> bool {
+        false // the firmware's tables don't change
+    }
+}
+
+// SMP ERRORS (-1200..) =============================
+
+impl fmt::Display for SmpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SmpError::Acpi(error) => write!(f, "smp: can't read the MADT: {}", error),
+            SmpError::TrampolineInUse => write!(f, "smp: the trampoline page is in use"),
+            SmpError::OutOfMemory => write!(f, "smp: out of memory"),
+            SmpError::PageTablesTooHigh => write!(f, "smp: page tables above 4 GiB"),
+        }
+    }
+}
+
+impl KernelError for SmpError {
+    fn error_code(&self) -> i64 {
+        match self {
+            SmpError::Acpi(error) => error.error_code(),
+            SmpError::TrampolineInUse => -1200,
+            SmpError::OutOfMemory => -1201,
+            SmpError::PageTablesTooHigh => -1202,
+        }
+    }
+
+    fn is_recoverable(&self) -> bool {
+        matches!(self, SmpError::OutOfMemory)
+    }
+}
+
+// VIRTIO ERRORS (-1300..) =============================
+
+impl fmt::Display for VirtioError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VirtioError::NotLegacy => write!(f, "virtio: no legacy I/O interface"),
+            VirtioError::MissingFeature(bit) => write!(f, "virtio: device lacks feature bit {}", bit),
+            VirtioError::NoQueue(index) => write!(f, "virtio: device has no queue {}", index),
+            VirtioError::OutOfMemory => write!(f, "virtio: out of DMA memory"),
+            VirtioError::QueueFull => write!(f, "virtio: queue full"),
+            VirtioError::BadUsedEntry(id) => write!(f, "virtio: bad used ring entry {}", id),
+        }
+    }
+}
+
+impl KernelError for VirtioError {
+    fn error_code(&self) -> i64 {
+        match self {
+            VirtioError::NotLegacy => -1300,
+            VirtioError::MissingFeature(_) => -1301,
+            VirtioError::NoQueue(_) => -1302,
+            VirtioError::OutOfMemory => -1303,
+            VirtioError::QueueFull => -1304,
+            VirtioError::BadUsedEntry(_) => -1305,
+        }
+    }
+
+    fn is_recoverable(&self) -> bool {
+        // the device takes the chains back eventually
+        matches!(self, VirtioError::OutOfMemory | VirtioError::QueueFull)
+    }
+}
+
+// SHELL ERRORS (-1400..) =============================
+// not part of UnifiedError: a command's error may wrap any other error, a UnifiedError included
+
+impl fmt::Display for ShellError {
+    // printed after the command's name (see kshell::run_line())
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ShellError::UnknownCommand => write!(f, "unknown command"),
+            ShellError::AlreadyRegistered => write!(f, "command already registered"),
+            ShellError::Usage(usage) => write!(f, "usage: {}", usage),
+            ShellError::BadArgument => write!(f, "bad argument"),
+            ShellError::Refused(reason) => write!(f, "{}", reason),
+            ShellError::Failed(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl KernelError for ShellError {
+    fn error_code(&self) -> i64 {
+        match self {
+            ShellError::UnknownCommand => -1400,
+            ShellError::AlreadyRegistered => -1401,
+            ShellError::Usage(_) => -1402,
+            ShellError::BadArgument => -1403,
+            ShellError::Refused(_) => -1404,
+            ShellError::Failed(error) => error.error_code(),
+        }
+    }
+
+    fn is_recoverable(&self) -> bool {
+        match self {
+            ShellError::Failed(error) => error.is_recoverable(),
+            _ => false,
+        }
+    }
+}
+
+// BOOT INFO ERRORS (-1500..) =============================
+// not part of UnifiedError: a bad BootInfo stops the boot (see check_boot_info_version()), nothing returns it
+
+impl fmt::Display for BootInfoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BootInfoError::VersionMismatch { expected, found } => write!(
+                f,
+                "bootloader API version {}.{}.{}, the kernel expects {}.{}.{}",
+                found.major, found.minor, found.patch, expected.major, expected.minor, expected.patch
+            ),
+            BootInfoError::BadMemoryMap => write!(f, "memory map makes no sense"),
+            BootInfoError::BadPhysicalMemoryOffset(offset) => write!(f, "bad physical memory offset {:#x}", offset),
+        }
+    }
+}
+
+impl KernelError for BootInfoError {
+    fn error_code(&self) -> i64 {
+        match self {
+            BootInfoError::VersionMismatch { .. } => -1500,
+            BootInfoError::BadMemoryMap => -1501,
+            BootInfoError::BadPhysicalMemoryOffset(_) => -1502,
+        }
+    }
+
+    fn is_recoverable(&self) -> bool {
+        false
+    }
+}
+
+// UNIFIED ERROR =============================
+
+/// Any kernel error, for code that calls into several subsystems and wants a single error type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnifiedError {
+    AddressSpace(AddressSpaceError),
+    Elf(ElfError),
+    Ata(AtaError),
+    Block(BlockError),
+    Fat(FatError),
+    Fs(FsError),
+    Net(NetError),
+    Ipc(IpcError),
+    Acpi(AcpiError),
+    Keyboard(KeyboardError),
+    Smbios(SmbiosError),
+    Smp(SmpError),
+    Virtio(VirtioError),
+}
+
+impl UnifiedError {
+    fn inner(&self) -> &dyn KernelError {
+        match self {
+            UnifiedError::AddressSpace(error) => error,
+            UnifiedError::Elf(error) => error,
+            UnifiedError::Ata(error) => error,
+            UnifiedError::Block(error) => error,
+            UnifiedError::Fat(error) => error,
+            UnifiedError::Fs(error) => error,
+            UnifiedError::Net(error) => error,
+            UnifiedError::Ipc(error) => error,
+            UnifiedError::Acpi(error) => error,
+            UnifiedError::Keyboard(error) => error,
+            UnifiedError::Smbios(error) => error,
+            UnifiedError::Smp(error) => error,
+            UnifiedError::Virtio(error) => error,
+        }
+    }
+}
+
+impl fmt::Display for UnifiedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self.inner(), f)
+    }
+}
+
+impl KernelError for UnifiedError {
+    fn error_code(&self) -> i64 {
+        self.inner().error_code()
+    }
+
+    fn is_recoverable(&self) -> bool {
+        self.inner().is_recoverable()
+    }
+}
+
+impl From<AddressSpaceError> for UnifiedError {
+    fn from(error: AddressSpaceError) -> Self {
+        UnifiedError::AddressSpace(error)
+    }
+}
+
+impl From<ElfError> for UnifiedError {
+    fn from(error: ElfError) -> Self {
+        UnifiedError::Elf(error)
+    }
+}
+
+impl From<AtaError> for UnifiedError {
+    fn from(error: AtaError) -> Self {
+        UnifiedError::Ata(error)
+    }
+}
+
+impl From<BlockError> for UnifiedError {
+    fn from(error: BlockError) -> Self {
+        UnifiedError::Block(error)
+    }
+}
+
+impl From<FatError> for UnifiedError {
+    fn from(error: FatError) -> Self {
+        UnifiedError::Fat(error)
+    }
+}
+
+impl From<FsError> for UnifiedError {
+    fn from(error: FsError) -> Self {
+        UnifiedError::Fs(error)
+    }
+}
+
+impl From<NetError> for UnifiedError {
+    fn from(error: NetError) -> Self {
+        UnifiedError::Net(error)
+    }
+}
+
+impl From<IpcError> for UnifiedError {
+    fn from(error: IpcError) -> Self {
+        UnifiedError::Ipc(error)
+    }
+}
+
+impl From<AcpiError> for UnifiedError {
+    fn from(error: AcpiError) -> Self {
+        UnifiedError::Acpi(error)
+    }
+}
+
+impl From<KeyboardError> for UnifiedError {
+    fn from(error: KeyboardError) -> Self {
+        UnifiedError::Keyboard(error)
+    }
+}
+
+impl From<SmbiosError> for UnifiedError {
+    fn from(error: SmbiosError) -> Self {
+        UnifiedError::Smbios(error)
+    }
+}
+
+impl From<SmpError> for UnifiedError {
+    fn from(error: SmpError) -> Self {
+        UnifiedError::Smp(error)
+    }
+}
+
+impl From<VirtioError> for UnifiedError {
+    fn from(error: VirtioError) -> Self {
+        UnifiedError::Virtio(error)
+    }
+}
+
+// TESTS ===================================
+
+#[test_case]
+fn test_error_codes_unique_and_negative() {
+    use alloc::{format, vec, vec::Vec};
+
+    let errors: Vec<UnifiedError> = vec![
+        AddressSpaceError::NotUserAddress.into(),
+        AddressSpaceError::AlreadyMapped.into(),
+        AddressSpaceError::FrameAllocationFailed.into(),
+        ElfError::TooShort.into(),
+        ElfError::BadMagic.into(),
+        ElfError::UnsupportedClass.into(),
+        ElfError::UnsupportedEndianness.into(),
+        ElfError::UnsupportedMachine.into(),
+        ElfError::UnsupportedType.into(),
+        ElfError::BadProgramHeaders.into(),
+        ElfError::SegmentOutOfBounds.into(),
+        ElfError::SizeOverflow.into(),
+        ElfError::BadSegmentSize.into(),
+        ElfError::MisalignedSegment.into(),
+        ElfError::OverlappingSegments.into(),
+        ElfError::BadEntryPoint.into(),
+        ElfError::Map(AddressSpaceError::NotUserAddress).into(),
+        AtaError::NoDevice.into(),
+        AtaError::NotAta.into(),
+        AtaError::Timeout.into(),
+        AtaError::DeviceError(0x04).into(),
+        AtaError::DeviceFault.into(),
+        AtaError::OutOfRange.into(),
+        AtaError::BadBuffer.into(),
+        BlockError::OutOfRange.into(),
+        BlockError::BadBuffer.into(),
+        BlockError::NameInUse.into(),
+        BlockError::ReadOnly.into(),
+        FatError::BadBootSector.into(),
+        FatError::Unsupported.into(),
+        FatError::Truncated.into(),
+        FatError::BadCluster.into(),
+        FatError::BadChain.into(),
+        FatError::UnexpectedEndOfChain.into(),
+        FatError::NotFound.into(),
+        FatError::NotADirectory.into(),
+        FatError::IsADirectory.into(),
+        FsError::RelativePath.into(),
+        FsError::NotFound.into(),
+        FsError::NotADirectory.into(),
+        FsError::IsADirectory.into(),
+        FsError::NotMounted.into(),
+        FsError::AlreadyMounted.into(),
+        FsError::ReadOnly.into(),
+        NetError::NoDevice.into(),
+        NetError::FrameTooLarge.into(),
+        NetError::Timeout.into(),
+        NetError::TransmitFailed.into(),
+        NetError::NoAddress.into(),
+        IpcError::Closed.into(),
+        AcpiError::NoRsdp.into(),
+        AcpiError::UnsupportedRevision(1).into(),
+        AcpiError::BadChecksum(crate::acpi::Signature(*b"APIC")).into(),
+        AcpiError::BadLength(crate::acpi::Signature(*b"APIC")).into(),
+        AcpiError::WrongSignature(crate::acpi::Signature(*b"APIC")).into(),
+        AcpiError::NotFound(crate::acpi::Signature(*b"APIC")).into(),
+        AcpiError::BadAddress(u64::MAX).into(),
+        KeyboardError::UnsupportedScancodeSet(3).into(),
+        KeyboardError::Timeout.into(),
+        KeyboardError::NoAck(0xF0).into(),
+        SmbiosError::NoEntryPoint.into(),
+        SmbiosError::BadChecksum.into(),
+        SmbiosError::BadLength.into(),
+        SmbiosError::BadAddress(u64::MAX).into(),
+        SmpError::TrampolineInUse.into(),
+        SmpError::OutOfMemory.into(),
+        SmpError::PageTablesTooHigh.into(),
+        VirtioError::NotLegacy.into(),
+        VirtioError::MissingFeature(5).into(),
+        VirtioError::NoQueue(1).into(),
+        VirtioError::OutOfMemory.into(),
+        VirtioError::QueueFull.into(),
+        VirtioError::BadUsedEntry(8).into(),
+    ];
+
+    let mut codes: Vec<i64> = Vec::new();
+    for error in &errors {
+        assert!(!format!("{}", error).is_empty());
+        let code = error.error_code();
+        assert!(code < 0);
+        assert!(!codes.contains(&code), "duplicate error code {}", code);
+        codes.push(code);
+    }
+    let shell_errors = [
+        ShellError::UnknownCommand,
+        ShellError::AlreadyRegistered,
+        ShellError::Usage("help"),
+        ShellError::BadArgument,
+        ShellError::Refused("no"),
+    ];
+    for error in &shell_errors {
+        assert!(!format!("{}", error).is_empty());
+        assert!(!codes.contains(&error.error_code()), "duplicate error code {}", error.error_code());
+        codes.push(error.error_code());
+    }
+    let version = crate::EXPECTED_API_VERSION;
+    let boot_info_errors = [
+        BootInfoError::VersionMismatch { expected: version, found: version },
+        BootInfoError::BadMemoryMap,
+        BootInfoError::BadPhysicalMemoryOffset(0),
+    ];
+    for error in &boot_info_errors {
+        assert!(!format!("{}", error).is_empty());
+        assert!(!codes.contains(&error.error_code()), "duplicate error code {}", error.error_code());
+        codes.push(error.error_code());
+    }
+
+    // wrapping keeps the code of the underlying error
+    assert_eq!(ElfError::Map(AddressSpaceError::FrameAllocationFailed).error_code(), -102);
+    assert!(ElfError::Map(AddressSpaceError::FrameAllocationFailed).is_recoverable());
+    assert!(!ElfError::BadMagic.is_recoverable());
+    assert_eq!(ShellError::Failed(NetError::Timeout.into()).error_code(), -702);
+}
@@ -0,0 +1,439 @@
+// Pixel framebuffer --> a linear array of pixels in video memory, row after row (`pitch` bytes apart, which can be more
+// than width * bytes per pixel)
+// - 24 and 32 bits per pixel, with the red and blue bytes in either order (PixelFormat)
+// - every drawing function clips to the screen: coordinates outside of it are ignored, not a panic
+// - the only source of a framebuffer for now is the BGA card (see drivers/bga.rs), turned on with `framebuffer=on` on
+//   the command line --> without it nothing here is used and the VGA text mode stays as it is
+// - a framebuffer set up by the firmware (UEFI GOP) goes through init_framebuffer(), see FIRMWARE FRAMEBUFFER
+pub mod font;
+pub mod text;
+
+pub use text::TextConsole;
+
+use crate::console::ConsoleOutput;
+use crate::drivers::bga;
+use crate::vga_buffer::Color as VgaColor;
+use bootloader::BootInfo;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::PhysAddr;
+
+/// A color, converted to the framebuffer's layout when drawn.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Color {
+    pub const BLACK: Color = Color::rgb(0, 0, 0);
+    pub const WHITE: Color = Color::rgb(255, 255, 255);
+
+    pub const fn rgb(r: u8, g: u8, b: u8) -> Self {
+        Color { r, g, b }
+    }
+
+    /// What the VGA text mode shows for `color` (its default palette).
+    pub const fn from_vga(color: VgaColor) -> Self {
+        match color {
+            VgaColor::Black => Color::rgb(0x00, 0x00, 0x00),
+            VgaColor::Blue => Color::rgb(0x00, 0x00, 0xAA),
+            VgaColor::Green => Color::rgb(0x00, 0xAA, 0x00),
+            VgaColor::Cyan => Color::rgb(0x00, 0xAA, 0xAA),
+            VgaColor::Red => Color::rgb(0xAA, 0x00, 0x00),
+            VgaColor::Magenta => Color::rgb(0xAA, 0x00, 0xAA),
+            VgaColor::Brown => Color::rgb(0xAA, 0x55, 0x00),
+            VgaColor::LightGray => Color::rgb(0xAA, 0xAA, 0xAA),
+            VgaColor::DarkGray => Color::rgb(0x55, 0x55, 0x55),
+            VgaColor::LightBlue => Color::rgb(0x55, 0x55, 0xFF),
+            VgaColor::LightGreen => Color::rgb(0x55, 0xFF, 0x55),
+            VgaColor::LightCyan => Color::rgb(0x55, 0xFF, 0xFF),
+            VgaColor::LightRed => Color::rgb(0xFF, 0x55, 0x55),
+            VgaColor::Pink => Color::rgb(0xFF, 0x55, 0xFF),
+            VgaColor::Yellow => Color::rgb(0xFF, 0xFF, 0x55),
+            VgaColor::White => Color::rgb(0xFF, 0xFF, 0xFF),
+        }
+    }
+}
+
+/// The byte order of a pixel in memory (lowest address first).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    Rgb,
+    Bgr,
+    /// The bits of each color in the pixel read as a little endian number.
+    Bitmask { red: u32, green: u32, blue: u32 },
+    /// No framebuffer to write to, only the firmware's blit function (gone once boot services are exited).
+    BltOnly,
+}
+
+/// The layout of a framebuffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FramebufferInfo {
+    pub phys_addr: PhysAddr,
+    pub width: usize,
+    pub height: usize,
+    /// Bytes from one row to the next.
+    pub pitch: usize,
+    /// 3 or 4.
+    pub bytes_per_pixel: usize,
+    pub format: PixelFormat,
+}
+
+/// A mapped framebuffer to draw on.
+pub struct Framebuffer {
+    info: FramebufferInfo,
+    base: *mut u8,
+}
+
+// the framebuffer is only used through the FRAMEBUFFER lock
+unsafe impl Send for Framebuffer {}
+
+impl Framebuffer {
+    /// Draw on the framebuffer described by `info`, mapped at `base`.
+    ///
+    /// Unsafe because `base` must be valid for `pitch * height` bytes and not be used by anything else.
+    pub unsafe fn new(info: FramebufferInfo, base: *mut u8) -> Self {
+        assert!(info.bytes_per_pixel == 3 || info.bytes_per_pixel == 4, "unsupported pixel size");
+        assert!(info.pitch >= info.width * info.bytes_per_pixel, "rows overlap");
+        assert!(info.format != PixelFormat::BltOnly, "no framebuffer to draw on");
+        Framebuffer { info, base }
+    }
+
+    pub fn info(&self) -> &FramebufferInfo {
+        &self.info
+    }
+
+    // the bytes of `color` in this framebuffer's layout
+    fn pixel_bytes(&self, color: Color) -> [u8; 4] {
+        match self.info.format {
+            PixelFormat::Rgb => [color.r, color.g, color.b, 0],
+            PixelFormat::Bgr => [color.b, color.g, color.r, 0],
+            PixelFormat::Bitmask { red, green, blue } => {
+                (mask_channel(color.r, red) | mask_channel(color.g, green) | mask_channel(color.b, blue)).to_le_bytes()
+            }
+            PixelFormat::BltOnly => unreachable!("checked by new()"),
+        }
+    }
+
+    // write a pixel whose coordinates are known to be on screen
+    fn write_pixel(&mut self, x: usize, y: usize, bytes: [u8; 4]) {
+        let offset = y * self.info.pitch + x * self.info.bytes_per_pixel;
+        unsafe {
+            let pixel = self.base.add(offset);
+            if self.info.bytes_per_pixel == 4 {
+                (pixel as *mut u32).write_volatile(u32::from_le_bytes(bytes));
+            } else {
+                for (i, &byte) in bytes[..3].iter().enumerate() {
+                    pixel.add(i).write_volatile(byte);
+                }
+            }
+        }
+    }
+
+    /// Set one pixel, nothing happens if it's off screen.
+    pub fn set_pixel(&mut self, x: usize, y: usize, color: Color) {
+        if x < self.info.width && y < self.info.height {
+            let bytes = self.pixel_bytes(color);
+            self.write_pixel(x, y, bytes);
+        }
+    }
+
+    /// `set_pixel()` with the color as its parts.
+    pub fn put_pixel(&mut self, x: usize, y: usize, r: u8, g: u8, b: u8) {
+        self.set_pixel(x, y, Color::rgb(r, g, b));
+    }
+
+    /// Fill the `width` x `height` rectangle at (`x`, `y`), clipped to the screen.
+    pub fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, color: Color) {
+        let x_end = x.saturating_add(width).min(self.info.width);
+        let y_end = y.saturating_add(height).min(self.info.height);
+        let bytes = self.pixel_bytes(color);
+        for row in y..y_end {
+            for column in x..x_end {
+                self.write_pixel(column, row, bytes);
+            }
+        }
+    }
+
+    /// Fill the whole screen.
+    pub fn clear(&mut self, color: Color) {
+        self.fill_rect(0, 0, self.info.width, self.info.height, color);
+    }
+
+    /// Move the picture up by `pixels` rows and fill the rows that become free at the bottom with `fill`.
+    pub fn scroll_up(&mut self, pixels: usize, fill: Color) {
+        let FramebufferInfo { width, height, pitch, bytes_per_pixel, .. } = self.info;
+        let pixels = pixels.min(height);
+        let row_bytes = width * bytes_per_pixel; // the padding at the end of a row is left alone
+        // a row at a time: one memmove per row is as fast as it gets without a shadow buffer (the framebuffer is mapped
+        // write-through, so the reads come from the cache), and nothing reads the old rows afterwards to merge writes with
+        for row in pixels..height {
+            unsafe {
+                let src = self.base.add(row * pitch);
+                core::ptr::copy(src, src.sub(pixels * pitch), row_bytes);
+            }
+        }
+        self.fill_rect(0, height - pixels, width, pixels, fill);
+    }
+}
+
+// an 8 bit color value shifted into the bits of `mask` --> cut down (or widened) to as many bits as the mask has
+fn mask_channel(value: u8, mask: u32) -> u32 {
+    if mask == 0 {
+        return 0;
+    }
+    let shift = mask.trailing_zeros();
+    let bits = (mask >> shift).count_ones();
+    let value = if bits >= 8 { u32::from(value) << (bits - 8) } else { u32::from(value) >> (8 - bits) };
+    (value << shift) & mask
+}
+
+/// Red grows to the right, green downwards, on a blue background --> every row and column has its own color, so a
+/// wrong pitch or pixel size shows up as a skewed or striped picture.
+pub fn draw_test_pattern(framebuffer: &mut Framebuffer) {
+    let FramebufferInfo { width, height, .. } = *framebuffer.info();
+    for y in 0..height {
+        for x in 0..width {
+            let r = (x * 255 / width.max(1)) as u8;
+            let g = (y * 255 / height.max(1)) as u8;
+            framebuffer.set_pixel(x, y, Color::rgb(r, g, 128));
+        }
+    }
+}
+
+// GLOBAL ====================================
+
+static FRAMEBUFFER: Mutex<Option<Framebuffer>> = Mutex::new(None);
+
+/// Switch to a `width` x `height` 32 bpp mode on the BGA card and map its framebuffer (needs the heap and `pci::init()`).
+///
+/// `None` (and the text mode stays) if there is no such card or the mode can't be set.
+pub fn init(width: u16, height: u16) -> Option<FramebufferInfo> {
+    let mode = bga::set_mode(width, height, 32)?;
+    let info = FramebufferInfo {
+        phys_addr: mode.framebuffer,
+        width: usize::from(mode.width),
+        height: usize::from(mode.height),
+        pitch: mode.pitch(),
+        bytes_per_pixel: usize::from(mode.bpp / 8),
+        format: PixelFormat::Bgr,
+    };
+    let size = (info.pitch * info.height) as u64;
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::WRITE_THROUGH;
+    let base = crate::memory::map_physical(info.phys_addr, size, flags).ok()?;
+    let framebuffer = unsafe { Framebuffer::new(info, base.as_mut_ptr()) };
+    interrupts::without_interrupts(|| *FRAMEBUFFER.lock() = Some(framebuffer));
+    Some(info)
+}
+
+/// Run `f` on the framebuffer, `None` if `init()` didn't set one up.
+pub fn with_framebuffer<R>(f: impl FnOnce(&mut Framebuffer) -> R) -> Option<R> {
+    interrupts::without_interrupts(|| match FRAMEBUFFER.lock().as_mut() {
+        Some(framebuffer) => Some(f(framebuffer)),
+        None => CONSOLE.lock().as_mut().map(|console| f(console.framebuffer_mut())),
+    })
+}
+
+// FIRMWARE FRAMEBUFFER ====================================
+// UEFI firmware (its Graphics Output Protocol) sets up a framebuffer before the kernel runs and the bootloader passes
+// it on --> bootloader 0.9 boots through the BIOS and leaves the VGA text mode on, its BootInfo has no framebuffer
+// (0.11 has `BootInfo::framebuffer`), so the boot information is read through a trait like the memory map is
+
+/// Boot information that may describe a framebuffer the firmware set up.
+pub trait FirmwareFramebuffer {
+    /// The framebuffer's layout, `pitch` in bytes (UEFI counts its stride in pixels).
+    fn framebuffer(&self) -> Option<FramebufferInfo>;
+}
+
+impl FirmwareFramebuffer for BootInfo {
+    fn framebuffer(&self) -> Option<FramebufferInfo> {
+        None // BIOS boot, see above
+    }
+}
+
+/// Map the framebuffer the firmware left behind, `None` if there is none, it's `BltOnly` or it can't be mapped.
+///
+/// The framebuffer is returned as it is, not made the one `with_framebuffer()` draws on.
+pub fn init_framebuffer(boot_info: &impl FirmwareFramebuffer) -> Option<Framebuffer> {
+    let info = boot_info.framebuffer()?;
+    if info.format == PixelFormat::BltOnly {
+        return None;
+    }
+    let size = (info.pitch * info.height) as u64;
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::WRITE_THROUGH;
+    let base = crate::memory::map_physical(info.phys_addr, size, flags).ok()?;
+    Some(unsafe { Framebuffer::new(info, base.as_mut_ptr()) })
+}
+
+// CONSOLE ====================================
+
+// the text console owns the framebuffer once it is started
+static CONSOLE: Mutex<Option<TextConsole>> = Mutex::new(None);
+
+/// Prints into the framebuffer's text console (see `start_console()`).
+pub struct FramebufferOutput;
+
+impl ConsoleOutput for FramebufferOutput {
+    fn write_str(&self, s: &str) {
+        interrupts::without_interrupts(|| {
+            if let Some(console) = CONSOLE.lock().as_mut() {
+                console.write_string(s);
+            }
+        });
+    }
+
+    fn clear(&self) {
+        interrupts::without_interrupts(|| {
+            if let Some(console) = CONSOLE.lock().as_mut() {
+                console.clear();
+            }
+        });
+    }
+
+    fn columns(&self) -> Option<usize> {
+        interrupts::without_interrupts(|| CONSOLE.lock().as_ref().map(|console| console.size().0))
+    }
+
+    fn set_colors(&self, foreground: VgaColor, background: VgaColor) {
+        interrupts::without_interrupts(|| {
+            if let Some(console) = CONSOLE.lock().as_mut() {
+                console.set_colors(Color::from_vga(foreground), Color::from_vga(background));
+            }
+        });
+    }
+}
+
+static FRAMEBUFFER_OUTPUT: FramebufferOutput = FramebufferOutput;
+
+/// Turn the framebuffer from `init()` into a text console and show print!/println! there instead of in the VGA buffer.
+///
+/// Returns false if there is no framebuffer.
+pub fn start_console(foreground: Color, background: Color) -> bool {
+    let started = interrupts::without_interrupts(|| match FRAMEBUFFER.lock().take() {
+        Some(framebuffer) => {
+            *CONSOLE.lock() = Some(TextConsole::new(framebuffer, foreground, background));
+            true
+        }
+        None => false,
+    });
+    if started {
+        crate::console::set_display(&FRAMEBUFFER_OUTPUT);
+    }
+    started
+}
+
+// TESTS ===================================
+
+#[cfg(test)]
+fn test_framebuffer(memory: &mut [u8], width: usize, height: usize, pitch: usize, bytes_per_pixel: usize) -> Framebuffer {
+    let info = FramebufferInfo { phys_addr: PhysAddr::zero(), width, height, pitch, bytes_per_pixel, format: PixelFormat::Bgr };
+    assert!(memory.len() >= pitch * height);
+    unsafe { Framebuffer::new(info, memory.as_mut_ptr()) }
+}
+
+// 32 bpp pixels are written as one u32 --> the memory standing in for video memory must be 4 byte aligned like the
+// real thing
+#[cfg(test)]
+#[repr(align(4))]
+struct AlignedMemory<const N: usize>([u8; N]);
+
+#[test_case]
+fn test_draw_32bpp_with_clipping() {
+    // 4x3 pixels, rows padded to 20 bytes
+    let mut memory = AlignedMemory([0u8; 60]);
+    let memory = &mut memory.0;
+    let mut framebuffer = test_framebuffer(memory, 4, 3, 20, 4);
+    framebuffer.set_pixel(1, 2, Color::rgb(0x11, 0x22, 0x33));
+    framebuffer.set_pixel(4, 0, Color::WHITE); // off screen
+    framebuffer.fill_rect(3, 1, 10, 10, Color::rgb(0xAA, 0xBB, 0xCC)); // clipped to (3, 1) and (3, 2)
+    drop(framebuffer);
+
+    assert_eq!(memory[2 * 20 + 4..2 * 20 + 8], [0x33, 0x22, 0x11, 0]);
+    assert_eq!(memory[20 + 12..20 + 16], [0xCC, 0xBB, 0xAA, 0]);
+    assert_eq!(memory[2 * 20 + 12..2 * 20 + 16], [0xCC, 0xBB, 0xAA, 0]);
+    assert!(memory[16..20].iter().all(|&byte| byte == 0), "pixel written into the row padding");
+    assert_eq!(memory.iter().filter(|&&byte| byte != 0).count(), 9);
+}
+
+#[test_case]
+fn test_draw_24bpp() {
+    let mut memory = [0u8; 2 * 9];
+    let mut framebuffer = test_framebuffer(&mut memory, 3, 2, 9, 3);
+    framebuffer.clear(Color::rgb(1, 2, 3));
+    framebuffer.set_pixel(2, 1, Color::rgb(7, 8, 9));
+    drop(framebuffer);
+
+    // blue, green, red in memory (PixelFormat::Bgr)
+    assert_eq!(memory[..9], [3, 2, 1, 3, 2, 1, 3, 2, 1]);
+    assert_eq!(memory[9..15], [3, 2, 1, 3, 2, 1]);
+    assert_eq!(memory[15..18], [9, 8, 7]);
+
+    // red 0, 85, 170 from left to right, green 0, 127 from top to bottom, blue 128 --> fills exactly the 3x2 pixels
+    let mut pattern = [0u8; 2 * 9 + 3];
+    draw_test_pattern(&mut test_framebuffer(&mut pattern, 3, 2, 9, 3));
+    assert_eq!(pattern[..9], [128, 0, 0, 128, 0, 85, 128, 0, 170]);
+    assert_eq!(pattern[9..18], [128, 127, 0, 128, 127, 85, 128, 127, 170]);
+    assert_eq!(pattern[18..], [0, 0, 0], "drawn past the last row");
+}
+
+#[test_case]
+fn test_bitmask_pixels() {
+    // 16 bit pixels in 4 bytes: 5 bits red, 6 bits green, 5 bits blue
+    let format = PixelFormat::Bitmask { red: 0xf800, green: 0x07e0, blue: 0x001f };
+    let info = FramebufferInfo { phys_addr: PhysAddr::zero(), width: 2, height: 1, pitch: 8, bytes_per_pixel: 4, format };
+    let mut memory = AlignedMemory([0u8; 8]);
+    let memory = &mut memory.0;
+    let mut framebuffer = unsafe { Framebuffer::new(info, memory.as_mut_ptr()) };
+    framebuffer.put_pixel(0, 0, 255, 128, 0);
+    framebuffer.put_pixel(1, 0, 0, 0, 255);
+    drop(framebuffer);
+
+    assert_eq!(u32::from_le_bytes(memory[..4].try_into().unwrap()), (0x1f << 11) | (0x20 << 5));
+    assert_eq!(u32::from_le_bytes(memory[4..].try_into().unwrap()), 0x1f);
+    assert_eq!(mask_channel(0xab, 0xff_0000), 0xab_0000);
+    assert_eq!(mask_channel(0xff, 0), 0);
+}
+
+#[test_case]
+fn test_init_firmware_framebuffer() {
+    use crate::memory::{phys_to_virt, unmap_physical, GlobalFrameAllocator};
+    use x86_64::structures::paging::{FrameAllocator, FrameDeallocator};
+    use x86_64::VirtAddr;
+
+    struct MockFirmware(Option<FramebufferInfo>);
+    impl FirmwareFramebuffer for MockFirmware {
+        fn framebuffer(&self) -> Option<FramebufferInfo> {
+            self.0
+        }
+    }
+
+    // a frame of RAM stands in for video memory, read back through the physical memory mapping
+    let frame = GlobalFrameAllocator.allocate_frame().expect("no free frame");
+    let info = FramebufferInfo {
+        phys_addr: frame.start_address(),
+        width: 4,
+        height: 2,
+        pitch: 12,
+        bytes_per_pixel: 3,
+        format: PixelFormat::Bgr,
+    };
+    let mut framebuffer = init_framebuffer(&MockFirmware(Some(info))).expect("framebuffer not mapped");
+    assert_eq!(*framebuffer.info(), info);
+    framebuffer.put_pixel(0, 0, 255, 128, 0);
+
+    let memory = phys_to_virt(frame.start_address()).as_ptr::<[u8; 3]>();
+    assert_eq!(unsafe { memory.read_volatile() }, [0, 128, 255]);
+
+    assert!(init_framebuffer(&MockFirmware(None)).is_none());
+    let blt_only = FramebufferInfo { format: PixelFormat::BltOnly, ..info };
+    assert!(init_framebuffer(&MockFirmware(Some(blt_only))).is_none());
+
+    // the window and the frame go back, the framebuffer is gone with them
+    let base = VirtAddr::from_ptr(framebuffer.base);
+    drop(framebuffer);
+    unmap_physical(base, (info.pitch * info.height) as u64).expect("framebuffer not mapped");
+    unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+}
@@ -0,0 +1,156 @@
+// Text on the framebuffer --> a grid of 8x16 character cells, written like the VGA text writer: characters go at the
+// cursor, a full line or '\n' moves to the next line and the last line scrolls everything up by one line
+use super::font::{self, GLYPH_HEIGHT, GLYPH_WIDTH};
+use super::{Color, Framebuffer};
+use core::fmt;
+
+/// A text console drawing into a framebuffer.
+pub struct TextConsole {
+    framebuffer: Framebuffer,
+    column: usize,
+    row: usize,
+    columns: usize,
+    rows: usize,
+    foreground: Color,
+    background: Color,
+}
+
+impl TextConsole {
+    /// Write text into `framebuffer` starting at the top left (what is on screen stays until it is overwritten).
+    pub fn new(framebuffer: Framebuffer, foreground: Color, background: Color) -> Self {
+        let info = *framebuffer.info();
+        TextConsole {
+            framebuffer,
+            column: 0,
+            row: 0,
+            columns: info.width / GLYPH_WIDTH,
+            rows: (info.height / GLYPH_HEIGHT).max(1),
+            foreground,
+            background,
+        }
+    }
+
+    /// The cursor as (column, row) in character cells.
+    pub fn cursor(&self) -> (usize, usize) {
+        (self.column, self.row)
+    }
+
+    /// The size of the grid as (columns, rows).
+    pub fn size(&self) -> (usize, usize) {
+        (self.columns, self.rows)
+    }
+
+    pub fn set_colors(&mut self, foreground: Color, background: Color) {
+        self.foreground = foreground;
+        self.background = background;
+    }
+
+    pub fn framebuffer_mut(&mut self) -> &mut Framebuffer {
+        &mut self.framebuffer
+    }
+
+    /// Fill the screen with the background color and move the cursor to the top left.
+    pub fn clear(&mut self) {
+        self.framebuffer.clear(self.background);
+        self.column = 0;
+        self.row = 0;
+    }
+
+    // draw `byte` into the cell at (column, row), foreground and background pixels alike
+    fn draw_glyph(&mut self, byte: u8, column: usize, row: usize) {
+        let glyph = font::glyph(byte);
+        let (x, y) = (column * GLYPH_WIDTH, row * GLYPH_HEIGHT);
+        for glyph_row in 0..GLYPH_HEIGHT {
+            for glyph_column in 0..GLYPH_WIDTH {
+                let color = if font::pixel(glyph, glyph_column, glyph_row) { self.foreground } else { self.background };
+                self.framebuffer.set_pixel(x + glyph_column, y + glyph_row, color);
+            }
+        }
+    }
+
+    fn new_line(&mut self) {
+        self.column = 0;
+        if self.row + 1 < self.rows {
+            self.row += 1;
+        } else {
+            self.framebuffer.scroll_up(GLYPH_HEIGHT, self.background);
+        }
+    }
+
+    pub fn write_byte(&mut self, byte: u8) {
+        match byte {
+            b'\n' => self.new_line(),
+            b'\r' => self.column = 0,
+            0x08 => self.column = self.column.saturating_sub(1),
+            byte => {
+                if self.column >= self.columns {
+                    self.new_line();
+                }
+                self.draw_glyph(byte, self.column, self.row);
+                self.column += 1;
+            }
+        }
+    }
+
+    pub fn write_string(&mut self, s: &str) {
+        for byte in s.bytes() {
+            self.write_byte(byte);
+        }
+    }
+}
+
+impl fmt::Write for TextConsole {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_string(s);
+        Ok(())
+    }
+}
+
+// TESTS ===================================
+
+#[test_case]
+fn test_glyph_lookup() {
+    assert_eq!(font::glyph(b'A'), &[0x0C, 0x1E, 0x33, 0x33, 0x3F, 0x33, 0x33, 0x00]);
+    assert_eq!(font::glyph(b' '), &[0; 8]);
+    assert_eq!(font::glyph(b'~'), &[0x6E, 0x3B, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+    assert_eq!(font::glyph(0x7F), font::glyph(0xC3), "unprintable bytes share the replacement glyph");
+    // bit 0 is the leftmost pixel, font rows are doubled
+    assert!(font::pixel(font::glyph(b'A'), 2, 0) && font::pixel(font::glyph(b'A'), 2, 1));
+    assert!(!font::pixel(font::glyph(b'A'), 0, 0));
+}
+
+#[test_case]
+fn test_text_console_wraps_and_scrolls() {
+    use alloc::vec;
+
+    // 3 x 2 cells of 8x16 pixels at 32 bpp
+    let (width, height) = (3 * GLYPH_WIDTH, 2 * GLYPH_HEIGHT);
+    let pitch = width * 4;
+    let mut memory = vec![0u8; pitch * height];
+    let framebuffer = super::test_framebuffer(&mut memory, width, height, pitch, 4);
+    let (white, black) = (Color::WHITE, Color::BLACK);
+    let mut console = TextConsole::new(framebuffer, white, black);
+    assert_eq!(console.size(), (3, 2));
+
+    console.write_string("abc");
+    assert_eq!(console.cursor(), (3, 0));
+    console.write_string("d"); // wraps
+    assert_eq!(console.cursor(), (1, 1));
+    console.write_string("\rA");
+    assert_eq!(console.cursor(), (1, 1));
+    // the pixel in column 2 of the 'A' cell's first row is set, its column 0 isn't (see test_glyph_lookup)
+    let pixel = |memory: &[u8], x: usize, y: usize| -> [u8; 4] { memory[y * pitch + x * 4..][..4].try_into().unwrap() };
+    assert_eq!(pixel(&memory, 2, GLYPH_HEIGHT), [0xFF, 0xFF, 0xFF, 0]);
+    assert_eq!(pixel(&memory, 0, GLYPH_HEIGHT), [0, 0, 0, 0]);
+
+    // a new line on the last row scrolls: 'A' moves up, the last row is blank
+    console.write_byte(b'\n');
+    assert_eq!(console.cursor(), (0, 1));
+    assert_eq!(pixel(&memory, 2, 0), [0xFF, 0xFF, 0xFF, 0]);
+    assert_eq!(pixel(&memory, 2, 1), [0xFF, 0xFF, 0xFF, 0]);
+    assert!(memory[GLYPH_HEIGHT * pitch..].iter().all(|&byte| byte == 0), "last row not cleared");
+
+    console.clear();
+    assert_eq!(console.cursor(), (0, 0));
+    assert!(memory.iter().all(|&byte| byte == 0));
+}
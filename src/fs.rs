@@ -0,0 +1,236 @@
+// Filesystems --> files and directories on top of block devices (see drivers/block.rs)
+//
+// the VFS ("virtual filesystem") layer lets callers open files by path without knowing which filesystem they are on:
+// - every filesystem implements FileSystem, opening a path inside it gives a FileHandle
+// - the mount table maps path prefixes to filesystems ("/disk" --> a FAT volume, "/dev" --> devfs), the longest
+//   matching prefix wins and the rest of the path is handed to that filesystem
+// - paths are absolute and normalized lexically before the lookup: repeated slashes and "." are dropped,
+//   ".." removes the previous component (".." at the root stays at the root)
+pub mod devfs;
+pub mod fat;
+
+use crate::drivers::block::BlockDevice;
+use alloc::{boxed::Box, string::String, sync::Arc, vec, vec::Vec};
+use fat::FatError;
+use spin::Mutex;
+
+/// Errors reported by the VFS layer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    /// The path doesn't start with a '/'.
+    RelativePath,
+    /// A component of the path doesn't exist.
+    NotFound,
+    /// A component of the path that should be a directory is a file.
+    NotADirectory,
+    /// Tried to read a directory like a file.
+    IsADirectory,
+    /// No filesystem is mounted at or above the path.
+    NotMounted,
+    /// Something is already mounted at that path.
+    AlreadyMounted,
+    /// The file can't be written to.
+    ReadOnly,
+    /// The FAT volume behind the path failed.
+    Fat(FatError),
+}
+
+impl From<FatError> for FsError {
+    fn from(error: FatError) -> Self {
+        match error {
+            FatError::NotFound => FsError::NotFound,
+            FatError::NotADirectory => FsError::NotADirectory,
+            FatError::IsADirectory => FsError::IsADirectory,
+            error => FsError::Fat(error),
+        }
+    }
+}
+
+/// What a path points at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    File,
+    Directory,
+    Device,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    /// Size in bytes (0 for directories and devices).
+    pub size: u64,
+    pub kind: FileKind,
+}
+
+/// An open file, directory or device.
+pub trait FileHandle: Send + Sync {
+    fn metadata(&self) -> Metadata;
+
+    /// Read up to `buf.len()` bytes starting at byte `offset`, returns the number of bytes read (0 at the end).
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError>;
+
+    /// Write `buf` starting at byte `offset`, returns the number of bytes written.
+    fn write_at(&self, _offset: u64, _buf: &[u8]) -> Result<usize, FsError> {
+        Err(FsError::ReadOnly)
+    }
+}
+
+/// A filesystem that can be mounted in the VFS.
+pub trait FileSystem: Send + Sync {
+    /// Open the entry at `path`: the normalized components below the mount point (empty --> the filesystem's root).
+    fn open(&self, path: &[&str]) -> Result<Box<dyn FileHandle>, FsError>;
+}
+
+// PATHS =============================
+
+/// Split the absolute `path` into its components, resolving "." and ".." and ignoring repeated slashes.
+pub fn normalize(path: &str) -> Result<Vec<&str>, FsError> {
+    if !path.starts_with('/') {
+        return Err(FsError::RelativePath);
+    }
+    let mut components = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            component => components.push(component),
+        }
+    }
+    Ok(components)
+}
+
+// MOUNT TABLE =============================
+
+struct Mount {
+    prefix: Vec<String>, // normalized components of the mount point
+    fs: Arc<dyn FileSystem>,
+}
+
+static MOUNTS: Mutex<Vec<Mount>> = Mutex::new(Vec::new());
+
+/// Mount `fs` at the absolute path `at`, fails if something is already mounted there.
+pub fn mount(at: &str, fs: Arc<dyn FileSystem>) -> Result<(), FsError> {
+    let prefix: Vec<String> = normalize(at)?.into_iter().map(String::from).collect();
+    let mut mounts = MOUNTS.lock();
+    if mounts.iter().any(|mount| mount.prefix == prefix) {
+        return Err(FsError::AlreadyMounted);
+    }
+    mounts.push(Mount { prefix, fs });
+    Ok(())
+}
+
+/// Remove the filesystem mounted at `at`, returning it.
+pub fn unmount(at: &str) -> Result<Arc<dyn FileSystem>, FsError> {
+    let prefix = normalize(at)?;
+    let mut mounts = MOUNTS.lock();
+    let index = mounts.iter().position(|mount| mount.prefix == prefix).ok_or(FsError::NotMounted)?;
+    Ok(mounts.remove(index).fs)
+}
+
+/// Mount the FAT volume on `device` at `at`.
+pub fn mount_fat(at: &str, device: Arc<dyn BlockDevice>) -> Result<(), FsError> {
+    mount(at, Arc::new(fat::FatFileSystem::new(device)?))
+}
+
+/// Open the file, directory or device at the absolute path `path`.
+pub fn open(path: &str) -> Result<Box<dyn FileHandle>, FsError> {
+    let components = normalize(path)?;
+    // longest prefix wins --> "/disk/sub" mounted inside "/disk" takes over everything below it
+    let (fs, depth) = {
+        let mounts = MOUNTS.lock();
+        let mount = mounts
+            .iter()
+            .filter(|mount| mount.prefix.len() <= components.len() && mount.prefix.iter().zip(&components).all(|(a, b)| a == b))
+            .max_by_key(|mount| mount.prefix.len())
+            .ok_or(FsError::NotMounted)?;
+        (mount.fs.clone(), mount.prefix.len())
+    };
+    // the filesystem is called without the mount table locked, so it may open other paths itself
+    fs.open(&components[depth..])
+}
+
+/// Read the whole file at `path`.
+pub fn read_to_vec(path: &str) -> Result<Vec<u8>, FsError> {
+    let file = open(path)?;
+    let metadata = file.metadata();
+    if metadata.kind == FileKind::Directory {
+        return Err(FsError::IsADirectory);
+    }
+    // the size is only a hint (devices report 0) --> keep reading until the end
+    let mut data = vec![0u8; (metadata.size as usize).max(512)];
+    let mut filled = 0;
+    loop {
+        if filled == data.len() {
+            data.resize(data.len() * 2, 0);
+        }
+        let read = file.read_at(filled as u64, &mut data[filled..])?;
+        if read == 0 {
+            break;
+        }
+        filled += read;
+    }
+    data.truncate(filled);
+    Ok(data)
+}
+
+/// Mount the filesystems every kernel has: devfs at "/dev".
+pub fn init() {
+    mount("/dev", Arc::new(devfs::DevFs)).expect("/dev already mounted");
+}
+
+// TESTS ===================================
+
+#[test_case]
+fn test_normalize() {
+    assert_eq!(normalize("/"), Ok(vec![]));
+    assert_eq!(normalize("/disk/HELLO.TXT"), Ok(vec!["disk", "HELLO.TXT"]));
+    assert_eq!(normalize("//disk///docs//"), Ok(vec!["disk", "docs"]));
+    assert_eq!(normalize("/disk/./docs/."), Ok(vec!["disk", "docs"]));
+    assert_eq!(normalize("/disk/docs/../HELLO.TXT"), Ok(vec!["disk", "HELLO.TXT"]));
+    assert_eq!(normalize("/../.."), Ok(vec![]));
+    assert_eq!(normalize("/a/b/../../c"), Ok(vec!["c"]));
+    assert_eq!(normalize("disk/HELLO.TXT"), Err(FsError::RelativePath));
+    assert_eq!(normalize(""), Err(FsError::RelativePath));
+}
+
+#[test_case]
+fn test_mount_table() {
+    let fs: Arc<dyn FileSystem> = Arc::new(devfs::DevFs);
+    mount("/test_mnt", fs.clone()).expect("mount failed");
+    assert_eq!(mount("//test_mnt/", fs).err(), Some(FsError::AlreadyMounted));
+    assert_eq!(open("/test_mnt/./null").expect("open failed").metadata().kind, FileKind::Device);
+    assert_eq!(open("/test_mnt/missing").err(), Some(FsError::NotFound));
+    assert!(unmount("/test_mnt").is_ok());
+    assert_eq!(open("/test_mnt/null").err(), Some(FsError::NotMounted));
+    assert_eq!(unmount("/test_mnt").err(), Some(FsError::NotMounted));
+}
+
+#[test_case]
+fn test_read_fat_through_vfs() {
+    use crate::drivers::ramdisk::RamDisk;
+
+    let disk = Arc::new(RamDisk::from_bytes(fat::TEST_IMAGE));
+    mount_fat("/test_disk", disk).expect("mount failed");
+
+    assert_eq!(read_to_vec("/test_disk/HELLO.TXT").as_deref(), Ok(&b"Hello from a FAT12 image!\n"[..]));
+    assert_eq!(read_to_vec("/test_disk//DOCS/./../docs/readme.txt").as_deref(), Ok(&b"Nested files work too.\n"[..]));
+    assert_eq!(read_to_vec("/test_disk/BIG.TXT").map(|data| data.len()), Ok(3000));
+
+    // reads in the middle of the file, starting in the second cluster
+    let big = open("/test_disk/BIG.TXT").expect("open failed");
+    assert_eq!(big.metadata(), Metadata { size: 3000, kind: FileKind::File });
+    let mut buf = [0u8; 100];
+    assert_eq!(big.read_at(1500, &mut buf), Ok(100));
+    assert!(buf.iter().enumerate().all(|(i, &byte)| byte == (((1500 + i) * 7 + (1500 + i) / 256) % 256) as u8));
+    assert_eq!(big.read_at(2950, &mut buf), Ok(50));
+    assert_eq!(big.read_at(3000, &mut buf), Ok(0));
+
+    assert_eq!(open("/test_disk").expect("open failed").metadata().kind, FileKind::Directory);
+    assert_eq!(open("/test_disk/DOCS").expect("open failed").metadata().kind, FileKind::Directory);
+    assert_eq!(read_to_vec("/test_disk/DOCS").err(), Some(FsError::IsADirectory));
+    assert_eq!(read_to_vec("/test_disk/MISSING.TXT").err(), Some(FsError::NotFound));
+    assert_eq!(read_to_vec("/test_disk/HELLO.TXT/X").err(), Some(FsError::NotADirectory));
+    assert_eq!(read_to_vec("/test_disk/BAD.TXT").err(), Some(FsError::Fat(FatError::BadCluster)));
+    unmount("/test_disk").expect("unmount failed");
+}
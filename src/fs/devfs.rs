@@ -0,0 +1,80 @@
+// devfs --> devices as files, mounted at "/dev" by fs::init()
+// - "console": writes go to the screen, reads return nothing (keyboard input isn't buffered anywhere yet)
+// - "null": reads return nothing, writes are thrown away
+use super::{FileHandle, FileKind, FileSystem, FsError, Metadata};
+use alloc::{boxed::Box, string::String};
+
+/// The built-in device filesystem.
+pub struct DevFs;
+
+const DEVICE: Metadata = Metadata { size: 0, kind: FileKind::Device };
+
+struct Console;
+
+impl FileHandle for Console {
+    fn metadata(&self) -> Metadata {
+        DEVICE
+    }
+
+    fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> Result<usize, FsError> {
+        Ok(0)
+    }
+
+    fn write_at(&self, _offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        crate::print!("{}", String::from_utf8_lossy(buf));
+        Ok(buf.len())
+    }
+}
+
+struct Null;
+
+impl FileHandle for Null {
+    fn metadata(&self) -> Metadata {
+        DEVICE
+    }
+
+    fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> Result<usize, FsError> {
+        Ok(0)
+    }
+
+    fn write_at(&self, _offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        Ok(buf.len())
+    }
+}
+
+// the root directory of devfs
+struct Root;
+
+impl FileHandle for Root {
+    fn metadata(&self) -> Metadata {
+        Metadata { size: 0, kind: FileKind::Directory }
+    }
+
+    fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> Result<usize, FsError> {
+        Err(FsError::IsADirectory)
+    }
+}
+
+impl FileSystem for DevFs {
+    fn open(&self, path: &[&str]) -> Result<Box<dyn FileHandle>, FsError> {
+        match path {
+            [] => Ok(Box::new(Root)),
+            ["console"] => Ok(Box::new(Console)),
+            ["null"] => Ok(Box::new(Null)),
+            ["console" | "null", ..] => Err(FsError::NotADirectory),
+            _ => Err(FsError::NotFound),
+        }
+    }
+}
+
+// TESTS ===================================
+
+#[test_case]
+fn test_devfs_null() {
+    let null = DevFs.open(&["null"]).expect("open failed");
+    assert_eq!(null.metadata().kind, FileKind::Device);
+    assert_eq!(null.write_at(0, b"gone"), Ok(4));
+    assert_eq!(null.read_at(0, &mut [0u8; 8]), Ok(0));
+    assert_eq!(DevFs.open(&["null", "x"]).err(), Some(FsError::NotADirectory));
+    assert_eq!(DevFs.open(&["zero"]).err(), Some(FsError::NotFound));
+}
@@ -0,0 +1,664 @@
+// Read-only FAT12/FAT16 filesystem --> the filesystem of floppies, old hard disks and most small disk images
+// for an overview see: https://wiki.osdev.org/FAT
+//
+// layout of a volume (in sectors): [reserved (boot sector + BPB)] [FAT copies] [root directory] [data area (clusters)]
+// - the BPB ("BIOS parameter block") in the boot sector describes the sizes of everything
+// - the data area is split into clusters (1+ sectors), numbered from 2
+// - the FAT ("file allocation table") has an entry per cluster holding the number of the next cluster of the same
+//   file (a linked list --> the "cluster chain"), or an end of chain / bad cluster / free marker
+// - FAT12 packs two 12 bit entries into 3 bytes, FAT16 entries are plain u16s
+// - directories are arrays of 32 byte entries (8.3 name, attributes, first cluster, size); the root directory has a
+//   fixed place and size, every other directory is stored in clusters like a file
+// the FAT type isn't stored anywhere reliable, it follows from the number of clusters (< 4085 = FAT12, < 65525 = FAT16)
+use crate::drivers::block::{BlockDevice, BlockError};
+use super::{FileHandle, FileKind, FileSystem, FsError, Metadata};
+use alloc::{boxed::Box, string::String, sync::Arc, vec, vec::Vec};
+use spin::Mutex;
+
+const DIR_ENTRY_SIZE: usize = 32;
+
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_LONG_NAME: u8 = 0x0F; // read only + hidden + system + volume id --> a long file name part, skipped
+
+const ENTRY_END: u8 = 0x00; // this entry and all after it are unused
+const ENTRY_DELETED: u8 = 0xE5;
+const ENTRY_KANJI_E5: u8 = 0x05; // a name that really starts with 0xE5
+
+/// Errors reported by the FAT driver.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatError {
+    /// Reading the device failed.
+    Block(BlockError),
+    /// The boot sector doesn't describe a valid FAT volume.
+    BadBootSector,
+    /// A FAT32 volume, or a sector size the device can't address (or a device without a usable block size).
+    Unsupported,
+    /// The volume is larger than the device (or a structure points past its end).
+    Truncated,
+    /// A cluster chain runs into a cluster marked as bad.
+    BadCluster,
+    /// A cluster chain points at a free or nonexistent cluster, or loops.
+    BadChain,
+    /// A cluster chain ends before the file's size is reached.
+    UnexpectedEndOfChain,
+    /// No entry with that name.
+    NotFound,
+    /// A path component that should be a directory is a file.
+    NotADirectory,
+    /// Tried to open a directory as a file.
+    IsADirectory,
+}
+
+impl From<BlockError> for FatError {
+    fn from(error: BlockError) -> Self {
+        match error {
+            BlockError::OutOfRange => FatError::Truncated,
+            error => FatError::Block(error),
+        }
+    }
+}
+
+/// FAT12 or FAT16.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+}
+
+// what a FAT entry says about the cluster after the current one
+enum FatEntry {
+    Next(u32),
+    EndOfChain,
+}
+
+/// A mounted FAT12/FAT16 volume on a block device.
+pub struct Volume<'a> {
+    device: &'a dyn BlockDevice,
+    fat_type: FatType,
+    bytes_per_sector: usize,
+    sectors_per_cluster: u32,
+    device_blocks_per_sector: u64,
+    fat_start: u32, // first sector of the first FAT
+    root_dir_start: u32,
+    root_dir_sectors: u32,
+    data_start: u32,
+    cluster_count: u32, // valid clusters are 2..cluster_count + 2
+}
+
+/// Where a directory's entries are stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DirLocation {
+    /// The fixed root directory area.
+    Root,
+    /// A cluster chain starting at this cluster.
+    Cluster(u32),
+}
+
+/// An entry of a directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    /// The 8.3 name, ex. "HELLO.TXT" (no dot without an extension).
+    pub name: String,
+    pub attributes: u8,
+    pub first_cluster: u32,
+    pub size: u32,
+}
+
+impl DirEntry {
+    pub fn is_dir(&self) -> bool {
+        self.attributes & ATTR_DIRECTORY != 0
+    }
+
+    /// Where the entries of this directory are stored (the ".." entry of a top level directory points at cluster 0 --> the root).
+    pub fn dir_location(&self) -> DirLocation {
+        match self.first_cluster {
+            0 => DirLocation::Root,
+            cluster => DirLocation::Cluster(cluster),
+        }
+    }
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
+
+// "HELLO   TXT" --> "HELLO.TXT"
+fn format_short_name(raw: &[u8]) -> String {
+    let mut name = String::new();
+    for (i, &byte) in raw[..8].iter().enumerate() {
+        let byte = if i == 0 && byte == ENTRY_KANJI_E5 { 0xE5 } else { byte };
+        if byte != b' ' {
+            name.push(char::from(byte));
+        }
+    }
+    if raw[8..11].iter().any(|&byte| byte != b' ') {
+        name.push('.');
+        name.extend(raw[8..11].iter().filter(|&&byte| byte != b' ').map(|&byte| char::from(byte)));
+    }
+    name
+}
+
+impl<'a> Volume<'a> {
+    /// Read the boot sector of `device` and check that it holds a FAT12 or FAT16 volume.
+    pub fn open(device: &'a dyn BlockDevice) -> Result<Volume<'a>, FatError> {
+        let block_size = device.block_size();
+        if block_size == 0 || block_size > 4096 {
+            return Err(FatError::Unsupported); // a broken device, everything below divides by the block size
+        }
+        // the BPB is in the first 512 bytes, which may be less than a device block or more
+        let mut first = vec![0u8; block_size.max(512)];
+        device.read_blocks(0, &mut first[..block_size.max(512) / block_size * block_size])?;
+        let boot = &first[..512];
+
+        let bytes_per_sector = usize::from(read_u16(boot, 11));
+        let sectors_per_cluster = u32::from(boot[13]);
+        let reserved_sectors = u32::from(read_u16(boot, 14));
+        let fat_count = u32::from(boot[16]);
+        let root_entries = u32::from(read_u16(boot, 17));
+        let total_sectors = match read_u16(boot, 19) {
+            0 => read_u32(boot, 32),
+            sectors => u32::from(sectors),
+        };
+        let fat_sectors = u32::from(read_u16(boot, 22));
+
+        if ![512, 1024, 2048, 4096].contains(&bytes_per_sector)
+            || !sectors_per_cluster.is_power_of_two()
+            || reserved_sectors == 0
+            || fat_count == 0
+            || read_u16(boot, 510) != 0xAA55
+        {
+            return Err(FatError::BadBootSector);
+        }
+        if fat_sectors == 0 {
+            return Err(FatError::Unsupported); // FAT32 keeps its FAT size elsewhere
+        }
+        if bytes_per_sector % block_size != 0 {
+            return Err(FatError::Unsupported);
+        }
+
+        let root_dir_sectors = (root_entries * DIR_ENTRY_SIZE as u32).div_ceil(bytes_per_sector as u32);
+        let fat_start = reserved_sectors;
+        let root_dir_start = fat_start + fat_count * fat_sectors;
+        let data_start = root_dir_start + root_dir_sectors;
+        if data_start >= total_sectors {
+            return Err(FatError::BadBootSector);
+        }
+        let cluster_count = (total_sectors - data_start) / sectors_per_cluster;
+        let fat_type = match cluster_count {
+            0..=4084 => FatType::Fat12,
+            4085..=65524 => FatType::Fat16,
+            _ => return Err(FatError::Unsupported),
+        };
+
+        let device_blocks_per_sector = (bytes_per_sector / block_size) as u64;
+        if u64::from(total_sectors) * device_blocks_per_sector > device.num_blocks() {
+            return Err(FatError::Truncated);
+        }
+        // the FAT has to have room for an entry per cluster
+        let fat_bits = match fat_type {
+            FatType::Fat12 => 12,
+            FatType::Fat16 => 16,
+        };
+        if u64::from(cluster_count + 2) * fat_bits > u64::from(fat_sectors) * bytes_per_sector as u64 * 8 {
+            return Err(FatError::BadBootSector);
+        }
+
+        Ok(Volume {
+            device,
+            fat_type,
+            bytes_per_sector,
+            sectors_per_cluster,
+            device_blocks_per_sector,
+            fat_start,
+            root_dir_start,
+            root_dir_sectors,
+            data_start,
+            cluster_count,
+        })
+    }
+
+    pub fn fat_type(&self) -> FatType {
+        self.fat_type
+    }
+
+    /// Size of a cluster in bytes.
+    pub fn cluster_size(&self) -> usize {
+        self.bytes_per_sector * self.sectors_per_cluster as usize
+    }
+
+    fn read_sector(&self, sector: u32, buf: &mut [u8]) -> Result<(), FatError> {
+        Ok(self.device.read_blocks(u64::from(sector) * self.device_blocks_per_sector, buf)?)
+    }
+
+    fn is_valid_cluster(&self, cluster: u32) -> bool {
+        cluster >= 2 && cluster < self.cluster_count + 2
+    }
+
+    fn cluster_start(&self, cluster: u32) -> u32 {
+        self.data_start + (cluster - 2) * self.sectors_per_cluster
+    }
+
+    // the FAT entry of `cluster`
+    fn fat_entry(&self, cluster: u32) -> Result<FatEntry, FatError> {
+        let (offset, bad, end) = match self.fat_type {
+            FatType::Fat12 => (cluster as usize + cluster as usize / 2, 0xFF7, 0xFF8),
+            FatType::Fat16 => (cluster as usize * 2, 0xFFF7, 0xFFF8),
+        };
+        // a FAT12 entry can straddle two sectors --> read both
+        let sector = self.fat_start + (offset / self.bytes_per_sector) as u32;
+        let mut sectors = vec![0u8; self.bytes_per_sector * 2];
+        let straddles = offset % self.bytes_per_sector == self.bytes_per_sector - 1;
+        let len = if straddles { self.bytes_per_sector * 2 } else { self.bytes_per_sector };
+        self.read_sector(sector, &mut sectors[..len])?;
+
+        let raw = u32::from(read_u16(&sectors, offset % self.bytes_per_sector));
+        let value = match self.fat_type {
+            // even clusters use the low 12 bits, odd clusters the high 12 bits
+            FatType::Fat12 if cluster % 2 == 0 => raw & 0xFFF,
+            FatType::Fat12 => raw >> 4,
+            FatType::Fat16 => raw,
+        };
+        if value >= end {
+            Ok(FatEntry::EndOfChain)
+        } else if value == bad {
+            Err(FatError::BadCluster)
+        } else if self.is_valid_cluster(value) {
+            Ok(FatEntry::Next(value))
+        } else {
+            Err(FatError::BadChain) // free, reserved or past the end of the volume
+        }
+    }
+
+    // the cluster after `cluster` in its chain, `None` at the end
+    fn next_cluster(&self, cluster: u32) -> Result<Option<u32>, FatError> {
+        match self.fat_entry(cluster)? {
+            FatEntry::Next(next) => Ok(Some(next)),
+            FatEntry::EndOfChain => Ok(None),
+        }
+    }
+
+    /// The entries of the directory at `location` (without deleted entries, long name parts and the volume label).
+    pub fn read_dir(&self, location: DirLocation) -> Result<Vec<DirEntry>, FatError> {
+        let mut entries = Vec::new();
+        let mut sector_buf = vec![0u8; self.bytes_per_sector];
+
+        // every sector of the directory, in order
+        let mut sectors: Vec<u32> = Vec::new();
+        match location {
+            DirLocation::Root => sectors.extend(self.root_dir_start..self.root_dir_start + self.root_dir_sectors),
+            DirLocation::Cluster(first) => {
+                if !self.is_valid_cluster(first) {
+                    return Err(FatError::BadChain);
+                }
+                let mut cluster = Some(first);
+                let mut steps = 0;
+                while let Some(current) = cluster {
+                    steps += 1;
+                    if steps > self.cluster_count {
+                        return Err(FatError::BadChain); // longer than the volume --> the chain loops
+                    }
+                    let start = self.cluster_start(current);
+                    sectors.extend(start..start + self.sectors_per_cluster);
+                    cluster = self.next_cluster(current)?;
+                }
+            }
+        }
+
+        for sector in sectors {
+            self.read_sector(sector, &mut sector_buf)?;
+            for raw in sector_buf.chunks_exact(DIR_ENTRY_SIZE) {
+                match raw[0] {
+                    ENTRY_END => return Ok(entries),
+                    ENTRY_DELETED => continue,
+                    _ => {}
+                }
+                let attributes = raw[11];
+                if attributes & ATTR_LONG_NAME == ATTR_LONG_NAME || attributes & ATTR_VOLUME_ID != 0 {
+                    continue;
+                }
+                entries.push(DirEntry {
+                    name: format_short_name(&raw[..11]),
+                    attributes,
+                    first_cluster: u32::from(read_u16(raw, 26)),
+                    size: read_u32(raw, 28),
+                });
+            }
+        }
+        Ok(entries)
+    }
+
+    /// The entries of the root directory.
+    pub fn root_dir(&self) -> Result<Vec<DirEntry>, FatError> {
+        self.read_dir(DirLocation::Root)
+    }
+
+    /// Look up the entry at `path` ("DOCS/README.TXT", names are compared case insensitively).
+    pub fn find(&self, path: &str) -> Result<DirEntry, FatError> {
+        let mut location = DirLocation::Root;
+        let mut components = path.split('/').filter(|component| !component.is_empty()).peekable();
+        while let Some(component) = components.next() {
+            let entry = self
+                .read_dir(location)?
+                .into_iter()
+                .find(|entry| entry.name.eq_ignore_ascii_case(component))
+                .ok_or(FatError::NotFound)?;
+            if components.peek().is_none() {
+                return Ok(entry);
+            }
+            if !entry.is_dir() {
+                return Err(FatError::NotADirectory);
+            }
+            location = entry.dir_location();
+        }
+        // an empty path is the root directory, which has no entry of its own
+        Err(FatError::IsADirectory)
+    }
+
+    /// Open the file at `path` for reading.
+    pub fn open_file(&self, path: &str) -> Result<File<'_, 'a>, FatError> {
+        let entry = self.find(path)?;
+        if entry.is_dir() {
+            return Err(FatError::IsADirectory);
+        }
+        Ok(self.file(&entry))
+    }
+
+    /// A reader for the file described by `entry`.
+    pub fn file(&self, entry: &DirEntry) -> File<'_, 'a> {
+        File {
+            volume: self,
+            size: entry.size,
+            position: 0,
+            first_cluster: entry.first_cluster,
+            cluster: entry.first_cluster,
+        }
+    }
+}
+
+/// An open file, read front to back.
+pub struct File<'v, 'a> {
+    volume: &'v Volume<'a>,
+    size: u32,
+    position: u32,
+    first_cluster: u32,
+    cluster: u32, // the cluster `position` lies in
+}
+
+impl File<'_, '_> {
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    /// Read up to `buf.len()` bytes at the current position, returns the number of bytes read (0 at the end of the file).
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, FatError> {
+        let volume = self.volume;
+        let cluster_size = volume.cluster_size() as u32;
+        let sector_size = volume.bytes_per_sector as u32;
+        let mut sector_buf = vec![0u8; volume.bytes_per_sector];
+        let mut read = 0;
+
+        while read < buf.len() && self.position < self.size {
+            // moved past the end of the current cluster --> follow the chain
+            if self.position > 0 && self.position % cluster_size == 0 {
+                self.cluster = volume.next_cluster(self.cluster)?.ok_or(FatError::UnexpectedEndOfChain)?;
+            }
+            if !volume.is_valid_cluster(self.cluster) {
+                return Err(FatError::BadChain);
+            }
+
+            let offset_in_cluster = self.position % cluster_size;
+            let sector = volume.cluster_start(self.cluster) + offset_in_cluster / sector_size;
+            volume.read_sector(sector, &mut sector_buf)?;
+
+            let offset_in_sector = (offset_in_cluster % sector_size) as usize;
+            let len = (sector_buf.len() - offset_in_sector)
+                .min(buf.len() - read)
+                .min((self.size - self.position) as usize);
+            buf[read..read + len].copy_from_slice(&sector_buf[offset_in_sector..offset_in_sector + len]);
+            read += len;
+            self.position += len as u32;
+        }
+        Ok(read)
+    }
+
+    /// Move to byte `position` (at most the end of the file) by walking the cluster chain, from the current cluster
+    /// if `position` isn't before it, otherwise from the start.
+    pub fn seek(&mut self, position: u32) -> Result<(), FatError> {
+        let position = position.min(self.size);
+        let cluster_size = self.volume.cluster_size() as u32;
+        // read() only moves on to the next cluster once it reads past a cluster boundary --> at a boundary
+        // `cluster` is the one before it
+        let target_index = position.saturating_sub(1) / cluster_size;
+        let current_index = self.position.saturating_sub(1) / cluster_size;
+        let (mut cluster, skip) = if current_index <= target_index {
+            (self.cluster, target_index - current_index)
+        } else {
+            (self.first_cluster, target_index) // the chain only links forward
+        };
+        for _ in 0..skip {
+            cluster = self.volume.next_cluster(cluster)?.ok_or(FatError::UnexpectedEndOfChain)?;
+        }
+        self.cluster = cluster;
+        self.position = position;
+        Ok(())
+    }
+
+    /// Read the rest of the file.
+    pub fn read_to_end(&mut self) -> Result<Vec<u8>, FatError> {
+        let mut data = vec![0u8; (self.size - self.position) as usize];
+        let mut filled = 0;
+        while filled < data.len() {
+            filled += self.read(&mut data[filled..])?;
+        }
+        Ok(data)
+    }
+}
+
+// VFS =============================
+// the VFS needs a filesystem that owns its device, a Volume only borrows one --> FatFileSystem keeps the device and
+// opens a Volume (one boot sector read) for every operation
+// - a file handle remembers where its last read_at() stopped (position and cluster) so sequential reads continue
+//   from there instead of walking the cluster chain from the start every time
+
+/// A FAT volume mountable in the VFS (see fs.rs).
+pub struct FatFileSystem {
+    device: Arc<dyn BlockDevice>,
+}
+
+impl FatFileSystem {
+    /// Check that `device` holds a FAT12 or FAT16 volume.
+    pub fn new(device: Arc<dyn BlockDevice>) -> Result<FatFileSystem, FatError> {
+        Volume::open(&*device)?;
+        Ok(FatFileSystem { device })
+    }
+}
+
+impl FileSystem for FatFileSystem {
+    fn open(&self, path: &[&str]) -> Result<Box<dyn FileHandle>, FsError> {
+        let volume = Volume::open(&*self.device)?;
+        if path.is_empty() {
+            return Ok(Box::new(FatDirHandle)); // the root directory has no entry of its own
+        }
+        let entry = volume.find(&path.join("/"))?;
+        if entry.is_dir() {
+            return Ok(Box::new(FatDirHandle));
+        }
+        let cursor = Mutex::new((0, entry.first_cluster));
+        Ok(Box::new(FatFileHandle { device: self.device.clone(), entry, cursor }))
+    }
+}
+
+struct FatFileHandle {
+    device: Arc<dyn BlockDevice>,
+    entry: DirEntry,
+    cursor: Mutex<(u32, u32)>, // the position after the last read and the cluster it lies in (see File)
+}
+
+impl FileHandle for FatFileHandle {
+    fn metadata(&self) -> Metadata {
+        Metadata { size: u64::from(self.entry.size), kind: FileKind::File }
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let volume = Volume::open(&*self.device)?;
+        let mut file = volume.file(&self.entry);
+        (file.position, file.cluster) = *self.cursor.lock();
+        file.seek(u32::try_from(offset).unwrap_or(u32::MAX))?;
+        let read = file.read(buf)?;
+        *self.cursor.lock() = (file.position, file.cluster);
+        Ok(read)
+    }
+}
+
+struct FatDirHandle;
+
+impl FileHandle for FatDirHandle {
+    fn metadata(&self) -> Metadata {
+        Metadata { size: 0, kind: FileKind::Directory }
+    }
+
+    fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> Result<usize, FsError> {
+        Err(FsError::IsADirectory)
+    }
+}
+
+// TESTS ===================================
+// the test image is built by fixtures/make_fat12.py, which also documents its layout
+
+#[cfg(test)]
+pub(crate) static TEST_IMAGE: &[u8] = include_bytes!("fixtures/fat12.img");
+
+#[cfg(test)]
+fn test_disk() -> crate::drivers::ramdisk::RamDisk {
+    crate::drivers::ramdisk::RamDisk::from_bytes(TEST_IMAGE)
+}
+
+#[test_case]
+fn test_fat_list_root() {
+    let disk = test_disk();
+    let volume = Volume::open(&disk).expect("open failed");
+    assert_eq!(volume.fat_type(), FatType::Fat12);
+    assert_eq!(volume.cluster_size(), 1024);
+
+    let names: Vec<String> = volume.root_dir().expect("read_dir failed").into_iter().map(|entry| entry.name).collect();
+    assert_eq!(names, ["HELLO.TXT", "BIG.TXT", "DOCS", "EMPTY.TXT", "BAD.TXT", "SHORT.TXT"]);
+}
+
+#[test_case]
+fn test_fat_read_files() {
+    let disk = test_disk();
+    let volume = Volume::open(&disk).expect("open failed");
+
+    let hello = volume.open_file("hello.txt").expect("open_file failed").read_to_end().expect("read failed");
+    assert_eq!(&hello[..], b"Hello from a FAT12 image!\n");
+
+    // 3000 bytes over 3 clusters that are out of order on disk, read in odd sized pieces
+    let mut big = volume.open_file("BIG.TXT").expect("open_file failed");
+    let mut data = Vec::new();
+    let mut chunk = [0u8; 333];
+    loop {
+        let read = big.read(&mut chunk).expect("read failed");
+        if read == 0 {
+            break;
+        }
+        data.extend_from_slice(&chunk[..read]);
+    }
+    assert_eq!(data.len(), 3000);
+    assert!(data.iter().enumerate().all(|(i, &byte)| byte == ((i * 7 + i / 256) % 256) as u8));
+
+    let readme = volume.open_file("/DOCS/README.TXT").expect("open_file failed").read_to_end().expect("read failed");
+    assert_eq!(&readme[..], b"Nested files work too.\n");
+    assert!(volume.open_file("EMPTY.TXT").expect("open_file failed").read_to_end().expect("read failed").is_empty());
+}
+
+#[test_case]
+fn test_fat_errors() {
+    let disk = test_disk();
+    let volume = Volume::open(&disk).expect("open failed");
+
+    assert_eq!(volume.open_file("BAD.TXT").and_then(|mut file| file.read_to_end()), Err(FatError::BadCluster));
+    assert_eq!(volume.open_file("SHORT.TXT").and_then(|mut file| file.read_to_end()), Err(FatError::UnexpectedEndOfChain));
+    assert_eq!(volume.open_file("MISSING.TXT").err(), Some(FatError::NotFound));
+    assert_eq!(volume.open_file("HELLO.TXT/X").err(), Some(FatError::NotADirectory));
+    assert_eq!(volume.open_file("DOCS").err(), Some(FatError::IsADirectory));
+
+    // an image cut short
+    let truncated = crate::drivers::ramdisk::RamDisk::from_bytes(&TEST_IMAGE[..20 * 512]);
+    assert_eq!(Volume::open(&truncated).err(), Some(FatError::Truncated));
+
+    // not a FAT volume at all
+    let blank = crate::drivers::ramdisk::RamDisk::new(4);
+    assert_eq!(Volume::open(&blank).err(), Some(FatError::BadBootSector));
+
+    // a device that claims 0 byte blocks
+    struct NoBlocks;
+    impl BlockDevice for NoBlocks {
+        fn block_size(&self) -> usize {
+            0
+        }
+        fn num_blocks(&self) -> u64 {
+            0
+        }
+        fn read_block(&self, _lba: u64, _buf: &mut [u8]) -> Result<(), BlockError> {
+            Err(BlockError::OutOfRange)
+        }
+        fn write_block(&self, _lba: u64, _buf: &[u8]) -> Result<(), BlockError> {
+            Err(BlockError::OutOfRange)
+        }
+    }
+    assert_eq!(Volume::open(&NoBlocks).err(), Some(FatError::Unsupported));
+}
+
+#[test_case]
+fn test_fat_sequential_read_at() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    // counts the blocks read, to see whether a read walked the cluster chain
+    struct CountingDisk {
+        disk: crate::drivers::ramdisk::RamDisk,
+        reads: AtomicUsize,
+    }
+    impl BlockDevice for CountingDisk {
+        fn block_size(&self) -> usize {
+            self.disk.block_size()
+        }
+        fn num_blocks(&self) -> u64 {
+            self.disk.num_blocks()
+        }
+        fn read_block(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+            self.reads.fetch_add(1, Ordering::Relaxed);
+            self.disk.read_block(lba, buf)
+        }
+        fn write_block(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+            self.disk.write_block(lba, buf)
+        }
+    }
+
+    let disk = Arc::new(CountingDisk { disk: test_disk(), reads: AtomicUsize::new(0) });
+    let fs = FatFileSystem::new(disk.clone()).expect("not a FAT volume");
+    let file = fs.open(&["BIG.TXT"]).expect("open failed");
+    let read_at = |offset: u64, buf: &mut [u8]| {
+        let before = disk.reads.load(Ordering::Relaxed);
+        assert_eq!(file.read_at(offset, buf), Ok(buf.len()));
+        disk.reads.load(Ordering::Relaxed) - before
+    };
+
+    // 128 byte pieces never straddle a sector --> every read is the boot sector and one data sector
+    let mut chunk = [0u8; 128];
+    let first_cost = read_at(0, &mut chunk);
+    for offset in (128..2560).step_by(128) {
+        read_at(offset, &mut chunk);
+    }
+    // a piece in the third cluster continues from the remembered cluster --> no FAT reads to get there
+    assert_eq!(read_at(2560, &mut chunk), first_cost);
+    assert!(chunk.iter().enumerate().all(|(i, &byte)| byte == (((2560 + i) * 7 + (2560 + i) / 256) % 256) as u8));
+
+    // going back still works, from the start of the chain
+    read_at(0, &mut chunk);
+    assert!(chunk.iter().enumerate().all(|(i, &byte)| byte == ((i * 7 + i / 256) % 256) as u8));
+}
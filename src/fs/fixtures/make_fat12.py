@@ -0,0 +1,107 @@
+#!/usr/bin/env python3
+# Builds fat12.img, the FAT12 test image used by src/fs/fat.rs --> run from this directory: `python3 make_fat12.py`
+# 64 KiB: 512 byte sectors, 2 sectors per cluster, 1 reserved sector, 2 FATs of 1 sector, 32 root entries
+# the layout is fixed so the tests can rely on it:
+#   HELLO.TXT   cluster 2
+#   BIG.TXT     3000 bytes in clusters 5 -> 3 -> 8 (out of order, odd and even cluster numbers)
+#   DOCS/       cluster 4 --> README.TXT cluster 6
+#   EMPTY.TXT   no clusters
+#   BAD.TXT     cluster 9, whose FAT entry marks the next cluster as bad
+#   SHORT.TXT   claims 3000 bytes but its chain ends after cluster 10
+import struct
+
+SECTOR = 512
+SECTORS_PER_CLUSTER = 2
+CLUSTER = SECTOR * SECTORS_PER_CLUSTER
+TOTAL_SECTORS = 128
+RESERVED = 1
+FATS = 2
+FAT_SECTORS = 1
+ROOT_ENTRIES = 32
+ROOT_SECTORS = ROOT_ENTRIES * 32 // SECTOR
+DATA_START = RESERVED + FATS * FAT_SECTORS + ROOT_SECTORS
+
+ATTR_READ_ONLY, ATTR_VOLUME_ID, ATTR_DIRECTORY, ATTR_ARCHIVE = 0x01, 0x08, 0x10, 0x20
+EOC, BAD = 0xFFF, 0xFF7
+
+image = bytearray(TOTAL_SECTORS * SECTOR)
+
+# boot sector + BPB
+boot = struct.pack(
+    "<3s8sHBHBHHBHHHIIBBBI11s8s",
+    b"\xEB\x3C\x90", b"MINI_OS ", SECTOR, SECTORS_PER_CLUSTER, RESERVED, FATS, ROOT_ENTRIES,
+    TOTAL_SECTORS, 0xF8, FAT_SECTORS, 32, 2, 0, 0, 0x80, 0, 0x29, 0x12345678, b"MINIOS     ", b"FAT12   ",
+)
+image[0:len(boot)] = boot
+image[510:512] = b"\x55\xAA"
+
+fat = {0: 0xFF8, 1: EOC}
+
+
+def chain(clusters):
+    for a, b in zip(clusters, clusters[1:]):
+        fat[a] = b
+    fat[clusters[-1]] = EOC
+
+
+def cluster_offset(n):
+    return (DATA_START + (n - 2) * SECTORS_PER_CLUSTER) * SECTOR
+
+
+def write_clusters(clusters, data):
+    for i, n in enumerate(clusters):
+        part = data[i * CLUSTER:(i + 1) * CLUSTER]
+        image[cluster_offset(n):cluster_offset(n) + len(part)] = part
+
+
+def entry(name, ext, attr, cluster, size):
+    return struct.pack("<8s3sB10xHHHI", name.ljust(8).encode(), ext.ljust(3).encode(), attr, 0, 0, cluster, size)
+
+
+hello = b"Hello from a FAT12 image!\n"
+big = bytes((i * 7 + i // 256) % 256 for i in range(3000))
+readme = b"Nested files work too.\n"
+
+chain([2]); write_clusters([2], hello)
+chain([5, 3, 8]); write_clusters([5, 3, 8], big)
+chain([4])
+chain([6]); write_clusters([6], readme)
+fat[9] = BAD
+chain([10]); write_clusters([10], b"s" * CLUSTER)
+
+root = [
+    entry("MINIOS", "", ATTR_VOLUME_ID, 0, 0),
+    entry("HELLO", "TXT", ATTR_ARCHIVE, 2, len(hello)),
+    b"\xE5" + entry("GONE", "TXT", ATTR_ARCHIVE, 0, 0)[1:],  # deleted
+    entry("BIG", "TXT", ATTR_ARCHIVE, 5, len(big)),
+    entry("DOCS", "", ATTR_DIRECTORY, 4, 0),
+    entry("EMPTY", "TXT", ATTR_ARCHIVE | ATTR_READ_ONLY, 0, 0),
+    entry("BAD", "TXT", ATTR_ARCHIVE, 9, 2 * CLUSTER),
+    entry("SHORT", "TXT", ATTR_ARCHIVE, 10, 3000),
+]
+root_offset = (RESERVED + FATS * FAT_SECTORS) * SECTOR
+image[root_offset:root_offset + 32 * len(root)] = b"".join(root)
+
+docs = [
+    entry(".", "", ATTR_DIRECTORY, 4, 0),
+    entry("..", "", ATTR_DIRECTORY, 0, 0),
+    entry("README", "TXT", ATTR_ARCHIVE, 6, len(readme)),
+]
+image[cluster_offset(4):cluster_offset(4) + 32 * len(docs)] = b"".join(docs)
+
+# FAT12 packs two 12 bit entries into 3 bytes
+fat_bytes = bytearray(FAT_SECTORS * SECTOR)
+for n, value in fat.items():
+    offset = n + n // 2
+    if n % 2 == 0:
+        fat_bytes[offset] = value & 0xFF
+        fat_bytes[offset + 1] = (fat_bytes[offset + 1] & 0xF0) | (value >> 8)
+    else:
+        fat_bytes[offset] = (fat_bytes[offset] & 0x0F) | ((value & 0x0F) << 4)
+        fat_bytes[offset + 1] = value >> 4
+for i in range(FATS):
+    start = (RESERVED + i * FAT_SECTORS) * SECTOR
+    image[start:start + len(fat_bytes)] = fat_bytes
+
+with open("fat12.img", "wb") as f:
+    f.write(image)
@@ -1,61 +1,283 @@
 // more info on the GDT (global descriptor table) and TSS:
-// https://web.archive.org/web/20190217233448/https://www.flingos.co.uk/docs/reference/Global-Descriptor-Table/ 
+// https://web.archive.org/web/20190217233448/https://www.flingos.co.uk/docs/reference/Global-Descriptor-Table/
 // https://en.wikipedia.org/wiki/X86_memory_segmentation
 // https://pages.cs.wisc.edu/~remzi/OSTEP/
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::structures::gdt::{GlobalDescriptorTable, Descriptor, SegmentSelector};
-use x86_64::VirtAddr;
-use lazy_static::lazy_static;
+use x86_64::{PrivilegeLevel, VirtAddr};
+use spin::Mutex;
+use core::ptr::{addr_of, addr_of_mut};
+use alloc::boxed::Box;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+// a machine check can arrive at any point, even in the middle of a stack switch --> it gets a known good stack too
+pub const MACHINE_CHECK_IST_INDEX: u16 = 1;
+// a stack overflow runs into the guard page below the kernel stack --> the CPU can't push the page fault's frame on the
+// stack that just overflowed, on a stack of its own the page fault handler sees the overflow (instead of a double fault)
+// (a page fault inside the handler starts over at the top of it, see NESTING at page_fault_handler() in interrupts.rs)
+pub const PAGE_FAULT_IST_INDEX: u16 = 2;
 
-lazy_static!{
-    static ref TSS: TaskStateSegment = {
-        // we create a new TSS instance --> create a new stack for all double fault exceptions for the CPU to switch to
-        // this is for cases like stack overflow where new exceptions cause new faults --> prevent TRIPLE FAULTS
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            // manually create a stack via `static mut`, static b/c it is a stack and mut because we need to be able to change it
-            // this is a very archaic stack definition --> there are no guard pages to protect against stack overflow corruption
-            // also b/c we are using static muts and unsafe blocks directly
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            // in x86 stacks fill from high address to low address (top to bottom)
-            // therefore we pass the stacks end pointer as the stack pointer
-            let stack_start = VirtAddr::from_ptr(unsafe {&STACK});
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
-        tss
+// the TSS is a plain `static mut` rather than a lazy static b/c its privilege stack table (RSP0) has to change at runtime:
+// RSP0 is the stack the CPU switches to when an interrupt or syscall arrives while running user code (ring 3), see process.rs
+// the CPU reads the TSS straight from memory on every such switch, so writing to it takes effect immediately
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
+
+// set up the TSS before the GDT (which points to it) is loaded
+fn init_tss() {
+    // we create a new TSS instance --> create a new stack for all double fault exceptions for the CPU to switch to
+    // this is for cases like stack overflow where new exceptions cause new faults --> prevent TRIPLE FAULTS
+    let double_fault_stack = {
+        // manually create a stack via `static mut`, static b/c it is a stack and mut because we need to be able to change it
+        // this is a very archaic stack definition --> there are no guard pages to protect against stack overflow corruption
+        // also b/c we are using static muts and unsafe blocks directly
+        const STACK_SIZE: usize = 4096 * 5;
+        static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+        // in x86 stacks fill from high address to low address (top to bottom)
+        // therefore we pass the stacks end pointer as the stack pointer
+        let stack_start = VirtAddr::from_ptr(unsafe { addr_of!(STACK) });
+        let stack_end = stack_start + STACK_SIZE;
+        stack_end
+    };
+    let machine_check_stack = {
+        const STACK_SIZE: usize = 4096 * 2;
+        static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+        VirtAddr::from_ptr(unsafe { addr_of!(STACK) }) + STACK_SIZE
+    };
+    let page_fault_stack = {
+        const STACK_SIZE: usize = 4096 * 5;
+        static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+        VirtAddr::from_ptr(unsafe { addr_of!(STACK) }) + STACK_SIZE
     };
+    unsafe {
+        (*addr_of_mut!(TSS)).interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = double_fault_stack;
+        (*addr_of_mut!(TSS)).interrupt_stack_table[MACHINE_CHECK_IST_INDEX as usize] = machine_check_stack;
+        (*addr_of_mut!(TSS)).interrupt_stack_table[PAGE_FAULT_IST_INDEX as usize] = page_fault_stack;
+    }
 }
 
-struct Selectors {
-    code_selector: SegmentSelector,
-    tss_selector: SegmentSelector
+/// Set the stack the CPU switches to when an interrupt or syscall arrives from user mode (TSS RSP0).
+///
+/// This function is unsafe because the caller must guarantee that `stack_top` is the top of a valid, mapped stack
+/// for as long as user code can run.
+pub unsafe fn set_kernel_stack(stack_top: VirtAddr) {
+    (*addr_of_mut!(TSS)).privilege_stack_table[0] = stack_top;
+}
+
+/// The segment selectors of the GDT entries.
+#[derive(Debug, Clone, Copy)]
+pub struct Selectors {
+    pub code_selector: SegmentSelector,
+    pub data_selector: SegmentSelector,
+    pub user_code_selector: SegmentSelector, // requested privilege level 3
+    pub user_data_selector: SegmentSelector, // requested privilege level 3
+    pub tss_selector: SegmentSelector,
 }
 
 // x86 processers still use some basic form of the segmentation system (as opposed to memory paging, which is newer + better)
 // in order for the CPU to use the TSS we need to set a segment descriptor pointing to the TSS segment
-lazy_static!{
-    static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
-        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (gdt, Selectors {code_selector, tss_selector})
-    };
+// the user code/data segments let us drop to ring 3 (see process.rs), their order (kernel code, kernel data, user data, user code)
+// is the one the `syscall`/`sysret` instructions expect in case we switch over to them later
+// every CPU gets a GDT built by this --> the selectors are the same on all of them
+fn new_gdt(tss: &'static TaskStateSegment) -> (GlobalDescriptorTable, Selectors) {
+    let mut gdt = GlobalDescriptorTable::new();
+    let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+    let data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
+    let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+    let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+    let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+    (gdt, Selectors {
+        code_selector,
+        data_selector,
+        user_code_selector: SegmentSelector::new(user_code_selector.index(), PrivilegeLevel::Ring3),
+        user_data_selector: SegmentSelector::new(user_data_selector.index(), PrivilegeLevel::Ring3),
+        tss_selector,
+    })
+}
+
+// MUTABLE GDT ====================================
+// the boot CPU's GDT lives in MUT_GDT so entries can be added after boot (ex. more segments for user mode), which only
+// take effect once the table is loaded again with reload()
+// - the table never moves (it's in a static) --> lgdt can point at it even though the Mutex only lends it out
+// - `ltr` marks the TSS descriptor busy in the table and refuses a busy one --> reload() makes it available again first
+// - the table holds 8 entries: the null entry, 4 segments and the TSS (2 entries) leave room for one more
+
+/// The boot CPU's GDT, with the selectors of its entries (see `MUT_GDT`).
+pub struct MutableGdt {
+    table: GlobalDescriptorTable,
+    selectors: Selectors,
+}
+
+// the available/busy bit of a TSS descriptor's type (available 0b1001, busy 0b1011)
+const TSS_BUSY: u64 = 1 << 41;
+// the entries a GlobalDescriptorTable has room for
+const GDT_ENTRIES: usize = 8;
+
+impl MutableGdt {
+    const fn empty() -> Self {
+        let null = SegmentSelector::new(0, PrivilegeLevel::Ring0);
+        MutableGdt {
+            table: GlobalDescriptorTable::new(),
+            selectors: Selectors {
+                code_selector: null,
+                data_selector: null,
+                user_code_selector: null,
+                user_data_selector: null,
+                tss_selector: null,
+            },
+        }
+    }
+
+    /// The selectors of the entries `init()` put in.
+    pub fn selectors(&self) -> Selectors {
+        self.selectors
+    }
+
+    /// Append `desc` to the table and return its selector (with the descriptor's privilege level), it can be used once
+    /// `reload()` has run.
+    ///
+    /// Panics if the table is full.
+    pub fn add_entry(&mut self, desc: Descriptor) -> SegmentSelector {
+        self.table.add_entry(desc)
+    }
+
+    /// `add_entry()` without the panic: `None` if there is no room left for `desc` (a TSS descriptor takes 2 entries).
+    pub fn try_add_entry(&mut self, desc: Descriptor) -> Option<SegmentSelector> {
+        let needed = match desc {
+            Descriptor::UserSegment(_) => 1,
+            Descriptor::SystemSegment(_, _) => 2,
+        };
+        if self.table.as_raw_slice().len() + needed > GDT_ENTRIES {
+            return None;
+        }
+        Some(self.table.add_entry(desc))
+    }
+
+    /// Load the table on this CPU and reload CS, SS and the task register from it, with interrupts off in between.
+    ///
+    /// Takes `&mut self` b/c the CPU writes the table: loading the TSS marks its descriptor busy.
+    pub fn reload(&mut self) {
+        use x86_64::instructions::segmentation::{Segment, CS, SS};
+        use x86_64::instructions::tables::{load_tss, sgdt};
+
+        // an interrupt in between would run with the new table but the old segment registers
+        let _interrupts = crate::sync::without_interrupts_guard();
+        unsafe {
+            // MutableGdt only exists in MUT_GDT, the table stays where it is for as long as the kernel runs
+            self.table.load_unsafe();
+            CS::set_reg(self.selectors.code_selector);
+            SS::set_reg(self.selectors.data_selector);
+            // loaded before --> the TSS descriptor is busy and `ltr` would fault
+            let entries = sgdt().base.as_mut_ptr::<u64>();
+            *entries.add(usize::from(self.selectors.tss_selector.index())) &= !TSS_BUSY;
+            load_tss(self.selectors.tss_selector);
+        }
+    }
+}
+
+/// The boot CPU's GDT, filled and loaded by `init()`. Only lock it with interrupts off (`reload()` disables them).
+pub static MUT_GDT: Mutex<MutableGdt> = Mutex::new(MutableGdt::empty());
+
+/// The selectors of the loaded GDT.
+pub fn selectors() -> Selectors {
+    let _interrupts = crate::sync::without_interrupts_guard();
+    MUT_GDT.lock().selectors
 }
 
 pub fn init() {
+    init_tss();
+    let _interrupts = crate::sync::without_interrupts_guard();
+    let mut gdt = MUT_GDT.lock();
+    let (table, selectors) = new_gdt(unsafe { &*addr_of!(TSS) });
+    *gdt = MutableGdt { table, selectors };
+    gdt.reload();
+}
+
+// load `gdt` and the segment registers/TSS from it
+fn load(gdt: &'static GlobalDescriptorTable, selectors: Selectors) {
     use x86_64::instructions::tables::load_tss;
-    use x86_64::instructions::segmentation::{CS, Segment};
+    use x86_64::instructions::segmentation::{CS, SS, Segment};
 
-    GDT.0.load();
+    gdt.load();
     unsafe {
         // since we created a new GDT (from the one the bootloader loads in) we have to reload the CS (code segment) register since the old one could point to something else
-        // we also need to tell the cpu to use the TSS instance via the `ltr` x86 instruction
-        CS::set_reg(GDT.1.code_selector);
-        load_tss(GDT.1.tss_selector);
+        // (same for the stack segment) we also need to tell the cpu to use the TSS instance via the `ltr` x86 instruction
+        CS::set_reg(selectors.code_selector);
+        SS::set_reg(selectors.data_selector);
+        load_tss(selectors.tss_selector);
     }
-}
\ No newline at end of file
+}
+
+// PER-CPU GDT ====================================
+// a TSS can't be shared: `ltr` marks its descriptor busy (a second CPU loading it faults), and every CPU needs its own
+// interrupt stacks --> each application processor (see smp.rs) gets a GDT with its own TSS, same layout and selectors as above
+// the boot CPU keeps the statics, set_kernel_stack() only changes its TSS (processes only run there)
+
+/// The GDT and TSS of an application processor, built on the heap by `CpuTables::allocate()` and never freed.
+pub struct CpuTables {
+    gdt: GlobalDescriptorTable,
+    selectors: Selectors,
+}
+
+impl CpuTables {
+    /// Allocate a TSS with fresh double fault, machine check and page fault stacks and a GDT pointing to it (needs the
+    /// heap and the frame allocator), `None` if there is no memory for the stacks.
+    pub fn allocate() -> Option<&'static CpuTables> {
+        let mut tss = TaskStateSegment::new();
+        // the same sizes as the boot CPU's stacks, from frames b/c the heap is small
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = crate::memory::allocate_stack(5)?;
+        tss.interrupt_stack_table[MACHINE_CHECK_IST_INDEX as usize] = crate::memory::allocate_stack(2)?;
+        tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX as usize] = crate::memory::allocate_stack(5)?;
+        let tss: &'static TaskStateSegment = Box::leak(Box::new(tss));
+        let (gdt, selectors) = new_gdt(tss);
+        Some(Box::leak(Box::new(CpuTables { gdt, selectors })))
+    }
+
+    /// Load the GDT and TSS on the calling CPU.
+    pub fn load(&'static self) {
+        load(&self.gdt, self.selectors);
+    }
+}
+
+// TESTS ===================================
+
+#[test_case]
+fn test_add_entry_and_reload() {
+    use x86_64::instructions::segmentation::{Segment, CS};
+    use x86_64::instructions::tables::sgdt;
+
+    // the descriptor privilege level of a segment descriptor
+    const DPL_SHIFT: u64 = 45;
+
+    let _interrupts = crate::sync::without_interrupts_guard();
+    let mut gdt = MUT_GDT.lock();
+    let before = gdt.selectors();
+    // the table is the live one --> put it back the way it was at the end, the one free entry is needed again
+    let mut saved = [0u64; GDT_ENTRIES];
+    let saved_len = gdt.table.as_raw_slice().len();
+    saved[..saved_len].copy_from_slice(gdt.table.as_raw_slice());
+
+    let selector = gdt.try_add_entry(Descriptor::user_data_segment()).expect("no free GDT entry");
+    assert_eq!(selector.rpl(), PrivilegeLevel::Ring3);
+    gdt.reload();
+    gdt.reload(); // the TSS is loaded again from a busy descriptor
+
+    // read the new entry back from the table the CPU uses
+    let pointer = sgdt();
+    assert!(u64::from(pointer.limit) + 1 >= (u64::from(selector.index()) + 1) * 8, "entry beyond the GDT limit");
+    let entry = unsafe { pointer.base.as_ptr::<u64>().add(usize::from(selector.index())).read() };
+    assert_eq!((entry >> DPL_SHIFT) & 0b11, 3);
+    assert_eq!(entry & (1 << 47), 1 << 47, "not present");
+    assert_eq!(CS::get_reg(), before.code_selector);
+    assert_eq!(gdt.selectors().tss_selector, before.tss_selector);
+
+    // that was the last free entry
+    assert_eq!(gdt.try_add_entry(Descriptor::user_code_segment()), None);
+    assert_eq!(gdt.try_add_entry(Descriptor::tss_segment(unsafe { &*addr_of!(TSS) })), None);
+
+    gdt.table = unsafe { GlobalDescriptorTable::from_raw_slice(&saved[..saved_len]) };
+    gdt.reload();
+    assert_eq!(u64::from(sgdt().limit) + 1, saved_len as u64 * 8, "the test entry is still in the GDT");
+}
@@ -2,13 +2,17 @@
 // there is a lot of "magic" that goes behind the scenes (setting up the stack, pointers, registers etc...)
 
 // the x86 crate provides us with idt structs and enums to make setup easier
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::structures::idt::{HandlerFunc, InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::{PrivilegeLevel, VirtAddr};
 
 use crate::{println, print};
 use lazy_static::lazy_static;
 use spin::Mutex;
 use pic8259::ChainedPics;
 use crate::hlt_loop;
+use crate::drivers::port::{HardwarePorts, PortIo};
+use pc_keyboard::KeyCode;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
 // the difference between hardware interrupts and cpu exceptions is that the former is asynchronous, but both are still interrupts by nature
 // therefore both have entries in the IDT (interrupt descriptor table; in protected mode) and/or IVT (interrupt vector table ; in real mode)
@@ -22,14 +26,351 @@ pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 pub static PICS: Mutex<ChainedPics> =
     Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
+// the PIT (programmable interval timer) is clocked at ~1.193182 MHz and divides that down by a 16 bit reload value
+// channel 0 is wired to IRQ 0 --> the timer interrupt below
+pub const PIT_BASE_FREQUENCY: u32 = 1_193_182;
+
+/// Reprogram PIT channel 0 to fire the timer interrupt roughly `hz` times per second.
+///
+/// The divisor is clamped to what fits in 16 bits, so the slowest rate is ~18.2 Hz (the power-on default).
+pub fn set_timer_frequency(hz: u32) {
+    use x86_64::instructions::port::Port;
+
+    let divisor = (PIT_BASE_FREQUENCY / hz.max(1)).clamp(1, 0xffff) as u16;
+    let mut command: Port<u8> = Port::new(0x43);
+    let mut channel_0: Port<u8> = Port::new(0x40);
+
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        // 0x36 --> channel 0, write low byte then high byte, mode 3 (square wave), binary counting
+        command.write(0x36);
+        channel_0.write((divisor & 0xff) as u8);
+        channel_0.write((divisor >> 8) as u8);
+    });
+}
+
+// number of timer interrupts since boot (see set_timer_frequency() for how often they happen)
+static TIMER_TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Number of timer interrupts since boot.
+pub fn timer_ticks() -> u64 {
+    TIMER_TICKS.load(Ordering::Relaxed)
+}
+
+// how often the other interrupts happened since boot (see stats())
+static BREAKPOINTS: AtomicU64 = AtomicU64::new(0);
+static KEYBOARD_INTERRUPTS: AtomicU64 = AtomicU64::new(0);
+#[allow(clippy::declare_interior_mutable_const)] // only used to initialize the array below
+const NO_IRQS: AtomicU64 = AtomicU64::new(0);
+static DEVICE_IRQS: [AtomicU64; IRQ_LINES] = [NO_IRQS; IRQ_LINES];
+
+/// Interrupt counts since boot.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct InterruptStats {
+    pub timer_ticks: u64,
+    pub keyboard: u64,
+    pub breakpoints: u64,
+    /// Per IRQ line, only lines with a handler from `register_irq_handler()` (or spurious IRQs) are counted.
+    pub device_irqs: [u64; IRQ_LINES],
+}
+
+/// A snapshot of the interrupt counters.
+pub fn stats() -> InterruptStats {
+    InterruptStats {
+        timer_ticks: timer_ticks(),
+        keyboard: KEYBOARD_INTERRUPTS.load(Ordering::Relaxed),
+        breakpoints: BREAKPOINTS.load(Ordering::Relaxed),
+        device_irqs: core::array::from_fn(|irq| DEVICE_IRQS[irq].load(Ordering::Relaxed)),
+    }
+}
+
+/// Maximum number of timer callbacks.
+pub const MAX_TIMER_CALLBACKS: usize = 8;
+// called by the timer interrupt with the new tick count (ex. the running test's timeout, see lib.rs); a fixed array so
+// registering never allocates
+static TIMER_CALLBACKS: Mutex<[Option<fn(u64)>; MAX_TIMER_CALLBACKS]> = Mutex::new([None; MAX_TIMER_CALLBACKS]);
+
+/// Call `callback` with the current tick count on every timer interrupt, panics if `MAX_TIMER_CALLBACKS` are registered already.
+///
+/// It runs inside the interrupt handler --> it has to be short and must not take locks that are held with interrupts enabled
+/// (or register another callback).
+pub fn register_timer_callback(callback: fn(u64)) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut callbacks = TIMER_CALLBACKS.lock();
+        let slot = callbacks.iter_mut().find(|slot| slot.is_none()).expect("too many timer callbacks");
+        *slot = Some(callback);
+    });
+}
+
+/// Stop calling `callback` (registered with `register_timer_callback()`), returns false if it wasn't registered.
+///
+/// Once this returns the callback isn't running and won't run again.
+pub fn unregister_timer_callback(callback: fn(u64)) -> bool {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut callbacks = TIMER_CALLBACKS.lock();
+        match callbacks.iter_mut().find(|slot| slot.map_or(false, |registered| core::ptr::fn_addr_eq(registered, callback))) {
+            Some(slot) => {
+                *slot = None;
+                true
+            }
+            None => false,
+        }
+    })
+}
+
+// the IRQ lines of the two chained PICs: 0 is the timer, 1 the keyboard and 2 connects the secondary PIC (the "cascade"),
+// the rest are free for devices, ex. PCI cards get one assigned by the firmware (see interrupt_line in drivers/pci.rs)
+pub const IRQ_LINES: usize = 16;
+const IRQ_CASCADE: u8 = 2;
+const FIRST_DEVICE_IRQ: u8 = 3;
+
+// the PICs' data ports hold their interrupt masks, a set bit ignores that line
+const PIC_1_DATA: u16 = 0x21;
+const PIC_2_DATA: u16 = 0xA1;
+// writing OCW3_READ_ISR to a command port makes the next read of it return the in-service register (the IRQs being handled)
+const PIC_1_COMMAND: u16 = 0x20;
+const PIC_2_COMMAND: u16 = 0xA0;
+const OCW3_READ_ISR: u8 = 0x0B;
+
+static IRQ_HANDLERS: Mutex<[Option<fn()>; IRQ_LINES]> = Mutex::new([None; IRQ_LINES]);
+
+/// Call `handler` on every interrupt of IRQ line `irq` and unmask the line in the PIC.
+///
+/// Like a timer callback it runs inside the interrupt handler (see `register_timer_callback()`). Panics if the line
+/// belongs to the timer, the keyboard or the cascade, or already has a handler.
+pub fn register_irq_handler(irq: u8, handler: fn()) {
+    assert!((FIRST_DEVICE_IRQ..IRQ_LINES as u8).contains(&irq), "IRQ {} can't be used by devices", irq);
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut handlers = IRQ_HANDLERS.lock();
+        let slot = &mut handlers[usize::from(irq)];
+        assert!(slot.is_none(), "IRQ {} already has a handler", irq);
+        *slot = Some(handler);
+        unmask_irq(irq);
+    });
+}
+
+fn unmask_irq(irq: u8) {
+    use x86_64::instructions::port::Port;
+
+    let _pics = PICS.lock(); // nobody else talks to the PICs meanwhile
+    let mut primary: Port<u8> = Port::new(PIC_1_DATA);
+    let mut secondary: Port<u8> = Port::new(PIC_2_DATA);
+    unsafe {
+        if irq < 8 {
+            let mask = primary.read();
+            primary.write(mask & !(1 << irq));
+        } else {
+            let mask = secondary.read();
+            secondary.write(mask & !(1 << (irq - 8)));
+            // the secondary PIC's lines only arrive through the cascade line of the primary one
+            let mask = primary.read();
+            primary.write(mask & !(1 << IRQ_CASCADE));
+        }
+    }
+}
+
+// SHELL COMMANDS ====================================
+
+/// Add `irqs` to the kernel shell (see kshell.rs), once the heap is up. `uptime` is in time.rs.
+pub fn init_shell_commands() {
+    crate::kshell::register_command("irqs", "interrupt counts since boot", irqs_command).expect("irqs registered twice");
+}
+
+fn irqs_command(args: &[&str]) -> Result<(), crate::kshell::ShellError> {
+    if !args.is_empty() {
+        return Err(crate::kshell::ShellError::Usage("irqs"));
+    }
+    let stats = stats();
+    println!("timer {}, keyboard {}, breakpoints {}", stats.timer_ticks, stats.keyboard, stats.breakpoints);
+    for (irq, count) in stats.device_irqs.iter().enumerate().filter(|(_, &count)| count > 0) {
+        println!("  irq {:2}: {}", irq, count);
+    }
+    Ok(())
+}
+
 // TESTS ===================================
 
 #[test_case]
 fn test_breakpoint_exception() {
+    let before = stats().breakpoints;
     // invoke a breakpoint exception
     x86_64::instructions::interrupts::int3();
+    assert_eq!(stats().breakpoints, before + 1);
+}
+
+// answers the 8042 controller's and keyboard's questions from a script and remembers every write
+#[cfg(test)]
+struct ScriptedKeyboard {
+    answers: alloc::collections::VecDeque<u8>,
+    writes: alloc::vec::Vec<(u16, u8)>,
+}
+
+#[cfg(test)]
+impl PortIo for ScriptedKeyboard {
+    fn read_u8(&mut self, port: u16) -> u8 {
+        match port {
+            KEYBOARD_STATUS_COMMAND if self.answers.is_empty() => 0,
+            KEYBOARD_STATUS_COMMAND => STATUS_OUTPUT_FULL,
+            _ => self.answers.pop_front().expect("read without an answer"),
+        }
+    }
+
+    fn write_u8(&mut self, port: u16, value: u8) {
+        self.writes.push((port, value));
+    }
+}
+
+#[test_case]
+fn test_switch_to_scancode_set_2() {
+    use pc_keyboard::DecodedKey;
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        // translation on in the configuration byte, the keyboard asks for the set number once more
+        let answers = [0x61, KEYBOARD_ACK, KEYBOARD_RESEND, KEYBOARD_ACK].into_iter().collect();
+        let mut ports = ScriptedKeyboard { answers, writes: alloc::vec::Vec::new() };
+        switch_scancode_set(&mut ports, 2).expect("switching failed");
+        assert_eq!(ports.writes, [
+            (KEYBOARD_STATUS_COMMAND, CONTROLLER_READ_CONFIG),
+            (KEYBOARD_STATUS_COMMAND, CONTROLLER_WRITE_CONFIG),
+            (KEYBOARD_DATA, 0x21),
+            (KEYBOARD_DATA, KEYBOARD_SCANCODE_SET),
+            (KEYBOARD_DATA, 2),
+            (KEYBOARD_DATA, 2),
+        ]);
+        assert_eq!(KEYBOARD.lock().set(), 2);
+        // 'A' is 0x1C in set 2 (0x1E in set 1), its release is 0xF0 0x1C
+        let decoded = [0x1C, 0xF0, 0x1C].map(|byte| KEYBOARD.lock().decode(byte));
+        assert_eq!(decoded, [Some(DecodedKey::Unicode('a')), None, None]);
+
+        // back to set 1 for QEMU's keyboard (this only changed the decoder, QEMU still translates)
+        let answers = [0x21, KEYBOARD_ACK, KEYBOARD_ACK].into_iter().collect();
+        switch_scancode_set(&mut ScriptedKeyboard { answers, writes: alloc::vec::Vec::new() }, 1).unwrap();
+        assert_eq!(KEYBOARD.lock().set(), 1);
+    });
+
+    let mut silent = ScriptedKeyboard { answers: Default::default(), writes: alloc::vec::Vec::new() };
+    assert_eq!(switch_scancode_set(&mut silent, 3), Err(KeyboardError::UnsupportedScancodeSet(3)));
+    assert!(silent.writes.is_empty());
+    assert_eq!(scancode_set(), 1);
+}
+
+#[test_case]
+fn test_f1_prints_help() {
+    use crate::vga_buffer::{screen_row, BUFFER_HEIGHT};
+    use pc_keyboard::DecodedKey;
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        // F1 is 0x3B in set 1, the release is 0xBB
+        let decoded = [0x3B, 0xBB].map(|byte| KEYBOARD.lock().decode(byte));
+        assert_eq!(decoded, [Some(DecodedKey::RawKey(KeyCode::F1)), None]);
+        assert!(process_special_key(KeyCode::F1));
+        // println! moved the help up by one row
+        let row = screen_row(BUFFER_HEIGHT - 2);
+        assert_eq!(&row[..KEY_HELP.len()], KEY_HELP.as_bytes());
+    });
+    assert!(!process_special_key(KeyCode::A));
+    assert!(process_special_key(KeyCode::PageUp), "special keys without a handler are still taken");
+}
+
+#[test_case]
+fn test_machine_check_handler_registered() {
+    use core::arch::asm;
+
+    // the IDT as the CPU sees it: 10 bytes of limit and base
+    let mut idtr = [0u8; 10];
+    unsafe { asm!("sidt [{}]", in(reg) idtr.as_mut_ptr(), options(nostack, preserves_flags)) };
+    let base = u64::from_le_bytes(idtr[2..10].try_into().unwrap());
+    // vector 18, 16 bytes per gate: offset 0-15, selector, IST, flags, offset 16-31, offset 32-63
+    let gate = unsafe { core::slice::from_raw_parts((base + 18 * 16) as *const u8, 16) };
+    let offset = u64::from(u16::from_le_bytes([gate[0], gate[1]]))
+        | u64::from(u16::from_le_bytes([gate[6], gate[7]])) << 16
+        | u64::from(u32::from_le_bytes(gate[8..12].try_into().unwrap())) << 32;
+    assert_ne!(offset, 0);
+    assert_eq!(offset, machine_check_handler as usize as u64);
+    assert!(gate[5] & 0x80 != 0, "gate not present");
+    // the IST field counts from 1
+    assert_eq!(u16::from(gate[4] & 0x7), crate::gdt::MACHINE_CHECK_IST_INDEX + 1);
+}
+
+#[test_case]
+fn test_simd_divide_by_zero() {
+    use crate::cpu::sse::{self, MXCSR_DEFAULT, MXCSR_FLAGS, MXCSR_MASK_SHIFT, MXCSR_ZE};
+    use core::arch::asm;
+
+    sse::enable_sse();
+    let previous = sse::read_mxcsr();
+    let before = SIMD_EXCEPTIONS.load(Ordering::Relaxed);
+    // unmask divide by zero only
+    unsafe { sse::write_mxcsr(MXCSR_DEFAULT & !(MXCSR_ZE << MXCSR_MASK_SHIFT)) };
+    let result: u32;
+    // 1.0 / 0.0 --> no xmm clobbers: the kernel is built without SSE, so no compiled code keeps anything in them
+    unsafe {
+        asm!(
+            "xorps xmm0, xmm0",
+            "movd xmm1, {one:e}",
+            "divss xmm1, xmm0",
+            "movd {result:e}, xmm1",
+            one = in(reg) 1.0f32.to_bits(),
+            result = out(reg) result,
+            options(nomem, nostack),
+        );
+    }
+    let mxcsr = sse::read_mxcsr();
+    unsafe { sse::write_mxcsr(previous) };
+
+    assert_eq!(SIMD_EXCEPTIONS.load(Ordering::Relaxed), before + 1);
+    // the handler masked the exception, the retried division only set the (sticky) flag again and produced +infinity
+    assert_eq!(mxcsr & MXCSR_FLAGS, MXCSR_ZE);
+    assert!(mxcsr & (MXCSR_ZE << MXCSR_MASK_SHIFT) != 0);
+    assert_eq!(result, f32::INFINITY.to_bits());
+}
+
+#[test_case]
+fn test_describe_page_fault() {
+    use alloc::format;
+
+    let described = |bits: u64| format!("{}", describe_page_fault(PageFaultErrorCode::from_bits_truncate(bits)));
+
+    // 0b111: a user mode write to a present page
+    let text = described(0b111);
+    assert!(text.starts_with("PROTECTION_VIOLATION | CAUSED_BY_WRITE | USER_MODE"), "{}", text);
+    assert!(text.contains("User Write to present page"), "{}", text);
+    // 0: a kernel read of a page that isn't mapped
+    assert_eq!(described(0), "NONE (Kernel Read from non-present page)");
+    // 0b10: a kernel write to a page that isn't mapped
+    assert!(described(0b10).contains("Kernel Write to non-present page"));
+    // 0b10101: a user mode fetch from a present NX page
+    let text = described(0b10101);
+    assert!(text.contains("INSTRUCTION_FETCH") && text.contains("User Instruction fetch from present page"), "{}", text);
+    // 0b1001: reserved bits win over everything else
+    let text = described(0b1001);
+    assert!(text.contains("MALFORMED_TABLE") && text.contains("reserved bits"), "{}", text);
+    // fetch + write can't happen
+    assert!(described(0b10010).contains("unexpected"));
+
+    let d = describe_page_fault(PageFaultErrorCode::USER_MODE | PageFaultErrorCode::CAUSED_BY_WRITE);
+    assert!(d.user_mode && d.caused_by_write && !d.protection_violation && !d.malformed_table && !d.instruction_fetch);
+}
+
+// with the PIC at its power-on offset of 0 the timer IRQ would arrive as vector 0
+#[test_case]
+static IRQ_HANDLER_AT_VECTOR_0: crate::ShouldPanic<fn()> = crate::ShouldPanic("mini_os::interrupts::irq_handler_at_vector_0", || {
+    let mut idt = InterruptDescriptorTable::new();
+    set_irq_vector_handler(&mut idt, 0, timer_interrupt_handler);
+});
+
+#[test_case]
+fn test_idt_entry_checks() {
+    let mut idt = InterruptDescriptorTable::new();
+    set_hardware_irq_handler(&mut idt, 15, timer_interrupt_handler);
+    set_exception_handler(&mut idt, 3, breakpoint_handler);
 }
 
+#[test_case]
+static EXCEPTION_HANDLER_AT_AN_IRQ: crate::ShouldPanic<fn()> = crate::ShouldPanic("mini_os::interrupts::exception_handler_at_an_irq", || {
+    let mut idt = InterruptDescriptorTable::new();
+    set_exception_handler(&mut idt, PIC_1_OFFSET, breakpoint_handler);
+});
+
 // END TESTS ===============================
 
 // Store different types of hardware interrupts for the intel 8259 as an enum
@@ -44,54 +385,157 @@ impl InterruptIndex {
     fn as_u8(self) -> u8 {
         self as u8
     }
-
-    fn as_usize(self) -> usize {
-        usize::from(self.as_u8())
-    }
 }
 
 // the idt struct has to have a static lifetime (i.e. live for the whole lifetime of the os) 
 // b/c handling exceptions/interrupts are required till the very end of the program
 // however we must also mutate the struct --> mutating statics (with no protection) is unsafe --> use lazy statics instead (still uses unsafe code bts)
+// (or a KernelOnce with the replace_lazy_static feature, see sync.rs)
+
+// create a new idt instance which contains fields for every type of interrupt
+// the breakpoint exception occurs when `int3` instruction is executed, temporarily pausing the program
+// we then set the handler function to handle that cpu exception
+fn new_idt() -> InterruptDescriptorTable {
+    let mut idt = InterruptDescriptorTable::new();
+    set_exception_handler(&mut idt, 3, breakpoint_handler);
+    unsafe {
+        // switch to different stack before invoking handler function --> recover from stack overflow
+        // and also prevent triple faults
+        idt.double_fault.set_handler_fn(double_fault_handler).set_stack_index(crate::gdt::DOUBLE_FAULT_IST_INDEX);
+    }
+    // the PIC's IRQ lines (see set_hardware_irq_handler()) -> set the timer interrupt handler func
+    set_hardware_irq_handler(&mut idt, InterruptIndex::Timer.as_u8() - PIC_1_OFFSET, timer_interrupt_handler);
+    set_hardware_irq_handler(&mut idt, InterruptIndex::Keyboard.as_u8() - PIC_1_OFFSET, keyboard_interrupt_handler); // set keyboard interrupt handler func
+    unsafe {
+        // on its own stack: a kernel stack overflow is a page fault in the guard page (see gdt.rs)
+        idt.page_fault.set_handler_fn(page_fault_handler).set_stack_index(crate::gdt::PAGE_FAULT_IST_INDEX);
+    }
+    idt.simd_floating_point.set_handler_fn(simd_fp_exception_handler); // only raised once SSE is enabled, see cpu/sse.rs
+    unsafe {
+        idt.machine_check.set_handler_fn(machine_check_handler).set_stack_index(crate::gdt::MACHINE_CHECK_IST_INDEX);
+    }
+    for &(irq, handler) in DEVICE_IRQ_HANDLERS {
+        set_hardware_irq_handler(&mut idt, irq, handler);
+    }
+    unsafe {
+        // the syscall entry is an assembly stub (it needs the caller's registers), privilege level 3 lets user code `int 0x80`
+        idt[usize::from(crate::syscall::abi::SYSCALL_VECTOR)]
+            .set_handler_addr(VirtAddr::new(crate::syscall::entry_address()))
+            .set_privilege_level(PrivilegeLevel::Ring3);
+    }
+    idt
+}
+
+// IDT ENTRIES ====================================
+// vectors 0-31 are the CPU's exceptions, the PICs' IRQs are remapped to 32-47 --> an IRQ handler on an exception vector
+// would be called for that exception (with an error code it doesn't pop, for some of them), so the two get set through
+// functions that check which kind of vector they write
+// - only while new_idt() builds the table: once loaded it's immutable (load() needs a &'static), nothing can race with
+//   it --> devices change their handler at runtime through register_irq_handler() instead
+
+/// Set the handler for the PIC's IRQ line `irq` (vector `PIC_1_OFFSET + irq`), panics unless that is one of 32-47.
+pub fn set_hardware_irq_handler(idt: &mut InterruptDescriptorTable, irq: u8, handler: HandlerFunc) {
+    let vector = irq.checked_add(PIC_1_OFFSET).unwrap_or_else(|| panic!("IRQ {} is no PIC IRQ line", irq));
+    set_irq_vector_handler(idt, vector, handler);
+}
+
+// the check of set_hardware_irq_handler(), by vector
+fn set_irq_vector_handler(idt: &mut InterruptDescriptorTable, vector: u8, handler: HandlerFunc) {
+    assert!(vector >= PIC_1_OFFSET, "vector {} is a CPU exception, not a hardware IRQ", vector);
+    assert!(vector < PIC_2_OFFSET + 8, "vector {} is past the PIC's IRQs (32-47)", vector);
+    idt[usize::from(vector)].set_handler_fn(handler);
+}
+
+/// Set the handler for CPU exception `vector`, panics unless it is one of 0-31.
+///
+/// Only for the exceptions without an error code that return (the x86_64 crate has other handler types for the
+/// others, set them on the named fields of the IDT).
+pub fn set_exception_handler(idt: &mut InterruptDescriptorTable, vector: u8, handler: HandlerFunc) {
+    assert!(vector < PIC_1_OFFSET, "vector {} is not a CPU exception (0-31)", vector);
+    idt[usize::from(vector)].set_handler_fn(handler); // panics itself for the other handler types and reserved vectors
+}
+
+#[cfg(not(feature = "replace_lazy_static"))]
 lazy_static! {
-    // create a new idt instance which contains fields for every type of interrupt
-    // the breakpoint exception occurs when `int3` instruction is executed, temporarily pausing the program
-    // we then set the handler function to handle that cpu exception
-    static ref IDT: InterruptDescriptorTable = {
-        let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        unsafe {
-            // switch to different stack before invoking handler function --> recover from stack overflow
-            // and also prevent triple faults
-            idt.double_fault.set_handler_fn(double_fault_handler).set_stack_index(crate::gdt::DOUBLE_FAULT_IST_INDEX);
-        }
-        // InterruptDescriptorTable implements IndexMut which allows array indexing syntax -> set the timer interrupt handler func
-        idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler); 
-        idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler); // set keyboard interrupt handler func
-        idt.page_fault.set_handler_fn(page_fault_handler); // set page fault handler
-        idt
-    };
+    static ref IDT: InterruptDescriptorTable = new_idt();
+}
+
+#[cfg(not(feature = "replace_lazy_static"))]
+pub fn init_idt() {
+    IDT.load();
 }
 
+// with the replace_lazy_static feature the IDT is built by init_idt() itself --> it may only be called once
+#[cfg(feature = "replace_lazy_static")]
+static IDT: crate::sync::KernelOnce<InterruptDescriptorTable> = crate::sync::KernelOnce::new();
+
+#[cfg(feature = "replace_lazy_static")]
 pub fn init_idt() {
+    IDT.init(new_idt()).load();
+}
+
+/// Load the IDT built by `init_idt()` on another CPU (see smp.rs), they all share the one table.
+pub fn load_idt() {
+    #[cfg(not(feature = "replace_lazy_static"))]
     IDT.load();
+    #[cfg(feature = "replace_lazy_static")]
+    IDT.get_or_panic().load();
 }
 
 // the "x86-interrupt" calling convention makes sure that all registers before the exception are preserved (typically by backing up to the stack)
 // many required steps are also executed: (using the `iretq` instruction to return from the handler func, aligning the stack, etc...)
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
+    let _no_alloc = crate::allocator::no_alloc_guard();
+    BREAKPOINTS.fetch_add(1, Ordering::Relaxed);
     println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
 
 // the double fault handler must be a diverging function b/c x86 arch does not allow returning from a double fault exception
+// set by the double fault handler before it panics --> the test panic handler exits with DoubleFault (see lib.rs)
+static DOUBLE_FAULTED: AtomicBool = AtomicBool::new(false);
+
+/// Whether there was a double fault, its handler panics right after.
+pub fn double_faulted() -> bool {
+    DOUBLE_FAULTED.load(Ordering::SeqCst)
+}
+
+// the frame pointer and stack pointer of the code that double faulted, for the backtrace the panic handler prints
+static DOUBLE_FAULT_RBP: AtomicU64 = AtomicU64::new(0);
+static DOUBLE_FAULT_RSP: AtomicU64 = AtomicU64::new(0);
+
+/// The frame pointer and stack pointer of the code that double faulted (see `backtrace::print_from()`), `None` before
+/// a double fault.
+pub fn double_fault_frame() -> Option<(u64, u64)> {
+    double_faulted().then(|| (DOUBLE_FAULT_RBP.load(Ordering::SeqCst), DOUBLE_FAULT_RSP.load(Ordering::SeqCst)))
+}
+
 extern "x86-interrupt" fn double_fault_handler(stack_frame: InterruptStackFrame, _error_code: u64) -> ! {
+    let _no_alloc = crate::allocator::no_alloc_guard();
+    // the handler's prologue pushed the interrupted code's rbp --> its frame chain, on the stack that faulted (printed by
+    // backtrace::print() in the panic handler, after the panic message)
+    let rbp: u64;
+    unsafe { core::arch::asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+    DOUBLE_FAULT_RBP.store(unsafe { *(rbp as *const u64) }, Ordering::SeqCst);
+    DOUBLE_FAULT_RSP.store(stack_frame.stack_pointer.as_u64(), Ordering::SeqCst);
+    DOUBLE_FAULTED.store(true, Ordering::SeqCst);
     panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
 }
 
 // the hardware timer interrupt handler --> notice the CPU reacts identically to CPU exceptions and external interrupts (proof: "x86-interrupt" ABI)
 // only difference is that some exceptions push an error code
 // the hardwire timer in this system is called the PIT chip
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
+extern "x86-interrupt" fn timer_interrupt_handler(stack_frame: InterruptStackFrame) {
+    let _no_alloc = crate::allocator::no_alloc_guard(); // also covers the timer callbacks
+    let ticks = TIMER_TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+    crate::watchdog::check(ticks, &stack_frame); // first, the rest of the handler may be what hangs
+    // roughly once a second --> close the cpu usage window and redraw the status bar with it
+    if ticks % u64::from(crate::config::get().timer_hz) == 0 {
+        crate::task::usage::roll_window();
+        crate::vga_buffer::refresh_status_bar();
+    }
+    for callback in TIMER_CALLBACKS.lock().iter().flatten() {
+        callback(ticks);
+    }
     print!(".");
     unsafe {
         // the intel 8259 PIC expects an EOI (end of interrupt signal) to continue processing interrupts
@@ -102,44 +546,464 @@ extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFr
 // Note: we can only handle PS/2 keyboards here, not USB keyboards. However, the mainboard/QEMU emulates USB keyboards as PS/2 devices
 // so we can safely ignore USB keyboards until we have USB support in our kernel!
 extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
+    let _no_alloc = crate::allocator::no_alloc_guard();
     use x86_64::instructions::port::Port;
 
-    lazy_static! {
-        static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
-            Mutex::new(Keyboard::new(ScancodeSet1::new(), layouts::Us104Key,
-                HandleControl::Ignore)
-            );
+    KEYBOARD_INTERRUPTS.fetch_add(1, Ordering::Relaxed);
+
+    let mut port = Port::new(KEYBOARD_DATA); // set up the 0x60 port (data port for the PS/2 keyboard)
+    let scancode: u8 = unsafe { port.read() }; // read the scancode from the keyboard
+    handle_scancode(scancode);
+
+    unsafe {
+        PICS.lock().notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
     }
+}
+
+/// Decode `scancode` and hand the key on, as the keyboard interrupt does with every byte the keyboard sends (also used
+/// by `task::keyboard::inject_scancode()`). Interrupts have to be off.
+pub(crate) fn handle_scancode(scancode: u8) {
+    use crate::task::keyboard::push_key;
+    use pc_keyboard::DecodedKey;
 
     let mut keyboard = KEYBOARD.lock(); // lock the mutex on each interrupt
-    let mut port = Port::new(0x60); // set up the 0x60 port (data port for the PS/2 keyboard)
+    let key = keyboard.decode(scancode); // decode the scancode, only key presses give a key
+    drop(keyboard); // a key handler may switch the scancode set
+    if let Some(key) = key {
+        match key {
+            DecodedKey::Unicode(character) => { // decoded key is either unicode or raw
+                if !push_key(key) { // goes to the task reading the keyboard, if there is one
+                    print!("{}", character);
+                }
+            }
+            DecodedKey::RawKey(code) => {
+                // a key with a handler runs it (see SPECIAL KEYS below), the others go to the reading task first
+                let handled = has_key_handler(code) && process_special_key(code);
+                if !handled && !push_key(key) && !process_special_key(code) {
+                    print!("{:?}", code);
+                }
+            }
+        }
+    }
+}
 
-    let scancode: u8 = unsafe { port.read() }; // read the scancode from the keyboard
-    if let Ok(Some(key_event)) = keyboard.add_byte(scancode) { // process/decode the scancode --> bind to key_event
-        if let Some(key) = keyboard.process_keyevent(key_event) { // get only the key (not release or pressed info) --> bind to key
-            match key {
-                DecodedKey::Unicode(character) => print!("{}", character), // decoded key is either unicode or raw --> print it
-                DecodedKey::RawKey(key) => print!("{:?}", key),
+// SPECIAL KEYS ====================================
+// function, arrow and navigation keys don't produce characters --> they run the handler registered for them instead
+// (F1 prints a short help by default, the others do nothing until something registers a handler)
+
+/// The keys `process_special_key()` takes care of.
+pub const SPECIAL_KEYS: [KeyCode; 22] = [
+    KeyCode::F1, KeyCode::F2, KeyCode::F3, KeyCode::F4, KeyCode::F5, KeyCode::F6,
+    KeyCode::F7, KeyCode::F8, KeyCode::F9, KeyCode::F10, KeyCode::F11, KeyCode::F12,
+    KeyCode::ArrowUp, KeyCode::ArrowDown, KeyCode::ArrowLeft, KeyCode::ArrowRight,
+    KeyCode::Home, KeyCode::End, KeyCode::PageUp, KeyCode::PageDown, KeyCode::Insert, KeyCode::Delete,
+];
+
+/// What F1 prints.
+pub const KEY_HELP: &str = "keys: F1 this help | commands: type `help` in the shell or on the serial console";
+
+fn print_key_help() {
+    println!("\n{}", KEY_HELP);
+}
+
+// indexed by `KeyCode as usize`, a const fn b/c the table is a static
+const fn default_key_handlers() -> [Option<fn()>; 256] {
+    let mut table: [Option<fn()>; 256] = [None; 256];
+    table[KeyCode::F1 as usize] = Some(print_key_help);
+    table
+}
+
+static KEY_HANDLER_TABLE: Mutex<[Option<fn()>; 256]> = Mutex::new(default_key_handlers());
+
+/// Run `handler` whenever `key` (one of `SPECIAL_KEYS`) is pressed, replacing its current handler.
+///
+/// It runs inside the keyboard interrupt handler, see `register_timer_callback()` for what that means.
+pub fn register_key_handler(key: KeyCode, handler: fn()) {
+    assert!(SPECIAL_KEYS.contains(&key), "{:?} isn't a special key", key);
+    x86_64::instructions::interrupts::without_interrupts(|| KEY_HANDLER_TABLE.lock()[key as usize] = Some(handler));
+}
+
+// whether something was registered for `key` (or it has a default handler)
+fn has_key_handler(key: KeyCode) -> bool {
+    x86_64::instructions::interrupts::without_interrupts(|| KEY_HANDLER_TABLE.lock()[key as usize].is_some())
+}
+
+/// Run the handler of a function, arrow or navigation key. Returns false for other keys.
+pub fn process_special_key(key: KeyCode) -> bool {
+    if !SPECIAL_KEYS.contains(&key) {
+        return false;
+    }
+    // copied out --> the lock isn't held while the handler runs, and interrupts are off while it is (callers outside of
+    // the keyboard interrupt may have them on, the interrupt would then spin on the lock forever)
+    let handler = x86_64::instructions::interrupts::without_interrupts(|| KEY_HANDLER_TABLE.lock()[key as usize]);
+    if let Some(handler) = handler {
+        handler();
+    }
+    true
+}
+
+// KEYBOARD ====================================
+// PS/2 keyboards know three scancode sets (what bytes a key press and release send):
+// - set 1 is the original XT one, set 2 the AT one (the default of every keyboard since), set 3 is rarely supported
+// - the 8042 controller translates set 2 to set 1 by default ("AT-to-XT translation", bit 6 of its configuration byte)
+//   --> that is why decoding set 1 works out of the box, set_scancode_set() turns the translation off so the decoder
+//   sees what the keyboard actually sends
+// - pc_keyboard only has decoders for set 1 and 2, so set 3 is refused
+
+// the 8042's ports: data (keyboard bytes and controller command arguments), status (read) / command (write)
+const KEYBOARD_DATA: u16 = 0x60;
+const KEYBOARD_STATUS_COMMAND: u16 = 0x64;
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+const CONTROLLER_READ_CONFIG: u8 = 0x20;
+const CONTROLLER_WRITE_CONFIG: u8 = 0x60;
+const CONFIG_TRANSLATION: u8 = 1 << 6;
+// keyboard commands and answers
+const KEYBOARD_SCANCODE_SET: u8 = 0xF0;
+const KEYBOARD_ACK: u8 = 0xFA;
+const KEYBOARD_RESEND: u8 = 0xFE;
+const KEYBOARD_RETRIES: usize = 3;
+// how often the status register is polled before giving up (about 1 µs per read)
+const KEYBOARD_POLLS: usize = 100_000;
+
+/// Why `set_scancode_set()` failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyboardError {
+    /// There is no decoder for this set.
+    UnsupportedScancodeSet(u8),
+    /// The controller or keyboard didn't answer.
+    Timeout,
+    /// The keyboard didn't acknowledge this byte.
+    NoAck(u8),
+}
+
+/// The scancode decoder in use, one variant per set (pc_keyboard's set is a type parameter).
+pub enum ActiveScancodeSet {
+    Set1(pc_keyboard::Keyboard<pc_keyboard::layouts::Us104Key, pc_keyboard::ScancodeSet1>),
+    Set2(pc_keyboard::Keyboard<pc_keyboard::layouts::Us104Key, pc_keyboard::ScancodeSet2>),
+}
+
+impl ActiveScancodeSet {
+    /// A fresh decoder for scancode set `set`, `None` if there is none.
+    pub fn new(set: u8) -> Option<Self> {
+        use pc_keyboard::{layouts::Us104Key, HandleControl, Keyboard, ScancodeSet1, ScancodeSet2};
+
+        match set {
+            1 => Some(ActiveScancodeSet::Set1(Keyboard::new(ScancodeSet1::new(), Us104Key, HandleControl::MapLettersToUnicode))),
+            2 => Some(ActiveScancodeSet::Set2(Keyboard::new(ScancodeSet2::new(), Us104Key, HandleControl::MapLettersToUnicode))),
+            _ => None,
+        }
+    }
+
+    /// The number of the set.
+    pub fn set(&self) -> u8 {
+        match self {
+            ActiveScancodeSet::Set1(_) => 1,
+            ActiveScancodeSet::Set2(_) => 2,
+        }
+    }
+
+    /// Feed one byte from the keyboard, returns the key once a key press is complete.
+    pub fn decode(&mut self, scancode: u8) -> Option<pc_keyboard::DecodedKey> {
+        match self {
+            ActiveScancodeSet::Set1(keyboard) => {
+                let event = keyboard.add_byte(scancode).ok()??;
+                keyboard.process_keyevent(event)
+            }
+            ActiveScancodeSet::Set2(keyboard) => {
+                let event = keyboard.add_byte(scancode).ok()??;
+                keyboard.process_keyevent(event)
             }
         }
     }
+}
+
+lazy_static! {
+    static ref KEYBOARD: Mutex<ActiveScancodeSet> =
+        Mutex::new(ActiveScancodeSet::new(1).expect("no set 1 decoder"));
+}
+
+/// The scancode set the keyboard interrupt decodes.
+pub fn scancode_set() -> u8 {
+    x86_64::instructions::interrupts::without_interrupts(|| KEYBOARD.lock().set())
+}
+
+// wait until the controller took the last byte written to it
+fn wait_input_empty<P: PortIo>(ports: &mut P) -> Result<(), KeyboardError> {
+    for _ in 0..KEYBOARD_POLLS {
+        if ports.read_u8(KEYBOARD_STATUS_COMMAND) & STATUS_INPUT_FULL == 0 {
+            return Ok(());
+        }
+    }
+    Err(KeyboardError::Timeout)
+}
 
+// the next byte from the controller or keyboard
+fn read_data<P: PortIo>(ports: &mut P) -> Result<u8, KeyboardError> {
+    for _ in 0..KEYBOARD_POLLS {
+        if ports.read_u8(KEYBOARD_STATUS_COMMAND) & STATUS_OUTPUT_FULL != 0 {
+            return Ok(ports.read_u8(KEYBOARD_DATA));
+        }
+    }
+    Err(KeyboardError::Timeout)
+}
+
+// send a byte to the keyboard and wait for its ACK, resending when it asks for it
+fn send_to_keyboard<P: PortIo>(ports: &mut P, byte: u8) -> Result<(), KeyboardError> {
+    for _ in 0..KEYBOARD_RETRIES {
+        wait_input_empty(ports)?;
+        ports.write_u8(KEYBOARD_DATA, byte);
+        match read_data(ports)? {
+            KEYBOARD_ACK => return Ok(()),
+            KEYBOARD_RESEND => continue,
+            _ => break,
+        }
+    }
+    Err(KeyboardError::NoAck(byte))
+}
+
+// turn off the controller's AT-to-XT translation, switch the keyboard to `set` and swap the decoder
+fn switch_scancode_set<P: PortIo>(ports: &mut P, set: u8) -> Result<(), KeyboardError> {
+    let decoder = ActiveScancodeSet::new(set).ok_or(KeyboardError::UnsupportedScancodeSet(set))?;
+    wait_input_empty(ports)?;
+    ports.write_u8(KEYBOARD_STATUS_COMMAND, CONTROLLER_READ_CONFIG);
+    let config = read_data(ports)?;
+    if config & CONFIG_TRANSLATION != 0 {
+        wait_input_empty(ports)?;
+        ports.write_u8(KEYBOARD_STATUS_COMMAND, CONTROLLER_WRITE_CONFIG);
+        wait_input_empty(ports)?;
+        ports.write_u8(KEYBOARD_DATA, config & !CONFIG_TRANSLATION);
+    }
+    send_to_keyboard(ports, KEYBOARD_SCANCODE_SET)?;
+    send_to_keyboard(ports, set)?;
+    *KEYBOARD.lock() = decoder;
+    Ok(())
+}
+
+/// Switch the keyboard to scancode set `set` (1 or 2) and decode that set from now on.
+///
+/// On an error the decoder stays as it was, but the keyboard may have switched already (ex. when only the second ACK
+/// is missing).
+pub fn set_scancode_set(set: u8) -> Result<(), KeyboardError> {
+    // the answers are read here, the keyboard interrupt mustn't take them
+    x86_64::instructions::interrupts::without_interrupts(|| switch_scancode_set(&mut HardwarePorts, set))
+}
+
+// every device IRQ line needs its own entry point, the handler can't tell through which vector it was called
+macro_rules! device_irq_handlers {
+    ($($irq:literal => $name:ident),* $(,)?) => {
+        $(
+            extern "x86-interrupt" fn $name(_stack_frame: InterruptStackFrame) {
+                device_irq_handler($irq);
+            }
+        )*
+        const DEVICE_IRQ_HANDLERS: &[(u8, HandlerFunc)] = &[$(($irq, $name)),*];
+    };
+}
+
+device_irq_handlers!(
+    3 => irq3_handler, 4 => irq4_handler, 5 => irq5_handler, 6 => irq6_handler, 7 => irq7_handler,
+    8 => irq8_handler, 9 => irq9_handler, 10 => irq10_handler, 11 => irq11_handler, 12 => irq12_handler,
+    13 => irq13_handler, 14 => irq14_handler, 15 => irq15_handler,
+);
+
+// runs whatever register_irq_handler() installed for the line (lines without a handler stay masked, except for spurious IRQs)
+fn device_irq_handler(irq: u8) {
+    let _no_alloc = crate::allocator::no_alloc_guard();
+    DEVICE_IRQS[usize::from(irq)].fetch_add(1, Ordering::Relaxed);
+    if is_spurious_irq(irq) {
+        // the PIC that raised it isn't handling anything --> no EOI for it, but the primary PIC did pass on the
+        // secondary's cascade line and waits for its EOI
+        if irq >= 8 {
+            unsafe { PICS.lock().notify_end_of_interrupt(PIC_1_OFFSET + IRQ_CASCADE) };
+        }
+        return;
+    }
+    let handler = IRQ_HANDLERS.lock()[usize::from(irq)]; // copied out --> the lock isn't held while the handler runs
+    if let Some(handler) = handler {
+        handler();
+    }
     unsafe {
-        PICS.lock().notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
+        PICS.lock().notify_end_of_interrupt(PIC_1_OFFSET + irq);
+    }
+}
+
+// a line that drops again before the PIC delivers it comes in as that PIC's lowest priority line (IRQ 7 or 15) without
+// its in-service bit set --> nobody raised it, see https://wiki.osdev.org/8259_PIC#Spurious_IRQs
+fn is_spurious_irq(irq: u8) -> bool {
+    use x86_64::instructions::port::Port;
+
+    let command = match irq {
+        7 => PIC_1_COMMAND,
+        15 => PIC_2_COMMAND,
+        _ => return false,
+    };
+    let _pics = PICS.lock(); // nobody else talks to the PICs meanwhile
+    let mut port: Port<u8> = Port::new(command);
+    let in_service = unsafe {
+        port.write(OCW3_READ_ISR);
+        port.read()
+    };
+    in_service & (1 << 7) == 0
+}
+
+// CPUID.1:EDX feature bits
+const CPUID_MCE: u32 = 1 << 7; // the machine check exception
+const CPUID_MCA: u32 = 1 << 14; // the machine check architecture (MCG_CAP and the banks)
+
+/// Deliver machine checks as #MC (CR4.MCE) instead of shutting the CPU down, if the CPU has them.
+pub fn enable_machine_check() {
+    use x86_64::registers::control::{Cr4, Cr4Flags};
+
+    if crate::cpuid::detect_cpu_features().leaf1.edx & CPUID_MCE != 0 {
+        unsafe { Cr4::update(|flags| flags.insert(Cr4Flags::MACHINE_CHECK_EXCEPTION)) };
+    }
+}
+
+// a machine check means the hardware itself failed (ex. an uncorrectable ECC error) --> print what the MCA banks say and stop
+// the x86_64 crate only takes a diverging handler for #MC (there is no iretq back), so even when MCG_STATUS.RIPV says
+// the interrupted code could go on we halt, it is only reported
+extern "x86-interrupt" fn machine_check_handler(stack_frame: InterruptStackFrame) -> ! {
+    let _no_alloc = crate::allocator::no_alloc_guard();
+    use crate::cpu::msr;
+
+    crate::serial_println!("EXCEPTION: MACHINE CHECK\n{:#?}", stack_frame);
+    if crate::cpuid::detect_cpu_features().leaf1.edx & CPUID_MCA != 0 {
+        unsafe {
+            let status = msr::read(msr::IA32_MCG_STATUS);
+            let banks = (msr::read(msr::IA32_MCG_CAP) & msr::MCG_CAP_COUNT_MASK) as u8;
+            for bank in 0..banks {
+                let bank_status = msr::read(msr::ia32_mc_status(bank));
+                if bank_status & msr::MC_STATUS_VAL != 0 {
+                    crate::serial_println!(
+                        "  bank {}: status {:#018x} MCA error code {:#06x}{}",
+                        bank, bank_status, msr::mca_error_code(bank_status),
+                        if bank_status & msr::MC_STATUS_UC != 0 { " (uncorrected)" } else { "" }
+                    );
+                }
+            }
+            // done handling --> another machine check is reported instead of shutting the CPU down
+            msr::clear_bits(msr::IA32_MCG_STATUS, msr::MCG_STATUS_MCIP);
+            let restartable = status & msr::MCG_STATUS_RIPV != 0;
+            crate::serial_println!("  MCG_STATUS {:#x}, restart {}possible, halting", status, if restartable { "" } else { "not " });
+        }
+    }
+    hlt_loop();
+}
+
+// number of SIMD floating point exceptions (#XF) since boot
+static SIMD_EXCEPTIONS: AtomicU64 = AtomicU64::new(0);
+
+// an unmasked SSE exception (ex. a division by zero with MXCSR.ZM clear) --> print which ones were raised and go on
+// #XF is a fault, the instruction runs again after we return: the raised exceptions are masked so that it completes with the
+// default result this time instead of faulting forever, and their sticky flags are cleared
+extern "x86-interrupt" fn simd_fp_exception_handler(stack_frame: InterruptStackFrame) {
+    let _no_alloc = crate::allocator::no_alloc_guard();
+    use crate::cpu::sse::{self, MXCSR_FLAGS, MXCSR_FLAG_NAMES, MXCSR_MASK_SHIFT};
+
+    SIMD_EXCEPTIONS.fetch_add(1, Ordering::Relaxed);
+    let mxcsr = sse::read_mxcsr();
+    let raised = mxcsr & MXCSR_FLAGS;
+    print!("EXCEPTION: SIMD FLOATING POINT (MXCSR {:#x}):", mxcsr);
+    for (flag, name) in MXCSR_FLAG_NAMES {
+        if raised & flag != 0 {
+            print!(" {}", name);
+        }
+    }
+    println!("\n{:#?}", stack_frame);
+    unsafe { sse::write_mxcsr((mxcsr & !MXCSR_FLAGS) | raised << MXCSR_MASK_SHIFT) };
+}
+
+// PAGE FAULT ERROR CODE ====================================
+
+/// The bits of a page fault's error code, see `describe_page_fault()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageFaultDescription {
+    /// The page was present, the access broke its protection (otherwise: the page wasn't present).
+    pub protection_violation: bool,
+    /// A write (otherwise: a read).
+    pub caused_by_write: bool,
+    /// The access came from ring 3.
+    pub user_mode: bool,
+    /// A page table entry had a reserved bit set.
+    pub malformed_table: bool,
+    /// The access was an instruction fetch (needs NX enabled).
+    pub instruction_fetch: bool,
+}
+
+/// Split a page fault's error code into its flags, printed as `FLAG | FLAG (what happened)`.
+pub fn describe_page_fault(code: PageFaultErrorCode) -> PageFaultDescription {
+    PageFaultDescription {
+        protection_violation: code.contains(PageFaultErrorCode::PROTECTION_VIOLATION),
+        caused_by_write: code.contains(PageFaultErrorCode::CAUSED_BY_WRITE),
+        user_mode: code.contains(PageFaultErrorCode::USER_MODE),
+        malformed_table: code.contains(PageFaultErrorCode::MALFORMED_TABLE),
+        instruction_fetch: code.contains(PageFaultErrorCode::INSTRUCTION_FETCH),
+    }
+}
+
+impl core::fmt::Display for PageFaultDescription {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        let flags = [
+            (self.protection_violation, "PROTECTION_VIOLATION"),
+            (self.caused_by_write, "CAUSED_BY_WRITE"),
+            (self.user_mode, "USER_MODE"),
+            (self.malformed_table, "MALFORMED_TABLE"),
+            (self.instruction_fetch, "INSTRUCTION_FETCH"),
+        ];
+        let mut any = false;
+        for (_, name) in flags.iter().filter(|(set, _)| *set) {
+            write!(f, "{}{}", if any { " | " } else { "" }, name)?;
+            any = true;
+        }
+        if !any {
+            f.write_str("NONE")?;
+        }
+
+        let who = if self.user_mode { "User" } else { "Kernel" };
+        let page = if self.protection_violation { "present page" } else { "non-present page" };
+        match (self.malformed_table, self.instruction_fetch, self.caused_by_write) {
+            // the CPU checks the reserved bits while walking the tables --> nothing else in the code matters then
+            (true, _, _) => write!(f, " ({} access through a page table with reserved bits set)", who),
+            (false, false, true) => write!(f, " ({} Write to {})", who, page),
+            (false, false, false) => write!(f, " ({} Read from {})", who, page),
+            (false, true, false) => write!(f, " ({} Instruction fetch from {})", who, page),
+            // a fetch never writes, the CPU doesn't report this
+            (false, true, true) => f.write_str(" (unexpected combination)"),
+        }
     }
 }
 
 // page fault occurs when accessing unmapped or out of bounds memory + others (different from segmentation fault)
-// NOTE: guard pages (stack overflow protection) cause page faults to catch stack overflows, however when a stack overflow occurs
-// two page faults will be called in succession because pushing the interrupt stack frame is also invalid, 
-// which means even though we set up a page fault handler on stack overflow the double fault exception will be the one called
+// NOTE: guard pages (stack overflow protection) cause page faults to catch stack overflows, on the faulting stack pushing the
+// interrupt stack frame would fault again (--> double fault), so the handler runs on its own IST stack (PAGE_FAULT_IST_INDEX)
+// and a kernel stack overflow shows up here as an access to the guard page (tests/stack_overflow_guarded.rs)
+// NESTING: every page fault starts at the top of that same stack --> a page fault inside the handler (ex. a bad pointer
+// while printing) overwrites the frames of the handler it interrupted. That handler never runs again: it only ever
+// halts, or a test's recovery jumps out of it (expect_page_fault(), a panic under test_runner()), and the nested one
+// doesn't return to it either --> IN_PAGE_FAULT marks a nested fault, which reports itself without locks and halts
+static IN_PAGE_FAULT: AtomicBool = AtomicBool::new(false);
+
+/// For the test recovery once it jumped out of the page fault handler (there is no handler left on its stack).
+pub(crate) fn reset_page_fault_nesting() {
+    IN_PAGE_FAULT.store(false, Ordering::SeqCst);
+}
+
 extern "x86-interrupt" fn page_fault_handler(stack_frame: InterruptStackFrame, error_code: PageFaultErrorCode) {
     use x86_64::registers::control::Cr2; // cr2 register contains the virtual addr that caused the page fault
 
+    if IN_PAGE_FAULT.swap(true, Ordering::SeqCst) {
+        // the interrupted handler may hold the output locks, and its frames are gone
+        crate::panic_println!("EXCEPTION: PAGE FAULT in the page fault handler, accessing {:?}", Cr2::read());
+        crate::panic_println!("Error Code: {}", describe_page_fault(error_code));
+        hlt_loop();
+    }
+    crate::expected_page_fault(error_code); // before the no-alloc guard, a test expecting this fault doesn't come back here
+    let _no_alloc = crate::allocator::no_alloc_guard();
+
     println!("EXCEPTION: PAGE FAULT");
     println!("Accessed Address: {:?}", Cr2::read());
-    println!("Error Code: {:?}", error_code);
+    println!("Error Code: {}", describe_page_fault(error_code));
     println!("{:#?}", stack_frame);
     hlt_loop();
 }
\ No newline at end of file
@@ -0,0 +1,302 @@
+// Message queues between tasks --> a fixed size ring of messages plus the tasks waiting to send or receive
+// - send()/recv() are futures: a task that finds the queue full (or empty) registers its waker in the sender (or receiver)
+//   wait queue and returns Poll::Pending, the other side wakes one waiting task after each recv() (or send())
+// - the waker is registered while the queue is still locked, so a message arriving right after the check can't be missed
+// - a queue is shared by reference: a `static` (MessageQueue::new() is const) gives every task a `&'static` handle,
+//   an `Arc<MessageQueue>` works too
+// - try_send()/try_recv() never wait and never allocate --> usable from interrupt handlers
+use crate::sync::IrqMutex;
+use alloc::collections::VecDeque;
+use core::future::poll_fn;
+use core::mem::MaybeUninit;
+use core::task::{Poll, Waker};
+
+/// Errors of the IPC primitives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpcError {
+    /// The receiving side called `close()`.
+    Closed,
+}
+
+// RING BUFFER ====================================
+
+/// A FIFO of at most `CAP` values stored inline (no heap).
+pub struct RingBuffer<T, const CAP: usize> {
+    slots: [MaybeUninit<T>; CAP],
+    head: usize, // oldest value
+    len: usize,
+}
+
+impl<T, const CAP: usize> RingBuffer<T, CAP> {
+    pub const fn new() -> Self {
+        RingBuffer {
+            // an array of MaybeUninit needs no initialization
+            slots: unsafe { MaybeUninit::<[MaybeUninit<T>; CAP]>::uninit().assume_init() },
+            head: 0,
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == CAP
+    }
+
+    /// Append `value`, gives it back if the buffer is full.
+    pub fn push(&mut self, value: T) -> Result<(), T> {
+        if self.is_full() {
+            return Err(value);
+        }
+        self.slots[(self.head + self.len) % CAP].write(value);
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Remove the oldest value.
+    pub fn pop(&mut self) -> Option<T> {
+        if self.is_empty() {
+            return None;
+        }
+        let value = unsafe { self.slots[self.head].assume_init_read() };
+        self.head = (self.head + 1) % CAP;
+        self.len -= 1;
+        Some(value)
+    }
+}
+
+impl<T, const CAP: usize> Default for RingBuffer<T, CAP> {
+    fn default() -> Self {
+        RingBuffer::new()
+    }
+}
+
+impl<T, const CAP: usize> Drop for RingBuffer<T, CAP> {
+    fn drop(&mut self) {
+        while self.pop().is_some() {}
+    }
+}
+
+// WAIT QUEUE ====================================
+
+/// Tasks waiting for something, woken in the order they started waiting.
+pub struct WaitQueue {
+    wakers: IrqMutex<VecDeque<Waker>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        WaitQueue { wakers: IrqMutex::new(VecDeque::new()) }
+    }
+
+    /// Wake `waker` on the next `wake_one()`/`wake_all()` (a task polled again while waiting is only queued once).
+    pub fn register(&self, waker: &Waker) {
+        let mut wakers = self.wakers.lock_irq();
+        if !wakers.iter().any(|queued| queued.will_wake(waker)) {
+            wakers.push_back(waker.clone());
+        }
+    }
+
+    /// Wake the task that has been waiting the longest.
+    pub fn wake_one(&self) {
+        let waker = self.wakers.lock_irq().pop_front(); // woken without the lock, the waker may take other locks
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+
+    /// Wake every waiting task.
+    pub fn wake_all(&self) {
+        while let Some(waker) = self.wakers.lock_irq().pop_front() {
+            waker.wake();
+        }
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        WaitQueue::new()
+    }
+}
+
+// MESSAGE QUEUE ====================================
+
+struct QueueState<T, const CAP: usize> {
+    buf: RingBuffer<T, CAP>,
+    closed: bool,
+}
+
+/// A bounded queue of messages between tasks, see the top of this file.
+pub struct MessageQueue<T, const CAP: usize> {
+    state: IrqMutex<QueueState<T, CAP>>,
+    sender_wq: WaitQueue,
+    receiver_wq: WaitQueue,
+}
+
+impl<T, const CAP: usize> MessageQueue<T, CAP> {
+    pub const fn new() -> Self {
+        MessageQueue {
+            state: IrqMutex::new(QueueState { buf: RingBuffer::new(), closed: false }),
+            sender_wq: WaitQueue::new(),
+            receiver_wq: WaitQueue::new(),
+        }
+    }
+
+    /// Send `msg`, waiting while the queue is full. Fails once the queue is closed.
+    pub async fn send(&self, msg: T) -> Result<(), IpcError> {
+        let mut msg = Some(msg);
+        poll_fn(|cx| {
+            let mut state = self.state.lock_irq();
+            if state.closed {
+                return Poll::Ready(Err(IpcError::Closed));
+            }
+            if state.buf.is_full() {
+                self.sender_wq.register(cx.waker());
+                return Poll::Pending;
+            }
+            if let Some(msg) = msg.take() {
+                let _ = state.buf.push(msg); // not full, checked above
+            }
+            drop(state);
+            self.receiver_wq.wake_one();
+            Poll::Ready(Ok(()))
+        })
+        .await
+    }
+
+    /// Receive the oldest message, waiting while the queue is empty.
+    pub async fn recv(&self) -> T {
+        poll_fn(|cx| {
+            let mut state = self.state.lock_irq();
+            match state.buf.pop() {
+                Some(msg) => {
+                    drop(state);
+                    self.sender_wq.wake_one();
+                    Poll::Ready(msg)
+                }
+                None => {
+                    self.receiver_wq.register(cx.waker());
+                    Poll::Pending
+                }
+            }
+        })
+        .await
+    }
+
+    /// Send `msg` if there is room right now, gives it back otherwise (or if the queue is closed).
+    pub fn try_send(&self, msg: T) -> Result<(), T> {
+        let mut state = self.state.lock_irq();
+        if state.closed {
+            return Err(msg);
+        }
+        state.buf.push(msg)?;
+        drop(state);
+        self.receiver_wq.wake_one();
+        Ok(())
+    }
+
+    /// The oldest message, if there is one.
+    pub fn try_recv(&self) -> Option<T> {
+        let msg = self.state.lock_irq().buf.pop()?;
+        self.sender_wq.wake_one();
+        Some(msg)
+    }
+
+    /// Called by the receiving side when it stops receiving: queued messages are dropped and every send fails with
+    /// `IpcError::Closed` from now on (waiting senders included).
+    pub fn close(&self) {
+        let dropped = {
+            let mut state = self.state.lock_irq();
+            state.closed = true;
+            core::mem::take(&mut state.buf)
+        };
+        drop(dropped); // outside the lock, dropping a message may take locks of its own
+        self.sender_wq.wake_all();
+    }
+
+    /// Number of queued messages.
+    pub fn len(&self) -> usize {
+        self.state.lock_irq().buf.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T, const CAP: usize> Default for MessageQueue<T, CAP> {
+    fn default() -> Self {
+        MessageQueue::new()
+    }
+}
+
+// TESTS ===================================
+
+#[test_case]
+fn test_ring_buffer_wraps() {
+    let mut ring: RingBuffer<u32, 3> = RingBuffer::new();
+    for round in 0..4 {
+        assert_eq!(ring.push(round), Ok(()));
+        assert_eq!(ring.push(round + 10), Ok(()));
+        assert_eq!(ring.pop(), Some(round));
+        assert_eq!(ring.pop(), Some(round + 10));
+    }
+    assert!(ring.is_empty());
+    for value in 0..3 {
+        ring.push(value).unwrap();
+    }
+    assert!(ring.is_full());
+    assert_eq!(ring.push(3), Err(3));
+}
+
+#[test_case]
+fn test_try_send_and_close() {
+    let queue: MessageQueue<u32, 2> = MessageQueue::new();
+    assert_eq!(queue.try_recv(), None);
+    assert_eq!(queue.try_send(1), Ok(()));
+    assert_eq!(queue.try_send(2), Ok(()));
+    assert_eq!(queue.try_send(3), Err(3));
+    assert_eq!(queue.try_recv(), Some(1));
+    assert_eq!(queue.len(), 1);
+
+    queue.close();
+    assert!(queue.is_empty());
+    assert_eq!(queue.try_send(4), Err(4));
+}
+
+#[test_case]
+fn test_tasks_exchange_messages_in_order() {
+    use crate::task::{executor::Executor, Task};
+    use alloc::{rc::Rc, vec::Vec};
+    use core::cell::RefCell;
+
+    // much smaller than the number of messages --> both sides have to wait for each other
+    static QUEUE: MessageQueue<u32, 4> = MessageQueue::new();
+
+    let received = Rc::new(RefCell::new(Vec::new()));
+    let mut executor = Executor::new();
+    let task_received = received.clone();
+    let receiver = executor.spawn(Task::new(async move {
+        for _ in 0..100 {
+            let msg = QUEUE.recv().await;
+            task_received.borrow_mut().push(msg);
+        }
+    }));
+    let sender = executor.spawn(Task::new(async {
+        for msg in 0..100 {
+            QUEUE.send(msg).await.expect("queue closed");
+        }
+    }));
+
+    // every send wakes the receiver and every recv the sender --> no interrupt needed to finish
+    executor.run_until_idle();
+    assert!(sender.is_finished() && receiver.is_finished(), "a wake up got lost");
+    assert_eq!(*received.borrow(), (0..100).collect::<Vec<u32>>());
+    assert!(QUEUE.is_empty());
+}
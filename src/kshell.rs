@@ -0,0 +1,1097 @@
+// Kernel shell --> a prompt that runs the commands typed after it, as an async task (see main.rs)
+// - a shell reads keys from a ShellInput (the keyboard stream of task/keyboard.rs, or the bytes received on the serial
+//   port) and writes to the ConsoleOutput it is bound to (the screen, or the serial port) --> two shells run at once,
+//   with the same commands but a line and a prompt each
+// - a line is read with readline(), which lets it be edited while it is typed (see LINE EDITING), enter runs it
+// - the line is split on whitespace: the first word picks a registered command (see COMMANDS), the rest are its arguments
+// - commands print with print!, which goes to the shell's output while they run (see console::with_output())
+// - every shell keeps the last HISTORY_LEN lines it ran, up/down bring them back (see HISTORY)
+// - lines are capped at MAX_LINE characters or the width of the screen, anything typed past that is dropped with a
+//   warning (the line still runs)
+// - it isn't started in test builds: the harness owns the screen, and a shell waiting for input would never let it finish
+use crate::console::{self, ConsoleOutput};
+use crate::error::UnifiedError;
+use crate::serial::{rx_stream, RxStream};
+use crate::task::keyboard::{key_stream, KeyStream};
+use crate::{print, println};
+use alloc::{collections::VecDeque, string::String, vec::Vec};
+use core::fmt;
+use core::future::Future;
+use pc_keyboard::{DecodedKey, KeyCode};
+use spin::{Mutex, Once};
+
+/// Longest line in characters.
+pub const MAX_LINE: usize = 256;
+/// Printed before every line.
+pub const PROMPT: &str = "kshell> ";
+/// Printed before every line of the shell on the serial port.
+pub const SERIAL_PROMPT: &str = "kshell(serial)> ";
+/// Lines a shell remembers.
+pub const HISTORY_LEN: usize = 32;
+
+// COMMANDS ====================================
+// every subsystem registers its own commands with register_command() (ex. memory.rs registers `meminfo`), the shell
+// only knows the built-in ones below --> the registry is a Vec on the heap, kept sorted by name for `help`
+// handlers run without the registry locked, so a command may register others
+
+/// Why a command failed (or couldn't be registered), printed by the shell after the command's name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShellError {
+    /// No command has that name.
+    UnknownCommand,
+    /// A command with that name is registered already.
+    AlreadyRegistered,
+    /// The arguments don't fit the command, with how it's used (ex. "meminfo [-v]").
+    Usage(&'static str),
+    /// An argument has the wrong format (ex. not a number).
+    BadArgument,
+    /// The arguments are fine but the command won't do it, with why (ex. "address not mapped").
+    Refused(&'static str),
+    /// The command failed with a kernel error.
+    Failed(UnifiedError),
+}
+
+impl From<UnifiedError> for ShellError {
+    fn from(error: UnifiedError) -> Self {
+        ShellError::Failed(error)
+    }
+}
+
+/// A command: gets the words of the line after its name.
+pub type CommandHandler = fn(&[&str]) -> Result<(), ShellError>;
+
+#[derive(Clone, Copy)]
+struct Command {
+    name: &'static str,
+    help: &'static str,
+    handler: CommandHandler,
+}
+
+static COMMANDS: Mutex<Vec<Command>> = Mutex::new(Vec::new());
+
+/// Make `name` run `handler`, `help` is what the `help` command says about it. Needs the heap.
+pub fn register_command(name: &'static str, help: &'static str, handler: CommandHandler) -> Result<(), ShellError> {
+    let mut commands = COMMANDS.lock();
+    match commands.binary_search_by(|command| command.name.cmp(name)) {
+        Ok(_) => Err(ShellError::AlreadyRegistered),
+        Err(index) => {
+            commands.insert(index, Command { name, help, handler });
+            Ok(())
+        }
+    }
+}
+
+/// The registered commands with their help, sorted by name.
+pub fn commands() -> Vec<(&'static str, &'static str)> {
+    COMMANDS.lock().iter().map(|command| (command.name, command.help)).collect()
+}
+
+fn find_command(name: &str) -> Option<CommandHandler> {
+    let commands = COMMANDS.lock();
+    let index = commands.binary_search_by(|command| command.name.cmp(name)).ok()?;
+    Some(commands[index].handler)
+}
+
+// BUILT-IN COMMANDS ====================================
+
+static BUILTINS: Once<()> = Once::new();
+
+/// Register the shell's own commands (help, echo, clear, color, banner, exit, reboot, history). Needs the heap, only the
+/// first call does anything.
+pub fn init() {
+    BUILTINS.call_once(|| {
+        let builtins: [(&'static str, &'static str, CommandHandler); 8] = [
+            ("help", "list the commands", help_command),
+            ("echo", "print the arguments: echo [-n] <text> (\\n and \\t work)", echo_command),
+            ("clear", "clear the screen", clear_command),
+            ("color", "set the text colors: color <fg> <bg>", color_command),
+            ("banner", "draw a centered banner: banner <text>", banner_command),
+            ("exit", "power the machine off", exit_command),
+            ("reboot", "restart the machine", reboot_command),
+            ("history", "list the lines this shell ran", history_command),
+        ];
+        for (name, help, handler) in builtins {
+            register_command(name, help, handler).expect("built-in command registered twice");
+        }
+    });
+}
+
+fn help_command(_args: &[&str]) -> Result<(), ShellError> {
+    for (name, help) in commands() {
+        println!("  {:10} {}", name, help);
+    }
+    Ok(())
+}
+
+// writes `arg` with its escapes (\n, \t, \\) replaced, an unknown escape stays as it is
+fn write_unescaped(out: &mut impl fmt::Write, arg: &str) -> fmt::Result {
+    let mut chars = arg.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.write_char(c)?;
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.write_char('\n')?,
+            Some('t') => out.write_char('\t')?,
+            Some('\\') => out.write_char('\\')?,
+            Some(other) => write!(out, "\\{}", other)?,
+            None => out.write_char('\\')?,
+        }
+    }
+    Ok(())
+}
+
+fn echo_command(args: &[&str]) -> Result<(), ShellError> {
+    let (args, newline) = match args {
+        ["-n", rest @ ..] => (rest, false),
+        _ => (args, true),
+    };
+    for (i, arg) in args.iter().enumerate() {
+        if i > 0 {
+            print!(" ");
+        }
+        write_unescaped(&mut Screen, arg).expect("printing failed");
+    }
+    if newline {
+        println!();
+    }
+    Ok(())
+}
+
+// the console puts the cursor at the start of the text area and the line editor starts over at the next prompt, there
+// is no other state to reset
+fn clear_command(_args: &[&str]) -> Result<(), ShellError> {
+    crate::console::clear_screen();
+    Ok(())
+}
+
+fn color_command(args: &[&str]) -> Result<(), ShellError> {
+    use crate::vga_buffer::Color;
+
+    let (foreground, background) = match args {
+        [foreground, background] => (Color::from_name(foreground), Color::from_name(background)),
+        _ => return Err(ShellError::Usage("color <fg> <bg>")),
+    };
+    match (foreground, background) {
+        (Some(foreground), Some(background)) => {
+            crate::console::set_colors(foreground, background);
+            Ok(())
+        }
+        _ => {
+            print!("colors:");
+            for color in Color::ALL {
+                print!(" {}", color.name());
+            }
+            println!();
+            Err(ShellError::BadArgument)
+        }
+    }
+}
+
+// the banner for `text` in a `width` column screen: the text in a box, centered
+fn write_banner(out: &mut impl fmt::Write, text: &str, width: usize) -> fmt::Result {
+    // the box takes 4 columns: "| " and " |"
+    let inner = width.saturating_sub(4);
+    let text = match text.char_indices().nth(inner) {
+        Some((end, _)) => &text[..end],
+        None => text,
+    };
+    let len = text.chars().count();
+    let indent = (width.saturating_sub(len + 4)) / 2;
+    let border = |out: &mut dyn fmt::Write| -> fmt::Result {
+        write!(out, "{:indent$}+", "")?;
+        for _ in 0..len + 2 {
+            out.write_char('-')?;
+        }
+        out.write_str("+\n")
+    };
+    border(out)?;
+    writeln!(out, "{:indent$}| {} |", "", text)?;
+    border(out)
+}
+
+fn banner_command(args: &[&str]) -> Result<(), ShellError> {
+    if args.is_empty() {
+        return Err(ShellError::Usage("banner <text>"));
+    }
+    let text = args.join(" ");
+    // one column less than the screen: a line as wide as the screen wraps into an empty row
+    let width = crate::console::columns().unwrap_or(80) - 1;
+    write_banner(&mut Screen, &text, width).expect("printing failed");
+    Ok(())
+}
+
+fn exit_command(_args: &[&str]) -> Result<(), ShellError> {
+    #[cfg(test)]
+    crate::exit_qemu(crate::QemuExitCode::Success);
+    crate::power::shutdown();
+}
+
+// the serial console had it (see serial.rs), the shell on the serial port takes its place
+fn reboot_command(_args: &[&str]) -> Result<(), ShellError> {
+    crate::power::reboot();
+}
+
+// every shell answers `history` itself (see Shell::run_line()), it is registered for `help` --> only reached by lines run
+// outside of a shell
+fn history_command(_args: &[&str]) -> Result<(), ShellError> {
+    Err(ShellError::Refused("no shell runs this line"))
+}
+
+// the most words of a line that are looked at, the rest is ignored
+const MAX_ARGS: usize = 16;
+
+/// Run the command `line` (an empty line is fine), its error is printed and returned.
+pub fn run_line(line: &str) -> Result<(), ShellError> {
+    let mut words = [""; MAX_ARGS];
+    let mut count = 0;
+    for (slot, word) in words.iter_mut().zip(line.split_whitespace()) {
+        *slot = word;
+        count += 1;
+    }
+    let (name, args) = match words[..count].split_first() {
+        Some(split) => split,
+        None => return Ok(()),
+    };
+    let result = match find_command(name) {
+        Some(handler) => handler(args),
+        None => Err(ShellError::UnknownCommand),
+    };
+    if let Err(error) = result {
+        println!("{}: {}", name, error);
+    }
+    result
+}
+
+// LINE EDITING ====================================
+// - only printable ASCII goes into the line --> a byte is a column on screen, so the cursor is moved by printing '\x08'
+//   (one column back, see vga_buffer.rs) or the characters in front of it (forward)
+// - an edit in the middle of the line prints the rest of the line again, followed by a blank for every character that
+//   got removed, and goes back to the cursor
+// - the line never wraps: it is capped at the columns left after the prompt (and MAX_LINE), as '\x08' can't move back
+//   into the previous row --> the prompt has to start at the beginning of a row
+// - enter finishes the line, Ctrl+C abandons it, Ctrl+U kills it, backspace/delete remove the character before/under
+//   the cursor, arrow left/right, home and end move the cursor
+// - up/down replace the line with an older/newer one of the history (see feed_with_history()), below the newest one is
+//   what was typed before the first up
+
+const BACKSPACE: char = '\u{8}';
+const DELETE: char = '\u{7f}';
+const CTRL_C: char = '\u{3}';
+const CTRL_U: char = '\u{15}';
+
+/// What `LineEditor::feed()` made of a key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EditResult {
+    /// The line isn't finished yet.
+    Continue,
+    /// Enter was pressed, see `LineEditor::line()`.
+    Done,
+    /// Ctrl+C was pressed, the line is empty.
+    Cancelled,
+}
+
+/// The line being typed after a prompt, edited in place on the screen.
+pub struct LineEditor<'a> {
+    prompt: &'a str,
+    bytes: [u8; MAX_LINE],
+    len: usize,
+    cursor: usize,
+    limit: usize,
+    overflowed: bool,
+    // the history line shown (0 is the newest), `None` while the typed line is
+    recalled: Option<usize>,
+    draft: [u8; MAX_LINE],
+    draft_len: usize,
+}
+
+impl<'a> LineEditor<'a> {
+    /// An empty line after `prompt` on a screen `columns` wide (see `console::columns()`, `None` if it doesn't wrap).
+    pub fn new(prompt: &'a str, columns: Option<usize>) -> Self {
+        let limit = columns.map_or(MAX_LINE, |columns| columns.saturating_sub(prompt.len()).min(MAX_LINE));
+        LineEditor {
+            prompt,
+            bytes: [0; MAX_LINE],
+            len: 0,
+            cursor: 0,
+            limit,
+            overflowed: false,
+            recalled: None,
+            draft: [0; MAX_LINE],
+            draft_len: 0,
+        }
+    }
+
+    /// The line so far.
+    pub fn line(&self) -> &str {
+        // only printable ASCII is ever added
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap_or("")
+    }
+
+    /// The position of the cursor in the line.
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    /// The most characters the line takes.
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Apply one key to the line and write what changes on screen to `out`.
+    pub fn feed(&mut self, key: DecodedKey, out: &mut impl fmt::Write) -> Result<EditResult, fmt::Error> {
+        match key {
+            DecodedKey::Unicode('\n') | DecodedKey::Unicode('\r') => {
+                out.write_char('\n')?;
+                return Ok(EditResult::Done);
+            }
+            DecodedKey::Unicode(CTRL_C) => {
+                out.write_str("^C\n")?;
+                self.len = 0;
+                self.cursor = 0;
+                return Ok(EditResult::Cancelled);
+            }
+            DecodedKey::Unicode(CTRL_U) => {
+                self.move_left(self.cursor, out)?;
+                let erased = self.len;
+                self.len = 0;
+                self.redraw_tail(erased, out)?;
+            }
+            DecodedKey::Unicode(BACKSPACE) => {
+                if self.cursor > 0 {
+                    self.move_left(1, out)?;
+                    self.remove(out)?;
+                }
+            }
+            DecodedKey::Unicode(DELETE) => self.remove(out)?,
+            DecodedKey::Unicode(character) if character.is_ascii() && !character.is_ascii_control() => {
+                self.insert(character as u8, out)?
+            }
+            DecodedKey::RawKey(KeyCode::ArrowLeft) => self.move_left(1, out)?,
+            DecodedKey::RawKey(KeyCode::ArrowRight) => self.move_right(1, out)?,
+            DecodedKey::RawKey(KeyCode::Home) => self.move_left(self.cursor, out)?,
+            DecodedKey::RawKey(KeyCode::End) => self.move_right(self.len - self.cursor, out)?,
+            _ => {}
+        }
+        Ok(EditResult::Continue)
+    }
+
+    /// Apply one key like `feed()`, up and down bring back the lines of `history`.
+    ///
+    /// The line brought back is a copy --> editing it changes nothing in `history`.
+    pub fn feed_with_history(
+        &mut self,
+        key: DecodedKey,
+        history: &History,
+        out: &mut impl fmt::Write,
+    ) -> Result<EditResult, fmt::Error> {
+        match key {
+            DecodedKey::RawKey(KeyCode::ArrowUp) => {
+                let age = self.recalled.map_or(0, |age| age + 1);
+                if let Some(line) = history.get(age) {
+                    if self.recalled.is_none() {
+                        self.draft[..self.len].copy_from_slice(&self.bytes[..self.len]);
+                        self.draft_len = self.len;
+                    }
+                    self.recalled = Some(age);
+                    self.replace(line.as_bytes(), out)?;
+                }
+            }
+            DecodedKey::RawKey(KeyCode::ArrowDown) => match self.recalled {
+                None => {}
+                Some(0) => {
+                    self.recalled = None;
+                    let draft = self.draft;
+                    self.replace(&draft[..self.draft_len], out)?;
+                }
+                Some(age) => {
+                    self.recalled = Some(age - 1);
+                    self.replace(history.get(age - 1).unwrap_or("").as_bytes(), out)?;
+                }
+            },
+            _ => return self.feed(key, out),
+        }
+        Ok(EditResult::Continue)
+    }
+
+    // show `line` (cut to the limit) instead of the current one, with the cursor at its end
+    fn replace(&mut self, line: &[u8], out: &mut impl fmt::Write) -> fmt::Result {
+        self.move_left(self.cursor, out)?;
+        let erased = self.len;
+        self.len = line.len().min(self.limit);
+        self.bytes[..self.len].copy_from_slice(&line[..self.len]);
+        self.cursor = self.len;
+        out.write_str(self.line())?;
+        let blanks = erased.saturating_sub(self.len);
+        for _ in 0..blanks {
+            out.write_char(' ')?;
+        }
+        self.back(blanks, out)
+    }
+
+    fn insert(&mut self, byte: u8, out: &mut impl fmt::Write) -> fmt::Result {
+        if self.len == self.limit {
+            if self.overflowed {
+                return Ok(());
+            }
+            // warn once, below the line, and show the line again under the warning
+            self.overflowed = true;
+            writeln!(out, "\nwarning: line longer than {} characters, the rest is discarded", self.limit)?;
+            write!(out, "{}{}", self.prompt, self.line())?;
+            return self.back(self.len - self.cursor, out);
+        }
+        self.bytes.copy_within(self.cursor..self.len, self.cursor + 1);
+        self.bytes[self.cursor] = byte;
+        self.len += 1;
+        self.cursor += 1;
+        out.write_char(char::from(byte))?;
+        self.redraw_tail(0, out)
+    }
+
+    // remove the character under the cursor
+    fn remove(&mut self, out: &mut impl fmt::Write) -> fmt::Result {
+        if self.cursor == self.len {
+            return Ok(());
+        }
+        self.bytes.copy_within(self.cursor + 1..self.len, self.cursor);
+        self.len -= 1;
+        self.redraw_tail(1, out)
+    }
+
+    // print the line from the cursor on and `erased` blanks after it, then go back to the cursor
+    fn redraw_tail(&self, erased: usize, out: &mut impl fmt::Write) -> fmt::Result {
+        out.write_str(&self.line()[self.cursor..])?;
+        for _ in 0..erased {
+            out.write_char(' ')?;
+        }
+        self.back(self.len - self.cursor + erased, out)
+    }
+
+    fn move_left(&mut self, columns: usize, out: &mut impl fmt::Write) -> fmt::Result {
+        let columns = columns.min(self.cursor);
+        self.cursor -= columns;
+        self.back(columns, out)
+    }
+
+    fn move_right(&mut self, columns: usize, out: &mut impl fmt::Write) -> fmt::Result {
+        let end = (self.cursor + columns).min(self.len);
+        out.write_str(&self.line()[self.cursor..end])?;
+        self.cursor = end;
+        Ok(())
+    }
+
+    fn back(&self, columns: usize, out: &mut impl fmt::Write) -> fmt::Result {
+        for _ in 0..columns {
+            out.write_char(BACKSPACE)?;
+        }
+        Ok(())
+    }
+}
+
+// the editor's output, on the console
+struct Screen;
+
+impl fmt::Write for Screen {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        print!("{}", s);
+        Ok(())
+    }
+}
+
+// the editor's output, on the output of a shell
+struct Bound(&'static dyn ConsoleOutput);
+
+impl fmt::Write for Bound {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.write_str(s);
+        Ok(())
+    }
+}
+
+// HISTORY ====================================
+// the last HISTORY_LEN lines a shell ran, on the heap --> every shell has its own
+// - an empty line isn't kept, neither is the same line twice in a row
+// - the oldest line goes once the history is full
+
+/// The lines a shell ran, the newest last.
+#[derive(Debug, Default)]
+pub struct History {
+    lines: VecDeque<String>,
+}
+
+impl History {
+    /// An empty history.
+    pub const fn new() -> Self {
+        History { lines: VecDeque::new() }
+    }
+
+    /// Remember `line` as the newest one (unless it is empty or the newest one already).
+    pub fn add(&mut self, line: &str) {
+        if line.trim().is_empty() || self.lines.back().map(String::as_str) == Some(line) {
+            return;
+        }
+        if self.lines.len() == HISTORY_LEN {
+            self.lines.pop_front();
+        }
+        self.lines.push_back(String::from(line));
+    }
+
+    /// The line `age` lines back, 0 is the newest one.
+    pub fn get(&self, age: usize) -> Option<&str> {
+        let index = self.lines.len().checked_sub(age + 1)?;
+        Some(&self.lines[index])
+    }
+
+    /// The lines, the oldest first.
+    pub fn iter(&self) -> impl Iterator<Item = &str> {
+        self.lines.iter().map(String::as_str)
+    }
+
+    /// Number of lines remembered.
+    pub fn len(&self) -> usize {
+        self.lines.len()
+    }
+
+    /// No line has been remembered yet.
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+}
+
+// INPUT ====================================
+// a terminal on the serial port sends bytes, not keys --> TerminalDecoder makes the keys the line editor knows of them
+// - enter is CR, LF or CR LF depending on the terminal: each of them ends a line, the LF of a CR LF is dropped
+// - backspace is DEL (0x7f) on most terminals, BS on some
+// - the arrows, home, end and delete are escape sequences: ESC '[' [number] letter/'~'
+
+/// Where a shell gets its keys from.
+pub trait ShellInput {
+    /// The next key, waits until there is one.
+    fn next_key(&mut self) -> impl Future<Output = DecodedKey>;
+}
+
+impl ShellInput for KeyStream {
+    async fn next_key(&mut self) -> DecodedKey {
+        KeyStream::next_key(self).await
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EscapeState {
+    None,
+    Escape,
+    // inside "ESC [", with the number so far
+    Sequence(u8),
+}
+
+/// Makes keys of the bytes a terminal sends.
+#[derive(Debug)]
+pub struct TerminalDecoder {
+    state: EscapeState,
+    after_cr: bool,
+}
+
+impl TerminalDecoder {
+    /// A decoder that hasn't seen any bytes yet.
+    pub const fn new() -> Self {
+        TerminalDecoder { state: EscapeState::None, after_cr: false }
+    }
+
+    /// The key `byte` finishes, `None` if it is part of one that isn't finished yet (or of nothing).
+    pub fn feed(&mut self, byte: u8) -> Option<DecodedKey> {
+        let after_cr = core::mem::replace(&mut self.after_cr, byte == b'\r');
+        match self.state {
+            EscapeState::None => {}
+            EscapeState::Escape => {
+                self.state = if byte == b'[' { EscapeState::Sequence(0) } else { EscapeState::None };
+                return None;
+            }
+            EscapeState::Sequence(number) => {
+                if byte.is_ascii_digit() {
+                    self.state = EscapeState::Sequence(number.saturating_mul(10).saturating_add(byte - b'0'));
+                    return None;
+                }
+                if byte == b';' {
+                    // another number follows, only the last one is kept
+                    self.state = EscapeState::Sequence(0);
+                    return None;
+                }
+                self.state = EscapeState::None;
+                return match (byte, number) {
+                    (b'A', _) => Some(DecodedKey::RawKey(KeyCode::ArrowUp)),
+                    (b'B', _) => Some(DecodedKey::RawKey(KeyCode::ArrowDown)),
+                    (b'C', _) => Some(DecodedKey::RawKey(KeyCode::ArrowRight)),
+                    (b'D', _) => Some(DecodedKey::RawKey(KeyCode::ArrowLeft)),
+                    (b'H', _) | (b'~', 1) | (b'~', 7) => Some(DecodedKey::RawKey(KeyCode::Home)),
+                    (b'F', _) | (b'~', 4) | (b'~', 8) => Some(DecodedKey::RawKey(KeyCode::End)),
+                    (b'~', 3) => Some(DecodedKey::Unicode(DELETE)),
+                    _ => None,
+                };
+            }
+        }
+        match byte {
+            0x1b => {
+                self.state = EscapeState::Escape;
+                None
+            }
+            b'\n' if after_cr => None,
+            b'\r' | b'\n' => Some(DecodedKey::Unicode('\n')),
+            0x08 | 0x7f => Some(DecodedKey::Unicode(BACKSPACE)),
+            _ => Some(DecodedKey::Unicode(char::from(byte))),
+        }
+    }
+}
+
+impl Default for TerminalDecoder {
+    fn default() -> Self {
+        TerminalDecoder::new()
+    }
+}
+
+/// The keys typed on the terminal at the other end of the serial port.
+#[derive(Debug)]
+pub struct SerialInput {
+    bytes: RxStream,
+    decoder: TerminalDecoder,
+}
+
+impl SerialInput {
+    /// The keys in `bytes`.
+    pub fn new(bytes: RxStream) -> Self {
+        SerialInput { bytes, decoder: TerminalDecoder::new() }
+    }
+}
+
+impl ShellInput for SerialInput {
+    async fn next_key(&mut self) -> DecodedKey {
+        loop {
+            if let Some(key) = self.decoder.feed(self.bytes.next_byte().await) {
+                return key;
+            }
+        }
+    }
+}
+
+// SHELL ====================================
+
+/// Write `prompt` to `output` and read a line from `input`, edited as it is typed (see LINE EDITING) with the lines of
+/// `history` a key away. Ctrl+C gives an empty line.
+pub async fn readline(
+    input: &mut impl ShellInput,
+    output: &'static dyn ConsoleOutput,
+    prompt: &str,
+    history: &History,
+) -> String {
+    let mut editor = LineEditor::new(prompt, output.columns());
+    output.write_str(prompt);
+    loop {
+        let key = input.next_key().await;
+        match editor.feed_with_history(key, history, &mut Bound(output)) {
+            Ok(EditResult::Continue) => {}
+            Ok(EditResult::Done) => return String::from(editor.line()),
+            Ok(EditResult::Cancelled) | Err(_) => return String::new(),
+        }
+    }
+}
+
+/// A shell: where its keys come from, where it writes, its prompt and its history. All shells run the same commands.
+pub struct Shell<I> {
+    input: I,
+    output: &'static dyn ConsoleOutput,
+    prompt: &'static str,
+    history: History,
+}
+
+impl<I: ShellInput> Shell<I> {
+    /// A shell reading keys from `input`, writing `prompt`, the line and what its commands print to `output`.
+    pub fn new(input: I, output: &'static dyn ConsoleOutput, prompt: &'static str) -> Self {
+        Shell { input, output, prompt, history: History::new() }
+    }
+
+    /// Run the command `line` with its output (and error) on this shell's output.
+    ///
+    /// Only the command runs bound to it, never a wait for keys --> the other shells' commands print on their own outputs.
+    /// `history` is answered here, it lists this shell's history.
+    pub fn run_line(&self, line: &str) -> Result<(), ShellError> {
+        console::with_output(self.output, || {
+            let mut words = line.split_whitespace();
+            if words.next() != Some("history") {
+                return run_line(line);
+            }
+            if words.next().is_some() {
+                let error = ShellError::Usage("history");
+                println!("history: {}", error);
+                return Err(error);
+            }
+            for (number, line) in self.history.iter().enumerate() {
+                println!("  {:3}  {}", number + 1, line);
+            }
+            Ok(())
+        })
+    }
+
+    /// The lines this shell ran.
+    pub fn history(&self) -> &History {
+        &self.history
+    }
+
+    /// Prompt, read a line, remember it, run it, forever.
+    pub async fn run(mut self) {
+        self.output.write_str("kshell: type `help` for the commands\n");
+        loop {
+            let line = readline(&mut self.input, self.output, self.prompt, &self.history).await;
+            self.history.add(&line);
+            let _ = self.run_line(&line); // the error is printed already
+        }
+    }
+}
+
+/// The shell task on the screen and the keyboard. Returns right away if another task reads the keyboard.
+pub async fn run() {
+    let keys = match key_stream() {
+        Some(keys) => keys,
+        None => {
+            println!("kshell: the keyboard is already in use");
+            return;
+        }
+    };
+    init();
+    Shell::new(keys, &console::CurrentDisplay, PROMPT).run().await
+}
+
+/// The shell task on the serial port (COM1, see `serial::init_console()`). Returns right away if another task reads it.
+pub async fn run_serial() {
+    let bytes = match rx_stream() {
+        Some(bytes) => bytes,
+        None => {
+            println!("kshell: the serial port is already in use");
+            return;
+        }
+    };
+    init();
+    Shell::new(SerialInput::new(bytes), &crate::serial::SerialOutput, SERIAL_PROMPT).run().await
+}
+
+// TESTS ===================================
+
+// feed `keys` to `editor`, returns the last result and what was written on screen
+#[cfg(test)]
+fn feed_keys(editor: &mut LineEditor, keys: &[DecodedKey]) -> (EditResult, String) {
+    let mut out = String::new();
+    let mut result = EditResult::Continue;
+    for &key in keys {
+        result = editor.feed(key, &mut out).expect("writing into a string failed");
+    }
+    (result, out)
+}
+
+#[cfg(test)]
+fn typed(text: &str) -> alloc::vec::Vec<DecodedKey> {
+    text.chars().map(DecodedKey::Unicode).collect()
+}
+
+#[test_case]
+fn test_editor_backspace_at_end() {
+    let mut editor = LineEditor::new(PROMPT, None);
+    feed_keys(&mut editor, &typed("abc\u{8}\u{8}d"));
+    assert_eq!(editor.line(), "ad");
+    let (result, out) = feed_keys(&mut editor, &typed("\n"));
+    assert_eq!(result, EditResult::Done);
+    assert_eq!(out, "\n");
+    assert_eq!(editor.line(), "ad");
+}
+
+#[test_case]
+fn test_editor_backspace_in_middle() {
+    let mut editor = LineEditor::new(PROMPT, None);
+    let (_, out) = feed_keys(&mut editor, &typed("abc"));
+    assert_eq!(out, "abc");
+    let (_, out) = feed_keys(&mut editor, &[DecodedKey::RawKey(KeyCode::ArrowLeft), DecodedKey::Unicode(BACKSPACE)]);
+    assert_eq!(editor.line(), "ac");
+    assert_eq!(editor.cursor(), 1);
+    // back over 'c' and the 'b', then "c" moves left and a blank erases the old 'c'
+    assert_eq!(out, "\u{8}\u{8}c \u{8}\u{8}");
+    feed_keys(&mut editor, &typed("XY"));
+    assert_eq!(editor.line(), "aXYc");
+    feed_keys(&mut editor, &[DecodedKey::Unicode(DELETE), DecodedKey::RawKey(KeyCode::End)]);
+    assert_eq!(editor.line(), "aXY");
+    assert_eq!(editor.cursor(), 3);
+}
+
+#[test_case]
+fn test_editor_backspace_at_start() {
+    let mut editor = LineEditor::new(PROMPT, None);
+    let (_, out) = feed_keys(&mut editor, &typed("\u{8}"));
+    assert_eq!((editor.line(), out.as_str()), ("", ""));
+    feed_keys(&mut editor, &typed("ab"));
+    let (_, out) = feed_keys(&mut editor, &[DecodedKey::RawKey(KeyCode::Home), DecodedKey::Unicode(BACKSPACE)]);
+    assert_eq!(out, "\u{8}\u{8}", "backspace at the start changed the screen");
+    feed_keys(&mut editor, &typed("x\u{8}\u{8}"));
+    assert_eq!(editor.line(), "ab");
+    assert_eq!(editor.cursor(), 0);
+}
+
+#[test_case]
+fn test_editor_kill_and_cancel() {
+    let mut editor = LineEditor::new(PROMPT, None);
+    feed_keys(&mut editor, &typed("abc"));
+    feed_keys(&mut editor, &[DecodedKey::RawKey(KeyCode::ArrowLeft), DecodedKey::Unicode(CTRL_U)]);
+    assert_eq!((editor.line(), editor.cursor()), ("", 0));
+    let (result, _) = feed_keys(&mut editor, &typed("echo hi\n"));
+    assert_eq!((result, editor.line()), (EditResult::Done, "echo hi"));
+
+    let mut editor = LineEditor::new(PROMPT, None);
+    let (result, _) = feed_keys(&mut editor, &typed("exit\u{3}"));
+    assert_eq!((result, editor.line()), (EditResult::Cancelled, ""));
+}
+
+#[test_case]
+fn test_editor_caps_at_columns() {
+    assert_eq!(LineEditor::new(PROMPT, None).limit(), MAX_LINE);
+    let mut editor = LineEditor::new(PROMPT, Some(20));
+    assert_eq!(editor.limit(), 20 - PROMPT.len());
+    let (_, out) = feed_keys(&mut editor, &typed("0123456789abcdef"));
+    assert_eq!(editor.line(), "0123456789ab");
+    assert_eq!(out.matches("warning").count(), 1, "not warned exactly once");
+    // the line is shown again after the warning
+    assert!(out.ends_with("kshell> 0123456789ab"));
+    // there is room again after a backspace
+    feed_keys(&mut editor, &typed("\u{8}Z"));
+    assert_eq!(editor.line(), "0123456789aZ");
+}
+
+#[test_case]
+fn test_run_line() {
+    init();
+    assert_eq!(run_line(""), Ok(()));
+    assert_eq!(run_line("   \t "), Ok(()));
+    assert_eq!(run_line("echo  hello   kernel"), Ok(()));
+    assert_eq!(run_line("help"), Ok(()));
+    assert_eq!(run_line("definitely-not-a-command with args"), Err(ShellError::UnknownCommand));
+    assert!(commands().iter().any(|(name, _)| *name == "exit"));
+}
+
+#[test_case]
+fn test_register_command() {
+    use alloc::string::ToString;
+
+    static ARGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+    fn record_args(args: &[&str]) -> Result<(), ShellError> {
+        *ARGS.lock() = args.iter().map(|arg| arg.to_string()).collect();
+        match args.first() {
+            Some(&"fail") => Err(ShellError::BadArgument),
+            _ => Ok(()),
+        }
+    }
+
+    init();
+    register_command("test-record", "remember the arguments", record_args).expect("name taken");
+    assert_eq!(run_line("  test-record one   two "), Ok(()));
+    assert_eq!(*ARGS.lock(), ["one", "two"]);
+    assert_eq!(run_line("test-record fail"), Err(ShellError::BadArgument));
+    assert_eq!(run_line("test-record"), Ok(()));
+    assert!(ARGS.lock().is_empty());
+
+    assert_eq!(register_command("test-record", "again", record_args), Err(ShellError::AlreadyRegistered));
+    assert_eq!(register_command("echo", "not the built-in", record_args), Err(ShellError::AlreadyRegistered));
+    // help lists them sorted by name
+    let names: Vec<&str> = commands().iter().map(|(name, _)| *name).collect();
+    assert!(names.windows(2).all(|pair| pair[0] < pair[1]), "not sorted: {:?}", names);
+    assert!(commands().contains(&("test-record", "remember the arguments")));
+}
+
+#[test_case]
+fn test_echo_escapes() {
+    use crate::console::capture_output;
+
+    let unescape = |arg: &str| {
+        let mut out = String::new();
+        write_unescaped(&mut out, arg).unwrap();
+        out
+    };
+    assert_eq!(unescape("plain"), "plain");
+    assert_eq!(unescape("a\\nb\\tc"), "a\nb\tc");
+    assert_eq!(unescape("back\\\\slash"), "back\\slash");
+    assert_eq!(unescape("\\q stays"), "\\q stays");
+    assert_eq!(unescape("trailing\\"), "trailing\\");
+
+    let mut result = Err(ShellError::BadArgument);
+    let output = capture_output(|| result = echo_command(&["one\\ttwo", "three"]));
+    assert_eq!((result, output.as_str()), (Ok(()), "one\ttwo three\n"));
+    let output = capture_output(|| result = echo_command(&["-n", "no", "newline"]));
+    assert_eq!((result, output.as_str()), (Ok(()), "no newline"));
+    let output = capture_output(|| result = echo_command(&["-n"]));
+    assert_eq!((result, output.as_str()), (Ok(()), ""));
+}
+
+#[test_case]
+fn test_banner_and_color() {
+    let mut out = String::new();
+    write_banner(&mut out, "hi", 20).unwrap();
+    assert_eq!(out, "       +----+\n       | hi |\n       +----+\n");
+    // cut to fit the screen
+    out.clear();
+    write_banner(&mut out, "abcdefgh", 10).unwrap();
+    assert_eq!(out, "+--------+\n| abcdef |\n+--------+\n");
+
+    assert_eq!(banner_command(&[]), Err(ShellError::Usage("banner <text>")));
+    assert_eq!(color_command(&["yellow"]), Err(ShellError::Usage("color <fg> <bg>")));
+    let output = crate::console::capture_output(|| assert_eq!(color_command(&["yelow", "black"]), Err(ShellError::BadArgument)));
+    assert!(output.contains("light-gray"), "no list of colors in {:?}", output);
+    assert_eq!(color_command(&["yellow", "black"]), Ok(())); // what print! starts with
+}
+
+#[test_case]
+fn test_terminal_decoder() {
+    let decode = |bytes: &[u8]| -> Vec<DecodedKey> {
+        let mut decoder = TerminalDecoder::new();
+        bytes.iter().filter_map(|&byte| decoder.feed(byte)).collect()
+    };
+    let enter = DecodedKey::Unicode('\n');
+    assert_eq!(decode(b"ls\r"), [DecodedKey::Unicode('l'), DecodedKey::Unicode('s'), enter]);
+    assert_eq!(decode(b"\r\n"), [enter]);
+    assert_eq!(decode(b"\n\n"), [enter, enter]);
+    assert_eq!(decode(b"\r\r\n"), [enter, enter]);
+    assert_eq!(decode(b"\x7f\x08"), [DecodedKey::Unicode(BACKSPACE); 2]);
+    assert_eq!(
+        decode(b"\x1b[A\x1b[B\x1b[D\x1b[C\x1b[H\x1b[4~\x1b[3~"),
+        [
+            DecodedKey::RawKey(KeyCode::ArrowUp),
+            DecodedKey::RawKey(KeyCode::ArrowDown),
+            DecodedKey::RawKey(KeyCode::ArrowLeft),
+            DecodedKey::RawKey(KeyCode::ArrowRight),
+            DecodedKey::RawKey(KeyCode::Home),
+            DecodedKey::RawKey(KeyCode::End),
+            DecodedKey::Unicode(DELETE),
+        ]
+    );
+    // an unknown sequence is dropped as a whole
+    assert_eq!(decode(b"\x1b[15;2Rx"), [DecodedKey::Unicode('x')]);
+}
+
+// the keys of a script, then nothing (forever)
+#[cfg(test)]
+struct Scripted(VecDeque<DecodedKey>);
+
+#[cfg(test)]
+impl ShellInput for Scripted {
+    async fn next_key(&mut self) -> DecodedKey {
+        match self.0.pop_front() {
+            Some(key) => key,
+            None => core::future::pending().await,
+        }
+    }
+}
+
+// what a shell with the prompt "test> " writes for `keys`, after it ran out of them
+#[cfg(test)]
+fn run_script(keys: VecDeque<DecodedKey>) -> String {
+    use crate::task::{executor::Executor, Task};
+
+    init();
+    console::capture_writes(|output| {
+        let mut executor = Executor::new();
+        executor.spawn(Task::new(Shell::new(Scripted(keys), output, "test> ").run()));
+        executor.run_until_idle();
+    })
+}
+
+#[test_case]
+fn test_shell_on_its_own_output() {
+    let mut decoder = TerminalDecoder::new();
+    let keys = b"echo over serial\r\nnope\rech\x7fho x\n".iter().filter_map(|&byte| decoder.feed(byte)).collect();
+    let output = run_script(keys);
+    let expected = "kshell: type `help` for the commands\n\
+        test> echo over serial\nover serial\n\
+        test> nope\nnope: unknown command\n\
+        test> ech\u{8} \u{8}ho x\nx\n\
+        test> ";
+    // the echo of the keys, the prompts and the commands' output all went to the shell's output
+    assert_eq!(output, expected);
+}
+
+#[test_case]
+fn test_history_ring() {
+    let mut history = History::new();
+    history.add("one");
+    history.add("one");
+    history.add("   ");
+    history.add("two");
+    history.add("one");
+    assert_eq!(history.iter().collect::<Vec<_>>(), ["one", "two", "one"]);
+    assert_eq!((history.get(0), history.get(2), history.get(3)), (Some("one"), Some("one"), None));
+
+    let mut history = History::new();
+    for number in 0..HISTORY_LEN + 3 {
+        history.add(&alloc::format!("line {}", number));
+    }
+    assert_eq!(history.len(), HISTORY_LEN);
+    assert_eq!(history.iter().next(), Some("line 3"));
+    assert_eq!(history.get(0), Some("line 34"));
+}
+
+#[test_case]
+fn test_editor_recalls_history() {
+    let up = DecodedKey::RawKey(KeyCode::ArrowUp);
+    let down = DecodedKey::RawKey(KeyCode::ArrowDown);
+    let mut history = History::new();
+    for line in ["first", "second", "third"] {
+        history.add(line);
+    }
+    let feed = |editor: &mut LineEditor, keys: &[DecodedKey]| {
+        let mut out = String::new();
+        let mut result = EditResult::Continue;
+        for &key in keys {
+            result = editor.feed_with_history(key, &history, &mut out).unwrap();
+        }
+        (result, out)
+    };
+
+    // up-up-down-enter --> the newest but one, then the newest
+    let mut editor = LineEditor::new(PROMPT, None);
+    let (result, _) = feed(&mut editor, &[up, up, down, DecodedKey::Unicode('\n')]);
+    assert_eq!((result, editor.line()), (EditResult::Done, "third"));
+
+    // past the oldest stays on it, down below the newest gives back what was typed
+    let mut editor = LineEditor::new(PROMPT, None);
+    feed(&mut editor, &typed("ec"));
+    feed(&mut editor, &[up, up, up, up]);
+    assert_eq!(editor.line(), "first");
+    let (_, out) = feed(&mut editor, &[down, down, down]);
+    assert_eq!((editor.line(), editor.cursor()), ("ec", 2));
+    // the longer line is blanked out behind the draft
+    assert!(out.ends_with("third \u{8}\u{8}\u{8}\u{8}\u{8}\u{8}ec   \u{8}\u{8}\u{8}"), "got {:?}", out);
+
+    // down without up and up without history change nothing
+    let mut editor = LineEditor::new(PROMPT, None);
+    let (_, out) = feed(&mut editor, &[down]);
+    assert_eq!((editor.line(), out.as_str()), ("", ""));
+    let mut out = String::new();
+    editor.feed_with_history(up, &History::new(), &mut out).unwrap();
+    assert_eq!((editor.line(), out.as_str()), ("", ""));
+}
+
+#[test_case]
+fn test_shell_history() {
+    let up = DecodedKey::RawKey(KeyCode::ArrowUp);
+    let down = DecodedKey::RawKey(KeyCode::ArrowDown);
+    let mut keys: VecDeque<DecodedKey> = typed("echo a\necho b\n").into();
+    // up-up-down-enter runs "echo b" again, kept once
+    keys.extend([up, up, down]);
+    keys.extend(typed("\n"));
+    // the recalled line is edited into a new one, "echo b" stays
+    keys.push_back(up);
+    keys.extend(typed("\u{8}c\nhistory\n"));
+    let output = run_script(keys);
+    assert!(output.contains("test> echo b\nb\ntest> "), "got {:?}", output);
+    assert_eq!(output.matches("\nb\n").count(), 2, "echo b didn't run twice: {:?}", output);
+    assert!(output.contains("\nc\n"), "echo c didn't run: {:?}", output);
+    assert!(
+        output.ends_with("    1  echo a\n    2  echo b\n    3  echo c\n    4  history\ntest> "),
+        "got {:?}",
+        output
+    );
+    // outside of a shell there is no history
+    assert_eq!(run_line("history"), Err(ShellError::Refused("no shell runs this line")));
+}
@@ -5,18 +5,52 @@
 #![reexport_test_harness_main = "test_main"]
 #![feature(abi_x86_interrupt)] // see interrupts.rs
 #![feature(const_mut_refs)] //see allocator.rs and fixed_size_block.rs
+#![feature(alloc_error_handler)] // see Out of memory in allocator.rs
 
 pub mod serial;
 pub mod vga_buffer;
+pub mod console;
+pub mod framebuffer;
 pub mod interrupts;
 pub mod gdt;
 pub mod memory;
 pub mod allocator;
+pub mod config;
+pub mod task;
+pub mod kshell;
+pub mod percpu;
+pub mod sync;
+pub mod mmio;
+pub mod syscall;
+pub mod process;
+pub mod log;
+pub mod drivers;
+pub mod fs;
+pub mod net;
+pub mod ipc;
+pub mod collections;
+pub mod acpi;
+pub mod smbios;
+pub mod smp;
+pub mod power;
+pub mod watchdog;
+pub mod error;
+pub mod cpu;
+pub mod cpuid;
+pub mod rand;
+pub mod selftest;
+pub mod snapshot;
+pub mod time;
+pub mod bench;
+pub mod backtrace;
 
 // use built-in alloc crate --> subset of the standard library --> building for custom target (have to recompile --> see .cargo/config.toml)
 extern crate alloc;
 
+use bootloader::BootInfo;
 use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use x86_64::structures::idt::PageFaultErrorCode;
 
 // use the `hlt` instruction to create an energy-efficient endless loop rather than burning CPU resources
 pub fn hlt_loop() -> ! {
@@ -25,6 +59,100 @@ pub fn hlt_loop() -> ! {
     }
 }
 
+// EARLY PRINTING ===============================================
+
+// position (cell index) of the next character early_print() writes --> an atomic b/c statics need interior mutability
+// and this must work without any locks
+static EARLY_CURSOR: AtomicUsize = AtomicUsize::new(vga_buffer::FIRST_TEXT_ROW * vga_buffer::BUFFER_WIDTH);
+
+// light gray on black
+const EARLY_COLOR: u16 = 0x07;
+
+/// Print to the VGA buffer before anything (GDT, IDT, heap, the VGA writer...) is set up.
+///
+/// Writes straight to `0xb8000` with volatile writes, no locks and no interrupt handling, so it can't deadlock
+/// but also isn't synchronized with anything --> only use it during early boot or when everything else is broken.
+/// Text starts below the status bar and wraps back to the top when the screen is full (no scrolling).
+/// With the `debugcon` feature the text goes to port 0xE9 as well.
+pub fn early_print(s: &str) {
+    use vga_buffer::{BUFFER_ADDRESS, BUFFER_HEIGHT, BUFFER_WIDTH, FIRST_TEXT_ROW};
+
+    #[cfg(feature = "debugcon")]
+    drivers::debugcon::write_str(s);
+
+    let buffer = BUFFER_ADDRESS as *mut u16;
+    let first_cell = FIRST_TEXT_ROW * BUFFER_WIDTH;
+    let cells = BUFFER_HEIGHT * BUFFER_WIDTH;
+    let mut cursor = EARLY_CURSOR.load(Ordering::Relaxed);
+
+    for byte in s.bytes() {
+        if byte == b'\n' {
+            cursor += BUFFER_WIDTH - cursor % BUFFER_WIDTH; // start of the next row
+        } else {
+            let byte = match byte {
+                0x20..=0x7e => byte,
+                _ => 0xfe, // same replacement character as the regular writer
+            };
+            unsafe { buffer.add(cursor).write_volatile(EARLY_COLOR << 8 | byte as u16) };
+            cursor += 1;
+        }
+        if cursor >= cells {
+            cursor = first_cell;
+        }
+    }
+
+    EARLY_CURSOR.store(cursor, Ordering::Relaxed);
+}
+
+/// `fmt::Write` adapter for `early_print()`, so `write!` works before init too.
+pub struct EarlyPrinter;
+
+impl core::fmt::Write for EarlyPrinter {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        early_print(s);
+        Ok(())
+    }
+}
+
+/// print! for early boot, through `early_print()` (no locks, no lazy statics).
+#[macro_export]
+macro_rules! early_print {
+    ($($arg:tt)*) => ({
+        use core::fmt::Write as _;
+        let _ = $crate::EarlyPrinter.write_fmt(format_args!($($arg)*));
+    });
+}
+
+// PANIC OUTPUT ================================================
+// a panic handler prints the panic (location and message) first, with panic_println!: serial, screen and the console's
+// extra outputs, none of them waiting for a lock (see PANIC OUTPUT in serial.rs and vga_buffer.rs) --> it gets out even
+// if the panicking code held the serial port or the VGA writer, or nothing was initialized yet
+// then release_output_locks(), and only after that whatever else the handler prints (backtrace, test summary...), which
+// goes through the regular print!/serial_print! and may fault or hang on its own
+
+#[doc(hidden)]
+pub fn _panic_print(args: core::fmt::Arguments) {
+    serial::panic_print(args);
+    vga_buffer::panic_print(args);
+    console::try_print_to_sinks(args);
+}
+
+/// println! for panic handlers: to the serial port, the screen and the console's extra outputs without waiting for a
+/// lock or initializing anything.
+#[macro_export]
+macro_rules! panic_println {
+    ($($arg:tt)*) => ($crate::_panic_print(format_args!("{}\n", format_args!($($arg)*))));
+}
+
+/// Free the serial port's and the VGA writer's locks, for panic handlers once the panic is printed.
+///
+/// Unsafe because whoever holds them must never run again (the code that panicked: the handler halts, exits or jumps
+/// back to the test runner).
+pub unsafe fn release_output_locks() {
+    serial::force_unlock();
+    vga_buffer::force_unlock();
+}
+
 // lib.rs TESTS ================================================
 
 #[test_case]
@@ -32,10 +160,84 @@ fn trivial_lib_assertion() {
     assert_eq!(1, 1);
 }
 
+#[test_case]
+fn test_matching_test_filter() {
+    let name = "mini_os::allocator::test_many_boxes";
+    assert!(test_matches_filter(name, ""));
+    assert!(test_matches_filter(name, "   "));
+    assert!(test_matches_filter(name, "allocator"));
+    assert!(test_matches_filter(name, "vga allocator"), "one of the words is enough");
+    assert!(!test_matches_filter(name, "vga"));
+    assert!(!test_matches_filter(name, "!many_boxes"));
+    assert!(!test_matches_filter(name, "allocator !many_boxes"), "an exclusion wins");
+    assert!(test_matches_filter(name, "!vga"));
+    assert!(!test_matches_filter(name, "!vga memory"), "only exclusions let everything else through, a word doesn't");
+    assert!(test_matches_filter(name, "!"), "an empty exclusion excludes nothing");
+}
+
+#[test_case]
+fn test_early_print_writes_vga_memory() {
+    // nothing else may touch the screen in between (ex. the timer interrupt printing dots)
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let start = EARLY_CURSOR.load(Ordering::Relaxed);
+        early_print("ok");
+        let cell = |i: usize| unsafe { (vga_buffer::BUFFER_ADDRESS as *const u16).add(i).read_volatile() };
+        // the second character wraps to the first text row if the first one landed in the last cell
+        let next = match start + 1 {
+            n if n >= vga_buffer::BUFFER_HEIGHT * vga_buffer::BUFFER_WIDTH => vga_buffer::FIRST_TEXT_ROW * vga_buffer::BUFFER_WIDTH,
+            n => n,
+        };
+        assert_eq!(cell(start), EARLY_COLOR << 8 | b'o' as u16);
+        assert_eq!(cell(next), EARLY_COLOR << 8 | b'k' as u16);
+    });
+}
+
+#[test_case]
+fn test_duration_of_a_busy_wait() {
+    let hz = u64::from(config::get().timer_hz);
+    let wait_for = |tick: u64| {
+        while interrupts::timer_ticks() < tick {
+            core::hint::spin_loop();
+        }
+    };
+    // start right after a tick --> the wait is TICKS whole timer periods
+    const TICKS: u64 = 3;
+    wait_for(interrupts::timer_ticks() + 1);
+    let before = running_test_duration().0.expect("test_runner() calibrates the TSC");
+    wait_for(interrupts::timer_ticks() + TICKS);
+    let after = running_test_duration().0.unwrap();
+
+    let expected = TICKS * 1000 / hz;
+    let measured = after - before;
+    // the TSC is calibrated against the timer --> a little below is its error, not a short wait
+    assert!(measured >= expected * 8 / 10, "{} ms for {} ms", measured, expected);
+    // a slow or busy host can delay ticks (or the busy wait itself), only twice as long is surely broken
+    assert!(measured <= expected * 2 + 10, "{} ms for {} ms", measured, expected);
+}
+
+#[test_case]
+static PAGE_FAULT_WITH_OTHER_BITS: ShouldPanic<fn()> = ShouldPanic("mini_os::page_fault_with_other_bits", || {
+    // a read of an unmapped page is a non-present fault, not a write
+    expect_page_fault(PageFaultErrorCode::CAUSED_BY_WRITE, || unsafe {
+        ((memory::PHYS_MAP_START - 4096) as *const u64).read_volatile();
+    });
+});
+
+#[test_case]
+static NO_PAGE_FAULT: ShouldPanic<fn()> = ShouldPanic("mini_os::no_page_fault", || {
+    let value = 7u64;
+    expect_page_fault(PageFaultErrorCode::empty(), || unsafe {
+        (&value as *const u64).read_volatile();
+    });
+});
+
 // CONFIG TEST FUNCS (for main.rs, lib.rs and all integration tests)===============================
 
 pub trait Testable {
     fn run(&self) -> ();
+
+    /// What the test is called in the output and for the filter (see TEST FILTER).
+    fn name(&self) -> &'static str;
 }
 
 // implement testable trait for all functions which implement Fn() which prints test messages to the host system via serial ports
@@ -45,44 +247,595 @@ where
     T: Fn(),
 {
     fn run(&self) -> () {
-        serial_print!("{}...\t", core::any::type_name::<T>());
-        self();
-        serial_println!("[ok]");
+        // a panic already printed "[failed]" (see TEST RECOVERY)
+        if !run_test(self.name(), self) {
+            serial_println!("[ok] ({})", running_test_duration());
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        core::any::type_name::<T>()
+    }
+}
+
+// prints the name and runs `test` with the per-test timeout (see TEST TIMEOUT) and timed (see TEST DURATIONS),
+// returns whether it panicked
+fn run_test(name: &'static str, test: &dyn Fn()) -> bool {
+    serial_print!("{}...\t", name);
+    x86_64::instructions::interrupts::without_interrupts(|| *RUNNING_TEST.lock() = name);
+    // the timer interrupt ends the test if it's still running at the deadline (only with the timer running, see init())
+    let timeout = TEST_TIMEOUT_TICKS.load(Ordering::Relaxed);
+    if timeout != 0 {
+        TEST_DEADLINE.store(interrupts::timer_ticks() + timeout, Ordering::Relaxed);
+        if !TEST_TIMEOUT_REGISTERED.swap(true, Ordering::Relaxed) {
+            interrupts::register_timer_callback(check_test_timeout);
+        }
+    }
+    let start = task::usage::rdtsc();
+    TEST_START_TSC.store(start, Ordering::Relaxed);
+    TEST_RUNNING.store(true, Ordering::SeqCst);
+    let panicked = run_recoverable(test);
+    TEST_RUNNING.store(false, Ordering::SeqCst);
+    record_test_duration(name, task::usage::rdtsc().wrapping_sub(start));
+    // no unregistering: after a panic in a timer callback the callbacks' lock is still held --> stays registered, idle
+    TEST_DEADLINE.store(u64::MAX, Ordering::Relaxed);
+    panicked
+}
+
+// SHOULD PANIC ======================================
+// a test that passes by panicking: the panic handler prints "[ok]" for it and the runner goes on with the next test
+// (see TEST RECOVERY), not panicking is its failure
+// - after the panic the serial port and the VGA writer have to be free (see PANIC OUTPUT), a lock left held fails it
+// - an assert in it can't fail it (its panic passes) --> a check before the panic returns instead
+// - outside of test_runner() there is nothing to go back to: its panic ends the run with Success, so it has to be last
+// - the flag is a plain static: there is one CPU running tests and the panic handler reads it on that CPU
+
+static EXPECTING_PANIC: AtomicBool = AtomicBool::new(false);
+
+/// A test that has to panic, with its name: `#[test_case] static FAILS: ShouldPanic<fn()> = ShouldPanic("fails", fails);`
+pub struct ShouldPanic<F>(pub &'static str, pub F);
+
+impl<F: Fn()> Testable for ShouldPanic<F> {
+    fn run(&self) -> () {
+        EXPECTING_PANIC.store(true, Ordering::SeqCst);
+        let panicked = run_test(self.name(), &self.1);
+        EXPECTING_PANIC.store(false, Ordering::SeqCst);
+        if panicked {
+            // the handler has to leave the outputs free for the tests after it, even if this one held them
+            if !serial::lock_is_free() || !vga_buffer::lock_is_free() {
+                serial::panic_print(format_args!("Error: the panic handler left an output locked\n\n"));
+                unsafe { release_output_locks() };
+                record_failure(self.name(), QemuExitCode::Failed, format_args!("output locked after the panic"));
+            }
+            return;
+        }
+        serial_println!("[failed]\n");
+        serial_println!("Error: test did not panic\n");
+        if !RECOVER_FROM_PANICS.load(Ordering::SeqCst) {
+            exit_qemu(QemuExitCode::Failed);
+            hlt_loop();
+        }
+        record_failure(self.name(), QemuExitCode::Failed, format_args!("did not panic"));
+    }
+
+    fn name(&self) -> &'static str {
+        self.0
+    }
+}
+
+// TEST RECOVERY ======================================
+// there is no unwinding (panic = "abort"), so a panic can't return into the runner the usual way --> every test runs
+// through mini_os_run_recoverable(), which saves the callee-saved registers, rflags and the stack pointer (like
+// switch_task() in task/coroutine.rs) and returns 0 once the test is done; the panic handler calls
+// mini_os_resume_recoverable(), which throws away the test's stack and makes that call return 1 instead
+// - only under test_runner(): an integration test calling run() itself has no summary that would report the failure
+// - the failures (test name and panic message) are kept in FAILURES, a fixed buffer --> no heap needed, the panic may
+//   have come from the allocator; test_runner() lists them after the last test
+// - LIMITATION: nothing on the thrown away stack is dropped --> a lock held by the test stays locked (the next test to
+//   need it hangs until its timeout), a panic in an interrupt handler skips its end of interrupt; no-alloc guards are
+//   reset by the runner
+
+static RECOVER_FROM_PANICS: AtomicBool = AtomicBool::new(false);
+// set while a test runs, a panic without it is PanicOutsideTest (see EXIT QEMU FUNCS)
+static TEST_RUNNING: AtomicBool = AtomicBool::new(false);
+// the stack pointer mini_os_run_recoverable() saved, valid while IN_RECOVERABLE_TEST is set
+static RECOVERY_RSP: AtomicU64 = AtomicU64::new(0);
+static IN_RECOVERABLE_TEST: AtomicBool = AtomicBool::new(false);
+static TESTS_FAILED: AtomicUsize = AtomicUsize::new(0);
+// the exit code of the first failure (see EXIT QEMU FUNCS), 0 before there was one
+static FIRST_FAILURE_CODE: AtomicU32 = AtomicU32::new(0);
+static FAILURES: spin::Mutex<FailureLog> = spin::Mutex::new(FailureLog { buf: [0; FAILURE_LOG_SIZE], len: 0 });
+
+const FAILURE_LOG_SIZE: usize = 2048;
+
+// the failures as "name: message" lines, what doesn't fit is cut off
+struct FailureLog {
+    buf: [u8; FAILURE_LOG_SIZE],
+    len: usize,
+}
+
+impl FailureLog {
+    fn as_str(&self) -> &str {
+        // cut off in the middle of a character --> up to where it is still valid
+        match core::str::from_utf8(&self.buf[..self.len]) {
+            Ok(text) => text,
+            Err(error) => core::str::from_utf8(&self.buf[..error.valid_up_to()]).unwrap_or(""),
+        }
+    }
+}
+
+impl core::fmt::Write for FailureLog {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let n = s.len().min(FAILURE_LOG_SIZE - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+// count the failure and remember it for the list at the end, the first one's `code` is what the run exits with
+fn record_failure(name: &str, code: QemuExitCode, message: core::fmt::Arguments) {
+    use core::fmt::Write;
+
+    TESTS_FAILED.fetch_add(1, Ordering::Relaxed);
+    let _ = FIRST_FAILURE_CODE.compare_exchange(0, code as u32, Ordering::Relaxed, Ordering::Relaxed);
+    // try_lock(): a panic while the list was being written mustn't hang the panic handler
+    if let Some(mut failures) = FAILURES.try_lock() {
+        let _ = writeln!(failures, "    {}: {}", name, message);
+    }
+}
+
+// run `test`, returns true if it panicked and the panic handler came back here
+fn run_recoverable(test: &dyn Fn()) -> bool {
+    extern "C" fn call(arg: *mut ()) {
+        let test = unsafe { *(arg as *const &dyn Fn()) };
+        test();
+    }
+
+    if !RECOVER_FROM_PANICS.load(Ordering::SeqCst) {
+        test();
+        return false;
+    }
+    let mut test = test;
+    IN_RECOVERABLE_TEST.store(true, Ordering::SeqCst);
+    let panicked = unsafe { mini_os_run_recoverable(RECOVERY_RSP.as_ptr(), call, &mut test as *mut &dyn Fn() as *mut ()) };
+    IN_RECOVERABLE_TEST.store(false, Ordering::SeqCst);
+    if panicked != 0 {
+        allocator::reset_no_alloc_guards();
+        interrupts::reset_page_fault_nesting(); // the test may have panicked in the page fault handler
+    }
+    panicked != 0
+}
+
+// mini_os_run_recoverable(rsp_slot, f, arg) -> u64
+//   pushes rflags and the callee-saved registers, saves the stack pointer to *rsp_slot, calls f(arg), returns 0
+// mini_os_resume_recoverable(rsp_slot) -> !
+//   loads the stack pointer from *rsp_slot and returns 1 from the mini_os_run_recoverable() call that saved it
+//   (popping rflags turns interrupts back on if they were on when the test started)
+// 7 pushes after the call's return address --> the stack is 16 byte aligned for `call rsi`
+core::arch::global_asm!(
+    ".global mini_os_run_recoverable",
+    "mini_os_run_recoverable:",
+    "pushfq",
+    "push rbx",
+    "push rbp",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov [rdi], rsp",
+    "mov rdi, rdx",
+    "call rsi",
+    "xor eax, eax",
+    "jmp 2f",
+    "",
+    ".global mini_os_resume_recoverable",
+    "mini_os_resume_recoverable:",
+    "mov rsp, [rdi]",
+    "mov eax, 1",
+    "2:",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbp",
+    "pop rbx",
+    "popfq",
+    "ret",
+);
+
+extern "C" {
+    fn mini_os_run_recoverable(rsp_slot: *mut u64, f: extern "C" fn(*mut ()), arg: *mut ()) -> u64;
+    fn mini_os_resume_recoverable(rsp_slot: *const u64) -> !;
+}
+
+// EXPECTED PAGE FAULTS ======================================
+// tests of guard pages, NX and write protection are "this access has to fault, with these error bits"
+// --> expect_page_fault() runs the access through mini_os_run_recoverable() (see TEST RECOVERY) with a save point of
+// its own and stores the expected error code; the page fault handler asks expected_page_fault() first, which jumps
+// back to the save point for a fault with exactly those bits --> the faulting instruction is abandoned, not retried,
+// and expect_page_fault() returns
+// - other bits, or no fault at all, panic with what was expected and what came --> an ordinary test failure
+// - the same with or without test_runner(): a harness = false binary just goes on after it (see tests/page_fault.rs)
+// - the access runs in kernel mode, so the expected code has no USER_MODE bit
+
+// EXPECTING | the expected error code bits, 0 without an expectation
+static EXPECTED_PAGE_FAULT: AtomicU64 = AtomicU64::new(0);
+const EXPECTING: u64 = 1 << 63;
+// the save point of expect_page_fault()
+static PAGE_FAULT_RSP: AtomicU64 = AtomicU64::new(0);
+
+/// Run `f`, which has to page fault with exactly the error code `expected`. `f` stops at the fault.
+///
+/// Panics if it faults with another code or doesn't fault at all.
+pub fn expect_page_fault<F: FnOnce()>(expected: PageFaultErrorCode, f: F) {
+    extern "C" fn call<F: FnOnce()>(arg: *mut ()) {
+        let f = unsafe { (*(arg as *mut Option<F>)).take() };
+        if let Some(f) = f {
+            f();
+        }
+    }
+
+    let mut f = Some(f);
+    EXPECTED_PAGE_FAULT.store(EXPECTING | expected.bits(), Ordering::SeqCst);
+    let faulted = unsafe { mini_os_run_recoverable(PAGE_FAULT_RSP.as_ptr(), call::<F>, &mut f as *mut Option<F> as *mut ()) };
+    EXPECTED_PAGE_FAULT.store(0, Ordering::SeqCst);
+    if faulted != 0 {
+        interrupts::reset_page_fault_nesting();
+    }
+    assert!(faulted != 0, "expected a page fault with {}, there was none", interrupts::describe_page_fault(expected));
+}
+
+/// Called first thing by the page fault handler: returns if no fault was expected, jumps back into
+/// `expect_page_fault()` if this was the one, panics otherwise.
+pub(crate) fn expected_page_fault(code: PageFaultErrorCode) {
+    let expected = EXPECTED_PAGE_FAULT.swap(0, Ordering::SeqCst);
+    if expected & EXPECTING == 0 {
+        return;
+    }
+    let expected = PageFaultErrorCode::from_bits_truncate(expected);
+    if code == expected {
+        unsafe { mini_os_resume_recoverable(PAGE_FAULT_RSP.as_ptr()) };
+    }
+    panic!(
+        "expected a page fault with {}, got {} at {:?}",
+        interrupts::describe_page_fault(expected),
+        interrupts::describe_page_fault(code),
+        x86_64::registers::control::Cr2::read()
+    );
+}
+
+// TEST FILTER ======================================
+// to run only some of the tests of a binary: words separated by spaces, from the fw_cfg file TEST_FILTER_FILE
+// (`-fw_cfg name=opt/org.mini_os/test-filter,string="heap !many_boxes"`) or else TEST_FILTER at compile time
+// - a test runs if its name contains one of the words (any name does without such words) and none of the `!words`
+// - the summary line says how many ran and how many were skipped --> an empty run from a mistyped filter shows
+
+// the counts for the summary, printed by test_runner() (TESTS_FAILED is in TEST RECOVERY)
+static TESTS_RUN: AtomicUsize = AtomicUsize::new(0);
+static TESTS_SKIPPED: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether the test `name` runs with `filter` (see TEST FILTER), an empty filter runs everything.
+pub fn test_matches_filter(name: &str, filter: &str) -> bool {
+    let mut included = None;
+    for word in filter.split_whitespace() {
+        match word.strip_prefix('!') {
+            Some(excluded) => {
+                if !excluded.is_empty() && name.contains(excluded) {
+                    return false;
+                }
+            }
+            None => included = Some(included.unwrap_or(false) || name.contains(word)),
+        }
+    }
+    included.unwrap_or(true)
+}
+
+// the filter from fw_cfg (read into `buf`) or compile time, "" without one
+fn test_filter(buf: &mut [u8]) -> &str {
+    let compiled_in = option_env!("TEST_FILTER").unwrap_or("");
+    match drivers::fw_cfg::read_file_into(drivers::fw_cfg::TEST_FILTER_FILE, buf) {
+        Some(size) => core::str::from_utf8(&buf[..size.min(buf.len())])
+            .map(|filter| filter.trim_end_matches('\0'))
+            .unwrap_or(compiled_in),
+        None => compiled_in,
+    }
+}
+
+fn print_test_summary() {
+    let failed = TESTS_FAILED.load(Ordering::Relaxed);
+    serial_println!(
+        "test result: {}. {} passed, {} failed, {} skipped",
+        if failed == 0 { "ok" } else { "FAILED" },
+        TESTS_RUN.load(Ordering::Relaxed) - failed,
+        failed,
+        TESTS_SKIPPED.load(Ordering::Relaxed)
+    );
+}
+
+// TEST DURATIONS ======================================
+// every test is timed with the TSC (calibrated by test_runner(), see TSC in time.rs) --> works with interrupts off too
+// - "[ok] (12 ms)" on the test's line, "(? ms)" without a calibrated TSC (ex. run() called without test_runner())
+// - test_runner() prints the total and the SLOWEST_COUNT slowest tests at the end, kept in a fixed array: no heap needed
+
+const SLOWEST_COUNT: usize = 3;
+
+// the TSC when the running test started
+static TEST_START_TSC: AtomicU64 = AtomicU64::new(0);
+static TESTS_TOTAL_CYCLES: AtomicU64 = AtomicU64::new(0);
+// (name, cycles), the slowest first, "" for a free slot
+static SLOWEST_TESTS: spin::Mutex<[(&'static str, u64); SLOWEST_COUNT]> = spin::Mutex::new([("", 0); SLOWEST_COUNT]);
+
+// milliseconds for the output, "? ms" without a calibrated TSC
+struct Millis(Option<u64>);
+
+impl core::fmt::Display for Millis {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self.0 {
+            Some(ms) => write!(f, "{} ms", ms),
+            None => f.write_str("? ms"),
+        }
+    }
+}
+
+// how long the running test has been running
+fn running_test_duration() -> Millis {
+    Millis(time::tsc_cycles_to_ms(task::usage::rdtsc().wrapping_sub(TEST_START_TSC.load(Ordering::Relaxed))))
+}
+
+// add a finished test to the total and, if it's one of them, the slowest tests
+fn record_test_duration(name: &'static str, cycles: u64) {
+    TESTS_TOTAL_CYCLES.fetch_add(cycles, Ordering::Relaxed);
+    // try_lock(): after a panic that came while the list was updated it stays locked
+    if let Some(mut slowest) = SLOWEST_TESTS.try_lock() {
+        if let Some(slot) = slowest.iter().position(|&(_, slower)| cycles > slower) {
+            slowest[slot..].rotate_right(1);
+            slowest[slot] = (name, cycles);
+        }
+    }
+}
+
+fn print_test_durations() {
+    serial_println!("total time: {}", Millis(time::tsc_cycles_to_ms(TESTS_TOTAL_CYCLES.load(Ordering::Relaxed))));
+    serial_println!("slowest tests:");
+    for &(name, cycles) in SLOWEST_TESTS.lock().iter().filter(|(name, _)| !name.is_empty()) {
+        serial_println!("    {} ({})", name, Millis(time::tsc_cycles_to_ms(cycles)));
     }
 }
 
+// TEST TIMEOUT ======================================
+// one test hanging used to hang the whole run until cargo's test-timeout killed QEMU (without saying which test it was)
+// --> each test gets TEST_TIMEOUT_TICKS timer ticks (`test_timeout=<seconds>` on the command line, see config.rs), then
+// the timer callback prints "[timeout] <test>" and exits QEMU with Timeout
+// - it doesn't panic: a test hung on a spinlock may hold the lock the panic handler would need next
+// - the name of the running test is in RUNNING_TEST, set with interrupts off --> the callback only has to try_lock() it
+
+// 0 = no timeout, for tests that call run() without test_runner()
+static TEST_TIMEOUT_TICKS: AtomicU64 = AtomicU64::new(0);
+// the tick count at which the running test times out, u64::MAX between tests
+static TEST_DEADLINE: AtomicU64 = AtomicU64::new(u64::MAX);
+// check_test_timeout() is registered by the first test with a timeout and stays registered
+static TEST_TIMEOUT_REGISTERED: AtomicBool = AtomicBool::new(false);
+static RUNNING_TEST: spin::Mutex<&'static str> = spin::Mutex::new("");
+static TEST_TIMEOUT_EXIT: spin::Mutex<TestTimeoutExit> = spin::Mutex::new(exit_on_test_timeout);
+
+// the watchdog stays as the backstop for a hang the timeout callback can't end (ex. with interrupts off, see watchdog.rs)
+const TEST_WATCHDOG_SECONDS: u64 = 120;
+
+/// What a timed out test ends with, after "[timeout] <test>" is printed: gets the test's name and the exit code.
+pub type TestTimeoutExit = fn(&'static str, QemuExitCode);
+
+/// Fail every test `Testable::run()` runs from now on that takes more than `ticks` timer ticks, 0 turns the timeout off.
+pub fn set_test_timeout_ticks(ticks: u64) {
+    TEST_TIMEOUT_TICKS.store(ticks, Ordering::Relaxed);
+}
+
+/// Replace what a timed out test ends with (by default QEMU exits with the code), for the test of the timeout itself.
+pub fn set_test_timeout_exit(exit: TestTimeoutExit) {
+    x86_64::instructions::interrupts::without_interrupts(|| *TEST_TIMEOUT_EXIT.lock() = exit);
+}
+
+fn exit_on_test_timeout(_name: &'static str, code: QemuExitCode) {
+    exit_qemu_without_report(code);
+}
+
+// a timer callback, registered while a test runs
+fn check_test_timeout(ticks: u64) {
+    let deadline = TEST_DEADLINE.load(Ordering::Relaxed);
+    if ticks < deadline {
+        return;
+    }
+    let name = RUNNING_TEST.try_lock().map_or("<unknown test>", |name| *name);
+    let exit = TEST_TIMEOUT_EXIT.try_lock().map_or(exit_on_test_timeout as TestTimeoutExit, |exit| *exit);
+    serial_println!("[timeout] {}", name);
+    exit(name, QemuExitCode::Timeout);
+    hlt_loop();
+}
+
 // Custom test runner function --> automatically runned by test_main() and inputs all test cases
 pub fn test_runner(tests: &[&dyn Testable]) {
-    serial_println!("Running {} tests", tests.len());
-    // run all tests
-    for test in tests {
+    let mut buf = [0; 128];
+    let filter = test_filter(&mut buf);
+    let selected = |test: &&&dyn Testable| test_matches_filter(test.name(), filter);
+    let skipped = tests.len() - tests.iter().filter(selected).count();
+    TESTS_SKIPPED.store(skipped, Ordering::Relaxed);
+    if filter.trim().is_empty() {
+        serial_println!("Running {} tests", tests.len());
+    } else {
+        serial_println!("Running {} of {} tests (filter {:?})", tests.len() - skipped, tests.len(), filter);
+    }
+    let timeout_seconds = u64::from(config::get().test_timeout_secs);
+    set_test_timeout_ticks(timeout_seconds * u64::from(config::get().timer_hz));
+    // a hung test fails the run right away instead of waiting for the test timeout (only with the timer running, see init())
+    // --> after the per-test timeout had its chance, and not at all with `test_timeout=0` (no limit means none, ex. to
+    // sit in a debugger)
+    if timeout_seconds != 0 {
+        watchdog::set_action(watchdog::WatchdogAction::ExitQemu(QemuExitCode::Timeout));
+        watchdog::arm(TEST_WATCHDOG_SECONDS.max(2 * timeout_seconds) * u64::from(config::get().timer_hz));
+    }
+    if time::tsc_hz().is_none() {
+        time::calibrate_tsc(); // for the durations, see TEST DURATIONS
+    }
+    // a panicking test fails and the next one runs (see TEST RECOVERY)
+    RECOVER_FROM_PANICS.store(true, Ordering::SeqCst);
+    for test in tests.iter().filter(selected) {
+        TESTS_RUN.fetch_add(1, Ordering::Relaxed);
         test.run();
+        watchdog::pet();
+    }
+    RECOVER_FROM_PANICS.store(false, Ordering::SeqCst);
+    watchdog::disarm();
+    let failed = TESTS_FAILED.load(Ordering::Relaxed);
+    if failed != 0 {
+        serial_println!("\nfailures:\n{}", FAILURES.lock().as_str());
     }
+    print_test_durations();
+    print_test_summary();
     // exit qemu --> cargo test considers all exit codes other than 0 to be failures, but we literally can't exit with code 0 as discussed above
     // b/c of qemu restrictions on isa-debug-exit --> workaround bootimage crate lets us remap exit codes, see Cargo.toml
-    exit_qemu(QemuExitCode::Success);
+    let code = QemuExitCode::from_code(FIRST_FAILURE_CODE.load(Ordering::Relaxed)).unwrap_or(QemuExitCode::Failed);
+    exit_qemu(if failed == 0 { QemuExitCode::Success } else { code });
 }
 
-// what to do when the test fails
+// what to do when the test fails (or, for a ShouldPanic test, passes)
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
-    serial_println!("[failed]\n");
-    serial_println!("Error: {}\n", info);
-    exit_qemu(QemuExitCode::Failed);
+    let expected = EXPECTING_PANIC.swap(false, Ordering::SeqCst);
+    // first, without waiting for the serial port (see PANIC OUTPUT)
+    if expected {
+        serial::panic_print(format_args!("[ok] ({})\n", running_test_duration()));
+    } else {
+        serial::panic_print(format_args!("[failed]\n\nError: {}\n\n", info));
+    }
+    unsafe { release_output_locks() }; // the test that panicked never runs again
+    if !expected {
+        backtrace::print();
+    }
+    let code = if expected { QemuExitCode::Success } else { test_failure_code(info) };
+    // under test_runner() --> back to it, it goes on with the next test (not after a double fault, the CPU state is suspect)
+    if IN_RECOVERABLE_TEST.swap(false, Ordering::SeqCst) && code != QemuExitCode::DoubleFault {
+        if !expected {
+            let name = RUNNING_TEST.try_lock().map_or("<unknown test>", |name| *name);
+            record_failure(name, code, format_args!("{}", info));
+        }
+        unsafe { mini_os_resume_recoverable(RECOVERY_RSP.as_ptr()) };
+    }
+    if expected {
+        print_test_summary();
+    }
+    exit_qemu(code);
     hlt_loop();
 }
 
+/// The exit code a test run ends with for the unexpected panic `info` (see EXIT QEMU FUNCS).
+///
+/// For the panic handlers of integration tests that check how they failed.
+pub fn test_failure_code(info: &PanicInfo) -> QemuExitCode {
+    if interrupts::double_faulted() {
+        QemuExitCode::DoubleFault
+    } else if message_starts_with(info.message(), allocator::OOM_PANIC_PREFIX) {
+        QemuExitCode::Oom
+    } else if !TEST_RUNNING.load(Ordering::SeqCst) {
+        QemuExitCode::PanicOutsideTest
+    } else {
+        QemuExitCode::Failed
+    }
+}
+
+// whether `message` starts with `prefix`, checked while it's formatted --> no buffer, no heap (a failed allocation may
+// be what panicked)
+fn message_starts_with(message: impl core::fmt::Display, prefix: &str) -> bool {
+    use core::fmt::Write;
+
+    struct Prefix<'a>(&'a str);
+
+    impl core::fmt::Write for Prefix<'_> {
+        fn write_str(&mut self, s: &str) -> core::fmt::Result {
+            let n = s.len().min(self.0.len());
+            if s.as_bytes()[..n] != self.0.as_bytes()[..n] {
+                return Err(core::fmt::Error); // a mismatch, stop formatting
+            }
+            self.0 = self.0.get(n..).unwrap_or("");
+            Ok(())
+        }
+    }
+
+    let mut rest = Prefix(prefix);
+    write!(rest, "{}", message).is_ok() && rest.0.is_empty()
+}
+
 // EXIT QEMU FUNCS ======================================
 
 // define an enum to represent our possible exit status', see exit_qemu() for more info
 // we also represent the enum variants as u32 because we defined the "port size" as 4 bytes so u32 would equal the max value
+// what the host sees is QEMU's exit status (code << 1) | 1 --> odd, and far from QEMU's own 0 (normal exit) and 1 (error):
+//
+//   code   host status   meaning
+//   0x10   33            Success (bootimage turns it into 0, see test-success-exit-code in Cargo.toml)
+//   0x11   35            Failed: a test's assertion (or other panic) failed
+//   0x12   37            Timeout: the per-test timeout or the watchdog ended a hung test
+//   0x13   39            DoubleFault: a double fault, ex. an exception without a handler
+//   0x14   41            Oom: a heap allocation failed
+//   0x15   43            PanicOutsideTest: a panic while no test was running (ex. during init)
+//
+// a run that went on after failures (see TEST RECOVERY) exits with the code of the first one
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u32)]
 pub enum QemuExitCode {
     Success = 0x10,
     Failed = 0x11,
+    Timeout = 0x12,
+    DoubleFault = 0x13,
+    Oom = 0x14,
+    PanicOutsideTest = 0x15,
+}
+
+impl QemuExitCode {
+    /// Every exit code.
+    pub const ALL: [QemuExitCode; 6] = [
+        QemuExitCode::Success,
+        QemuExitCode::Failed,
+        QemuExitCode::Timeout,
+        QemuExitCode::DoubleFault,
+        QemuExitCode::Oom,
+        QemuExitCode::PanicOutsideTest,
+    ];
+
+    /// The exit code with the value `code`.
+    pub fn from_code(code: u32) -> Option<Self> {
+        Self::ALL.into_iter().find(|&exit_code| exit_code as u32 == code)
+    }
+
+    /// The exit status QEMU ends with for this code, what the host sees.
+    pub const fn host_status(self) -> i32 {
+        ((self as u32) << 1 | 1) as i32
+    }
+}
+
+#[test_case]
+fn test_exit_codes() {
+    for (i, code) in QemuExitCode::ALL.into_iter().enumerate() {
+        assert_eq!(QemuExitCode::from_code(code as u32), Some(code));
+        assert!(code.host_status() > 1, "QEMU's own exit statuses are 0 and 1");
+        assert!(QemuExitCode::ALL[..i].iter().all(|other| other.host_status() != code.host_status()));
+    }
+    assert_eq!(QemuExitCode::Success.host_status(), 33);
+    assert_eq!(QemuExitCode::from_code(0), None);
+
+    use allocator::OOM_PANIC_PREFIX;
+    assert!(message_starts_with(format_args!("memory allocation of {} bytes (align {}) failed", 64, 8), OOM_PANIC_PREFIX));
+    assert!(message_starts_with(format_args!("{}{}", "memory alloc", "ation of 8 bytes failed"), OOM_PANIC_PREFIX));
+    assert!(!message_starts_with(format_args!("memory allocation"), OOM_PANIC_PREFIX), "shorter than the prefix");
+    assert!(!message_starts_with(format_args!("assertion failed: memory allocation of"), OOM_PANIC_PREFIX));
 }
 
 pub fn exit_qemu(exit_code: QemuExitCode) {
+    allocator::print_heap_peak(); // how close the run came to running out of heap
+    exit_qemu_without_report(exit_code);
+}
+
+/// `exit_qemu()` without printing anything first --> takes no locks, for code that may run while the serial port's
+/// lock is held (see watchdog.rs).
+pub fn exit_qemu_without_report(exit_code: QemuExitCode) {
     // enable use of special port I/O cpu instructions via rust abstractions
     use x86_64::instructions::port::Port;
 
@@ -95,19 +848,145 @@ pub fn exit_qemu(exit_code: QemuExitCode) {
     }
 }
 
+// BOOT INFO CHECK ====================================================
+// the bootloader hands over a `&'static BootInfo` whose layout belongs to the bootloader crate version it was built with
+// --> a kernel linked against another version reads the fields at the wrong offsets and crashes somewhere much later
+// - bootloader 0.10 and up put an `api_version` into the BootInfo, 0.9 (the one we use, see Cargo.toml) doesn't
+//   --> boot_info_api_version() has nothing to read yet and the fields we use are checked for plausibility instead,
+//   which is what garbage from a layout mismatch fails first
+
+/// A bootloader crate version.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Version {
+    pub major: u16,
+    pub minor: u16,
+    pub patch: u16,
+}
+
+impl Version {
+    /// Whether a BootInfo of version `other` has the layout of this one: the same major and, before 1.0, the same minor
+    /// version (semver, a patch release doesn't change the layout).
+    pub fn is_compatible_with(&self, other: &Version) -> bool {
+        self.major == other.major && (self.major != 0 || self.minor == other.minor)
+    }
+}
+
+/// The bootloader crate version the kernel's view of `BootInfo` is compiled from (see Cargo.toml).
+pub const EXPECTED_API_VERSION: Version = Version { major: 0, minor: 9, patch: 23 };
+
+/// Why the BootInfo from the bootloader can't be used (see `check_boot_info()`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootInfoError {
+    VersionMismatch { expected: Version, found: Version },
+    /// The memory map is empty, has a region that ends before it starts or above the 52 bit physical address space, or
+    /// no usable memory.
+    BadMemoryMap,
+    /// The physical memory offset isn't a page aligned canonical address.
+    BadPhysicalMemoryOffset(u64),
+}
+
+// physical addresses have at most 52 bits on x86_64
+const MAX_PHYS_ADDR: u64 = 1 << 52;
+
+/// The `api_version` the bootloader put into `boot_info`, `None` if its version doesn't pass one (0.9 doesn't).
+pub fn boot_info_api_version(_boot_info: &BootInfo) -> Option<Version> {
+    None
+}
+
+/// Check that `boot_info` was written by a bootloader with our layout: `api_version` (if there is one) has to be
+/// compatible with `EXPECTED_API_VERSION` and the fields have to make sense.
+pub fn check_boot_info(boot_info: &BootInfo, api_version: Option<Version>) -> Result<(), BootInfoError> {
+    use bootloader::bootinfo::MemoryRegionType;
+
+    if let Some(found) = api_version {
+        if !EXPECTED_API_VERSION.is_compatible_with(&found) {
+            return Err(BootInfoError::VersionMismatch { expected: EXPECTED_API_VERSION, found });
+        }
+    }
+    let regions = &*boot_info.memory_map;
+    let plausible = regions.iter().all(|region| region.range.start_addr() <= region.range.end_addr() && region.range.end_addr() <= MAX_PHYS_ADDR);
+    let usable = regions.iter().any(|region| region.region_type == MemoryRegionType::Usable);
+    if regions.is_empty() || !plausible || !usable {
+        return Err(BootInfoError::BadMemoryMap);
+    }
+    let offset = boot_info.physical_memory_offset;
+    if offset % 4096 != 0 || x86_64::VirtAddr::try_new(offset).is_err() {
+        return Err(BootInfoError::BadPhysicalMemoryOffset(offset));
+    }
+    Ok(())
+}
+
+/// Stop right away (exit QEMU with `Failed`, or halt) if `boot_info` doesn't pass `check_boot_info()`.
+///
+/// Called first thing in the entry point: nothing else may look at a BootInfo with the wrong layout.
+pub fn check_boot_info_version(boot_info: &BootInfo) {
+    if let Err(error) = check_boot_info(boot_info, boot_info_api_version(boot_info)) {
+        serial_println!("unusable BootInfo, is the bootloader the version the kernel was built with? {}", error);
+        early_print("unusable BootInfo from the bootloader, see serial output\n");
+        exit_qemu(QemuExitCode::Failed);
+        hlt_loop(); // not running in QEMU
+    }
+}
+
+#[test_case]
+fn test_check_boot_info() {
+    use bootloader::bootinfo::{FrameRange, MemoryMap, MemoryRegion, MemoryRegionType};
+
+    let region = |start: u64, end: u64, region_type| MemoryRegion { range: FrameRange::new(start, end), region_type };
+    let boot_info = |regions: &[MemoryRegion], offset: u64| {
+        let mut map = MemoryMap::new();
+        for &region in regions {
+            map.add_region(region);
+        }
+        BootInfo::new(map, None, 0, offset)
+    };
+    let good = [region(0, 0x1000, MemoryRegionType::FrameZero), region(0x1000, 0x9f000, MemoryRegionType::Usable)];
+    let offset = 0x100_0000_0000;
+
+    let fake = boot_info(&good, offset);
+    assert_eq!(check_boot_info(&fake, None), Ok(()));
+    assert_eq!(check_boot_info(&fake, Some(Version { patch: 99, ..EXPECTED_API_VERSION })), Ok(()));
+    // what a 0.11 bootloader would report
+    let found = Version { major: 0, minor: 11, patch: 0 };
+    assert_eq!(
+        check_boot_info(&fake, Some(found)),
+        Err(BootInfoError::VersionMismatch { expected: EXPECTED_API_VERSION, found })
+    );
+
+    let backwards = [region(0x3000, 0x1000, MemoryRegionType::Usable)];
+    assert_eq!(check_boot_info(&boot_info(&backwards, offset), None), Err(BootInfoError::BadMemoryMap));
+    let too_high = [region(0x1000, 0x2000, MemoryRegionType::Usable), region(0, 1 << 60, MemoryRegionType::Reserved)];
+    assert_eq!(check_boot_info(&boot_info(&too_high, offset), None), Err(BootInfoError::BadMemoryMap));
+    let nothing_usable = [region(0, 0x1000, MemoryRegionType::Reserved)];
+    assert_eq!(check_boot_info(&boot_info(&nothing_usable, offset), None), Err(BootInfoError::BadMemoryMap));
+    assert_eq!(check_boot_info(&boot_info(&[], offset), None), Err(BootInfoError::BadMemoryMap));
+    assert_eq!(check_boot_info(&boot_info(&good, 0x1234), None), Err(BootInfoError::BadPhysicalMemoryOffset(0x1234)));
+    let non_canonical = 0x8000_0000_0000;
+    assert_eq!(
+        check_boot_info(&boot_info(&good, non_canonical), None),
+        Err(BootInfoError::BadPhysicalMemoryOffset(non_canonical))
+    );
+}
+
 // INIT FUNCTIONS ====================================================
 
 pub fn init() {
+    vga_buffer::init_writer(); // print!/println! work from here on, before that there is only early_print()
+    #[cfg(feature = "debugcon")]
+    drivers::debugcon::register(); // needs no setup, so it sees everything printed from here on
     gdt::init(); // initialize the Global Descriptor Table (GDT) and Task State Segment (TSS) needed by the IDT
     interrupts::init_idt(); // Set up the interrupt table (IDT: Interrupt Descriptor Table) to handle interrupts and handler functions
     unsafe { interrupts::PICS.lock().initialize() }; // Initialize both PIC's (primary and secondary) with our offsets
     x86_64::instructions::interrupts::enable(); // enable interrupts on our CPU
+    cpuid::detect_cpu_features(); // read the CPUID leaves once, see cpuid.rs
+    interrupts::enable_machine_check(); // hardware errors go to the #MC handler instead of resetting the machine
+    percpu::init_per_cpu(); // point GS at this CPU's data, see percpu.rs
 }
 
 // ENTRY FUNCTIONS (for `cargo test` in lib.rs) =======================
 
 #[cfg(test)]
-use bootloader::{entry_point, BootInfo};
+use bootloader::entry_point;
 
 #[cfg(test)]
 entry_point!(test_kernel_main); // no longer need to explicitly delcare _start entry point --> see main.rs
@@ -115,9 +994,20 @@ entry_point!(test_kernel_main); // no longer need to explicitly delcare _start e
 /// Entry point for `cargo test`
 /// lib.rs is tested independently of main.rs so we need a entry point AND panic handler here too (only in test mode)
 #[cfg(test)]
-fn test_kernel_main(_boot_info: &'static BootInfo) -> ! {
+fn test_kernel_main(boot_info: &'static BootInfo) -> ! {
+    use memory::GlobalFrameAllocator;
+    use x86_64::VirtAddr;
+
+    check_boot_info_version(boot_info);
     // like before
     init();
+
+    // some unit tests (ex. task.rs, process.rs) allocate memory or frames, so set up the heap like main.rs does
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    unsafe { memory::init_frame_allocator(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut GlobalFrameAllocator).expect("heap initialization failed");
+
     test_main(); //test harness entry func --> see crate/lib attributes (top of file) and test runner
     hlt_loop();
 }
@@ -0,0 +1,48 @@
+// Kernel logging --> klog!(level, ...) prints to the screen and the serial port if `level` is enabled
+// the screen part goes through print!, so the console's sinks (ex. a test's capture, see console/capture.rs) get it too
+// the maximum level comes from the boot configuration (`log_level=` on the kernel command line, see config.rs)
+use crate::config::{self, LogLevel};
+use core::fmt;
+
+/// Whether messages of `level` are printed with the current configuration.
+pub fn enabled(level: LogLevel) -> bool {
+    level <= config::get().log_level
+}
+
+fn prefix(level: LogLevel) -> &'static str {
+    match level {
+        LogLevel::Error => "[ERROR]",
+        LogLevel::Warn => "[WARN ]",
+        LogLevel::Info => "[INFO ]",
+        LogLevel::Debug => "[DEBUG]",
+        LogLevel::Trace => "[TRACE]",
+    }
+}
+
+#[doc(hidden)]
+pub fn _log(level: LogLevel, args: fmt::Arguments) {
+    if !enabled(level) {
+        return;
+    }
+    crate::println!("{} {}", prefix(level), args);
+    crate::serial_println!("{} {}", prefix(level), args);
+}
+
+/// Log a message at the given `LogLevel` to the screen and the serial port.
+#[macro_export]
+macro_rules! klog {
+    ($level:expr, $($arg:tt)*) => ($crate::log::_log($level, format_args!($($arg)*)));
+}
+
+// TESTS ===================================
+
+#[test_case]
+fn test_log_filtering() {
+    let output = crate::console::capture_output(|| {
+        klog!(LogLevel::Error, "disk {} on fire", 0);
+        klog!(LogLevel::Trace, "every detail");
+    });
+    assert!(output.contains("[ERROR] disk 0 on fire\n"), "got {:?}", output);
+    // Error is always enabled, Trace only with `log_level=trace`
+    assert_eq!(output.contains("[TRACE] every detail"), enabled(LogLevel::Trace), "got {:?}", output);
+}
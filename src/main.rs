@@ -75,22 +75,31 @@ fn trivial_main_assertion() {
 // }
 
 fn kernel_main(boot_info: &'static BootInfo) -> ! {
-    println!("Hello World!!!!");
+    mini_os::early_print("mini_os booting...\n"); // works before any initialization, see lib.rs
+    mini_os::check_boot_info_version(boot_info); // stops here if the bootloader's BootInfo layout isn't ours
+
+    // bootloader 0.9 can't pass us a command line, so it comes from QEMU's fw_cfg or is baked in at compile time --> see config.rs
+    let mut cmdline = [0u8; 512];
+    let config = mini_os::config::parse_kernel_args(mini_os::config::boot_cmdline(&mut cmdline));
+    mini_os::config::init(config);
+
     mini_os::init();
+    println!("Hello World!!!!"); // print!/println! need the VGA writer set up by init()
+    mini_os::interrupts::set_timer_frequency(config.timer_hz);
     #[cfg(test)]
     test_main();
 
     {
         // ALLOCATOR/PAGING SETUP ==========================
-        use mini_os::memory::{ BootInfoFrameAllocator, self };
+        use mini_os::memory::{ GlobalFrameAllocator, self };
         use mini_os::allocator;
         use x86_64::{structures::paging::Page, VirtAddr};
 
         let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
         let mut mapper = unsafe { memory::init(phys_mem_offset) };
-        let mut frame_allocator = unsafe {
-            BootInfoFrameAllocator::init(&boot_info.memory_map)
-        };
+        // a single global frame allocator so frames can also be allocated (and freed) later on, ex. for processes
+        unsafe { memory::init_frame_allocator(&boot_info.memory_map, phys_mem_offset) };
+        let mut frame_allocator = GlobalFrameAllocator;
     
         // TEST PAGING ALLOCATION AND WRITE CODE ======================
         // map an unused page
@@ -102,8 +111,12 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
         unsafe { page_ptr.offset(400).write_volatile(0x_f021_f077_f065_f04e)};
 
         // HEAP ALLOCATION =======================================
+        memory::print_memory_map(&boot_info.memory_map);
+        // before any usable memory is trusted with the heap
+        mini_os::selftest::test_memory_regions(phys_mem_offset, &boot_info.memory_map);
         // initialize the heap
-        allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+        allocator::init_heap_with_size(&mut mapper, &mut frame_allocator, config.heap_size_kb * 1024)
+            .expect("heap initialization failed");
 
         // test --> allocate a number on the heap 
         let heap_value = Box::new(41);
@@ -122,22 +135,95 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
         println!("current reference count is {}", Rc::strong_count(&cloned_reference));
         core::mem::drop(reference_counted);
         println!("reference count is {} now", Rc::strong_count(&cloned_reference));
+
+        // SELF-TEST =======================================
+        // every present page table entry has to point at a frame the frame allocator knows is in use
+        let errors = memory::check_active_page_tables();
+        println!("page table check: {} inconsistencies", errors.len());
+        for error in errors {
+            println!("  {:?}", error);
+        }
+    }
+
+    // DEVICES ==========================
+    mini_os::smbios::init(); // prints the machine's manufacturer, product and BIOS version
+    mini_os::acpi::init(); // prints the signatures of the firmware's tables
+    if let Err(error) = mini_os::smp::init() { // prints "<n> CPUs online", see smp.rs
+        println!("{}", error);
+    }
+    mini_os::serial::init_console(); // type `help` in the terminal QEMU's serial port is attached to (a shell from below on)
+    mini_os::drivers::pci::init();
+    if config.framebuffer {
+        // the VGA text is gone from here on --> print!/println! continue on the framebuffer's text console
+        match mini_os::framebuffer::init(1024, 768) {
+            Some(info) => {
+                mini_os::serial_println!("framebuffer: {:?}", info);
+                use mini_os::framebuffer::Color;
+                mini_os::framebuffer::with_framebuffer(|framebuffer| framebuffer.clear(Color::BLACK));
+                mini_os::framebuffer::start_console(Color::rgb(0xC0, 0xC0, 0xC0), Color::BLACK);
+                println!("framebuffer: {}x{} text console", info.width, info.height);
+            }
+            None => println!("framebuffer: no BGA graphics card, staying in text mode"),
+        }
+    }
+    if let Some(nic) = mini_os::drivers::rtl8139::init() { // prints the MAC address if there is one
+        mini_os::net::register_interface(nic);
+        mini_os::net::set_ipv4(mini_os::net::Ipv4Address([10, 0, 2, 15])); // QEMU's user network hands out this one
     }
+
+    // STORAGE ==========================
+    let disks = mini_os::drivers::ata::register_drives();
+    #[cfg(feature = "ramdisk")]
+    {
+        use alloc::sync::Arc;
+        use mini_os::drivers::{block, ramdisk::RamDisk};
+        // 32 KiB --> the heap is small (see HEAP_SIZE in allocator.rs)
+        block::register("ram0", Arc::new(RamDisk::new(64))).expect("ram0 already registered");
+    }
+    println!("{} ATA disk(s): {:?}", disks, mini_os::drivers::block::device_names());
+
+    // FILESYSTEMS ==========================
+    mini_os::fs::init();
+    // the first disk holding a FAT volume shows up under /disk
+    let fat_disk = mini_os::drivers::block::device_names().into_iter().find(|name| {
+        let device = mini_os::drivers::block::lookup(name).expect("device disappeared");
+        mini_os::fs::mount_fat("/disk", device).is_ok()
+    });
+    println!("/disk: {:?}", fat_disk);
     
 
     print!("Heelo yet again :< --> ")    ;
     println!("It did not crash!");
     println!("Some numbers: {} {}", 42, 1.337);
-    mini_os::hlt_loop();
+
+    // SHELL ==========================
+    // every subsystem adds its own commands (see kshell.rs)
+    mini_os::kshell::init();
+    mini_os::memory::init_shell_commands();
+    mini_os::interrupts::init_shell_commands();
+    mini_os::time::init_shell_commands();
+    let mut executor = mini_os::task::executor::Executor::new();
+    #[cfg(not(test))] // the test harness has to finish on its own
+    executor.spawn(mini_os::task::Task::with_name("kshell", mini_os::kshell::run()));
+    #[cfg(not(test))]
+    executor.spawn(mini_os::task::Task::with_name("kshell-serial", mini_os::kshell::run_serial()));
+    executor.run();
 }
 
-// Called on panic (not in test mode) --> loop infinitely for now --> diverging function returns "never" type
+// Called on panic (not in test mode) --> halt forever, or power off after a while if the command line asks for it
+// (panic_poweroff=<seconds>) --> diverging function returns "never" type
 // FIXME: The duplicate lang item `panic_impl` error is cased by rust_analyzer in vscode --> FIXED, see .vscode/settings.json
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    println!("{}", info);
-    mini_os::hlt_loop();
+    mini_os::panic_println!("{}", info); // first, to the screen and serial even if their locks are held
+    unsafe { mini_os::release_output_locks() }; // nothing runs after the panic but this handler
+    mini_os::backtrace::print();
+    mini_os::drivers::speaker::start_tone(880); // keeps sounding while halted, see drivers/speaker.rs
+    match mini_os::config::get().panic_poweroff_secs {
+        0 => mini_os::hlt_loop(),
+        seconds => mini_os::power::shutdown_after(seconds),
+    }
 }
 // the panic handler when run `cargo test` --> print via serial to host system and exit qemu
 #[cfg(test)]
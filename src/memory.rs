@@ -5,13 +5,25 @@ use x86_64::{
         PhysFrame,
         Size4KiB, 
         FrameAllocator,
+        FrameDeallocator,
         Page,
+        PageTableFlags,
         Mapper,
+        Translate,
+        mapper::{MapToError, UnmapError},
     },
     VirtAddr,
     PhysAddr
 };
-use bootloader::bootinfo::{ MemoryMap, MemoryRegionType };
+use bootloader::bootinfo::{ MemoryMap, MemoryRegion, MemoryRegionType };
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::{Mutex, Once};
+use alloc::{collections::BTreeSet, vec::Vec};
+
+pub mod address_space;
+pub mod dma;
+pub mod inspect;
 
 /// Initialize a new OffsetPageTable.
 ///
@@ -20,10 +32,24 @@ use bootloader::bootinfo::{ MemoryMap, MemoryRegionType };
 /// `physical_memory_offset`. Also, this function must be only called once
 /// to avoid aliasing `&mut` references (which is undefined behavior).
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    // allow the NO_EXECUTE page flag (without this bit it counts as a reserved bit and using it page faults)
+    // a CPU without NX keeps it off, every mapping takes its flag from no_execute() below
+    crate::cpu::msr::enable_nxe();
+
     let level_4_table = active_level_4_table(physical_memory_offset);
     OffsetPageTable::new(level_4_table, physical_memory_offset)
 }
 
+/// `PageTableFlags::NO_EXECUTE`, or no flag at all on a CPU without NX (where the bit is reserved and data stays
+/// executable).
+pub fn no_execute() -> PageTableFlags {
+    if crate::cpu::msr::efer().no_execute_enabled() {
+        PageTableFlags::NO_EXECUTE
+    } else {
+        PageTableFlags::empty()
+    }
+}
+
 /// Returns a mutable reference to the active level 4 table.
 ///
 /// This function is unsafe because the caller must guarantee that the
@@ -42,43 +68,133 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut
     &mut *page_table_ptr // unsafe --> return a mutable reference via the raw pointer
 }
 
-/// A FrameAllocator that returns usable frames from the bootloader's memory map.
-pub struct BootInfoFrameAllocator {
-    memory_map: &'static MemoryMap, // the memory map is passed by the BIOS/UEFI on boot --> memory map contains ALL memory regions
+// FIRMWARE MEMORY MAP ================================
+// the frame allocator only needs "which physical ranges are free RAM" --> FirmwareMemoryMap hides where the map comes
+// from: bootloader 0.9's MemoryMap (from the BIOS E820 map) now, UEFI memory descriptors or another bootloader later
+// - a region is a start and a size in bytes, they don't have to be frame aligned (only whole frames inside are used)
+
+/// What a firmware memory map region is used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryKind {
+    /// Free RAM.
+    Usable,
+    /// RAM the bootloader already put something in: the kernel, its stack, page tables, the boot info...
+    InUse,
+    /// ACPI tables, usable once they were read.
+    AcpiReclaimable,
+    /// ACPI non-volatile storage, never usable.
+    AcpiNvs,
+    /// RAM with errors.
+    Bad,
+    /// Reserved by the firmware, or a type we don't know.
+    Reserved,
+}
+
+/// A region of a firmware memory map.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FirmwareRegion {
+    pub start: PhysAddr,
+    /// In bytes.
+    pub size: u64,
+    pub kind: MemoryKind,
+}
+
+/// The physical memory layout handed over at boot, sorted by address.
+pub trait FirmwareMemoryMap {
+    fn regions(&self) -> impl Iterator<Item = FirmwareRegion>;
+}
+
+impl From<MemoryRegionType> for MemoryKind {
+    fn from(region_type: MemoryRegionType) -> Self {
+        match region_type {
+            MemoryRegionType::Usable => MemoryKind::Usable,
+            MemoryRegionType::InUse
+            | MemoryRegionType::Kernel
+            | MemoryRegionType::KernelStack
+            | MemoryRegionType::PageTable
+            | MemoryRegionType::Bootloader
+            | MemoryRegionType::BootInfo
+            | MemoryRegionType::Package => MemoryKind::InUse,
+            MemoryRegionType::AcpiReclaimable => MemoryKind::AcpiReclaimable,
+            MemoryRegionType::AcpiNvs => MemoryKind::AcpiNvs,
+            MemoryRegionType::BadMemory => MemoryKind::Bad,
+            MemoryRegionType::Reserved
+            | MemoryRegionType::FrameZero
+            | MemoryRegionType::Empty
+            | MemoryRegionType::UnknownUefi(_)
+            | MemoryRegionType::UnknownBios(_) => MemoryKind::Reserved,
+        }
+    }
+}
+
+impl FirmwareMemoryMap for &MemoryMap {
+    fn regions(&self) -> impl Iterator<Item = FirmwareRegion> {
+        self.iter().map(|region| FirmwareRegion {
+            start: PhysAddr::new(region.range.start_addr()),
+            size: region.range.end_addr() - region.range.start_addr(),
+            kind: region.region_type.into(),
+        })
+    }
+}
+
+/// A FrameAllocator that returns usable frames from the bootloader's memory map (or any other `FirmwareMemoryMap`).
+pub struct BootInfoFrameAllocator<M: FirmwareMemoryMap = &'static MemoryMap> {
+    memory_map: M, // the memory map is passed by the BIOS/UEFI on boot --> memory map contains ALL memory regions
     next: usize, // number of the next frame that the allocator should return
+    excluded: Option<(PhysAddr, PhysAddr)>, // never handed out even if marked usable, inclusive --> see with_kernel_exclusion()
 }
 
-impl BootInfoFrameAllocator {
+impl<M: FirmwareMemoryMap> BootInfoFrameAllocator<M> {
     /// Create a FrameAllocator from the passed memory map.
     ///
     /// This function is unsafe because the caller must guarantee that the passed
     /// memory map is valid. The main requirement is that all frames that are marked
     /// as `USABLE` in it are really unused.
-    pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
+    pub unsafe fn init(memory_map: M) -> Self {
         BootInfoFrameAllocator {
             memory_map,
             next: 0,
+            excluded: None,
+        }
+    }
+
+    /// Same as `init()`, but frames overlapping `[kernel_start, kernel_end]` are never returned.
+    ///
+    /// For memory maps that mark the frames the kernel was loaded to as usable (bootloader 0.9 marks them as `Kernel`,
+    /// but other loaders don't) --> handing one out would let its new owner overwrite kernel code or data.
+    pub unsafe fn with_kernel_exclusion(memory_map: M, kernel_start: PhysAddr, kernel_end: PhysAddr) -> Self {
+        BootInfoFrameAllocator {
+            excluded: Some((kernel_start, kernel_end)),
+            ..Self::init(memory_map)
         }
     }
 
     /// Returns an iterator over the usable frames specified in the memory map.
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
+    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> + '_ {
         // get usable regions from memory map
-        let regions = self.memory_map.iter();
+        let regions = self.memory_map.regions();
         let usable_regions = regions
-            .filter(|r| r.region_type == MemoryRegionType::Usable);
-        // map each region to its address range 
+            .filter(|r| r.kind == MemoryKind::Usable);
+        // map each region to the address range of the whole frames in it
         let addr_ranges = usable_regions
-            .map(|r| r.range.start_addr()..r.range.end_addr()); // use range syntax
+            .map(|r| x86_64::align_up(r.start.as_u64(), 4096)..x86_64::align_down(r.start.as_u64() + r.size, 4096)); // use range syntax
         // transform to an iterator of frame start addresses
-        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096)); // move 4KiB every iter --> ignoring non-start addresses
+        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096)); // move 4KiB every iter
         // create `PhysFrame` types from the start addresses
-        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr))) // return the frame containing the start address
+        let frames = frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr))); // return the frame containing the start address
+        // skip the excluded range (a frame overlaps it unless it ends before the start or starts after the end)
+        let excluded = self.excluded;
+        let frames = frames.filter(move |frame| match excluded {
+            Some((start, end)) => frame.start_address() + frame.size() <= start || frame.start_address() > end,
+            None => true,
+        });
+        // the application processors start in real mode at a fixed address below 1 MiB (see smp.rs) --> keep that frame free
+        frames.filter(|frame| frame.start_address().as_u64() != crate::smp::TRAMPOLINE_ADDR)
     }
 }
 
 /// Return a usable frame to map to (just return don't actually map it --> do that via .map_to())
-unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
+unsafe impl<M: FirmwareMemoryMap> FrameAllocator<Size4KiB> for BootInfoFrameAllocator<M> {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
         let frame = self.usable_frames().nth(self.next);
         self.next += 1;
@@ -86,6 +202,447 @@ unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     }
 }
 
+// GLOBAL FRAME ALLOCATOR ================================
+
+/// Wraps a `BootInfoFrameAllocator` so that frames can be given back and handed out again.
+///
+/// Freed frames form a linked list that is stored inside the free frames themselves (each one holds the physical
+/// address of the next, reached through the physical memory mapping) --> no heap needed and no size limit.
+pub struct RecyclingFrameAllocator {
+    boot_info_allocator: BootInfoFrameAllocator,
+    physical_memory_offset: VirtAddr,
+    free_list: Option<PhysFrame>, // most recently freed frame
+    allocated: usize, // frames currently handed out (allocations minus deallocations)
+}
+
+impl RecyclingFrameAllocator {
+    /// This function is unsafe for the same reasons as `BootInfoFrameAllocator::init()`, and because the caller
+    /// must guarantee that the complete physical memory is mapped at `physical_memory_offset`.
+    pub unsafe fn new(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) -> Self {
+        RecyclingFrameAllocator {
+            boot_info_allocator: BootInfoFrameAllocator::init(memory_map),
+            physical_memory_offset,
+            free_list: None,
+            allocated: 0,
+        }
+    }
+
+    fn next_free_ptr(&self, frame: PhysFrame) -> *mut u64 {
+        (self.physical_memory_offset + frame.start_address().as_u64()).as_mut_ptr()
+    }
+
+    /// Number of frames that have been allocated and not freed.
+    pub fn allocated_frames(&self) -> usize {
+        self.allocated
+    }
+
+    /// Allocate `count` physically contiguous frames that all lie below `limit`, returns the first one.
+    ///
+    /// Only fresh frames from the memory map are used (freed frames are scattered all over), the ones skipped while
+    /// looking for a long enough run go onto the free list so they are handed out again.
+    pub fn allocate_contiguous(&mut self, count: usize, limit: PhysAddr) -> Option<PhysFrame> {
+        let mut run: Option<(PhysFrame, usize)> = None; // first frame and length of the run so far
+        loop {
+            match run {
+                Some((first, len)) if len == count => {
+                    self.allocated += count;
+                    return Some(first);
+                }
+                _ => {}
+            }
+            // the memory map is sorted --> once a frame lies above the limit all later ones do too
+            let frame = match self.boot_info_allocator.allocate_frame() {
+                Some(frame) if frame.start_address().as_u64() + 4096 <= limit.as_u64() => frame,
+                above_limit => {
+                    self.free_run(run);
+                    if let Some(frame) = above_limit {
+                        unsafe { self.push_free(frame) }; // taken from the memory map, so it's ours to hand out later
+                    }
+                    return None;
+                }
+            };
+            run = match run {
+                Some((first, len)) if first + len as u64 == frame => Some((first, len + 1)),
+                _ => {
+                    self.free_run(run);
+                    Some((frame, 1))
+                }
+            };
+        }
+    }
+
+    // put a run of frames that was never handed out onto the free list
+    fn free_run(&mut self, run: Option<(PhysFrame, usize)>) {
+        if let Some((first, len)) = run {
+            for i in 0..len {
+                unsafe { self.push_free(first + i as u64) };
+            }
+        }
+    }
+
+    unsafe fn push_free(&mut self, frame: PhysFrame) {
+        // physical frame 0 is never usable memory, so 0 can mark the end of the list
+        let next = self.free_list.map_or(0, |f| f.start_address().as_u64());
+        self.next_free_ptr(frame).write(next);
+        self.free_list = Some(frame);
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for RecyclingFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let frame = match self.free_list {
+            Some(frame) => {
+                // pop the head of the free list, the frame itself tells us where the next one is (0 = end of list)
+                let next = unsafe { self.next_free_ptr(frame).read() };
+                self.free_list = match next {
+                    0 => None,
+                    addr => Some(PhysFrame::containing_address(PhysAddr::new(addr))),
+                };
+                frame
+            }
+            None => self.boot_info_allocator.allocate_frame()?,
+        };
+        self.allocated += 1;
+        Some(frame)
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for RecyclingFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        self.push_free(frame);
+        self.allocated -= 1;
+    }
+}
+
+// the kernel's frame allocator, shared by everything that maps memory after boot (heap, processes, drivers...)
+static FRAME_ALLOCATOR: Mutex<Option<RecyclingFrameAllocator>> = Mutex::new(None);
+static PHYSICAL_MEMORY_OFFSET: Once<VirtAddr> = Once::new();
+
+/// Set up the global frame allocator, use `GlobalFrameAllocator` to allocate from it afterwards.
+///
+/// This function is unsafe for the same reasons as `RecyclingFrameAllocator::new()`. It must only be called once,
+/// and no other frame allocator may hand out frames from the same memory map.
+pub unsafe fn init_frame_allocator(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) {
+    PHYSICAL_MEMORY_OFFSET.call_once(|| physical_memory_offset);
+    *FRAME_ALLOCATOR.lock() = Some(RecyclingFrameAllocator::new(memory_map, physical_memory_offset));
+}
+
+/// The type of the bootloader memory map region `addr` is in, `None` if no region covers it.
+///
+/// Panics if `init_frame_allocator()` hasn't been called yet.
+pub fn memory_region_type(addr: PhysAddr) -> Option<MemoryRegionType> {
+    memory_map()
+        .iter()
+        .find(|region| (region.range.start_addr()..region.range.end_addr()).contains(&addr.as_u64()))
+        .map(|region| region.region_type)
+}
+
+/// The bootloader's memory map that `init_frame_allocator()` was given.
+///
+/// Panics if `init_frame_allocator()` hasn't been called yet.
+pub fn memory_map() -> &'static MemoryMap {
+    with_frame_allocator(|allocator| allocator.boot_info_allocator.memory_map)
+}
+
+/// The virtual address the complete physical memory is mapped at (see Cargo.toml, `map_physical_memory`).
+///
+/// Panics if `init_frame_allocator()` hasn't been called yet.
+pub fn physical_memory_offset() -> VirtAddr {
+    *PHYSICAL_MEMORY_OFFSET.r#try().expect("memory::init_frame_allocator() not called")
+}
+
+/// The virtual address a physical address can be accessed at through the physical memory mapping.
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    physical_memory_offset() + addr.as_u64()
+}
+
+/// Whether `is_mapped()` can look at the page tables (`init_frame_allocator()` has been called).
+pub fn can_check_mappings() -> bool {
+    PHYSICAL_MEMORY_OFFSET.r#try().is_some()
+}
+
+/// Whether `addr` is mapped in the active page tables, false if they can't be checked (see `can_check_mappings()`).
+///
+/// Takes no locks and doesn't allocate --> usable in panic handlers, ex. to check a pointer before following it.
+pub fn is_mapped(addr: VirtAddr) -> bool {
+    let offset = match PHYSICAL_MEMORY_OFFSET.r#try() {
+        Some(&offset) => offset,
+        None => return false,
+    };
+    // only read, like a second mapper over the same tables would be
+    let mapper = unsafe { OffsetPageTable::new(active_level_4_table(offset), offset) };
+    mapper.translate_addr(addr).is_some()
+}
+
+// TRANSLATION CACHE ================================
+// remembers the last TRANSLATION_CACHE_SIZE translations, the oldest one is replaced on a miss (round robin)
+// --> with the offset mapping a miss is a single addition, the cache pays off for callers whose translation is more
+// than that (and keeps counts of how often the same addresses come up again)
+
+/// Number of entries in a `TranslationCache`.
+pub const TRANSLATION_CACHE_SIZE: usize = 16;
+
+/// A small cache of physical to virtual address translations for hot paths (ex. receive buffers).
+#[derive(Debug, Clone)]
+pub struct TranslationCache {
+    entries: [(PhysAddr, VirtAddr); TRANSLATION_CACHE_SIZE],
+    len: usize,  // entries in use
+    next: usize, // the entry replaced on the next miss
+    offset: VirtAddr, // the physical memory offset the entries were computed with
+    hits: u64,
+    misses: u64,
+}
+
+impl TranslationCache {
+    pub const fn new() -> Self {
+        TranslationCache {
+            entries: [(PhysAddr::zero(), VirtAddr::zero()); TRANSLATION_CACHE_SIZE],
+            len: 0,
+            next: 0,
+            offset: VirtAddr::zero(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// The virtual address of `phys` through the physical memory mapping at `phys_mem_offset`.
+    pub fn translate(&mut self, phys: PhysAddr, phys_mem_offset: VirtAddr) -> VirtAddr {
+        if phys_mem_offset != self.offset {
+            self.len = 0; // computed for another mapping
+            self.offset = phys_mem_offset;
+        }
+        if let Some(&(_, virt)) = self.entries[..self.len].iter().find(|(cached, _)| *cached == phys) {
+            self.hits += 1;
+            return virt;
+        }
+        self.misses += 1;
+        let virt = phys_mem_offset + phys.as_u64();
+        self.entries[self.next] = (phys, virt);
+        self.next = (self.next + 1) % TRANSLATION_CACHE_SIZE;
+        self.len = (self.len + 1).min(TRANSLATION_CACHE_SIZE);
+        virt
+    }
+
+    /// Lookups answered from the cache.
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    /// Lookups that had to compute the translation.
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+}
+
+impl Default for TranslationCache {
+    fn default() -> Self {
+        TranslationCache::new()
+    }
+}
+
+/// Read a `T` from physical memory through the physical memory mapping (it doesn't have to be aligned).
+///
+/// The memory at `addr` must hold a valid `T` (any bit pattern is valid for integers and byte arrays).
+pub unsafe fn phys_read<T: Copy>(addr: PhysAddr) -> T {
+    phys_to_virt(addr).as_ptr::<T>().read_unaligned()
+}
+
+/// The `len` bytes of physical memory at `addr`, through the physical memory mapping.
+///
+/// Nothing may write to them while the slice is in use (ex. firmware tables, which are never written after boot).
+pub unsafe fn phys_slice(addr: PhysAddr, len: usize) -> &'static [u8] {
+    core::slice::from_raw_parts(phys_to_virt(addr).as_ptr(), len)
+}
+
+fn with_frame_allocator<R>(f: impl FnOnce(&mut RecyclingFrameAllocator) -> R) -> R {
+    // interrupts off so an interrupt handler that maps memory can't deadlock on the lock we are holding
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut allocator = FRAME_ALLOCATOR.lock();
+        f(allocator.as_mut().expect("memory::init_frame_allocator() not called"))
+    })
+}
+
+/// Number of frames handed out by the global frame allocator that haven't been freed.
+pub fn allocated_frame_count() -> usize {
+    with_frame_allocator(|allocator| allocator.allocated_frames())
+}
+
+/// Frame usage of the global frame allocator (see `stats()`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryStats {
+    /// Frames the memory map marks as usable.
+    pub usable_frames: u64,
+    /// Frames handed out and not freed.
+    pub allocated_frames: u64,
+    /// Freed frames waiting on the free list to be handed out again.
+    pub recycled_frames: u64,
+}
+
+/// A snapshot of the global frame allocator's usage (doesn't allocate).
+pub fn stats() -> MemoryStats {
+    with_frame_allocator(|allocator| {
+        let mut recycled_frames = 0;
+        let mut next = allocator.free_list;
+        while let Some(frame) = next {
+            recycled_frames += 1;
+            next = match unsafe { allocator.next_free_ptr(frame).read() } {
+                0 => None,
+                addr => Some(PhysFrame::containing_address(PhysAddr::new(addr))),
+            };
+        }
+        MemoryStats {
+            usable_frames: allocator.boot_info_allocator.usable_frames().count() as u64,
+            allocated_frames: allocator.allocated as u64,
+            recycled_frames,
+        }
+    })
+}
+
+/// Allocate `count` physically contiguous frames below `limit` from the global frame allocator (see `DmaBuffer`).
+pub fn allocate_contiguous_frames(count: usize, limit: PhysAddr) -> Option<PhysFrame> {
+    with_frame_allocator(|allocator| allocator.allocate_contiguous(count, limit))
+}
+
+/// Allocate a stack of `pages` physically contiguous frames, used through the physical memory mapping (no guard page),
+/// returns its top. `None` if there is no free run of frames that long.
+///
+/// Nothing gives the frames back, for stacks that live as long as the kernel (ex. the other CPUs' ones, see smp.rs).
+pub fn allocate_stack(pages: usize) -> Option<VirtAddr> {
+    let first = allocate_contiguous_frames(pages, PhysAddr::new(0x000F_FFFF_FFFF_F000))?; // anywhere in physical memory
+    Some(phys_to_virt(first.start_address()) + pages as u64 * 4096)
+}
+
+/// A handle to the global frame allocator, usable wherever a `FrameAllocator` is expected.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GlobalFrameAllocator;
+
+unsafe impl FrameAllocator<Size4KiB> for GlobalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        with_frame_allocator(|allocator| allocator.allocate_frame())
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for GlobalFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        with_frame_allocator(|allocator| allocator.deallocate_frame(frame))
+    }
+}
+
+// PHYSICAL MAPPINGS ================================
+// device memory outside of RAM (ex. a PCI BAR like a framebuffer) isn't covered by the bootloader's physical memory
+// mapping --> it gets mapped into its own window of virtual memory, each mapping right after the previous one
+
+/// Start of the virtual memory window for `map_physical()`.
+pub const PHYS_MAP_START: u64 = 0x_6666_0000_0000;
+
+static NEXT_PHYS_MAP_PAGE: AtomicU64 = AtomicU64::new(PHYS_MAP_START);
+
+/// Map the `size` bytes of physical memory at `addr` into the physical mappings window with `flags` (PRESENT and
+/// `no_execute()` are always added), returns the virtual address of `addr`.
+///
+/// Mappings stay until `unmap_physical()`. Pass NO_CACHE for device registers, WRITE_THROUGH for memory the device only reads
+/// (ex. a framebuffer: real write-combining would need the PAT to be reprogrammed).
+pub fn map_physical(addr: PhysAddr, size: u64, flags: PageTableFlags) -> Result<VirtAddr, MapToError<Size4KiB>> {
+    let first_frame = PhysFrame::<Size4KiB>::containing_address(addr);
+    let last_frame = PhysFrame::<Size4KiB>::containing_address(addr + size.max(1) - 1u64);
+    let frames = PhysFrame::range_inclusive(first_frame, last_frame);
+    let start = NEXT_PHYS_MAP_PAGE.fetch_add(frames.count() as u64 * 4096, Ordering::Relaxed);
+
+    let flags = flags | PageTableFlags::PRESENT | no_execute();
+    // the kernel's page tables, the boot time mapper isn't kept around
+    let mut mapper = unsafe { OffsetPageTable::new(active_level_4_table(physical_memory_offset()), physical_memory_offset()) };
+    let first_page = Page::<Size4KiB>::containing_address(VirtAddr::new(start));
+    for (i, frame) in PhysFrame::range_inclusive(first_frame, last_frame).enumerate() {
+        unsafe { mapper.map_to(first_page + i as u64, frame, flags, &mut GlobalFrameAllocator)?.flush() };
+    }
+    Ok(VirtAddr::new(start + addr.as_u64() % 4096))
+}
+
+/// Remove the mapping `map_physical()` returned `addr` for (with the same `size`), the physical memory is left alone.
+///
+/// The window's virtual addresses aren't handed out again.
+pub fn unmap_physical(addr: VirtAddr, size: u64) -> Result<(), UnmapError> {
+    let first_page = Page::<Size4KiB>::containing_address(addr);
+    let last_page = Page::<Size4KiB>::containing_address(addr + size.max(1) - 1u64);
+    let mut mapper = unsafe { OffsetPageTable::new(active_level_4_table(physical_memory_offset()), physical_memory_offset()) };
+    for page in Page::range_inclusive(first_page, last_page) {
+        mapper.unmap(page)?.1.flush();
+    }
+    Ok(())
+}
+
+/// Map the `count` pages from `start` to fresh frames of the global frame allocator with `flags` (PRESENT is always
+/// added), in the kernel's page tables.
+///
+/// Fails with `FrameAllocationFailed` before `init_frame_allocator()`. Pages mapped before a failure stay mapped.
+pub fn map_new_pages(start: VirtAddr, count: u64, flags: PageTableFlags) -> Result<(), MapToError<Size4KiB>> {
+    let offset = *PHYSICAL_MEMORY_OFFSET.r#try().ok_or(MapToError::FrameAllocationFailed)?;
+    let mut mapper = unsafe { OffsetPageTable::new(active_level_4_table(offset), offset) };
+    let first_page = Page::<Size4KiB>::containing_address(start);
+    for i in 0..count {
+        let frame = GlobalFrameAllocator.allocate_frame().ok_or(MapToError::FrameAllocationFailed)?;
+        unsafe { mapper.map_to(first_page + i, frame, flags | PageTableFlags::PRESENT, &mut GlobalFrameAllocator)?.flush() };
+    }
+    Ok(())
+}
+
+// FRAME TRACING ================================
+// every frame handed out or given back is written to serial (or any fmt::Write), ex. to find a frame that is freed twice
+// or never freed --> the `frame_trace` feature puts it between the heap and its frame allocator at boot
+
+/// Wraps a frame allocator and logs each allocation ("FRAME_ALLOC: <frame> count=<n>") and free ("FRAME_FREE: <frame>").
+pub struct LoggingFrameAllocator<A, W: fmt::Write = SerialWriter> {
+    inner: A,
+    writer: W,
+    alloc_count: u64,
+    free_count: u64,
+}
+
+impl<A> LoggingFrameAllocator<A> {
+    /// Log to serial.
+    pub fn new(inner: A) -> Self {
+        LoggingFrameAllocator::with_writer(inner, SerialWriter)
+    }
+}
+
+impl<A, W: fmt::Write> LoggingFrameAllocator<A, W> {
+    /// Log to `writer` instead of serial.
+    pub fn with_writer(inner: A, writer: W) -> Self {
+        LoggingFrameAllocator { inner, writer, alloc_count: 0, free_count: 0 }
+    }
+
+    /// Number of frames handed out so far.
+    pub fn alloc_count(&self) -> u64 {
+        self.alloc_count
+    }
+
+    /// Number of frames given back so far.
+    pub fn free_count(&self) -> u64 {
+        self.free_count
+    }
+
+    pub fn writer(&self) -> &W {
+        &self.writer
+    }
+}
+
+unsafe impl<A: FrameAllocator<Size4KiB>, W: fmt::Write> FrameAllocator<Size4KiB> for LoggingFrameAllocator<A, W> {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let frame = self.inner.allocate_frame()?;
+        self.alloc_count += 1;
+        let _ = writeln!(self.writer, "FRAME_ALLOC: {:?} count={}", frame, self.alloc_count); // logging never fails an allocation
+        Some(frame)
+    }
+}
+
+impl<A: FrameDeallocator<Size4KiB>, W: fmt::Write> FrameDeallocator<Size4KiB> for LoggingFrameAllocator<A, W> {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        self.inner.deallocate_frame(frame);
+        self.free_count += 1;
+        let _ = writeln!(self.writer, "FRAME_FREE: {:?}", frame);
+    }
+}
+
 /// Creates an example mapping for the given page to frame `0xb8000`.
 /// TODO: DELETE THIS FUNCTION
 pub fn create_example_mapping(
@@ -103,4 +660,993 @@ pub fn create_example_mapping(
         mapper.map_to(page, frame, flags, frame_allocator)
     };
     map_to_result.expect("map_to failed").flush();
-}
\ No newline at end of file
+}
+
+// RECYCLING MAPPER ================================
+
+/// A mapper that gives back the page tables emptied by unmapping, not just the mapped frames.
+///
+/// Unmapped frames and emptied level 1/level 2 tables go into `recycled`, which `allocate_frame()` hands out again
+/// before asking the global frame allocator. Level 3 tables are never freed: process address spaces copy the
+/// kernel's level 4 entries (see address_space.rs), so one of them may still point at it.
+/// Recycled frames that are left over go back to the global frame allocator on drop.
+pub struct RecyclingMapper {
+    inner: OffsetPageTable<'static>,
+    recycled: Vec<PhysFrame>,
+}
+
+// hands out recycled frames first --> lets map_to() allocate page tables while `inner` is borrowed
+struct RecycledFrames<'a>(&'a mut Vec<PhysFrame>);
+
+unsafe impl FrameAllocator<Size4KiB> for RecycledFrames<'_> {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        self.0.pop().or_else(|| GlobalFrameAllocator.allocate_frame())
+    }
+}
+
+impl RecyclingMapper {
+    pub fn new(inner: OffsetPageTable<'static>) -> Self {
+        RecyclingMapper { inner, recycled: Vec::new() }
+    }
+
+    pub fn mapper(&mut self) -> &mut OffsetPageTable<'static> {
+        &mut self.inner
+    }
+
+    /// Number of frames waiting to be handed out again.
+    pub fn recycled_count(&self) -> usize {
+        self.recycled.len()
+    }
+
+    /// Map `page` to a fresh frame, taking the frame and any new page tables from the recycled frames first.
+    pub fn map(&mut self, page: Page, flags: PageTableFlags) -> Result<PhysFrame, MapToError<Size4KiB>> {
+        let mut frames = RecycledFrames(&mut self.recycled);
+        let frame = frames.allocate_frame().ok_or(MapToError::FrameAllocationFailed)?;
+        match unsafe { self.inner.map_to(page, frame, flags, &mut frames) } {
+            Ok(flush) => {
+                flush.flush();
+                Ok(frame)
+            }
+            Err(error) => {
+                self.recycled.push(frame);
+                Err(error)
+            }
+        }
+    }
+
+    /// Unmap `page`, recycle its frame and free the level 1 and level 2 tables above it if they are now empty.
+    pub fn unmap_and_recycle(&mut self, page: Page) -> Result<(), UnmapError> {
+        let (frame, flush) = self.inner.unmap(page)?;
+        flush.flush();
+        self.recycled.push(frame);
+
+        let offset = self.inner.phys_offset();
+        let table_at = |frame: PhysFrame| -> &'static mut PageTable {
+            unsafe { &mut *(offset + frame.start_address().as_u64()).as_mut_ptr() }
+        };
+        let is_empty = |table: &PageTable| table.iter().all(|entry| entry.is_unused());
+
+        // unmap() succeeded, so every level above the page is present and not a huge page
+        let level_4_table = self.inner.level_4_table();
+        let level_3_frame = level_4_table[page.p4_index()].frame().expect("unmapped page without level 3 table");
+        let level_3_table = table_at(level_3_frame);
+        let level_2_frame = level_3_table[page.p3_index()].frame().expect("unmapped page without level 2 table");
+        let level_2_table = table_at(level_2_frame);
+        let level_1_frame = level_2_table[page.p2_index()].frame().expect("unmapped page without level 1 table");
+
+        if !is_empty(table_at(level_1_frame)) {
+            return Ok(());
+        }
+        level_2_table[page.p2_index()].set_unused();
+        self.recycled.push(level_1_frame);
+
+        if is_empty(level_2_table) {
+            level_3_table[page.p3_index()].set_unused();
+            self.recycled.push(level_2_frame);
+        }
+        // an empty level 3 table stays even then: every process's level 4 table has a copy of the kernel's entry for
+        // it (see address_space.rs), freeing it would leave those pointing at a frame that gets handed out again
+        // the CPU may still cache the table entries we just removed
+        x86_64::instructions::tlb::flush_all();
+        Ok(())
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for RecyclingMapper {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        RecycledFrames(&mut self.recycled).allocate_frame()
+    }
+}
+
+impl Drop for RecyclingMapper {
+    fn drop(&mut self) {
+        for frame in self.recycled.drain(..) {
+            unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+        }
+    }
+}
+
+// PAGE TABLE CONSISTENCY ================================
+// every frame a present page table entry points at has to be in use: a free frame can be handed out again, after which
+// two owners write to it (ex. an unmapped page whose frame was freed while another mapping to it was left behind)
+// the physical memory mapping is the exception, it maps every frame on purpose, free or not
+
+/// A present page table entry pointing at a frame the frame allocator considers free (see `check_page_table_consistency()`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConsistencyError {
+    /// A page table lives in a free frame.
+    PresentEntryInAllocatorFreeList { frame: PhysFrame },
+    /// The page at `virt` is mapped to the free frame at `phys` (once per 4 KiB frame for huge pages).
+    MappedFrameInFreeList { virt: VirtAddr, phys: PhysAddr },
+}
+
+impl RecyclingFrameAllocator {
+    fn free_list_frames(&self) -> BTreeSet<PhysFrame> {
+        let mut frames = BTreeSet::new();
+        let mut next = self.free_list;
+        while let Some(frame) = next {
+            frames.insert(frame);
+            next = match unsafe { self.next_free_ptr(frame).read() } {
+                0 => None,
+                addr => Some(PhysFrame::containing_address(PhysAddr::new(addr))),
+            };
+        }
+        frames
+    }
+}
+
+/// Walk every present entry of `mapper`'s page tables and report the ones pointing at frames `frame_alloc` considers free:
+/// frames on its free list and usable frames it hasn't handed out yet.
+pub fn check_page_table_consistency(
+    mapper: &mut OffsetPageTable,
+    frame_alloc: &RecyclingFrameAllocator,
+) -> Vec<ConsistencyError> {
+    let free_list = frame_alloc.free_list_frames();
+    // the boot info allocator hands out usable frames in ascending order --> everything usable from its next frame on is fresh
+    let boot_info = &frame_alloc.boot_info_allocator;
+    let first_fresh = boot_info.usable_frames().nth(boot_info.next);
+    let is_free = |frame: PhysFrame| {
+        free_list.contains(&frame)
+            || first_fresh.map_or(false, |first| {
+                let addr = frame.start_address().as_u64();
+                frame >= first
+                    && boot_info.memory_map.iter().any(|region| {
+                        region.region_type == MemoryRegionType::Usable
+                            && (region.range.start_addr()..region.range.end_addr()).contains(&addr)
+                    })
+            })
+    };
+
+    let offset = mapper.phys_offset();
+    let mut errors = Vec::new();
+    check_table(mapper.level_4_table(), 4, 0, offset, &is_free, &mut errors);
+    errors
+}
+
+// check the entries of a level `level` table whose first entry maps virtual address `base`
+fn check_table(
+    table: &PageTable,
+    level: u8,
+    base: u64,
+    offset: VirtAddr,
+    is_free: &dyn Fn(PhysFrame) -> bool,
+    errors: &mut Vec<ConsistencyError>,
+) {
+    let entry_size = 4096u64 << (9 * (level - 1)); // how much memory one entry covers
+    for (index, entry) in table.iter().enumerate() {
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        let mut virt = base + index as u64 * entry_size;
+        if level == 4 && index >= 256 {
+            virt |= 0xFFFF_0000_0000_0000; // the upper half is sign extended
+        }
+        let phys = entry.addr();
+
+        if level == 1 || flags.contains(PageTableFlags::HUGE_PAGE) {
+            if virt.wrapping_sub(phys.as_u64()) == offset.as_u64() {
+                continue; // part of the physical memory mapping
+            }
+            for frame_offset in (0..entry_size).step_by(4096) {
+                if is_free(PhysFrame::containing_address(phys + frame_offset)) {
+                    errors.push(ConsistencyError::MappedFrameInFreeList {
+                        virt: VirtAddr::new(virt + frame_offset),
+                        phys: phys + frame_offset,
+                    });
+                }
+            }
+        } else {
+            let frame = PhysFrame::containing_address(phys);
+            if is_free(frame) {
+                errors.push(ConsistencyError::PresentEntryInAllocatorFreeList { frame });
+            }
+            let child = unsafe { &*(offset + phys.as_u64()).as_ptr::<PageTable>() };
+            check_table(child, level - 1, virt, offset, is_free, errors);
+        }
+    }
+}
+
+/// Run `check_page_table_consistency()` on the active page tables and the global frame allocator.
+pub fn check_active_page_tables() -> Vec<ConsistencyError> {
+    // a second OffsetPageTable over the active tables is fine, it is only read
+    let mut mapper = unsafe { init(physical_memory_offset()) };
+    with_frame_allocator(|allocator| check_page_table_consistency(&mut mapper, allocator))
+}
+
+// MAPPED REGIONS ================================
+// walks the page tables in address order and merges neighbouring pages into one region as long as they continue both the
+// virtual and the physical range with the same flags --> the physical memory mapping shows up as one big region
+
+/// A run of mapped memory, see `MappedRegions::mapped_regions()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MappedRegion {
+    pub virt_start: VirtAddr,
+    pub phys_start: PhysAddr,
+    /// In bytes.
+    pub size: usize,
+    /// The flags of the entries mapping the region, without the ones the CPU or the page size decide (accessed, dirty,
+    /// huge page).
+    pub flags: PageTableFlags,
+}
+
+/// Enumerating the mappings of a page table.
+pub trait MappedRegions {
+    /// Iterate over all mapped regions, lowest virtual address first (the upper half comes after the lower half).
+    ///
+    /// Takes `&mut self` because `OffsetPageTable::level_4_table()` does, the tables are only read.
+    fn mapped_regions(&mut self) -> MappedRegionIter<'_>;
+}
+
+impl MappedRegions for OffsetPageTable<'_> {
+    fn mapped_regions(&mut self) -> MappedRegionIter<'_> {
+        let offset = self.phys_offset();
+        MappedRegionIter {
+            level_4: self.level_4_table(),
+            offset,
+            l4_idx: 0,
+            l3_idx: 0,
+            l2_idx: 0,
+            l1_idx: 0,
+            current_start: None,
+            current_phys: None,
+            current_size: 0,
+            current_flags: PageTableFlags::empty(),
+        }
+    }
+}
+
+/// Iterator over the regions of a page table, from `MappedRegions::mapped_regions()`.
+pub struct MappedRegionIter<'a> {
+    level_4: &'a PageTable,
+    offset: VirtAddr,
+    // the next entry to look at in each level
+    l4_idx: usize,
+    l3_idx: usize,
+    l2_idx: usize,
+    l1_idx: usize,
+    // the region being merged
+    current_start: Option<VirtAddr>,
+    current_phys: Option<PhysAddr>,
+    current_size: u64,
+    current_flags: PageTableFlags,
+}
+
+impl<'a> MappedRegionIter<'a> {
+    fn table(&self, addr: PhysAddr) -> &'a PageTable {
+        unsafe { &*(self.offset + addr.as_u64()).as_ptr::<PageTable>() }
+    }
+
+    fn virt(&self, l3_idx: usize, l2_idx: usize, l1_idx: usize) -> VirtAddr {
+        let addr = (self.l4_idx << 39) | (l3_idx << 30) | (l2_idx << 21) | (l1_idx << 12);
+        VirtAddr::new_truncate(addr as u64) // sign extends the upper half
+    }
+
+    // the next present leaf entry (a 4 KiB page or a huge page) as (virtual address, physical address, size, flags)
+    fn next_leaf(&mut self) -> Option<(VirtAddr, PhysAddr, u64, PageTableFlags)> {
+        while self.l4_idx < 512 {
+            let l4_entry = &self.level_4[self.l4_idx];
+            if !l4_entry.flags().contains(PageTableFlags::PRESENT) || self.l3_idx >= 512 {
+                self.l4_idx += 1;
+                (self.l3_idx, self.l2_idx, self.l1_idx) = (0, 0, 0);
+                continue;
+            }
+            let l3_entry = &self.table(l4_entry.addr())[self.l3_idx];
+            if !l3_entry.flags().contains(PageTableFlags::PRESENT) || self.l2_idx >= 512 {
+                self.l3_idx += 1;
+                (self.l2_idx, self.l1_idx) = (0, 0);
+                continue;
+            }
+            if l3_entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                let leaf = (self.virt(self.l3_idx, 0, 0), l3_entry.addr(), 1 << 30, l3_entry.flags());
+                self.l3_idx += 1;
+                return Some(leaf);
+            }
+            let l2_entry = &self.table(l3_entry.addr())[self.l2_idx];
+            if !l2_entry.flags().contains(PageTableFlags::PRESENT) || self.l1_idx >= 512 {
+                self.l2_idx += 1;
+                self.l1_idx = 0;
+                continue;
+            }
+            if l2_entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                let leaf = (self.virt(self.l3_idx, self.l2_idx, 0), l2_entry.addr(), 1 << 21, l2_entry.flags());
+                self.l2_idx += 1;
+                return Some(leaf);
+            }
+            let l1_entry = &self.table(l2_entry.addr())[self.l1_idx];
+            let leaf = (self.virt(self.l3_idx, self.l2_idx, self.l1_idx), l1_entry.addr(), 1 << 12, l1_entry.flags());
+            self.l1_idx += 1;
+            if leaf.3.contains(PageTableFlags::PRESENT) {
+                return Some(leaf);
+            }
+        }
+        None
+    }
+
+    fn take_current(&mut self) -> Option<MappedRegion> {
+        Some(MappedRegion {
+            virt_start: self.current_start.take()?,
+            phys_start: self.current_phys.take()?,
+            size: self.current_size as usize,
+            flags: self.current_flags,
+        })
+    }
+}
+
+impl Iterator for MappedRegionIter<'_> {
+    type Item = MappedRegion;
+
+    fn next(&mut self) -> Option<MappedRegion> {
+        let ignored = PageTableFlags::ACCESSED | PageTableFlags::DIRTY | PageTableFlags::HUGE_PAGE;
+        while let Some((virt, phys, size, flags)) = self.next_leaf() {
+            let flags = flags - ignored;
+            if let (Some(start), Some(start_phys)) = (self.current_start, self.current_phys) {
+                // raw addresses: the end of a region may be non-canonical (ex. the end of the lower half)
+                let continues = start.as_u64().wrapping_add(self.current_size) == virt.as_u64()
+                    && start_phys.as_u64() + self.current_size == phys.as_u64()
+                    && self.current_flags == flags;
+                if continues {
+                    self.current_size += size;
+                    continue;
+                }
+            }
+            let finished = self.take_current();
+            (self.current_start, self.current_phys) = (Some(virt), Some(phys));
+            (self.current_size, self.current_flags) = (size, flags);
+            if finished.is_some() {
+                return finished;
+            }
+        }
+        self.take_current()
+    }
+}
+
+// VOLATILE COPIES ================================
+// memory shared with a device (DMA buffers, MMIO) has to be written exactly as the code says: core::ptr::copy/write_bytes
+// turn into memcpy/memset, which the compiler may merge, reorder against other accesses or drop when it thinks nobody reads
+// the memory afterwards --> these do every access one at a time with read_volatile/write_volatile instead
+
+/// Copy `len` bytes from `src` to `dst` one byte at a time with volatile accesses.
+///
+/// # Safety
+/// `src` has to be valid for `len` byte reads and `dst` for `len` byte writes. The ranges may overlap only if `dst <= src`.
+pub unsafe fn volatile_copy_to(dst: *mut u8, src: *const u8, len: usize) {
+    for i in 0..len {
+        core::ptr::write_volatile(dst.add(i), core::ptr::read_volatile(src.add(i)));
+    }
+}
+
+/// Set `len` bytes at `dst` to `val` one byte at a time with volatile writes.
+///
+/// # Safety
+/// `dst` has to be valid for `len` byte writes.
+pub unsafe fn volatile_set(dst: *mut u8, val: u8, len: usize) {
+    for i in 0..len {
+        core::ptr::write_volatile(dst.add(i), val);
+    }
+}
+
+/// Zero `len` bytes at `dst` one byte at a time with volatile writes.
+///
+/// # Safety
+/// `dst` has to be valid for `len` byte writes.
+pub unsafe fn volatile_zero(dst: *mut u8, len: usize) {
+    volatile_set(dst, 0, len);
+}
+
+/// Copy `count` 32 bit words from `src` to `dst` with volatile accesses (for registers that only accept whole words).
+///
+/// # Safety
+/// `src` has to be valid for `count` aligned u32 reads and `dst` for `count` aligned u32 writes, the ranges must not overlap.
+pub unsafe fn volatile_copy_nonoverlapping_u32(dst: *mut u32, src: *const u32, count: usize) {
+    for i in 0..count {
+        core::ptr::write_volatile(dst.add(i), core::ptr::read_volatile(src.add(i)));
+    }
+}
+
+// MEMORY MAP PRINTING ================================
+
+// whether a region counts as usable in the totals, everything else (firmware, ACPI, bad memory, and whatever the
+// bootloader already put in memory for us) counts as reserved
+fn is_usable(region_type: MemoryRegionType) -> bool {
+    match region_type {
+        MemoryRegionType::Usable => true,
+        MemoryRegionType::InUse
+        | MemoryRegionType::Reserved
+        | MemoryRegionType::AcpiReclaimable
+        | MemoryRegionType::AcpiNvs
+        | MemoryRegionType::BadMemory
+        | MemoryRegionType::Kernel
+        | MemoryRegionType::KernelStack
+        | MemoryRegionType::PageTable
+        | MemoryRegionType::Bootloader
+        | MemoryRegionType::FrameZero
+        | MemoryRegionType::Empty
+        | MemoryRegionType::BootInfo
+        | MemoryRegionType::Package
+        | MemoryRegionType::UnknownUefi(_)
+        | MemoryRegionType::UnknownBios(_) => false,
+    }
+}
+
+// one line per region, then the totals
+fn write_memory_map(out: &mut impl fmt::Write, regions: &[MemoryRegion]) -> fmt::Result {
+    let (mut usable, mut reserved) = (0u64, 0u64);
+    for region in regions {
+        let (start, end) = (region.range.start_addr(), region.range.end_addr());
+        let size = end - start;
+        writeln!(out, "[0x{:016x} - 0x{:016x}] {:?} ({} KiB)", start, end, region.region_type, size / 1024)?;
+        if is_usable(region.region_type) {
+            usable += size;
+        } else {
+            reserved += size;
+        }
+    }
+    writeln!(out, "Total usable: {} MiB, Total reserved: {} MiB", usable / (1024 * 1024), reserved / (1024 * 1024))
+}
+
+/// `serial_print!` as a `fmt::Write`, needs no heap (the memory map is printed before the heap exists).
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SerialWriter;
+
+impl fmt::Write for SerialWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        crate::serial_print!("{}", s);
+        Ok(())
+    }
+}
+
+/// Print every region of the bootloader's memory map and the usable/reserved totals to serial.
+pub fn print_memory_map(map: &MemoryMap) {
+    write_memory_map(&mut SerialWriter, map).expect("printing to serial failed");
+}
+
+// SHELL COMMANDS ================================
+
+/// A number of bytes printed with a binary unit and one decimal ("512 B", "1.5 KiB", "3.0 GiB"), honors the width and
+/// alignment of the format string (`{:>10}`) without allocating.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteSize(pub u64);
+
+impl fmt::Display for ByteSize {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use fmt::Write;
+
+        const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+        // the longest text is "16777215.9 TiB" (u64::MAX)
+        struct Text([u8; 16], usize);
+        impl fmt::Write for Text {
+            fn write_str(&mut self, s: &str) -> fmt::Result {
+                let end = self.1 + s.len();
+                self.0.get_mut(self.1..end).ok_or(fmt::Error)?.copy_from_slice(s.as_bytes());
+                self.1 = end;
+                Ok(())
+            }
+        }
+
+        let mut text = Text([0; 16], 0);
+        if self.0 < 1024 {
+            write!(text, "{} B", self.0)?;
+        } else {
+            let exponent = ((63 - self.0.leading_zeros()) / 10).min(UNITS.len() as u32);
+            let tenths = (u128::from(self.0) * 10) >> (10 * exponent);
+            write!(text, "{}.{} {}", tenths / 10, tenths % 10, UNITS[exponent as usize - 1])?;
+        }
+        f.pad(core::str::from_utf8(&text.0[..text.1]).map_err(|_| fmt::Error)?)
+    }
+}
+
+/// Add `meminfo` and the memory inspection commands (see inspect.rs) to the kernel shell, once the heap is up.
+pub fn init_shell_commands() {
+    crate::kshell::register_command("meminfo", "memory, frame and heap usage (-v: fragmentation, mappings)", meminfo_command)
+        .expect("meminfo registered twice");
+    inspect::init_shell_commands();
+}
+
+// the label column of meminfo, the values are lined up after it
+const LABEL_WIDTH: usize = 12;
+
+// one letter per flag that matters for a mapping, '-' if it isn't set
+fn flag_letters(flags: PageTableFlags) -> [char; 4] {
+    [
+        if flags.contains(PageTableFlags::WRITABLE) { 'w' } else { '-' },
+        if flags.contains(PageTableFlags::NO_EXECUTE) { '-' } else { 'x' },
+        if flags.contains(PageTableFlags::USER_ACCESSIBLE) { 'u' } else { '-' },
+        if flags.contains(PageTableFlags::GLOBAL) { 'g' } else { '-' },
+    ]
+}
+
+// every line fits the 80 column VGA screen
+fn meminfo_command(args: &[&str]) -> Result<(), crate::kshell::ShellError> {
+    use crate::allocator::{self, fixed_size_block::BLOCK_SIZES};
+
+    let verbose = match args {
+        [] => false,
+        ["-v"] => true,
+        _ => return Err(crate::kshell::ShellError::Usage("meminfo [-v]")),
+    };
+
+    let (mut total, mut usable) = (0u64, 0u64);
+    for region in memory_map().iter() {
+        let size = region.range.end_addr() - region.range.start_addr();
+        total += size;
+        if is_usable(region.region_type) {
+            usable += size;
+        }
+    }
+    let frames = stats();
+    let free_frames = frames.usable_frames.saturating_sub(frames.allocated_frames);
+    let heap = allocator::stats();
+    let caches = allocator::heap_caches();
+    let w = LABEL_WIDTH;
+
+    crate::println!(
+        "{:<w$}total {:>10}   usable {:>10}   reserved {:>10}",
+        "memory",
+        ByteSize(total),
+        ByteSize(usable),
+        ByteSize(total - usable)
+    );
+    crate::println!(
+        "{:<w$}usable {:>9}   allocated {:>7}   free {:>14}",
+        "frames", frames.usable_frames, frames.allocated_frames, free_frames
+    );
+    crate::println!(
+        "{:<w$}size {:>11}   in use {:>10}   peak {:>14}",
+        "heap",
+        ByteSize(heap.heap_size),
+        ByteSize(heap.bytes_in_use),
+        ByteSize(allocator::heap_high_water_mark() as u64)
+    );
+    // without heap_growth the heap is mapped in one go by init_heap() and never grows
+    #[cfg(not(feature = "heap_growth"))]
+    crate::println!("{:<w$}{} mapped (fixed size, no growth)", "heap pages", heap.heap_size / 4096);
+    #[cfg(feature = "heap_growth")]
+    crate::println!(
+        "{:<w$}{} mapped (grows up to {}, {} pages)",
+        "heap pages",
+        heap.heap_size / 4096,
+        ByteSize(allocator::MAX_HEAP_SIZE as u64),
+        allocator::MAX_HEAP_SIZE / 4096
+    );
+    crate::println!(
+        "{:<w$}{} allocations, {} frees, {} failed",
+        "heap calls", heap.allocations, heap.deallocations, heap.failed_allocations
+    );
+    crate::print!("{:<w$}", "size class");
+    for size in BLOCK_SIZES {
+        crate::print!("{:>6}", size);
+    }
+    crate::println!();
+    crate::print!("{:<w$}", "free blocks");
+    for count in caches.free_blocks {
+        crate::print!("{:>6}", count);
+    }
+    crate::println!();
+
+    if verbose {
+        // the free blocks only serve their own size class, the fallback serves anything --> the more of the free heap
+        // sits in the lists, the sooner a big allocation fails although there is enough free memory
+        let free = caches.cached_bytes() + caches.fallback_free;
+        let permille = (caches.cached_bytes() * 1000).checked_div(free).unwrap_or(0);
+        crate::println!(
+            "{:<w$}{} in free blocks + {} fallback = {}.{}% cached",
+            "fragmented",
+            ByteSize(caches.cached_bytes() as u64),
+            ByteSize(caches.fallback_free as u64),
+            permille / 10,
+            permille % 10
+        );
+        crate::println!("{:<w$}{} frames on the free list", "recycled", frames.recycled_frames);
+        crate::println!("regions     {:<18} {:<18} {:>10}  flags", "virtual", "physical");
+        // a second OffsetPageTable over the active tables is fine, it is only read
+        let mut mapper = unsafe { init(physical_memory_offset()) };
+        for region in mapper.mapped_regions() {
+            let [write, execute, user, global] = flag_letters(region.flags);
+            crate::println!(
+                "{:<w$}{:#018x} {:#018x} {:>10}  {}{}{}{}",
+                "",
+                region.virt_start.as_u64(),
+                region.phys_start.as_u64(),
+                ByteSize(region.size as u64),
+                write,
+                execute,
+                user,
+                global
+            );
+        }
+    }
+    Ok(())
+}
+
+// TESTS ===================================
+
+#[test_case]
+fn test_boot_info_allocator_kernel_exclusion() {
+    use alloc::boxed::Box;
+    use bootloader::bootinfo::FrameRange;
+
+    let mut map = MemoryMap::new();
+    map.add_region(MemoryRegion { range: FrameRange::new(0x10000, 0x20000), region_type: MemoryRegionType::Usable });
+    let map: &'static MemoryMap = Box::leak(Box::new(map));
+
+    // 4 frames, the range doesn't have to be frame aligned
+    let (kernel_start, kernel_end) = (PhysAddr::new(0x14800), PhysAddr::new(0x17fff));
+    let mut allocator = unsafe { BootInfoFrameAllocator::with_kernel_exclusion(map, kernel_start, kernel_end) };
+    let frames: Vec<PhysFrame> = core::iter::from_fn(|| allocator.allocate_frame()).collect();
+    assert_eq!(frames.len(), 16 - 4);
+    for frame in &frames {
+        let (start, end) = (frame.start_address(), frame.start_address() + 0xfffu64);
+        assert!(end < kernel_start || start > kernel_end, "{:?} overlaps the kernel", frame);
+    }
+    assert_eq!(unsafe { BootInfoFrameAllocator::init(map) }.usable_frames().count(), 16);
+}
+
+// a firmware memory map that is just a list
+#[cfg(test)]
+struct MockFirmwareMap(Vec<FirmwareRegion>);
+
+#[cfg(test)]
+impl FirmwareMemoryMap for MockFirmwareMap {
+    fn regions(&self) -> impl Iterator<Item = FirmwareRegion> {
+        self.0.iter().copied()
+    }
+}
+
+#[test_case]
+fn test_frames_from_a_firmware_map() {
+    let region = |start: u64, size: u64, kind| FirmwareRegion { start: PhysAddr::new(start), size, kind };
+    let map = MockFirmwareMap(alloc::vec![
+        region(0, 0x1000, MemoryKind::Reserved),
+        region(0x10000, 0x3000, MemoryKind::Usable),
+        region(0x13000, 0x1000, MemoryKind::AcpiNvs),
+        // not frame aligned at either end --> only the frame at 0x21000 is whole
+        region(0x20800, 0x1900, MemoryKind::Usable),
+        region(0x30000, 0x1000, MemoryKind::InUse),
+        region(0x40000, 0x2000, MemoryKind::Usable),
+    ]);
+    let allocator: BootInfoFrameAllocator<MockFirmwareMap> = unsafe { BootInfoFrameAllocator::init(map) };
+    let starts: Vec<u64> = allocator.usable_frames().map(|frame| frame.start_address().as_u64()).collect();
+    assert_eq!(starts, [0x10000, 0x11000, 0x12000, 0x21000, 0x40000, 0x41000]);
+
+    // the bridge to the bootloader's map keeps the sizes and kinds
+    use alloc::boxed::Box;
+    use bootloader::bootinfo::FrameRange;
+    let mut map = MemoryMap::new();
+    map.add_region(MemoryRegion { range: FrameRange::new(0x10000, 0x20000), region_type: MemoryRegionType::Usable });
+    map.add_region(MemoryRegion { range: FrameRange::new(0x20000, 0x21000), region_type: MemoryRegionType::Kernel });
+    let map: &'static MemoryMap = Box::leak(Box::new(map));
+    let regions: Vec<FirmwareRegion> = map.regions().collect();
+    assert_eq!(regions, [region(0x10000, 0x10000, MemoryKind::Usable), region(0x20000, 0x1000, MemoryKind::InUse)]);
+}
+
+#[test_case]
+fn test_no_execute_and_write_protection() {
+    use x86_64::structures::idt::PageFaultErrorCode;
+
+    // a `ret`, mapped read-only --> map_physical() always adds NO_EXECUTE (if the CPU has it, QEMU's CPUs do)
+    let frame = GlobalFrameAllocator.allocate_frame().expect("no free frame");
+    unsafe { phys_to_virt(frame.start_address()).as_mut_ptr::<u8>().write_volatile(0xc3) };
+    let page = map_physical(frame.start_address(), 4096, PageTableFlags::empty()).unwrap();
+
+    if crate::cpu::msr::nx_supported() {
+        crate::expect_page_fault(PageFaultErrorCode::INSTRUCTION_FETCH | PageFaultErrorCode::PROTECTION_VIOLATION, || {
+            let ret: extern "C" fn() = unsafe { core::mem::transmute(page.as_u64() as usize) };
+            ret();
+        });
+    }
+    crate::expect_page_fault(PageFaultErrorCode::CAUSED_BY_WRITE | PageFaultErrorCode::PROTECTION_VIOLATION, || unsafe {
+        page.as_mut_ptr::<u8>().write_volatile(0);
+    });
+    assert_eq!(unsafe { page.as_ptr::<u8>().read_volatile() }, 0xc3, "readable and unchanged");
+}
+
+#[test_case]
+fn test_translation_cache() {
+    let offset = VirtAddr::new(0x1000_0000_0000);
+    let mut cache = TranslationCache::new();
+    let phys = PhysAddr::new(0xb8000);
+    for _ in 0..1000 {
+        assert_eq!(cache.translate(phys, offset), offset + 0xb8000u64);
+    }
+    assert_eq!((cache.misses(), cache.hits()), (1, 999));
+
+    // 16 other addresses push it out, then it is a miss again
+    for i in 1..=TRANSLATION_CACHE_SIZE as u64 {
+        cache.translate(PhysAddr::new(i * 0x1000), offset);
+    }
+    assert_eq!(cache.misses(), 1 + TRANSLATION_CACHE_SIZE as u64);
+    cache.translate(phys, offset);
+    assert_eq!(cache.misses(), 2 + TRANSLATION_CACHE_SIZE as u64);
+
+    // nothing cached for another offset is used
+    let other = VirtAddr::new(0x2000_0000_0000);
+    assert_eq!(cache.translate(phys, other), other + 0xb8000u64);
+    assert_eq!(cache.misses(), 3 + TRANSLATION_CACHE_SIZE as u64);
+}
+
+#[test_case]
+fn test_logging_frame_allocator() {
+    use alloc::{format, string::String};
+
+    let mut logging = LoggingFrameAllocator::with_writer(GlobalFrameAllocator, String::new());
+    let frames: Vec<PhysFrame> = (0..5).map(|_| logging.allocate_frame().expect("out of frames")).collect();
+    let lines: Vec<&str> = logging.writer().lines().collect();
+    assert_eq!(lines.len(), 5);
+    for (i, (line, frame)) in lines.iter().zip(&frames).enumerate() {
+        assert_eq!(*line, format!("FRAME_ALLOC: {:?} count={}", frame, i + 1));
+    }
+    let distinct: BTreeSet<PhysFrame> = frames.iter().copied().collect();
+    assert_eq!(distinct.len(), 5, "a frame was handed out twice");
+
+    for &frame in &frames {
+        unsafe { logging.deallocate_frame(frame) };
+    }
+    assert_eq!((logging.alloc_count(), logging.free_count()), (5, 5));
+    assert_eq!(logging.writer().lines().filter(|line| line.starts_with("FRAME_FREE: ")).count(), 5);
+}
+
+#[test_case]
+fn test_volatile_copy_and_set() {
+    let src: [u8; 37] = core::array::from_fn(|i| i as u8 * 3);
+    let mut dst = [0u8; 37];
+    unsafe { volatile_copy_to(dst.as_mut_ptr(), src.as_ptr(), src.len()) };
+    assert_eq!(dst, src);
+
+    unsafe { volatile_set(dst.as_mut_ptr().add(5), 0xAB, 10) };
+    assert!(dst[..5] == src[..5] && dst[15..] == src[15..]);
+    assert!(dst[5..15].iter().all(|&byte| byte == 0xAB));
+
+    let src_words = [0xdead_beefu32, 0x0123_4567, 0x89ab_cdef];
+    let mut dst_words = [0u32; 3];
+    unsafe { volatile_copy_nonoverlapping_u32(dst_words.as_mut_ptr(), src_words.as_ptr(), 3) };
+    assert_eq!(dst_words, src_words);
+}
+
+#[test_case]
+fn test_volatile_zero_is_not_elided() {
+    // zeroing a buffer that is never read again is a dead store --> write_bytes/memset may be removed, but the volatile writes can't be
+    let mut secret = [0x5Au8; 64];
+    let ptr = secret.as_mut_ptr();
+    unsafe {
+        volatile_zero(ptr, 64);
+        for i in 0..64 {
+            assert_eq!(core::ptr::read_volatile(ptr.add(i)), 0);
+        }
+    }
+}
+
+#[test_case]
+fn test_recycling_mapper_reclaims_page_table() {
+    // 2 MiB aligned, in the same 1 GiB as the heap --> shares the heap's level 2 table but needs its own level 1 table
+    const REGION_START: u64 = 0x_4444_5000_0000;
+    let start = Page::<Size4KiB>::containing_address(VirtAddr::new(REGION_START));
+    let pages = Page::range(start, start + 512); // exactly one level 1 table
+
+    let baseline = allocated_frame_count();
+    // a second OffsetPageTable over the active tables is fine here, the test is the only one using it
+    let mut mapper = RecyclingMapper::new(unsafe { init(physical_memory_offset()) });
+    for page in pages {
+        mapper.map(page, PageTableFlags::PRESENT | PageTableFlags::WRITABLE).expect("map failed");
+    }
+    assert_eq!(allocated_frame_count(), baseline + 513);
+
+    for page in pages {
+        mapper.unmap_and_recycle(page).expect("unmap failed");
+    }
+    // 512 frames + the level 1 table, the level 2 table still maps the heap (and level 3 tables are never freed)
+    assert_eq!(mapper.recycled_count(), 513);
+
+    // all of them are handed out again before the global allocator is asked for more
+    let frames: Vec<PhysFrame> = (0..513).map(|_| mapper.allocate_frame().expect("allocation failed")).collect();
+    assert_eq!(allocated_frame_count(), baseline + 513);
+    assert_eq!(mapper.recycled_count(), 0);
+
+    for frame in frames {
+        unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+    }
+    drop(mapper);
+    assert_eq!(allocated_frame_count(), baseline);
+}
+
+#[test_case]
+fn test_recycling_mapper_reuses_frame() {
+    // its own 2 MiB, next to test_recycling_mapper_reclaims_page_table()'s
+    const REGION_START: u64 = 0x_4444_5020_0000;
+    let first = Page::<Size4KiB>::containing_address(VirtAddr::new(REGION_START));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+
+    let baseline = allocated_frame_count();
+    let mut mapper = RecyclingMapper::new(unsafe { init(physical_memory_offset()) });
+    let first_frame = mapper.map(first, flags).expect("map failed");
+    mapper.map(first + 1, flags).expect("map failed"); // keeps the level 1 table in use
+    let mapped = allocated_frame_count();
+
+    mapper.unmap_and_recycle(first).expect("unmap failed");
+    assert_eq!(mapper.recycled_count(), 1);
+    // the next page gets the frame the first one had, not a new one
+    assert_eq!(mapper.map(first + 2, flags).expect("map failed"), first_frame);
+    assert_eq!(mapper.recycled_count(), 0);
+    assert_eq!(allocated_frame_count(), mapped);
+
+    mapper.unmap_and_recycle(first + 1).expect("unmap failed");
+    mapper.unmap_and_recycle(first + 2).expect("unmap failed");
+    drop(mapper);
+    assert_eq!(allocated_frame_count(), baseline);
+}
+
+#[test_case]
+fn test_page_table_consistency() {
+    assert_eq!(check_active_page_tables(), Vec::new());
+
+    // map a page to a frame, then free the frame behind the mapping's back
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(0x_4444_6000_0000));
+    let frame = GlobalFrameAllocator.allocate_frame().expect("out of frames");
+    let mut mapper = unsafe { init(physical_memory_offset()) };
+    unsafe { mapper.map_to(page, frame, PageTableFlags::PRESENT, &mut GlobalFrameAllocator) }.expect("map failed").flush();
+    unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+
+    let errors = check_active_page_tables();
+    assert_eq!(errors, [ConsistencyError::MappedFrameInFreeList {
+        virt: page.start_address(),
+        phys: frame.start_address(),
+    }]);
+
+    // the frame stays free, only the mapping goes away
+    mapper.unmap(page).expect("unmap failed").1.flush();
+    assert_eq!(check_active_page_tables(), Vec::new());
+}
+
+#[test_case]
+fn test_allocate_contiguous_keeps_frames_above_the_limit() {
+    // every frame is above a limit of 0 --> the first one tried goes onto the free list instead of getting lost
+    let before = stats();
+    assert_eq!(allocate_contiguous_frames(1, PhysAddr::new(0)), None);
+    let after = stats();
+    assert_eq!(after.allocated_frames, before.allocated_frames);
+    assert_eq!(after.recycled_frames, before.recycled_frames + 1);
+}
+
+#[test_case]
+fn test_mapped_regions_coalesce() {
+    let start = Page::<Size4KiB>::containing_address(VirtAddr::new(0x_5555_0000_0000));
+    let frames = allocate_contiguous_frames(4, PhysAddr::new(dma::DMA32_LIMIT)).expect("out of contiguous memory");
+    let writable = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let mut mapper = unsafe { init(physical_memory_offset()) };
+    // three pages with the same flags, the fourth read only (physically contiguous all along)
+    for i in 0..4u64 {
+        let flags = if i < 3 { writable } else { PageTableFlags::PRESENT };
+        unsafe { mapper.map_to(start + i, frames + i, flags, &mut GlobalFrameAllocator) }.expect("map failed").flush();
+    }
+
+    let end = start.start_address() + 4 * 4096u64;
+    let regions: Vec<MappedRegion> = mapper
+        .mapped_regions()
+        .filter(|region| region.virt_start >= start.start_address() && region.virt_start < end)
+        .collect();
+    assert_eq!(regions, [
+        MappedRegion {
+            virt_start: start.start_address(),
+            phys_start: frames.start_address(),
+            size: 3 * 4096,
+            flags: writable,
+        },
+        MappedRegion {
+            virt_start: (start + 3).start_address(),
+            phys_start: (frames + 3).start_address(),
+            size: 4096,
+            flags: PageTableFlags::PRESENT,
+        },
+    ]);
+
+    for i in 0..4u64 {
+        mapper.unmap(start + i).expect("unmap failed").1.flush();
+        unsafe { GlobalFrameAllocator.deallocate_frame(frames + i) };
+    }
+}
+
+#[test_case]
+fn test_memory_map_formatting() {
+    use alloc::string::String;
+    use bootloader::bootinfo::FrameRange;
+
+    let regions = [
+        MemoryRegion { range: FrameRange::new(0x0, 0x1000), region_type: MemoryRegionType::FrameZero },
+        MemoryRegion { range: FrameRange::new(0x1000, 0x9f000), region_type: MemoryRegionType::Usable },
+        MemoryRegion { range: FrameRange::new(0x100000, 0x300000), region_type: MemoryRegionType::Kernel },
+        MemoryRegion { range: FrameRange::new(0x400000, 0x2400000), region_type: MemoryRegionType::Usable },
+        MemoryRegion { range: FrameRange::new(0xfffc0000, 0x100000000), region_type: MemoryRegionType::Reserved },
+    ];
+    let mut out = String::new();
+    write_memory_map(&mut out, &regions).expect("formatting failed");
+
+    let lines: alloc::vec::Vec<&str> = out.lines().collect();
+    assert_eq!(lines.len(), 6);
+    assert_eq!(lines[1], "[0x0000000000001000 - 0x000000000009f000] Usable (632 KiB)");
+    assert_eq!(lines[4], "[0x00000000fffc0000 - 0x0000000100000000] Reserved (256 KiB)");
+    assert!(lines[2].contains("0x0000000000100000") && lines[2].contains("Kernel (2048 KiB)"));
+    // 632 KiB + 32 MiB usable, 4 KiB + 2 MiB + 256 KiB reserved (rounded down)
+    assert_eq!(lines[5], "Total usable: 32 MiB, Total reserved: 2 MiB");
+}
+
+#[test_case]
+fn test_byte_size() {
+    use alloc::format;
+
+    assert_eq!(format!("{}", ByteSize(0)), "0 B");
+    assert_eq!(format!("{}", ByteSize(1023)), "1023 B");
+    assert_eq!(format!("{}", ByteSize(1536)), "1.5 KiB");
+    assert_eq!(format!("{}", ByteSize(100 * 1024 * 1024)), "100.0 MiB");
+    assert_eq!(format!("{}", ByteSize(3 << 30)), "3.0 GiB");
+    assert_eq!(format!("{}", ByteSize(u64::MAX)), "16777215.9 TiB");
+    assert_eq!(format!("[{:>8}]", ByteSize(2048)), "[ 2.0 KiB]");
+    assert_eq!(format!("[{:<8}]", ByteSize(2048)), "[2.0 KiB ]");
+}
+
+#[test_case]
+fn test_meminfo_command() {
+    use crate::console::capture_output;
+    use crate::kshell::ShellError;
+
+    // the number after `key` on the line starting with `label`
+    fn value<'a>(output: &'a str, label: &str, key: &str) -> &'a str {
+        let line = output.lines().find(|line| line.starts_with(label)).unwrap_or_else(|| panic!("no {} line", label));
+        let mut words = line[label.len()..].split_whitespace().skip_while(|&word| word != key);
+        words.nth(1).unwrap_or_else(|| panic!("no {} on {:?}", key, line))
+    }
+    fn number(output: &str, label: &str, key: &str) -> u64 {
+        value(output, label, key).parse().expect("not a number")
+    }
+
+    // keep a block alive so the heap is in use while the command runs
+    let _held = alloc::vec![0u8; 100];
+    let mut result = Ok(());
+    let output = capture_output(|| result = meminfo_command(&[]));
+    assert_eq!(result, Ok(()));
+    assert!(output.lines().all(|line| line.len() < 80), "line too long for the screen:\n{}", output);
+    for label in ["memory", "frames", "heap pages", "heap calls", "size class", "free blocks"] {
+        assert!(output.lines().any(|line| line.starts_with(label)), "no {:?} line in:\n{}", label, output);
+    }
+
+    let usable = number(&output, "frames", "usable");
+    let allocated = number(&output, "frames", "allocated");
+    assert_eq!(usable, allocated + number(&output, "frames", "free"));
+    assert!(allocated > 0, "the heap's frames aren't counted");
+    let heap = crate::allocator::stats();
+    let pages = output.lines().find_map(|line| line.strip_prefix("heap pages")).and_then(|rest| rest.split_whitespace().next());
+    assert_eq!(pages.map(str::parse::<u64>), Some(Ok(heap.heap_size / 4096)));
+    assert!(value(&output, "heap ", "peak").split('.').all(|part| part.parse::<u64>().is_ok()), "no peak usage in:\n{}", output);
+    assert!(crate::allocator::heap_high_water_mark() as u64 >= heap.bytes_in_use);
+    assert_eq!(
+        output.lines().find(|line| line.starts_with("size class")).map(|line| line.split_whitespace().count()),
+        Some(2 + crate::allocator::fixed_size_block::NUM_SIZE_CLASSES)
+    );
+
+    let output = capture_output(|| result = meminfo_command(&["-v"]));
+    assert_eq!(result, Ok(()));
+    assert!(output.contains("fragmented") && output.contains("recycled"), "no fragmentation report in:\n{}", output);
+    // the physical memory mapping at least
+    assert!(output.lines().skip_while(|line| !line.starts_with("regions")).nth(1).is_some(), "no regions in:\n{}", output);
+
+    assert_eq!(meminfo_command(&["-x"]), Err(ShellError::Usage("meminfo [-v]")));
+}
@@ -0,0 +1,227 @@
+// An isolated virtual address space for a user process --> its own level 4 page table
+// the kernel's mappings (code, stack, heap, physical memory mapping...) are shared by copying the kernel's level 4 entries,
+// so kernel code keeps working after switching CR3 (those pages aren't USER_ACCESSIBLE, so user code can't touch them)
+// user mappings live in the USER_SPACE range, which must not overlap any of the kernel's level 4 entries
+use super::{active_level_4_table, phys_to_virt, physical_memory_offset, GlobalFrameAllocator};
+use x86_64::{
+    structures::paging::{
+        mapper::{MapToError, TranslateResult},
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
+        PhysFrame, Size4KiB, Translate,
+    },
+    VirtAddr,
+};
+
+/// Start of the virtual memory range reserved for user mappings (level 4 entry 64).
+pub const USER_SPACE_START: u64 = 0x0000_2000_0000_0000;
+/// End (exclusive) of the virtual memory range reserved for user mappings (level 4 entry 128).
+pub const USER_SPACE_END: u64 = 0x0000_4000_0000_0000;
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Errors from mapping memory into an `AddressSpace`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressSpaceError {
+    NotUserAddress, // outside of USER_SPACE or inside a level 4 entry the kernel uses
+    AlreadyMapped,
+    FrameAllocationFailed,
+}
+
+impl From<MapToError<Size4KiB>> for AddressSpaceError {
+    fn from(error: MapToError<Size4KiB>) -> Self {
+        match error {
+            MapToError::FrameAllocationFailed => AddressSpaceError::FrameAllocationFailed,
+            MapToError::PageAlreadyMapped(_) | MapToError::ParentEntryHugePage => AddressSpaceError::AlreadyMapped,
+        }
+    }
+}
+
+/// A level 4 page table that shares the kernel mappings and owns everything mapped in user space.
+///
+/// Dropping it frees every user frame and page table it allocated (the kernel's tables are left alone).
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+    kernel_entries: [bool; 512], // level 4 entries copied from the kernel --> shared, never freed by us
+}
+
+impl AddressSpace {
+    /// Create a new address space containing only the kernel mappings of the active page table.
+    pub fn new() -> Result<AddressSpace, AddressSpaceError> {
+        let level_4_frame = GlobalFrameAllocator
+            .allocate_frame()
+            .ok_or(AddressSpaceError::FrameAllocationFailed)?;
+        let table: &mut PageTable = unsafe { &mut *phys_to_virt(level_4_frame.start_address()).as_mut_ptr() };
+        table.zero();
+
+        // only read through this reference, the kernel's own mapper is never used at the same time
+        let kernel_table = unsafe { active_level_4_table(physical_memory_offset()) };
+        let mut kernel_entries = [false; 512];
+        for (i, entry) in kernel_table.iter().enumerate() {
+            if !entry.is_unused() {
+                table[i] = entry.clone();
+                kernel_entries[i] = true;
+            }
+        }
+
+        Ok(AddressSpace { level_4_frame, kernel_entries })
+    }
+
+    /// The frame holding the level 4 table, load it into CR3 to switch to this address space.
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    fn level_4_table(&self) -> &'static mut PageTable {
+        unsafe { &mut *phys_to_virt(self.level_4_frame.start_address()).as_mut_ptr() }
+    }
+
+    /// A mapper over this address space (it does not have to be the active one).
+    pub fn mapper(&mut self) -> OffsetPageTable<'static> {
+        unsafe { OffsetPageTable::new(self.level_4_table(), physical_memory_offset()) }
+    }
+
+    /// Whether `addr` may hold user mappings in this address space.
+    pub fn is_user_address(&self, addr: VirtAddr) -> bool {
+        let addr_u64 = addr.as_u64();
+        (USER_SPACE_START..USER_SPACE_END).contains(&addr_u64)
+            && !self.kernel_entries[usize::from(addr.p4_index())]
+    }
+
+    /// Map a fresh, zeroed frame at `page` with the given flags (USER_ACCESSIBLE and PRESENT are always added).
+    pub fn map_user_page(&mut self, page: Page, flags: PageTableFlags) -> Result<PhysFrame, AddressSpaceError> {
+        if !self.is_user_address(page.start_address()) {
+            return Err(AddressSpaceError::NotUserAddress);
+        }
+        let frame = GlobalFrameAllocator
+            .allocate_frame()
+            .ok_or(AddressSpaceError::FrameAllocationFailed)?;
+        unsafe { phys_to_virt(frame.start_address()).as_mut_ptr::<u8>().write_bytes(0, PAGE_SIZE as usize) };
+
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        // the address space isn't necessarily active so there is no TLB entry to flush
+        match unsafe { self.mapper().map_to(page, frame, flags, &mut GlobalFrameAllocator) } {
+            Ok(flush) => flush.ignore(),
+            Err(error) => {
+                unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+                return Err(error.into());
+            }
+        }
+        Ok(frame)
+    }
+
+    /// Map `page_count` fresh pages starting at `start` (see `map_user_page()`).
+    pub fn map_user_range(&mut self, start: VirtAddr, page_count: u64, flags: PageTableFlags) -> Result<(), AddressSpaceError> {
+        let first = Page::<Size4KiB>::containing_address(start);
+        for i in 0..page_count {
+            self.map_user_page(first + i, flags)?;
+        }
+        Ok(())
+    }
+
+    /// The flags of the page mapped at `addr`, `None` if nothing is mapped there.
+    pub fn translate_flags(&mut self, addr: VirtAddr) -> Option<PageTableFlags> {
+        match self.mapper().translate(addr) {
+            TranslateResult::Mapped { flags, .. } => Some(flags),
+            _ => None,
+        }
+    }
+
+    /// Whether every byte of `[start, start + len)` is mapped and accessible from user mode.
+    pub fn is_user_range_mapped(&mut self, start: VirtAddr, len: u64) -> bool {
+        if len == 0 {
+            return true;
+        }
+        let end = match start.as_u64().checked_add(len - 1) {
+            Some(end) => end,
+            None => return false,
+        };
+        if !self.is_user_address(start) || end >= USER_SPACE_END {
+            return false;
+        }
+        let mut page_addr = start.align_down(PAGE_SIZE).as_u64();
+        while page_addr <= end {
+            let addr = VirtAddr::new(page_addr);
+            if !self.is_user_address(addr) {
+                return false;
+            }
+            match self.translate_flags(addr) {
+                Some(flags) if flags.contains(PageTableFlags::USER_ACCESSIBLE) => {}
+                _ => return false,
+            }
+            page_addr += PAGE_SIZE;
+        }
+        true
+    }
+
+    /// Copy `bytes` to `addr` in this address space (through the physical memory mapping, so it works while inactive).
+    ///
+    /// Every destination page has to be mapped already, returns `false` (without copying anything) otherwise.
+    pub fn write(&mut self, addr: VirtAddr, bytes: &[u8]) -> bool {
+        if !self.is_user_range_mapped(addr, bytes.len() as u64) {
+            return false;
+        }
+        let mapper = self.mapper();
+        let mut copied = 0;
+        while copied < bytes.len() {
+            let dest = addr + copied as u64;
+            let in_page = (PAGE_SIZE - dest.as_u64() % PAGE_SIZE) as usize;
+            let chunk = in_page.min(bytes.len() - copied);
+            let phys = mapper.translate_addr(dest).expect("checked above");
+            unsafe {
+                core::ptr::copy_nonoverlapping(bytes[copied..].as_ptr(), phys_to_virt(phys).as_mut_ptr::<u8>(), chunk);
+            }
+            copied += chunk;
+        }
+        true
+    }
+
+    // free a page table and (depending on level) everything below it
+    // level 1 tables hold the user frames themselves, higher levels hold tables
+    unsafe fn free_table(frame: PhysFrame, level: u8) {
+        let table: &PageTable = &*phys_to_virt(frame.start_address()).as_ptr();
+        for entry in table.iter() {
+            if entry.is_unused() {
+                continue;
+            }
+            let child = PhysFrame::containing_address(entry.addr());
+            if level == 1 {
+                GlobalFrameAllocator.deallocate_frame(child);
+            } else {
+                Self::free_table(child, level - 1);
+            }
+        }
+        GlobalFrameAllocator.deallocate_frame(frame);
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        let table = self.level_4_table();
+        for (i, entry) in table.iter().enumerate() {
+            if entry.is_unused() || self.kernel_entries[i] {
+                continue;
+            }
+            unsafe { Self::free_table(PhysFrame::containing_address(entry.addr()), 3) };
+        }
+        unsafe { GlobalFrameAllocator.deallocate_frame(self.level_4_frame) };
+    }
+}
+
+// TESTS ===================================
+
+#[test_case]
+fn test_address_space_frees_frames() {
+    let baseline = super::allocated_frame_count();
+    {
+        let mut space = AddressSpace::new().expect("address space creation failed");
+        let start = VirtAddr::new(USER_SPACE_START);
+        space.map_user_range(start, 3, PageTableFlags::WRITABLE).expect("mapping failed");
+        assert!(space.write(start + 4090u64, b"across a page boundary"));
+        assert!(space.is_user_range_mapped(start, 3 * PAGE_SIZE));
+        assert!(!space.is_user_range_mapped(start, 3 * PAGE_SIZE + 1));
+        // kernel addresses are never user memory
+        assert!(!space.is_user_range_mapped(VirtAddr::new(crate::allocator::HEAP_START as u64), 8));
+        assert!(super::allocated_frame_count() > baseline);
+    }
+    assert_eq!(super::allocated_frame_count(), baseline);
+}
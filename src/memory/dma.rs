@@ -0,0 +1,76 @@
+// DMA buffers --> memory a device reads or writes by itself (ex. a network card's receive ring)
+// a device only knows physical addresses and doesn't go through our page tables, so a buffer larger than a frame has to be
+// physically contiguous, and devices that only take 32 bit addresses (ex. the RTL8139) need it below 4 GiB
+// the CPU reaches the buffer through the physical memory mapping (see phys_to_virt())
+use super::{allocate_contiguous_frames, phys_to_virt, GlobalFrameAllocator};
+use x86_64::structures::paging::{FrameDeallocator, PhysFrame};
+use x86_64::PhysAddr;
+
+const FRAME_SIZE: usize = 4096;
+
+/// Highest address (+ 1) a device with 32 bit DMA addresses can reach.
+pub const DMA32_LIMIT: u64 = 1 << 32;
+
+/// Physically contiguous, zeroed memory below 4 GiB, given back to the frame allocator on drop.
+#[derive(Debug)]
+pub struct DmaBuffer {
+    start: PhysFrame,
+    frames: usize,
+}
+
+impl DmaBuffer {
+    /// Allocate at least `size` bytes (rounded up to whole frames), `None` if no long enough run of frames is left.
+    pub fn new(size: usize) -> Option<Self> {
+        let frames = size.max(1).div_ceil(FRAME_SIZE);
+        let start = allocate_contiguous_frames(frames, PhysAddr::new(DMA32_LIMIT))?;
+        let buffer = DmaBuffer { start, frames };
+        // the device may read parts we never write --> don't hand it whatever the frames held before
+        unsafe { super::volatile_zero(buffer.as_mut_ptr(), buffer.len()) };
+        Some(buffer)
+    }
+
+    /// The address to give to the device.
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.start.start_address()
+    }
+
+    /// Where the CPU reads and writes the buffer.
+    pub fn as_mut_ptr(&self) -> *mut u8 {
+        phys_to_virt(self.phys_addr()).as_mut_ptr()
+    }
+
+    /// Size in bytes (a multiple of the frame size).
+    pub fn len(&self) -> usize {
+        self.frames * FRAME_SIZE
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        for i in 0..self.frames {
+            unsafe { GlobalFrameAllocator.deallocate_frame(self.start + i as u64) };
+        }
+    }
+}
+
+// TESTS ===================================
+
+#[test_case]
+fn test_dma_buffer_contiguous() {
+    let frames_before = super::allocated_frame_count();
+    let buffer = DmaBuffer::new(3 * FRAME_SIZE + 1).expect("out of contiguous memory");
+    assert_eq!(buffer.len(), 4 * FRAME_SIZE);
+    assert_eq!(super::allocated_frame_count(), frames_before + 4);
+    assert!(buffer.phys_addr().as_u64() + buffer.len() as u64 <= DMA32_LIMIT);
+    assert!(buffer.phys_addr().is_aligned(FRAME_SIZE as u64));
+
+    // zeroed, and the CPU pointer really is the physical memory mapping of the buffer
+    let bytes = unsafe { core::slice::from_raw_parts_mut(buffer.as_mut_ptr(), buffer.len()) };
+    assert!(bytes.iter().all(|&byte| byte == 0));
+    bytes[buffer.len() - 1] = 0xAB;
+    let last = phys_to_virt(buffer.phys_addr() + (buffer.len() - 1) as u64).as_ptr::<u8>();
+    assert_eq!(unsafe { last.read_volatile() }, 0xAB);
+
+    drop(buffer);
+    assert_eq!(super::allocated_frame_count(), frames_before);
+}
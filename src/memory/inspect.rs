@@ -0,0 +1,246 @@
+// Looking at memory from the shell --> `hexdump`, `peek8/32/64` and `poke8/32/64`, for live debugging
+// - addresses and values are hex, with or without 0x, a hexdump length is decimal unless it has the 0x
+// - every page of the target is looked up in the active page tables first: an unmapped address is refused instead of
+//   page faulting the shell, so is a poke to a page that isn't writable unless it says --force (which may still fault,
+//   the CPU enforces read only pages in ring 0 as well)
+// - peeks and pokes are single volatile accesses of their size --> usable on MMIO registers, so they have to be
+//   naturally aligned
+use super::{init, physical_memory_offset};
+use crate::kshell::ShellError;
+use core::fmt;
+use x86_64::structures::paging::mapper::{Translate, TranslateResult};
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+/// Bytes per row of `hexdump()`.
+pub const HEXDUMP_ROW: usize = 16;
+
+// the most bytes one `hexdump` prints, 64 rows
+const MAX_HEXDUMP_LEN: u64 = 1024;
+const DEFAULT_HEXDUMP_LEN: u64 = 64;
+
+/// Write `bytes` (found at `addr`) to `out` in rows of `HEXDUMP_ROW` bytes, like xxd does:
+/// "ffff800000001000: 4865 6c6c 6f2c 2077 6f72 6c64 210a 0000  Hello, world!..." (75 columns).
+pub fn hexdump(out: &mut impl fmt::Write, addr: u64, bytes: &[u8]) -> fmt::Result {
+    for (row, chunk) in bytes.chunks(HEXDUMP_ROW).enumerate() {
+        write!(out, "{:016x}:", addr + (row * HEXDUMP_ROW) as u64)?;
+        for i in 0..HEXDUMP_ROW {
+            if i % 2 == 0 {
+                out.write_char(' ')?;
+            }
+            match chunk.get(i) {
+                Some(byte) => write!(out, "{:02x}", byte)?,
+                None => out.write_str("  ")?,
+            }
+        }
+        out.write_str("  ")?;
+        for &byte in chunk {
+            out.write_char(if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' })?;
+        }
+        out.write_char('\n')?;
+    }
+    Ok(())
+}
+
+/// A hex number, with or without 0x ("0xff", "FF").
+pub fn parse_hex(s: &str) -> Result<u64, ShellError> {
+    let digits = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")).unwrap_or(s);
+    u64::from_str_radix(digits, 16).map_err(|_| ShellError::BadArgument)
+}
+
+// a length: decimal, or hex with 0x
+fn parse_len(s: &str) -> Result<u64, ShellError> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(_) => parse_hex(s),
+        None => s.parse().map_err(|_| ShellError::BadArgument),
+    }
+}
+
+// the flags of every page of [addr, addr + len) must contain `required`, `Refused` if one isn't mapped at all or is
+// missing a flag (with `missing` as the reason)
+fn check_mapped(addr: u64, len: u64, required: PageTableFlags, missing: &'static str) -> Result<(), ShellError> {
+    let start = VirtAddr::try_new(addr).map_err(|_| ShellError::Refused("not a canonical address"))?;
+    let last = addr.checked_add(len.max(1) - 1).and_then(|last| VirtAddr::try_new(last).ok());
+    let last = last.ok_or(ShellError::Refused("range not mapped"))?;
+    // a second OffsetPageTable over the active tables is fine, it is only read
+    let mapper = unsafe { init(physical_memory_offset()) };
+    let mut page = start.align_down(4096u64);
+    while page <= last {
+        match mapper.translate(page) {
+            TranslateResult::Mapped { flags, .. } if flags.contains(required) => {}
+            TranslateResult::Mapped { .. } => return Err(ShellError::Refused(missing)),
+            _ => return Err(ShellError::Refused("address not mapped")),
+        }
+        page = match page.as_u64().checked_add(4096).and_then(|next| VirtAddr::try_new(next).ok()) {
+            Some(next) => next,
+            None => break,
+        };
+    }
+    Ok(())
+}
+
+fn check_aligned(addr: u64, size: usize) -> Result<(), ShellError> {
+    match addr % size as u64 {
+        0 => Ok(()),
+        _ => Err(ShellError::Refused("address not aligned")),
+    }
+}
+
+/// Read the `size` (1, 4 or 8) byte value at `addr`, after checking it's mapped.
+pub fn peek(addr: u64, size: usize) -> Result<u64, ShellError> {
+    check_aligned(addr, size)?;
+    check_mapped(addr, size as u64, PageTableFlags::PRESENT, "address not mapped")?;
+    let value = unsafe {
+        match size {
+            1 => u64::from((addr as *const u8).read_volatile()),
+            4 => u64::from((addr as *const u32).read_volatile()),
+            8 => (addr as *const u64).read_volatile(),
+            _ => return Err(ShellError::BadArgument),
+        }
+    };
+    Ok(value)
+}
+
+/// Write the `size` (1, 4 or 8) byte `value` to `addr`, after checking it's mapped and (unless `force`) writable.
+pub fn poke(addr: u64, size: usize, value: u64, force: bool) -> Result<(), ShellError> {
+    if size < 8 && value >> (size * 8) != 0 {
+        return Err(ShellError::BadArgument); // doesn't fit
+    }
+    check_aligned(addr, size)?;
+    let required = if force { PageTableFlags::PRESENT } else { PageTableFlags::PRESENT | PageTableFlags::WRITABLE };
+    check_mapped(addr, size as u64, required, "page not writable (poke ... --force to try anyway)")?;
+    unsafe {
+        match size {
+            1 => (addr as *mut u8).write_volatile(value as u8),
+            4 => (addr as *mut u32).write_volatile(value as u32),
+            8 => (addr as *mut u64).write_volatile(value),
+            _ => return Err(ShellError::BadArgument),
+        }
+    }
+    Ok(())
+}
+
+// SHELL COMMANDS ================================
+
+/// Add `hexdump`, `peek*` and `poke*` to the kernel shell (see kshell.rs), once the heap is up.
+pub fn init_shell_commands() {
+    use crate::kshell::register_command;
+
+    let commands: [(&str, &str, crate::kshell::CommandHandler); 7] = [
+        ("hexdump", "show memory: hexdump <addr> [len]", hexdump_command),
+        ("peek8", "read a byte: peek8 <addr>", |args| peek_command(args, 1, "peek8 <addr>")),
+        ("peek32", "read 4 bytes: peek32 <addr>", |args| peek_command(args, 4, "peek32 <addr>")),
+        ("peek64", "read 8 bytes: peek64 <addr>", |args| peek_command(args, 8, "peek64 <addr>")),
+        ("poke8", "write a byte: poke8 <addr> <value> [--force]", |args| poke_command(args, 1, "poke8 <addr> <value> [--force]")),
+        ("poke32", "write 4 bytes: poke32 <addr> <value> [--force]", |args| poke_command(args, 4, "poke32 <addr> <value> [--force]")),
+        ("poke64", "write 8 bytes: poke64 <addr> <value> [--force]", |args| poke_command(args, 8, "poke64 <addr> <value> [--force]")),
+    ];
+    for (name, help, handler) in commands {
+        register_command(name, help, handler).expect("memory inspection command registered twice");
+    }
+}
+
+// the console as a fmt::Write, for hexdump()
+struct Console;
+
+impl fmt::Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        crate::print!("{}", s);
+        Ok(())
+    }
+}
+
+fn hexdump_command(args: &[&str]) -> Result<(), ShellError> {
+    let (addr, len) = match args {
+        [addr] => (parse_hex(addr)?, DEFAULT_HEXDUMP_LEN),
+        [addr, len] => (parse_hex(addr)?, parse_len(len)?),
+        _ => return Err(ShellError::Usage("hexdump <addr> [len]")),
+    };
+    let len = len.min(MAX_HEXDUMP_LEN);
+    check_mapped(addr, len, PageTableFlags::PRESENT, "address not mapped")?;
+    // the range is mapped (checked above), a plain read can't fault
+    let bytes = unsafe { core::slice::from_raw_parts(addr as *const u8, len as usize) };
+    hexdump(&mut Console, addr, bytes).expect("printing failed");
+    Ok(())
+}
+
+fn peek_command(args: &[&str], size: usize, usage: &'static str) -> Result<(), ShellError> {
+    let addr = match args {
+        [addr] => parse_hex(addr)?,
+        _ => return Err(ShellError::Usage(usage)),
+    };
+    let value = peek(addr, size)?;
+    crate::println!("{:#018x}: {:#0width$x} ({})", addr, value, value, width = 2 + 2 * size);
+    Ok(())
+}
+
+fn poke_command(args: &[&str], size: usize, usage: &'static str) -> Result<(), ShellError> {
+    let (args, force) = match args {
+        [rest @ .., "--force"] => (rest, true),
+        _ => (args, false),
+    };
+    match args {
+        [addr, value] => poke(parse_hex(addr)?, size, parse_hex(value)?, force),
+        _ => Err(ShellError::Usage(usage)),
+    }
+}
+
+// TESTS ===================================
+
+#[test_case]
+fn test_parse_arguments() {
+    assert_eq!(parse_hex("0x1F"), Ok(0x1f));
+    assert_eq!(parse_hex("ffff800000001000"), Ok(0xffff_8000_0000_1000));
+    assert_eq!(parse_hex("0Xab"), Ok(0xab));
+    assert_eq!(parse_hex("0x"), Err(ShellError::BadArgument));
+    assert_eq!(parse_hex("12g"), Err(ShellError::BadArgument));
+    assert_eq!(parse_hex("10000000000000000"), Err(ShellError::BadArgument), "more than 64 bits");
+    assert_eq!(parse_len("64"), Ok(64));
+    assert_eq!(parse_len("0x40"), Ok(64));
+
+    assert_eq!(hexdump_command(&[]), Err(ShellError::Usage("hexdump <addr> [len]")));
+    assert_eq!(peek_command(&["1000", "2"], 1, "peek8 <addr>"), Err(ShellError::Usage("peek8 <addr>")));
+    assert_eq!(poke_command(&["1000"], 1, "poke8"), Err(ShellError::Usage("poke8")));
+    assert_eq!(poke_command(&["zz", "1"], 1, "poke8"), Err(ShellError::BadArgument));
+    assert_eq!(poke(0x1000, 1, 0x100, false), Err(ShellError::BadArgument), "value too big for a byte");
+}
+
+#[test_case]
+fn test_unmapped_address_refused() {
+    // far below anything the bootloader or the tests map
+    let unmapped = 0x7fff_dead_0000;
+    assert_eq!(peek(unmapped, 8), Err(ShellError::Refused("address not mapped")));
+    assert_eq!(poke(unmapped, 8, 1, true), Err(ShellError::Refused("address not mapped")));
+    assert_eq!(hexdump_command(&["0x7fffdead0000"]), Err(ShellError::Refused("address not mapped")));
+    assert_eq!(peek(0x8000_0000_0000, 1), Err(ShellError::Refused("not a canonical address")));
+    // mapped, but a 4 byte peek has to be 4 byte aligned
+    let boxed = alloc::boxed::Box::new(0u64);
+    let addr = &*boxed as *const u64 as u64;
+    assert_eq!(peek(addr + 1, 4), Err(ShellError::Refused("address not aligned")));
+}
+
+#[test_case]
+fn test_poke_peek_round_trip() {
+    use crate::console::capture_output;
+    use alloc::format;
+
+    // poked through its address --> the pointer must come from a mutable borrow
+    let mut buffer = alloc::vec![0u8; 32];
+    let addr = buffer.as_mut_ptr() as u64;
+    let addr_hex = format!("{:#x}", addr);
+
+    assert_eq!(poke_command(&[&addr_hex, "0x1122334455667788"], 8, "poke64"), Ok(()));
+    assert_eq!(poke_command(&[&format!("{:x}", addr + 8), "ab"], 1, "poke8"), Ok(()));
+    assert_eq!(peek(addr, 8), Ok(0x1122_3344_5566_7788));
+    assert_eq!(peek(addr + 4, 4), Ok(0x1122_3344));
+    assert_eq!(buffer[..9], [0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11, 0xab]);
+
+    let mut result = Ok(());
+    let output = capture_output(|| result = peek_command(&[&addr_hex], 8, "peek64"));
+    assert_eq!(result, Ok(()));
+    assert_eq!(output, format!("{:#018x}: 0x1122334455667788 ({})\n", addr, 0x1122_3344_5566_7788u64));
+
+    let output = capture_output(|| result = hexdump_command(&[&addr_hex, "16"]));
+    assert_eq!(result, Ok(()));
+    assert_eq!(output, format!("{:016x}: 8877 6655 4433 2211 ab00 0000 0000 0000  .wfUD3\".........\n", addr));
+}
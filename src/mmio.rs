@@ -0,0 +1,85 @@
+// Memory mapped I/O helpers --> device registers that live in the physical address space (ex. the VGA buffer, PCI BARs, the local APIC)
+// the compiler must not merge or drop accesses to them (-> volatile) and the CPU must not reorder them where the device would notice (-> fences)
+//
+// which one to use:
+// - mmio_write_u32 / mmio_read_u32: single register accesses, ex. writing a command register then reading a status register
+// - mmio_sfence: after filling memory that a device reads by itself (DMA) and BEFORE telling the device about it,
+//   ex. writing a DMA descriptor and then ringing the device's doorbell register --> the descriptor must be visible first
+// - mmio_write_fence (mfence): when stores must also be ordered against later loads,
+//   ex. writing a register and then reading back memory the device writes in response
+//
+// regular (write-back cached) memory on x86 is already strongly ordered, so these mostly matter for write-combining mappings
+// (framebuffers) and non-temporal stores, but they are cheap compared to a device access and make the intent explicit
+//
+// the fences are written as inline asm: core::arch::x86_64::{_mm_mfence, _mm_sfence, _mm_lfence} emit the exact same
+// instructions but are gated behind the sse/sse2 target features, which our target spec turns off (see x86_64-mini_os.json)
+use core::arch::asm;
+
+/// Full memory fence (`mfence`): every load and store before it completes before any load or store after it.
+#[inline]
+pub fn mmio_write_fence() {
+    unsafe { asm!("mfence", options(nostack, preserves_flags)) };
+}
+
+/// Store fence (`sfence`): every store before it is globally visible before any store after it.
+#[inline]
+pub fn mmio_sfence() {
+    unsafe { asm!("sfence", options(nostack, preserves_flags)) };
+}
+
+/// Load fence (`lfence`): every load before it completes before any load after it starts.
+#[inline]
+pub fn mmio_lfence() {
+    unsafe { asm!("lfence", options(nostack, preserves_flags)) };
+}
+
+/// Volatile 32 bit write to a device register, followed by a store fence.
+///
+/// This function is unsafe because the caller must guarantee that `ptr` is valid, aligned and mapped.
+#[inline]
+pub unsafe fn mmio_write_u32(ptr: *mut u32, val: u32) {
+    ptr.write_volatile(val);
+    mmio_sfence();
+}
+
+/// Load fence followed by a volatile 32 bit read of a device register.
+///
+/// This function is unsafe because the caller must guarantee that `ptr` is valid, aligned and mapped.
+#[inline]
+pub unsafe fn mmio_read_u32(ptr: *const u32) -> u32 {
+    mmio_lfence();
+    ptr.read_volatile()
+}
+
+// TESTS ===================================
+
+// the machine code of the fences (`0f ae` + a ModRM byte picking which fence)
+#[cfg(test)]
+const MFENCE: [u8; 3] = [0x0f, 0xae, 0xf0];
+#[cfg(test)]
+const SFENCE: [u8; 3] = [0x0f, 0xae, 0xf8];
+#[cfg(test)]
+const LFENCE: [u8; 3] = [0x0f, 0xae, 0xe8];
+
+// look for an instruction in the first `len` bytes of a function's machine code
+#[cfg(test)]
+fn code_contains(function: *const u8, len: usize, instruction: &[u8]) -> bool {
+    let code = unsafe { core::slice::from_raw_parts(function, len) };
+    code.windows(instruction.len()).any(|window| window == instruction)
+}
+
+#[test_case]
+fn test_fences_emit_fence_instructions() {
+    assert!(code_contains(mmio_write_fence as *const u8, 32, &MFENCE));
+    assert!(code_contains(mmio_sfence as *const u8, 32, &SFENCE));
+    assert!(code_contains(mmio_lfence as *const u8, 32, &LFENCE));
+}
+
+#[test_case]
+fn test_mmio_read_write_round_trip() {
+    let mut register: u32 = 0;
+    unsafe {
+        mmio_write_u32(&mut register, 0xdead_beef);
+        assert_eq!(mmio_read_u32(&register), 0xdead_beef);
+    }
+}
@@ -0,0 +1,280 @@
+// Networking --> sending and receiving ethernet frames through a network card
+//
+// - a NetworkDevice is a network card driver (ex. drivers/rtl8139.rs), it sends and receives whole ethernet frames
+// - one card is registered as the interface (register_interface()): send() builds frames and hands them to it, and every
+//   frame it receives goes through receive_frame(), which drops what isn't for us before passing the rest on
+// - ARP (arp.rs) and IPv4 (ipv4.rs, with ICMP on top in icmp.rs) are handled right there, for the one static IPv4
+//   address set with set_ipv4()
+// - the counters (stats()) are atomics --> receive_frame() runs in the card's interrupt handler and must not allocate
+pub mod arp;
+pub mod ethernet;
+pub mod icmp;
+pub mod ipv4;
+
+use core::fmt;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use ethernet::{EthernetFrame, FrameError, ETHERTYPE_ARP, ETHERTYPE_IPV4, MAX_FRAME_LEN};
+use spin::{Mutex, Once};
+use x86_64::instructions::interrupts;
+
+/// Errors reported by the network layers and network card drivers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetError {
+    /// No network card was found (or its driver isn't initialized).
+    NoDevice,
+    /// The frame is larger than the card can send in one go.
+    FrameTooLarge,
+    /// The card (or the other end) didn't answer in time.
+    Timeout,
+    /// The card gave up sending the frame (ex. an underrun or too many collisions).
+    TransmitFailed,
+    /// No IPv4 address is set (see `set_ipv4()`).
+    NoAddress,
+}
+
+/// An ethernet (MAC) address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MacAddress(pub [u8; 6]);
+
+impl MacAddress {
+    /// Every card on the network receives frames sent here.
+    pub const BROADCAST: MacAddress = MacAddress([0xFF; 6]);
+
+    /// Whether the address is meant for a group of cards (the lowest bit of the first byte, broadcast included).
+    pub fn is_multicast(&self) -> bool {
+        self.0[0] & 1 != 0
+    }
+}
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}", a, b, c, d, e, g)
+    }
+}
+
+/// An IPv4 address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Ipv4Address(pub [u8; 4]);
+
+impl Ipv4Address {
+    /// "No address", ex. in an ARP probe.
+    pub const UNSPECIFIED: Ipv4Address = Ipv4Address([0; 4]);
+}
+
+impl fmt::Display for Ipv4Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [a, b, c, d] = self.0;
+        write!(f, "{}.{}.{}.{}", a, b, c, d)
+    }
+}
+
+/// A network card.
+///
+/// Methods take `&self` so the card can be shared --> implementations lock internally.
+pub trait NetworkDevice: Send + Sync {
+    fn mac(&self) -> MacAddress;
+    /// Largest payload (after the ethernet header) of a frame.
+    fn mtu(&self) -> usize;
+    /// Send a whole frame (header included, without the CRC).
+    fn send(&self, frame: &[u8]) -> Result<(), NetError>;
+    /// Call `sink` with every frame received from now on, from the card's interrupt handler.
+    fn set_rx_sink(&self, sink: fn(&[u8]));
+}
+
+// INTERFACE =============================
+
+static INTERFACE: Once<&'static dyn NetworkDevice> = Once::new();
+
+// gets every frame receive_frame() accepts, ex. the ARP and IP layers
+static FRAME_HANDLER: Mutex<Option<fn(&EthernetFrame)>> = Mutex::new(None);
+
+/// Use `device` for all networking from now on, panics if an interface is registered already.
+pub fn register_interface(device: &'static dyn NetworkDevice) {
+    let mut registered = false;
+    INTERFACE.call_once(|| {
+        registered = true;
+        device
+    });
+    assert!(registered, "a network interface is already registered");
+    device.set_rx_sink(receive_frame);
+}
+
+/// The card given to `register_interface()`.
+pub fn interface() -> Option<&'static dyn NetworkDevice> {
+    INTERFACE.r#try().copied()
+}
+
+// our address packed into a u32 (0 = none) --> readable from the interrupt handler without a lock
+static IPV4: AtomicU32 = AtomicU32::new(0);
+
+/// Answer ARP requests and pings for `addr` from now on.
+pub fn set_ipv4(addr: Ipv4Address) {
+    IPV4.store(u32::from_be_bytes(addr.0), Ordering::Relaxed);
+}
+
+/// The address set with `set_ipv4()`.
+pub fn ipv4() -> Option<Ipv4Address> {
+    match IPV4.load(Ordering::Relaxed) {
+        0 => None,
+        addr => Some(Ipv4Address(addr.to_be_bytes())),
+    }
+}
+
+/// Call `handler` with every frame the interface receives that is for us (runs in the interrupt handler, see `receive_frame()`).
+pub fn set_frame_handler(handler: fn(&EthernetFrame)) {
+    interrupts::without_interrupts(|| *FRAME_HANDLER.lock() = Some(handler));
+}
+
+/// Send `payload` to `destination` through the interface, the frame is padded to the minimum length.
+pub fn send(destination: MacAddress, ethertype: u16, payload: &[u8]) -> Result<(), NetError> {
+    let device = interface().ok_or(NetError::NoDevice)?;
+    if payload.len() > device.mtu() {
+        return Err(NetError::FrameTooLarge);
+    }
+    let mut buf = [0; MAX_FRAME_LEN];
+    let len = EthernetFrame { destination, source: device.mac(), ethertype, payload }.build(&mut buf)?;
+    match device.send(&buf[..len]) {
+        Ok(()) => {
+            COUNTERS.frames_sent.fetch_add(1, Ordering::Relaxed);
+            COUNTERS.bytes_sent.fetch_add(len as u64, Ordering::Relaxed);
+            Ok(())
+        }
+        Err(error) => {
+            COUNTERS.send_errors.fetch_add(1, Ordering::Relaxed);
+            Err(error)
+        }
+    }
+}
+
+/// The rx sink of the interface: drops runts, oversize frames and frames for other cards, handles ARP and IPv4 and
+/// passes every frame to the frame handler.
+pub fn receive_frame(bytes: &[u8]) {
+    let frame = match EthernetFrame::parse(bytes) {
+        Ok(frame) => frame,
+        Err(FrameError::Runt) => {
+            COUNTERS.dropped_runt.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        Err(FrameError::Oversize) => {
+            COUNTERS.dropped_oversize.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+    };
+    // the card may pass on more than it was asked to (ex. in promiscuous mode)
+    let for_us = frame.destination.is_multicast() || interface().map_or(true, |device| device.mac() == frame.destination);
+    if !for_us {
+        COUNTERS.dropped_not_for_us.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    COUNTERS.frames_received.fetch_add(1, Ordering::Relaxed);
+    COUNTERS.bytes_received.fetch_add(bytes.len() as u64, Ordering::Relaxed);
+
+    match frame.ethertype {
+        ETHERTYPE_ARP => arp::receive(&frame),
+        ETHERTYPE_IPV4 => ipv4::receive(&frame),
+        _ => {}
+    }
+    let handler = *FRAME_HANDLER.lock(); // copied out --> the handler may set another one
+    if let Some(handler) = handler {
+        handler(&frame);
+    }
+}
+
+// COUNTERS =============================
+
+/// What the interface sent, received and dropped since boot (see `stats()`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NetStats {
+    pub frames_sent: u64,
+    pub bytes_sent: u64,
+    pub frames_received: u64,
+    pub bytes_received: u64,
+    /// Received frames shorter than the minimum frame length.
+    pub dropped_runt: u64,
+    /// Received frames longer than the maximum frame length.
+    pub dropped_oversize: u64,
+    /// Received frames addressed to another card.
+    pub dropped_not_for_us: u64,
+    /// Frames the card failed to send.
+    pub send_errors: u64,
+}
+
+struct Counters {
+    frames_sent: AtomicU64,
+    bytes_sent: AtomicU64,
+    frames_received: AtomicU64,
+    bytes_received: AtomicU64,
+    dropped_runt: AtomicU64,
+    dropped_oversize: AtomicU64,
+    dropped_not_for_us: AtomicU64,
+    send_errors: AtomicU64,
+}
+
+static COUNTERS: Counters = Counters {
+    frames_sent: AtomicU64::new(0),
+    bytes_sent: AtomicU64::new(0),
+    frames_received: AtomicU64::new(0),
+    bytes_received: AtomicU64::new(0),
+    dropped_runt: AtomicU64::new(0),
+    dropped_oversize: AtomicU64::new(0),
+    dropped_not_for_us: AtomicU64::new(0),
+    send_errors: AtomicU64::new(0),
+};
+
+/// A snapshot of the interface's counters.
+pub fn stats() -> NetStats {
+    let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+    NetStats {
+        frames_sent: load(&COUNTERS.frames_sent),
+        bytes_sent: load(&COUNTERS.bytes_sent),
+        frames_received: load(&COUNTERS.frames_received),
+        bytes_received: load(&COUNTERS.bytes_received),
+        dropped_runt: load(&COUNTERS.dropped_runt),
+        dropped_oversize: load(&COUNTERS.dropped_oversize),
+        dropped_not_for_us: load(&COUNTERS.dropped_not_for_us),
+        send_errors: load(&COUNTERS.send_errors),
+    }
+}
+
+// TESTS ===================================
+
+// the RTL8139 as the interface with QEMU's default guest address (other tests may have taken over its rx callback)
+#[cfg(test)]
+pub(crate) fn test_interface() -> &'static dyn NetworkDevice {
+    let device = match interface() {
+        Some(device) => device,
+        None => {
+            crate::drivers::pci::init();
+            let nic = crate::drivers::rtl8139::init().expect("no RTL8139, is QEMU started with -device rtl8139?");
+            register_interface(nic);
+            nic
+        }
+    };
+    device.set_rx_sink(receive_frame);
+    set_ipv4(Ipv4Address([10, 0, 2, 15]));
+    device
+}
+
+#[test_case]
+fn test_mac_address_display() {
+    use alloc::format;
+
+    assert_eq!(format!("{}", MacAddress([0x52, 0x54, 0x00, 0x12, 0x34, 0x56])), "52:54:00:12:34:56");
+    assert_eq!(format!("{}", MacAddress::BROADCAST), "ff:ff:ff:ff:ff:ff");
+    assert_eq!(format!("{}", Ipv4Address([10, 0, 2, 15])), "10.0.2.15");
+}
+
+#[test_case]
+fn test_receive_frame_counters() {
+    let before = stats();
+    receive_frame(&[0xFF; 20]);
+    receive_frame(&[0xFF; MAX_FRAME_LEN + 1]);
+    receive_frame(&[0xFF; ethernet::MIN_FRAME_LEN]); // broadcast --> for everyone
+    let after = stats();
+    // the card may receive real frames meanwhile, but never runts or oversize ones (see ethernet.rs)
+    assert_eq!(after.dropped_runt, before.dropped_runt + 1);
+    assert_eq!(after.dropped_oversize, before.dropped_oversize + 1);
+    assert!(after.frames_received > before.frames_received);
+    assert!(after.bytes_received >= before.bytes_received + ethernet::MIN_FRAME_LEN as u64);
+}
@@ -0,0 +1,226 @@
+// ARP --> finding the MAC address of an IPv4 address on the local network
+// packet (ethernet/IPv4 only): [hardware type 1][protocol type 0x0800][lengths 6, 4][opcode][sender MAC][sender IP]
+// [target MAC][target IP], 28 bytes
+// - requests are broadcast ("who has <target IP>?"), the owner answers with a reply sent straight to the asker
+// - every packet we see tells us the sender's MAC --> kept in a small cache, replacing the oldest entry when full
+// - receive() runs in the card's interrupt handler: the cache is a fixed array (no allocation) and task side code
+//   locks it with interrupts off
+use super::ethernet::{EthernetFrame, ETHERTYPE_ARP, ETHERTYPE_IPV4};
+use super::{Ipv4Address, MacAddress, NetError};
+use crate::task::sleep_ticks;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+pub const PACKET_LEN: usize = 28;
+pub const OPCODE_REQUEST: u16 = 1;
+pub const OPCODE_REPLY: u16 = 2;
+
+const HARDWARE_ETHERNET: u16 = 1;
+const CACHE_SIZE: usize = 16;
+
+/// An ethernet/IPv4 ARP packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArpPacket {
+    pub opcode: u16,
+    pub sender_mac: MacAddress,
+    pub sender_ip: Ipv4Address,
+    pub target_mac: MacAddress,
+    pub target_ip: Ipv4Address,
+}
+
+impl ArpPacket {
+    /// Read a packet from an ethernet payload, `None` if it's too short or not about ethernet and IPv4.
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < PACKET_LEN {
+            return None;
+        }
+        let u16_at = |offset: usize| u16::from_be_bytes([bytes[offset], bytes[offset + 1]]);
+        if u16_at(0) != HARDWARE_ETHERNET || u16_at(2) != ETHERTYPE_IPV4 || bytes[4] != 6 || bytes[5] != 4 {
+            return None;
+        }
+        let mac = |offset: usize| {
+            let mut mac = [0; 6];
+            mac.copy_from_slice(&bytes[offset..offset + 6]);
+            MacAddress(mac)
+        };
+        let ip = |offset: usize| Ipv4Address([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]]);
+        Some(ArpPacket {
+            opcode: u16_at(6),
+            sender_mac: mac(8),
+            sender_ip: ip(14),
+            target_mac: mac(18),
+            target_ip: ip(24),
+        })
+    }
+
+    /// The packet as sent on the wire.
+    pub fn to_bytes(&self) -> [u8; PACKET_LEN] {
+        let mut bytes = [0; PACKET_LEN];
+        bytes[0..2].copy_from_slice(&HARDWARE_ETHERNET.to_be_bytes());
+        bytes[2..4].copy_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        bytes[4] = 6;
+        bytes[5] = 4;
+        bytes[6..8].copy_from_slice(&self.opcode.to_be_bytes());
+        bytes[8..14].copy_from_slice(&self.sender_mac.0);
+        bytes[14..18].copy_from_slice(&self.sender_ip.0);
+        bytes[18..24].copy_from_slice(&self.target_mac.0);
+        bytes[24..28].copy_from_slice(&self.target_ip.0);
+        bytes
+    }
+
+    /// Our answer to this request, sent from `mac` (which owns the target IP).
+    pub fn reply(&self, mac: MacAddress) -> ArpPacket {
+        ArpPacket {
+            opcode: OPCODE_REPLY,
+            sender_mac: mac,
+            sender_ip: self.target_ip,
+            target_mac: self.sender_mac,
+            target_ip: self.sender_ip,
+        }
+    }
+}
+
+// CACHE ====================================
+
+struct ArpCache {
+    entries: [Option<(Ipv4Address, MacAddress)>; CACHE_SIZE],
+    next: usize, // slot to replace when the cache is full (round robin --> the oldest entry)
+}
+
+static CACHE: Mutex<ArpCache> = Mutex::new(ArpCache { entries: [None; CACHE_SIZE], next: 0 });
+
+impl ArpCache {
+    fn insert(&mut self, ip: Ipv4Address, mac: MacAddress) {
+        let slot = self.entries.iter().position(|entry| matches!(entry, Some((cached, _)) if *cached == ip))
+            .or_else(|| self.entries.iter().position(Option::is_none));
+        match slot {
+            Some(slot) => self.entries[slot] = Some((ip, mac)),
+            None => {
+                self.entries[self.next] = Some((ip, mac));
+                self.next = (self.next + 1) % CACHE_SIZE;
+            }
+        }
+    }
+
+    fn lookup(&self, ip: Ipv4Address) -> Option<MacAddress> {
+        self.entries.iter().flatten().find(|(cached, _)| *cached == ip).map(|&(_, mac)| mac)
+    }
+}
+
+/// The MAC address learned for `ip`, if any.
+pub fn lookup(ip: Ipv4Address) -> Option<MacAddress> {
+    interrupts::without_interrupts(|| CACHE.lock().lookup(ip))
+}
+
+// called by receive_frame() for every ARP frame (in the interrupt handler)
+pub(super) fn receive(frame: &EthernetFrame) {
+    let Some(packet) = ArpPacket::parse(frame.payload) else {
+        return;
+    };
+    // probes (sender 0.0.0.0) tell us nothing
+    if packet.sender_ip != Ipv4Address::UNSPECIFIED {
+        CACHE.lock().insert(packet.sender_ip, packet.sender_mac);
+    }
+    if packet.opcode != OPCODE_REQUEST || Some(packet.target_ip) != super::ipv4() {
+        return;
+    }
+    if let Some(device) = super::interface() {
+        let reply = packet.reply(device.mac());
+        // nothing to do about a failed reply, the asker will ask again
+        let _ = super::send(packet.sender_mac, ETHERTYPE_ARP, &reply.to_bytes());
+    }
+}
+
+/// Broadcast a request for the MAC address of `ip`, the reply ends up in the cache.
+pub fn request(ip: Ipv4Address) -> Result<(), NetError> {
+    let device = super::interface().ok_or(NetError::NoDevice)?;
+    let sender_ip = super::ipv4().ok_or(NetError::NoAddress)?;
+    let packet = ArpPacket {
+        opcode: OPCODE_REQUEST,
+        sender_mac: device.mac(),
+        sender_ip,
+        target_mac: MacAddress([0; 6]),
+        target_ip: ip,
+    };
+    super::send(MacAddress::BROADCAST, ETHERTYPE_ARP, &packet.to_bytes())
+}
+
+/// The MAC address of `ip`, from the cache or by asking (gives up after about a second).
+pub async fn resolve(ip: Ipv4Address) -> Result<MacAddress, NetError> {
+    if let Some(mac) = lookup(ip) {
+        return Ok(mac);
+    }
+    request(ip)?;
+    let hz = u64::from(crate::config::get().timer_hz);
+    for _ in 0..hz {
+        sleep_ticks(1).await;
+        if let Some(mac) = lookup(ip) {
+            return Ok(mac);
+        }
+    }
+    Err(NetError::Timeout)
+}
+
+// TESTS ===================================
+
+// the ARP payload of the gateway's request in ethernet.rs: 10.0.2.2 (52:55:0a:00:02:02) asking who has 10.0.2.15
+#[cfg(test)]
+const GATEWAY_REQUEST: [u8; PACKET_LEN] = [
+    0x00, 0x01, 0x08, 0x00, 0x06, 0x04, 0x00, 0x01, 0x52, 0x55, 0x0a, 0x00, 0x02, 0x02, 0x0a, 0x00, 0x02, 0x02,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0a, 0x00, 0x02, 0x0f,
+];
+
+#[test_case]
+fn test_parse_and_reply() {
+    let request = ArpPacket::parse(&GATEWAY_REQUEST).expect("request rejected");
+    assert_eq!(request.opcode, OPCODE_REQUEST);
+    assert_eq!(request.sender_mac, MacAddress([0x52, 0x55, 0x0a, 0x00, 0x02, 0x02]));
+    assert_eq!(request.sender_ip, Ipv4Address([10, 0, 2, 2]));
+    assert_eq!(request.target_ip, Ipv4Address([10, 0, 2, 15]));
+    assert_eq!(request.to_bytes(), GATEWAY_REQUEST);
+
+    // the reply swaps sender and target and fills in our MAC
+    let mac = MacAddress([0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
+    let reply = request.reply(mac).to_bytes();
+    assert_eq!(reply[..8], [0x00, 0x01, 0x08, 0x00, 0x06, 0x04, 0x00, 0x02]);
+    assert_eq!(reply[8..14], mac.0);
+    assert_eq!(reply[14..18], [10, 0, 2, 15]);
+    assert_eq!(reply[18..28], GATEWAY_REQUEST[8..18]);
+
+    assert_eq!(ArpPacket::parse(&GATEWAY_REQUEST[..PACKET_LEN - 1]), None);
+    let mut not_ipv4 = GATEWAY_REQUEST;
+    not_ipv4[2] = 0x86; // IPv6
+    assert_eq!(ArpPacket::parse(&not_ipv4), None);
+}
+
+#[test_case]
+fn test_cache_replaces_oldest() {
+    let mut cache = ArpCache { entries: [None; CACHE_SIZE], next: 0 };
+    let ip = |n: u8| Ipv4Address([192, 168, 0, n]);
+    let mac = |n: u8| MacAddress([2, 0, 0, 0, 0, n]);
+    for n in 0..CACHE_SIZE as u8 {
+        cache.insert(ip(n), mac(n));
+    }
+    cache.insert(ip(3), mac(33)); // known --> updated in place
+    assert_eq!(cache.lookup(ip(3)), Some(mac(33)));
+
+    cache.insert(ip(100), mac(100));
+    assert_eq!(cache.lookup(ip(100)), Some(mac(100)));
+    assert_eq!(cache.lookup(ip(0)), None);
+    assert_eq!(cache.lookup(ip(1)), Some(mac(1)));
+}
+
+#[test_case]
+fn test_receive_learns_and_answers() {
+    super::test_interface();
+    let frame = EthernetFrame {
+        destination: MacAddress::BROADCAST,
+        source: MacAddress([0x52, 0x55, 0x0a, 0x00, 0x02, 0x02]),
+        ethertype: ETHERTYPE_ARP,
+        payload: &GATEWAY_REQUEST,
+    };
+    let sent_before = super::stats().frames_sent;
+    interrupts::without_interrupts(|| receive(&frame));
+    assert_eq!(lookup(Ipv4Address([10, 0, 2, 2])), Some(frame.source));
+    assert_eq!(super::stats().frames_sent, sent_before + 1, "no reply sent");
+}
@@ -0,0 +1,140 @@
+// Ethernet frames --> [destination MAC][source MAC][ethertype u16, big endian][payload] (the card adds/strips the CRC)
+// the payload is 46 to 1500 bytes: shorter frames are padded with zeros on send, so a received payload may end in padding
+// that the protocol inside (ARP, IPv4...) has to ignore using its own length field
+use super::{MacAddress, NetError};
+
+pub const HEADER_LEN: usize = 14;
+/// Largest payload of a frame.
+pub const MTU: usize = 1500;
+/// Shortest frame on the wire (without the CRC), anything shorter is a runt.
+pub const MIN_FRAME_LEN: usize = 60;
+/// Longest frame on the wire (without the CRC).
+pub const MAX_FRAME_LEN: usize = HEADER_LEN + MTU;
+
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+
+/// Why a received frame was rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameError {
+    /// Shorter than `MIN_FRAME_LEN`.
+    Runt,
+    /// Longer than `MAX_FRAME_LEN`.
+    Oversize,
+}
+
+/// A frame borrowing its payload, from `parse()` or to be written with `build()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EthernetFrame<'a> {
+    pub destination: MacAddress,
+    pub source: MacAddress,
+    pub ethertype: u16,
+    pub payload: &'a [u8],
+}
+
+impl<'a> EthernetFrame<'a> {
+    /// Split a received frame into its header fields and payload (padding included).
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, FrameError> {
+        if bytes.len() < MIN_FRAME_LEN {
+            return Err(FrameError::Runt);
+        }
+        if bytes.len() > MAX_FRAME_LEN {
+            return Err(FrameError::Oversize);
+        }
+        let mac = |offset: usize| {
+            let mut mac = [0; 6];
+            mac.copy_from_slice(&bytes[offset..offset + 6]);
+            MacAddress(mac)
+        };
+        Ok(EthernetFrame {
+            destination: mac(0),
+            source: mac(6),
+            ethertype: u16::from_be_bytes([bytes[12], bytes[13]]),
+            payload: &bytes[HEADER_LEN..],
+        })
+    }
+
+    /// Write the frame into `buf`, padded to `MIN_FRAME_LEN`, returns its length.
+    pub fn build(&self, buf: &mut [u8; MAX_FRAME_LEN]) -> Result<usize, NetError> {
+        if self.payload.len() > MTU {
+            return Err(NetError::FrameTooLarge);
+        }
+        let len = (HEADER_LEN + self.payload.len()).max(MIN_FRAME_LEN);
+        buf[0..6].copy_from_slice(&self.destination.0);
+        buf[6..12].copy_from_slice(&self.source.0);
+        buf[12..14].copy_from_slice(&self.ethertype.to_be_bytes());
+        buf[HEADER_LEN..HEADER_LEN + self.payload.len()].copy_from_slice(self.payload);
+        buf[HEADER_LEN + self.payload.len()..len].fill(0);
+        Ok(len)
+    }
+}
+
+// TESTS ===================================
+
+// captured from QEMU's user network: the gateway 10.0.2.2 asking who has 10.0.2.15 (padded to 60 bytes by the sender)
+#[cfg(test)]
+const ARP_REQUEST: [u8; 60] = [
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x52, 0x55, 0x0a, 0x00, 0x02, 0x02, 0x08, 0x06, // ethernet header
+    0x00, 0x01, 0x08, 0x00, 0x06, 0x04, 0x00, 0x01, // ethernet/IPv4, request
+    0x52, 0x55, 0x0a, 0x00, 0x02, 0x02, 0x0a, 0x00, 0x02, 0x02, // sender MAC and IP
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0a, 0x00, 0x02, 0x0f, // target MAC (unknown) and IP
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, // padding
+];
+
+// an ICMP echo request from 10.0.2.2 to 10.0.2.15 (IPv4 header + 8 byte ICMP header + 4 bytes of data)
+#[cfg(test)]
+const IPV4_PACKET: [u8; 60] = [
+    0x52, 0x54, 0x00, 0x12, 0x34, 0x56, 0x52, 0x55, 0x0a, 0x00, 0x02, 0x02, 0x08, 0x00, // ethernet header
+    0x45, 0x00, 0x00, 0x20, 0x00, 0x01, 0x00, 0x00, 0x40, 0x01, 0x62, 0xcc, // IPv4 header (total length 32)
+    0x0a, 0x00, 0x02, 0x02, 0x0a, 0x00, 0x02, 0x0f,
+    0x08, 0x00, 0xf7, 0xfd, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, // ICMP echo request + data
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, // padding
+];
+
+#[test_case]
+fn test_parse_captured_frames() {
+    let arp = EthernetFrame::parse(&ARP_REQUEST).expect("ARP request rejected");
+    assert_eq!(arp.destination, MacAddress::BROADCAST);
+    assert_eq!(arp.source, MacAddress([0x52, 0x55, 0x0a, 0x00, 0x02, 0x02]));
+    assert_eq!(arp.ethertype, ETHERTYPE_ARP);
+    assert_eq!(arp.payload.len(), 46);
+    assert_eq!(arp.payload[..8], [0x00, 0x01, 0x08, 0x00, 0x06, 0x04, 0x00, 0x01]);
+
+    let ipv4 = EthernetFrame::parse(&IPV4_PACKET).expect("IPv4 packet rejected");
+    assert_eq!(ipv4.destination, MacAddress([0x52, 0x54, 0x00, 0x12, 0x34, 0x56]));
+    assert_eq!(ipv4.ethertype, ETHERTYPE_IPV4);
+    assert_eq!(ipv4.payload[0], 0x45); // version 4, 5 word header
+
+    // a runt (the ARP request before padding) and a frame one byte too long
+    assert_eq!(EthernetFrame::parse(&ARP_REQUEST[..42]), Err(FrameError::Runt));
+    assert_eq!(EthernetFrame::parse(&[]), Err(FrameError::Runt));
+    assert_eq!(EthernetFrame::parse(&[0; MAX_FRAME_LEN + 1]), Err(FrameError::Oversize));
+    assert!(EthernetFrame::parse(&[0; MAX_FRAME_LEN]).is_ok());
+}
+
+#[test_case]
+fn test_build_round_trip() {
+    let mut buf = [0xAA; MAX_FRAME_LEN];
+
+    // short payloads are padded with zeros up to the minimum frame length
+    let frame = EthernetFrame {
+        destination: MacAddress::BROADCAST,
+        source: MacAddress([0x52, 0x54, 0x00, 0x12, 0x34, 0x56]),
+        ethertype: ETHERTYPE_ARP,
+        payload: &ARP_REQUEST[HEADER_LEN..42],
+    };
+    let len = frame.build(&mut buf).expect("build failed");
+    assert_eq!(len, MIN_FRAME_LEN);
+    let parsed = EthernetFrame::parse(&buf[..len]).expect("built frame rejected");
+    assert_eq!((parsed.destination, parsed.source, parsed.ethertype), (frame.destination, frame.source, frame.ethertype));
+    assert_eq!(parsed.payload[..28], *frame.payload);
+    assert!(parsed.payload[28..].iter().all(|&byte| byte == 0));
+
+    // a full payload round trips unchanged, one byte more doesn't fit
+    let payload = [0x5A; MTU + 1];
+    let frame = EthernetFrame { payload: &payload[..MTU], ..frame };
+    let len = frame.build(&mut buf).expect("build failed");
+    assert_eq!(len, MAX_FRAME_LEN);
+    assert_eq!(EthernetFrame::parse(&buf[..len]), Ok(frame));
+    assert_eq!(EthernetFrame { payload: &payload, ..frame }.build(&mut buf), Err(NetError::FrameTooLarge));
+}
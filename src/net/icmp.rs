@@ -0,0 +1,169 @@
+// ICMP --> only echo (ping): [type][code][checksum][identifier][sequence][data], the checksum covers the whole message
+// - echo requests to us are answered from the interrupt handler: same message with type 0 and a new checksum, sent back
+//   to the card it came from (no ARP lookup needed)
+// - echo replies are kept in a small ring that ping() checks each tick for its identifier and sequence number
+use super::ethernet::MTU;
+use super::ipv4::{self, Ipv4Packet, PROTOCOL_ICMP};
+use super::{arp, Ipv4Address, MacAddress, NetError};
+use crate::interrupts::timer_ticks;
+use crate::task::sleep_ticks;
+use core::sync::atomic::{AtomicU16, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+pub const HEADER_LEN: usize = 8;
+pub const TYPE_ECHO_REPLY: u8 = 0;
+pub const TYPE_ECHO_REQUEST: u8 = 8;
+
+// identifier of our pings, constant so the replies are easy to spot in a capture
+const PING_IDENTIFIER: u16 = 0x6D6F; // "mo"
+const PING_DATA: &[u8] = b"mini_os ping";
+const REPLY_SLOTS: usize = 8;
+
+/// Turn the echo request `message` into its reply in `buf`, returns the reply's length.
+///
+/// `None` if `message` isn't an echo request (or has a bad checksum).
+pub fn echo_reply(message: &[u8], buf: &mut [u8; MTU]) -> Option<usize> {
+    if message.len() < HEADER_LEN || message.len() > buf.len() || message[0] != TYPE_ECHO_REQUEST || message[1] != 0 {
+        return None;
+    }
+    if ipv4::checksum(message) != 0 {
+        return None;
+    }
+    let reply = &mut buf[..message.len()];
+    reply.copy_from_slice(message);
+    reply[0] = TYPE_ECHO_REPLY;
+    reply[2..4].fill(0);
+    let sum = ipv4::checksum(reply);
+    reply[2..4].copy_from_slice(&sum.to_be_bytes());
+    Some(message.len())
+}
+
+// REPLIES ====================================
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct EchoReply {
+    source: Ipv4Address,
+    identifier: u16,
+    sequence: u16,
+}
+
+struct ReplyRing {
+    slots: [Option<EchoReply>; REPLY_SLOTS],
+    next: usize,
+}
+
+static REPLIES: Mutex<ReplyRing> = Mutex::new(ReplyRing { slots: [None; REPLY_SLOTS], next: 0 });
+
+// called by ipv4::receive() for every ICMP packet to us (in the interrupt handler)
+pub(super) fn receive(source_mac: MacAddress, packet: &Ipv4Packet) {
+    let message = packet.payload;
+    if message.len() < HEADER_LEN || ipv4::checksum(message) != 0 {
+        return;
+    }
+    match message[0] {
+        TYPE_ECHO_REQUEST => {
+            let mut buf = [0; MTU];
+            if let Some(len) = echo_reply(message, &mut buf) {
+                // the sender pings again if the reply is lost
+                let _ = ipv4::send_to(source_mac, packet.source, PROTOCOL_ICMP, packet.identification, &buf[..len]);
+            }
+        }
+        TYPE_ECHO_REPLY => {
+            let reply = EchoReply {
+                source: packet.source,
+                identifier: u16::from_be_bytes([message[4], message[5]]),
+                sequence: u16::from_be_bytes([message[6], message[7]]),
+            };
+            let mut ring = REPLIES.lock();
+            let next = ring.next;
+            ring.slots[next] = Some(reply);
+            ring.next = (next + 1) % REPLY_SLOTS;
+        }
+        _ => {}
+    }
+}
+
+// take the reply to our ping `sequence` from `source` out of the ring
+fn take_reply(source: Ipv4Address, sequence: u16) -> bool {
+    let wanted = EchoReply { source, identifier: PING_IDENTIFIER, sequence };
+    interrupts::without_interrupts(|| {
+        let mut ring = REPLIES.lock();
+        match ring.slots.iter_mut().find(|slot| **slot == Some(wanted)) {
+            Some(slot) => {
+                *slot = None;
+                true
+            }
+            None => false,
+        }
+    })
+}
+
+// PING ====================================
+
+static NEXT_SEQUENCE: AtomicU16 = AtomicU16::new(0);
+
+/// Send an echo request to `destination` and wait (about a second at most) for the reply, returns the round trip time
+/// in timer ticks.
+pub async fn ping(destination: Ipv4Address) -> Result<u64, NetError> {
+    let mac = arp::resolve(destination).await?;
+    let sequence = NEXT_SEQUENCE.fetch_add(1, Ordering::Relaxed);
+
+    let mut message = [0; HEADER_LEN + PING_DATA.len()];
+    message[0] = TYPE_ECHO_REQUEST;
+    message[4..6].copy_from_slice(&PING_IDENTIFIER.to_be_bytes());
+    message[6..8].copy_from_slice(&sequence.to_be_bytes());
+    message[HEADER_LEN..].copy_from_slice(PING_DATA);
+    let sum = ipv4::checksum(&message);
+    message[2..4].copy_from_slice(&sum.to_be_bytes());
+
+    let start = timer_ticks();
+    ipv4::send_to(mac, destination, PROTOCOL_ICMP, sequence, &message)?;
+    let deadline = start + u64::from(crate::config::get().timer_hz);
+    while timer_ticks() < deadline {
+        if take_reply(destination, sequence) {
+            return Ok(timer_ticks() - start);
+        }
+        sleep_ticks(1).await;
+    }
+    Err(NetError::Timeout)
+}
+
+// TESTS ===================================
+
+#[test_case]
+fn test_echo_reply_rewrite() {
+    // the ICMP part of ethernet.rs's IPV4_PACKET: echo request, id 1, seq 1, 4 zero bytes of data
+    let request = [0x08, 0x00, 0xf7, 0xfd, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00];
+    let mut buf = [0; MTU];
+    let len = echo_reply(&request, &mut buf).expect("echo request rejected");
+    assert_eq!(buf[..len], [0x00, 0x00, 0xff, 0xfd, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00]);
+    assert_eq!(ipv4::checksum(&buf[..len]), 0);
+
+    let mut corrupted = request;
+    corrupted[8] = 1;
+    assert_eq!(echo_reply(&corrupted, &mut buf), None);
+    assert_eq!(echo_reply(&buf[..len], &mut [0; MTU]), None); // a reply isn't a request
+}
+
+#[test_case]
+fn test_ping_gateway() {
+    use crate::task::{executor::Executor, Task};
+    use alloc::rc::Rc;
+    use core::cell::Cell;
+
+    super::test_interface();
+    let result = Rc::new(Cell::new(None));
+    let task_result = result.clone();
+    let mut executor = Executor::new();
+    let pinger = executor.spawn(Task::new(async move {
+        // QEMU's user network gateway answers pings
+        task_result.set(Some(ping(Ipv4Address([10, 0, 2, 2])).await));
+    }));
+    while !pinger.is_finished() {
+        executor.run_until_idle();
+        x86_64::instructions::hlt();
+    }
+    let rtt = result.get().expect("ping didn't finish").expect("no reply from the gateway");
+    assert!(rtt < u64::from(crate::config::get().timer_hz));
+}
@@ -0,0 +1,199 @@
+// IPv4 packets --> [header: version/length, TOS, total length, id, flags/fragment, TTL, protocol, checksum, source, destination]
+// [options][payload], all big endian
+// - the header is 20 bytes without options, its length (in 32 bit words) is in the low nibble of the first byte
+// - the total length counts header + payload --> anything after it in the ethernet payload is padding
+// - we don't reassemble fragments or send options, and only pass ICMP on (see icmp.rs)
+use super::ethernet::{EthernetFrame, ETHERTYPE_IPV4, MTU};
+use super::{icmp, Ipv4Address, MacAddress, NetError};
+
+/// Header length without options.
+pub const HEADER_LEN: usize = 20;
+/// TTL of the packets we send.
+pub const DEFAULT_TTL: u8 = 64;
+
+pub const PROTOCOL_ICMP: u8 = 1;
+
+/// Why a received packet was rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketError {
+    /// Shorter than its header.
+    Truncated,
+    /// Not IPv4.
+    BadVersion,
+    /// Header length below 20 bytes.
+    BadHeaderLength,
+    /// The total length is smaller than the header or larger than what was received.
+    BadTotalLength,
+    /// The header checksum doesn't match.
+    BadChecksum,
+}
+
+/// A packet borrowing its payload, from `parse()` or to be written with `build()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv4Packet<'a> {
+    pub source: Ipv4Address,
+    pub destination: Ipv4Address,
+    pub protocol: u8,
+    pub ttl: u8,
+    pub identification: u16,
+    pub payload: &'a [u8],
+}
+
+/// The internet checksum (RFC 1071): the one's complement of the one's complement sum of the 16 bit words of `data`
+/// (an odd last byte is padded with zero).
+///
+/// A header with a valid checksum in it sums to 0.
+pub fn checksum(data: &[u8]) -> u16 {
+    let mut sum = 0u32;
+    let mut words = data.chunks_exact(2);
+    for word in &mut words {
+        sum += u32::from(u16::from_be_bytes([word[0], word[1]]));
+    }
+    if let [last] = words.remainder() {
+        sum += u32::from(*last) << 8;
+    }
+    // fold the carries back in (twice is enough: the first fold leaves at most 0x1FFFE)
+    sum = (sum & 0xFFFF) + (sum >> 16);
+    sum = (sum & 0xFFFF) + (sum >> 16);
+    !(sum as u16)
+}
+
+impl<'a> Ipv4Packet<'a> {
+    /// Check the header of a received packet and strip it (and any ethernet padding) from the payload.
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, PacketError> {
+        if bytes.len() < HEADER_LEN {
+            return Err(PacketError::Truncated);
+        }
+        if bytes[0] >> 4 != 4 {
+            return Err(PacketError::BadVersion);
+        }
+        let header_len = usize::from(bytes[0] & 0xF) * 4;
+        if header_len < HEADER_LEN {
+            return Err(PacketError::BadHeaderLength);
+        }
+        if bytes.len() < header_len {
+            return Err(PacketError::Truncated);
+        }
+        let total_len = usize::from(u16::from_be_bytes([bytes[2], bytes[3]]));
+        if total_len < header_len || total_len > bytes.len() {
+            return Err(PacketError::BadTotalLength);
+        }
+        if checksum(&bytes[..header_len]) != 0 {
+            return Err(PacketError::BadChecksum);
+        }
+        let address = |offset: usize| Ipv4Address([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]]);
+        Ok(Ipv4Packet {
+            source: address(12),
+            destination: address(16),
+            protocol: bytes[9],
+            ttl: bytes[8],
+            identification: u16::from_be_bytes([bytes[4], bytes[5]]),
+            payload: &bytes[header_len..total_len],
+        })
+    }
+
+    /// Write the packet (20 byte header, "don't fragment" set) into `buf`, returns its length.
+    pub fn build(&self, buf: &mut [u8; MTU]) -> Result<usize, NetError> {
+        let len = HEADER_LEN + self.payload.len();
+        if len > MTU {
+            return Err(NetError::FrameTooLarge);
+        }
+        buf[0] = 0x45; // version 4, 5 words
+        buf[1] = 0;
+        buf[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+        buf[4..6].copy_from_slice(&self.identification.to_be_bytes());
+        buf[6..8].copy_from_slice(&0x4000u16.to_be_bytes()); // don't fragment
+        buf[8] = self.ttl;
+        buf[9] = self.protocol;
+        buf[10..12].fill(0);
+        buf[12..16].copy_from_slice(&self.source.0);
+        buf[16..20].copy_from_slice(&self.destination.0);
+        let sum = checksum(&buf[..HEADER_LEN]);
+        buf[10..12].copy_from_slice(&sum.to_be_bytes());
+        buf[HEADER_LEN..len].copy_from_slice(self.payload);
+        Ok(len)
+    }
+}
+
+/// Send `payload` from our address to `destination`, whose card is `mac` (see `arp::resolve()`).
+pub fn send_to(mac: MacAddress, destination: Ipv4Address, protocol: u8, identification: u16, payload: &[u8]) -> Result<(), NetError> {
+    let source = super::ipv4().ok_or(NetError::NoAddress)?;
+    let packet = Ipv4Packet { source, destination, protocol, ttl: DEFAULT_TTL, identification, payload };
+    let mut buf = [0; MTU];
+    let len = packet.build(&mut buf)?;
+    super::send(mac, ETHERTYPE_IPV4, &buf[..len])
+}
+
+// called by receive_frame() for every IPv4 frame (in the interrupt handler)
+pub(super) fn receive(frame: &EthernetFrame) {
+    let Ok(packet) = Ipv4Packet::parse(frame.payload) else {
+        return;
+    };
+    if Some(packet.destination) != super::ipv4() {
+        return;
+    }
+    if packet.protocol == PROTOCOL_ICMP {
+        icmp::receive(frame.source, &packet);
+    }
+}
+
+// TESTS ===================================
+
+// the example header from Wikipedia's "IPv4 header checksum" article, checksum 0xb861
+#[cfg(test)]
+const EXAMPLE_HEADER: [u8; HEADER_LEN] = [
+    0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0xb8, 0x61, 0xc0, 0xa8, 0x00, 0x01, 0xc0, 0xa8, 0x00, 0xc7,
+];
+
+#[test_case]
+fn test_checksum_vectors() {
+    // RFC 1071 section 3: the sum of these words is 0xddf2
+    assert_eq!(checksum(&[0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7]), !0xddf2);
+    // an odd length is padded with a zero byte
+    assert_eq!(checksum(&[0x00, 0x01, 0xf2]), checksum(&[0x00, 0x01, 0xf2, 0x00]));
+    assert_eq!(checksum(&[]), 0xFFFF);
+
+    let mut header = EXAMPLE_HEADER;
+    assert_eq!(checksum(&header), 0);
+    header[10..12].fill(0);
+    assert_eq!(checksum(&header), 0xb861);
+}
+
+#[test_case]
+fn test_parse_rejects_bad_headers() {
+    let mut bytes = [0; 0x73];
+    bytes[..HEADER_LEN].copy_from_slice(&EXAMPLE_HEADER);
+    let packet = Ipv4Packet::parse(&bytes).expect("valid header rejected");
+    assert_eq!(packet.source, Ipv4Address([192, 168, 0, 1]));
+    assert_eq!(packet.destination, Ipv4Address([192, 168, 0, 199]));
+    assert_eq!(packet.protocol, 0x11);
+    assert_eq!(packet.payload.len(), 0x73 - HEADER_LEN);
+
+    assert_eq!(Ipv4Packet::parse(&bytes[..HEADER_LEN - 1]), Err(PacketError::Truncated));
+    assert_eq!(Ipv4Packet::parse(&bytes[..0x72]), Err(PacketError::BadTotalLength));
+    let mut bad = bytes;
+    bad[0] = 0x65;
+    assert_eq!(Ipv4Packet::parse(&bad), Err(PacketError::BadVersion));
+    bad[0] = 0x44;
+    assert_eq!(Ipv4Packet::parse(&bad), Err(PacketError::BadHeaderLength));
+    bad = bytes;
+    bad[8] -= 1; // TTL changed without fixing the checksum
+    assert_eq!(Ipv4Packet::parse(&bad), Err(PacketError::BadChecksum));
+}
+
+#[test_case]
+fn test_build_round_trip() {
+    let payload = [1, 2, 3, 4, 5];
+    let packet = Ipv4Packet {
+        source: Ipv4Address([10, 0, 2, 15]),
+        destination: Ipv4Address([10, 0, 2, 2]),
+        protocol: PROTOCOL_ICMP,
+        ttl: DEFAULT_TTL,
+        identification: 0x1234,
+        payload: &payload,
+    };
+    let mut buf = [0; MTU];
+    let len = packet.build(&mut buf).expect("build failed");
+    assert_eq!(len, HEADER_LEN + payload.len());
+    assert_eq!(Ipv4Packet::parse(&buf[..len]), Ok(packet));
+}
@@ -0,0 +1,200 @@
+// Thread-Local Storage (TLS) through the FS and GS segment bases
+// in 64-bit mode segmentation is mostly gone, but the FS and GS registers still have a base address that gets added to every
+// memory access made through them --> `mov rax, fs:[8]` reads the u64 at (FS base + 8)
+// the bases are set through MSRs (model specific registers) rather than the GDT, so each task can get its own block of memory
+// and reach it with a single instruction by pointing FS at it whenever that task is switched to (see task/executor.rs)
+// userspace conventionally owns FS for TLS, the kernel usually keeps GS for its per-cpu data
+use alloc::alloc::{alloc_zeroed, dealloc, Layout};
+use core::arch::asm;
+use core::sync::atomic::{AtomicU64, Ordering};
+use crate::task::TaskId;
+use crate::cpu::msr;
+use x86_64::VirtAddr;
+
+pub use msr::{IA32_FS_BASE, IA32_GS_BASE};
+
+/// Size of the TLS block every task gets on creation.
+pub const TASK_TLS_SIZE: usize = 256;
+
+// TLS blocks are aligned so any u64 slot (and SSE values if we ever enable them) can live at any 16 byte multiple
+const TLS_ALIGN: usize = 16;
+
+/// Point the FS segment base at `addr`.
+pub fn set_fs_base(addr: u64) {
+    // writing a non canonical address would #GP, so only ever hand this valid virtual addresses
+    unsafe { msr::write(IA32_FS_BASE, addr) };
+}
+
+/// The current FS segment base.
+pub fn get_fs_base() -> u64 {
+    unsafe { msr::read(IA32_FS_BASE) }
+}
+
+/// Point the GS segment base at `addr`.
+pub fn set_gs_base(addr: u64) {
+    unsafe { msr::write(IA32_GS_BASE, addr) };
+}
+
+/// The current GS segment base.
+pub fn get_gs_base() -> u64 {
+    unsafe { msr::read(IA32_GS_BASE) }
+}
+
+fn tls_layout(size: usize) -> Option<Layout> {
+    Layout::from_size_align(size, TLS_ALIGN).ok()
+}
+
+/// Allocate a zeroed, 16 byte aligned block on the heap to be used as a TLS block.
+///
+/// Returns `None` for a zero size or if the heap is out of memory.
+pub fn allocate_tls_block(size: usize) -> Option<VirtAddr> {
+    if size == 0 {
+        return None;
+    }
+    let ptr = unsafe { alloc_zeroed(tls_layout(size)?) };
+    if ptr.is_null() {
+        None
+    } else {
+        Some(VirtAddr::from_ptr(ptr))
+    }
+}
+
+/// Give a block from `allocate_tls_block()` back to the heap.
+///
+/// This function is unsafe because the caller must guarantee that `addr` and `size` are exactly what was
+/// allocated, and that FS/GS no longer point into the block.
+pub unsafe fn free_tls_block(addr: VirtAddr, size: usize) {
+    if let Some(layout) = tls_layout(size) {
+        dealloc(addr.as_mut_ptr(), layout);
+    }
+}
+
+/// Write `value` to the u64 at `offset` bytes into the current TLS block (`[fs:offset]`).
+///
+/// This function is unsafe because the caller must guarantee that FS points to a TLS block
+/// with at least `offset + 8` bytes.
+pub unsafe fn tls_set_slot(offset: usize, value: u64) {
+    asm!("mov fs:[{}], {}", in(reg) offset, in(reg) value, options(nostack, preserves_flags));
+}
+
+/// Read the u64 at `offset` bytes into the current TLS block (`[fs:offset]`).
+///
+/// This function is unsafe for the same reasons as `tls_set_slot()`.
+pub unsafe fn tls_get_slot(offset: usize) -> u64 {
+    let value: u64;
+    asm!("mov {}, fs:[{}]", out(reg) value, in(reg) offset, options(nostack, preserves_flags, readonly));
+    value
+}
+
+/// An owned TLS block that is freed when dropped --> every Task holds one.
+#[derive(Debug)]
+pub struct TlsBlock {
+    addr: VirtAddr,
+    size: usize,
+}
+
+impl TlsBlock {
+    pub fn new(size: usize) -> Option<TlsBlock> {
+        allocate_tls_block(size).map(|addr| TlsBlock { addr, size })
+    }
+
+    pub fn addr(&self) -> VirtAddr {
+        self.addr
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Make this the block `tls_get_slot()` and `tls_set_slot()` operate on.
+    pub fn activate(&self) {
+        set_fs_base(self.addr.as_u64());
+    }
+}
+
+impl Drop for TlsBlock {
+    fn drop(&mut self) {
+        // don't leave FS dangling into freed memory
+        if get_fs_base() == self.addr.as_u64() {
+            set_fs_base(0);
+        }
+        unsafe { free_tls_block(self.addr, self.size) };
+    }
+}
+
+// PER-CPU DATA ==============================
+// the kernel's data about "what this CPU is doing right now", reached through the GS base
+// every CPU points GS at its own block: index 0 is the boot CPU, the application processors follow in the order they were
+// started (see smp.rs)
+
+/// The most CPUs there is a `PerCpu` block for, `smp::init()` leaves any further ones halted.
+pub const MAX_CPUS: usize = 16;
+
+/// Data owned by one CPU.
+#[derive(Debug)]
+pub struct PerCpu {
+    current_task: AtomicU64, // raw TaskId of the task being polled, 0 = idle
+}
+
+#[allow(clippy::declare_interior_mutable_const)] // only used to initialize the array below
+const IDLE_CPU: PerCpu = PerCpu { current_task: AtomicU64::new(0) };
+static CPUS: [PerCpu; MAX_CPUS] = [IDLE_CPU; MAX_CPUS];
+
+/// Point GS at the boot CPU's `PerCpu` block.
+pub fn init_per_cpu() {
+    init_per_cpu_for(0);
+}
+
+/// Point GS at the `PerCpu` block of CPU `index` (0 = the boot CPU), panics for `MAX_CPUS` or more.
+pub fn init_per_cpu_for(index: usize) {
+    set_gs_base(&CPUS[index] as *const PerCpu as u64);
+}
+
+/// This CPU's `PerCpu` block, `None` before `init_per_cpu()`.
+pub fn this_cpu() -> Option<&'static PerCpu> {
+    let base = get_gs_base();
+    CPUS.iter().find(|cpu| *cpu as *const PerCpu as u64 == base)
+}
+
+impl PerCpu {
+    /// The task this CPU is running.
+    pub fn current_task(&self) -> TaskId {
+        TaskId::from_u64(self.current_task.load(Ordering::Relaxed))
+    }
+
+    /// Record that this CPU now runs `id` (`TaskId::IDLE` when it runs no task).
+    pub fn set_current_task(&self, id: TaskId) {
+        self.current_task.store(id.as_u64(), Ordering::Relaxed);
+    }
+}
+
+// TESTS ===================================
+
+#[test_case]
+fn test_tls_slots_are_isolated() {
+    let previous = get_fs_base();
+    let task_a = TlsBlock::new(64).expect("tls allocation failed");
+    let task_b = TlsBlock::new(64).expect("tls allocation failed");
+
+    // "switch" to task a and b in turn, each writing the same slot
+    task_a.activate();
+    unsafe { tls_set_slot(8, 0xaaaa) };
+    task_b.activate();
+    assert_eq!(unsafe { tls_get_slot(8) }, 0); // fresh blocks are zeroed
+    unsafe { tls_set_slot(8, 0xbbbb) };
+
+    task_a.activate();
+    assert_eq!(unsafe { tls_get_slot(8) }, 0xaaaa);
+    task_b.activate();
+    assert_eq!(unsafe { tls_get_slot(8) }, 0xbbbb);
+
+    set_fs_base(previous);
+}
+
+#[test_case]
+fn test_gs_base_round_trip() {
+    let previous = get_gs_base();
+    set_gs_base(0x_dead_b000);
+    assert_eq!(get_gs_base(), 0x_dead_b000);
+    set_gs_base(previous);
+}
@@ -0,0 +1,192 @@
+// Powering the machine off --> ACPI S5 ("soft off") first, then the emulators' magic ports, then give up and halt
+// - S5 is entered by writing SLP_TYP (bits 10-12) | SLP_EN (bit 13) to the PM1a (and PM1b) control register from the FADT,
+//   the right SLP_TYP value is in the \_S5 package of the DSDT's AML code --> without an AML interpreter we try the values
+//   firmware commonly uses (0 for QEMU/Bochs, 5 and 7 on many Intel chipsets), a wrong one is ignored or sends us to
+//   another sleep state we don't wake up from either
+// - ACPI mode has to be on (SCI_EN in PM1a control) for the PM1 registers to work, see enable_acpi_mode()
+// - QEMU also powers off on 0x2000 written to 0x604 (its PIIX4/ICH9 PM1a control), older QEMU/Bochs on 0xB004
+// - rebooting is a ladder too: the keyboard controller's reset line, the chipset's reset control register, and as a last
+//   resort a triple fault (an exception with no IDT to handle it) which resets every x86 CPU
+use crate::acpi::{self, Fadt};
+use crate::println;
+use x86_64::instructions::{interrupts, port::Port};
+
+const SLP_EN: u16 = 1 << 13;
+const SLP_TYP_SHIFT: u16 = 10;
+const SLP_TYP_MASK: u16 = 0b111 << SLP_TYP_SHIFT;
+// bit 0 of PM1 control, set by the firmware once ACPI mode is on
+const SCI_EN: u16 = 1;
+
+/// The SLP_TYP values tried for S5, in order.
+pub const S5_SLEEP_TYPES: [u8; 3] = [0, 5, 7];
+
+/// QEMU's and Bochs' power off ports and the value to write there.
+pub const EMULATOR_POWER_OFF: [(u16, u16); 2] = [(0x604, 0x2000), (0xB004, 0x2000)];
+
+// how long to wait for something to happen after a write (port reads, about 1 µs each)
+const SETTLE_READS: usize = 100_000;
+
+/// The PM1 control value that enters sleep type `slp_typ`, keeping the other bits of `current`.
+pub fn sleep_command(current: u16, slp_typ: u8) -> u16 {
+    (current & !SLP_TYP_MASK) | (u16::from(slp_typ) << SLP_TYP_SHIFT & SLP_TYP_MASK) | SLP_EN
+}
+
+// wait a little by reading a port nothing is attached to (like the port 0x80 delay of old)
+fn settle() {
+    let mut port: Port<u8> = Port::new(0x80);
+    for _ in 0..SETTLE_READS {
+        unsafe { port.read() };
+    }
+}
+
+// switch from legacy to ACPI mode if the firmware hasn't already
+fn enable_acpi_mode(fadt: &Fadt) {
+    let mut pm1a: Port<u16> = Port::new(fadt.pm1a_control_block as u16);
+    if unsafe { pm1a.read() } & SCI_EN != 0 || fadt.smi_command_port == 0 || fadt.acpi_enable == 0 {
+        return;
+    }
+    unsafe { Port::<u8>::new(fadt.smi_command_port as u16).write(fadt.acpi_enable) };
+    settle(); // the SMM handler may take a moment, S5 is tried either way
+}
+
+// try S5 with each of the common sleep types, returns if we're still running afterwards
+fn acpi_power_off(fadt: &Fadt) {
+    if fadt.pm1a_control_block == 0 {
+        return;
+    }
+    enable_acpi_mode(fadt);
+    let mut pm1a: Port<u16> = Port::new(fadt.pm1a_control_block as u16);
+    let mut pm1b: Port<u16> = Port::new(fadt.pm1b_control_block as u16);
+    for &slp_typ in &S5_SLEEP_TYPES {
+        unsafe {
+            let command = sleep_command(pm1a.read(), slp_typ);
+            if fadt.pm1b_control_block != 0 {
+                pm1b.write(sleep_command(pm1b.read(), slp_typ));
+            }
+            pm1a.write(command);
+        }
+        settle();
+    }
+}
+
+/// Turn the machine off. Prints "power off failed" and halts forever if nothing worked.
+///
+/// Doesn't allocate, so it can be called from interrupt handlers and the panic handler.
+pub fn shutdown() -> ! {
+    interrupts::disable(); // nothing runs in between the attempts
+    println!("powering off");
+    crate::allocator::print_heap_peak();
+    if let Some(fadt) = acpi::tables().and_then(|tables| tables.fadt().ok()) {
+        acpi_power_off(&fadt);
+    }
+    for &(port, value) in &EMULATOR_POWER_OFF {
+        unsafe { Port::<u16>::new(port).write(value) };
+        settle();
+    }
+    println!("power off failed");
+    crate::hlt_loop();
+}
+
+/// The `shutdown` console command (see serial.rs).
+pub fn shutdown_command() {
+    shutdown();
+}
+
+/// Wait `seconds` (counted on the RTC, so this works with interrupts off) and then `shutdown()`.
+pub fn shutdown_after(seconds: u32) -> ! {
+    let mut last = crate::drivers::cmos::read_rtc_time().second;
+    let mut remaining = seconds;
+    while remaining > 0 {
+        let second = crate::drivers::cmos::read_rtc_time().second;
+        if second != last {
+            last = second;
+            remaining -= 1;
+        }
+        core::hint::spin_loop();
+    }
+    shutdown();
+}
+
+// REBOOT ====================================
+
+// the 8042 keyboard controller: status bit 1 is set while it hasn't taken the last byte written to it yet
+const KBC_STATUS_COMMAND: u16 = 0x64;
+const KBC_INPUT_FULL: u8 = 1 << 1;
+const KBC_PULSE_RESET: u8 = 0xFE;
+// the PIIX/ICH reset control register: bit 1 = hard reset, bit 2 = do it (0 -> 1 edge)
+const RESET_CONTROL: u16 = 0xCF9;
+const RESET_CONTROL_HARD: u8 = 0x02;
+const RESET_CONTROL_RESET: u8 = 0x04;
+
+// pulse the CPU's reset line through the keyboard controller
+fn keyboard_controller_reset() {
+    let mut port: Port<u8> = Port::new(KBC_STATUS_COMMAND);
+    for _ in 0..SETTLE_READS {
+        if unsafe { port.read() } & KBC_INPUT_FULL == 0 {
+            break;
+        }
+    }
+    unsafe { port.write(KBC_PULSE_RESET) };
+}
+
+fn reset_control_reset() {
+    let mut port: Port<u8> = Port::new(RESET_CONTROL);
+    unsafe {
+        port.write(RESET_CONTROL_HARD);
+        port.write(RESET_CONTROL_HARD | RESET_CONTROL_RESET);
+    }
+}
+
+// an exception with an empty IDT --> double fault without a handler --> triple fault --> reset
+fn triple_fault() {
+    use x86_64::instructions::tables::lidt;
+    use x86_64::structures::DescriptorTablePointer;
+
+    let empty = DescriptorTablePointer { limit: 0, base: x86_64::VirtAddr::zero() };
+    unsafe {
+        lidt(&empty);
+        core::arch::asm!("int3");
+    }
+}
+
+/// Restart the machine: keyboard controller reset line, then the 0xCF9 reset register, then a triple fault.
+///
+/// Doesn't allocate, so it can be called from interrupt handlers and the panic handler.
+pub fn reboot() -> ! {
+    interrupts::disable(); // no handler may run in the middle of the sequence (or catch the triple fault's int3)
+    println!("rebooting");
+    keyboard_controller_reset();
+    settle();
+    reset_control_reset();
+    settle();
+    triple_fault();
+    println!("reboot failed");
+    crate::hlt_loop();
+}
+
+/// The `reboot` console command (see serial.rs).
+pub fn reboot_command() {
+    reboot();
+}
+
+// TESTS ===================================
+
+#[test_case]
+fn test_sleep_command() {
+    // SCI_EN and the other bits survive, an old SLP_TYP is replaced
+    assert_eq!(sleep_command(0x0001, 0), 0x2001);
+    assert_eq!(sleep_command(0x0001, 5), 0x3401);
+    assert_eq!(sleep_command(0x1C01, 0), 0x2001);
+    assert_eq!(sleep_command(0, 7), 0x3C00);
+    assert_eq!(sleep_command(0, 0xFF), 0x3C00, "SLP_TYP is only 3 bits");
+    // QEMU's port write is S5 with sleep type 0
+    assert_eq!(sleep_command(0, S5_SLEEP_TYPES[0]), EMULATOR_POWER_OFF[0].1);
+}
+
+#[test_case]
+fn test_fadt_has_power_management() {
+    let fadt = acpi::init().expect("no ACPI tables").fadt().expect("bad FADT");
+    assert_eq!(fadt.pm1_control_length, 2);
+    // QEMU's PIIX4 and ICH9 power management blocks both have PM1a control at offset 4
+    assert_eq!(fadt.pm1a_control_block & 0xF, 4);
+}
@@ -0,0 +1,326 @@
+// User processes --> a program running in ring 3 inside its own address space (see memory/address_space.rs)
+// for now there is no scheduling: Process::run() jumps into the program and only comes back once it calls exit
+//
+// entering user mode: we build the stack frame an interrupt would have pushed (SS, RSP, RFLAGS, CS, RIP) with the user
+// segments and execute `iretq`, the CPU "returns" into ring 3 at the entry point
+// leaving user mode: interrupts and syscalls from ring 3 switch to the stack in TSS RSP0 (the process's kernel stack),
+// the exit syscall then jumps back onto the stack Process::run() was called from (like setjmp/longjmp) --> the "kernel continuation"
+use crate::memory::address_space::{AddressSpace, AddressSpaceError, USER_SPACE_END};
+use alloc::{vec, vec::Vec};
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+pub mod elf;
+
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::PageTableFlags,
+    VirtAddr,
+};
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Pages in the user stack (one more unmapped guard page sits below it so an overflow page faults).
+pub const USER_STACK_PAGES: u64 = 4;
+/// Top of the user stack --> one page below the end of user space so there is an unmapped page above it too.
+pub const USER_STACK_TOP: u64 = USER_SPACE_END - PAGE_SIZE;
+
+const KERNEL_STACK_SIZE: usize = 4096 * 4;
+
+/// A process identifier.
+pub type Pid = u64;
+
+fn next_pid() -> Pid {
+    static NEXT_PID: AtomicU64 = AtomicU64::new(1);
+    NEXT_PID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// A user program's address space, user stack and kernel stack.
+pub struct Process {
+    pid: Pid,
+    address_space: AddressSpace,
+    kernel_stack: Vec<u8>, // RSP0 while the process runs --> interrupts and syscalls from ring 3 run on this
+}
+
+impl Process {
+    /// Create a process with a fresh address space and a mapped user stack, ready for a program to be mapped into it.
+    pub fn new() -> Result<Process, AddressSpaceError> {
+        let mut address_space = AddressSpace::new()?;
+        let stack_bottom = VirtAddr::new(USER_STACK_TOP - USER_STACK_PAGES * PAGE_SIZE);
+        address_space.map_user_range(
+            stack_bottom,
+            USER_STACK_PAGES,
+            PageTableFlags::WRITABLE | crate::memory::no_execute(),
+        )?;
+
+        Ok(Process {
+            pid: next_pid(),
+            address_space,
+            kernel_stack: vec![0; KERNEL_STACK_SIZE],
+        })
+    }
+
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    pub fn address_space(&self) -> &AddressSpace {
+        &self.address_space
+    }
+
+    pub fn address_space_mut(&mut self) -> &mut AddressSpace {
+        &mut self.address_space
+    }
+
+    fn kernel_stack_top(&self) -> VirtAddr {
+        // stacks grow down, keep the top 16 byte aligned
+        (VirtAddr::from_ptr(self.kernel_stack.as_ptr()) + self.kernel_stack.len()).align_down(16u64)
+    }
+
+    /// Switch to the process's address space and run it in user mode starting at `entry`.
+    ///
+    /// Returns the exit code once the program calls the exit syscall, after switching back to the kernel's
+    /// address space and tearing the process down (which frees all of its frames).
+    pub fn run(mut self, entry: VirtAddr) -> i64 {
+        use x86_64::instructions::interrupts;
+
+        let interrupts_were_enabled = interrupts::are_enabled();
+        let selectors = crate::gdt::selectors();
+        let (kernel_level_4_frame, cr3_flags) = Cr3::read();
+
+        // no interrupts until we are in user mode, the CPU state is half switched in between
+        interrupts::disable();
+        *CURRENT.lock() = Some(RunningProcess {
+            pid: self.pid,
+            address_space: &mut self.address_space,
+        });
+        let exit_code = unsafe {
+            crate::gdt::set_kernel_stack(self.kernel_stack_top());
+            Cr3::write(self.address_space.level_4_frame(), cr3_flags);
+            mini_os_enter_user_mode(
+                entry.as_u64(),
+                USER_STACK_TOP,
+                u64::from(selectors.user_code_selector.0),
+                u64::from(selectors.user_data_selector.0),
+                core::ptr::addr_of_mut!(KERNEL_RSP),
+            )
+        };
+
+        // back from exit (still with interrupts disabled, the syscall came in through an interrupt gate)
+        unsafe { Cr3::write(kernel_level_4_frame, cr3_flags) };
+        *CURRENT.lock() = None;
+        drop(self); // frees the address space and the kernel stack we are no longer on
+        if interrupts_were_enabled {
+            interrupts::enable();
+        }
+        exit_code
+    }
+}
+
+// CURRENT PROCESS ====================================
+
+struct RunningProcess {
+    pid: Pid,
+    // points into the Process owned by Process::run() further up the kernel stack, which can't return (and drop it)
+    // before the process has exited and this has been cleared
+    address_space: *mut AddressSpace,
+}
+
+// only ever touched from the single CPU the process runs on
+unsafe impl Send for RunningProcess {}
+
+// the process that is running right now (only one at a time until processes are scheduled)
+static CURRENT: Mutex<Option<RunningProcess>> = Mutex::new(None);
+
+// kernel stack pointer saved when entering user mode, restored by exit
+static mut KERNEL_RSP: u64 = 0;
+
+/// The pid of the running process, `None` when no process is running.
+pub fn current_pid() -> Option<Pid> {
+    CURRENT.lock().as_ref().map(|process| process.pid)
+}
+
+/// Whether `[start, start + len)` is mapped user memory of the running process.
+pub fn current_user_range_is_mapped(start: VirtAddr, len: u64) -> bool {
+    match CURRENT.lock().as_ref() {
+        Some(process) => unsafe { (*process.address_space).is_user_range_mapped(start, len) },
+        None => false,
+    }
+}
+
+/// Leave user mode and return `code` from `Process::run()`, called by the exit syscall.
+pub fn exit_current(code: i64) -> ! {
+    assert!(CURRENT.lock().is_some(), "exit called with no running process");
+    unsafe { mini_os_return_to_kernel(KERNEL_RSP, code) }
+}
+
+// ENTERING/LEAVING USER MODE ====================================
+
+// mini_os_enter_user_mode(rip, rsp, cs, ss, saved_rsp) -> exit code
+//   saves the callee-saved registers + the stack pointer (to *saved_rsp), then `iretq`s into user mode
+// mini_os_return_to_kernel(saved_rsp, code) -> !
+//   switches back to the saved stack, restores the callee-saved registers and returns `code` from mini_os_enter_user_mode
+global_asm!(
+    ".global mini_os_enter_user_mode",
+    "mini_os_enter_user_mode:",
+    "push rbx",
+    "push rbp",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov [r8], rsp",
+    "push rcx", // ss
+    "push rsi", // rsp
+    "push 0x202", // rflags --> only the interrupt flag (+ the always set bit 1)
+    "push rdx", // cs
+    "push rdi", // rip
+    // don't leak kernel values into user mode
+    "xor eax, eax",
+    "xor ebx, ebx",
+    "xor ecx, ecx",
+    "xor edx, edx",
+    "xor esi, esi",
+    "xor edi, edi",
+    "xor ebp, ebp",
+    "xor r8d, r8d",
+    "xor r9d, r9d",
+    "xor r10d, r10d",
+    "xor r11d, r11d",
+    "xor r12d, r12d",
+    "xor r13d, r13d",
+    "xor r14d, r14d",
+    "xor r15d, r15d",
+    "iretq",
+    "",
+    ".global mini_os_return_to_kernel",
+    "mini_os_return_to_kernel:",
+    "mov rsp, rdi",
+    "mov rax, rsi",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbp",
+    "pop rbx",
+    "ret",
+);
+
+extern "C" {
+    fn mini_os_enter_user_mode(rip: u64, rsp: u64, cs: u64, ss: u64, saved_rsp: *mut u64) -> i64;
+    fn mini_os_return_to_kernel(saved_rsp: u64, code: i64) -> !;
+}
+
+// TESTS ===================================
+
+#[test_case]
+fn test_run_hand_assembled_program() {
+    use crate::memory::address_space::USER_SPACE_START;
+
+    #[rustfmt::skip]
+    const PROGRAM: &[u8] = &[
+        0xb8, 0x01, 0x00, 0x00, 0x00, // mov eax, 1 (write)
+        0xbf, 0x01, 0x00, 0x00, 0x00, // mov edi, 1 (stdout)
+        0x48, 0x8d, 0x35, 0x15, 0x00, 0x00, 0x00, // lea rsi, [rip + 0x15] (the message below)
+        0xba, 0x0c, 0x00, 0x00, 0x00, // mov edx, 12
+        0xcd, 0x80, // int 0x80
+        0xb8, 0x3c, 0x00, 0x00, 0x00, // mov eax, 60 (exit)
+        0xbf, 0x2a, 0x00, 0x00, 0x00, // mov edi, 42
+        0xcd, 0x80, // int 0x80
+        0xeb, 0xfe, // jmp $ (never reached)
+        b'h', b'i', b' ', b'f', b'r', b'o', b'm', b' ', b'u', b's', b'e', b'r',
+    ];
+
+    let baseline = crate::memory::allocated_frame_count();
+    let mut process = Process::new().expect("process creation failed");
+    let entry = VirtAddr::new(USER_SPACE_START);
+    // code pages are read only (no WRITABLE) and executable (no NO_EXECUTE)
+    process.address_space_mut().map_user_range(entry, 1, PageTableFlags::empty()).expect("mapping failed");
+    assert!(process.address_space_mut().write(entry, PROGRAM));
+
+    assert_eq!(process.run(entry), 42);
+    assert_eq!(crate::memory::allocated_frame_count(), baseline);
+    assert_eq!(current_pid(), None);
+}
+
+#[test_case]
+fn test_syscalls_from_user_mode() {
+    use crate::interrupts::timer_ticks;
+    use crate::memory::address_space::USER_SPACE_START;
+    use crate::syscall::ms_to_ticks;
+    use crate::console::capture;
+
+    #[rustfmt::skip]
+    const PROGRAM: &[u8] = &[
+        0xb8, 0x27, 0x00, 0x00, 0x00, // mov eax, 39 (getpid)
+        0xcd, 0x80, // int 0x80
+        0x89, 0xc3, // mov ebx, eax (syscalls preserve every register but rax)
+        0xb8, 0x01, 0x00, 0x00, 0x00, // mov eax, 1 (write)
+        0xbf, 0x01, 0x00, 0x00, 0x00, // mov edi, 1 (stdout)
+        0x48, 0x8d, 0x35, 0x1e, 0x00, 0x00, 0x00, // lea rsi, [rip + 0x1e] (the message below)
+        0xba, 0x0d, 0x00, 0x00, 0x00, // mov edx, 13
+        0xcd, 0x80, // int 0x80
+        0xb8, 0x23, 0x00, 0x00, 0x00, // mov eax, 35 (sleep_ms)
+        0xbf, 0x64, 0x00, 0x00, 0x00, // mov edi, 100
+        0xcd, 0x80, // int 0x80
+        0xb8, 0x3c, 0x00, 0x00, 0x00, // mov eax, 60 (exit)
+        0x89, 0xdf, // mov edi, ebx (exit with our pid)
+        0xcd, 0x80, // int 0x80
+        0xeb, 0xfe, // jmp $ (never reached)
+        b'\n', b's', b'y', b's', b'c', b'a', b'l', b'l', b's', b' ', b'o', b'k', b'\n',
+    ];
+
+    let mut process = Process::new().expect("process creation failed");
+    let pid = process.pid();
+    let entry = VirtAddr::new(USER_SPACE_START);
+    process.address_space_mut().map_user_range(entry, 1, PageTableFlags::empty()).expect("mapping failed");
+    assert!(process.address_space_mut().write(entry, PROGRAM));
+
+    let start = timer_ticks();
+    capture::start();
+    let exit_code = process.run(entry);
+    let output = capture::take();
+    assert_eq!(exit_code, pid as i64); // getpid echoed back through exit
+    assert!(timer_ticks() - start >= ms_to_ticks(100));
+    assert!(output.contains("\nsyscalls ok\n"), "got {:?}", output);
+}
+
+#[test_case]
+fn test_write_rejects_bad_pointers() {
+    use crate::memory::address_space::USER_SPACE_START;
+    use crate::syscall::abi::EFAULT;
+
+    // write(1, buf, 4) and exit with its result, for a `buf` that isn't mapped user memory
+    fn write_and_exit_program(buf: u64) -> [u8; 27] {
+        let mut program = [
+            0xb8, 0x01, 0x00, 0x00, 0x00, // mov eax, 1 (write)
+            0xbf, 0x01, 0x00, 0x00, 0x00, // mov edi, 1 (stdout)
+            0x48, 0xbe, 0, 0, 0, 0, 0, 0, 0, 0, // mov rsi, buf
+            0xba, 0x04, 0x00, 0x00, 0x00, // mov edx, 4
+            0xcd, 0x80, // int 0x80
+        ];
+        program[12..20].copy_from_slice(&buf.to_le_bytes());
+        program
+    }
+    #[rustfmt::skip]
+    const EXIT_WITH_RESULT: &[u8] = &[
+        0x48, 0x89, 0xc7, // mov rdi, rax
+        0xb8, 0x3c, 0x00, 0x00, 0x00, // mov eax, 60 (exit)
+        0xcd, 0x80, // int 0x80
+        0xeb, 0xfe, // jmp $ (never reached)
+    ];
+
+    let bad_pointers = [
+        crate::allocator::HEAP_START as u64, // kernel memory
+        USER_SPACE_START + 0x10_0000, // user address, but nothing is mapped there
+        USER_STACK_TOP - 2, // runs off the top of the user stack into the unmapped page above it
+    ];
+    for buf in bad_pointers {
+        let mut process = Process::new().expect("process creation failed");
+        let entry = VirtAddr::new(USER_SPACE_START);
+        process.address_space_mut().map_user_range(entry, 1, PageTableFlags::empty()).expect("mapping failed");
+        assert!(process.address_space_mut().write(entry, &write_and_exit_program(buf)));
+        assert!(process.address_space_mut().write(entry + 27u64, EXIT_WITH_RESULT));
+        assert_eq!(process.run(entry), EFAULT);
+    }
+}
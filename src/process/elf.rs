@@ -0,0 +1,359 @@
+// ELF64 loader --> maps the PT_LOAD segments of a static x86_64 executable into an AddressSpace
+// spec: https://refspecs.linuxfoundation.org/elf/gabi4+/ch4.eheader.html (file header) and ch5.pheader.html (program headers)
+// every field is read with bounds checked little endian reads and every sum is overflow checked, so a malformed file
+// can only ever produce an ElfError --> nothing is mapped until all headers have been validated
+use crate::memory::address_space::{AddressSpace, AddressSpaceError, USER_SPACE_START};
+use alloc::vec::Vec;
+use x86_64::{structures::paging::PageTableFlags, VirtAddr};
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Base address position independent (ET_DYN) executables are loaded at.
+pub const DYN_BASE: u64 = USER_SPACE_START;
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1; // little endian
+const EM_X86_64: u16 = 0x3e;
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+const FILE_HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+/// Why an ELF file couldn't be loaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    TooShort, // smaller than the file header
+    BadMagic,
+    UnsupportedClass, // not 64 bit
+    UnsupportedEndianness, // not little endian
+    UnsupportedMachine, // not x86_64
+    UnsupportedType, // not ET_EXEC or ET_DYN
+    BadProgramHeaders, // program header table out of the file or with the wrong entry size
+    SegmentOutOfBounds, // segment file contents past the end of the file
+    SizeOverflow, // an offset + size or address + size overflows
+    BadSegmentSize, // file size bigger than memory size
+    MisalignedSegment, // offset and address don't agree modulo the page size
+    OverlappingSegments,
+    BadEntryPoint, // entry point not inside an executable segment
+    Map(AddressSpaceError), // segment outside of user space, or mapping it failed
+}
+
+impl From<AddressSpaceError> for ElfError {
+    fn from(error: AddressSpaceError) -> Self {
+        ElfError::Map(error)
+    }
+}
+
+// bounds checked little endian reads
+fn read_u16(bytes: &[u8], offset: usize) -> Result<u16, ElfError> {
+    let field = bytes.get(offset..offset + 2).ok_or(ElfError::TooShort)?;
+    Ok(u16::from_le_bytes([field[0], field[1]]))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Result<u32, ElfError> {
+    let field = bytes.get(offset..offset + 4).ok_or(ElfError::TooShort)?;
+    Ok(u32::from_le_bytes([field[0], field[1], field[2], field[3]]))
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> Result<u64, ElfError> {
+    let field = bytes.get(offset..offset + 8).ok_or(ElfError::TooShort)?;
+    let mut value = [0; 8];
+    value.copy_from_slice(field);
+    Ok(u64::from_le_bytes(value))
+}
+
+// a validated PT_LOAD segment
+struct Segment {
+    vaddr: u64,
+    mem_end: u64,
+    file_offset: usize,
+    file_size: usize,
+    first_page: u64, // page aligned start address
+    page_count: u64,
+    flags: u32,
+}
+
+impl Segment {
+    fn contains(&self, addr: u64) -> bool {
+        addr >= self.vaddr && addr < self.mem_end
+    }
+
+    fn overlaps(&self, other: &Segment) -> bool {
+        self.first_page < other.first_page + other.page_count * PAGE_SIZE
+            && other.first_page < self.first_page + self.page_count * PAGE_SIZE
+    }
+
+    fn page_flags(&self) -> PageTableFlags {
+        let mut flags = PageTableFlags::empty();
+        if self.flags & PF_W != 0 {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if self.flags & PF_X == 0 {
+            flags |= crate::memory::no_execute();
+        }
+        flags
+    }
+}
+
+fn parse_segment(bytes: &[u8], header_offset: usize, base: u64) -> Result<Option<Segment>, ElfError> {
+    if read_u32(bytes, header_offset)? != PT_LOAD {
+        return Ok(None); // only loadable segments matter to us
+    }
+    let flags = read_u32(bytes, header_offset + 4)?;
+    let offset = read_u64(bytes, header_offset + 8)?;
+    let vaddr = read_u64(bytes, header_offset + 16)?
+        .checked_add(base)
+        .ok_or(ElfError::SizeOverflow)?;
+    let file_size = read_u64(bytes, header_offset + 32)?;
+    let mem_size = read_u64(bytes, header_offset + 40)?;
+    let align = read_u64(bytes, header_offset + 48)?;
+
+    if file_size > mem_size {
+        return Err(ElfError::BadSegmentSize);
+    }
+    let file_end = offset.checked_add(file_size).ok_or(ElfError::SizeOverflow)?;
+    if file_end > bytes.len() as u64 {
+        return Err(ElfError::SegmentOutOfBounds);
+    }
+    let mem_end = vaddr.checked_add(mem_size).ok_or(ElfError::SizeOverflow)?;
+    // p_align of 0 or 1 means no alignment, otherwise it must be a power of two and offset/address must agree modulo it
+    // we map whole pages, so they always have to agree modulo the page size
+    if align > 1 && (!align.is_power_of_two() || offset % align != vaddr % align) {
+        return Err(ElfError::MisalignedSegment);
+    }
+    if offset % PAGE_SIZE != vaddr % PAGE_SIZE {
+        return Err(ElfError::MisalignedSegment);
+    }
+    if VirtAddr::try_new(vaddr).is_err() || VirtAddr::try_new(mem_end).is_err() {
+        return Err(ElfError::Map(AddressSpaceError::NotUserAddress));
+    }
+
+    let first_page = vaddr - vaddr % PAGE_SIZE;
+    let last_page_end = mem_end.checked_add(PAGE_SIZE - 1).ok_or(ElfError::SizeOverflow)? / PAGE_SIZE * PAGE_SIZE;
+    Ok(Some(Segment {
+        vaddr,
+        mem_end,
+        file_offset: offset as usize,
+        file_size: file_size as usize,
+        first_page,
+        page_count: (last_page_end - first_page) / PAGE_SIZE,
+        flags,
+    }))
+}
+
+/// Validate an ELF64 executable, map its loadable segments into `aspace` and return the entry point.
+///
+/// File contents are copied in and the rest of each segment (the BSS) is left zeroed. Segment pages are writable only
+/// with PF_W and executable only with PF_X. On error nothing has been mapped, unless the error is `ElfError::Map`.
+pub fn load(bytes: &[u8], aspace: &mut AddressSpace) -> Result<VirtAddr, ElfError> {
+    // FILE HEADER
+    if bytes.len() < FILE_HEADER_SIZE {
+        return Err(ElfError::TooShort);
+    }
+    if bytes[0..4] != ELF_MAGIC {
+        return Err(ElfError::BadMagic);
+    }
+    if bytes[4] != ELFCLASS64 {
+        return Err(ElfError::UnsupportedClass);
+    }
+    if bytes[5] != ELFDATA2LSB {
+        return Err(ElfError::UnsupportedEndianness);
+    }
+    let base = match read_u16(bytes, 16)? {
+        ET_EXEC => 0,
+        ET_DYN => DYN_BASE,
+        _ => return Err(ElfError::UnsupportedType),
+    };
+    if read_u16(bytes, 18)? != EM_X86_64 {
+        return Err(ElfError::UnsupportedMachine);
+    }
+    let entry = read_u64(bytes, 24)?.checked_add(base).ok_or(ElfError::SizeOverflow)?;
+    let program_header_offset = read_u64(bytes, 32)?;
+    let program_header_size = read_u16(bytes, 54)? as usize;
+    let program_header_count = read_u16(bytes, 56)? as usize;
+
+    // PROGRAM HEADERS
+    if program_header_size != PROGRAM_HEADER_SIZE {
+        return Err(ElfError::BadProgramHeaders);
+    }
+    let table_size = (program_header_size * program_header_count) as u64; // both are u16, can't overflow
+    let table_end = program_header_offset.checked_add(table_size).ok_or(ElfError::SizeOverflow)?;
+    if table_end > bytes.len() as u64 {
+        return Err(ElfError::BadProgramHeaders);
+    }
+
+    let mut segments: Vec<Segment> = Vec::new();
+    for i in 0..program_header_count {
+        let header_offset = program_header_offset as usize + i * program_header_size;
+        if let Some(segment) = parse_segment(bytes, header_offset, base)? {
+            if segments.iter().any(|other| other.overlaps(&segment)) {
+                return Err(ElfError::OverlappingSegments);
+            }
+            segments.push(segment);
+        }
+    }
+    if !segments.iter().any(|s| s.flags & PF_X != 0 && s.contains(entry)) {
+        return Err(ElfError::BadEntryPoint);
+    }
+
+    // MAPPING
+    for segment in &segments {
+        let first_page = VirtAddr::new(segment.first_page);
+        for i in 0..segment.page_count {
+            if !aspace.is_user_address(first_page + i * PAGE_SIZE) {
+                return Err(ElfError::Map(AddressSpaceError::NotUserAddress));
+            }
+        }
+        aspace.map_user_range(first_page, segment.page_count, segment.page_flags())?;
+        let contents = &bytes[segment.file_offset..segment.file_offset + segment.file_size];
+        // fresh pages are zeroed --> the BSS (memory size past the file size) needs no extra work
+        if !aspace.write(VirtAddr::new(segment.vaddr), contents) {
+            return Err(ElfError::Map(AddressSpaceError::NotUserAddress));
+        }
+    }
+
+    Ok(VirtAddr::new(entry))
+}
+
+// TESTS ===================================
+
+// builds a minimal ELF: the whole file (headers + code) is one read+execute PT_LOAD segment at USER_SPACE_START,
+// with 0x100 bytes of BSS after it, and the entry point right after the headers
+#[cfg(test)]
+fn tiny_elf(code: &[u8]) -> Vec<u8> {
+    let header_size = (FILE_HEADER_SIZE + PROGRAM_HEADER_SIZE) as u64;
+    let file_size = header_size + code.len() as u64;
+    let mut elf = Vec::new();
+    // file header
+    elf.extend_from_slice(&ELF_MAGIC);
+    elf.extend_from_slice(&[ELFCLASS64, ELFDATA2LSB, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    elf.extend_from_slice(&ET_EXEC.to_le_bytes());
+    elf.extend_from_slice(&EM_X86_64.to_le_bytes());
+    elf.extend_from_slice(&1u32.to_le_bytes()); // version
+    elf.extend_from_slice(&(USER_SPACE_START + header_size).to_le_bytes()); // entry
+    elf.extend_from_slice(&(FILE_HEADER_SIZE as u64).to_le_bytes()); // program header offset
+    elf.extend_from_slice(&0u64.to_le_bytes()); // section header offset
+    elf.extend_from_slice(&0u32.to_le_bytes()); // flags
+    elf.extend_from_slice(&(FILE_HEADER_SIZE as u16).to_le_bytes());
+    elf.extend_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+    elf.extend_from_slice(&1u16.to_le_bytes()); // program header count
+    elf.extend_from_slice(&[0; 6]); // no section headers
+    // program header
+    elf.extend_from_slice(&PT_LOAD.to_le_bytes());
+    elf.extend_from_slice(&(PF_X | 4).to_le_bytes()); // read + execute
+    elf.extend_from_slice(&0u64.to_le_bytes()); // offset
+    elf.extend_from_slice(&USER_SPACE_START.to_le_bytes()); // vaddr
+    elf.extend_from_slice(&USER_SPACE_START.to_le_bytes()); // paddr
+    elf.extend_from_slice(&file_size.to_le_bytes());
+    elf.extend_from_slice(&(file_size + 0x100).to_le_bytes()); // memory size
+    elf.extend_from_slice(&PAGE_SIZE.to_le_bytes()); // align
+    elf.extend_from_slice(code);
+    elf
+}
+
+#[cfg(test)]
+const EXIT_7: &[u8] = &[
+    0xbf, 0x07, 0x00, 0x00, 0x00, // mov edi, 7
+    0xb8, 0x3c, 0x00, 0x00, 0x00, // mov eax, 60 (exit)
+    0xcd, 0x80, // int 0x80
+];
+
+#[test_case]
+fn test_load_and_run_tiny_elf() {
+    use crate::process::Process;
+
+    let elf = tiny_elf(EXIT_7);
+    let mut process = Process::new().expect("process creation failed");
+    let entry = load(&elf, process.address_space_mut()).expect("valid elf rejected");
+    assert_eq!(entry.as_u64(), USER_SPACE_START + (FILE_HEADER_SIZE + PROGRAM_HEADER_SIZE) as u64);
+    // code is executable and read only, the BSS is zeroed
+    let flags = process.address_space_mut().translate_flags(entry).expect("entry not mapped");
+    assert!(!flags.contains(PageTableFlags::WRITABLE));
+    assert!(!flags.contains(PageTableFlags::NO_EXECUTE));
+    assert_eq!(process.run(entry), 7);
+}
+
+// built by a real toolchain (fixtures/make_user_program.sh, fixtures/user_program.S explains the exit code): the file
+// header, .text, .rodata and .data + .bss in 4 PT_LOAD segments, the last one at an offset that isn't page aligned and
+// with a BSS running 3 pages past the end of the file
+#[cfg(test)]
+static USER_PROGRAM: &[u8] = include_bytes!("fixtures/user_program.elf");
+
+#[test_case]
+fn test_load_and_run_linked_elf() {
+    use crate::process::Process;
+
+    let mut process = Process::new().expect("process creation failed");
+    let entry = load(USER_PROGRAM, process.address_space_mut()).expect("valid elf rejected");
+    assert_eq!(entry.as_u64(), USER_SPACE_START + 0x1000);
+    // what p_flags turned into (NO_EXECUTE only if the CPU has NX, see memory::no_execute())
+    let flags = |aspace: &mut AddressSpace, offset: u64| {
+        let flags = aspace.translate_flags(VirtAddr::new(USER_SPACE_START + offset)).expect("segment not mapped");
+        flags & (PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE)
+    };
+    let (read_only, writable) = (crate::memory::no_execute(), PageTableFlags::WRITABLE | crate::memory::no_execute());
+    let aspace = process.address_space_mut();
+    assert_eq!(flags(aspace, 0), read_only, "file header");
+    assert_eq!(flags(aspace, 0x1000), PageTableFlags::empty(), ".text");
+    assert_eq!(flags(aspace, 0x2000), read_only, ".rodata");
+    assert_eq!(flags(aspace, 0x3001), writable, ".data");
+    assert_eq!(flags(aspace, 0x6007), writable, "end of .bss");
+    assert!(aspace.translate_flags(VirtAddr::new(USER_SPACE_START + 0x7000)).is_none(), "mapped past the .bss");
+    assert_eq!(process.run(entry), 42);
+}
+
+#[test_case]
+fn test_load_rejects_corrupted_elves() {
+    fn corrupt(offset: usize, value: &[u8]) -> Vec<u8> {
+        let mut elf = tiny_elf(EXIT_7);
+        elf[offset..offset + value.len()].copy_from_slice(value);
+        elf
+    }
+    let phdr = FILE_HEADER_SIZE;
+    let corpus: &[(Vec<u8>, ElfError)] = &[
+        (tiny_elf(EXIT_7)[..40].to_vec(), ElfError::TooShort),
+        (corrupt(0, b"\x7fELG"), ElfError::BadMagic),
+        (corrupt(4, &[1]), ElfError::UnsupportedClass),
+        (corrupt(5, &[2]), ElfError::UnsupportedEndianness),
+        (corrupt(16, &1u16.to_le_bytes()), ElfError::UnsupportedType), // ET_REL
+        (corrupt(18, &3u16.to_le_bytes()), ElfError::UnsupportedMachine), // i386
+        (corrupt(32, &u64::MAX.to_le_bytes()), ElfError::SizeOverflow), // program header offset
+        (corrupt(54, &32u16.to_le_bytes()), ElfError::BadProgramHeaders), // 32 bit program header size
+        (corrupt(56, &100u16.to_le_bytes()), ElfError::BadProgramHeaders), // more headers than the file holds
+        (corrupt(phdr + 8, &u64::MAX.to_le_bytes()), ElfError::SizeOverflow), // segment offset
+        (corrupt(phdr + 8, &0x1000u64.to_le_bytes()), ElfError::SegmentOutOfBounds),
+        (corrupt(phdr + 16, &(USER_SPACE_START + 1).to_le_bytes()), ElfError::MisalignedSegment),
+        (corrupt(phdr + 32, &0x1000u64.to_le_bytes()), ElfError::BadSegmentSize), // file size > memory size
+        (corrupt(phdr + 40, &u64::MAX.to_le_bytes()), ElfError::SizeOverflow), // memory size
+        (corrupt(phdr + 4, &4u32.to_le_bytes()), ElfError::BadEntryPoint), // segment not executable
+        (corrupt(24, &0u64.to_le_bytes()), ElfError::BadEntryPoint),
+        (corrupt(phdr + 16, &0x1000u64.to_le_bytes()), ElfError::BadEntryPoint), // moved away from the entry point
+    ];
+
+    let baseline = crate::memory::allocated_frame_count();
+    for (elf, expected) in corpus {
+        let mut aspace = AddressSpace::new().expect("address space creation failed");
+        assert_eq!(load(elf, &mut aspace), Err(*expected));
+    }
+    assert_eq!(crate::memory::allocated_frame_count(), baseline);
+
+    // a second copy of the segment over the same pages
+    // (the "code" is just room for the second program header)
+    let mut overlapping = tiny_elf(&[0x90; PROGRAM_HEADER_SIZE]);
+    overlapping[56..58].copy_from_slice(&2u16.to_le_bytes());
+    let header: Vec<u8> = overlapping[phdr..phdr + PROGRAM_HEADER_SIZE].to_vec();
+    overlapping[phdr + PROGRAM_HEADER_SIZE..phdr + 2 * PROGRAM_HEADER_SIZE].copy_from_slice(&header[..]);
+    let mut aspace = AddressSpace::new().expect("address space creation failed");
+    assert_eq!(load(&overlapping, &mut aspace), Err(ElfError::OverlappingSegments));
+
+    // kernel addresses are not user space
+    let mut kernel_elf = corrupt(phdr + 16, &(crate::allocator::HEAP_START as u64).to_le_bytes());
+    kernel_elf[24..32].copy_from_slice(&(crate::allocator::HEAP_START as u64 + 120).to_le_bytes());
+    let mut aspace = AddressSpace::new().expect("address space creation failed");
+    assert_eq!(load(&kernel_elf, &mut aspace), Err(ElfError::Map(AddressSpaceError::NotUserAddress)));
+}
@@ -0,0 +1,10 @@
+#!/bin/sh
+# builds user_program.elf (the ELF loader's test program, see elf.rs) from user_program.S with binutils
+# a static executable at USER_SPACE_START (see memory/address_space.rs), no libc
+set -e
+cd "$(dirname "$0")"
+as --64 -o user_program.o user_program.S
+ld -static -nostdlib -z separate-code -z noexecstack --build-id=none -Ttext-segment=0x200000000000 \
+    -e _start -o user_program.elf user_program.o
+strip user_program.elf
+rm user_program.o
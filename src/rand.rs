@@ -0,0 +1,184 @@
+// Random numbers --> from the CPU's hardware generator (RDRAND) when there is one, from a PRNG otherwise
+// - RDRAND can fail (carry flag clear) when the generator is drained for a moment, Intel recommends retrying 10 times
+// - without RDRAND (ex. QEMU's default CPU model) xoshiro256** takes over, seeded once from RDSEED if available, the TSC
+//   and the RTC --> not fit for secrets, but different on every boot
+// - everything lives in statics (no heap) so it works in early boot and in interrupt handlers
+use crate::cpuid::detect_cpu_features;
+use core::arch::x86_64::{_rdrand64_step, _rdseed64_step};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// How many times RDRAND/RDSEED is tried before giving up (Intel's recommendation).
+pub const RETRY_LIMIT: usize = 10;
+
+// CPUID.1:ECX.RDRAND and CPUID.(7,0):EBX.RDSEED
+const CPUID_1_ECX_RDRAND: u32 = 1 << 30;
+const CPUID_7_EBX_RDSEED: u32 = 1 << 18;
+
+/// Whether the CPU has the RDRAND instruction.
+pub fn has_rdrand() -> bool {
+    detect_cpu_features().leaf1.ecx & CPUID_1_ECX_RDRAND != 0
+}
+
+/// Whether the CPU has the RDSEED instruction.
+pub fn has_rdseed() -> bool {
+    detect_cpu_features().leaf7.ebx & CPUID_7_EBX_RDSEED != 0
+}
+
+#[target_feature(enable = "rdrand")]
+unsafe fn rdrand_step() -> Option<u64> {
+    let mut value = 0;
+    (_rdrand64_step(&mut value) == 1).then_some(value)
+}
+
+#[target_feature(enable = "rdseed")]
+unsafe fn rdseed_step() -> Option<u64> {
+    let mut value = 0;
+    (_rdseed64_step(&mut value) == 1).then_some(value)
+}
+
+/// A value from RDRAND, `None` if the CPU doesn't have it or it failed `RETRY_LIMIT` times in a row.
+pub fn rdrand() -> Option<u64> {
+    if !has_rdrand() {
+        return None;
+    }
+    (0..RETRY_LIMIT).find_map(|_| unsafe { rdrand_step() })
+}
+
+/// A value from RDSEED, `None` if the CPU doesn't have it or it failed `RETRY_LIMIT` times in a row.
+pub fn rdseed() -> Option<u64> {
+    if !has_rdseed() {
+        return None;
+    }
+    (0..RETRY_LIMIT).find_map(|_| unsafe { rdseed_step() })
+}
+
+// XOSHIRO256** ====================================
+// https://prng.di.unimi.it/xoshiro256starstar.c
+
+/// A deterministic xoshiro256** generator, the same seed always gives the same sequence (ex. for reproducible tests).
+#[derive(Debug, Clone)]
+pub struct Xoshiro256 {
+    state: [u64; 4],
+}
+
+impl Xoshiro256 {
+    /// A generator starting from `state` as is (it must not be all zeros).
+    pub const fn from_state(state: [u64; 4]) -> Self {
+        Xoshiro256 { state }
+    }
+
+    /// A generator whose state is expanded from `seed` with splitmix64, like the reference implementation suggests.
+    pub fn seed_from_u64(seed: u64) -> Self {
+        let mut x = seed;
+        let mut splitmix64 = || {
+            x = x.wrapping_add(0x9E37_79B9_7F4A_7C15);
+            let mut z = x;
+            z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+            z ^ (z >> 31)
+        };
+        Xoshiro256 { state: [splitmix64(), splitmix64(), splitmix64(), splitmix64()] }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let s = &mut self.state;
+        let result = s[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+        let t = s[1] << 17;
+        s[2] ^= s[0];
+        s[3] ^= s[1];
+        s[1] ^= s[2];
+        s[0] ^= s[3];
+        s[2] ^= t;
+        s[3] = s[3].rotate_left(45);
+        result
+    }
+
+    pub fn fill_bytes(&mut self, buf: &mut [u8]) {
+        fill_with(buf, || self.next_u64());
+    }
+}
+
+fn fill_with(buf: &mut [u8], mut next: impl FnMut() -> u64) {
+    for chunk in buf.chunks_mut(8) {
+        chunk.copy_from_slice(&next().to_le_bytes()[..chunk.len()]);
+    }
+}
+
+// the fallback generator, seeded on first use
+static FALLBACK: Mutex<Option<Xoshiro256>> = Mutex::new(None);
+
+// whatever differs from boot to boot: RDSEED if we have it, the TSC (cycles since reset) and the wall clock time
+fn boot_seed() -> u64 {
+    let time = crate::drivers::cmos::read_rtc_time();
+    let clock = [u64::from(time.year), u64::from(time.month), u64::from(time.day), u64::from(time.hour),
+        u64::from(time.minute), u64::from(time.second)]
+        .iter()
+        .fold(0u64, |acc, &field| acc.wrapping_mul(61).wrapping_add(field));
+    rdseed().unwrap_or(0) ^ crate::task::usage::rdtsc() ^ clock.rotate_left(32)
+}
+
+fn fallback_u64() -> u64 {
+    interrupts::without_interrupts(|| {
+        FALLBACK.lock().get_or_insert_with(|| Xoshiro256::seed_from_u64(boot_seed())).next_u64()
+    })
+}
+
+/// A random `u64`: from RDRAND if the CPU has it (and it doesn't keep failing), from the PRNG otherwise.
+pub fn u64() -> u64 {
+    rdrand().unwrap_or_else(fallback_u64)
+}
+
+/// Fill `buf` with random bytes (see `u64()`).
+pub fn fill_bytes(buf: &mut [u8]) {
+    fill_with(buf, u64);
+}
+
+// TESTS ===================================
+
+#[test_case]
+fn test_fill_bytes_differs() {
+    // no heap needed
+    let _no_alloc = crate::allocator::no_alloc_guard();
+    let (mut a, mut b) = ([0u8; 32], [0u8; 32]);
+    fill_bytes(&mut a);
+    fill_bytes(&mut b);
+    assert_ne!(a, b);
+    assert_ne!(a, [0; 32]);
+
+    // the fallback on its own, even where RDRAND exists
+    assert_ne!(fallback_u64(), fallback_u64());
+}
+
+#[test_case]
+fn test_xoshiro_reproducible() {
+    // the first outputs of the reference implementation for the state [1, 2, 3, 4]
+    let mut rng = Xoshiro256::from_state([1, 2, 3, 4]);
+    let outputs = [rng.next_u64(), rng.next_u64(), rng.next_u64(), rng.next_u64()];
+    assert_eq!(outputs, [11520, 0, 1509978240, 1215971899390074240]);
+
+    let (mut a, mut b) = (Xoshiro256::seed_from_u64(42), Xoshiro256::seed_from_u64(42));
+    let (mut bytes_a, mut bytes_b) = ([0u8; 13], [0u8; 13]); // not a multiple of 8
+    a.fill_bytes(&mut bytes_a);
+    b.fill_bytes(&mut bytes_b);
+    assert_eq!(bytes_a, bytes_b);
+    assert_eq!(a.next_u64(), b.next_u64());
+    assert_ne!(Xoshiro256::seed_from_u64(43).next_u64(), Xoshiro256::seed_from_u64(42).next_u64());
+}
+
+#[test_case]
+fn test_rdrand_returns() {
+    // QEMU's default CPU has neither, `-cpu max` (or KVM with `-cpu host`) has both
+    if has_rdrand() {
+        let values = [rdrand(), rdrand()];
+        assert!(values.iter().all(Option::is_some), "RDRAND kept failing");
+        assert_ne!(values[0], values[1]);
+    } else {
+        assert_eq!(rdrand(), None);
+    }
+    if has_rdseed() {
+        assert!(rdseed().is_some() || rdseed().is_some(), "RDSEED kept failing");
+    } else {
+        assert_eq!(rdseed(), None);
+    }
+}
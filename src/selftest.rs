@@ -0,0 +1,107 @@
+// Boot time self tests --> checks run before the kernel trusts the hardware with anything
+// - test_memory_regions(): every usable region of the memory map gets a pattern written to its first and last 8 bytes
+//   through the physical memory mapping and read back --> catches a memory map that reports memory that isn't there
+//   (or isn't RAM) before the frame allocator hands it out for the heap
+// - only the ends of a region: testing every byte would take seconds on a big machine, a region that is missing or
+//   shadowed is wrong at its ends too
+// - the first MiB is skipped, it's the legacy BIOS area (real mode IVT, EBDA, option ROMs) even where the map says usable
+// - the old contents are written back --> nothing has to be reinitialized after the test
+use crate::serial_println;
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use x86_64::VirtAddr;
+
+/// What `test_memory_regions()` writes and expects to read back.
+pub const MEMORY_TEST_PATTERN: u64 = 0xA5A5_A5A5_A5A5_A5A5;
+
+/// Usable memory below this physical address isn't tested.
+pub const LEGACY_AREA_END: u64 = 0x10_0000;
+
+/// Write `MEMORY_TEST_PATTERN` to the first and last 8 bytes of every usable region above `LEGACY_AREA_END` and read it
+/// back, through the mapping of all physical memory at `phys_mem_offset`.
+///
+/// Every mismatch is reported on the serial port with its physical address, returns how many there were.
+pub fn test_memory_regions(phys_mem_offset: VirtAddr, map: &MemoryMap) -> usize {
+    test_memory_regions_with(phys_mem_offset, map, test_word)
+}
+
+// test_memory_regions() with `test_word` checking each word --> the tests can make one fail
+fn test_memory_regions_with(
+    phys_mem_offset: VirtAddr,
+    map: &MemoryMap,
+    mut test_word: impl FnMut(VirtAddr) -> Result<(), u64>,
+) -> usize {
+    let mut failures = 0;
+    for region in map.iter().filter(|region| region.region_type == MemoryRegionType::Usable) {
+        let start = region.range.start_addr().max(LEGACY_AREA_END);
+        let end = region.range.end_addr();
+        if end < start + 8 {
+            continue; // all of it in the legacy area, or too small
+        }
+        for phys in [start, end - 8] {
+            if let Err(actual) = test_word(phys_mem_offset + phys) {
+                serial_println!(
+                    "memory test: mismatch at physical {:#x}: expected {:#018x}, read {:#018x}",
+                    phys, MEMORY_TEST_PATTERN, actual
+                );
+                failures += 1;
+            }
+        }
+    }
+    failures
+}
+
+// write the pattern to `addr`, read it back and put the old value back, Err with what was read instead of the pattern
+// - interrupts are off in between: a handler using the word would see the pattern instead of its value (or change it
+//   under us)
+fn test_word(addr: VirtAddr) -> Result<(), u64> {
+    let word: *mut u64 = addr.as_mut_ptr();
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        let old = word.read_volatile();
+        word.write_volatile(MEMORY_TEST_PATTERN);
+        let actual = word.read_volatile();
+        word.write_volatile(old);
+        if actual == MEMORY_TEST_PATTERN { Ok(()) } else { Err(actual) }
+    })
+}
+
+// TESTS ===================================
+
+#[test_case]
+fn test_memory_regions_on_a_mock_region() {
+    use bootloader::bootinfo::{FrameRange, MemoryRegion};
+
+    // a buffer posing as physical memory at LEGACY_AREA_END - 0x1000..LEGACY_AREA_END + 0x1000: the offset is chosen so
+    // that "physical" address LEGACY_AREA_END - 0x1000 is the buffer's first byte
+    const SIZE: usize = 0x2000;
+    let mut memory = alloc::vec![0x11u8; SIZE];
+    let base = memory.as_mut_ptr() as u64;
+    let offset = VirtAddr::new(base - (LEGACY_AREA_END - 0x1000));
+    let mut map = MemoryMap::new();
+    map.add_region(MemoryRegion { range: FrameRange::new(LEGACY_AREA_END - 0x1000, LEGACY_AREA_END + 0x1000), region_type: MemoryRegionType::Usable });
+
+    assert_eq!(test_memory_regions(offset, &map), 0);
+    // both ends were tested and put back
+    assert!(memory.iter().all(|&byte| byte == 0x11));
+
+    // memory that doesn't hold the pattern at the region's end --> one failure, the start (above the legacy area) passes
+    let end = offset + (LEGACY_AREA_END + 0x1000 - 8);
+    let mut tested = alloc::vec::Vec::new();
+    let faulty = |addr: VirtAddr| {
+        tested.push(addr);
+        if addr == end { Err(0) } else { test_word(addr) }
+    };
+    assert_eq!(test_memory_regions_with(offset, &map, faulty), 1);
+    assert_eq!(tested, [offset + LEGACY_AREA_END, end]);
+
+    // a region entirely in the legacy area isn't tested --> an offset pointing nowhere doesn't matter
+    let mut legacy = MemoryMap::new();
+    legacy.add_region(MemoryRegion { range: FrameRange::new(0x8000, 0x9000), region_type: MemoryRegionType::Usable });
+    assert_eq!(test_memory_regions(VirtAddr::new(0xdead_0000_0000), &legacy), 0);
+}
+
+#[test_case]
+fn test_word_reads_back_the_pattern() {
+    let mut word: u64 = 42;
+    assert_eq!(test_word(VirtAddr::new(&mut word as *mut u64 as u64)), Ok(()));
+    assert_eq!(word, 42);
+}
@@ -1,5 +1,8 @@
+use crate::ipc::MessageQueue;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use uart_16550::SerialPort;
 use spin::Mutex;
+#[cfg(not(feature = "replace_lazy_static"))]
 use lazy_static::lazy_static;
 
 // similar to the VGA buffer we create a static global serial "writer"
@@ -7,12 +10,41 @@ use lazy_static::lazy_static;
 // We use mutex because we want to avoid data races when the writer is accessed from multiple processes and we still need interior mutability
 // We spinlocks/spin mutexes rather than regular ones because we don't have the concept of threads and blocking (and other OS abstractions)
 // We’re passing the port address 0x3F8, which is the standard port number for the first serial interface.
+fn new_serial_port() -> Mutex<SerialPort> {
+    let mut serial_port = unsafe { SerialPort::new(COM1_PORT) };
+    serial_port.init();
+    UART_READY.store(true, Ordering::Release);
+    Mutex::new(serial_port)
+}
+
+const COM1_PORT: u16 = 0x3F8;
+
+#[cfg(not(feature = "replace_lazy_static"))]
 lazy_static!{
-    pub static ref SERIAL1: Mutex<SerialPort> = {
-        let mut serial_port = unsafe { SerialPort::new(0x3F8) };
-        serial_port.init();
-        Mutex::new(serial_port)
+    pub static ref SERIAL1: Mutex<SerialPort> = new_serial_port();
+}
+
+#[cfg(not(feature = "replace_lazy_static"))]
+fn serial1() -> &'static Mutex<SerialPort> {
+    let serial = &*SERIAL1;
+    mark_serial1_created();
+    serial
+}
+
+// created on first use like the VGA writer (see vga_buffer.rs), tests print to serial before anything is initialized
+#[cfg(feature = "replace_lazy_static")]
+pub static SERIAL1: crate::sync::KernelOnce<Mutex<SerialPort>> = crate::sync::KernelOnce::new();
+
+#[cfg(feature = "replace_lazy_static")]
+fn serial1() -> &'static Mutex<SerialPort> {
+    use x86_64::instructions::interrupts;
+
+    let serial = match SERIAL1.get() {
+        Some(serial) => serial,
+        None => interrupts::without_interrupts(|| SERIAL1.get().unwrap_or_else(|| SERIAL1.init(new_serial_port()))),
     };
+    mark_serial1_created();
+    serial
 }
 
 // IMPLEMENTING MACROS --> very similar to VGA buffer except SerialPort already implements Write trait which we don't need to do here
@@ -25,7 +57,7 @@ pub fn _print(args: ::core::fmt::Arguments) {
     
     // prevent deadlocks via interrupts
     interrupts::without_interrupts(|| {
-        SERIAL1.lock().write_fmt(args).expect("Printing to serial failed"); 
+        serial1().lock().write_fmt(args).expect("Printing to serial failed"); 
     });
 }
 
@@ -44,4 +76,319 @@ macro_rules! serial_println {
     ($fmt:expr) => ($crate::serial_print!(concat!($fmt, "\n")));
     ($fmt:expr, $($arg:tt)*) => ($crate::serial_print!(
         concat!($fmt, "\n"), $($arg)*));
-}
\ No newline at end of file
+}
+
+/// The serial port as a console output (for a shell on the serial port, see kshell.rs).
+///
+/// `clear()` and `set_colors()` send the ANSI escape sequences, the host's terminal takes care of them.
+pub struct SerialOutput;
+
+impl crate::console::ConsoleOutput for SerialOutput {
+    fn write_str(&self, s: &str) {
+        _print(format_args!("{}", s));
+    }
+
+    fn clear(&self) {
+        _print(format_args!("\x1b[2J\x1b[H"));
+    }
+
+    fn set_colors(&self, foreground: crate::vga_buffer::Color, background: crate::vga_buffer::Color) {
+        // the VGA colors are blue, green, red in the low 3 bits and bright in the 4th, ANSI's are red, green, blue
+        const ANSI: [u8; 8] = [0, 4, 2, 6, 1, 5, 3, 7];
+        let code = |color: crate::vga_buffer::Color, base: u8| {
+            let color = color as u8;
+            ANSI[usize::from(color & 7)] + if color & 8 != 0 { base + 60 } else { base }
+        };
+        _print(format_args!("\x1b[{};{}m", code(foreground, 30), code(background, 40)));
+    }
+}
+
+// PANIC OUTPUT =======================================
+// a panic can come before SERIAL1 was created (its lazy init would run in the panic handler, or spin forever if the panic
+// came from inside it) or while the code that panicked holds its lock (it never lets go) --> panic_print() doesn't wait:
+// - SERIAL1 created and its lock free: through it, like serial_print!
+// - otherwise straight to the UART's ports through a SerialPort of its own (set up first if SERIAL1 never did) and to
+//   the debug console (port 0xE9, see drivers/debugcon.rs) --> the text may land in the middle of the line the lock
+//   holder was writing, but it gets out
+// force_unlock() then frees the lock for the rest of the panic handler (the test summary, the next test after a recovery)
+
+// set once the UART is initialized (by SERIAL1 or panic_print()), SERIAL_READY only once SERIAL1 exists --> a panic
+// in the middle of creating it doesn't wait for it
+static UART_READY: AtomicBool = AtomicBool::new(false);
+static SERIAL_READY: AtomicBool = AtomicBool::new(false);
+
+// called by serial1() once SERIAL1 is there
+fn mark_serial1_created() {
+    if !SERIAL_READY.load(Ordering::Relaxed) {
+        SERIAL_READY.store(true, Ordering::Release);
+    }
+}
+
+// a SerialPort of its own for COM1, initialized if nothing did that yet
+fn raw_serial_port() -> SerialPort {
+    let mut raw = unsafe { SerialPort::new(COM1_PORT) };
+    if !UART_READY.swap(true, Ordering::AcqRel) {
+        raw.init();
+    }
+    raw
+}
+
+/// Write `args` to the serial port without waiting for a lock or initializing SERIAL1, for panic handlers.
+pub fn panic_print(args: ::core::fmt::Arguments) {
+    use core::fmt::Write;
+
+    if SERIAL_READY.load(Ordering::Acquire) {
+        if let Some(mut serial) = serial1().try_lock() {
+            let _ = serial.write_fmt(args);
+            return;
+        }
+    }
+    let _ = raw_serial_port().write_fmt(args);
+    let _ = crate::drivers::debugcon::DebugConWriter.write_fmt(args);
+}
+
+/// Release SERIAL1's lock if something holds it, for panic handlers once they printed the panic.
+///
+/// Unsafe because whoever holds the lock must never run again (the code that panicked, abandoned by the handler).
+pub unsafe fn force_unlock() {
+    if SERIAL_READY.load(Ordering::Acquire) {
+        serial1().force_unlock();
+    }
+}
+
+/// Whether SERIAL1's lock is free (or there is no SERIAL1 yet), see `force_unlock()`.
+pub(crate) fn lock_is_free() -> bool {
+    !SERIAL_READY.load(Ordering::Acquire) || serial1().try_lock().is_some()
+}
+
+// LOOPBACK =======================================
+// with bit 4 of the modem control register set the UART hands what it sends to its own receiver instead of the line
+// --> a test can read back what really came out of the UART (ex. panic_print() past a held lock)
+// - the receive FIFO holds LOOPBACK_LEN bytes, what is sent after that is lost
+// - interrupts have to be off, serial_interrupt() would take the bytes first
+
+/// Bytes `loopback()` can read back.
+pub const LOOPBACK_LEN: usize = 16;
+
+const MODEM_CONTROL_PORT: u16 = COM1_PORT + 4;
+const LINE_STATUS_PORT: u16 = COM1_PORT + 5;
+const MODEM_CONTROL_LOOPBACK: u8 = 1 << 4;
+const LINE_STATUS_DATA_READY: u8 = 1 << 0;
+const LINE_STATUS_TRANSMITTER_EMPTY: u8 = 1 << 6;
+
+/// Run `f` with COM1 in loopback mode and return what it sent through the UART (the first `LOOPBACK_LEN` bytes and
+/// their number), none of it reaches the host. Interrupts have to be off.
+pub fn loopback(f: impl FnOnce()) -> ([u8; LOOPBACK_LEN], usize) {
+    use x86_64::instructions::port::Port;
+
+    // initializing the UART clears the loopback bit --> before it is set
+    drop(raw_serial_port());
+    let mut data = Port::<u8>::new(COM1_PORT);
+    let mut modem_control = Port::<u8>::new(MODEM_CONTROL_PORT);
+    let mut line_status = Port::<u8>::new(LINE_STATUS_PORT);
+    let mut bytes = [0; LOOPBACK_LEN];
+    let mut len = 0;
+    unsafe {
+        // what was sent before still goes to the host, what was received before isn't taken for what f() sent
+        while line_status.read() & LINE_STATUS_TRANSMITTER_EMPTY == 0 {}
+        while line_status.read() & LINE_STATUS_DATA_READY != 0 {
+            data.read();
+        }
+        let modem_bits = modem_control.read();
+        modem_control.write(modem_bits | MODEM_CONTROL_LOOPBACK);
+        f();
+        while line_status.read() & LINE_STATUS_TRANSMITTER_EMPTY == 0 {}
+        while line_status.read() & LINE_STATUS_DATA_READY != 0 {
+            let byte = data.read();
+            if len < LOOPBACK_LEN {
+                bytes[len] = byte;
+                len += 1;
+            }
+        }
+        modem_control.write(modem_bits);
+    }
+    (bytes, len)
+}
+
+// SERIAL CONSOLE =======================================
+// a line of input from the host (ex. QEMU's `-serial stdio`) is run as a command when enter is pressed
+// - COM1 raises IRQ 4 for every received byte (SerialPort::init() enables the "data available" interrupt)
+// - everything runs in the interrupt handler --> fixed size line buffer, no allocations
+// - while a task reads the port (see SERIAL INPUT) the bytes go to it instead
+
+const COM1_IRQ: u8 = 4;
+const MAX_LINE: usize = 64;
+
+struct LineBuffer {
+    bytes: [u8; MAX_LINE],
+    len: usize,
+}
+
+static LINE: Mutex<LineBuffer> = Mutex::new(LineBuffer { bytes: [0; MAX_LINE], len: 0 });
+
+/// Commands of the serial console: name, description, what to run.
+pub const COMMANDS: &[(&str, &str, fn())] = &[
+    ("help", "list the commands", help_command),
+    ("shutdown", "power the machine off", crate::power::shutdown_command),
+    ("reboot", "restart the machine", crate::power::reboot_command),
+];
+
+fn help_command() {
+    for (name, description, _) in COMMANDS {
+        serial_println!("  {:10} {}", name, description);
+    }
+}
+
+/// Run the console command `line` (surrounding whitespace is ignored).
+pub fn run_command(line: &str) {
+    let line = line.trim();
+    if line.is_empty() {
+        return;
+    }
+    match COMMANDS.iter().find(|(name, _, _)| *name == line) {
+        Some((_, _, command)) => command(),
+        None => serial_println!("unknown command: {} (try help)", line),
+    }
+}
+
+// called for every COM1 interrupt, interrupts are off
+fn serial_interrupt() {
+    let byte = serial1().lock().receive();
+    if push_byte(byte) {
+        return;
+    }
+    let mut line = LINE.lock();
+    match byte {
+        b'\r' | b'\n' => {
+            let mut command = [0; MAX_LINE];
+            let len = line.len;
+            command[..len].copy_from_slice(&line.bytes[..len]);
+            line.len = 0;
+            drop(line); // a command may never return (shutdown)
+            serial_println!();
+            run_command(core::str::from_utf8(&command[..len]).unwrap_or(""));
+        }
+        0x08 | 0x7f if line.len > 0 => {
+            line.len -= 1;
+            serial_print!("\x08 \x08"); // erase the character on the terminal
+        }
+        0x20..=0x7e if line.len < MAX_LINE => {
+            let len = line.len;
+            line.bytes[len] = byte;
+            line.len += 1;
+            serial_print!("{}", byte as char); // echo
+        }
+        _ => {}
+    }
+}
+
+/// Start running commands typed on COM1, see `COMMANDS`.
+pub fn init_console() {
+    crate::interrupts::register_irq_handler(COM1_IRQ, serial_interrupt);
+}
+
+// SERIAL INPUT =======================================
+// like the keyboard (see task/keyboard.rs): one task at a time opens the stream with rx_stream() and gets the received
+// bytes in order through a message queue --> the console above only runs while no stream is open
+// - the bytes are passed on as they are, making keys of them (line endings, escape sequences) is up to the reader
+// - the interrupt handler can't wait --> bytes received while the queue is full are dropped (and counted)
+
+/// Bytes that can be received before the reading task gets to them.
+pub const RX_QUEUE_SIZE: usize = 64;
+
+static RX: MessageQueue<u8, RX_QUEUE_SIZE> = MessageQueue::new();
+static RX_OPEN: AtomicBool = AtomicBool::new(false);
+static DROPPED_BYTES: AtomicU64 = AtomicU64::new(0);
+
+// called by the interrupt handler for every received byte, returns false if no task reads the port
+fn push_byte(byte: u8) -> bool {
+    if !RX_OPEN.load(Ordering::Acquire) {
+        return false;
+    }
+    if RX.try_send(byte).is_err() {
+        DROPPED_BYTES.fetch_add(1, Ordering::Relaxed);
+    }
+    true
+}
+
+/// Number of received bytes dropped b/c the queue was full.
+pub fn dropped_bytes() -> u64 {
+    DROPPED_BYTES.load(Ordering::Relaxed)
+}
+
+/// The bytes received on COM1, see `rx_stream()`. The console runs again once it is dropped.
+#[derive(Debug)]
+pub struct RxStream {
+    _private: (),
+}
+
+/// Start reading COM1, `None` if another task already does. Needs `init_console()` for the interrupt.
+pub fn rx_stream() -> Option<RxStream> {
+    RX_OPEN
+        .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+        .ok()
+        .map(|_| RxStream { _private: () })
+}
+
+impl RxStream {
+    /// The next byte, waits until there is one.
+    pub async fn next_byte(&mut self) -> u8 {
+        RX.recv().await
+    }
+
+    /// The next byte if there is one already.
+    pub fn try_next_byte(&mut self) -> Option<u8> {
+        RX.try_recv()
+    }
+}
+
+impl Drop for RxStream {
+    fn drop(&mut self) {
+        RX_OPEN.store(false, Ordering::Release);
+        while RX.try_recv().is_some() {}
+    }
+}
+
+// TESTS ===================================
+
+#[test_case]
+fn test_unknown_command_is_ignored() {
+    run_command("   ");
+    run_command("definitely-not-a-command");
+    run_command("help");
+    assert!(COMMANDS.iter().any(|(name, _, _)| *name == "shutdown"));
+}
+
+#[test_case]
+fn test_rx_stream() {
+    assert!(!push_byte(b'x'), "byte taken without a reader");
+    let mut stream = rx_stream().expect("stream already open");
+    assert!(rx_stream().is_none(), "two readers");
+    for &byte in b"ok\r" {
+        assert!(push_byte(byte));
+    }
+    assert_eq!([stream.try_next_byte(), stream.try_next_byte(), stream.try_next_byte()], [Some(b'o'), Some(b'k'), Some(b'\r')]);
+    assert_eq!(stream.try_next_byte(), None);
+    push_byte(b'!');
+    drop(stream);
+    // what was left isn't meant for the next reader
+    let mut stream = rx_stream().expect("stream still open");
+    assert_eq!(stream.try_next_byte(), None);
+}
+
+// the panic handler can't wait for the lock this test holds --> it prints "[ok]" past it and frees it (checked by
+// ShouldPanic, see SHOULD PANIC in lib.rs), a panic handler that waited would hang until the test timeout
+#[test_case]
+static PANIC_HOLDING_SERIAL1: crate::ShouldPanic<fn()> = crate::ShouldPanic("mini_os::serial::panic_holding_serial1", || {
+    // no interrupts while it's held (and for the loopback), the recovery after the panic turns them back on
+    x86_64::instructions::interrupts::disable();
+    let _held = serial1().lock();
+    // the way the panic handler's output takes: past the lock straight to the UART --> it has to come out of it
+    let (sent, len) = loopback(|| panic_print(format_args!("past the lock")));
+    if &sent[..len] != b"past the lock" {
+        // an assert would panic, which passes this test --> it fails by not panicking
+        panic_print(format_args!("the UART sent {:?} ", &sent[..len]));
+        return;
+    }
+    panic!("panic while SERIAL1 is locked");
+});
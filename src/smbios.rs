@@ -0,0 +1,443 @@
+// SMBIOS --> the firmware's description of the machine: manufacturer and model, BIOS version, memory modules...
+// see: https://wiki.osdev.org/System_Management_BIOS and the DMTF SMBIOS specification (DSP0134)
+//
+// - the entry point is found by searching for "_SM3_" (64 bit, SMBIOS 3.0+) or "_SM_" (32 bit) on 16 byte boundaries in
+//   0xF0000-0xFFFFF, its bytes sum to 0 mod 256 (the 32 bit one has a second, "_DMI_", checksum over its tail)
+// - it points to the structure table: structures back to back, each one a formatted area (type, length, handle and the
+//   type's fields) followed by a set of NUL terminated strings that ends with an extra NUL ("\0\0" if there are none)
+// - string fields hold a 1 based index into the structure's strings, 0 means "no string"
+// - the table ends with a type 127 structure (or after the entry point's structure count / table length)
+// - like in acpi.rs everything is parsed from byte slices: a truncated or malformed table ends the iteration early, an
+//   unknown type is skipped, a field past a structure's length is `None`, nothing panics
+use crate::memory::phys_slice;
+use core::fmt;
+use spin::Once;
+use x86_64::PhysAddr;
+
+const SEARCH_START: u64 = 0xF0000;
+const SEARCH_END: u64 = 0x100000;
+const ANCHOR_32: &[u8; 4] = b"_SM_";
+const ANCHOR_64: &[u8; 5] = b"_SM3_";
+const INTERMEDIATE_ANCHOR: &[u8; 5] = b"_DMI_";
+const ENTRY_POINT_32_LEN: usize = 0x1F;
+const ENTRY_POINT_64_LEN: usize = 0x18;
+// nothing real comes close, a larger table is broken
+const MAX_TABLE_LEN: usize = 1 << 20;
+
+const HEADER_LEN: usize = 4;
+
+pub const TYPE_BIOS: u8 = 0;
+pub const TYPE_SYSTEM: u8 = 1;
+pub const TYPE_MEMORY_DEVICE: u8 = 17;
+pub const TYPE_END_OF_TABLE: u8 = 127;
+
+/// Errors of finding the SMBIOS tables.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmbiosError {
+    /// No valid entry point in the BIOS area.
+    NoEntryPoint,
+    /// The bytes of the entry point don't sum to 0.
+    BadChecksum,
+    /// The entry point is too short or the table length implausible.
+    BadLength,
+    /// The table address of the entry point can't be a physical address (over 52 bits).
+    BadAddress(u64),
+}
+
+// like in acpi.rs: the firmware may put any value where an address should be --> never PhysAddr::new(), which panics
+fn phys_addr(address: u64) -> Result<PhysAddr, SmbiosError> {
+    PhysAddr::try_new(address).map_err(|_| SmbiosError::BadAddress(address))
+}
+
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+// ENTRY POINT ====================================
+
+/// Where the structure table is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EntryPoint {
+    pub major_version: u8,
+    pub minor_version: u8,
+    pub table_address: u64,
+    /// The exact length for the 32 bit entry point, the maximum length for the 64 bit one.
+    pub table_len: usize,
+    /// Only the 32 bit entry point counts the structures.
+    pub structure_count: Option<u16>,
+}
+
+impl EntryPoint {
+    /// Check and decode a 32 ("_SM_") or 64 bit ("_SM3_") entry point.
+    pub fn parse(bytes: &[u8]) -> Result<Self, SmbiosError> {
+        if bytes.starts_with(ANCHOR_64) {
+            if bytes.len() < ENTRY_POINT_64_LEN || (bytes[6] as usize) < ENTRY_POINT_64_LEN || bytes.len() < bytes[6] as usize {
+                return Err(SmbiosError::BadLength);
+            }
+            if !checksum_ok(&bytes[..bytes[6] as usize]) {
+                return Err(SmbiosError::BadChecksum);
+            }
+            Ok(EntryPoint {
+                major_version: bytes[7],
+                minor_version: bytes[8],
+                table_address: u64_at(bytes, 0x10),
+                table_len: (u32_at(bytes, 0x0C) as usize).min(MAX_TABLE_LEN),
+                structure_count: None,
+            })
+        } else if bytes.starts_with(ANCHOR_32) {
+            if bytes.len() < ENTRY_POINT_32_LEN || (bytes[5] as usize) < ENTRY_POINT_32_LEN || bytes.len() < bytes[5] as usize {
+                return Err(SmbiosError::BadLength);
+            }
+            if !checksum_ok(&bytes[..bytes[5] as usize]) || &bytes[0x10..0x15] != INTERMEDIATE_ANCHOR
+                || !checksum_ok(&bytes[0x10..ENTRY_POINT_32_LEN])
+            {
+                return Err(SmbiosError::BadChecksum);
+            }
+            Ok(EntryPoint {
+                major_version: bytes[6],
+                minor_version: bytes[7],
+                table_address: u64::from(u32_at(bytes, 0x18)),
+                table_len: usize::from(u16_at(bytes, 0x16)),
+                structure_count: Some(u16_at(bytes, 0x1C)),
+            })
+        } else {
+            Err(SmbiosError::NoEntryPoint)
+        }
+    }
+}
+
+/// Find the entry point in the BIOS area, the 64 bit one if there are both.
+pub fn find_entry_point() -> Result<EntryPoint, SmbiosError> {
+    let len = (SEARCH_END - SEARCH_START) as usize;
+    let area = unsafe { phys_slice(phys_addr(SEARCH_START)?, len) };
+    let candidates = || (0..len - ENTRY_POINT_64_LEN).step_by(16).map(|offset| &area[offset..]);
+    candidates()
+        .filter(|bytes| bytes.starts_with(ANCHOR_64))
+        .chain(candidates().filter(|bytes| bytes.starts_with(ANCHOR_32)))
+        .find_map(|bytes| EntryPoint::parse(bytes).ok())
+        .ok_or(SmbiosError::NoEntryPoint)
+}
+
+// STRUCTURES ====================================
+
+/// One structure of the table.
+#[derive(Clone, Copy)]
+pub struct Structure<'a> {
+    pub kind: u8,
+    pub handle: u16,
+    /// The formatted area, header included (so field offsets are the ones from the specification).
+    formatted: &'a [u8],
+    /// The string set without its final NUL.
+    strings: &'a [u8],
+}
+
+impl<'a> Structure<'a> {
+    /// The byte at `offset`, `None` past the formatted area (an older version of the structure).
+    pub fn byte(&self, offset: usize) -> Option<u8> {
+        self.formatted.get(offset).copied()
+    }
+
+    pub fn word(&self, offset: usize) -> Option<u16> {
+        Some(u16::from_le_bytes([self.byte(offset)?, self.byte(offset + 1)?]))
+    }
+
+    pub fn dword(&self, offset: usize) -> Option<u32> {
+        Some(u32::from_le_bytes(self.formatted.get(offset..offset + 4)?.try_into().unwrap()))
+    }
+
+    /// String number `index` (1 based), `None` for 0, a missing string or one that isn't UTF-8.
+    pub fn string(&self, index: u8) -> Option<&'a str> {
+        let index = usize::from(index).checked_sub(1)?;
+        let string = self.strings.split(|&byte| byte == 0).nth(index)?;
+        core::str::from_utf8(string).ok()
+    }
+
+    /// The string the byte at `offset` refers to.
+    pub fn string_at(&self, offset: usize) -> Option<&'a str> {
+        self.string(self.byte(offset)?)
+    }
+}
+
+impl fmt::Debug for Structure<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Structure")
+            .field("kind", &self.kind)
+            .field("handle", &self.handle)
+            .field("len", &self.formatted.len())
+            .finish()
+    }
+}
+
+/// Iterator over the structures of a table, see `SmbiosTables::structures()`.
+pub struct Structures<'a> {
+    table: &'a [u8],
+    offset: usize,
+    remaining: Option<u16>,
+}
+
+impl<'a> Structures<'a> {
+    /// Iterate over the structures in `table`, at most `count` of them if given.
+    pub fn new(table: &'a [u8], count: Option<u16>) -> Self {
+        Structures { table, offset: 0, remaining: count }
+    }
+}
+
+impl<'a> Iterator for Structures<'a> {
+    type Item = Structure<'a>;
+
+    fn next(&mut self) -> Option<Structure<'a>> {
+        if self.remaining == Some(0) {
+            return None;
+        }
+        let rest = self.table.get(self.offset..)?;
+        if rest.len() < HEADER_LEN {
+            return None;
+        }
+        let len = usize::from(rest[1]);
+        if len < HEADER_LEN || len > rest.len() {
+            self.table = &[]; // truncated --> nothing after it can be trusted
+            return None;
+        }
+        // the string set ends at the first double NUL (its own NUL and the set's)
+        let end = match rest[len..].windows(2).position(|pair| pair == [0, 0]) {
+            Some(position) => len + position,
+            None => {
+                self.table = &[];
+                return None;
+            }
+        };
+        let structure = Structure { kind: rest[0], handle: u16_at(rest, 2), formatted: &rest[..len], strings: &rest[len..end] };
+        self.offset += end + 2;
+        self.remaining = self.remaining.map(|count| count - 1);
+        if structure.kind == TYPE_END_OF_TABLE {
+            self.table = &[];
+        }
+        Some(structure)
+    }
+}
+
+// TYPED STRUCTURES ====================================
+
+/// Type 0: BIOS information.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BiosInfo<'a> {
+    pub vendor: Option<&'a str>,
+    pub version: Option<&'a str>,
+    pub release_date: Option<&'a str>,
+}
+
+impl<'a> BiosInfo<'a> {
+    pub fn parse(structure: &Structure<'a>) -> Option<Self> {
+        (structure.kind == TYPE_BIOS).then(|| BiosInfo {
+            vendor: structure.string_at(0x04),
+            version: structure.string_at(0x05),
+            release_date: structure.string_at(0x08),
+        })
+    }
+}
+
+/// Type 1: system information.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SystemInfo<'a> {
+    pub manufacturer: Option<&'a str>,
+    pub product_name: Option<&'a str>,
+    pub version: Option<&'a str>,
+    pub serial_number: Option<&'a str>,
+}
+
+impl<'a> SystemInfo<'a> {
+    pub fn parse(structure: &Structure<'a>) -> Option<Self> {
+        (structure.kind == TYPE_SYSTEM).then(|| SystemInfo {
+            manufacturer: structure.string_at(0x04),
+            product_name: structure.string_at(0x05),
+            version: structure.string_at(0x06),
+            serial_number: structure.string_at(0x07),
+        })
+    }
+}
+
+/// Type 17: a memory device (a slot, with or without a module in it).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryDevice<'a> {
+    /// Size of the installed module in KiB, `Some(0)` for an empty slot, `None` if unknown.
+    pub size_kb: Option<u64>,
+    pub device_locator: Option<&'a str>,
+    pub bank_locator: Option<&'a str>,
+    /// MT/s, `None` if unknown.
+    pub speed: Option<u16>,
+    pub manufacturer: Option<&'a str>,
+    pub part_number: Option<&'a str>,
+}
+
+impl<'a> MemoryDevice<'a> {
+    pub fn parse(structure: &Structure<'a>) -> Option<Self> {
+        if structure.kind != TYPE_MEMORY_DEVICE {
+            return None;
+        }
+        // bit 15 set: the size is in KiB, otherwise MiB, 0x7FFF: the real size (MiB) is in the extended size field
+        let size_kb = match structure.word(0x0C) {
+            Some(0xFFFF) | None => None,
+            Some(0x7FFF) => structure.dword(0x1C).map(|mb| u64::from(mb & 0x7FFF_FFFF) * 1024),
+            Some(size) if size & 0x8000 != 0 => Some(u64::from(size & 0x7FFF)),
+            Some(size) => Some(u64::from(size) * 1024),
+        };
+        Some(MemoryDevice {
+            size_kb,
+            device_locator: structure.string_at(0x10),
+            bank_locator: structure.string_at(0x11),
+            speed: structure.word(0x15).filter(|&speed| speed != 0 && speed != 0xFFFF),
+            manufacturer: structure.string_at(0x17),
+            part_number: structure.string_at(0x1A),
+        })
+    }
+}
+
+// TABLES ====================================
+
+/// The structure table found through the entry point.
+#[derive(Debug)]
+pub struct SmbiosTables {
+    pub entry_point: EntryPoint,
+    table: &'static [u8],
+}
+
+impl SmbiosTables {
+    /// Find the entry point and its table (needs the physical memory mapping).
+    pub fn discover() -> Result<Self, SmbiosError> {
+        let entry_point = find_entry_point()?;
+        if entry_point.table_len == 0 {
+            return Err(SmbiosError::BadLength);
+        }
+        let table = unsafe { phys_slice(phys_addr(entry_point.table_address)?, entry_point.table_len) };
+        Ok(SmbiosTables { entry_point, table })
+    }
+
+    pub fn structures(&self) -> Structures<'static> {
+        Structures::new(self.table, self.entry_point.structure_count)
+    }
+
+    pub fn bios(&self) -> Option<BiosInfo<'static>> {
+        self.structures().find_map(|structure| BiosInfo::parse(&structure))
+    }
+
+    pub fn system(&self) -> Option<SystemInfo<'static>> {
+        self.structures().find_map(|structure| SystemInfo::parse(&structure))
+    }
+
+    pub fn memory_devices(&self) -> impl Iterator<Item = MemoryDevice<'static>> {
+        self.structures().filter_map(|structure| MemoryDevice::parse(&structure))
+    }
+}
+
+// GLOBAL ====================================
+
+static TABLES: Once<Option<SmbiosTables>> = Once::new();
+
+/// Find the tables and print the machine and BIOS (only the first call does anything), `None` without SMBIOS.
+///
+/// Needs the physical memory mapping (`memory::init_frame_allocator()`).
+pub fn init() -> Option<&'static SmbiosTables> {
+    TABLES
+        .call_once(|| match SmbiosTables::discover() {
+            Ok(tables) => {
+                let system = tables.system();
+                let bios = tables.bios();
+                crate::println!(
+                    "SMBIOS {}.{}: {} {}, BIOS {} {}",
+                    tables.entry_point.major_version,
+                    tables.entry_point.minor_version,
+                    system.and_then(|system| system.manufacturer).unwrap_or("?"),
+                    system.and_then(|system| system.product_name).unwrap_or("?"),
+                    bios.and_then(|bios| bios.vendor).unwrap_or("?"),
+                    bios.and_then(|bios| bios.version).unwrap_or("?"),
+                );
+                Some(tables)
+            }
+            Err(error) => {
+                crate::println!("SMBIOS: {:?}", error);
+                None
+            }
+        })
+        .as_ref()
+}
+
+/// The tables found by `init()`.
+pub fn tables() -> Option<&'static SmbiosTables> {
+    TABLES.r#try().and_then(Option::as_ref)
+}
+
+// TESTS ===================================
+
+#[test_case]
+fn test_qemu_smbios() {
+    let tables = init().expect("no SMBIOS tables");
+    assert_eq!(tables.system().and_then(|system| system.manufacturer), Some("QEMU"));
+    assert!(tables.bios().and_then(|bios| bios.version).is_some());
+    // QEMU describes its RAM as at least one memory device
+    assert!(tables.memory_devices().any(|device| device.size_kb.unwrap_or(0) > 0));
+    // the iteration ends with the end of table structure
+    let last = tables.structures().last().expect("no structures");
+    assert_eq!(last.kind, TYPE_END_OF_TABLE);
+}
+
+#[test_case]
+fn test_malformed_structures() {
+    // type 1 (length 8) with two strings, an unknown type 200 without strings, end of table
+    let table: &[u8] = &[
+        1, 8, 0x10, 0x00, 1, 2, 0, 9, b'A', b'c', b'm', b'e', 0, b'B', b'o', b'x', 0, 0,
+        200, 4, 0x11, 0x00, 0, 0,
+        127, 4, 0x12, 0x00, 0, 0,
+        0xAA, 0xBB, // garbage after the end
+    ];
+    let structures: alloc::vec::Vec<Structure> = Structures::new(table, None).collect();
+    assert_eq!(structures.iter().map(|structure| structure.kind).collect::<alloc::vec::Vec<_>>(), [1, 200, 127]);
+    let system = SystemInfo::parse(&structures[0]).unwrap();
+    assert_eq!((system.manufacturer, system.product_name), (Some("Acme"), Some("Box")));
+    assert_eq!(system.version, None, "string index 0");
+    assert_eq!(system.serial_number, None, "string index past the set");
+    assert_eq!(structures[0].byte(8), None, "field past the formatted area");
+    assert!(BiosInfo::parse(&structures[1]).is_none());
+
+    // cut off in the formatted area, in the strings and without the final NUL: no panic, nothing half parsed
+    for len in [3, 6, 12, 17] {
+        assert_eq!(Structures::new(&table[..len], None).count(), 0);
+    }
+    // a length below the header size stops the walk
+    assert_eq!(Structures::new(&[1, 2, 0, 0, 0, 0], None).count(), 0);
+    // the structure count from the entry point ends it too
+    assert_eq!(Structures::new(table, Some(2)).count(), 2);
+}
+
+#[test_case]
+fn test_entry_point_checksums() {
+    let mut bytes = [0u8; ENTRY_POINT_64_LEN];
+    bytes[..5].copy_from_slice(ANCHOR_64);
+    bytes[6] = ENTRY_POINT_64_LEN as u8;
+    bytes[7] = 3;
+    bytes[0x0C..0x10].copy_from_slice(&0x200u32.to_le_bytes());
+    bytes[0x10..0x18].copy_from_slice(&0x000F_1000u64.to_le_bytes());
+    bytes[5] = 0u8.wrapping_sub(bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)));
+    let entry = EntryPoint::parse(&bytes).unwrap();
+    assert_eq!((entry.major_version, entry.table_address, entry.table_len), (3, 0xF1000, 0x200));
+
+    bytes[0x10] ^= 1;
+    assert_eq!(EntryPoint::parse(&bytes), Err(SmbiosError::BadChecksum));
+    assert_eq!(EntryPoint::parse(&bytes[..10]), Err(SmbiosError::BadLength));
+    assert_eq!(EntryPoint::parse(b"_XX_ not an entry point"), Err(SmbiosError::NoEntryPoint));
+
+    // a 64 bit entry point can hold any address, discover() refuses the ones past 52 bits
+    assert_eq!(phys_addr(entry.table_address), Ok(PhysAddr::new(0xF1000)));
+    assert_eq!(phys_addr(u64::MAX), Err(SmbiosError::BadAddress(u64::MAX)));
+}
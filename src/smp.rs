@@ -0,0 +1,381 @@
+// SMP --> starting the other CPUs ("application processors", APs) next to the one the firmware booted (the BSP)
+// see: https://wiki.osdev.org/SMP and the Intel SDM vol. 3, 8.4 "Multiple-Processor (MP) Initialization"
+//
+// - the MADT (acpi.rs) lists a local APIC per CPU, an AP is woken by sending it IPIs through our own local APIC:
+//   INIT (reset it), wait 10 ms, then SIPI ("startup IPI") twice with 200 µs in between
+// - a SIPI starts the AP in real mode at physical address `vector * 4096` --> the trampoline below is copied to a fixed
+//   page below 1 MiB (TRAMPOLINE_ADDR, kept out of the frame allocator, see memory.rs), it goes straight from real mode
+//   to long mode with the kernel's own page tables (plus an identity mapping of that page) and calls ap_main()
+// - the APs are started one at a time: the trampoline's data (stack, argument) is only handed to the next one after the
+//   previous one reported in through the ONLINE counter
+// - each AP gets its own stack, GDT/TSS (gdt.rs) and GS block (percpu.rs), loads the shared IDT and then halts with
+//   interrupts off --> they don't run anything yet, the PICs only deliver interrupts to the BSP anyway
+use crate::acpi::{self, MadtEntry, LAPIC_ENABLED};
+use crate::cpu::msr;
+use crate::gdt::CpuTables;
+use crate::memory::{self, GlobalFrameAllocator};
+use crate::mmio::{mmio_read_u32, mmio_write_u32};
+use crate::percpu::{self, MAX_CPUS};
+use crate::println;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Once;
+use x86_64::instructions::port::Port;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{mapper::MapToError, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Physical address the application processors start at (a page below 1 MiB, SIPI vector 0x08).
+pub const TRAMPOLINE_ADDR: u64 = 0x8000;
+
+// how long each AP gets to report in before we give up on it
+const AP_TIMEOUT_US: u32 = 100_000;
+// pages of the stack ap_main() runs on
+const AP_STACK_PAGES: usize = 4;
+
+/// Why `init()` couldn't start the application processors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmpError {
+    /// The MADT (the list of CPUs) couldn't be read.
+    Acpi(acpi::AcpiError),
+    /// Something else lives in the trampoline's page (ex. the bootloader put its page tables there).
+    TrampolineInUse,
+    /// Mapping the local APIC or the trampoline, or allocating an AP's stacks, failed.
+    OutOfMemory,
+    /// The page tables are above 4 GiB, where the trampoline's 32 bit `mov cr3` can't reach them.
+    PageTablesTooHigh,
+}
+
+// LOCAL APIC ====================================
+// every CPU reaches its own local APIC at the same physical address (0xFEE00000 unless moved) --> one mapping serves all
+
+const LAPIC_ID: usize = 0x20;
+const LAPIC_ICR_LOW: usize = 0x300;
+const LAPIC_ICR_HIGH: usize = 0x310;
+
+// interrupt command register bits
+const ICR_INIT: u32 = 0b101 << 8;
+const ICR_STARTUP: u32 = 0b110 << 8;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+
+static LAPIC: Once<VirtAddr> = Once::new();
+
+fn lapic_register(offset: usize) -> *mut u32 {
+    (*LAPIC.r#try().expect("local APIC not mapped") + offset as u64).as_mut_ptr()
+}
+
+// map the local APIC's registers (uncached) the first time it is needed
+fn map_lapic() -> Result<(), SmpError> {
+    if LAPIC.r#try().is_none() {
+        let address = crate::cpu::msr::apic_base().address();
+        let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE;
+        let base = memory::map_physical(address, 4096, flags).map_err(|_| SmpError::OutOfMemory)?;
+        LAPIC.call_once(|| base);
+    }
+    Ok(())
+}
+
+// send an IPI to the local APIC with `apic_id` and wait until it left ours
+fn send_ipi(apic_id: u8, command: u32) {
+    unsafe {
+        mmio_write_u32(lapic_register(LAPIC_ICR_HIGH), u32::from(apic_id) << 24);
+        mmio_write_u32(lapic_register(LAPIC_ICR_LOW), command); // writing the low half sends it
+        while mmio_read_u32(lapic_register(LAPIC_ICR_LOW)) & ICR_DELIVERY_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+// busy wait about `us` microseconds (a port 0x80 read takes about 1 µs), works without interrupts or calibration
+fn delay_us(us: u32) {
+    let mut port: Port<u8> = Port::new(0x80);
+    for _ in 0..us {
+        unsafe { port.read() };
+    }
+}
+
+/// The local APIC ID of the calling CPU.
+pub fn current_cpu_id() -> u8 {
+    match LAPIC.r#try() {
+        Some(_) => (unsafe { mmio_read_u32(lapic_register(LAPIC_ID)) } >> 24) as u8,
+        // before init() --> the initial APIC ID the CPU reports (CPUID.1:EBX[31:24]), the same unless someone changed it
+        None => (crate::cpuid::detect_cpu_features().leaf1.ebx >> 24) as u8,
+    }
+}
+
+// TRAMPOLINE ====================================
+// assembled into the kernel (as data, it never runs from there) and copied to TRAMPOLINE_ADDR, so every address in it
+// is computed as TRAMPOLINE_ADDR + (label - smp_trampoline_start)
+// - 16 bit: load a temporary GDT, enable PAE, load CR3, set EFER.LME and EFER.NXE if the BSP has it (the kernel's page
+//   tables use the NO_EXECUTE bit then, see memory::no_execute()), then protection and paging at once --> a far jump
+//   into the 64 bit code segment finishes the switch
+// - 64 bit: load the data segments, the stack and the argument from the data at the end and call ap_main()
+global_asm!(
+    r#"
+.pushsection .rodata.smp_trampoline, "a"
+.global smp_trampoline_start
+.global smp_trampoline_end
+.global smp_trampoline_cr3
+.global smp_trampoline_stack
+.global smp_trampoline_entry
+.global smp_trampoline_arg
+.global smp_trampoline_efer
+
+.code16
+smp_trampoline_start:
+    cli
+    cld
+    xor ax, ax
+    mov ds, ax
+    lgdt [{base} + (smp_trampoline_gdt_pointer - smp_trampoline_start)]
+    mov eax, cr4
+    or eax, 1 << 5
+    mov cr4, eax
+    mov eax, [{base} + (smp_trampoline_cr3 - smp_trampoline_start)]
+    mov cr3, eax
+    mov ecx, 0xC0000080
+    rdmsr
+    or eax, [{base} + (smp_trampoline_efer - smp_trampoline_start)]
+    wrmsr
+    mov eax, cr0
+    or eax, (1 << 31) | 1
+    mov cr0, eax
+    .byte 0x66, 0xEA
+    .long {base} + (smp_trampoline_long_mode - smp_trampoline_start)
+    .word 0x08
+
+.code64
+smp_trampoline_long_mode:
+    mov ax, 0x10
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+    xor ax, ax
+    mov fs, ax
+    mov gs, ax
+    mov rsp, [{base} + (smp_trampoline_stack - smp_trampoline_start)]
+    mov rdi, [{base} + (smp_trampoline_arg - smp_trampoline_start)]
+    mov rax, [{base} + (smp_trampoline_entry - smp_trampoline_start)]
+    call rax
+    ud2
+
+.align 8
+smp_trampoline_gdt:
+    .quad 0
+    .quad 0x00AF9A000000FFFF
+    .quad 0x00CF92000000FFFF
+smp_trampoline_gdt_pointer:
+    .word 3 * 8 - 1
+    .long {base} + (smp_trampoline_gdt - smp_trampoline_start)
+
+.align 8
+smp_trampoline_cr3: .quad 0
+smp_trampoline_stack: .quad 0
+smp_trampoline_entry: .quad 0
+smp_trampoline_arg: .quad 0
+smp_trampoline_efer: .quad 0
+smp_trampoline_end:
+.popsection
+"#,
+    base = const TRAMPOLINE_ADDR,
+);
+
+extern "C" {
+    static smp_trampoline_start: u8;
+    static smp_trampoline_end: u8;
+    static smp_trampoline_cr3: u8;
+    static smp_trampoline_stack: u8;
+    static smp_trampoline_entry: u8;
+    static smp_trampoline_arg: u8;
+    static smp_trampoline_efer: u8;
+}
+
+// the trampoline as assembled into the kernel
+fn trampoline_code() -> &'static [u8] {
+    unsafe {
+        let start = &smp_trampoline_start as *const u8;
+        let len = &smp_trampoline_end as *const u8 as usize - start as usize;
+        core::slice::from_raw_parts(start, len)
+    }
+}
+
+// the u64 at `field` (one of the smp_trampoline_* data labels) in the copy at TRAMPOLINE_ADDR
+fn trampoline_field(field: &u8) -> *mut u64 {
+    let offset = field as *const u8 as u64 - unsafe { &smp_trampoline_start as *const u8 as u64 };
+    (memory::phys_to_virt(PhysAddr::new(TRAMPOLINE_ADDR)) + offset).as_mut_ptr()
+}
+
+// identity map the trampoline's page (executable) in the kernel's page tables, for the instructions right after paging
+// is turned on --> returns false if it already was
+fn map_trampoline() -> Result<bool, SmpError> {
+    let mut mapper = unsafe { memory::init(memory::physical_memory_offset()) };
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(TRAMPOLINE_ADDR));
+    let frame = PhysFrame::containing_address(PhysAddr::new(TRAMPOLINE_ADDR));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    match unsafe { mapper.map_to(page, frame, flags, &mut GlobalFrameAllocator) } {
+        Ok(flush) => {
+            flush.flush();
+            Ok(true)
+        }
+        Err(MapToError::PageAlreadyMapped(mapped)) if mapped == frame => Ok(false),
+        Err(_) => Err(SmpError::OutOfMemory),
+    }
+}
+
+fn unmap_trampoline() {
+    let mut mapper = unsafe { memory::init(memory::physical_memory_offset()) };
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(TRAMPOLINE_ADDR));
+    if let Ok((_, flush)) = mapper.unmap(page) {
+        flush.flush();
+    }
+}
+
+// BRINGUP ====================================
+
+// what ap_main() gets from the BSP (through the trampoline's argument)
+struct ApStart {
+    cpu_index: usize,
+    tables: &'static CpuTables,
+}
+
+// number of CPUs running kernel code, the BSP included
+static ONLINE: AtomicUsize = AtomicUsize::new(1);
+
+/// Number of CPUs that are up (1 before `init()` or with a single CPU).
+pub fn cpu_count() -> usize {
+    ONLINE.load(Ordering::SeqCst)
+}
+
+// where the trampoline takes an AP, on the stack the BSP gave it
+extern "C" fn ap_main(start: &'static ApStart) -> ! {
+    start.tables.load();
+    crate::interrupts::load_idt();
+    crate::interrupts::enable_machine_check();
+    percpu::init_per_cpu_for(start.cpu_index);
+    ONLINE.fetch_add(1, Ordering::SeqCst);
+    // nothing to run yet --> interrupts stay off (the trampoline cleared them) and the CPU sleeps for good
+    crate::hlt_loop();
+}
+
+// INIT-SIPI-SIPI and wait for the AP to bump ONLINE, returns whether it did
+fn start_ap(apic_id: u8) -> bool {
+    let expected = ONLINE.load(Ordering::SeqCst) + 1;
+    let vector = (TRAMPOLINE_ADDR / 4096) as u32;
+    send_ipi(apic_id, ICR_INIT | ICR_LEVEL_ASSERT);
+    delay_us(10_000);
+    for _ in 0..2 {
+        // the second SIPI is for CPUs that missed the first one, it is ignored by a CPU that is already running
+        send_ipi(apic_id, ICR_STARTUP | ICR_LEVEL_ASSERT | vector);
+        delay_us(200);
+    }
+    for _ in 0..AP_TIMEOUT_US {
+        if ONLINE.load(Ordering::SeqCst) >= expected {
+            return true;
+        }
+        delay_us(1);
+    }
+    false
+}
+
+/// Start every enabled CPU from the MADT (up to `MAX_CPUS`) and print how many are online, returns that number.
+///
+/// Needs the heap, the frame allocator and `acpi::init()`. Must only be called once, from the BSP.
+pub fn init() -> Result<usize, SmpError> {
+    let madt = acpi::tables().ok_or(SmpError::Acpi(acpi::AcpiError::NoRsdp))?.madt().map_err(SmpError::Acpi)?;
+    let bsp = current_cpu_id();
+    let aps: Vec<u8> = madt
+        .entries()
+        .filter_map(|entry| match entry {
+            MadtEntry::LocalApic { apic_id, flags, .. } if flags & LAPIC_ENABLED != 0 && apic_id != bsp => Some(apic_id),
+            _ => None,
+        })
+        .collect();
+    if aps.is_empty() {
+        println!("1 CPU online");
+        return Ok(1);
+    }
+
+    // the bootloader's memory regions are dead once the kernel runs, anything else in the page must be left alone
+    use bootloader::bootinfo::MemoryRegionType;
+    match memory::memory_region_type(PhysAddr::new(TRAMPOLINE_ADDR)) {
+        Some(MemoryRegionType::Usable) | Some(MemoryRegionType::Bootloader) => {}
+        _ => return Err(SmpError::TrampolineInUse),
+    }
+    let cr3 = Cr3::read().0.start_address().as_u64();
+    if cr3 > u64::from(u32::MAX) {
+        return Err(SmpError::PageTablesTooHigh);
+    }
+    map_lapic()?;
+    let mapped_here = map_trampoline()?;
+    // the copy is the only part that is written to, the AP runs it through the identity mapping
+    let code = trampoline_code();
+    unsafe {
+        let copy = memory::phys_to_virt(PhysAddr::new(TRAMPOLINE_ADDR)).as_mut_ptr::<u8>();
+        core::ptr::copy_nonoverlapping(code.as_ptr(), copy, code.len());
+        trampoline_field(&smp_trampoline_cr3).write_volatile(cr3);
+        trampoline_field(&smp_trampoline_entry).write_volatile(ap_main as usize as u64);
+        // the same NXE as this CPU: its page tables are shared, and setting NXE without NX would #GP
+        let efer_bits = msr::EFER_LME | (msr::efer().0 & msr::EFER_NXE);
+        trampoline_field(&smp_trampoline_efer).write_volatile(efer_bits);
+    }
+
+    let mut result = Ok(());
+    for (i, &apic_id) in aps.iter().enumerate().take(MAX_CPUS - 1) {
+        let (tables, stack) = match (CpuTables::allocate(), memory::allocate_stack(AP_STACK_PAGES)) {
+            (Some(tables), Some(stack)) => (tables, stack),
+            _ => {
+                result = Err(SmpError::OutOfMemory);
+                break;
+            }
+        };
+        let start: &'static ApStart = Box::leak(Box::new(ApStart { cpu_index: i + 1, tables }));
+        unsafe {
+            trampoline_field(&smp_trampoline_stack).write_volatile(stack.as_u64());
+            trampoline_field(&smp_trampoline_arg).write_volatile(start as *const ApStart as u64);
+        }
+        if !start_ap(apic_id) {
+            // a late AP would still use this stack and argument --> don't hand the trampoline to another one
+            println!("smp: CPU with APIC ID {} didn't start", apic_id);
+            break;
+        }
+    }
+
+    if mapped_here {
+        unmap_trampoline();
+    }
+    println!("{} CPUs online", cpu_count());
+    result.map(|()| cpu_count())
+}
+
+// TESTS ===================================
+
+#[test_case]
+fn test_bsp_cpu_id() {
+    // the tests run with a single CPU and without init(): the BSP is the only enabled local APIC
+    let madt = acpi::init().expect("no ACPI tables").madt().expect("bad MADT");
+    let first = madt.entries().find_map(|entry| match entry {
+        MadtEntry::LocalApic { apic_id, flags, .. } if flags & LAPIC_ENABLED != 0 => Some(apic_id),
+        _ => None,
+    });
+    assert_eq!(first, Some(current_cpu_id()));
+    assert_eq!(cpu_count(), 1);
+}
+
+#[test_case]
+fn test_trampoline_fits_its_page() {
+    let code = trampoline_code();
+    assert!(code.len() <= 4096, "trampoline is {} bytes", code.len());
+    assert_eq!(code[0], 0xFA, "trampoline doesn't start with cli");
+    // the data fields are where trampoline_field() writes, inside the copy
+    let fields = unsafe {
+        [&smp_trampoline_cr3, &smp_trampoline_stack, &smp_trampoline_entry, &smp_trampoline_arg, &smp_trampoline_efer]
+    };
+    for field in fields {
+        let offset = field as *const u8 as usize - code.as_ptr() as usize;
+        assert!(offset + 8 <= code.len());
+        assert_eq!(offset % 8, 0);
+    }
+}
@@ -0,0 +1,245 @@
+// Kernel state snapshots --> the interesting counters and registers packed into bytes, ex. to send over serial for debugging
+// encoding: a sequence of TLV records [type u8][length u16, little endian][value], each value is a list of little endian
+// u64 fields in the order of the struct's fields
+// - parse_snapshot() skips records with unknown types, so a newer kernel can add records without breaking older tools
+// - nothing here allocates --> a snapshot can be taken with the heap broken or in an interrupt handler
+use crate::allocator::AllocatorStats;
+use crate::interrupts::{InterruptStats, IRQ_LINES};
+use crate::memory::MemoryStats;
+
+pub const TAG_ALLOCATOR: u8 = 1;
+pub const TAG_MEMORY: u8 = 2;
+pub const TAG_CPU: u8 = 3;
+pub const TAG_INTERRUPTS: u8 = 4;
+
+const RECORD_HEADER_LEN: usize = 3;
+
+/// Writes itself as one TLV record.
+pub trait KernelSerialize {
+    /// Write the record to the start of `buf`, returns its length (0 if it doesn't fit, nothing is written then).
+    fn serialize(&self, buf: &mut [u8]) -> usize;
+}
+
+// write a record made of `fields`
+fn write_record(buf: &mut [u8], tag: u8, fields: &[u64]) -> usize {
+    let len = RECORD_HEADER_LEN + fields.len() * 8;
+    if buf.len() < len {
+        return 0;
+    }
+    buf[0] = tag;
+    buf[1..3].copy_from_slice(&((fields.len() * 8) as u16).to_le_bytes());
+    for (field, bytes) in fields.iter().zip(buf[RECORD_HEADER_LEN..len].chunks_exact_mut(8)) {
+        bytes.copy_from_slice(&field.to_le_bytes());
+    }
+    len
+}
+
+// the fields of a record's value, `None` if it doesn't hold exactly N of them
+fn read_fields<const N: usize>(value: &[u8]) -> Option<[u64; N]> {
+    if value.len() != N * 8 {
+        return None;
+    }
+    let mut fields = [0; N];
+    for (field, bytes) in fields.iter_mut().zip(value.chunks_exact(8)) {
+        *field = u64::from_le_bytes(bytes.try_into().unwrap());
+    }
+    Some(fields)
+}
+
+impl KernelSerialize for AllocatorStats {
+    fn serialize(&self, buf: &mut [u8]) -> usize {
+        write_record(buf, TAG_ALLOCATOR, &[
+            self.heap_size,
+            self.allocations,
+            self.deallocations,
+            self.failed_allocations,
+            self.bytes_in_use,
+        ])
+    }
+}
+
+impl KernelSerialize for MemoryStats {
+    fn serialize(&self, buf: &mut [u8]) -> usize {
+        write_record(buf, TAG_MEMORY, &[self.usable_frames, self.allocated_frames, self.recycled_frames])
+    }
+}
+
+impl KernelSerialize for InterruptStats {
+    fn serialize(&self, buf: &mut [u8]) -> usize {
+        let mut fields = [0; 3 + IRQ_LINES];
+        fields[..3].copy_from_slice(&[self.timer_ticks, self.keyboard, self.breakpoints]);
+        fields[3..].copy_from_slice(&self.device_irqs);
+        write_record(buf, TAG_INTERRUPTS, &fields)
+    }
+}
+
+// CPU CONTEXT ====================================
+
+/// The control registers, flags and stack pointer of the CPU at the time of `capture()`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CpuContext {
+    pub cr0: u64,
+    /// The last page fault address.
+    pub cr2: u64,
+    /// The active level 4 table + PCID/flags.
+    pub cr3: u64,
+    pub cr4: u64,
+    pub efer: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+}
+
+impl CpuContext {
+    /// Read the registers of the calling CPU.
+    pub fn capture() -> Self {
+        use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+        use x86_64::registers::rflags;
+
+        let rsp: u64;
+        unsafe { core::arch::asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags)) };
+        let (level_4_frame, cr3_flags) = Cr3::read_raw();
+        CpuContext {
+            cr0: Cr0::read_raw(),
+            cr2: Cr2::read().as_u64(),
+            cr3: level_4_frame.start_address().as_u64() | u64::from(cr3_flags),
+            cr4: Cr4::read_raw(),
+            efer: crate::cpu::msr::efer().0,
+            rflags: rflags::read_raw(),
+            rsp,
+        }
+    }
+}
+
+impl KernelSerialize for CpuContext {
+    fn serialize(&self, buf: &mut [u8]) -> usize {
+        write_record(buf, TAG_CPU, &[self.cr0, self.cr2, self.cr3, self.cr4, self.efer, self.rflags, self.rsp])
+    }
+}
+
+// SNAPSHOTS ====================================
+
+/// Serialize the allocator, frame allocator, CPU and interrupt state into `buf`, returns the snapshot's length.
+///
+/// Records that don't fit are left out (`parse_snapshot()` reports them as missing).
+pub fn snapshot_kernel_state(buf: &mut [u8]) -> usize {
+    let mut len = 0;
+    len += crate::allocator::stats().serialize(&mut buf[len..]);
+    len += crate::memory::stats().serialize(&mut buf[len..]);
+    len += CpuContext::capture().serialize(&mut buf[len..]);
+    len += crate::interrupts::stats().serialize(&mut buf[len..]);
+    len
+}
+
+/// A snapshot read back by `parse_snapshot()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KernelSnapshot {
+    pub allocator: AllocatorStats,
+    pub memory: MemoryStats,
+    pub cpu: CpuContext,
+    pub interrupts: InterruptStats,
+}
+
+/// Why `parse_snapshot()` failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError {
+    /// The buffer ends in the middle of a record.
+    Truncated,
+    /// A known record has the wrong length for its type.
+    BadLength { tag: u8, len: usize },
+    /// No record of this type.
+    Missing { tag: u8 },
+}
+
+/// Read a snapshot written by `snapshot_kernel_state()`.
+pub fn parse_snapshot(buf: &[u8]) -> Result<KernelSnapshot, ParseError> {
+    let (mut allocator, mut memory, mut cpu, mut interrupts) = (None, None, None, None);
+    let mut rest = buf;
+    while !rest.is_empty() {
+        if rest.len() < RECORD_HEADER_LEN {
+            return Err(ParseError::Truncated);
+        }
+        let tag = rest[0];
+        let len = usize::from(u16::from_le_bytes([rest[1], rest[2]]));
+        let value = rest.get(RECORD_HEADER_LEN..RECORD_HEADER_LEN + len).ok_or(ParseError::Truncated)?;
+        rest = &rest[RECORD_HEADER_LEN + len..];
+
+        let bad_length = ParseError::BadLength { tag, len };
+        match tag {
+            TAG_ALLOCATOR => {
+                let [heap_size, allocations, deallocations, failed_allocations, bytes_in_use] =
+                    read_fields(value).ok_or(bad_length)?;
+                allocator = Some(AllocatorStats { heap_size, allocations, deallocations, failed_allocations, bytes_in_use });
+            }
+            TAG_MEMORY => {
+                let [usable_frames, allocated_frames, recycled_frames] = read_fields(value).ok_or(bad_length)?;
+                memory = Some(MemoryStats { usable_frames, allocated_frames, recycled_frames });
+            }
+            TAG_CPU => {
+                let [cr0, cr2, cr3, cr4, efer, rflags, rsp] = read_fields(value).ok_or(bad_length)?;
+                cpu = Some(CpuContext { cr0, cr2, cr3, cr4, efer, rflags, rsp });
+            }
+            TAG_INTERRUPTS => {
+                let fields: [u64; 3 + IRQ_LINES] = read_fields(value).ok_or(bad_length)?;
+                let mut device_irqs = [0; IRQ_LINES];
+                device_irqs.copy_from_slice(&fields[3..]);
+                interrupts = Some(InterruptStats {
+                    timer_ticks: fields[0],
+                    keyboard: fields[1],
+                    breakpoints: fields[2],
+                    device_irqs,
+                });
+            }
+            _ => {} // from a newer kernel
+        }
+    }
+    Ok(KernelSnapshot {
+        allocator: allocator.ok_or(ParseError::Missing { tag: TAG_ALLOCATOR })?,
+        memory: memory.ok_or(ParseError::Missing { tag: TAG_MEMORY })?,
+        cpu: cpu.ok_or(ParseError::Missing { tag: TAG_CPU })?,
+        interrupts: interrupts.ok_or(ParseError::Missing { tag: TAG_INTERRUPTS })?,
+    })
+}
+
+// TESTS ===================================
+
+#[test_case]
+fn test_snapshot_round_trip() {
+    let allocator = AllocatorStats { heap_size: 100 * 1024, allocations: 7, deallocations: 5, failed_allocations: 1, bytes_in_use: 96 };
+    let memory = MemoryStats { usable_frames: 30000, allocated_frames: 42, recycled_frames: 3 };
+    let cpu = CpuContext { cr0: 0x8001_0033, cr2: 0xdead_b000, cr3: 0x1000, cr4: 0x6a0, efer: 0xd01, rflags: 0x246, rsp: 0x4444_0000_1ff8 };
+    let mut interrupts = InterruptStats { timer_ticks: 1234, keyboard: 2, breakpoints: 1, device_irqs: [0; IRQ_LINES] };
+    interrupts.device_irqs[11] = 99;
+
+    let mut buf = [0u8; 512];
+    let mut len = 0;
+    len += allocator.serialize(&mut buf[len..]);
+    len += memory.serialize(&mut buf[len..]);
+    // an unknown record in the middle is skipped
+    len += write_record(&mut buf[len..], 0xEE, &[1, 2]);
+    len += cpu.serialize(&mut buf[len..]);
+    len += interrupts.serialize(&mut buf[len..]);
+    assert_eq!(parse_snapshot(&buf[..len]), Ok(KernelSnapshot { allocator, memory, cpu, interrupts }));
+
+    assert_eq!(parse_snapshot(&buf[..len - 1]), Err(ParseError::Truncated));
+    assert_eq!(parse_snapshot(&buf[..2]), Err(ParseError::Truncated));
+    let allocator_len = allocator.serialize(&mut [0; 64]);
+    assert_eq!(parse_snapshot(&buf[..allocator_len]), Err(ParseError::Missing { tag: TAG_MEMORY }));
+    assert_eq!(memory.serialize(&mut [0; 10]), 0, "record written past the buffer");
+}
+
+#[test_case]
+fn test_snapshot_kernel_state() {
+    let mut buf = [0u8; 512];
+    let len = snapshot_kernel_state(&mut buf);
+    let snapshot = parse_snapshot(&buf[..len]).expect("snapshot doesn't parse");
+    assert!(snapshot.allocator.heap_size > 0);
+    assert!(snapshot.memory.allocated_frames > 0);
+    assert!(snapshot.memory.usable_frames >= snapshot.memory.allocated_frames);
+    assert_ne!(snapshot.cpu.cr0 & 0x8000_0000, 0, "paging not enabled?");
+    assert_eq!(snapshot.cpu.cr3 & !0xFFF, x86_64::registers::control::Cr3::read().0.start_address().as_u64());
+    assert!(snapshot.interrupts.timer_ticks <= crate::interrupts::timer_ticks());
+
+    // too small for everything --> the missing records are reported
+    let len = snapshot_kernel_state(&mut buf[..60]);
+    assert!(matches!(parse_snapshot(&buf[..len]), Err(ParseError::Missing { .. })));
+}
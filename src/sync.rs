@@ -0,0 +1,219 @@
+// Synchronization helpers on top of the spin crate and the CPU interrupt flag
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+use spin::{Mutex, MutexGuard, Once};
+use x86_64::instructions::interrupts;
+
+/// Keeps interrupts disabled for as long as it is alive.
+///
+/// The RAII version of `x86_64::instructions::interrupts::without_interrupts()` --> when dropped it re-enables
+/// interrupts, but only if they were enabled when the guard was created, so guards can be nested and early returns
+/// can't leave interrupts switched off by accident.
+#[must_use = "interrupts are re-enabled as soon as the guard is dropped"]
+pub struct InterruptGuard {
+    was_enabled: bool,
+}
+
+/// Disable interrupts until the returned guard is dropped.
+pub fn without_interrupts_guard() -> InterruptGuard {
+    let was_enabled = interrupts::are_enabled();
+    if was_enabled {
+        interrupts::disable();
+    }
+    InterruptGuard { was_enabled }
+}
+
+impl InterruptGuard {
+    /// Whether interrupts were enabled before this guard disabled them.
+    pub fn was_enabled(&self) -> bool {
+        self.was_enabled
+    }
+}
+
+impl Drop for InterruptGuard {
+    fn drop(&mut self) {
+        if self.was_enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+// IRQ MUTEX =============================
+
+/// A spinlock that is always taken with interrupts disabled, for data shared with interrupt handlers.
+///
+/// An interrupt handler spinning on a lock held by the code it interrupted never gets it back --> `lock_irq()` disables
+/// interrupts before locking, instead of wrapping every `lock()` in `without_interrupts()` by hand.
+pub struct IrqMutex<T> {
+    inner: Mutex<T>,
+}
+
+/// The guard of `IrqMutex::lock_irq()`: unlocks, then restores the interrupt flag to what it was before locking.
+///
+/// Not `Send`: the saved flag belongs to the CPU that took the lock.
+#[must_use = "the lock is released as soon as the guard is dropped"]
+pub struct IrqMutexGuard<'a, T> {
+    // fields are dropped in order --> the lock is released before interrupts can come back
+    guard: MutexGuard<'a, T>,
+    _interrupts: InterruptGuard,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<T> IrqMutex<T> {
+    pub const fn new(value: T) -> Self {
+        IrqMutex { inner: Mutex::new(value) }
+    }
+
+    /// Disable interrupts (remembering whether they were enabled), then lock.
+    pub fn lock_irq(&self) -> IrqMutexGuard<T> {
+        let interrupts = without_interrupts_guard();
+        IrqMutexGuard { guard: self.inner.lock(), _interrupts: interrupts, _not_send: PhantomData }
+    }
+}
+
+impl<T> Deref for IrqMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for IrqMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+// ONCE =============================
+
+/// A value that is set exactly once at runtime and read-only afterwards, a plain alternative to `lazy_static!`.
+///
+/// Unlike `spin::Once::call_once()`, which silently ignores every call after the first, initializing a `KernelOnce`
+/// twice is a bug --> a debug assertion. Release builds keep the first value and drop the new one.
+pub struct KernelOnce<T> {
+    once: Once<T>,
+}
+
+impl<T> KernelOnce<T> {
+    pub const fn new() -> Self {
+        KernelOnce { once: Once::new() }
+    }
+
+    /// Store `value`, returns a reference to the stored value. Must be called only once.
+    pub fn init(&self, value: T) -> &T {
+        let mut first = false;
+        let stored = self.once.call_once(|| {
+            first = true;
+            value
+        });
+        debug_assert!(first, "KernelOnce initialized twice");
+        stored
+    }
+
+    /// The value, `None` before `init()`.
+    pub fn get(&self) -> Option<&T> {
+        self.once.r#try()
+    }
+
+    /// The value, panics before `init()`.
+    pub fn get_or_panic(&self) -> &T {
+        self.get().expect("KernelOnce used before init()")
+    }
+}
+
+impl<T> Default for KernelOnce<T> {
+    fn default() -> Self {
+        KernelOnce::new()
+    }
+}
+
+// TESTS ===================================
+// NOTE: we build with panic=abort so drop code never runs on panic --> only the regular and early return paths can be tested
+
+#[test_case]
+fn test_interrupt_guard_restores_enabled() {
+    let before = interrupts::are_enabled();
+    let guard = without_interrupts_guard();
+    assert!(!interrupts::are_enabled());
+    drop(guard);
+    assert_eq!(interrupts::are_enabled(), before);
+}
+
+#[test_case]
+fn test_interrupt_guard_nested_stays_disabled() {
+    let before = interrupts::are_enabled();
+    let outer = without_interrupts_guard();
+    {
+        let inner = without_interrupts_guard();
+        assert!(!inner.was_enabled());
+    }
+    // dropping the inner guard must not turn interrupts back on while the outer guard is alive
+    assert!(!interrupts::are_enabled());
+    drop(outer);
+    assert_eq!(interrupts::are_enabled(), before);
+}
+
+#[test_case]
+fn test_interrupt_guard_early_return() {
+    fn early_return(bail: bool) -> u32 {
+        let _guard = without_interrupts_guard();
+        if bail {
+            return 0;
+        }
+        1
+    }
+
+    let before = interrupts::are_enabled();
+    early_return(true);
+    assert_eq!(interrupts::are_enabled(), before);
+    early_return(false);
+    assert_eq!(interrupts::are_enabled(), before);
+}
+
+#[test_case]
+fn test_irq_mutex_disables_interrupts() {
+    let mutex = IrqMutex::new(0);
+    interrupts::enable();
+    {
+        let mut value = mutex.lock_irq();
+        assert!(!interrupts::are_enabled());
+        *value += 1;
+    }
+    assert!(interrupts::are_enabled());
+    assert_eq!(*mutex.lock_irq(), 1);
+}
+
+#[test_case]
+fn test_irq_mutex_keeps_interrupts_disabled() {
+    let mutex = IrqMutex::new(0);
+    let outer = without_interrupts_guard();
+    drop(mutex.lock_irq());
+    // interrupts were already off when locking --> they stay off after unlocking
+    assert!(!interrupts::are_enabled());
+    drop(outer);
+}
+
+#[test_case]
+fn test_irq_mutex_bounds() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<IrqMutex<u64>>(); // usable in a static
+
+    // the guard must not be Send: if it were, both impls would apply and the call below would be ambiguous
+    trait AmbiguousIfSend<A> {
+        fn check() {}
+    }
+    impl<T: ?Sized> AmbiguousIfSend<()> for T {}
+    impl<T: ?Sized + Send> AmbiguousIfSend<u8> for T {}
+    <IrqMutexGuard<'static, u64> as AmbiguousIfSend<_>>::check();
+}
+
+// init() twice is tested in tests/kernel_once.rs, it panics in debug builds
+#[test_case]
+fn test_kernel_once_init() {
+    let once = KernelOnce::new();
+    assert!(once.get().is_none());
+    assert_eq!(*once.init(42), 42);
+    assert_eq!(once.get(), Some(&42));
+    assert_eq!(*once.get_or_panic(), 42);
+}
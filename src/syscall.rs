@@ -0,0 +1,171 @@
+// System calls --> how user programs (ring 3) ask the kernel to do things for them
+// a program puts the syscall number in rax and the arguments in rdi, rsi, rdx, then executes `int 0x80`
+// --> the result comes back in rax (see abi.rs for the numbers and conventions)
+// the IDT entry for 0x80 has privilege level 3 so user code is allowed to trigger it (see interrupts.rs)
+use core::arch::global_asm;
+use abi::*;
+use x86_64::VirtAddr;
+pub mod abi;
+
+/// The general purpose registers of the calling program, in the order the entry stub pushes them.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct SyscallRegisters {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+}
+
+// the "x86-interrupt" calling convention doesn't give us the registers of the interrupted code, so the entry is written in assembly:
+// save every register on the (kernel) stack, hand a pointer to them to the rust dispatcher, restore them (with rax = result) and `iretq`
+// stack alignment: the CPU aligns the stack to 16 bytes and pushes 5 values, + 15 registers = 160 bytes --> aligned again for the call
+global_asm!(
+    ".global mini_os_syscall_entry",
+    "mini_os_syscall_entry:",
+    "push rax",
+    "push rbx",
+    "push rcx",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push rbp",
+    "push r8",
+    "push r9",
+    "push r10",
+    "push r11",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "cld",
+    "mov rdi, rsp",
+    "call {dispatch}",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop r11",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rbp",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop rcx",
+    "pop rbx",
+    "pop rax",
+    "iretq",
+    dispatch = sym syscall_dispatch,
+);
+
+extern "C" {
+    fn mini_os_syscall_entry();
+}
+
+/// Address of the `int 0x80` entry stub, for the IDT.
+pub fn entry_address() -> u64 {
+    mini_os_syscall_entry as usize as u64
+}
+
+extern "C" fn syscall_dispatch(regs: &mut SyscallRegisters) {
+    let result = match regs.rax {
+        SYS_WRITE => sys_write(regs.rdi, regs.rsi, regs.rdx),
+        SYS_SLEEP_MS => sys_sleep_ms(regs.rdi),
+        SYS_GETPID => sys_getpid(),
+        SYS_EXIT => crate::process::exit_current(regs.rdi as i64),
+        _ => ENOSYS,
+    };
+    regs.rax = result as u64;
+}
+
+// never trust a user pointer --> `[buf, buf + len)` has to be mapped user memory of the calling process,
+// checked by walking its page tables (so kernel memory and unmapped holes are refused) before anything is read
+fn user_bytes(buf: u64, len: u64) -> Result<&'static [u8], i64> {
+    let start = VirtAddr::try_new(buf).map_err(|_| EFAULT)?;
+    if !crate::process::current_user_range_is_mapped(start, len) {
+        return Err(EFAULT);
+    }
+    Ok(unsafe { core::slice::from_raw_parts(buf as *const u8, len as usize) })
+}
+
+fn sys_write(fd: u64, buf: u64, len: u64) -> i64 {
+    if fd != 1 {
+        return EBADF;
+    }
+    let bytes = match user_bytes(buf, len) {
+        Ok(bytes) => bytes,
+        Err(error) => return error,
+    };
+    let text = match core::str::from_utf8(bytes) {
+        Ok(text) => text,
+        Err(_) => return EINVAL,
+    };
+    crate::print!("{}", text);
+    crate::serial_print!("{}", text);
+    len as i64
+}
+
+/// Number of timer ticks covering at least `ms` milliseconds at the configured timer frequency.
+pub fn ms_to_ticks(ms: u64) -> u64 {
+    let hz = u64::from(crate::config::get().timer_hz);
+    match ms.checked_mul(hz) {
+        Some(cycles) => cycles.div_ceil(1000),
+        None => u64::MAX, // too long to count in ticks --> as good as forever
+    }
+}
+
+/// Block the CPU for at least `ms` milliseconds (in whole timer ticks) by halting until enough timer ticks went by.
+///
+/// Interrupts are only turned on while halting so the timer can tick, afterwards they are as enabled or disabled as before.
+pub fn sleep_ms(ms: u64) {
+    use crate::interrupts::timer_ticks;
+    use x86_64::instructions::interrupts;
+
+    let were_enabled = interrupts::are_enabled();
+    let wake_at = timer_ticks().saturating_add(ms_to_ticks(ms));
+    interrupts::disable();
+    while timer_ticks() < wake_at {
+        interrupts::enable_and_hlt();
+        interrupts::disable();
+    }
+    if were_enabled {
+        interrupts::enable();
+    }
+}
+
+// there is no process scheduler yet, so the process "blocks" by halting the CPU (interrupts are off inside the syscall gate)
+fn sys_sleep_ms(ms: u64) -> i64 {
+    sleep_ms(ms);
+    0
+}
+
+fn sys_getpid() -> i64 {
+    match crate::process::current_pid() {
+        Some(pid) => pid as i64,
+        None => EINVAL,
+    }
+}
+
+// TESTS ===================================
+
+#[test_case]
+fn test_ms_to_ticks_rounds_up() {
+    let hz = u64::from(crate::config::get().timer_hz);
+    assert_eq!(ms_to_ticks(0), 0);
+    assert_eq!(ms_to_ticks(1), 1);
+    assert_eq!(ms_to_ticks(1000), hz);
+    assert_eq!(ms_to_ticks(u64::MAX), u64::MAX); // saturates instead of overflowing
+}
@@ -0,0 +1,29 @@
+// The syscall ABI --> what user programs can rely on, this must not change once programs are built against it
+//
+// calling convention (int 0x80):
+//   rax = syscall number
+//   rdi, rsi, rdx = arguments 1-3 (like the C calling convention)
+//   rax = result on return, every other register is preserved
+// results: a value >= 0 is success (its meaning depends on the syscall), a negative value is -errno
+// the numbers follow linux where there is an equivalent syscall, so disassembled programs are easier to read
+
+/// The interrupt vector of the syscall gate (`int 0x80`).
+pub const SYSCALL_VECTOR: u8 = 0x80;
+
+/// write(fd, buf, len) -> bytes written --> only fd 1 (stdout = VGA + serial) exists, `buf` has to be valid utf-8.
+pub const SYS_WRITE: u64 = 1;
+/// sleep_ms(ms) -> 0 --> blocks the process for at least `ms` milliseconds (in whole timer ticks).
+pub const SYS_SLEEP_MS: u64 = 35;
+/// getpid() -> pid of the calling process.
+pub const SYS_GETPID: u64 = 39;
+/// exit(code) --> never returns, `code` is returned from `Process::run()`.
+pub const SYS_EXIT: u64 = 60;
+
+/// Bad file descriptor.
+pub const EBADF: i64 = -9;
+/// Bad address --> a pointer argument isn't mapped user memory of the calling process.
+pub const EFAULT: i64 = -14;
+/// Invalid argument.
+pub const EINVAL: i64 = -22;
+/// Unknown syscall number.
+pub const ENOSYS: i64 = -38;
@@ -0,0 +1,465 @@
+// Cooperative multitasking via async/await --> every task is a future that an executor polls until it completes
+// tasks can't be interrupted by other tasks, they give up the CPU themselves by returning Poll::Pending (i.e. at each `.await`)
+// the waker passed with every poll lets whatever the task is waiting on (a timer, the keyboard, another task...) put it back in the ready queue
+// see: https://os.phil-opp.com/async-await/
+use alloc::{boxed::Box, collections::{BTreeMap, VecDeque}, vec::Vec};
+use core::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll, Waker},
+};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use crate::percpu::{TlsBlock, TASK_TLS_SIZE};
+
+pub mod coroutine;
+pub mod executor;
+pub mod keyboard;
+pub mod scheduler;
+pub mod usage;
+
+pub use scheduler::{sleep_ticks, TaskPriority};
+pub use usage::cpu_usage;
+
+/// A unique, monotonically assigned identifier for a task.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TaskId(u64);
+
+impl TaskId {
+    /// What the CPU "runs" when it isn't polling any task, never handed out by `next_task_id()`.
+    pub const IDLE: TaskId = TaskId(0);
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+
+    pub(crate) fn from_u64(id: u64) -> TaskId {
+        TaskId(id)
+    }
+}
+
+impl fmt::Display for TaskId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Task#{}", self.0)
+    }
+}
+
+// starts at 1, 0 is TaskId::IDLE
+static NEXT_TASK_ID: AtomicU64 = AtomicU64::new(1);
+
+/// A new, unique task id (never `TaskId::IDLE`).
+pub fn next_task_id() -> TaskId {
+    loop {
+        // fetch_add is a single atomic instruction --> unique even if an interrupt handler creates a task in between
+        let id = NEXT_TASK_ID.fetch_add(1, Ordering::Relaxed);
+        if id != TaskId::IDLE.0 {
+            return TaskId(id); // only after 2^64 ids would the counter wrap around to 0
+        }
+    }
+}
+
+/// The task the CPU is polling right now, `TaskId::IDLE` outside of a task (or before the per-CPU data is set up).
+pub fn current_task_id() -> TaskId {
+    crate::percpu::this_cpu().map_or(TaskId::IDLE, |cpu| cpu.current_task())
+}
+
+/// Named scheduling priorities for the common cases (see scheduler.rs for how priorities work).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    High,
+    Normal,
+    Low,
+}
+
+impl Priority {
+    pub fn as_task_priority(self) -> TaskPriority {
+        match self {
+            Priority::High => 32,
+            Priority::Normal => 128,
+            Priority::Low => 224,
+        }
+    }
+}
+
+impl From<Priority> for TaskPriority {
+    fn from(priority: Priority) -> Self {
+        priority.as_task_priority()
+    }
+}
+
+/// A pinned, heap allocated future with no output plus the bookkeeping the executor needs.
+pub struct Task {
+    id: TaskId,
+    name: Option<&'static str>,
+    priority: TaskPriority, // base priority, see scheduler.rs
+    // pinned b/c async blocks can be self referential --> moving them in memory would invalidate their internal pointers
+    future: Pin<Box<dyn Future<Output = ()>>>,
+    tls: Option<TlsBlock>, // FS points here while the task is being polled --> see percpu.rs
+}
+
+impl Task {
+    /// Create an unnamed task from a future.
+    pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
+        Task {
+            id: next_task_id(),
+            name: None,
+            priority: Priority::Normal.as_task_priority(),
+            future: Box::pin(future),
+            tls: TlsBlock::new(TASK_TLS_SIZE),
+        }
+    }
+
+    /// Create a task with a name that shows up in `task::list()`.
+    pub fn with_name(name: &'static str, future: impl Future<Output = ()> + 'static) -> Task {
+        Task {
+            name: Some(name),
+            ..Task::new(future)
+        }
+    }
+
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    pub fn name(&self) -> Option<&'static str> {
+        self.name
+    }
+
+    pub fn priority(&self) -> TaskPriority {
+        self.priority
+    }
+
+    /// The task's TLS block, `None` if the heap was too full to give it one.
+    pub fn tls(&self) -> Option<&TlsBlock> {
+        self.tls.as_ref()
+    }
+
+    fn poll(&mut self, context: &mut Context) -> Poll<()> {
+        // "switch" to the task --> its thread-local slots become reachable through FS
+        if let Some(tls) = &self.tls {
+            tls.activate();
+        }
+        self.future.as_mut().poll(context)
+    }
+}
+
+// TASK TABLE ====================================
+
+/// Where a task is in its lifecycle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskState {
+    Ready, // in a ready queue, waiting to be polled
+    Waiting, // returned Poll::Pending, waiting for its waker
+    Finished, // returned Poll::Ready, kept around until its JoinHandle is dropped
+}
+
+/// A snapshot of a single task, see `list()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TaskInfo {
+    pub id: TaskId,
+    pub name: Option<&'static str>,
+    pub priority: TaskPriority,
+    pub state: TaskState,
+}
+
+struct TaskEntry {
+    name: Option<&'static str>,
+    priority: TaskPriority,
+    state: TaskState,
+    join_waker: Option<Waker>, // woken when the task finishes
+    detached: bool, // the JoinHandle was dropped --> nobody will ask about this task again once it finishes
+}
+
+// every spawned task (on any executor) has an entry here, which is what lets JoinHandles and `list()` work without
+// a reference to the executor that runs the task
+// it is only ever locked with interrupts disabled so wakers are free to touch it from interrupt handlers
+static TASK_TABLE: Mutex<BTreeMap<TaskId, TaskEntry>> = Mutex::new(BTreeMap::new());
+
+fn with_task_table<R>(f: impl FnOnce(&mut BTreeMap<TaskId, TaskEntry>) -> R) -> R {
+    interrupts::without_interrupts(|| f(&mut TASK_TABLE.lock()))
+}
+
+fn register(task: &Task) {
+    let entry = TaskEntry {
+        name: task.name,
+        priority: task.priority,
+        state: TaskState::Ready,
+        join_waker: None,
+        detached: false,
+    };
+    with_task_table(|table| table.insert(task.id, entry));
+}
+
+fn set_state(id: TaskId, state: TaskState) {
+    with_task_table(|table| {
+        match table.get_mut(&id) {
+            Some(entry) if entry.state != TaskState::Finished => entry.state = state, // a late wake up can't revive a finished task
+            _ => {}
+        }
+    });
+}
+
+// the base priority of task `id`, checked by executors whenever the task becomes ready
+fn base_priority(id: TaskId) -> Option<TaskPriority> {
+    with_task_table(|table| table.get(&id).map(|entry| entry.priority))
+}
+
+/// Change the base priority of task `id`, on whichever executor runs it. Takes effect the next time the task becomes ready.
+pub fn set_task_priority(id: TaskId, priority: TaskPriority) {
+    with_task_table(|table| {
+        if let Some(entry) = table.get_mut(&id) {
+            entry.priority = priority;
+        }
+    });
+}
+
+fn mark_finished(id: TaskId) {
+    let join_waker = with_task_table(|table| {
+        let entry = table.get_mut(&id)?;
+        entry.state = TaskState::Finished;
+        let waker = entry.join_waker.take();
+        if entry.detached {
+            table.remove(&id);
+        }
+        waker
+    });
+    // wake outside of the table lock, the waker takes the executor queue lock
+    if let Some(waker) = join_waker {
+        waker.wake();
+    }
+}
+
+/// Snapshot the id, name and state of every task that is alive (or finished but still joinable).
+pub fn list() -> Vec<TaskInfo> {
+    with_task_table(|table| {
+        table
+            .iter()
+            .map(|(&id, entry)| TaskInfo {
+                id,
+                name: entry.name,
+                priority: entry.priority,
+                state: entry.state,
+            })
+            .collect()
+    })
+}
+
+// SPAWNING ====================================
+
+// tasks that were spawned without access to an executor (i.e. from inside another task) wait here until the running executor picks them up
+struct SpawnQueue(VecDeque<Task>);
+
+// futures are not required to be Send, but we only have a single CPU and no threads
+// so tasks never actually move between threads by sitting in this queue
+unsafe impl Send for SpawnQueue {}
+
+static SPAWN_QUEUE: Mutex<SpawnQueue> = Mutex::new(SpawnQueue(VecDeque::new()));
+
+/// Spawn a task on whichever executor is currently running --> use this from inside tasks.
+pub fn spawn(task: Task) -> JoinHandle {
+    spawn_with_priority(task, Priority::Normal)
+}
+
+/// Same as `spawn()` but the task is scheduled with the given priority (a `Priority` or a `TaskPriority`).
+pub fn spawn_with_priority(mut task: Task, priority: impl Into<TaskPriority>) -> JoinHandle {
+    task.priority = priority.into();
+    let id = task.id;
+    register(&task);
+    interrupts::without_interrupts(|| SPAWN_QUEUE.lock().0.push_back(task));
+    JoinHandle { id }
+}
+
+fn take_spawned() -> Option<Task> {
+    interrupts::without_interrupts(|| SPAWN_QUEUE.lock().0.pop_front())
+}
+
+fn spawn_queue_is_empty() -> bool {
+    interrupts::without_interrupts(|| SPAWN_QUEUE.lock().0.is_empty())
+}
+
+// JOIN HANDLES ====================================
+
+/// Awaiting a `JoinHandle` completes once the task it belongs to has finished.
+///
+/// Dropping the handle detaches the task, it keeps running but is forgotten as soon as it finishes.
+#[derive(Debug)]
+pub struct JoinHandle {
+    id: TaskId,
+}
+
+impl JoinHandle {
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    pub fn is_finished(&self) -> bool {
+        with_task_table(|table| match table.get(&self.id) {
+            Some(entry) => entry.state == TaskState::Finished,
+            None => true,
+        })
+    }
+}
+
+impl Future for JoinHandle {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        // checking the state and registering the waker under the same lock means we can't miss the wake up
+        with_task_table(|table| match table.get_mut(&self.id) {
+            Some(entry) if entry.state != TaskState::Finished => {
+                entry.join_waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+            _ => Poll::Ready(()),
+        })
+    }
+}
+
+impl Drop for JoinHandle {
+    fn drop(&mut self) {
+        with_task_table(|table| {
+            if let Some(entry) = table.get_mut(&self.id) {
+                if entry.state == TaskState::Finished {
+                    table.remove(&self.id);
+                } else {
+                    entry.detached = true;
+                    entry.join_waker = None;
+                }
+            }
+        });
+    }
+}
+
+// YIELDING ====================================
+
+/// Give up the CPU once --> the task is put straight back at the end of its ready queue.
+pub fn yield_now() -> YieldNow {
+    YieldNow { yielded: false }
+}
+
+/// Future returned by `yield_now()`.
+pub struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.yielded {
+            Poll::Ready(())
+        } else {
+            self.yielded = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+}
+
+// TESTS ===================================
+
+#[test_case]
+fn test_join_spawned_child() {
+    use alloc::sync::Arc;
+    use core::sync::atomic::AtomicBool;
+    use executor::Executor;
+
+    let side_effect = Arc::new(AtomicBool::new(false));
+    let observed = Arc::new(AtomicBool::new(false));
+
+    let mut executor = Executor::new();
+    let (parent_side_effect, parent_observed) = (side_effect.clone(), observed.clone());
+    let parent = executor.spawn(Task::with_name("parent", async move {
+        let child_side_effect = parent_side_effect.clone();
+        let child = spawn(Task::with_name("child", async move {
+            child_side_effect.store(true, Ordering::SeqCst);
+        }));
+        // the child can't have run yet, the parent hasn't given up the CPU
+        assert!(!parent_side_effect.load(Ordering::SeqCst));
+        child.await;
+        parent_observed.store(parent_side_effect.load(Ordering::SeqCst), Ordering::SeqCst);
+    }));
+    executor.run_until_idle();
+
+    assert!(parent.is_finished());
+    assert!(observed.load(Ordering::SeqCst));
+}
+
+#[test_case]
+fn test_join_finished_task() {
+    use executor::Executor;
+
+    let mut executor = Executor::new();
+    let child = executor.spawn(Task::with_name("finished_child", async {}));
+    executor.run_until_idle();
+    assert!(child.is_finished());
+    let child_id = child.id();
+    assert!(list().iter().any(|info| info.id == child_id && info.state == TaskState::Finished));
+
+    let parent = executor.spawn(Task::new(async move { child.await }));
+    executor.run_until_idle();
+    assert!(parent.is_finished());
+    // both handles are gone or finished --> the child is no longer listed
+    assert!(!list().iter().any(|info| info.id == child_id));
+}
+
+#[test_case]
+fn test_self_wake_leaves_task_ready() {
+    use executor::Executor;
+
+    let mut executor = Executor::new();
+    let task = executor.spawn(Task::new(async { yield_now().await }));
+    // the first poll wakes the task itself before returning Pending
+    assert!(executor.poll_next());
+    let id = task.id();
+    assert!(list().iter().any(|info| info.id == id && info.state == TaskState::Ready));
+    executor.run_until_idle();
+    assert!(task.is_finished());
+}
+
+#[test_case]
+fn test_task_ids_unique_across_interleaved_tasks() {
+    use alloc::{rc::Rc, vec::Vec};
+    use core::cell::RefCell;
+    use executor::Executor;
+
+    // 4 tasks take turns (yielding after every id) to get 1000 ids between them, as concurrent tasks would
+    let ids = Rc::new(RefCell::new(Vec::new()));
+    let mut executor = Executor::new();
+    for _ in 0..4 {
+        let ids = ids.clone();
+        executor.spawn(Task::new(async move {
+            for _ in 0..250 {
+                ids.borrow_mut().push(next_task_id());
+                yield_now().await;
+            }
+        }));
+    }
+    executor.run_until_idle();
+
+    let mut ids = ids.borrow().clone();
+    assert_eq!(ids.len(), 1000);
+    assert!(!ids.contains(&TaskId::IDLE));
+    ids.sort();
+    ids.dedup();
+    assert_eq!(ids.len(), 1000);
+    assert_eq!(alloc::format!("{}", TaskId(42)), "Task#42");
+}
+
+#[test_case]
+fn test_current_task_id() {
+    use alloc::rc::Rc;
+    use core::cell::Cell;
+    use executor::Executor;
+
+    let seen = Rc::new(Cell::new(TaskId::IDLE));
+    let mut executor = Executor::new();
+    let task_seen = seen.clone();
+    let handle = executor.spawn(Task::new(async move { task_seen.set(current_task_id()) }));
+    executor.run_until_idle();
+
+    assert_eq!(seen.get(), handle.id());
+    assert_eq!(current_task_id(), TaskId::IDLE);
+}
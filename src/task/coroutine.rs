@@ -0,0 +1,207 @@
+// Stackful coroutines --> a function running on a stack of its own that can give the CPU back in the middle of it
+// (yield_back()) and go on from there when it is resumed, without being written as a future
+// - resume() and yield_back() are the same switch in both directions: switch_task() pushes the callee-saved registers on
+//   the current stack, stores the stack pointer in one TaskContext and loads the other one --> the registers are popped
+//   off the other stack and `ret` continues where that side switched away
+// - a new coroutine's stack is prepared as if it had switched away right before its first instruction: the first
+//   resume() "returns" into mini_os_coroutine_start, which calls the function with its argument
+// - the function never returns (its stack has nowhere to return to), it ends with finish()
+// - CURRENT is the coroutine running right now --> yield_back() knows which one to leave, a coroutine may resume another
+//   one (the outer one is CURRENT again once the inner one yields), only on the CPU that runs the executor
+// - no guard page under the stack: a coroutine has CORO_STACK_SIZE bytes and mustn't use more
+// - dropping a coroutine that hasn't finished frees its stack without running anything on it --> whatever it owns leaks
+use alloc::{boxed::Box, vec};
+use core::arch::global_asm;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+/// Bytes of stack every coroutine gets.
+pub const CORO_STACK_SIZE: usize = 4096 * 4;
+
+/// Where a stopped context continues: its stack pointer, the callee-saved registers are on that stack (see switch_task()).
+#[derive(Debug, Default)]
+#[repr(C)]
+pub struct TaskContext {
+    rsp: u64,
+}
+
+/// What a coroutine is doing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CoroState {
+    /// Not started yet or yielded, `resume()` runs it.
+    Ready,
+    /// It is running (it resumed another one or asks for its own state).
+    Running,
+    /// It called `finish()`, resuming it does nothing.
+    Done,
+}
+
+/// A function with a stack of its own, see `Coroutine::new()`.
+pub struct Coroutine {
+    stack: Box<[u8; CORO_STACK_SIZE]>,
+    ctx: TaskContext,
+    // where resume() was called from, yield_back() switches to it
+    caller: TaskContext,
+    state: CoroState,
+}
+
+static CURRENT: AtomicPtr<Coroutine> = AtomicPtr::new(ptr::null_mut());
+
+impl Coroutine {
+    /// A coroutine that runs `f(arg)` once it is resumed. `f` ends with `Coroutine::finish()`.
+    ///
+    /// `arg` is handed on as it is, it has to stay valid for as long as `f` uses it.
+    pub fn new(f: fn(*mut ()) -> !, arg: *mut ()) -> Self {
+        // through a Vec: Box::new([0; CORO_STACK_SIZE]) would build the array on the current stack first
+        let stack: Box<[u8; CORO_STACK_SIZE]> = vec![0; CORO_STACK_SIZE].into_boxed_slice().try_into().expect("stack size");
+        let mut coroutine = Coroutine { stack, ctx: TaskContext::default(), caller: TaskContext::default(), state: CoroState::Ready };
+
+        // what switch_task() pops: r15, r14, r13, r12, rbp, rbx, then the address it returns to
+        // the top is 16 byte aligned --> after the `ret` rsp is 16 byte aligned, so the `call` in
+        // mini_os_coroutine_start enters the function with rsp 16 byte aligned + 8, as the System V ABI expects on entry
+        let top = (coroutine.stack.as_mut_ptr() as u64 + CORO_STACK_SIZE as u64) & !0xf;
+        let frame: [u64; 7] = [0, 0, f as usize as u64, arg as u64, 0, 0, mini_os_coroutine_start as usize as u64];
+        let rsp = top - (frame.len() * 8) as u64;
+        unsafe { ptr::copy_nonoverlapping(frame.as_ptr(), rsp as *mut u64, frame.len()) };
+        coroutine.ctx.rsp = rsp;
+        coroutine
+    }
+
+    /// What the coroutine is doing.
+    pub fn state(&self) -> CoroState {
+        self.state
+    }
+
+    /// Run the coroutine until it yields or finishes, returns its state then (`Ready` or `Done`).
+    pub fn resume(&mut self) -> CoroState {
+        if self.state != CoroState::Ready {
+            return self.state;
+        }
+        self.state = CoroState::Running;
+        let outer = CURRENT.swap(self, Ordering::AcqRel);
+        unsafe { mini_os_switch_task(&mut self.caller, &self.ctx) };
+        CURRENT.store(outer, Ordering::Release);
+        self.state
+    }
+
+    /// Give the CPU back to where the running coroutine was resumed, returns once it is resumed again.
+    ///
+    /// Panics outside of a coroutine.
+    pub fn yield_back() {
+        let current = CURRENT.load(Ordering::Acquire);
+        assert!(!current.is_null(), "yield_back() outside of a coroutine");
+        unsafe {
+            (*current).state = CoroState::Ready;
+            mini_os_switch_task(&mut (*current).ctx, &(*current).caller);
+        }
+    }
+
+    /// End the running coroutine, its `resume()` returns `Done`.
+    ///
+    /// Panics outside of a coroutine.
+    pub fn finish() -> ! {
+        let current = CURRENT.load(Ordering::Acquire);
+        assert!(!current.is_null(), "finish() outside of a coroutine");
+        unsafe {
+            (*current).state = CoroState::Done;
+            mini_os_switch_task(&mut (*current).ctx, &(*current).caller);
+        }
+        unreachable!("a finished coroutine was resumed");
+    }
+}
+
+// SWITCHING ====================================
+
+// mini_os_switch_task(old, new)
+//   pushes the callee-saved registers, saves the stack pointer to old->rsp, loads new->rsp, pops the registers saved
+//   there and returns to where `new` switched away
+// mini_os_coroutine_start
+//   where a new coroutine "returns" to the first time: calls r13 (the function) with r12 (its argument)
+global_asm!(
+    ".global mini_os_switch_task",
+    "mini_os_switch_task:",
+    "push rbx",
+    "push rbp",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov [rdi], rsp",
+    "mov rsp, [rsi]",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbp",
+    "pop rbx",
+    "ret",
+    "",
+    ".global mini_os_coroutine_start",
+    "mini_os_coroutine_start:",
+    "mov rdi, r12",
+    "call r13",
+    "ud2", // the function never returns
+);
+
+extern "C" {
+    fn mini_os_switch_task(old: *mut TaskContext, new: *const TaskContext);
+    fn mini_os_coroutine_start();
+}
+
+// TESTS ===================================
+
+#[test_case]
+fn test_coroutine_counts_with_yields() {
+    use alloc::vec::Vec;
+
+    // the coroutine writes its count, the caller reads it between the steps
+    fn count(arg: *mut ()) -> ! {
+        let counter = arg as *mut u64;
+        for i in 0..10 {
+            unsafe { counter.write_volatile(i) };
+            Coroutine::yield_back();
+        }
+        Coroutine::finish();
+    }
+
+    let mut counter: u64 = u64::MAX;
+    let counter_ptr: *mut u64 = &mut counter;
+    let mut coroutine = Coroutine::new(count, counter_ptr as *mut ());
+    assert_eq!(coroutine.state(), CoroState::Ready);
+    let mut seen = Vec::new();
+    while coroutine.resume() == CoroState::Ready {
+        seen.push(unsafe { counter_ptr.read_volatile() });
+    }
+    assert_eq!(seen, (0..10).collect::<Vec<u64>>());
+    assert_eq!(coroutine.state(), CoroState::Done);
+    assert_eq!(coroutine.resume(), CoroState::Done, "a finished coroutine ran again");
+    assert!(CURRENT.load(Ordering::Relaxed).is_null());
+}
+
+#[test_case]
+fn test_nested_coroutines() {
+    // the outer coroutine resumes the inner one until it is done, yielding after every step of it
+    fn inner(arg: *mut ()) -> ! {
+        let steps = arg as *mut u64;
+        for _ in 0..3 {
+            unsafe { *steps += 1 };
+            Coroutine::yield_back();
+        }
+        Coroutine::finish();
+    }
+    fn outer(arg: *mut ()) -> ! {
+        let mut inner = Coroutine::new(inner, arg);
+        while inner.resume() == CoroState::Ready {
+            Coroutine::yield_back();
+        }
+        Coroutine::finish();
+    }
+
+    let mut steps: u64 = 0;
+    let mut outer = Coroutine::new(outer, &mut steps as *mut u64 as *mut ());
+    let mut resumes = 0;
+    while outer.resume() == CoroState::Ready {
+        resumes += 1;
+    }
+    assert_eq!((resumes, steps), (3, 3));
+}
@@ -0,0 +1,387 @@
+use super::{Task, TaskId, TaskState, JoinHandle, Priority, TaskPriority};
+use super::scheduler::{self, priority_class, PriorityScheduler, PRIORITY_CLASSES};
+use super::usage::{self, rdtsc};
+use crate::interrupts::timer_ticks;
+use alloc::{collections::BTreeMap, sync::Arc, task::Wake};
+use core::task::{Context, Poll, Waker};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+pub use super::scheduler::STARVATION_LIMIT;
+
+// the ready queues are shared between the executor and every waker it hands out --> Arc + Mutex
+// wakers can be called from interrupt handlers so every lock has to happen with interrupts disabled (see with_scheduler)
+type ReadyQueues = Arc<Mutex<PriorityScheduler>>;
+
+fn with_scheduler<R>(queues: &ReadyQueues, f: impl FnOnce(&mut PriorityScheduler) -> R) -> R {
+    interrupts::without_interrupts(|| f(&mut queues.lock()))
+}
+
+// make a task ready again, picking up a base priority changed through task::set_task_priority()
+fn wake(queues: &ReadyQueues, id: TaskId) {
+    let base = super::base_priority(id);
+    with_scheduler(queues, |scheduler| {
+        if let Some(base) = base {
+            scheduler.set_priority_if_changed(id, base);
+        }
+        scheduler.enqueue(id);
+    });
+}
+
+/// Counters about what an executor has been doing.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ExecutorStats {
+    scheduled: [u64; PRIORITY_CLASSES], // number of polls per class of base priority
+}
+
+impl ExecutorStats {
+    /// How many times a task with a base priority in the same class as `priority` has been polled.
+    pub fn scheduled(&self, priority: impl Into<TaskPriority>) -> u64 {
+        self.scheduled[priority_class(priority.into())]
+    }
+}
+
+/// Polls tasks whenever they are woken, sleeping the CPU while there is nothing to do.
+pub struct Executor {
+    tasks: BTreeMap<TaskId, Task>,
+    ready: ReadyQueues, // ready task ids by priority, see scheduler.rs
+    waker_cache: BTreeMap<TaskId, Waker>, // reuse the same waker for each poll of a task instead of allocating a new one
+    stats: ExecutorStats,
+}
+
+impl Executor {
+    pub fn new() -> Self {
+        let ready = Arc::new(Mutex::new(PriorityScheduler::new()));
+        scheduler::register_scheduler(&ready); // so sleep_ticks() works for tasks of this executor
+        Executor {
+            tasks: BTreeMap::new(),
+            ready,
+            waker_cache: BTreeMap::new(),
+            stats: ExecutorStats::default(),
+        }
+    }
+
+    /// Add a task with normal priority to this executor, it is polled for the first time on the next run.
+    pub fn spawn(&mut self, task: Task) -> JoinHandle {
+        self.spawn_with_priority(task, Priority::Normal)
+    }
+
+    /// Add a task with the given priority (a `Priority` or a `TaskPriority`) to this executor.
+    pub fn spawn_with_priority(&mut self, mut task: Task, priority: impl Into<TaskPriority>) -> JoinHandle {
+        task.priority = priority.into();
+        let id = task.id;
+        super::register(&task);
+        self.insert(task);
+        JoinHandle { id }
+    }
+
+    pub fn stats(&self) -> ExecutorStats {
+        self.stats
+    }
+
+    fn insert(&mut self, task: Task) {
+        let id = task.id;
+        let priority = task.priority;
+        if self.tasks.insert(id, task).is_some() {
+            panic!("task with same ID already in tasks");
+        }
+        with_scheduler(&self.ready, |scheduler| {
+            scheduler.add(id, priority);
+            scheduler.enqueue(id);
+        });
+    }
+
+    // move the tasks spawned via task::spawn() over to this executor
+    fn spawn_pending(&mut self) {
+        while let Some(task) = super::take_spawned() {
+            self.insert(task);
+        }
+    }
+
+    /// Poll a single ready task, returns `false` if no task was ready.
+    pub fn poll_next(&mut self) -> bool {
+        self.spawn_pending();
+        let task_id = match with_scheduler(&self.ready, |scheduler| {
+            scheduler.wake_sleeping_tasks(timer_ticks());
+            scheduler.schedule()
+        }) {
+            Some(id) => id,
+            None => return false,
+        };
+        let task = match self.tasks.get_mut(&task_id) {
+            Some(task) => task,
+            None => return true, // task no longer exists (a waker fired after it finished)
+        };
+        let ready = &self.ready;
+        let waker = self
+            .waker_cache
+            .entry(task_id)
+            .or_insert_with(|| TaskWaker::new(task_id, ready.clone()));
+        let mut context = Context::from_waker(waker);
+        self.stats.scheduled[priority_class(task.priority)] += 1;
+        let cpu = crate::percpu::this_cpu();
+        if let Some(cpu) = cpu {
+            cpu.set_current_task(task_id);
+        }
+        // Waiting before the poll, not after it --> a wake up during the poll (ex. yield_now() waking itself) sets it
+        // back to Ready, setting it afterwards would overwrite that and lose the wake up
+        super::set_state(task_id, TaskState::Waiting);
+        let poll_start = rdtsc();
+        let poll_result = task.poll(&mut context);
+        usage::record_busy(poll_start);
+        if let Some(cpu) = cpu {
+            cpu.set_current_task(TaskId::IDLE);
+        }
+        match poll_result {
+            Poll::Ready(()) => {
+                // task done -> remove it and its cached waker
+                self.tasks.remove(&task_id);
+                self.waker_cache.remove(&task_id);
+                with_scheduler(&self.ready, |scheduler| scheduler.remove(task_id));
+                super::mark_finished(task_id);
+            }
+            Poll::Pending => {
+                with_scheduler(&self.ready, |scheduler| scheduler.poll_pending(task_id));
+            }
+        }
+        true
+    }
+
+    fn run_ready_tasks(&mut self) {
+        while self.poll_next() {}
+    }
+
+    // also wakes the sleepers that are due --> a tick that came after the last poll_next() can't be slept through
+    fn is_idle(&self) -> bool {
+        let no_ready_tasks = with_scheduler(&self.ready, |scheduler| {
+            scheduler.wake_sleeping_tasks(timer_ticks());
+            scheduler.is_empty()
+        });
+        no_ready_tasks && super::spawn_queue_is_empty()
+    }
+
+    /// Run tasks forever.
+    pub fn run(&mut self) -> ! {
+        loop {
+            self.run_ready_tasks();
+            crate::watchdog::pet(); // idle or not, the tasks returned control
+            self.sleep_if_idle();
+        }
+    }
+
+    /// Run tasks until none of them are ready anymore (tasks that are still waiting stay in the executor).
+    pub fn run_until_idle(&mut self) {
+        self.run_ready_tasks();
+    }
+
+    // halt the CPU until the next interrupt if no task is ready
+    // interrupts are disabled while checking so an interrupt can't wake a task between the check and the `hlt` (we'd sleep with a ready task)
+    // enable_and_hlt() re-enables them and halts as a single atomic operation
+    fn sleep_if_idle(&self) {
+        interrupts::disable();
+        if self.is_idle() {
+            let idle_start = rdtsc();
+            interrupts::enable_and_hlt();
+            usage::record_idle(idle_start);
+        } else {
+            interrupts::enable();
+        }
+    }
+}
+
+impl Default for Executor {
+    fn default() -> Self {
+        Executor::new()
+    }
+}
+
+// WAKERS ====================================
+
+struct TaskWaker {
+    task_id: TaskId,
+    ready: ReadyQueues,
+}
+
+impl TaskWaker {
+    fn new(task_id: TaskId, ready: ReadyQueues) -> Waker {
+        Waker::from(Arc::new(TaskWaker {
+            task_id,
+            ready,
+        }))
+    }
+
+    fn wake_task(&self) {
+        super::set_state(self.task_id, TaskState::Ready);
+        wake(&self.ready, self.task_id);
+    }
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_task();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.wake_task();
+    }
+}
+
+// TESTS ===================================
+
+#[test_case]
+fn test_high_priority_not_stuck_behind_low_flood() {
+    use super::yield_now;
+
+    let mut executor = Executor::new();
+    for _ in 0..100 {
+        executor.spawn_with_priority(Task::new(async {
+            for _ in 0..5 {
+                yield_now().await;
+            }
+        }), Priority::Low);
+    }
+    let high = executor.spawn_with_priority(Task::new(async {
+        for _ in 0..3 {
+            yield_now().await;
+        }
+    }), Priority::High);
+
+    // the high task needs 4 polls, the lows only get one poll in every STARVATION_LIMIT
+    let mut iterations = 0;
+    while !high.is_finished() {
+        assert!(executor.poll_next());
+        iterations += 1;
+        assert!(iterations <= 4 + 4 / STARVATION_LIMIT + 1, "high priority task starved");
+    }
+    assert_eq!(executor.stats().scheduled(Priority::High), 4);
+
+    executor.run_until_idle();
+    assert_eq!(executor.stats().scheduled(Priority::Low), 100 * 6);
+}
+
+#[test_case]
+fn test_low_priority_not_starved() {
+    use super::yield_now;
+
+    let mut executor = Executor::new();
+    let high = executor.spawn_with_priority(Task::new(async {
+        for _ in 0..(STARVATION_LIMIT * 4) {
+            yield_now().await;
+        }
+    }), Priority::High);
+    let low = executor.spawn_with_priority(Task::new(async {}), Priority::Low);
+
+    // the low task finishes long before the busy high priority task does
+    while !low.is_finished() {
+        assert!(executor.poll_next());
+    }
+    assert!(!high.is_finished());
+    executor.run_until_idle();
+    assert!(high.is_finished());
+}
+
+#[test_case]
+fn test_higher_priority_runs_first() {
+    use alloc::{rc::Rc, vec::Vec};
+    use core::cell::RefCell;
+
+    let order = Rc::new(RefCell::new(Vec::new()));
+    let mut executor = Executor::new();
+    // spawned back to back (the low one even first) --> only the priority decides
+    let low_order = order.clone();
+    executor.spawn_with_priority(Task::new(async move { low_order.borrow_mut().push("low") }), 200);
+    let high_order = order.clone();
+    executor.spawn_with_priority(Task::new(async move { high_order.borrow_mut().push("high") }), 10);
+    executor.run_until_idle();
+    assert_eq!(*order.borrow(), ["high", "low"]);
+}
+
+#[test_case]
+fn test_set_task_priority() {
+    use super::{set_task_priority, yield_now};
+    use alloc::{rc::Rc, vec::Vec};
+    use core::cell::RefCell;
+
+    let order = Rc::new(RefCell::new(Vec::new()));
+    let mut executor = Executor::new();
+    let first_order = order.clone();
+    let first = executor.spawn_with_priority(Task::new(async move {
+        yield_now().await;
+        first_order.borrow_mut().push("first");
+    }), 100);
+    let second_order = order.clone();
+    executor.spawn_with_priority(Task::new(async move {
+        yield_now().await;
+        second_order.borrow_mut().push("second");
+    }), 120);
+
+    // both are polled once, then "first" drops below "second" before either is woken again
+    assert!(executor.poll_next());
+    set_task_priority(first.id(), 250);
+    executor.run_until_idle();
+    assert_eq!(*order.borrow(), ["second", "first"]);
+}
+
+#[test_case]
+fn test_sleep_ticks() {
+    use super::sleep_ticks;
+    use alloc::rc::Rc;
+    use core::cell::Cell;
+
+    let woken_at = Rc::new(Cell::new(None));
+    let mut executor = Executor::new();
+    let start = timer_ticks();
+    let task_woken_at = woken_at.clone();
+    let sleeper = executor.spawn(Task::new(async move {
+        sleep_ticks(50).await;
+        task_woken_at.set(Some(timer_ticks()));
+    }));
+
+    let state = || super::list().into_iter().find(|task| task.id == sleeper.id()).map(|task| task.state);
+    // the sleeping task isn't ready --> the executor only halts until the timer wakes it
+    executor.run_until_idle();
+    assert!(woken_at.get().is_none());
+    assert_eq!(state(), Some(TaskState::Waiting));
+    while timer_ticks() < start + 50 {
+        executor.sleep_if_idle();
+    }
+    // its tick has passed --> out of the sleep queue and Ready before it is polled again
+    assert!(!executor.is_idle());
+    assert_eq!(state(), Some(TaskState::Ready));
+    while !sleeper.is_finished() {
+        executor.run_until_idle();
+        executor.sleep_if_idle();
+    }
+    let woken_at = woken_at.get().expect("task didn't finish");
+    assert!(woken_at >= start + 50);
+    // a slow or busy host can delay ticks, only a task that slept twice as long is surely broken
+    assert!(woken_at <= start + 100, "task woke up late");
+}
+
+#[test_case]
+fn test_cpu_usage_follows_busy_task() {
+    use usage::CpuSample;
+
+    let mut executor = Executor::new();
+    let start = CpuSample::now();
+    executor.spawn(Task::with_name("busy", async {
+        let spin_start = rdtsc();
+        while rdtsc().wrapping_sub(spin_start) < 10_000_000 {
+            core::hint::spin_loop();
+        }
+    }));
+    executor.run_until_idle();
+
+    // nothing halted while the busy task ran
+    let after_busy = CpuSample::now();
+    let (busy_permille, _) = start.usage_until(&after_busy);
+    assert!(busy_permille > 900);
+
+    // with the task done the executor just halts until the next timer interrupts
+    for _ in 0..3 {
+        executor.sleep_if_idle();
+    }
+    let (idle_busy_permille, idle_permille) = after_busy.usage_until(&CpuSample::now());
+    assert!(idle_busy_permille < busy_permille);
+    assert!(idle_permille > 500);
+}
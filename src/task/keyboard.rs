@@ -0,0 +1,191 @@
+// Keyboard input for tasks --> the keyboard interrupt hands every decoded key to a message queue (see ipc.rs)
+// - only one task reads the keyboard at a time: it opens the stream with key_stream() and gets the keys in order
+// - characters and the special keys without a handler of their own (arrows, Home/End...) go to the stream
+// - while no stream is open the interrupt handler echoes what is typed instead, like it always did
+// - the handler can't wait --> keys pressed while the queue is full are dropped (and counted)
+// - tests type with inject_scancode()/inject_str() (see INJECTION), the bytes take the interrupt's path from the decoder on
+use crate::ipc::MessageQueue;
+use pc_keyboard::DecodedKey;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::instructions::interrupts;
+
+/// Keys that can be typed ahead before the reading task gets to them.
+pub const KEY_QUEUE_SIZE: usize = 64;
+
+static KEYS: MessageQueue<DecodedKey, KEY_QUEUE_SIZE> = MessageQueue::new();
+static STREAM_OPEN: AtomicBool = AtomicBool::new(false);
+static DROPPED_KEYS: AtomicU64 = AtomicU64::new(0);
+
+/// Called by the keyboard interrupt for every key press, returns false if no task reads the keyboard.
+///
+/// Never waits or allocates.
+pub(crate) fn push_key(key: DecodedKey) -> bool {
+    if !STREAM_OPEN.load(Ordering::Acquire) {
+        return false;
+    }
+    if KEYS.try_send(key).is_err() {
+        DROPPED_KEYS.fetch_add(1, Ordering::Relaxed);
+    }
+    true
+}
+
+/// Number of keys dropped b/c the queue was full.
+pub fn dropped_keys() -> u64 {
+    DROPPED_KEYS.load(Ordering::Relaxed)
+}
+
+/// The keys pressed on the keyboard, see `key_stream()`. The handler echoes again once it is dropped.
+#[derive(Debug)]
+pub struct KeyStream {
+    _private: (),
+}
+
+/// Start reading the keyboard, `None` if another task already does.
+pub fn key_stream() -> Option<KeyStream> {
+    STREAM_OPEN
+        .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+        .ok()
+        .map(|_| KeyStream { _private: () })
+}
+
+impl KeyStream {
+    /// The next key, waits until there is one.
+    pub async fn next_key(&mut self) -> DecodedKey {
+        KEYS.recv().await
+    }
+
+    /// The next key if there is one already.
+    pub fn try_next_key(&mut self) -> Option<DecodedKey> {
+        KEYS.try_recv()
+    }
+}
+
+impl Drop for KeyStream {
+    fn drop(&mut self) {
+        STREAM_OPEN.store(false, Ordering::Release);
+        // whatever was typed for this reader isn't meant for the next one
+        while KEYS.try_recv().is_some() {}
+    }
+}
+
+// INJECTION ====================================
+// synthetic key presses for tests that drive the shell or a line editor without anyone at the keyboard
+// - a scancode goes through the interrupt's own decoder and push_key() (interrupts::handle_scancode()) with interrupts
+//   off --> a real key press can only come before or after it, never in the middle, and both end up in KEYS in order
+// - inject_str() types set 1 scancodes (what the decoder expects unless set_scancode_set() switched it) on a US layout,
+//   with left shift held for uppercase letters and the shifted symbols
+// - like real typing: nobody reading the keyboard = the keys are echoed, more than KEY_QUEUE_SIZE unread = dropped
+
+// left shift's press in set 1, its release (like every release) has bit 7 set
+const SET1_LEFT_SHIFT: u8 = 0x2A;
+const SET1_RELEASE: u8 = 0x80;
+
+/// Hand `scancode` to the keyboard's decoder as if the keyboard had sent it.
+pub fn inject_scancode(scancode: u8) {
+    interrupts::without_interrupts(|| crate::interrupts::handle_scancode(scancode));
+}
+
+/// Type `text` as set 1 press/release pairs, see INJECTION. Panics on a character that has no key on a US keyboard or
+/// if the decoder isn't on set 1.
+pub fn inject_str(text: &str) {
+    assert_eq!(crate::interrupts::scancode_set(), 1, "inject_str() types set 1 scancodes");
+    for character in text.chars() {
+        let (scancode, shift) = set1_key(character).unwrap_or_else(|| panic!("no key for {:?}", character));
+        if shift {
+            inject_scancode(SET1_LEFT_SHIFT);
+        }
+        inject_scancode(scancode);
+        inject_scancode(scancode | SET1_RELEASE);
+        if shift {
+            inject_scancode(SET1_LEFT_SHIFT | SET1_RELEASE);
+        }
+    }
+}
+
+// the set 1 scancode of the key that types `character` and whether shift has to be held for it
+fn set1_key(character: char) -> Option<(u8, bool)> {
+    const ROWS: [(&[u8], &[u8], u8); 4] = [
+        // unshifted, shifted, scancode of the first key
+        (b"1234567890-=", b"!@#$%^&*()_+", 0x02),
+        (b"qwertyuiop[]", b"QWERTYUIOP{}", 0x10),
+        (b"asdfghjkl;'`", b"ASDFGHJKL:\"~", 0x1E),
+        (b"zxcvbnm,./", b"ZXCVBNM<>?", 0x2C),
+    ];
+    match character {
+        '\x08' => return Some((0x0E, false)), // backspace
+        '\t' => return Some((0x0F, false)),
+        '\n' => return Some((0x1C, false)), // enter
+        ' ' => return Some((0x39, false)),
+        '\\' => return Some((0x2B, false)),
+        '|' => return Some((0x2B, true)),
+        _ => {}
+    }
+    let byte = u8::try_from(character).ok()?;
+    ROWS.iter().find_map(|&(unshifted, shifted, first)| {
+        let (position, shift) = match unshifted.iter().position(|&b| b == byte) {
+            Some(position) => (position, false),
+            None => (shifted.iter().position(|&b| b == byte)?, true),
+        };
+        Some((first + position as u8, shift))
+    })
+}
+
+// TESTS ===================================
+
+#[test_case]
+fn test_key_stream() {
+    use super::{executor::Executor, Task};
+    use alloc::rc::Rc;
+    use core::cell::RefCell;
+
+    assert!(!push_key(DecodedKey::Unicode('x')), "key taken without a reader");
+    let mut stream = key_stream().expect("stream already open");
+    assert!(key_stream().is_none(), "two readers");
+
+    let received = Rc::new(RefCell::new(alloc::string::String::new()));
+    let mut executor = Executor::new();
+    let task_received = received.clone();
+    executor.spawn(Task::new(async move {
+        for _ in 0..3 {
+            if let DecodedKey::Unicode(character) = stream.next_key().await {
+                task_received.borrow_mut().push(character);
+            }
+        }
+    }));
+    executor.run_until_idle(); // waits for the first key
+    for key in ['a', 'b', 'c'] {
+        assert!(push_key(DecodedKey::Unicode(key)));
+    }
+    executor.run_until_idle();
+    assert_eq!(*received.borrow(), "abc");
+
+    // the task finished and dropped the stream --> the next reader can open it
+    let stream = key_stream().expect("stream still open");
+    drop(stream);
+}
+
+#[test_case]
+fn test_set1_keys() {
+    assert_eq!(set1_key('a'), Some((0x1E, false)));
+    assert_eq!(set1_key('A'), Some((0x1E, true)));
+    assert_eq!(set1_key('0'), Some((0x0B, false)));
+    assert_eq!(set1_key(')'), Some((0x0B, true)));
+    assert_eq!(set1_key('"'), Some((0x28, true)));
+    assert_eq!(set1_key('~'), Some((0x29, true)));
+    assert_eq!(set1_key('/'), Some((0x35, false)));
+    assert_eq!(set1_key('\n'), Some((0x1C, false)));
+    assert_eq!(set1_key('é'), None);
+}
+
+#[test_case]
+fn test_injected_keys_reach_the_stream() {
+    let mut stream = key_stream().expect("stream already open");
+    inject_str("Hi, {x}!\n");
+    let mut typed = alloc::string::String::new();
+    while let Some(key) = stream.try_next_key() {
+        if let DecodedKey::Unicode(character) = key {
+            typed.push(character);
+        }
+    }
+    assert_eq!(typed, "Hi, {x}!\n");
+}
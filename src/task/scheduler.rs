@@ -0,0 +1,310 @@
+// Priority scheduling --> decides which ready task an executor polls next
+// every task has a priority from 0 (highest) to 255 (lowest); the 256 values are grouped into 8 classes of 32,
+// each class has its own FIFO ready queue and the highest non-empty class always goes first
+//
+// a task's *current* priority moves around its base priority so busy tasks can't hog the CPU:
+// - using up a timeslice (being ready again right after a poll, ex. yield_now()) lowers it by 1
+// - waking up after actually waiting on something raises it by 1 again (never above the base priority)
+// that alone takes a long time to let a low class run behind a busy high class, so as a backstop a lower class is
+// picked after STARVATION_LIMIT picks in a row that skipped it
+//
+// the queues hold task ids rather than tasks: the executor owns the tasks, and wakers (which may run in interrupt
+// handlers) only ever have the id of the task to put back in line
+//
+// sleeping: sleep_ticks() parks the current task in the sleep queue of its executor's scheduler (sorted by wake up tick),
+// the executor moves the tasks whose tick has come back into the ready queues before it picks a task and before it
+// halts (see wake_sleeping_tasks()) --> the timer interrupt only has to end the executor's `hlt`, it never touches a
+// scheduler itself (which could free the last reference to one, or grow a queue, inside the interrupt handler)
+use super::TaskId;
+use crate::interrupts::timer_ticks;
+use alloc::{collections::{BTreeMap, VecDeque}, sync::{Arc, Weak}, vec::Vec};
+use core::future::poll_fn;
+use core::task::Poll;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// Scheduling priority of a task, 0 is the highest and 255 the lowest.
+pub type TaskPriority = u8;
+
+/// Number of priority classes (= ready queues).
+pub const PRIORITY_CLASSES: usize = 8;
+
+/// After this many picks in a row of a higher class while a lower class has ready tasks,
+/// one lower class task gets picked so a busy high priority task can't starve everything else.
+pub const STARVATION_LIMIT: usize = 8;
+
+/// The class (ready queue) a priority belongs to.
+pub fn priority_class(priority: TaskPriority) -> usize {
+    usize::from(priority) / (256 / PRIORITY_CLASSES)
+}
+
+struct Entry {
+    base: TaskPriority,
+    current: TaskPriority,
+    queued: bool, // in a ready queue --> waking it again does nothing
+    waiting: bool, // returned Poll::Pending without being woken --> gets a boost when it is woken
+}
+
+/// Ready queues ordered by priority class.
+pub struct PriorityScheduler {
+    queues: [VecDeque<TaskId>; PRIORITY_CLASSES],
+    entries: BTreeMap<TaskId, Entry>,
+    consecutive_picks: usize, // picks in a row that skipped over a ready lower class
+    sleep_queue: Vec<(TaskId, u64)>, // sleeping tasks and the tick they wake up at, soonest first
+}
+
+impl PriorityScheduler {
+    pub fn new() -> Self {
+        PriorityScheduler {
+            queues: Default::default(),
+            entries: BTreeMap::new(),
+            consecutive_picks: 0,
+            sleep_queue: Vec::new(),
+        }
+    }
+
+    /// Start scheduling task `id` with the given base priority (not yet ready, see `enqueue()`).
+    pub fn add(&mut self, id: TaskId, priority: TaskPriority) {
+        let entry = Entry { base: priority, current: priority, queued: false, waiting: false };
+        self.entries.insert(id, entry);
+        // enqueue() runs from wakers called in interrupt handlers, which must not allocate --> make sure every queue
+        // already has room for all tasks, a task is in at most one queue at a time
+        for queue in &mut self.queues {
+            queue.reserve(self.entries.len());
+        }
+    }
+
+    /// Forget about task `id` (it finished), a stale id left in a queue is skipped by `schedule()`.
+    pub fn remove(&mut self, id: TaskId) {
+        self.entries.remove(&id);
+        self.sleep_queue.retain(|&(sleeper, _)| sleeper != id);
+    }
+
+    /// Change the base priority of task `id`, its current priority starts over from the new base.
+    pub fn set_priority(&mut self, id: TaskId, priority: TaskPriority) {
+        if let Some(entry) = self.entries.get_mut(&id) {
+            entry.base = priority;
+            entry.current = priority;
+        }
+    }
+
+    /// Like `set_priority()`, but keeps the current (aged) priority if the base priority is unchanged.
+    pub fn set_priority_if_changed(&mut self, id: TaskId, priority: TaskPriority) {
+        if self.entries.get(&id).is_some_and(|entry| entry.base != priority) {
+            self.set_priority(id, priority);
+        }
+    }
+
+    /// The current (aged) priority of task `id`.
+    pub fn priority(&self, id: TaskId) -> Option<TaskPriority> {
+        self.entries.get(&id).map(|entry| entry.current)
+    }
+
+    /// Mark task `id` ready --> put it at the back of the queue of its current priority.
+    pub fn enqueue(&mut self, id: TaskId) {
+        let entry = match self.entries.get_mut(&id) {
+            Some(entry) if !entry.queued => entry,
+            _ => return, // already queued, or finished
+        };
+        if entry.waiting {
+            entry.waiting = false;
+            entry.current = entry.current.saturating_sub(1).max(entry.base);
+        }
+        entry.queued = true;
+        self.queues[priority_class(entry.current)].push_back(id);
+    }
+
+    /// Called after polling task `id` returned `Poll::Pending`.
+    ///
+    /// If the task woke itself during the poll it is still ready and has used up its timeslice --> it ages down,
+    /// otherwise it is waiting and gets a boost once it is woken.
+    pub fn poll_pending(&mut self, id: TaskId) {
+        if let Some(entry) = self.entries.get_mut(&id) {
+            if entry.queued {
+                entry.current = entry.current.saturating_add(1);
+            } else {
+                entry.waiting = true;
+            }
+        }
+    }
+
+    /// Take the next task to poll out of its ready queue.
+    pub fn schedule(&mut self) -> Option<TaskId> {
+        loop {
+            let class = self.next_class()?;
+            let id = self.queues[class].pop_front()?;
+            if let Some(entry) = self.entries.get_mut(&id) {
+                entry.queued = false;
+                return Some(id);
+            }
+        }
+    }
+
+    // the highest class with a ready task, unless it has been picked STARVATION_LIMIT times in a row while a lower class was waiting
+    fn next_class(&mut self) -> Option<usize> {
+        let mut ready = (0..PRIORITY_CLASSES).filter(|&class| !self.queues[class].is_empty());
+        let highest = ready.next()?;
+        match ready.next() {
+            Some(lower) if self.consecutive_picks >= STARVATION_LIMIT => {
+                self.consecutive_picks = 0;
+                Some(lower)
+            }
+            Some(_) => {
+                self.consecutive_picks += 1;
+                Some(highest)
+            }
+            None => {
+                self.consecutive_picks = 0;
+                Some(highest)
+            }
+        }
+    }
+
+    /// Take task `id` out of the ready queues until `wake_sleeping_tasks()` is called with a tick of at least `until_ticks`.
+    pub fn sleep_task(&mut self, id: TaskId, until_ticks: u64) {
+        let entry = match self.entries.get_mut(&id) {
+            Some(entry) => entry,
+            None => return,
+        };
+        if entry.queued {
+            entry.queued = false;
+            self.queues[priority_class(entry.current)].retain(|&queued| queued != id);
+        }
+        self.sleep_queue.retain(|&(sleeper, _)| sleeper != id);
+        // after everyone waking up at the same tick --> same tick wakes up in the order the tasks went to sleep
+        let index = self.sleep_queue.partition_point(|&(_, until)| until <= until_ticks);
+        self.sleep_queue.insert(index, (id, until_ticks));
+    }
+
+    /// Make every task sleeping until `current_ticks` or earlier ready again.
+    pub fn wake_sleeping_tasks(&mut self, current_ticks: u64) {
+        let due = self.sleep_queue.partition_point(|&(_, until)| until <= current_ticks);
+        for i in 0..due {
+            let (id, _) = self.sleep_queue[i];
+            // like a waker would (the executor set it to Waiting before the poll that went to sleep)
+            super::set_state(id, super::TaskState::Ready);
+            self.enqueue(id);
+        }
+        self.sleep_queue.drain(..due);
+    }
+
+    /// Whether task `id` is in the sleep queue.
+    pub fn is_sleeping(&self, id: TaskId) -> bool {
+        self.sleep_queue.iter().any(|&(sleeper, _)| sleeper == id)
+    }
+
+    /// Whether no task is ready.
+    pub fn is_empty(&self) -> bool {
+        self.queues.iter().all(VecDeque::is_empty)
+    }
+}
+
+impl Default for PriorityScheduler {
+    fn default() -> Self {
+        PriorityScheduler::new()
+    }
+}
+
+// SLEEPING =============================
+
+// the schedulers of all executors, so sleep_ticks() can find the one running the current task
+// like the schedulers themselves only ever locked with interrupts disabled, always before a scheduler's lock
+static SCHEDULERS: Mutex<Vec<Weak<Mutex<PriorityScheduler>>>> = Mutex::new(Vec::new());
+
+/// Let the tasks of `scheduler` sleep with `sleep_ticks()` (executors call this for their scheduler).
+pub fn register_scheduler(scheduler: &Arc<Mutex<PriorityScheduler>>) {
+    interrupts::without_interrupts(|| {
+        let mut schedulers = SCHEDULERS.lock();
+        schedulers.retain(|scheduler| scheduler.strong_count() > 0); // executors that are gone
+        schedulers.push(Arc::downgrade(scheduler));
+    });
+}
+
+// put task `id` to sleep on whichever scheduler runs it, returns false if none does
+fn sleep_task(id: TaskId, until_ticks: u64) -> bool {
+    interrupts::without_interrupts(|| {
+        let schedulers = SCHEDULERS.lock();
+        for scheduler in schedulers.iter().filter_map(Weak::upgrade) {
+            let mut scheduler = scheduler.lock();
+            if scheduler.entries.contains_key(&id) {
+                scheduler.sleep_task(id, until_ticks);
+                return true;
+            }
+        }
+        false
+    })
+}
+
+/// Sleep for (at least) `n` timer ticks, the task isn't polled at all in the meantime.
+pub async fn sleep_ticks(n: u64) {
+    let until = timer_ticks().saturating_add(n);
+    poll_fn(|cx| {
+        if timer_ticks() >= until {
+            return Poll::Ready(());
+        }
+        // not polled by an executor (ex. in a test) --> fall back to checking again on the next poll
+        if !sleep_task(super::current_task_id(), until) {
+            cx.waker().wake_by_ref();
+        }
+        Poll::Pending
+    })
+    .await
+}
+
+// TESTS ===================================
+
+#[test_case]
+fn test_scheduler_aging() {
+    let mut scheduler = PriorityScheduler::new();
+    let id = super::next_task_id();
+    scheduler.add(id, 100);
+
+    // a task that keeps using up its timeslice sinks...
+    for expected in 101..=103 {
+        scheduler.enqueue(id);
+        assert_eq!(scheduler.schedule(), Some(id));
+        scheduler.enqueue(id); // woke itself during the poll
+        scheduler.poll_pending(id);
+        assert_eq!(scheduler.priority(id), Some(expected));
+        assert_eq!(scheduler.schedule(), Some(id));
+    }
+    // ...and rises again (up to its base) each time it had to wait
+    for expected in [102, 101, 100, 100] {
+        scheduler.poll_pending(id);
+        scheduler.enqueue(id);
+        assert_eq!(scheduler.priority(id), Some(expected));
+        assert_eq!(scheduler.schedule(), Some(id));
+    }
+    assert!(scheduler.is_empty());
+}
+
+#[test_case]
+fn test_scheduler_sleep_queue() {
+    let mut scheduler = PriorityScheduler::new();
+    let (early, late) = (super::next_task_id(), super::next_task_id());
+    scheduler.add(early, 100);
+    scheduler.add(late, 100);
+    scheduler.enqueue(early);
+    scheduler.enqueue(late);
+
+    // sleeping takes a ready task out of its queue
+    scheduler.sleep_task(late, 20);
+    scheduler.sleep_task(early, 10);
+    assert!(scheduler.is_empty());
+    assert!(scheduler.is_sleeping(early) && scheduler.is_sleeping(late));
+
+    scheduler.wake_sleeping_tasks(9);
+    assert!(scheduler.is_empty());
+    scheduler.wake_sleeping_tasks(15);
+    assert_eq!(scheduler.schedule(), Some(early));
+    assert!(scheduler.is_sleeping(late));
+    scheduler.wake_sleeping_tasks(20);
+    assert_eq!(scheduler.schedule(), Some(late));
+    assert!(!scheduler.is_sleeping(late));
+
+    // a finished task never wakes up
+    scheduler.sleep_task(early, 30);
+    scheduler.remove(early);
+    scheduler.wake_sleeping_tasks(30);
+    assert!(scheduler.is_empty());
+}
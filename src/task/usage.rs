@@ -0,0 +1,95 @@
+// CPU usage accounting --> the executor measures (with the TSC) how many cycles it spends polling tasks ("busy")
+// and how many it spends halted waiting for an interrupt ("idle"), see executor.rs
+// the counters only ever grow (and wrap around), usage over a period is the difference between two samples of them
+// once a second the timer interrupt closes the current window (see interrupts.rs), cpu_usage() reports the last closed window
+use core::arch::x86_64::_rdtsc;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+static BUSY_CYCLES: AtomicU64 = AtomicU64::new(0);
+static IDLE_CYCLES: AtomicU64 = AtomicU64::new(0);
+
+/// Read the CPU's time stamp counter (cycles since reset).
+pub fn rdtsc() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+pub(crate) fn record_busy(start_tsc: u64) {
+    BUSY_CYCLES.fetch_add(rdtsc().wrapping_sub(start_tsc), Ordering::Relaxed);
+}
+
+pub(crate) fn record_idle(start_tsc: u64) {
+    IDLE_CYCLES.fetch_add(rdtsc().wrapping_sub(start_tsc), Ordering::Relaxed);
+}
+
+/// A snapshot of the busy/idle cycle counters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuSample {
+    pub busy_cycles: u64,
+    pub idle_cycles: u64,
+}
+
+impl CpuSample {
+    pub fn now() -> CpuSample {
+        CpuSample {
+            busy_cycles: BUSY_CYCLES.load(Ordering::Relaxed),
+            idle_cycles: IDLE_CYCLES.load(Ordering::Relaxed),
+        }
+    }
+
+    /// The `(busy_permille, idle_permille)` split between this sample and a later one.
+    pub fn usage_until(&self, later: &CpuSample) -> (u32, u32) {
+        // wrapping_sub gives the right difference even if a counter wrapped around in between
+        let busy = later.busy_cycles.wrapping_sub(self.busy_cycles);
+        let idle = later.idle_cycles.wrapping_sub(self.idle_cycles);
+        permille(busy, idle)
+    }
+}
+
+// integer only --> no floats in the kernel, and u128 so `busy * 1000` can't overflow
+fn permille(busy: u64, idle: u64) -> (u32, u32) {
+    let total = busy as u128 + idle as u128;
+    if total == 0 {
+        return (0, 1000); // nothing measured counts as idle
+    }
+    let busy_permille = (busy as u128 * 1000 / total) as u32;
+    (busy_permille, 1000 - busy_permille)
+}
+
+// WINDOW ====================================
+
+static WINDOW_BUSY_START: AtomicU64 = AtomicU64::new(0);
+static WINDOW_IDLE_START: AtomicU64 = AtomicU64::new(0);
+static LAST_BUSY_PERMILLE: AtomicU32 = AtomicU32::new(0);
+
+/// Close the current usage window and start a new one, called once a second by the timer interrupt.
+pub fn roll_window() {
+    let start = CpuSample {
+        busy_cycles: WINDOW_BUSY_START.load(Ordering::Relaxed),
+        idle_cycles: WINDOW_IDLE_START.load(Ordering::Relaxed),
+    };
+    let now = CpuSample::now();
+    let (busy_permille, _) = start.usage_until(&now);
+    LAST_BUSY_PERMILLE.store(busy_permille, Ordering::Relaxed);
+    WINDOW_BUSY_START.store(now.busy_cycles, Ordering::Relaxed);
+    WINDOW_IDLE_START.store(now.idle_cycles, Ordering::Relaxed);
+}
+
+/// The `(busy_permille, idle_permille)` split of the last full second.
+pub fn cpu_usage() -> (u32, u32) {
+    let busy_permille = LAST_BUSY_PERMILLE.load(Ordering::Relaxed);
+    (busy_permille, 1000 - busy_permille)
+}
+
+// TESTS ===================================
+
+#[test_case]
+fn test_permille_arithmetic() {
+    assert_eq!(permille(0, 0), (0, 1000));
+    assert_eq!(permille(1, 3), (250, 750));
+    assert_eq!(permille(u64::MAX, u64::MAX), (500, 500));
+
+    // counters that wrapped between the samples
+    let before = CpuSample { busy_cycles: u64::MAX - 9, idle_cycles: 100 };
+    let after = CpuSample { busy_cycles: 10, idle_cycles: 120 };
+    assert_eq!(before.usage_until(&after), (500, 500));
+}
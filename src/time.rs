@@ -0,0 +1,198 @@
+// Uptime and wall clock time for people --> the `uptime` and `date` shell commands and the status bar format them the same way
+// - the uptime comes from the timer tick count and the configured timer frequency (see config.rs), the date from the
+//   RTC (see drivers/cmos.rs), which is only read: setting it would mean writing the CMOS, which nothing does yet
+// - integer math only, fractions of a second are whole hundredths
+// - nothing here allocates --> the status bar formats the uptime from the timer interrupt
+// - finer than a timer tick: the TSC, once calibrate_tsc() found its rate (see TSC)
+use crate::drivers::cmos::{self, RtcTime};
+use crate::kshell::ShellError;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Time since boot as a tick count, printed as "03:25:07" (and "2d 03:25:07" from the first day on).
+///
+/// The alternate form (`{:#}`) adds hundredths of a second: "03:25:07.42".
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Uptime {
+    pub ticks: u64,
+    /// Timer interrupts per second.
+    pub hz: u64,
+}
+
+impl Uptime {
+    /// The uptime right now.
+    pub fn now() -> Self {
+        Uptime { ticks: crate::interrupts::timer_ticks(), hz: u64::from(crate::config::get().timer_hz) }
+    }
+
+    /// Whole seconds since boot.
+    pub fn seconds(&self) -> u64 {
+        self.ticks / self.hz.max(1)
+    }
+}
+
+impl fmt::Display for Uptime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let seconds = self.seconds();
+        let (days, rest) = (seconds / SECONDS_PER_DAY, seconds % SECONDS_PER_DAY);
+        if days > 0 {
+            write!(f, "{}d ", days)?;
+        }
+        write!(f, "{:02}:{:02}:{:02}", rest / 3600, rest / 60 % 60, rest % 60)?;
+        if f.alternate() {
+            let hz = self.hz.max(1);
+            write!(f, ".{:02}", self.ticks % hz * 100 / hz)?;
+        }
+        Ok(())
+    }
+}
+
+/// Write the current uptime to `out` (see `Uptime`).
+pub fn format_uptime(out: &mut impl fmt::Write) -> fmt::Result {
+    write!(out, "{}", Uptime::now())
+}
+
+/// Write the current date and time from the RTC to `out`, "2024-02-29 23:59:59".
+pub fn format_date(out: &mut impl fmt::Write) -> fmt::Result {
+    write!(out, "{}", cmos::read_rtc_time())
+}
+
+// TSC ================================
+// the TSC counts at a fixed rate (invariant TSC on anything recent, and in QEMU) but nothing tells us which one
+// --> calibrate_tsc() counts cycles while PIT channel 2 (the PC speaker's, its output can be polled on port 0x61 without
+// any interrupt) counts down CALIBRATION_PIT_COUNT of its 1.193182 MHz ticks, ~10 ms
+// - works with interrupts off and before init(), the timer interrupt (channel 0) isn't touched
+// - 0 in TSC_HZ = not calibrated (yet, or channel 2 never finished, ex. no PIT)
+
+const CALIBRATION_PIT_COUNT: u16 = 11_932;
+// port 0x61 reads of the channel 2 output before giving up, each read takes ~1 µs
+const CALIBRATION_MAX_POLLS: usize = 1_000_000;
+
+static TSC_HZ: AtomicU64 = AtomicU64::new(0);
+
+/// Measure the TSC's rate against the PIT, returns it in Hz (`None` if the PIT didn't count).
+///
+/// Takes ~10 ms, later `tsc_hz()` has the result.
+pub fn calibrate_tsc() -> Option<u64> {
+    use crate::drivers::port::{HardwarePorts, PortIo};
+    use crate::task::usage::rdtsc;
+
+    let mut ports = HardwarePorts;
+    let hz = x86_64::instructions::interrupts::without_interrupts(|| {
+        let speaker = ports.read_u8(0x61);
+        ports.write_u8(0x61, (speaker & !0x02) | 0x01); // channel 2 gate on, speaker off
+        // 0xb0 --> channel 2, write low byte then high byte, mode 0 (output goes high at the end of the count), binary
+        ports.write_u8(0x43, 0xb0);
+        ports.write_u8(0x42, (CALIBRATION_PIT_COUNT & 0xff) as u8);
+        ports.write_u8(0x42, (CALIBRATION_PIT_COUNT >> 8) as u8);
+        let start = rdtsc();
+        let done = (0..CALIBRATION_MAX_POLLS).any(|_| ports.read_u8(0x61) & 0x20 != 0);
+        let cycles = rdtsc().wrapping_sub(start);
+        ports.write_u8(0x61, speaker);
+        done.then(|| cycles * u64::from(crate::interrupts::PIT_BASE_FREQUENCY) / u64::from(CALIBRATION_PIT_COUNT))
+    });
+    TSC_HZ.store(hz.unwrap_or(0), Ordering::Relaxed);
+    hz
+}
+
+/// The TSC's rate from `calibrate_tsc()`, `None` before it ran.
+pub fn tsc_hz() -> Option<u64> {
+    match TSC_HZ.load(Ordering::Relaxed) {
+        0 => None,
+        hz => Some(hz),
+    }
+}
+
+/// `cycles` TSC cycles in milliseconds, `None` without a calibrated TSC.
+pub fn tsc_cycles_to_ms(cycles: u64) -> Option<u64> {
+    tsc_hz().map(|hz| (u128::from(cycles) * 1000 / u128::from(hz)) as u64)
+}
+
+/// `cycles` TSC cycles in nanoseconds, `None` without a calibrated TSC.
+pub fn tsc_cycles_to_ns(cycles: u64) -> Option<u64> {
+    tsc_hz().map(|hz| (u128::from(cycles) * 1_000_000_000 / u128::from(hz)) as u64)
+}
+
+// SHELL COMMANDS ================================
+
+/// Add `uptime` and `date` to the kernel shell (see kshell.rs), once the heap is up.
+pub fn init_shell_commands() {
+    use crate::kshell::register_command;
+
+    register_command("uptime", "time since boot and interrupts serviced", uptime_command).expect("uptime registered twice");
+    register_command("date", "the date and time from the RTC", date_command).expect("date registered twice");
+}
+
+fn uptime_command(args: &[&str]) -> Result<(), ShellError> {
+    if !args.is_empty() {
+        return Err(ShellError::Usage("uptime"));
+    }
+    let uptime = Uptime::now();
+    let stats = crate::interrupts::stats();
+    let interrupts = stats.timer_ticks + stats.keyboard + stats.breakpoints + stats.device_irqs.iter().sum::<u64>();
+    crate::println!("up {:#} ({} ticks at {} Hz), {} interrupts serviced", uptime, uptime.ticks, uptime.hz, interrupts);
+    Ok(())
+}
+
+fn date_command(args: &[&str]) -> Result<(), ShellError> {
+    match args {
+        [] => {
+            crate::println!("{}", cmos::read_rtc_time());
+            Ok(())
+        }
+        ["--set", ..] => {
+            crate::println!("date: sorry, the clock can't be set yet (the RTC is only read, never written)");
+            Ok(())
+        }
+        _ => Err(ShellError::Usage("date")),
+    }
+}
+
+// TESTS ===================================
+
+#[test_case]
+fn test_uptime_format() {
+    use alloc::format;
+
+    let at_100_hz = |ticks| Uptime { ticks, hz: 100 };
+    assert_eq!(format!("{}", at_100_hz(0)), "00:00:00");
+    assert_eq!(format!("{:#}", at_100_hz(12_345)), "00:02:03.45");
+    assert_eq!(format!("{}", at_100_hz((SECONDS_PER_DAY - 1) * 100 + 99)), "23:59:59");
+    // the day rolls over into the day count, the hours start again
+    assert_eq!(format!("{:#}", at_100_hz(SECONDS_PER_DAY * 100)), "1d 00:00:00.00");
+    assert_eq!(format!("{}", at_100_hz((3 * SECONDS_PER_DAY + 4 * 3600 + 5 * 60 + 6) * 100)), "3d 04:05:06");
+    assert_eq!(format!("{}", Uptime { ticks: u64::MAX, hz: 1 }), "213503982334601d 07:00:15");
+    // the PIT's power-on rate doesn't divide evenly
+    assert_eq!(format!("{:#}", Uptime { ticks: 27, hz: 18 }), "00:00:01.50");
+    assert_eq!(format!("{}", Uptime { ticks: 5, hz: 0 }), "00:00:05", "no division by zero");
+}
+
+#[test_case]
+fn test_date_format() {
+    use alloc::format;
+
+    let time = RtcTime { year: 1999, month: 12, day: 31, hour: 23, minute: 59, second: 59 };
+    assert_eq!(format!("{}", time), "1999-12-31 23:59:59");
+    let time = RtcTime { year: 2000, month: 1, day: 1, hour: 0, minute: 0, second: 0 };
+    assert_eq!(format!("{}", time), "2000-01-01 00:00:00");
+}
+
+#[test_case]
+fn test_date_set_is_refused() {
+    let mut result = Err(ShellError::BadArgument);
+    let output = crate::console::capture_output(|| result = date_command(&["--set", "2024-01-01"]));
+    assert_eq!(result, Ok(()));
+    assert!(output.contains("can't be set"), "got {:?}", output);
+    assert_eq!(date_command(&["now"]), Err(ShellError::Usage("date")));
+}
+
+#[test_case]
+fn test_tsc_calibration() {
+    let hz = calibrate_tsc().expect("the PIT didn't count");
+    assert!(hz > 10_000_000, "a {} Hz TSC", hz);
+    assert_eq!(tsc_hz(), Some(hz));
+    assert_eq!(tsc_cycles_to_ms(3 * hz), Some(3000));
+    assert_eq!(tsc_cycles_to_ns(hz), Some(1_000_000_000));
+}
@@ -7,8 +7,13 @@
 
 // As to how we are able to access I/O only by accessing memory is b/c of "memory mapped I/O"
 
-const BUFFER_HEIGHT: usize = 25;
-const BUFFER_WIDTH: usize = 80;
+pub(crate) const BUFFER_HEIGHT: usize = 25;
+pub(crate) const BUFFER_WIDTH: usize = 80;
+pub(crate) const BUFFER_ADDRESS: usize = 0xb8000;
+
+// the top row is a status bar (see refresh_status_bar()) --> text scrolls in the rows below it and never overwrites it
+const STATUS_ROW: usize = 0;
+pub(crate) const FIRST_TEXT_ROW: usize = STATUS_ROW + 1;
 
 // Use volatile library to wrap certain types so they don't get optimized by the compiler
 use volatile::Volatile;
@@ -16,8 +21,8 @@ use volatile::Volatile;
 // Implement rusts formatting macros to use write! macro for our vga buffer
 use core::fmt;
 
-// Use lazy statics to dereference raw pointers in static variables
-use lazy_static::lazy_static;
+// The global writer is created at runtime by init_writer(), as we cannot dereference raw pointers in static initializers
+use spin::Once;
 
 // Use spinning mutexes (spinlocks) rather than regular mutexes which require blocking support and threads (which we don't have)
 // now why do we need "safe interior mutability" if our kernal won't even have the concept of threads in the first place!!!???
@@ -51,6 +56,46 @@ pub enum Color {
     White = 15,
 }
 
+impl Color {
+    /// Every color, in the order of their numbers.
+    pub const ALL: [Color; 16] = [
+        Color::Black, Color::Blue, Color::Green, Color::Cyan, Color::Red, Color::Magenta, Color::Brown, Color::LightGray,
+        Color::DarkGray, Color::LightBlue, Color::LightGreen, Color::LightCyan, Color::LightRed, Color::Pink,
+        Color::Yellow, Color::White,
+    ];
+
+    /// The name `from_name()` takes, ex. "light-gray".
+    pub fn name(self) -> &'static str {
+        match self {
+            Color::Black => "black",
+            Color::Blue => "blue",
+            Color::Green => "green",
+            Color::Cyan => "cyan",
+            Color::Red => "red",
+            Color::Magenta => "magenta",
+            Color::Brown => "brown",
+            Color::LightGray => "light-gray",
+            Color::DarkGray => "dark-gray",
+            Color::LightBlue => "light-blue",
+            Color::LightGreen => "light-green",
+            Color::LightCyan => "light-cyan",
+            Color::LightRed => "light-red",
+            Color::Pink => "pink",
+            Color::Yellow => "yellow",
+            Color::White => "white",
+        }
+    }
+
+    /// The color called `name` (see `name()`), ignoring case and the dash: "LightGray" and "light_gray" work too.
+    pub fn from_name(name: &str) -> Option<Color> {
+        let wanted = name.bytes().filter(|&byte| byte != b'-' && byte != b'_').map(|byte| byte.to_ascii_lowercase());
+        Color::ALL.into_iter().find(|color| {
+            let candidate = color.name().bytes().filter(|&byte| byte != b'-');
+            candidate.eq(wanted.clone())
+        })
+    }
+}
+
 // A struct to represent the full color byte (the second byte in each character cell)
 // Use repr(transparent) b/c "we have to use the exact same data layout as u8/Color"
 // I'm guessing this is similar to just doing `type ColorCode = u8;`... research more...
@@ -64,7 +109,7 @@ impl ColorCode {
     //  returns a value where the bit is changed to 1 if either the bit from x or n was 1
     // i.e. 0101 | 0010 --> 0111
     // we use it in this case to ammend the foreground as the first 4 bits of the second byte and the background as the last 4 bits
-    fn new(foreground: Color, background: Color) -> Self {
+    const fn new(foreground: Color, background: Color) -> Self {
         ColorCode((background as u8) << 4 | (foreground as u8))
     }
 }
@@ -108,6 +153,8 @@ impl Writer {
     pub fn write_byte(&mut self, byte: u8) {
         match byte {
             b'\n' => self.new_line(),
+            // backspace only moves left, the next character overwrites the cell (never back into the previous row)
+            0x08 => self.column_position = self.column_position.saturating_sub(1),
             byte => {
                 if self.column_position >= BUFFER_WIDTH {
                     self.new_line();
@@ -130,15 +177,15 @@ impl Writer {
     pub fn write_string(&mut self, s: &str) {
         for byte in s.bytes() {
             match byte {
-                0x20..=0x7e | b'\n' => self.write_byte(byte),
+                0x20..=0x7e | b'\n' | 0x08 => self.write_byte(byte),
                 _ => self.write_byte(0xfe)
             }
         }
     }
 
     fn new_line(&mut self) {
-        // shift every character in a line to the line above (the top-most line gets deleted instead)
-        for row in 1..BUFFER_HEIGHT {
+        // shift every character in a line to the line above (the top-most text line gets deleted instead)
+        for row in (FIRST_TEXT_ROW + 1)..BUFFER_HEIGHT {
             for col in 0..BUFFER_WIDTH {
                 let character = self.buffer.chars[row][col].read();
                 self.buffer.chars[row - 1][col].write(character);
@@ -148,6 +195,19 @@ impl Writer {
         self.column_position = 0;
     }
 
+    /// Write everything from now on in `foreground` on `background`.
+    pub fn set_colors(&mut self, foreground: Color, background: Color) {
+        self.color_code = ColorCode::new(foreground, background);
+    }
+
+    /// Blank every text row (the status bar stays) and continue at the start of the bottom row.
+    pub fn clear_screen(&mut self) {
+        for row in FIRST_TEXT_ROW..BUFFER_HEIGHT {
+            self.clear_row(row);
+        }
+        self.column_position = 0;
+    }
+
     // clears the row by writing a blank character to every cell in the row
     fn clear_row(&mut self, row: usize) {
         let blank = ScreenChar {
@@ -162,25 +222,146 @@ impl Writer {
 
 // -> Implement a global static writer, so other modules don't have to carry a spare writer instance
 // problems occur --> we cannot dereference raw pointers in static variables as they are initialized at compile time
-// -> Use the lazy_static crate which gives lazily evaluated static variables which are evaluated at runtime instead
+// -> Create the writer at runtime instead: init_writer() puts it into a spin::Once, first thing in init()
 // -> The buffer code may seem unusual but it is simple,
 // First we set a mutable raw pointer to a Buffer type to the address 0xb8000 (which is where the VGA buffer lives)
 // Then we dereference it --> giving us a Buffer type in memory and get a mutable reference to it instead
 // This ensures that we use rust references rather than manipulating raw pointers which would result in unsafe blocks being in the writer implementation instead
 // Rather we use a one-time unsafe block to access a specific location in memory
-lazy_static! {
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
+fn new_writer() -> Mutex<Writer> {
+    Mutex::new(Writer {
         column_position: 0,
         color_code: ColorCode::new(Color::Yellow, Color::Black),
-        buffer: unsafe { &mut *(0xb8000 as *mut Buffer)}
+        buffer: unsafe { &mut *buffer_address() }
+    })
+}
+
+// -> In test builds the writer writes into MOCK_BUFFER instead of 0xb8000 --> the tests read back exactly what the writer
+// wrote, whatever the hardware (or QEMU with `-display none`) does with the real buffer
+// -> early_print() still writes to 0xb8000 (it is independent of the writer, see lib.rs)
+// -> the tests still run inside QEMU: the crate is no_std with its own target and test runner, there is no `cargo test` on
+// the host (and the writer's users need `cli`/`sti`, which a host process isn't allowed to run)
+#[cfg(test)]
+static mut MOCK_BUFFER: [[ScreenChar; BUFFER_WIDTH]; BUFFER_HEIGHT] =
+    [[ScreenChar { ascii_character: b' ', color_code: ColorCode::new(Color::Yellow, Color::Black) }; BUFFER_WIDTH]; BUFFER_HEIGHT];
+
+#[cfg(test)]
+fn buffer_address() -> *mut Buffer {
+    // Volatile<ScreenChar> is repr(transparent) --> the same layout as the mock's ScreenChars
+    core::ptr::addr_of_mut!(MOCK_BUFFER) as *mut Buffer
+}
+
+#[cfg(not(test))]
+fn buffer_address() -> *mut Buffer {
+    BUFFER_ADDRESS as *mut Buffer
+}
+
+static WRITER: Once<Mutex<Writer>> = Once::new();
+
+/// Create the global writer behind print!/println!, called by `init()`. Only the first call does anything.
+pub fn init_writer() {
+    WRITER.call_once(new_writer);
+}
+
+// printing before init_writer() is a bug --> say so instead of writing through an uninitialized writer
+fn writer() -> &'static Mutex<Writer> {
+    WRITER
+        .r#try()
+        .expect("VGA writer used before vga_buffer::init_writer() (called by init()), use early_print() until then")
+}
+
+// PANIC OUTPUT ==========================================
+// like serial::panic_print(): a panic handler can't wait for the writer, whoever holds it may be what panicked
+
+/// Write `args` on screen without waiting: through the writer if it exists and is free, else with `early_print()`.
+pub fn panic_print(args: fmt::Arguments) {
+    use core::fmt::Write;
+
+    match WRITER.r#try().and_then(|writer| writer.try_lock()) {
+        Some(mut writer) => {
+            let _ = writer.write_fmt(args);
+        }
+        None => {
+            let _ = crate::EarlyPrinter.write_fmt(args);
+        }
+    }
+}
+
+/// Release the writer's lock if something holds it, see `serial::force_unlock()`.
+///
+/// Unsafe for the same reason.
+pub unsafe fn force_unlock() {
+    if let Some(writer) = WRITER.r#try() {
+        writer.force_unlock();
+    }
+}
+
+/// Whether the writer's lock is free (or there is no writer yet), see `force_unlock()`.
+pub(crate) fn lock_is_free() -> bool {
+    WRITER.r#try().map_or(true, |writer| writer.try_lock().is_some())
+}
+
+// STATUS BAR ==========================================
+
+const STATUS_COLOR: ColorCode = ColorCode::new(Color::Black, Color::LightGray);
+
+// writes formatted text into the status row, anything past the end of the row is cut off
+struct StatusWriter<'a> {
+    buffer: &'a mut Buffer,
+    column: usize,
+}
+
+impl fmt::Write for StatusWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            if self.column >= BUFFER_WIDTH {
+                break;
+            }
+            let byte = match byte {
+                0x20..=0x7e => byte,
+                _ => 0xfe,
+            };
+            self.buffer.chars[STATUS_ROW][self.column].write(ScreenChar {
+                ascii_character: byte,
+                color_code: STATUS_COLOR,
+            });
+            self.column += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Replace the contents of the status bar (the top row of the screen).
+pub fn set_status(args: fmt::Arguments) {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = writer().lock();
+        let mut status = StatusWriter { buffer: &mut *writer.buffer, column: 0 };
+        status.write_fmt(args).unwrap();
+        // pad the rest of the row so the old status doesn't show through
+        while status.column < BUFFER_WIDTH {
+            status.write_str(" ").unwrap();
+        }
     });
 }
 
-// Redefine the println!() and print!() macro to our implementation (spinning mutex, and write into the vga buffer)
+/// Redraw the status bar, called once a second from the timer interrupt.
+pub fn refresh_status_bar() {
+    let (busy, idle) = crate::task::cpu_usage();
+    set_status(format_args!(
+        " mini_os | cpu: {:>3}.{}% busy {:>3}.{}% idle | up {}",
+        busy / 10, busy % 10, idle / 10, idle % 10, crate::time::Uptime::now()
+    ));
+}
+
+// Redefine the println!() and print!() macro to our implementation (spinning mutex, and write into the console outputs,
+// the vga buffer by default --> see console.rs)
 
 #[macro_export]
 macro_rules! print {
-    ($($arg:tt)*) => ($crate::vga_buffer::_print(format_args!($($arg)*)));
+    ($($arg:tt)*) => ($crate::console::_print(format_args!($($arg)*)));
 }
 
 #[macro_export]
@@ -189,20 +370,61 @@ macro_rules! println {
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
 
-#[doc(hidden)]
-pub fn _print(args: fmt::Arguments) {
-    use core::fmt::Write;
-    use x86_64::instructions::interrupts;
+/// The VGA text buffer as a console output, the display until another one is set (see console.rs).
+pub struct VgaTextOutput;
 
-    // make sure no interrupts occur while the WRITER global is locked --> prevents deadlocks with interrupts
-    interrupts::without_interrupts(|| {
-        WRITER.lock().write_fmt(args).unwrap();
-    });
+impl crate::console::ConsoleOutput for VgaTextOutput {
+    fn write_str(&self, s: &str) {
+        x86_64::instructions::interrupts::without_interrupts(|| writer().lock().write_string(s));
+    }
+
+    fn clear(&self) {
+        clear_screen();
+    }
+
+    fn columns(&self) -> Option<usize> {
+        Some(BUFFER_WIDTH)
+    }
+
+    fn set_colors(&self, foreground: Color, background: Color) {
+        x86_64::instructions::interrupts::without_interrupts(|| writer().lock().set_colors(foreground, background));
+    }
+}
+
+/// Blank the VGA text rows (everything but the status bar).
+pub fn clear_screen() {
+    x86_64::instructions::interrupts::without_interrupts(|| writer().lock().clear_screen());
 }
 
 // TESTS =====================================
 // have to keep tests after the print macro declaration
 
+// the characters on screen in `row`, for tests elsewhere that check what got printed
+#[cfg(test)]
+pub(crate) fn screen_row(row: usize) -> [u8; BUFFER_WIDTH] {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let writer = writer().lock();
+        let mut line = [0; BUFFER_WIDTH];
+        for (col, byte) in line.iter_mut().enumerate() {
+            *byte = writer.buffer.chars[row][col].read().ascii_character;
+        }
+        line
+    })
+}
+
+#[test_case]
+fn test_init_writer_then_print() {
+    use x86_64::instructions::interrupts;
+
+    init_writer(); // already done by init(), a second call keeps the writer and its position
+    interrupts::without_interrupts(|| {
+        print!("\nprinted after init_writer");
+        let row = screen_row(BUFFER_HEIGHT - 1);
+        assert_eq!(&row[..25], b"printed after init_writer");
+        assert_eq!(writer().lock().column_position, 25);
+    });
+}
+
 #[test_case]
 fn test_println_simple() {
     println!("test_println_simple output");
@@ -225,7 +447,7 @@ fn test_println_output() {
     // make sure no interrupts occur during tests duration to prevent deadlocks and to prevent unwanted characters being written to the vga buffer
     // thus invalidating the test
     interrupts::without_interrupts(|| {
-        let mut writer = WRITER.lock();
+        let mut writer = writer().lock();
         writeln!(writer, "\n{}", s).expect("writeln failed");
         for (i, c) in s.chars().enumerate() {
             let screen_char = writer.buffer.chars[BUFFER_HEIGHT - 2][i].read();
@@ -234,4 +456,91 @@ fn test_println_output() {
     });
 }
 
-// TESTS END ===================================
\ No newline at end of file
+#[test_case]
+fn test_status_bar_does_not_scroll() {
+    use x86_64::instructions::interrupts;
+
+    let status = "status bar test";
+    // no timer interrupt can redraw the status bar in the middle of the test
+    interrupts::without_interrupts(|| {
+        set_status(format_args!("{}", status));
+        for _ in 0..BUFFER_HEIGHT {
+            println!("scrolling past the status bar");
+        }
+        let writer = writer().lock();
+        for (i, c) in status.chars().enumerate() {
+            let screen_char = writer.buffer.chars[STATUS_ROW][i].read();
+            assert_eq!(char::from(screen_char.ascii_character), c);
+        }
+    });
+}
+
+#[test_case]
+fn test_backspace_moves_left() {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = writer().lock();
+        write!(writer, "\nabc\x08\x08X").expect("write failed");
+        assert_eq!(writer.column_position, 2);
+        let row = &writer.buffer.chars[BUFFER_HEIGHT - 1];
+        assert_eq!(row[1].read().ascii_character, b'X');
+        assert_eq!(row[2].read().ascii_character, b'c', "backspace erased the cell");
+        // the start of the row is as far back as it goes
+        write!(writer, "\x08\x08\x08").expect("write failed");
+        assert_eq!(writer.column_position, 0);
+    });
+}
+
+#[test_case]
+fn test_writer_uses_mock_buffer() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = writer().lock();
+        writer.write_string("\nhello");
+        let row = unsafe { core::ptr::addr_of!(MOCK_BUFFER[BUFFER_HEIGHT - 1]).read_volatile() };
+        let text: [u8; 5] = core::array::from_fn(|i| row[i].ascii_character);
+        assert_eq!(&text, b"hello");
+    });
+}
+
+#[test_case]
+fn test_color_from_name() {
+    for color in Color::ALL {
+        assert_eq!(Color::from_name(color.name()), Some(color));
+    }
+    assert_eq!(Color::ALL.iter().enumerate().filter(|&(i, &color)| color as usize != i).count(), 0, "ALL out of order");
+    assert_eq!(Color::from_name("LightGray"), Some(Color::LightGray));
+    assert_eq!(Color::from_name("light_blue"), Some(Color::LightBlue));
+    assert_eq!(Color::from_name("WHITE"), Some(Color::White));
+    for typo in ["", "-", "grey", "light", "lightgrayy", "dark gray", "blu"] {
+        assert_eq!(Color::from_name(typo), None, "{:?}", typo);
+    }
+}
+
+// the test panic handler only prints to serial --> what panic_println! sends to the screen is checked here, with the
+// writer held like a panic handler would find it; that the handler frees it is checked by ShouldPanic (see SHOULD PANIC
+// in lib.rs)
+#[test_case]
+static PANIC_HOLDING_WRITER: crate::ShouldPanic<fn()> = crate::ShouldPanic("mini_os::vga_buffer::panic_holding_writer", || {
+    // no interrupts while it's held (the timer prints), the recovery after the panic turns them back on
+    x86_64::instructions::interrupts::disable();
+    let _held = writer().lock();
+    // panic_print() can't use the writer --> it goes to 0xb8000 through early_print() instead of waiting
+    let start = crate::EARLY_CURSOR.load(core::sync::atomic::Ordering::Relaxed);
+    panic_print(format_args!("past the lock"));
+    let first_cell = FIRST_TEXT_ROW * BUFFER_WIDTH;
+    let cell = |i: usize| (start - first_cell + i) % (BUFFER_HEIGHT * BUFFER_WIDTH - first_cell) + first_cell; // wraps
+    let screen = BUFFER_ADDRESS as *const u16;
+    let text: [u8; 13] = core::array::from_fn(|i| unsafe { screen.add(cell(i)).read_volatile() } as u8);
+    if &text != b"past the lock" {
+        // an assert would panic, which passes this test --> it fails by not panicking
+        crate::serial_print!("the screen shows {:?} ", text);
+        return;
+    }
+    panic!("panic while the VGA writer is locked");
+});
+
+// TESTS END ===================================
@@ -0,0 +1,115 @@
+// Software watchdog --> notices when the kernel stops making progress (ex. a deadlocked spinlock) instead of hanging
+// silently until someone (or CI's timeout) gives up
+// - foreground code calls pet() regularly (the executor loop, the test runner between tests), the timer interrupt
+//   checks how long ago that was and fires once it's more than the armed timeout
+// - firing prints the interrupted RIP and the interrupt counters and then exits QEMU (test runs) or reboots
+// - everything the check touches is an atomic and the report doesn't take any locks: the hung code may hold the
+//   serial/VGA locks, so the report writes to the serial port and the VGA buffer directly (see EmergencyWriter)
+// - it can't see hangs with interrupts off (no timer interrupt, no check) --> the double fault / test timeout catch those
+use crate::interrupts;
+use crate::QemuExitCode;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use x86_64::structures::idt::InterruptStackFrame;
+
+// 0 = disarmed
+static TIMEOUT_TICKS: AtomicU64 = AtomicU64::new(0);
+static LAST_PET: AtomicU64 = AtomicU64::new(0);
+static ACTION: AtomicU8 = AtomicU8::new(WatchdogAction::Reboot.encode());
+
+/// What happens after the report when the watchdog fires.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchdogAction {
+    /// `power::reboot()`, the default.
+    Reboot,
+    /// Leave QEMU through the isa-debug-exit device with this code (test runs).
+    ExitQemu(QemuExitCode),
+}
+
+impl WatchdogAction {
+    // 0 for Reboot, the exit code (all of them are non-zero bytes) for ExitQemu
+    const fn encode(self) -> u8 {
+        match self {
+            WatchdogAction::Reboot => 0,
+            WatchdogAction::ExitQemu(code) => code as u32 as u8,
+        }
+    }
+
+    fn decode(value: u8) -> Self {
+        match QemuExitCode::from_code(u32::from(value)) {
+            Some(code) => WatchdogAction::ExitQemu(code),
+            None => WatchdogAction::Reboot,
+        }
+    }
+}
+
+/// Choose what happens when the watchdog fires.
+pub fn set_action(action: WatchdogAction) {
+    ACTION.store(action.encode(), Ordering::Relaxed);
+}
+
+/// Fire if `pet()` isn't called for more than `timeout_ticks` timer interrupts (counting from now).
+pub fn arm(timeout_ticks: u64) {
+    pet();
+    TIMEOUT_TICKS.store(timeout_ticks.max(1), Ordering::Relaxed);
+}
+
+/// Stop watching.
+pub fn disarm() {
+    TIMEOUT_TICKS.store(0, Ordering::Relaxed);
+}
+
+/// Tell the watchdog the kernel is still making progress.
+pub fn pet() {
+    LAST_PET.store(interrupts::timer_ticks(), Ordering::Relaxed);
+}
+
+/// Called by the timer interrupt on every tick.
+pub(crate) fn check(ticks: u64, stack_frame: &InterruptStackFrame) {
+    let timeout = TIMEOUT_TICKS.load(Ordering::Relaxed);
+    if timeout == 0 || ticks.saturating_sub(LAST_PET.load(Ordering::Relaxed)) <= timeout {
+        return;
+    }
+    disarm(); // fire only once, even if the action returns somehow
+    fire(ticks, stack_frame);
+}
+
+// writes straight to COM1 and the VGA buffer, whoever holds their locks
+struct EmergencyWriter;
+
+impl Write for EmergencyWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        // COM1 was initialized long before anything could hang, a second handle just sends bytes
+        let mut serial = unsafe { uart_16550::SerialPort::new(0x3F8) };
+        let _ = serial.write_str(s);
+        crate::early_print(s);
+        Ok(())
+    }
+}
+
+fn fire(ticks: u64, stack_frame: &InterruptStackFrame) -> ! {
+    let stats = interrupts::stats();
+    let last_pet = LAST_PET.load(Ordering::Relaxed);
+    let _ = writeln!(
+        EmergencyWriter,
+        "\nWATCHDOG: kernel appears hung (no pet for {} ticks)\nRIP: {:?}\ninterrupts: {:?}",
+        ticks - last_pet, stack_frame.instruction_pointer, stats
+    );
+    match WatchdogAction::decode(ACTION.load(Ordering::Relaxed)) {
+        WatchdogAction::ExitQemu(code) => {
+            crate::exit_qemu_without_report(code);
+            crate::hlt_loop();
+        }
+        WatchdogAction::Reboot => crate::power::reboot(),
+    }
+}
+
+// TESTS ===================================
+
+#[test_case]
+fn test_action_encoding() {
+    assert_eq!(WatchdogAction::decode(WatchdogAction::Reboot.encode()), WatchdogAction::Reboot);
+    for code in QemuExitCode::ALL {
+        assert_eq!(WatchdogAction::decode(WatchdogAction::ExitQemu(code).encode()), WatchdogAction::ExitQemu(code));
+    }
+}
@@ -0,0 +1,85 @@
+// Integration Test Environment --> the ATA driver against the disks QEMU attaches
+// primary master = the boot image, primary slave = target/test_disk.img (see test-args in Cargo.toml)
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(mini_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use mini_os::drivers::ata::{AtaDevice, AtaError, Channel, Drive, SECTOR_SIZE};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use mini_os::allocator;
+    use mini_os::memory::{self, GlobalFrameAllocator};
+    use x86_64::VirtAddr;
+
+    mini_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    unsafe { memory::init_frame_allocator(&boot_info.memory_map, phys_mem_offset) };
+    // the block device conformance test needs the heap
+    allocator::init_heap(&mut mapper, &mut GlobalFrameAllocator).expect("heap initialization failed");
+
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    mini_os::test_panic_handler(info);
+}
+
+// TESTS ===================
+
+fn test_disk() -> AtaDevice {
+    AtaDevice::identify(Channel::PRIMARY, Drive::Slave).expect("no test disk attached as primary slave")
+}
+
+#[test_case]
+fn identify_drives() {
+    let boot_disk = AtaDevice::identify(Channel::PRIMARY, Drive::Master).expect("boot disk not found");
+    assert!(boot_disk.sectors() > 0);
+    assert!(!boot_disk.model().is_empty());
+
+    let disk = test_disk();
+    assert_eq!(disk.sectors(), 2048); // 1 MiB, see build.rs
+}
+
+#[test_case]
+fn write_then_read_back_sector_100() {
+    let disk = test_disk();
+    let mut pattern = [0u8; SECTOR_SIZE * 2];
+    for (i, byte) in pattern.iter_mut().enumerate() {
+        *byte = (i * 7 + 3) as u8;
+    }
+    disk.write_sectors(100, 2, &pattern).expect("write failed");
+
+    let mut read = [0u8; SECTOR_SIZE * 2];
+    disk.read_sectors(100, 2, &mut read).expect("read failed");
+    assert_eq!(read, pattern);
+}
+
+#[test_case]
+fn out_of_range_is_an_error() {
+    let disk = test_disk();
+    let mut buf = [0u8; SECTOR_SIZE];
+    assert_eq!(disk.read_sectors(disk.sectors(), 1, &mut buf), Err(AtaError::OutOfRange));
+}
+
+#[test_case]
+fn block_device_conformance() {
+    mini_os::drivers::block::conformance_test(&test_disk());
+}
+
+#[test_case]
+fn missing_drive_is_an_error() {
+    // QEMU puts nothing at the secondary slave --> this has to fail instead of hanging
+    assert!(AtaDevice::identify(Channel::SECONDARY, Drive::Slave).is_err());
+}
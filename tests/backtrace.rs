@@ -0,0 +1,77 @@
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use mini_os::console::capture;
+use mini_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+
+// NOTE: like stack_overflow.rs this test has no harness --> it panics three calls deep on purpose, and its panic handler
+// prints the backtrace like the kernel's do (see backtrace.rs) with the console captured (see console/capture.rs)
+// the handler then counts the distinct addresses in the "  #N 0x..." lines: at least the three calls have to be there
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use mini_os::allocator;
+    use mini_os::memory::{self, GlobalFrameAllocator};
+    use x86_64::VirtAddr;
+
+    serial_print!("backtrace::three_calls_deep...\t");
+    mini_os::init();
+    // the backtrace checks every frame against the page tables, it needs the physical memory offset
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    unsafe { memory::init_frame_allocator(&boot_info.memory_map, phys_mem_offset) };
+    // capture::take() returns the text as a String
+    allocator::init_heap(&mut mapper, &mut GlobalFrameAllocator).expect("heap initialization failed");
+    capture::start();
+
+    outer();
+    serial_println!("[failed] execution continued after the panic");
+    exit_qemu(QemuExitCode::Failed);
+    mini_os::hlt_loop();
+}
+
+#[inline(never)]
+fn outer() {
+    middle();
+    core::hint::black_box(()); // not a tail call
+}
+
+#[inline(never)]
+fn middle() {
+    inner();
+    core::hint::black_box(());
+}
+
+#[inline(never)]
+fn inner() {
+    panic!("three calls deep");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    mini_os::backtrace::print();
+    let text = capture::take();
+
+    let mut addresses = [0u64; mini_os::backtrace::MAX_FRAMES];
+    let mut distinct = 0;
+    for line in text.lines().filter_map(|line| line.trim_start().strip_prefix('#')) {
+        let address = line.split_whitespace().nth(1).and_then(|hex| hex.strip_prefix("0x"));
+        if let Some(address) = address.and_then(|hex| u64::from_str_radix(hex, 16).ok()) {
+            if distinct < addresses.len() && !addresses[..distinct].contains(&address) {
+                addresses[distinct] = address;
+                distinct += 1;
+            }
+        }
+    }
+    if distinct >= 3 {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed] {} distinct return addresses after \"{}\":\n{}", distinct, info, text);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    mini_os::hlt_loop();
+}
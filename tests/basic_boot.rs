@@ -10,12 +10,19 @@ use mini_os::println;
 
 // MAIN TEST ==============================================
 
-///Ensure println works right after booting
+///Ensure println works right after booting, with nothing but the VGA writer set up
 #[test_case]
 fn test_println() {
+    mini_os::vga_buffer::init_writer();
     println!("test_println output");
 }
 
+/// early_print! works without mini_os::init() (this test never calls it)
+#[test_case]
+fn test_early_print_before_init() {
+    mini_os::early_print!("test_early_print_before_init output {}\n", 42);
+}
+
 // END ====================================================
 
 #[no_mangle] // don't mangle the name of this function
@@ -0,0 +1,61 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use mini_os::{bench, exit_qemu, println, serial_println, QemuExitCode};
+use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB, Translate};
+use x86_64::VirtAddr;
+
+// NOTE: not a test --> every benchmark prints its BENCH line (see bench.rs) and the binary exits with Success, so the
+// numbers are collected by the host and nothing is compared here
+// it only builds with the benchmarks feature (required-features in Cargo.toml), a plain `cargo test` skips it:
+//   cargo test --test benchmarks --features benchmarks
+
+// an unused page for the map/unmap benchmark
+const BENCH_PAGE: u64 = 0x_4444_6000_0000;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use mini_os::allocator;
+    use mini_os::memory::{self, GlobalFrameAllocator};
+
+    mini_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    unsafe { memory::init_frame_allocator(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut GlobalFrameAllocator).expect("heap initialization failed");
+
+    // map and unmap the same page to the same frame, the TLB entry is flushed both times
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(BENCH_PAGE));
+    let frame = GlobalFrameAllocator.allocate_frame().expect("no free frame");
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    bench::run("map_unmap_page", 10_000, || unsafe {
+        mapper.map_to(page, frame, flags, &mut GlobalFrameAllocator).expect("map failed").flush();
+        mapper.unmap(page).expect("unmap failed").1.flush();
+    });
+
+    let addr = VirtAddr::new(allocator::HEAP_START as u64);
+    bench::run("translate", 100_000, || mapper.translate_addr(addr));
+
+    // to the VGA text buffer, 79 characters and the newline
+    bench::run("println_80_chars", 1_000, || {
+        println!("0123456789012345678901234567890123456789012345678901234567890123456789012345678")
+    });
+
+    bench::run("box_new_drop", 100_000, || drop(Box::new(42u64)));
+
+    exit_qemu(QemuExitCode::Success);
+    mini_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    serial_println!("[failed] {}", info);
+    exit_qemu(QemuExitCode::Failed);
+    mini_os::hlt_loop();
+}
@@ -0,0 +1,9 @@
+// shared by the integration tests that overflow the kernel stack (a directory module --> cargo doesn't build it as a test
+// of its own, each test includes it with `mod common;`)
+
+/// Recurse until the stack runs into the guard page below it.
+#[allow(unconditional_recursion)] // silence compiler warning about endless recursion
+pub fn stack_overflow() {
+    stack_overflow(); // for each recursion, the return address is pushed
+    volatile::Volatile::new(0).read(); // prevent tail recursion optimizations (compressing into single loop, not creating stack frames)
+}
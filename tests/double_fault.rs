@@ -0,0 +1,34 @@
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+use mini_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+
+// NOTE: like stack_overflow.rs this test has no harness, but it double faults with the kernel's own IDT: a `ud2` without
+// an invalid opcode handler raises a general protection fault, which has no handler either (a stack overflow is a page
+// fault on its own stack now, see stack_overflow_guarded.rs) --> its double fault handler panics and the test run would
+// exit with DoubleFault (host status 39, see EXIT QEMU FUNCS in lib.rs); the panic handler checks that this is the code
+// it would get and exits with Success instead
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    serial_print!("double_fault::exit_code...\t");
+    mini_os::init();
+    unsafe { core::arch::asm!("ud2") };
+    serial_println!("[failed] execution continued after the double fault");
+    exit_qemu(QemuExitCode::Failed);
+    mini_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let code = mini_os::test_failure_code(info);
+    if code == QemuExitCode::DoubleFault {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed] expected DoubleFault, got {:?}: {}", code, info);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    mini_os::hlt_loop();
+}
@@ -76,4 +76,28 @@ fn many_boxes_long_lived() {
         assert_eq!(*x, i);
     }
     assert_eq!(*long_lived, 1);
-}
\ No newline at end of file
+}
+
+#[test_case]
+fn high_water_mark_is_a_peak() {
+    use mini_os::allocator::{heap_high_water_mark, reset_high_water_mark, stats};
+
+    reset_high_water_mark();
+    let heap_size = stats().heap_size as usize;
+    let target = heap_size * 8 / 10;
+    // in pieces, a single 80% block may not find room between what earlier tests left behind
+    let mut chunks: Vec<Vec<u8>> = Vec::with_capacity(heap_size / 4096);
+    while (stats().bytes_in_use as usize) < target {
+        let mut chunk = Vec::new();
+        chunk.try_reserve_exact(4096).expect("heap full before 80% of it was in use");
+        chunks.push(chunk);
+    }
+    let allocated = stats().bytes_in_use as usize;
+    assert!(heap_high_water_mark() >= allocated);
+
+    drop(chunks);
+    assert!(stats().bytes_in_use as usize <= heap_size / 10, "chunks not freed");
+    assert!(heap_high_water_mark() >= allocated, "the high-water mark went down");
+    reset_high_water_mark();
+    assert!(heap_high_water_mark() < allocated);
+}
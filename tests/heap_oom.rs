@@ -0,0 +1,60 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use mini_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+
+// NOTE: like stack_overflow.rs this test has no harness --> the out of memory path ends in the panic handler
+// the contract (see Out of memory in allocator.rs): an allocation bigger than the heap reaches the alloc error handler,
+// which prints the layout and heap stats and panics, and a test run exits with Oom (host status 41) --> the panic
+// handler checks it got that code and exits with Success instead
+// with the heap_growth feature the heap grows and the same allocation has to succeed, run both:
+//   cargo test --test heap_oom
+//   cargo test --test heap_oom --features heap_growth
+
+// four times the default heap
+const OVERSIZED: usize = 4 * mini_os::allocator::HEAP_SIZE;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use mini_os::allocator;
+    use mini_os::memory::{self, GlobalFrameAllocator};
+    use x86_64::VirtAddr;
+
+    serial_print!("heap_oom::oversized_vec...\t");
+    mini_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    // the global frame allocator: a growing heap takes its frames from it
+    unsafe { memory::init_frame_allocator(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut GlobalFrameAllocator).expect("heap initialization failed");
+
+    let vec: Vec<u8> = Vec::with_capacity(OVERSIZED);
+    if cfg!(feature = "heap_growth") {
+        assert!(vec.capacity() >= OVERSIZED);
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed] {} bytes fit into a {} byte heap", OVERSIZED, allocator::stats().heap_size);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    mini_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let code = mini_os::test_failure_code(info);
+    if !cfg!(feature = "heap_growth") && code == QemuExitCode::Oom {
+        serial_println!("[ok] {}", info.message());
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed] expected {}, got {:?}: {}", if cfg!(feature = "heap_growth") { "no panic" } else { "Oom" }, code, info);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    mini_os::hlt_loop();
+}
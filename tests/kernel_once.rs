@@ -0,0 +1,47 @@
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+use mini_os::sync::KernelOnce;
+use mini_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+
+// NOTE: like stack_overflow.rs this test has no harness --> the panic handler is where a debug build ends up
+// a release build has no debug assertions, so there the second init() has to leave the first value in place instead
+
+static VALUE: KernelOnce<u32> = KernelOnce::new();
+
+// MAIN TEST ================================================
+
+fn init_twice() {
+    serial_print!("kernel_once::init_twice...\t");
+    assert_eq!(*VALUE.init(1), 1);
+    VALUE.init(2);
+    assert_eq!(VALUE.get(), Some(&1));
+}
+
+// END ========================================================
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    if cfg!(debug_assertions) {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]");
+        exit_qemu(QemuExitCode::Failed);
+    }
+    loop {}
+}
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    init_twice();
+    if cfg!(debug_assertions) {
+        serial_println!("[test did not panic]");
+        exit_qemu(QemuExitCode::Failed);
+    } else {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    }
+    loop {}
+}
@@ -0,0 +1,59 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(mini_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use mini_os::console::capture;
+
+// drives the keyboard shell with injected key presses (see INJECTION in task/keyboard.rs) --> nobody has to type
+// into the QEMU window, the shell writes to the console capture (see console/capture.rs) instead of the screen
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use mini_os::allocator;
+    use mini_os::memory::{self, GlobalFrameAllocator};
+    use x86_64::VirtAddr;
+
+    mini_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    unsafe { memory::init_frame_allocator(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut GlobalFrameAllocator).expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    mini_os::test_panic_handler(info)
+}
+
+// TESTS ===================
+
+#[test_case]
+fn shell_runs_an_injected_line() {
+    use mini_os::kshell::{self, Shell};
+    use mini_os::task::{executor::Executor, keyboard, Task};
+    use x86_64::instructions::interrupts;
+
+    kshell::init();
+    let keys = keyboard::key_stream().expect("the keyboard is already in use");
+    let mut executor = Executor::new();
+    // the shell writes to the capture itself, what its commands print! gets there too (once, see console::_print())
+    capture::start();
+    executor.spawn(Task::new(Shell::new(keys, &capture::CAPTURE, "test> ").run()));
+    executor.run_until_idle(); // waits for the first key
+
+    keyboard::inject_str("echo hi\n");
+    // the keys are queued already --> run the shell through them before the timer gets a turn to print
+    interrupts::without_interrupts(|| executor.run_until_idle());
+
+    let output = capture::take();
+    // the typed line, then what echo printed, then the next prompt
+    assert!(output.contains("test> echo hi\nhi\ntest> "), "got {:?}", output);
+}
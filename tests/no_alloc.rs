@@ -0,0 +1,56 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use mini_os::allocator;
+use mini_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+
+// NOTE: like stack_overflow.rs this test has no harness --> reaching the panic handler means the guard worked
+
+entry_point!(main);
+
+// MAIN TEST ================================================
+
+fn alloc_under_guard() {
+    serial_print!("no_alloc::alloc_under_guard...\t");
+
+    // dropping a guard (even a nested one) restores the state from before it --> allocating works again afterwards
+    {
+        let _outer = allocator::no_alloc_guard();
+        let _inner = allocator::no_alloc_guard();
+    }
+    assert_eq!(*Box::new(41), 41);
+
+    let _guard = allocator::no_alloc_guard();
+    let value = Box::new(1); // must panic
+    serial_println!("allocated {} under a no-alloc guard", value);
+}
+
+// END ========================================================
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use mini_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    mini_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    alloc_under_guard();
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
@@ -0,0 +1,66 @@
+// Template for tests of accesses that have to page fault (guard pages, NX, write protection): copy it, keep the setup
+// in main() and replace the tests --> see EXPECTED PAGE FAULTS in lib.rs
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(mini_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use mini_os::expect_page_fault;
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::{FrameAllocator, PageTableFlags};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use mini_os::memory;
+    use x86_64::VirtAddr;
+
+    mini_os::init(); // the IDT with the page fault handler
+    // page tables (memory::init() also turns NX on) and frames, for tests that map pages of their own
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let _mapper = unsafe { memory::init(phys_mem_offset) };
+    unsafe { memory::init_frame_allocator(&boot_info.memory_map, phys_mem_offset) };
+
+    test_main();
+    mini_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    mini_os::test_panic_handler(info)
+}
+
+// TESTS ===================
+
+/// nothing is mapped right below the physical mappings window, like a guard page --> a non-present read
+#[test_case]
+fn read_of_an_unmapped_page() {
+    let guard = (mini_os::memory::PHYS_MAP_START - 4096) as *const u64;
+    expect_page_fault(PageFaultErrorCode::empty(), || unsafe {
+        guard.read_volatile();
+    });
+}
+
+/// a write to the same page is a non-present fault too, with the write bit
+#[test_case]
+fn write_to_an_unmapped_page() {
+    let guard = (mini_os::memory::PHYS_MAP_START - 4096) as *mut u64;
+    expect_page_fault(PageFaultErrorCode::CAUSED_BY_WRITE, || unsafe {
+        guard.write_volatile(0);
+    });
+}
+
+/// map_physical() maps everything NO_EXECUTE --> running code there is a protection violation
+#[test_case]
+fn fetch_from_a_no_execute_page() {
+    let frame = mini_os::memory::GlobalFrameAllocator.allocate_frame().expect("no free frame");
+    let page = mini_os::memory::map_physical(frame.start_address(), 4096, PageTableFlags::WRITABLE).unwrap();
+    unsafe { page.as_mut_ptr::<u8>().write_volatile(0xc3) }; // ret
+    expect_page_fault(PageFaultErrorCode::INSTRUCTION_FETCH | PageFaultErrorCode::PROTECTION_VIOLATION, || {
+        let ret: extern "C" fn() = unsafe { core::mem::transmute(page.as_u64() as usize) };
+        ret();
+    });
+}
@@ -0,0 +1,33 @@
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+use mini_os::{exit_qemu_without_report, serial, QemuExitCode};
+
+// NOTE: like stack_overflow.rs this test has no harness --> it panics before anything is initialized, SERIAL1 included
+// the panic handler prints with panic_println! (see PANIC OUTPUT in lib.rs), which sets up the UART itself instead of
+// creating SERIAL1 --> it does that with the UART in loopback mode (see LOOPBACK in serial.rs) and passes only if the
+// panic message is what came out of it; a handler that hung on the way would end in bootimage's test timeout
+
+// short enough for the loopback (serial::LOOPBACK_LEN with the newline)
+const MESSAGE: &str = "early panic";
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    panic!("{}", MESSAGE);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    // interrupts are still off from the bootloader, nothing takes the looped back bytes
+    let (sent, len) = serial::loopback(|| mini_os::panic_println!("{}", info.message()));
+    let sent = &sent[..len];
+    if sent.strip_suffix(b"\n") == Some(MESSAGE.as_bytes()) {
+        mini_os::panic_println!("panic_output::before_serial_init...\t[ok]");
+        exit_qemu_without_report(QemuExitCode::Success); // no heap report, it would go through SERIAL1
+    } else {
+        mini_os::panic_println!("panic_output::before_serial_init...\t[failed]\n\nError: the UART sent {:?}\n", sent);
+        exit_qemu_without_report(QemuExitCode::Failed);
+    }
+    mini_os::hlt_loop();
+}
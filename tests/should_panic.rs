@@ -1,33 +1,48 @@
+// Integration Test Environment
 #![no_std]
 #![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(mini_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
 
 use core::panic::PanicInfo;
-use mini_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use mini_os::ShouldPanic;
 
-// NOTE: this test does not have any test / test-runner functionality b/c there is only 1 test and that test needs to fail
-// --> therefore you must set the "harness" for this test to equal "false" in Cargo.toml and all similar tests that need to fail
-// Instead we just directly call the testing function in the entry _start() function
+// NOTE: the tests that have to fail are ShouldPanics --> their panic is their [ok] and the runner goes on with the next
+// test (see SHOULD PANIC and TEST RECOVERY in lib.rs)
 
 // MAIN TEST ================================================
 
 fn should_fail() {
-    serial_print!("should_panic::should_fail...\t");
     assert_eq!(0, 1);
 }
 
-// END ========================================================
+fn should_fail_too() {
+    let v: [u8; 0] = [];
+    let _ = v[core::hint::black_box(0)];
+}
 
-#[panic_handler]
-fn panic(_info: &PanicInfo) -> ! {
-    serial_println!("[ok]");
-    exit_qemu(QemuExitCode::Success);
-    loop {}
+#[test_case]
+static SHOULD_FAIL: ShouldPanic<fn()> = ShouldPanic("should_panic::should_fail", should_fail);
+
+/// runs after a panic, the run went on
+#[test_case]
+fn passes_after_the_panic() {
+    assert_eq!(1, 1);
 }
 
+#[test_case]
+static SHOULD_FAIL_TOO: ShouldPanic<fn()> = ShouldPanic("should_panic::should_fail_too", should_fail_too);
+
+// END ========================================================
+
 #[no_mangle]
 pub extern "C" fn _start() -> ! {
-    should_fail();
-    serial_println!("[test did not panic]");
-    exit_qemu(QemuExitCode::Failed);
-    loop{}
+    test_main();
+    mini_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    mini_os::test_panic_handler(info)
 }
@@ -7,8 +7,14 @@ use mini_os::{ serial_print, exit_qemu, QemuExitCode, serial_println };
 use lazy_static::lazy_static;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
 
-// NOTE: this test does not have any test harness and test runner func --> see should_panic.rs for more info
-// (this is why we must serial print the test name and other stuff)
+mod common;
+use common::stack_overflow;
+
+// NOTE: this test does not have any test harness and test runner func --> it ends in its own double fault handler, not in
+// a panic the runner could expect (see ShouldPanic in lib.rs), so it has harness = false in Cargo.toml and _start() calls
+// the test directly (this is why we must serial print the test name and other stuff)
+// its IDT has no page fault handler --> the fault in the guard page can't be delivered and becomes a double fault,
+// stack_overflow_guarded.rs has one (on its own stack) and checks that the overflow shows up there
 
 #[no_mangle]
 pub extern "C" fn _start() -> ! {
@@ -29,12 +35,6 @@ fn panic(info: &PanicInfo) -> ! {
     mini_os::test_panic_handler(info) // fail if execution panics rather than passing to handler function (see below)
 }
 
-#[allow(unconditional_recursion)] // silence compiler warning about endless recursion
-fn stack_overflow() {
-    stack_overflow(); // for each recursion, the return address is pushed
-    volatile::Volatile::new(0).read(); // prevent tail recursion optimizations (compressing into single loop, not creating stack frames)
-}
-
 // Custom IDT initialization ==============================
 
 lazy_static! {
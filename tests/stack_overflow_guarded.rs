@@ -0,0 +1,104 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use mini_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use x86_64::registers::control::Cr2;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::structures::paging::{Page, Size4KiB, Translate};
+use x86_64::VirtAddr;
+
+mod common;
+use common::stack_overflow;
+
+// NOTE: like stack_overflow.rs this test has no harness, but its IDT has a page fault handler on its own stack
+// (PAGE_FAULT_IST_INDEX, see gdt.rs) --> the overflow has to end there, with CR2 in the guard page below the kernel stack
+// the bootloader leaves that page unmapped, the test finds it by walking down from the current stack pointer to the
+// first unmapped page; reaching the double fault handler means the page fault path didn't catch the overflow
+
+// printed by the page fault handler when CR2 is in the guard page
+const MARKER: &str = "STACK_GUARD_HIT";
+// pages to walk down before giving up (the bootloader's default kernel stack is 80 pages)
+const MAX_STACK_PAGES: u64 = 1024;
+
+static GUARD_PAGE: AtomicU64 = AtomicU64::new(0);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("stack_overflow_guarded::page_fault_in_guard_page...\t");
+
+    mini_os::gdt::init();
+    init_test_idt();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { mini_os::memory::init(phys_mem_offset) };
+    let rsp: u64;
+    unsafe { core::arch::asm!("mov {}, rsp", out(reg) rsp) };
+    let mut page = Page::<Size4KiB>::containing_address(VirtAddr::new(rsp));
+    for _ in 0..MAX_STACK_PAGES {
+        page -= 1;
+        if mapper.translate_addr(page.start_address()).is_none() {
+            GUARD_PAGE.store(page.start_address().as_u64(), Ordering::SeqCst);
+            break;
+        }
+    }
+    if GUARD_PAGE.load(Ordering::SeqCst) == 0 {
+        serial_println!("[failed] no unmapped page within {} pages below the stack", MAX_STACK_PAGES);
+        exit_qemu(QemuExitCode::Failed);
+    }
+
+    stack_overflow();
+
+    panic!("Execution continued after stack overflow");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    mini_os::test_panic_handler(info)
+}
+
+// Custom IDT initialization ==============================
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        unsafe {
+            idt.page_fault
+                .set_handler_fn(test_page_fault_handler)
+                .set_stack_index(mini_os::gdt::PAGE_FAULT_IST_INDEX);
+            idt.double_fault
+                .set_handler_fn(test_double_fault_handler)
+                .set_stack_index(mini_os::gdt::DOUBLE_FAULT_IST_INDEX);
+        }
+
+        idt
+    };
+}
+
+pub fn init_test_idt() {
+    TEST_IDT.load();
+}
+
+extern "x86-interrupt" fn test_page_fault_handler(_stack_frame: InterruptStackFrame, error_code: PageFaultErrorCode) {
+    let guard = GUARD_PAGE.load(Ordering::SeqCst);
+    let addr = Cr2::read().as_u64();
+    if (guard..guard + 4096).contains(&addr) {
+        serial_println!("[ok] {} at {:#x}", MARKER, addr);
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed] page fault at {:#x} ({:?}), the guard page is {:#x}", addr, error_code, guard);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    loop {}
+}
+
+extern "x86-interrupt" fn test_double_fault_handler(_stack_frame: InterruptStackFrame, _error_code: u64) -> ! {
+    serial_println!("[failed] double fault, the page fault handler didn't catch the overflow");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
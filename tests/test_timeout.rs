@@ -0,0 +1,74 @@
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+use mini_os::watchdog::{self, WatchdogAction};
+use mini_os::{exit_qemu, serial_println, set_test_timeout_exit, set_test_timeout_ticks, QemuExitCode, Testable};
+use spin::Mutex;
+
+// NOTE: like watchdog.rs this test has no harness --> its second test deadlocks on purpose and the test timeout ends it
+// a test run counts a timeout as a failure, so the exit of the timeout is replaced: it checks it was asked for Timeout
+// with the name of the hung test, then exits with Success --> not timing out (or the wrong test/code) is the failure
+
+const TIMEOUT_TICKS: u64 = 50;
+
+// set right before the test that has to time out, a timeout before that is a real failure
+static EXPECT_TIMEOUT: AtomicBool = AtomicBool::new(false);
+
+fn spin_ticks(ticks: u64) {
+    let start = mini_os::interrupts::timer_ticks();
+    while mini_os::interrupts::timer_ticks() - start < ticks {
+        core::hint::spin_loop();
+    }
+}
+
+// called by the timer interrupt after it printed "[timeout] <name>"
+fn timed_out(name: &'static str, code: QemuExitCode) {
+    if !EXPECT_TIMEOUT.load(Ordering::SeqCst) {
+        serial_println!("[failed] timed out too early");
+        mini_os::exit_qemu_without_report(QemuExitCode::Failed);
+    }
+    let expected = name.ends_with("::deadlock") && code == QemuExitCode::Timeout;
+    if !expected {
+        serial_println!("[failed] expected Timeout for test_timeout::deadlock, got {:?} for {}", code, name);
+    }
+    mini_os::exit_qemu_without_report(if expected { QemuExitCode::Success } else { QemuExitCode::Failed });
+}
+
+// MAIN TEST ================================================
+
+fn just_in_time() {
+    spin_ticks(TIMEOUT_TICKS - 10);
+}
+
+// the second lock() spins forever, interrupts stay on
+fn deadlock() {
+    static LOCK: Mutex<()> = Mutex::new(());
+    let _held = LOCK.lock();
+    let _never = LOCK.lock();
+}
+
+// END ========================================================
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    mini_os::init(); // the timer interrupt
+    set_test_timeout_ticks(TIMEOUT_TICKS);
+    set_test_timeout_exit(timed_out);
+    // backstop in case the timeout never comes
+    watchdog::set_action(WatchdogAction::ExitQemu(QemuExitCode::Failed));
+    watchdog::arm(10 * TIMEOUT_TICKS);
+    just_in_time.run();
+    watchdog::pet();
+    EXPECT_TIMEOUT.store(true, Ordering::SeqCst);
+    deadlock.run();
+    serial_println!("[test did not time out]");
+    exit_qemu(QemuExitCode::Failed);
+    mini_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    mini_os::test_panic_handler(info)
+}
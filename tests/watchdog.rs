@@ -0,0 +1,43 @@
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+use mini_os::watchdog::{self, WatchdogAction};
+use mini_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+
+// NOTE: like stack_overflow.rs this test has no harness --> it hangs on purpose and the watchdog ends it
+// a test run counts a fired watchdog as a failure, so here it exits with Success instead and not firing is the failure
+
+// the timer runs at its power-on rate of ~18 Hz (mini_os::init() doesn't change it)
+const TIMEOUT_TICKS: u64 = 18; // ~1 s
+const GIVE_UP_TICKS: u64 = 10 * 18;
+
+// MAIN TEST ================================================
+
+fn hang() -> ! {
+    serial_print!("watchdog::hang...\t");
+    let start = mini_os::interrupts::timer_ticks();
+    watchdog::set_action(WatchdogAction::ExitQemu(QemuExitCode::Success));
+    watchdog::arm(TIMEOUT_TICKS);
+    // never pets --> the timer interrupt has to end this
+    loop {
+        if mini_os::interrupts::timer_ticks() - start > GIVE_UP_TICKS {
+            serial_println!("[watchdog did not fire]");
+            exit_qemu(QemuExitCode::Failed);
+        }
+        core::hint::spin_loop();
+    }
+}
+
+// END ========================================================
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    mini_os::init(); // the timer interrupt
+    hang();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    mini_os::test_panic_handler(info)
+}